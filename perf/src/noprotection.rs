@@ -123,6 +123,18 @@ impl crypto::Session for NoProtectionSession {
     ) -> Result<(), crypto::ExportKeyingMaterialError> {
         self.inner.export_keying_material(output, label, context)
     }
+
+    fn is_resumed(&self) -> bool {
+        self.inner.is_resumed()
+    }
+
+    fn early_data_identity(&self) -> Option<Vec<u8>> {
+        self.inner.early_data_identity()
+    }
+
+    fn filter_early_data(&mut self, accept: Box<dyn FnOnce(&[u8]) -> bool + Send>) {
+        self.inner.filter_early_data(accept)
+    }
 }
 
 impl crypto::ClientConfig for NoProtectionClientConfig {
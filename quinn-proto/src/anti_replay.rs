@@ -0,0 +1,49 @@
+use std::{
+    collections::{hash_map::RandomState, HashSet, VecDeque},
+    hash::{BuildHasher, Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+/// Remembers the session tickets clients used for 0-RTT, to detect replayed early data
+///
+/// Shared by all connections of an endpoint. Tickets are stored as keyed 64-bit fingerprints; a
+/// collision only costs a legitimate client its 0-RTT data.
+#[derive(Debug, Default)]
+pub(crate) struct AntiReplay {
+    hasher: RandomState,
+    seen: HashSet<u64>,
+    /// Fingerprints in the order they were first seen
+    order: VecDeque<(Instant, u64)>,
+}
+
+impl AntiReplay {
+    /// Record a use of the ticket with `identity`, returning whether its early data may be accepted
+    ///
+    /// Uses are remembered for `window`. If `capacity` unexpired uses are already remembered, the
+    /// ticket is rejected, since a replay of it could not be detected later.
+    pub(crate) fn check(
+        &mut self,
+        now: Instant,
+        identity: &[u8],
+        window: Duration,
+        capacity: usize,
+    ) -> bool {
+        while let Some(&(time, fingerprint)) = self.order.front() {
+            if now.saturating_duration_since(time) < window {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&fingerprint);
+        }
+
+        let mut hasher = self.hasher.build_hasher();
+        identity.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+        if self.seen.contains(&fingerprint) || self.order.len() >= capacity {
+            return false;
+        }
+        self.seen.insert(fingerprint);
+        self.order.push_back((now, fingerprint));
+        true
+    }
+}
@@ -1,7 +1,9 @@
-use std::{fmt, num::TryFromIntError, sync::Arc, time::Duration};
+use std::{any::Any, fmt, net::SocketAddr, num::TryFromIntError, sync::Arc, time::Duration};
 
+use bytes::Bytes;
 use rustls::JlsServerConfig;
 use thiserror::Error;
+use zeroize::Zeroizing;
 
 #[cfg(feature = "ring")]
 use rand::RngCore;
@@ -10,7 +12,11 @@ use crate::{
     cid_generator::{ConnectionIdGenerator, RandomConnectionIdGenerator},
     congestion,
     crypto::{self, HandshakeTokenKey, HmacKey},
+    shared::ConnectionId,
+    token::ResetToken,
+    transport_parameters::CustomParameters,
     VarInt, VarIntBoundsExceeded, DEFAULT_SUPPORTED_VERSIONS, INITIAL_MTU, MAX_UDP_PAYLOAD,
+    RESET_TOKEN_SIZE,
 };
 
 /// Parameters governing the core QUIC state machine
@@ -32,6 +38,9 @@ pub struct TransportConfig {
     pub(crate) stream_receive_window: VarInt,
     pub(crate) receive_window: VarInt,
     pub(crate) send_window: u64,
+    pub(crate) receive_window_auto_tuning: bool,
+    pub(crate) max_stream_receive_window: VarInt,
+    pub(crate) max_receive_window: VarInt,
 
     pub(crate) max_tlps: u32,
     pub(crate) packet_threshold: u32,
@@ -42,11 +51,16 @@ pub struct TransportConfig {
     pub(crate) mtu_discovery_config: Option<MtuDiscoveryConfig>,
 
     pub(crate) persistent_congestion_threshold: u32,
+    pub(crate) degraded_after_ptos: u32,
+    pub(crate) broken_after_ptos: u32,
     pub(crate) keep_alive_interval: Option<Duration>,
     pub(crate) crypto_buffer_size: usize,
     pub(crate) allow_spin: bool,
     pub(crate) datagram_receive_buffer_size: Option<usize>,
     pub(crate) datagram_send_buffer_size: usize,
+    pub(crate) send_coalesce_delay: Option<Duration>,
+    pub(crate) send_ect1: bool,
+    pub(crate) pacing_horizon: Option<Duration>,
 
     pub(crate) congestion_controller_factory: Box<dyn congestion::ControllerFactory + Send + Sync>,
 }
@@ -120,6 +134,38 @@ impl TransportConfig {
         self
     }
 
+    /// Whether to grow the receive windows when the peer's delivery rate requires it
+    ///
+    /// When enabled, `stream_receive_window` and `receive_window` are only the initial windows.
+    /// Whenever the data read by the application over a round trip approaches the current window,
+    /// the window is doubled, up to `max_stream_receive_window` and `max_receive_window`
+    /// respectively. This allows a modest initial window to be configured without capping
+    /// throughput on paths with a high bandwidth-delay product.
+    ///
+    /// Disabled by default, so the windows stay as configured.
+    pub fn receive_window_auto_tuning(&mut self, value: bool) -> &mut Self {
+        self.receive_window_auto_tuning = value;
+        self
+    }
+
+    /// Upper bound for the per-stream receive window when auto-tuning is enabled
+    ///
+    /// Has no effect if this is smaller than `stream_receive_window`. See
+    /// [`receive_window_auto_tuning()`](Self::receive_window_auto_tuning).
+    pub fn max_stream_receive_window(&mut self, value: VarInt) -> &mut Self {
+        self.max_stream_receive_window = value;
+        self
+    }
+
+    /// Upper bound for the connection receive window when auto-tuning is enabled
+    ///
+    /// Has no effect if this is smaller than `receive_window`. See
+    /// [`receive_window_auto_tuning()`](Self::receive_window_auto_tuning).
+    pub fn max_receive_window(&mut self, value: VarInt) -> &mut Self {
+        self.max_receive_window = value;
+        self
+    }
+
     /// Maximum number of bytes to transmit to a peer without acknowledgment
     ///
     /// Provides an upper bound on memory when communicating with peers that issue large amounts of
@@ -225,6 +271,32 @@ impl TransportConfig {
         self
     }
 
+    /// Number of consecutive PTOs after which a connection's health is reported as degraded
+    ///
+    /// See [`ConnectionHealth`](crate::ConnectionHealth). Must not exceed
+    /// [`broken_after_ptos`](Self::broken_after_ptos). Defaults to 2.
+    pub fn degraded_after_ptos(&mut self, value: u32) -> Result<&mut Self, ConfigError> {
+        if value > self.broken_after_ptos {
+            return Err(ConfigError::OutOfBounds);
+        }
+
+        self.degraded_after_ptos = value;
+        Ok(self)
+    }
+
+    /// Number of consecutive PTOs after which a connection's health is reported as broken
+    ///
+    /// PTOs back off exponentially, so with the default of 4 this takes roughly 15 times the
+    /// probe timeout. Must be at least [`degraded_after_ptos`](Self::degraded_after_ptos).
+    pub fn broken_after_ptos(&mut self, value: u32) -> Result<&mut Self, ConfigError> {
+        if value < self.degraded_after_ptos {
+            return Err(ConfigError::OutOfBounds);
+        }
+
+        self.broken_after_ptos = value;
+        Ok(self)
+    }
+
     /// Period of inactivity before sending a keep-alive packet
     ///
     /// Keep-alive packets prevent an inactive but otherwise healthy connection from timing out.
@@ -274,6 +346,46 @@ impl TransportConfig {
         self
     }
 
+    /// How long small amounts of stream data may wait for more before being sent, if at all
+    ///
+    /// Chatty protocols issuing many small writes otherwise tend to send a packet per write. With a
+    /// delay set, stream data less than a full packet is held back for up to this long, so that
+    /// subsequent writes can share its packet. Data goes out immediately whenever anything else is
+    /// sent, when a stream is finished or flushed, or when flow control prevents further writes.
+    /// `None` to disable, which is the default. Can be changed on a live connection with
+    /// `Connection::set_send_coalesce_delay`.
+    pub fn send_coalesce_delay(&mut self, value: Option<Duration>) -> &mut Self {
+        self.send_coalesce_delay = value;
+        self
+    }
+
+    /// Whether to mark outgoing packets ECT(1) rather than ECT(0)
+    ///
+    /// ECT(1) identifies traffic using scalable congestion control as defined by L4S (RFC 9331),
+    /// which expects far more frequent CE marks than classic ECN. Only enable this along with a
+    /// congestion controller designed for it. Defaults to `false`.
+    pub fn send_ect1(&mut self, value: bool) -> &mut Self {
+        self.send_ect1 = value;
+        self
+    }
+
+    /// How far ahead of time pacing may schedule datagrams, if at all
+    ///
+    /// Normally a connection blocked by pacing sets a timer for when it may send again, and many
+    /// paced connections wake up very often between them. With a horizon set, datagrams pacing
+    /// would let out within that long are produced straight away, with [`Transmit::send_at`]
+    /// saying when they may leave, and the connection only needs waking once per horizon. The
+    /// endpoint driving the connection must then hold each transmit until its time comes.
+    ///
+    /// Packets count as sent when they're produced, so RTT samples may come out up to the horizon
+    /// too high. A few milliseconds is plenty. `None`, the default, disables this.
+    ///
+    /// [`Transmit::send_at`]: crate::Transmit::send_at
+    pub fn pacing_horizon(&mut self, value: Option<Duration>) -> &mut Self {
+        self.pacing_horizon = value;
+        self
+    }
+
     /// How to construct new `congestion::Controller`s
     ///
     /// Typically the refcounted configuration of a `congestion::Controller`,
@@ -309,6 +421,9 @@ impl Default for TransportConfig {
             stream_receive_window: STREAM_RWND.into(),
             receive_window: VarInt::MAX,
             send_window: (8 * STREAM_RWND).into(),
+            receive_window_auto_tuning: false,
+            max_stream_receive_window: (8 * STREAM_RWND).into(),
+            max_receive_window: VarInt::MAX,
 
             max_tlps: 2,
             packet_threshold: 3,
@@ -319,11 +434,16 @@ impl Default for TransportConfig {
             mtu_discovery_config: Some(MtuDiscoveryConfig::default()),
 
             persistent_congestion_threshold: 3,
+            degraded_after_ptos: 2,
+            broken_after_ptos: 4,
             keep_alive_interval: None,
             crypto_buffer_size: 16 * 1024,
             allow_spin: true,
             datagram_receive_buffer_size: Some(STREAM_RWND as usize),
             datagram_send_buffer_size: 1024 * 1024,
+            send_coalesce_delay: None,
+            send_ect1: false,
+            pacing_horizon: None,
 
             congestion_controller_factory: Box::new(Arc::new(congestion::CubicConfig::default())),
         }
@@ -345,6 +465,12 @@ impl fmt::Debug for TransportConfig {
             .field("stream_receive_window", &self.stream_receive_window)
             .field("receive_window", &self.receive_window)
             .field("send_window", &self.send_window)
+            .field(
+                "receive_window_auto_tuning",
+                &self.receive_window_auto_tuning,
+            )
+            .field("max_stream_receive_window", &self.max_stream_receive_window)
+            .field("max_receive_window", &self.max_receive_window)
             .field("max_tlps", &self.max_tlps)
             .field("packet_threshold", &self.packet_threshold)
             .field("time_threshold", &self.time_threshold)
@@ -353,6 +479,8 @@ impl fmt::Debug for TransportConfig {
                 "persistent_congestion_threshold",
                 &self.persistent_congestion_threshold,
             )
+            .field("degraded_after_ptos", &self.degraded_after_ptos)
+            .field("broken_after_ptos", &self.broken_after_ptos)
             .field("keep_alive_interval", &self.keep_alive_interval)
             .field("crypto_buffer_size", &self.crypto_buffer_size)
             .field("allow_spin", &self.allow_spin)
@@ -361,6 +489,9 @@ impl fmt::Debug for TransportConfig {
                 &self.datagram_receive_buffer_size,
             )
             .field("datagram_send_buffer_size", &self.datagram_send_buffer_size)
+            .field("send_coalesce_delay", &self.send_coalesce_delay)
+            .field("send_ect1", &self.send_ect1)
+            .field("pacing_horizon", &self.pacing_horizon)
             .field("congestion_controller_factory", &"[ opaque ]")
             .finish()
     }
@@ -479,6 +610,8 @@ impl Default for MtuDiscoveryConfig {
 #[derive(Clone)]
 pub struct EndpointConfig {
     pub(crate) reset_key: Arc<dyn HmacKey>,
+    /// What `reset_key` was derived from, if known
+    reset_key_seed: Option<Arc<KeySeed>>,
     pub(crate) max_udp_payload_size: VarInt,
     /// CID generator factory
     ///
@@ -487,6 +620,29 @@ pub struct EndpointConfig {
         Arc<dyn Fn() -> Box<dyn ConnectionIdGenerator> + Send + Sync>,
     pub(crate) supported_versions: Vec<u32>,
     pub(crate) grease_quic_bit: bool,
+    pub(crate) jls_forwarding: bool,
+    pub(crate) jls_forward_idle_timeout: Duration,
+    pub(crate) jls_forward_queue_limit: u64,
+    pub(crate) jls_forward_total_queue_limit: u64,
+    pub(crate) jls_forward_limit: u64,
+    pub(crate) jls_forward_rate: u64,
+    pub(crate) jls_forward_socket_per_client: bool,
+    pub(crate) jls_upstream_bind: Option<SocketAddr>,
+    pub(crate) jls_upstream_transport: JlsUpstreamTransport,
+    pub(crate) jls_upstream_socks5: Option<JlsSocks5Proxy>,
+    pub(crate) jls_upstream_host: Option<(String, u16)>,
+    pub(crate) jls_upstream_routes: Vec<(String, SocketAddr)>,
+    pub(crate) jls_drop_unrouted: bool,
+    pub(crate) jls_upstream_alternates: Vec<SocketAddr>,
+    pub(crate) jls_upstream_selection: JlsUpstreamSelection,
+    pub(crate) jls_upstream_response_timeout: Duration,
+    pub(crate) jls_forward_unanswered_warning: u32,
+    pub(crate) jls_forward_unanswered_limit: u64,
+    pub(crate) jls_client_redaction: JlsClientRedaction,
+    pub(crate) response_rate: u64,
+    pub(crate) stateless_reset_policy: StatelessResetPolicy,
+    pub(crate) recv_timestamps: bool,
+    pub(crate) max_queued_datagrams: usize,
 }
 
 impl EndpointConfig {
@@ -496,10 +652,34 @@ impl EndpointConfig {
             || Box::<RandomConnectionIdGenerator>::default();
         Self {
             reset_key,
+            reset_key_seed: None,
             max_udp_payload_size: MAX_UDP_PAYLOAD.into(), // See RFC 9000 (https://www.rfc-editor.org/rfc/rfc9000.html#section-18.2-4.10.1)
             connection_id_generator_factory: Arc::new(cid_factory),
             supported_versions: DEFAULT_SUPPORTED_VERSIONS.to_vec(),
             grease_quic_bit: true,
+            jls_forwarding: true,
+            jls_forward_idle_timeout: Duration::from_secs(30),
+            jls_forward_queue_limit: 1024 * 1024,
+            jls_forward_total_queue_limit: 64 * 1024 * 1024,
+            jls_forward_limit: 4096,
+            jls_forward_rate: 1024,
+            jls_forward_socket_per_client: false,
+            jls_upstream_bind: None,
+            jls_upstream_transport: JlsUpstreamTransport::Udp,
+            jls_upstream_socks5: None,
+            jls_upstream_host: None,
+            jls_upstream_routes: Vec::new(),
+            jls_drop_unrouted: false,
+            jls_upstream_alternates: Vec::new(),
+            jls_upstream_selection: JlsUpstreamSelection::Failover,
+            jls_upstream_response_timeout: Duration::from_secs(3),
+            jls_forward_unanswered_warning: 5,
+            jls_forward_unanswered_limit: 16 * 1024,
+            jls_client_redaction: JlsClientRedaction::Off,
+            response_rate: 4096,
+            stateless_reset_policy: StatelessResetPolicy::RateLimited,
+            recv_timestamps: false,
+            max_queued_datagrams: 1024,
         }
     }
 
@@ -525,9 +705,21 @@ impl EndpointConfig {
     /// communicating with a previous instance of this endpoint.
     pub fn reset_key(&mut self, key: Arc<dyn HmacKey>) -> &mut Self {
         self.reset_key = key;
+        self.reset_key_seed = None;
         self
     }
 
+    /// The stateless reset token this endpoint issues along with connection ID `cid`
+    ///
+    /// Peers accept a stateless reset for a connection if it ends with the token of a connection ID
+    /// they have been sending to. Derived from the [`reset_key`](Self::reset_key), so a new
+    /// instance of an endpoint configured with the same key computes the same tokens.
+    pub fn reset_token(&self, cid: &ConnectionId) -> [u8; RESET_TOKEN_SIZE] {
+        let mut token = [0; RESET_TOKEN_SIZE];
+        token.copy_from_slice(&ResetToken::new(&*self.reset_key, cid));
+        token
+    }
+
     /// Maximum UDP payload size accepted from peers (excluding UDP and IP overhead).
     ///
     /// Must be greater or equal than 1200.
@@ -570,6 +762,398 @@ impl EndpointConfig {
         self.grease_quic_bit = value;
         self
     }
+
+    /// Whether clients failing JLS authentication are forwarded to the upstream
+    ///
+    /// When disabled, such clients' first datagrams are dropped without an answer, and the endpoint
+    /// skips looking up forward connections for each datagram it receives, as well as checking on
+    /// its forward connections each time it's driven. Disable this when using the endpoint as a
+    /// plain QUIC endpoint. Defaults to `true`.
+    pub fn jls_forwarding(&mut self, value: bool) -> &mut Self {
+        self.jls_forwarding = value;
+        self
+    }
+
+    /// Get the current value of `jls_forwarding`
+    pub fn get_jls_forwarding(&self) -> bool {
+        self.jls_forwarding
+    }
+
+    /// How long a JLS forward connection may relay nothing in either direction before it's ended
+    ///
+    /// Long-lived, mostly idle connections being camouflaged call for a longer timeout, while busy
+    /// public relays may want a shorter one to free sockets sooner. `Duration::MAX` disables
+    /// expiry. Defaults to 30 seconds.
+    pub fn jls_forward_idle_timeout(&mut self, value: Duration) -> &mut Self {
+        self.jls_forward_idle_timeout = value;
+        self
+    }
+
+    /// Get the current value of `jls_forward_idle_timeout`
+    pub fn get_jls_forward_idle_timeout(&self) -> Duration {
+        self.jls_forward_idle_timeout
+    }
+
+    /// Bytes of datagrams from a forwarded client that may wait to be relayed to its upstream
+    ///
+    /// Further datagrams are dropped until the upstream's socket takes some, so a client can't
+    /// grow the queue without bound while the upstream is slow or unreachable. Defaults to 1 MiB.
+    pub fn jls_forward_queue_limit(&mut self, value: u64) -> &mut Self {
+        self.jls_forward_queue_limit = value;
+        self
+    }
+
+    /// Get the current value of `jls_forward_queue_limit`
+    pub fn get_jls_forward_queue_limit(&self) -> u64 {
+        self.jls_forward_queue_limit
+    }
+
+    /// Bytes of forwarded datagrams that may wait to be relayed, over all JLS forward connections
+    /// together
+    ///
+    /// Counts datagrams from clients waiting for their upstreams, as limited for each client by
+    /// [`jls_forward_queue_limit`](Self::jls_forward_queue_limit), and those from upstreams
+    /// waiting in the endpoint's transmit queue for their clients. Further datagrams in either
+    /// direction are dropped until some are sent, so thousands of forward connections can't
+    /// together hold more memory than this while the upstreams' link is saturated. Defaults to
+    /// 64 MiB.
+    pub fn jls_forward_total_queue_limit(&mut self, value: u64) -> &mut Self {
+        self.jls_forward_total_queue_limit = value;
+        self
+    }
+
+    /// Get the current value of `jls_forward_total_queue_limit`
+    pub fn get_jls_forward_total_queue_limit(&self) -> u64 {
+        self.jls_forward_total_queue_limit
+    }
+
+    /// Most JLS forward connections that may be live at once
+    ///
+    /// This bounds the state clients without JLS credentials can make the endpoint keep, and with
+    /// [a socket per client](Self::jls_forward_socket_per_client) the file descriptors too.
+    /// Clients beyond it aren't forwarded, and their datagrams are dropped. Defaults to 4096.
+    pub fn jls_forward_limit(&mut self, value: u64) -> &mut Self {
+        self.jls_forward_limit = value;
+        self
+    }
+
+    /// Get the current value of `jls_forward_limit`
+    pub fn get_jls_forward_limit(&self) -> u64 {
+        self.jls_forward_limit
+    }
+
+    /// New JLS forward connections that may be set up per second
+    ///
+    /// Bursts of up to a second's worth are allowed. Clients beyond the rate aren't forwarded, and
+    /// their datagrams are dropped, so a flood of spoofed Initial packets can't make the endpoint
+    /// spend its time binding sockets. Defaults to 1024.
+    pub fn jls_forward_rate(&mut self, value: u64) -> &mut Self {
+        self.jls_forward_rate = value;
+        self
+    }
+
+    /// Get the current value of `jls_forward_rate`
+    pub fn get_jls_forward_rate(&self) -> u64 {
+        self.jls_forward_rate
+    }
+
+    /// Whether each JLS forward connection gets a socket of its own to reach its upstream
+    ///
+    /// By default, forward connections share one socket, and datagrams from upstreams are matched
+    /// to clients by the connection IDs the clients chose, or by upstream if only one client is
+    /// forwarded to it. That can't tell apart clients choosing zero-length connection IDs, nor
+    /// follow an upstream switching to connection IDs it was issued later, and every client is
+    /// relayed from the same source port. Upstreams for which that matters call for a socket per
+    /// client, at the cost of a file descriptor each. Defaults to `false`.
+    pub fn jls_forward_socket_per_client(&mut self, value: bool) -> &mut Self {
+        self.jls_forward_socket_per_client = value;
+        self
+    }
+
+    /// Get the current value of `jls_forward_socket_per_client`
+    pub fn get_jls_forward_socket_per_client(&self) -> bool {
+        self.jls_forward_socket_per_client
+    }
+
+    /// Local address to bind the sockets reaching JLS upstreams to
+    ///
+    /// Lets a multi-homed host pick the interface, and source address, that forwarded traffic
+    /// leaves from. The port should usually be 0, as several sockets may be bound to the address.
+    /// An address of the other family than an upstream's can't reach it, so clients forwarded
+    /// there fail to be set up, unless it's the IPv6 wildcard address of a dual-stack host.
+    /// Defaults to `None`, binding to the wildcard address of each upstream's family.
+    pub fn jls_upstream_bind(&mut self, value: Option<SocketAddr>) -> &mut Self {
+        self.jls_upstream_bind = value;
+        self
+    }
+
+    /// Get the current value of `jls_upstream_bind`
+    pub fn get_jls_upstream_bind(&self) -> Option<SocketAddr> {
+        self.jls_upstream_bind
+    }
+
+    /// How JLS forward connections reach their upstreams
+    ///
+    /// Upstreams whose network rate-limits or drops UDP may be reached over TCP instead, through a
+    /// relay in front of them that speaks [`JlsUpstreamTransport::Tcp`]. Each forward connection
+    /// then has a TCP connection of its own, so
+    /// [`jls_forward_socket_per_client`](Self::jls_forward_socket_per_client) doesn't apply, while
+    /// [`jls_upstream_bind`](Self::jls_upstream_bind) still does. Defaults to
+    /// [`JlsUpstreamTransport::Udp`].
+    pub fn jls_upstream_transport(&mut self, value: JlsUpstreamTransport) -> &mut Self {
+        self.jls_upstream_transport = value;
+        self
+    }
+
+    /// Get the current value of `jls_upstream_transport`
+    pub fn get_jls_upstream_transport(&self) -> JlsUpstreamTransport {
+        self.jls_upstream_transport
+    }
+
+    /// SOCKS5 proxy through which JLS forward connections reach their upstreams over UDP
+    ///
+    /// For hosts whose only way out is an egress proxy. Each forward connection opens a UDP
+    /// association of its own with the proxy, and sends and receives through a socket of its own,
+    /// whatever [`jls_forward_socket_per_client`](Self::jls_forward_socket_per_client) says.
+    /// [`jls_upstream_bind`](Self::jls_upstream_bind) then applies to the proxy's address rather
+    /// than the upstream's. A forward connection whose association can't be opened fails to be
+    /// set up, alone. Doesn't apply to [`JlsUpstreamTransport::Tcp`]. Needs quinn's
+    /// `jls-forward-socks5` feature, and a runtime that can open TCP connections. Defaults to
+    /// `None`, reaching upstreams directly.
+    pub fn jls_upstream_socks5(&mut self, value: Option<JlsSocks5Proxy>) -> &mut Self {
+        self.jls_upstream_socks5 = value;
+        self
+    }
+
+    /// Get the current value of `jls_upstream_socks5`
+    pub fn get_jls_upstream_socks5(&self) -> Option<&JlsSocks5Proxy> {
+        self.jls_upstream_socks5.as_ref()
+    }
+
+    /// Host name and port of the upstream JLS forward connections go to, in place of the address
+    /// given by the JLS server configuration
+    ///
+    /// The name is looked up through the endpoint's runtime, so resolution never holds up the
+    /// endpoint's driver; clients that arrive meanwhile have their datagrams held until it
+    /// completes. The first address found is used until forward connections to it fail a few times
+    /// in a row, when the name is looked up again. Defaults to `None`.
+    pub fn jls_upstream_host(&mut self, value: Option<(String, u16)>) -> &mut Self {
+        self.jls_upstream_host = value;
+        self
+    }
+
+    /// Get the current value of `jls_upstream_host`
+    pub fn get_jls_upstream_host(&self) -> Option<(&str, u16)> {
+        self.jls_upstream_host
+            .as_ref()
+            .map(|(host, port)| (host.as_str(), *port))
+    }
+
+    /// Upstreams JLS forward connections go to by the server name in the client's ClientHello
+    ///
+    /// Lets one endpoint pass for several sites, each forwarded to its own upstream. A pattern is
+    /// either a server name, matched exactly, or `*.` followed by a domain, matching any name
+    /// below that domain. An exact match takes precedence over wildcards, and a longer wildcard
+    /// over a shorter one. Names are compared ignoring ASCII case and any trailing dot.
+    ///
+    /// A routed client's upstream takes the place of the one given by the JLS server
+    /// configuration or by [`jls_upstream_host`](Self::jls_upstream_host), while
+    /// [`jls_upstream_alternates`](Self::jls_upstream_alternates) still apply. Clients whose
+    /// server name matches no route go where they would without routes, unless
+    /// [`jls_drop_unrouted`](Self::jls_drop_unrouted) is set. Defaults to none.
+    pub fn jls_upstream_routes(&mut self, value: Vec<(String, SocketAddr)>) -> &mut Self {
+        self.jls_upstream_routes = value
+            .into_iter()
+            .map(|(pattern, addr)| {
+                let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+                (pattern, addr)
+            })
+            .collect();
+        self
+    }
+
+    /// Get the current value of `jls_upstream_routes`, with patterns in lowercase
+    pub fn get_jls_upstream_routes(&self) -> &[(String, SocketAddr)] {
+        &self.jls_upstream_routes
+    }
+
+    /// Whether clients whose server name matches none of the
+    /// [`jls_upstream_routes`](Self::jls_upstream_routes) are left unanswered rather than
+    /// forwarded
+    ///
+    /// Clients sending no server name at all count as unrouted. Doesn't apply while there are no
+    /// routes. Defaults to `false`.
+    pub fn jls_drop_unrouted(&mut self, value: bool) -> &mut Self {
+        self.jls_drop_unrouted = value;
+        self
+    }
+
+    /// Get the current value of `jls_drop_unrouted`
+    pub fn get_jls_drop_unrouted(&self) -> bool {
+        self.jls_drop_unrouted
+    }
+
+    /// Upstreams JLS forward connections may go to besides the one given by the JLS server
+    /// configuration, or by [`jls_upstream_host`](Self::jls_upstream_host)
+    ///
+    /// A forward connection whose upstream can't be reached, or doesn't answer the client's first
+    /// datagram within [`jls_upstream_response_timeout`](Self::jls_upstream_response_timeout),
+    /// moves on to the next upstream not yet tried, which is sent that datagram again. Which
+    /// upstream is tried first is up to
+    /// [`jls_upstream_selection`](Self::jls_upstream_selection). Defaults to none.
+    pub fn jls_upstream_alternates(&mut self, value: Vec<SocketAddr>) -> &mut Self {
+        self.jls_upstream_alternates = value;
+        self
+    }
+
+    /// Get the current value of `jls_upstream_alternates`
+    pub fn get_jls_upstream_alternates(&self) -> &[SocketAddr] {
+        &self.jls_upstream_alternates
+    }
+
+    /// How JLS forward connections choose among their upstreams, if there are
+    /// [alternates](Self::jls_upstream_alternates)
+    ///
+    /// Defaults to [`JlsUpstreamSelection::Failover`].
+    pub fn jls_upstream_selection(&mut self, value: JlsUpstreamSelection) -> &mut Self {
+        self.jls_upstream_selection = value;
+        self
+    }
+
+    /// Get the current value of `jls_upstream_selection`
+    pub fn get_jls_upstream_selection(&self) -> JlsUpstreamSelection {
+        self.jls_upstream_selection
+    }
+
+    /// How long an upstream gets to answer a forwarded client's first datagram before the forward
+    /// connection moves on to an [alternate](Self::jls_upstream_alternates)
+    ///
+    /// Forward connections with no upstream left to try wait for as long as their
+    /// [idle timeout](Self::jls_forward_idle_timeout) allows. Defaults to 3 seconds.
+    pub fn jls_upstream_response_timeout(&mut self, value: Duration) -> &mut Self {
+        self.jls_upstream_response_timeout = value;
+        self
+    }
+
+    /// Get the current value of `jls_upstream_response_timeout`
+    pub fn get_jls_upstream_response_timeout(&self) -> Duration {
+        self.jls_upstream_response_timeout
+    }
+
+    /// Datagrams a forwarded client may send before its upstream first answers without a warning
+    /// being logged
+    ///
+    /// A client that keeps retransmitting its first flight is a sign the upstream, or the path to
+    /// it, is broken, which gives the camouflage away. Each forward connection warns at most once.
+    /// Defaults to 5.
+    pub fn jls_forward_unanswered_warning(&mut self, value: u32) -> &mut Self {
+        self.jls_forward_unanswered_warning = value;
+        self
+    }
+
+    /// Get the current value of `jls_forward_unanswered_warning`
+    pub fn get_jls_forward_unanswered_warning(&self) -> u32 {
+        self.jls_forward_unanswered_warning
+    }
+
+    /// Bytes a forwarded client may have relayed to its upstream before the upstream first answers
+    ///
+    /// Nothing proves a forwarded client owns its address until the upstream's answer reaches it,
+    /// so a spoofed source could otherwise have the endpoint send the upstream as much as it likes.
+    /// Further datagrams are dropped until the upstream answers. The datagram carrying the
+    /// ClientHello is relayed regardless. `u64::MAX` disables the limit. Defaults to 16 KiB.
+    pub fn jls_forward_unanswered_limit(&mut self, value: u64) -> &mut Self {
+        self.jls_forward_unanswered_limit = value;
+        self
+    }
+
+    /// Get the current value of `jls_forward_unanswered_limit`
+    pub fn get_jls_forward_unanswered_limit(&self) -> u64 {
+        self.jls_forward_unanswered_limit
+    }
+
+    /// How the addresses of JLS-forwarded clients appear in logs
+    ///
+    /// Forward connections are logged under a span naming their client, and relays carry the
+    /// traffic of users who may not want their addresses kept. Defaults to
+    /// [`JlsClientRedaction::Off`].
+    pub fn jls_client_redaction(&mut self, value: JlsClientRedaction) -> &mut Self {
+        self.jls_client_redaction = value;
+        self
+    }
+
+    /// Get the current value of `jls_client_redaction`
+    pub fn get_jls_client_redaction(&self) -> JlsClientRedaction {
+        self.jls_client_redaction
+    }
+
+    /// Bytes per second the endpoint may send to any one address in answer to datagrams no
+    /// connection takes
+    ///
+    /// Version negotiation, Retry and refusal packets go to addresses nobody has validated, which
+    /// makes them a means of reflecting traffic at whoever a flood of spoofed Initial packets
+    /// names as their source. So do stateless resets, which are limited too unless the
+    /// [reset policy](Self::stateless_reset_policy) is [`StatelessResetPolicy::Always`]. Bursts of
+    /// up to a second's worth are allowed; responses beyond that are dropped. Connections' own
+    /// traffic, protected by their handshakes, isn't limited. `u64::MAX` disables the limit.
+    /// Defaults to 4 KiB.
+    pub fn response_rate(&mut self, value: u64) -> &mut Self {
+        self.response_rate = value;
+        self
+    }
+
+    /// Get the current value of `response_rate`
+    pub fn get_response_rate(&self) -> u64 {
+        self.response_rate
+    }
+
+    /// Whether to answer datagrams for unknown connections with stateless resets
+    ///
+    /// This covers peers still sending to connections that were closed and forgotten, as well as
+    /// clients of JLS forward connections that have ended. Resets the application sends with
+    /// [`Endpoint::stateless_reset_for`](crate::Endpoint::stateless_reset_for) are sent
+    /// regardless. Defaults to [`StatelessResetPolicy::RateLimited`].
+    pub fn stateless_reset_policy(&mut self, value: StatelessResetPolicy) -> &mut Self {
+        self.stateless_reset_policy = value;
+        self
+    }
+
+    /// Get the current value of `stateless_reset_policy`
+    pub fn get_stateless_reset_policy(&self) -> StatelessResetPolicy {
+        self.stateless_reset_policy
+    }
+
+    /// Whether to time received datagrams by when the kernel received them
+    ///
+    /// Makes RTT samples and other timing exclude delays between a datagram's arrival and the
+    /// endpoint getting to it, at some cost in receive throughput. Only supported on Linux, where
+    /// sockets given to `quinn` have `SO_TIMESTAMPNS` set; sockets of a custom runtime must report
+    /// timestamps themselves. Defaults to `false`.
+    pub fn recv_timestamps(&mut self, value: bool) -> &mut Self {
+        self.recv_timestamps = value;
+        self
+    }
+
+    /// Get the current value of `recv_timestamps`
+    pub fn get_recv_timestamps(&self) -> bool {
+        self.recv_timestamps
+    }
+
+    /// Maximum number of received datagrams queued for each connection
+    ///
+    /// Datagrams arriving for a connection that has fallen this far behind are dropped, to be
+    /// recovered like any other packet loss, so a stalled connection can't hold up the endpoint or
+    /// make it buffer without bound. Must be at least 1. Defaults to 1024.
+    pub fn max_queued_datagrams(&mut self, value: usize) -> &mut Self {
+        assert!(value > 0, "max_queued_datagrams must be at least 1");
+        self.max_queued_datagrams = value;
+        self
+    }
+
+    /// Get the current value of `max_queued_datagrams`
+    pub fn get_max_queued_datagrams(&self) -> usize {
+        self.max_queued_datagrams
+    }
 }
 
 impl fmt::Debug for EndpointConfig {
@@ -580,23 +1164,212 @@ impl fmt::Debug for EndpointConfig {
             .field("cid_generator_factory", &"[ elided ]")
             .field("supported_versions", &self.supported_versions)
             .field("grease_quic_bit", &self.grease_quic_bit)
+            .field("jls_forwarding", &self.jls_forwarding)
+            .field("jls_forward_idle_timeout", &self.jls_forward_idle_timeout)
+            .field("jls_forward_queue_limit", &self.jls_forward_queue_limit)
+            .field(
+                "jls_forward_total_queue_limit",
+                &self.jls_forward_total_queue_limit,
+            )
+            .field("jls_forward_limit", &self.jls_forward_limit)
+            .field("jls_forward_rate", &self.jls_forward_rate)
+            .field(
+                "jls_forward_socket_per_client",
+                &self.jls_forward_socket_per_client,
+            )
+            .field("jls_upstream_bind", &self.jls_upstream_bind)
+            .field("jls_upstream_transport", &self.jls_upstream_transport)
+            .field("jls_upstream_socks5", &self.jls_upstream_socks5)
+            .field("jls_upstream_host", &self.jls_upstream_host)
+            .field("jls_upstream_routes", &self.jls_upstream_routes)
+            .field("jls_drop_unrouted", &self.jls_drop_unrouted)
+            .field("jls_upstream_alternates", &self.jls_upstream_alternates)
+            .field("jls_upstream_selection", &self.jls_upstream_selection)
+            .field(
+                "jls_upstream_response_timeout",
+                &self.jls_upstream_response_timeout,
+            )
+            .field(
+                "jls_forward_unanswered_warning",
+                &self.jls_forward_unanswered_warning,
+            )
+            .field(
+                "jls_forward_unanswered_limit",
+                &self.jls_forward_unanswered_limit,
+            )
+            .field("jls_client_redaction", &self.jls_client_redaction)
+            .field("response_rate", &self.response_rate)
+            .field("stateless_reset_policy", &self.stateless_reset_policy)
+            .field("recv_timestamps", &self.recv_timestamps)
+            .field("max_queued_datagrams", &self.max_queued_datagrams)
             .finish()
     }
 }
 
+/// How an endpoint answers datagrams for connections it doesn't know
+///
+/// Those include connections the endpoint closed and has since forgotten, whose peers may keep
+/// sending for a while. See [`EndpointConfig::stateless_reset_policy`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatelessResetPolicy {
+    /// Answer each with a stateless reset, however many there are
+    Always,
+    /// Answer with stateless resets, within the [response rate](EndpointConfig::response_rate)
+    RateLimited,
+    /// Never answer
+    ///
+    /// Resets reveal a QUIC stack, so an endpoint passing for a different server, as with JLS,
+    /// shouldn't send them. Peers of forgotten connections find out once their idle timeout
+    /// expires instead.
+    Silent,
+}
+
+/// How JLS forward connections choose among their upstreams
+///
+/// See [`EndpointConfig::jls_upstream_alternates`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JlsUpstreamSelection {
+    /// Start with the upstream given by the JLS server configuration, then try the alternates in
+    /// order
+    Failover,
+    /// Start each forward connection with the next upstream in turn, then try those after it
+    RoundRobin,
+}
+
+/// How JLS forward connections reach their upstreams
+///
+/// See [`EndpointConfig::jls_upstream_transport`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JlsUpstreamTransport {
+    /// Relay each datagram as a UDP datagram
+    Udp,
+    /// Open a TCP connection for each forward connection, and send each datagram over it
+    /// prefixed with its length, as a 16-bit big-endian integer
+    ///
+    /// The upstream answers the same way. Needs quinn's `jls-forward-tcp` feature, and a runtime
+    /// that can open TCP connections; forward connections fail to be set up otherwise.
+    Tcp,
+}
+
+/// A SOCKS5 proxy, as used by [`EndpointConfig::jls_upstream_socks5`]
+#[derive(Clone, PartialEq, Eq)]
+pub struct JlsSocks5Proxy {
+    addr: SocketAddr,
+    credentials: Option<(String, String)>,
+}
+
+impl JlsSocks5Proxy {
+    /// A proxy at `addr` that asks for no authentication
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            credentials: None,
+        }
+    }
+
+    /// A proxy at `addr` that authenticates clients by username and password, per RFC 1929
+    ///
+    /// Each must be between 1 and 255 bytes long.
+    pub fn with_credentials(
+        addr: SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> Result<Self, ConfigError> {
+        if [username, password]
+            .iter()
+            .any(|x| x.is_empty() || x.len() > 255)
+        {
+            return Err(ConfigError::OutOfBounds);
+        }
+        Ok(Self {
+            addr,
+            credentials: Some((username.into(), password.into())),
+        })
+    }
+
+    /// The proxy's address
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The username and password to authenticate with, if any
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        self.credentials
+            .as_ref()
+            .map(|(user, password)| (&user[..], &password[..]))
+    }
+}
+
+impl fmt::Debug for JlsSocks5Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JlsSocks5Proxy")
+            .field("addr", &self.addr)
+            .field("username", &self.credentials.as_ref().map(|x| &x.0))
+            .finish_non_exhaustive()
+    }
+}
+
+/// How the addresses of JLS-forwarded clients appear in logs
+///
+/// See [`EndpointConfig::jls_client_redaction`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JlsClientRedaction {
+    /// Log the whole address, port included
+    Off,
+    /// Log only the network, i.e. the first 24 bits of IPv4 addresses and the first 48 of IPv6
+    /// ones
+    Prefix,
+    /// Log a hash of the IP address, keyed afresh for each endpoint
+    ///
+    /// The same client can be told apart across forward connections of one endpoint, but its
+    /// address can't be recovered, even by trying every IPv4 address.
+    Hashed,
+}
+
 #[cfg(feature = "ring")]
-impl Default for EndpointConfig {
-    fn default() -> Self {
-        let mut reset_key = [0; 64];
-        rand::thread_rng().fill_bytes(&mut reset_key);
+impl EndpointConfig {
+    /// Create a default config whose reset key was exported from another endpoint
+    ///
+    /// Peers of the other endpoint's connections accept stateless resets from this one.
+    pub fn with_imported_keys(keys: &SealedKeys) -> Self {
+        Self::with_reset_key_seed(keys.reset_key.clone())
+    }
 
-        Self::new(Arc::new(ring::hmac::Key::new(
+    /// Key material a replacement of this endpoint needs to carry on where it left off
+    ///
+    /// Includes the handshake token key of `server_config`, if any. Returns `None` if a key was
+    /// supplied through [`reset_key()`](Self::reset_key) or
+    /// [`ServerConfig::token_key()`], since only keys generated by quinn can be exported.
+    pub fn export_keys(&self, server_config: Option<&ServerConfig>) -> Option<SealedKeys> {
+        let token_key = match server_config {
+            Some(x) => Some(x.token_key_seed.clone()?),
+            None => None,
+        };
+        Some(SealedKeys {
+            reset_key: self.reset_key_seed.clone()?,
+            token_key,
+        })
+    }
+
+    fn with_reset_key_seed(seed: Arc<KeySeed>) -> Self {
+        let mut config = Self::new(Arc::new(ring::hmac::Key::new(
             ring::hmac::HMAC_SHA256,
-            &reset_key,
-        )))
+            &seed[..],
+        )));
+        config.reset_key_seed = Some(seed);
+        config
     }
 }
 
+#[cfg(feature = "ring")]
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self::with_reset_key_seed(Arc::new(random_key_seed()))
+    }
+}
+
+type TransportSelector = Arc<dyn Fn(SocketAddr) -> Arc<TransportConfig> + Send + Sync>;
+
 /// Parameters governing incoming connections
 ///
 /// Default values should be suitable for most internet applications.
@@ -605,6 +1378,9 @@ pub struct ServerConfig {
     /// Transport configuration to use for incoming connections
     pub transport: Arc<TransportConfig>,
 
+    /// Chooses the transport configuration for each incoming connection by client address
+    pub(crate) transport_selector: Option<TransportSelector>,
+
     /// TLS configuration used for incoming connections.
     ///
     /// Must be set to use TLS 1.3 only.
@@ -615,6 +1391,8 @@ pub struct ServerConfig {
 
     /// Used to generate one-time AEAD keys to protect handshake tokens
     pub(crate) token_key: Arc<dyn HandshakeTokenKey>,
+    /// What `token_key` was derived from, if known
+    token_key_seed: Option<Arc<KeySeed>>,
 
     /// Whether to require clients to prove ownership of an address before committing resources.
     ///
@@ -625,12 +1403,29 @@ pub struct ServerConfig {
 
     /// Maximum number of concurrent connections
     pub(crate) concurrent_connections: u32,
+    /// Maximum number of incoming connections concurrently performing their handshake
+    pub(crate) max_concurrent_handshakes: u32,
+    /// Whether to answer Initials in excess of `max_concurrent_handshakes` with a Retry
+    pub(crate) retry_excess_handshakes: bool,
+    /// Whether the application decides on each connection attempt before it's handshaken
+    pub(crate) defer_incoming: bool,
 
     /// Whether to allow clients to migrate to new addresses
     ///
     /// Improves behavior for clients that move between different internet connections or suffer NAT
     /// rebinding. Enabled by default.
     pub(crate) migration: bool,
+
+    /// Overrides the transport config's initial MTU
+    pub(crate) initial_mtu: Option<u16>,
+
+    /// How long the endpoint remembers session tickets used for 0-RTT, if at all
+    pub(crate) anti_replay_window: Option<Duration>,
+    /// Maximum number of session tickets remembered for anti-replay
+    pub(crate) anti_replay_capacity: usize,
+
+    /// Transport parameters exchanged on behalf of the application
+    pub(crate) custom_params: CustomParameters,
 }
 
 impl ServerConfig {
@@ -641,15 +1436,25 @@ impl ServerConfig {
     ) -> Self {
         Self {
             transport: Arc::new(TransportConfig::default()),
+            transport_selector: None,
             crypto,
 
             token_key,
+            token_key_seed: None,
             use_retry: false,
             retry_token_lifetime: Duration::from_secs(15),
 
             concurrent_connections: 100_000,
+            max_concurrent_handshakes: u32::MAX,
+            retry_excess_handshakes: true,
+            defer_incoming: false,
 
             migration: true,
+            initial_mtu: None,
+
+            anti_replay_window: None,
+            anti_replay_capacity: 100_000,
+            custom_params: CustomParameters::default(),
             jls_config: JlsServerConfig::default().into(),
         }
     }
@@ -660,9 +1465,31 @@ impl ServerConfig {
         self
     }
 
+    /// Choose the transport configuration of each incoming connection by the client's address
+    ///
+    /// Consulted once per connection, before the server's transport parameters are sent, in place
+    /// of [`transport_config`](Self::transport_config). Useful to e.g. give clients on the local
+    /// network larger flow control windows than those on the internet.
+    pub fn transport_selector(
+        &mut self,
+        selector: Arc<dyn Fn(SocketAddr) -> Arc<TransportConfig> + Send + Sync>,
+    ) -> &mut Self {
+        self.transport_selector = Some(selector);
+        self
+    }
+
+    /// The transport configuration to use for a connection from `remote`
+    pub(crate) fn transport_for(&self, remote: SocketAddr) -> Arc<TransportConfig> {
+        match self.transport_selector {
+            Some(ref selector) => selector(remote),
+            None => self.transport.clone(),
+        }
+    }
+
     /// Private key used to authenticate data included in handshake tokens.
     pub fn token_key(&mut self, value: Arc<dyn HandshakeTokenKey>) -> &mut Self {
         self.token_key = value;
+        self.token_key_seed = None;
         self
     }
 
@@ -689,6 +1516,42 @@ impl ServerConfig {
         self
     }
 
+    /// Maximum number of incoming connections that may be handshaking at once
+    ///
+    /// Handshakes are the most expensive work an unauthenticated peer can request. Once this many
+    /// incoming connections are handshaking, further connection attempts are answered with a
+    /// Retry or refused, depending on
+    /// [`retry_excess_handshakes`](Self::retry_excess_handshakes), until a handshake completes or
+    /// fails. Established connections do not count towards this limit. Unlimited by default.
+    pub fn max_concurrent_handshakes(&mut self, value: u32) -> &mut Self {
+        self.max_concurrent_handshakes = value;
+        self
+    }
+
+    /// Whether to answer connection attempts in excess of
+    /// [`max_concurrent_handshakes`](Self::max_concurrent_handshakes) with a Retry
+    ///
+    /// A Retry costs no handshake state and lets clients try again one round trip later. A client
+    /// that still finds no free slot after its Retry is refused, as are all excess attempts when
+    /// this is disabled. Enabled by default.
+    pub fn retry_excess_handshakes(&mut self, value: bool) -> &mut Self {
+        self.retry_excess_handshakes = value;
+        self
+    }
+
+    /// Whether to hand each connection attempt to the application before any TLS work is done
+    ///
+    /// When enabled, [`Endpoint::handle`](crate::Endpoint::handle) yields a
+    /// [`DatagramEvent::Incoming`](crate::DatagramEvent::Incoming) for each new connection
+    /// attempt that passes the endpoint's own limits and address validation, instead of starting
+    /// the connection. The application then accepts, refuses, retries or ignores it, e.g. after
+    /// consulting an allowlist or a per-source rate limit. JLS authentication is part of the
+    /// handshake, so it has not happened yet either. Disabled by default.
+    pub fn defer_incoming(&mut self, value: bool) -> &mut Self {
+        self.defer_incoming = value;
+        self
+    }
+
     /// Whether to allow clients to migrate to new addresses
     ///
     /// Improves behavior for clients that move between different internet connections or suffer NAT
@@ -697,6 +1560,65 @@ impl ServerConfig {
         self.migration = value;
         self
     }
+
+    /// Start incoming connections from this UDP payload size instead of the transport config's
+    /// [`initial_mtu`](TransportConfig::initial_mtu)
+    ///
+    /// The server's counterpart of [`ClientConfig::initial_mtu`], taking effect before the
+    /// server's first datagram, which a connection's
+    /// [`set_max_udp_payload_size()`](crate::Connection::set_max_udp_payload_size) can't. The value
+    /// is clamped to at least the transport config's [`min_mtu`](TransportConfig::min_mtu).
+    pub fn initial_mtu(&mut self, value: u16) -> &mut Self {
+        self.initial_mtu = Some(value);
+        self
+    }
+
+    /// How long to remember the session tickets clients send 0-RTT data with, if at all
+    ///
+    /// When set, the endpoint rejects early data sent with a ticket it has already seen within
+    /// this window, so replayed 0-RTT packets are never delivered to the application. The
+    /// rejection happens in the TLS handshake, which otherwise proceeds, so the client sees its
+    /// 0-RTT rejected as usual. For complete protection the window should cover the lifetime of
+    /// the server's tickets.
+    /// Disabled by default.
+    pub fn anti_replay_window(&mut self, value: Option<Duration>) -> &mut Self {
+        self.anti_replay_window = value;
+        self
+    }
+
+    /// Maximum number of session tickets remembered for anti-replay
+    ///
+    /// Once this many tickets have been seen within the anti-replay window, further 0-RTT attempts
+    /// are refused until older entries expire. Defaults to 100,000.
+    pub fn anti_replay_capacity(&mut self, value: usize) -> &mut Self {
+        self.anti_replay_capacity = value;
+        self
+    }
+
+    /// Send a transport parameter that RFC 9000 doesn't define to every peer
+    ///
+    /// `id` must not be one used by RFC 9000 or an extension this crate implements, nor one
+    /// reserved for GREASE. The peer's value for the same `id`, if any, is kept for
+    /// [`Connection::peer_custom_transport_parameters()`](crate::Connection::peer_custom_transport_parameters).
+    /// Replaces any value set earlier for `id`.
+    pub fn transport_parameter(
+        &mut self,
+        id: u64,
+        value: impl Into<Bytes>,
+    ) -> Result<&mut Self, ConfigError> {
+        self.custom_params
+            .send(VarInt::from_u64(id)?, value.into())?;
+        Ok(self)
+    }
+
+    /// Keep the peer's value of a transport parameter that RFC 9000 doesn't define
+    ///
+    /// As for [`transport_parameter()`](Self::transport_parameter), but without sending one.
+    /// Parameters that weren't asked for are ignored, as the specification requires.
+    pub fn record_transport_parameter(&mut self, id: u64) -> Result<&mut Self, ConfigError> {
+        self.custom_params.record(VarInt::from_u64(id)?)?;
+        Ok(self)
+    }
 }
 
 #[cfg(feature = "rustls")]
@@ -711,6 +1633,15 @@ impl ServerConfig {
         let crypto = crypto::rustls::server_config(cert_chain, key)?;
         Ok(Self::with_crypto(Arc::new(crypto)))
     }
+
+    /// Create a server config that asks `resolver` for the certificate chain of each handshake
+    ///
+    /// Lets certificates be rotated without replacing the server config: new handshakes use
+    /// whatever `resolver` returns at the time, while established connections are unaffected.
+    pub fn with_cert_resolver(resolver: Arc<dyn rustls::server::ResolvesServerCert>) -> Self {
+        let crypto = crypto::rustls::server_config_with_resolver(resolver);
+        Self::with_crypto(Arc::new(crypto))
+    }
 }
 
 #[cfg(feature = "ring")]
@@ -719,12 +1650,25 @@ impl ServerConfig {
     ///
     /// Uses a randomized handshake token key.
     pub fn with_crypto(crypto: Arc<dyn crypto::ServerConfig>) -> Self {
-        let rng = &mut rand::thread_rng();
-        let mut master_key = [0u8; 64];
-        rng.fill_bytes(&mut master_key);
-        let master_key = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, &[]).extract(&master_key);
+        Self::with_token_key_seed(crypto, Arc::new(random_key_seed()))
+    }
+
+    /// Create a server config whose handshake token key was exported from another endpoint
+    ///
+    /// Tokens issued by the other endpoint, e.g. in a Retry, are valid for this one. Returns
+    /// `None` if `keys` were exported without a server configuration.
+    pub fn with_imported_keys(
+        crypto: Arc<dyn crypto::ServerConfig>,
+        keys: &SealedKeys,
+    ) -> Option<Self> {
+        Some(Self::with_token_key_seed(crypto, keys.token_key.clone()?))
+    }
 
-        Self::new(crypto, Arc::new(master_key))
+    fn with_token_key_seed(crypto: Arc<dyn crypto::ServerConfig>, seed: Arc<KeySeed>) -> Self {
+        let master_key = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, &[]).extract(&seed[..]);
+        let mut config = Self::new(crypto, Arc::new(master_key));
+        config.token_key_seed = Some(seed);
+        config
     }
 }
 
@@ -732,12 +1676,23 @@ impl fmt::Debug for ServerConfig {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt.debug_struct("ServerConfig<T>")
             .field("transport", &self.transport)
+            .field(
+                "transport_selector",
+                &self.transport_selector.as_ref().map(|_| "[ elided ]"),
+            )
             .field("crypto", &"ServerConfig { elided }")
             .field("token_key", &"[ elided ]")
             .field("use_retry", &self.use_retry)
             .field("retry_token_lifetime", &self.retry_token_lifetime)
             .field("concurrent_connections", &self.concurrent_connections)
+            .field("max_concurrent_handshakes", &self.max_concurrent_handshakes)
+            .field("retry_excess_handshakes", &self.retry_excess_handshakes)
+            .field("defer_incoming", &self.defer_incoming)
             .field("migration", &self.migration)
+            .field("initial_mtu", &self.initial_mtu)
+            .field("anti_replay_window", &self.anti_replay_window)
+            .field("anti_replay_capacity", &self.anti_replay_capacity)
+            .field("custom_params", &self.custom_params)
             .finish()
     }
 }
@@ -756,6 +1711,31 @@ pub struct ClientConfig {
 
     /// QUIC protocol version to use
     pub(crate) version: u32,
+
+    /// Application policy the server must satisfy once the handshake completes
+    pub(crate) post_handshake_verifier: Option<Arc<PostHandshakeVerifier>>,
+
+    /// Whether servers that don't authenticate themselves with JLS are refused
+    pub(crate) require_jls: bool,
+
+    /// Overrides the transport config's initial MTU
+    pub(crate) initial_mtu: Option<u16>,
+
+    /// Ceiling on the connection's UDP payload size, including MTU probes
+    pub(crate) max_udp_payload_size: Option<u16>,
+
+    /// Overrides the transport config's initial RTT
+    pub(crate) initial_rtt: Option<Duration>,
+
+    /// Congestion window remembered from an earlier connection
+    pub(crate) initial_window: Option<u64>,
+
+    /// Overrides the transport config's congestion controller factory
+    pub(crate) congestion_controller_factory:
+        Option<Arc<dyn congestion::ControllerFactory + Send + Sync>>,
+
+    /// Transport parameters exchanged on behalf of the application
+    pub(crate) custom_params: CustomParameters,
 }
 
 impl ClientConfig {
@@ -765,6 +1745,14 @@ impl ClientConfig {
             transport: Default::default(),
             crypto,
             version: 1,
+            post_handshake_verifier: None,
+            require_jls: false,
+            initial_mtu: None,
+            max_udp_payload_size: None,
+            initial_rtt: None,
+            initial_window: None,
+            congestion_controller_factory: None,
+            custom_params: CustomParameters::default(),
         }
     }
 
@@ -779,6 +1767,192 @@ impl ClientConfig {
         self.version = version;
         self
     }
+
+    /// Check the server against an application policy once the handshake completes
+    ///
+    /// `verifier` runs after the server's certificate has been validated by the cryptographic
+    /// config, but before the connection is reported as established, e.g. to pin certificates
+    /// against a set that changes at runtime. If it returns an [`Abort`], the connection is closed
+    /// with the corresponding TLS alert instead, and fails with
+    /// [`ConnectionError::TransportError`](crate::ConnectionError::TransportError).
+    pub fn post_handshake_verifier(&mut self, verifier: Arc<PostHandshakeVerifier>) -> &mut Self {
+        self.post_handshake_verifier = Some(verifier);
+        self
+    }
+
+    /// Refuse servers that don't authenticate themselves with JLS
+    ///
+    /// Connections to them fail with
+    /// [`ConnectionError::JlsRejected`](crate::ConnectionError::JlsRejected) rather than with
+    /// whatever went wrong in the handshake, so that a client whose JLS password is wrong, and
+    /// which the server therefore forwarded to its upstream, can tell so. Set by
+    /// [`with_jls()`](Self::with_jls).
+    pub fn require_jls(&mut self, value: bool) -> &mut Self {
+        self.require_jls = value;
+        self
+    }
+
+    /// Start connections from this UDP payload size instead of the transport config's
+    /// [`initial_mtu`](TransportConfig::initial_mtu)
+    ///
+    /// Unlike the transport config, which may be shared by many connections, this only affects
+    /// connections made with this `ClientConfig`, e.g. to start high on a path known to be clean.
+    /// The value is clamped between the transport config's
+    /// [`min_mtu`](TransportConfig::min_mtu) and any [`max_udp_payload_size`](Self::max_udp_payload_size).
+    pub fn initial_mtu(&mut self, value: u16) -> &mut Self {
+        self.initial_mtu = Some(value);
+        self
+    }
+
+    /// Never send UDP payloads larger than `value` on connections made with this `ClientConfig`
+    ///
+    /// Lowers the ceiling of MTU discovery below the
+    /// [`MtuDiscoveryConfig::upper_bound`] for these connections only, e.g. for peers known to be
+    /// behind a tunnel. It can be lowered further while connected with
+    /// [`Connection::set_max_udp_payload_size()`](crate::Connection::set_max_udp_payload_size).
+    pub fn max_udp_payload_size(&mut self, value: u16) -> &mut Self {
+        self.max_udp_payload_size = Some(value);
+        self
+    }
+
+    /// Start connections from this RTT estimate instead of the transport config's
+    /// [`initial_rtt`](TransportConfig::initial_rtt)
+    ///
+    /// Meant for peers talked to recently, with the [`PathInfo::rtt`](crate::PathInfo::rtt) of an
+    /// earlier connection. It's replaced by the first RTT sample, but paces the first flight and
+    /// times its retransmission until then. The value is clamped between 1ms and 2s.
+    pub fn initial_rtt(&mut self, value: Duration) -> &mut Self {
+        self.initial_rtt = Some(value);
+        self
+    }
+
+    /// Skip part of slow start on connections made with this `ClientConfig`
+    ///
+    /// Meant for peers talked to recently, with the [`PathInfo::cwnd`](crate::PathInfo::cwnd) of
+    /// an earlier connection, usually along with its [`initial_rtt`](Self::initial_rtt). As in
+    /// careful resumption, connections start from half of `value`, sent paced over a round trip,
+    /// and back off as usual if that turns out to be too much. The result is never below the
+    /// congestion controller's initial window, nor above 16 MiB.
+    pub fn initial_window(&mut self, value: u64) -> &mut Self {
+        self.initial_window = Some(value);
+        self
+    }
+
+    /// Control congestion on connections made with this `ClientConfig` with controllers from
+    /// `factory`, instead of the transport config's
+    /// [`congestion_controller_factory`](TransportConfig::congestion_controller_factory)
+    ///
+    /// The config is cheap to clone, so the controller can be picked per destination at connect
+    /// time while sharing the transport config, e.g. BBR for long-haul peers.
+    pub fn congestion_controller_factory(
+        &mut self,
+        factory: Arc<dyn congestion::ControllerFactory + Send + Sync>,
+    ) -> &mut Self {
+        self.congestion_controller_factory = Some(factory);
+        self
+    }
+
+    /// Send a transport parameter that RFC 9000 doesn't define to every peer
+    ///
+    /// `id` must not be one used by RFC 9000 or an extension this crate implements, nor one
+    /// reserved for GREASE. The peer's value for the same `id`, if any, is kept for
+    /// [`Connection::peer_custom_transport_parameters()`](crate::Connection::peer_custom_transport_parameters).
+    /// Replaces any value set earlier for `id`.
+    pub fn transport_parameter(
+        &mut self,
+        id: u64,
+        value: impl Into<Bytes>,
+    ) -> Result<&mut Self, ConfigError> {
+        self.custom_params
+            .send(VarInt::from_u64(id)?, value.into())?;
+        Ok(self)
+    }
+
+    /// Keep the peer's value of a transport parameter that RFC 9000 doesn't define
+    ///
+    /// As for [`transport_parameter()`](Self::transport_parameter), but without sending one.
+    /// Parameters that weren't asked for are ignored, as the specification requires.
+    pub fn record_transport_parameter(&mut self, id: u64) -> Result<&mut Self, ConfigError> {
+        self.custom_params.record(VarInt::from_u64(id)?)?;
+        Ok(self)
+    }
+}
+
+/// Application policy checked by clients once the handshake completes
+///
+/// See [`ClientConfig::post_handshake_verifier()`].
+pub type PostHandshakeVerifier = dyn Fn(HandshakeInfo) -> Result<(), Abort> + Send + Sync;
+
+/// What a client learned about the server during the handshake
+#[non_exhaustive]
+pub struct HandshakeInfo {
+    /// The name the server was asked to authenticate as
+    pub server_name: String,
+    /// The server's validated identity, as from `crypto::Session::peer_identity`
+    ///
+    /// With rustls, this is a `Vec<rustls::Certificate>`; see
+    /// [`peer_certificates()`](Self::peer_certificates).
+    pub peer_identity: Option<Box<dyn Any>>,
+    /// Parameters negotiated during the handshake, as from `crypto::Session::handshake_data`
+    ///
+    /// With rustls, this is a [`crypto::rustls::HandshakeData`]; see
+    /// [`alpn_protocol()`](Self::alpn_protocol).
+    pub handshake_data: Option<Box<dyn Any>>,
+    /// Whether the server authenticated itself with JLS, if the session uses JLS
+    pub jls: Option<bool>,
+}
+
+#[cfg(feature = "rustls")]
+impl HandshakeInfo {
+    /// The server's certificate chain, starting with its end-entity certificate
+    pub fn peer_certificates(&self) -> Option<&[rustls::Certificate]> {
+        self.peer_identity
+            .as_ref()?
+            .downcast_ref::<Vec<rustls::Certificate>>()
+            .map(|x| &x[..])
+    }
+
+    /// The application protocol negotiated with ALPN, if any
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.handshake_data
+            .as_ref()?
+            .downcast_ref::<crypto::rustls::HandshakeData>()?
+            .protocol
+            .as_deref()
+    }
+}
+
+impl fmt::Debug for HandshakeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandshakeInfo")
+            .field("server_name", &self.server_name)
+            .field("jls", &self.jls)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Refusal of a server by a [`PostHandshakeVerifier`]
+#[derive(Debug, Clone)]
+pub struct Abort {
+    /// TLS alert description sent to the server
+    pub alert: u8,
+    /// Reason for the refusal, sent to the server
+    pub reason: String,
+}
+
+impl Abort {
+    /// Refuse the server with the `bad_certificate` alert
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self::with_alert(42, reason)
+    }
+
+    /// Refuse the server with a particular TLS alert
+    pub fn with_alert(alert: u8, reason: impl Into<String>) -> Self {
+        Self {
+            alert,
+            reason: reason.into(),
+        }
+    }
 }
 
 #[cfg(feature = "rustls")]
@@ -807,6 +1981,24 @@ impl ClientConfig {
     pub fn with_root_certificates(roots: rustls::RootCertStore) -> Self {
         Self::new(Arc::new(crypto::rustls::client_config(roots)))
     }
+
+    /// Create a client configuration that authenticates servers with JLS
+    ///
+    /// `password` and `iv` must match those of the server. The server name given when connecting
+    /// is the camouflage one: it's what the ClientHello shows to observers, and what decides the
+    /// upstream a server that doesn't recognize the client forwards it to. As servers that
+    /// authenticate with JLS need no certificate the client trusts, servers that don't are
+    /// [refused](Self::require_jls).
+    pub fn with_jls(password: &str, iv: &str) -> Result<Self, ConfigError> {
+        if password.is_empty() || iv.is_empty() {
+            return Err(ConfigError::EmptyJlsCredential);
+        }
+        let mut crypto = crypto::rustls::client_config(rustls::RootCertStore::empty());
+        crypto.jls_config = rustls::JlsConfig::new(password, iv);
+        let mut config = Self::new(Arc::new(crypto));
+        config.require_jls(true);
+        Ok(config)
+    }
 }
 
 impl fmt::Debug for ClientConfig {
@@ -815,10 +2007,117 @@ impl fmt::Debug for ClientConfig {
             .field("transport", &self.transport)
             .field("crypto", &"ClientConfig { elided }")
             .field("version", &self.version)
+            .field(
+                "post_handshake_verifier",
+                &self.post_handshake_verifier.as_ref().map(|_| ".."),
+            )
+            .field("require_jls", &self.require_jls)
+            .field("initial_mtu", &self.initial_mtu)
+            .field("max_udp_payload_size", &self.max_udp_payload_size)
+            .field("initial_rtt", &self.initial_rtt)
+            .field("initial_window", &self.initial_window)
+            .field(
+                "congestion_controller_factory",
+                &self.congestion_controller_factory.as_ref().map(|_| ".."),
+            )
+            .field("custom_params", &self.custom_params)
+            .finish()
+    }
+}
+
+/// Secret material from which an endpoint's keys are derived
+type KeySeed = Zeroizing<[u8; KEY_SEED_LEN]>;
+
+const KEY_SEED_LEN: usize = 64;
+
+#[cfg(feature = "ring")]
+fn random_key_seed() -> KeySeed {
+    let mut seed = Zeroizing::new([0; KEY_SEED_LEN]);
+    rand::thread_rng().fill_bytes(&mut seed[..]);
+    seed
+}
+
+/// Keys that let a new process take over from an endpoint without disrupting its clients
+///
+/// Obtained from [`EndpointConfig::export_keys()`], and passed to
+/// [`EndpointConfig::with_imported_keys()`] and [`ServerConfig::with_imported_keys()`], e.g. in
+/// a process that binds the same address with `SO_REUSEPORT` and replaces the exporting one.
+/// Covers the stateless reset key, so that clients of the old process accept resets for
+/// connections the new one doesn't know, and the handshake token key, so that tokens issued by
+/// either process are accepted by the other.
+///
+/// TLS session tickets aren't covered: rustls doesn't expose the keys of its ticketers. For
+/// resumption to survive a restart, give both processes a [`rustls::server::ProducesTickets`]
+/// sharing the same keys.
+///
+/// # Threat model
+///
+/// Whoever holds these keys can forge handshake tokens, skipping address validation, and reset
+/// any connection of an endpoint using them, given its connection IDs. They are as sensitive as
+/// the endpoint's private key, and should only ever travel over a channel that just the old and
+/// new process can access, such as a Unix socket or inherited pipe, never through files,
+/// environment variables or logs. Copies held by this type and by [`to_bytes()`](Self::to_bytes)
+/// are zeroed when dropped, but nothing guards against an attacker able to read either process'
+/// memory.
+#[derive(Clone)]
+pub struct SealedKeys {
+    reset_key: Arc<KeySeed>,
+    token_key: Option<Arc<KeySeed>>,
+}
+
+impl SealedKeys {
+    /// Encode the keys to be sent to another process
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut buf = Zeroizing::new(Vec::with_capacity(2 + 2 * KEY_SEED_LEN));
+        buf.push(SEALED_KEYS_VERSION);
+        buf.push(self.token_key.is_some() as u8);
+        buf.extend_from_slice(&self.reset_key[..]);
+        if let Some(ref token_key) = self.token_key {
+            buf.extend_from_slice(&token_key[..]);
+        }
+        buf
+    }
+
+    /// Decode keys encoded by [`to_bytes()`](Self::to_bytes)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, InvalidSealedKeys> {
+        let (flags, seeds) = match bytes {
+            [SEALED_KEYS_VERSION, flags, seeds @ ..] => (*flags, seeds),
+            _ => return Err(InvalidSealedKeys),
+        };
+        let seed = |i: usize| {
+            let mut seed = Zeroizing::new([0; KEY_SEED_LEN]);
+            seed.copy_from_slice(&seeds[i * KEY_SEED_LEN..(i + 1) * KEY_SEED_LEN]);
+            Arc::new(seed)
+        };
+        let token_key = match (flags, seeds.len()) {
+            (0, n) if n == KEY_SEED_LEN => None,
+            (1, n) if n == 2 * KEY_SEED_LEN => Some(seed(1)),
+            _ => return Err(InvalidSealedKeys),
+        };
+        Ok(Self {
+            reset_key: seed(0),
+            token_key,
+        })
+    }
+}
+
+impl fmt::Debug for SealedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SealedKeys")
+            .field("reset_key", &"[ elided ]")
+            .field("token_key", &self.token_key.as_ref().map(|_| "[ elided ]"))
             .finish()
     }
 }
 
+/// Version of the encoding produced by [`SealedKeys::to_bytes()`]
+const SEALED_KEYS_VERSION: u8 = 1;
+
+/// Bytes that aren't [`SealedKeys`] encoded by this version of quinn
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("invalid sealed keys")]
+pub struct InvalidSealedKeys;
+
 /// Errors in the configuration of an endpoint
 #[derive(Debug, Error, Clone, PartialEq, Eq)]
 #[non_exhaustive]
@@ -826,6 +2125,12 @@ pub enum ConfigError {
     /// Value exceeds supported bounds
     #[error("value exceeds supported bounds")]
     OutOfBounds,
+    /// Transport parameter id is taken by the protocol or reserved for GREASE
+    #[error("reserved transport parameter id")]
+    ReservedTransportParameter,
+    /// JLS password or IV is empty
+    #[error("empty JLS password or IV")]
+    EmptyJlsCredential,
 }
 
 impl From<TryFromIntError> for ConfigError {
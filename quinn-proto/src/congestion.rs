@@ -64,6 +64,12 @@ pub trait Controller: Send {
     /// Number of ack-eliciting bytes that may be in flight
     fn window(&self) -> u64;
 
+    /// Start from a congestion window learned by an earlier connection over the same path
+    ///
+    /// Called before anything is sent. Controllers that can't make use of it may ignore it.
+    #[allow(unused_variables)]
+    fn resume(&mut self, window: u64) {}
+
     /// Duplicate the controller's state
     fn clone_box(&self) -> Box<dyn Controller>;
 
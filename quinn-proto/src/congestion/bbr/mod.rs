@@ -485,6 +485,11 @@ impl Controller for Bbr {
         self.cwnd
     }
 
+    fn resume(&mut self, window: u64) {
+        self.init_cwnd = window.max(self.min_cwnd);
+        self.cwnd = self.init_cwnd;
+    }
+
     fn clone_box(&self) -> Box<dyn Controller> {
         Box::new(self.clone())
     }
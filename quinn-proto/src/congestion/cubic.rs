@@ -222,6 +222,10 @@ impl Controller for Cubic {
         self.window
     }
 
+    fn resume(&mut self, window: u64) {
+        self.window = window.max(self.minimum_window());
+    }
+
     fn clone_box(&self) -> Box<dyn Controller> {
         Box::new(self.clone())
     }
@@ -72,7 +72,9 @@ impl<'a> Datagrams<'a> {
 
     /// Receive an unreliable, unordered datagram
     pub fn recv(&mut self) -> Option<Bytes> {
-        self.conn.datagrams.recv()
+        let data = self.conn.datagrams.recv()?;
+        self.conn.stats.application.datagram_bytes_received += data.len() as u64;
+        Some(data)
     }
 
     /// Bytes available in the outgoing datagram buffer
@@ -127,22 +129,24 @@ impl DatagramState {
         Ok(was_empty)
     }
 
-    pub(super) fn write(&mut self, buf: &mut BytesMut, max_size: usize) -> bool {
+    /// Encode the next queued datagram into `buf`, returning the length of its payload
+    pub(super) fn write(&mut self, buf: &mut BytesMut, max_size: usize) -> Option<usize> {
         let datagram = match self.outgoing.pop_front() {
             Some(x) => x,
-            None => return false,
+            None => return None,
         };
 
         if buf.len() + datagram.size(true) > max_size {
             // Future work: we could be more clever about cramming small datagrams into
             // mostly-full packets when a larger one is queued first
             self.outgoing.push_front(datagram);
-            return false;
+            return None;
         }
 
-        self.outgoing_total -= datagram.data.len();
+        let len = datagram.data.len();
+        self.outgoing_total -= len;
         datagram.encode(true, buf);
-        true
+        Some(len)
     }
 
     pub(super) fn recv(&mut self) -> Option<Bytes> {
@@ -4,7 +4,7 @@ use std::{
     convert::TryFrom,
     fmt, io, mem,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
@@ -15,10 +15,11 @@ use thiserror::Error;
 use tracing::{debug, error, trace, trace_span, warn};
 
 use crate::{
+    anti_replay::AntiReplay,
     cid_generator::ConnectionIdGenerator,
     cid_queue::CidQueue,
     coding::BufMutExt,
-    config::{ServerConfig, TransportConfig},
+    config::{HandshakeInfo, PostHandshakeVerifier, ServerConfig, TransportConfig},
     crypto::{self, HeaderKey, KeyPair, Keys, PacketKey},
     frame,
     frame::{Close, Datagram, FrameStruct},
@@ -31,7 +32,8 @@ use crate::{
     token::ResetToken,
     transport_parameters::TransportParameters,
     Dir, EndpointConfig, Frame, Side, StreamId, Transmit, TransportError, TransportErrorCode,
-    VarInt, MAX_STREAM_COUNT, MIN_INITIAL_SIZE, RESET_TOKEN_SIZE, TIMER_GRANULARITY,
+    VarInt, MAX_STREAM_COUNT, MAX_UDP_PAYLOAD, MIN_INITIAL_SIZE, RESET_TOKEN_SIZE,
+    TIMER_GRANULARITY,
 };
 
 mod assembler;
@@ -52,7 +54,7 @@ use packet_builder::PacketBuilder;
 
 mod paths;
 use paths::PathData;
-pub use paths::RttEstimator;
+pub use paths::{MigrationPolicy, PathInfo, RttEstimator};
 
 mod send_buffer;
 
@@ -64,7 +66,10 @@ use spaces::Retransmits;
 use spaces::{PacketSpace, SendableFrames, SentPacket, ThinRetransmits};
 
 mod stats;
-pub use stats::{ConnectionStats, FrameStats, PathStats, UdpStats};
+pub use stats::{
+    ApplicationStats, ConnectionStats, EcnStats, FlowControlStats, FrameStats, HandshakeStats,
+    PathStats, UdpStats,
+};
 
 mod streams;
 #[cfg(fuzzing)]
@@ -78,7 +83,7 @@ pub use streams::{
 };
 
 mod timer;
-use crate::congestion::Controller;
+use crate::congestion::{Controller, ControllerFactory};
 use timer::{Timer, TimerTable};
 
 /// Protocol state and logic for a single QUIC connection
@@ -124,6 +129,12 @@ pub struct Connection {
     endpoint_config: Arc<EndpointConfig>,
     server_config: Option<Arc<ServerConfig>>,
     config: Arc<TransportConfig>,
+    /// How long stream data may be held back to coalesce it with later writes
+    send_coalesce_delay: Option<Duration>,
+    /// When stream data currently held back for coalescing must be sent
+    coalesce_deadline: Option<Instant>,
+    /// The latest time pacing scheduled a transmit for, under `TransportConfig::pacing_horizon`
+    paced_until: Option<Instant>,
     rng: StdRng,
     crypto: Box<dyn crypto::Session>,
     /// The CID we initially chose, for use during the handshake
@@ -133,6 +144,22 @@ pub struct Connection {
     /// The "real" local IP address which was was used to receive the initial packet.
     /// This is only populated for the server case, and if known
     local_ip: Option<IpAddr>,
+    /// Whether `local_ip` should be taken from the next received datagram, after a local address
+    /// change invalidated it
+    relearn_local_ip: bool,
+    migration_policy: MigrationPolicy,
+    /// Client-only policy checked once the handshake completes, and the server name it applies to
+    post_handshake_verifier: Option<(Arc<PostHandshakeVerifier>, String)>,
+    /// Client-only requirement that the server authenticate itself with JLS
+    require_jls: bool,
+    /// Builds the congestion controllers of new paths instead of the transport config's factory
+    congestion_controller_factory: Option<Arc<dyn ControllerFactory + Send + Sync>>,
+    /// MTU new paths start from, the transport config's unless overridden
+    initial_mtu: u16,
+    /// Largest UDP payload size any path may use, as limited locally
+    max_udp_payload_size: u16,
+    /// Ids of the peer's custom transport parameters the application asked for
+    recorded_params: Vec<VarInt>,
     path: PathData,
     prev_path: Option<PathData>,
     state: State,
@@ -189,12 +216,25 @@ pub struct Connection {
     //
     path_response: Option<PathResponse>,
     close: bool,
+    /// Whether the connection is forgotten once its close packet is sent, per
+    /// [`abandon()`](Self::abandon)
+    abandoned: bool,
 
     //
     // Loss Detection
     //
     /// The number of times a PTO has been sent without receiving an ack.
     pto_count: u32,
+    /// Whether a PING requested by `ping_rtt` has yet to be acknowledged
+    rtt_probe_outstanding: bool,
+    /// PTOs expired since anything was last acknowledged
+    ///
+    /// Unlike `pto_count`, also reset by ACKs before the peer has validated our address.
+    consecutive_ptos: u32,
+    /// Path validations failed since anything was last acknowledged
+    failed_validations: u32,
+    /// Connectivity, as judged by `update_health`
+    health: ConnectionHealth,
 
     //
     // Congestion Control
@@ -208,6 +248,8 @@ pub struct Connection {
     /// Whether the last `poll_transmit` call yielded no data because there was
     /// no outgoing application data.
     app_limited: bool,
+    /// Whether the last transmit `poll_transmit` returned carries nothing but ACK frames
+    last_transmit_ack_only: bool,
 
     streams: StreamsState,
     /// Surplus remote CIDs for future use on new paths
@@ -218,6 +260,14 @@ pub struct Connection {
     datagrams: DatagramState,
     /// Connection level statistics
     stats: ConnectionStats,
+    /// The endpoint's application payload totals
+    application_totals: Arc<Mutex<ApplicationStats>>,
+    /// Application payload counts as last added to `application_totals`
+    reported_application: ApplicationStats,
+    /// When the connection was created
+    created: Instant,
+    /// When the first packet was sent (client) or received (server)
+    first_packet: Option<Instant>,
     /// QUIC version used for the connection.
     version: u32,
 }
@@ -226,13 +276,15 @@ impl Connection {
     pub(crate) fn new(
         endpoint_config: Arc<EndpointConfig>,
         server_config: Option<Arc<ServerConfig>>,
+        anti_replay: Option<Arc<Mutex<AntiReplay>>>,
+        application_totals: Arc<Mutex<ApplicationStats>>,
         config: Arc<TransportConfig>,
         init_cid: ConnectionId,
         loc_cid: ConnectionId,
         rem_cid: ConnectionId,
         remote: SocketAddr,
         local_ip: Option<IpAddr>,
-        crypto: Box<dyn crypto::Session>,
+        mut crypto: Box<dyn crypto::Session>,
         cid_gen: &dyn ConnectionIdGenerator,
         now: Instant,
         version: u32,
@@ -252,11 +304,30 @@ impl Connection {
             expected_token: Bytes::new(),
             client_hello: None,
         });
+        if let (Some(anti_replay), Some(config)) = (anti_replay, &server_config) {
+            if let Some(window) = config.anti_replay_window {
+                // Refuse replays in the TLS handshake, so a client whose 0-RTT was refused sees
+                // it rejected
+                let capacity = config.anti_replay_capacity;
+                crypto.filter_early_data(Box::new(move |identity| {
+                    anti_replay
+                        .lock()
+                        .unwrap()
+                        .check(now, identity, window, capacity)
+                }));
+            }
+        }
         let mut rng = StdRng::from_entropy();
         let path_validated = server_config.as_ref().map_or(true, |c| c.use_retry);
+        let recorded_params = server_config
+            .as_ref()
+            .map_or_else(Vec::new, |c| c.custom_params.recorded.clone());
         let mut this = Self {
             endpoint_config,
             server_config,
+            send_coalesce_delay: config.send_coalesce_delay,
+            coalesce_deadline: None,
+            paced_until: None,
             crypto,
             handshake_cid: loc_cid,
             rem_handshake_cid: rem_cid,
@@ -278,6 +349,14 @@ impl Connection {
                 path_validated,
             ),
             local_ip,
+            relearn_local_ip: false,
+            migration_policy: MigrationPolicy::default(),
+            post_handshake_verifier: None,
+            require_jls: false,
+            congestion_controller_factory: None,
+            initial_mtu: config.get_initial_mtu(),
+            max_udp_payload_size: MAX_UDP_PAYLOAD,
+            recorded_params,
             prev_path: None,
             side,
             state,
@@ -307,10 +386,16 @@ impl Connection {
 
             path_response: None,
             close: false,
+            abandoned: false,
 
             pto_count: 0,
+            rtt_probe_outstanding: false,
+            consecutive_ptos: 0,
+            failed_validations: 0,
+            health: ConnectionHealth::Healthy,
 
             app_limited: false,
+            last_transmit_ack_only: false,
             in_flight: InFlight::new(),
             receiving_ecn: false,
             total_authed_packets: 0,
@@ -328,8 +413,22 @@ impl Connection {
             rem_cids: CidQueue::new(rem_cid),
             rng,
             stats: ConnectionStats::default(),
+            application_totals,
+            reported_application: ApplicationStats::default(),
+            created: now,
+            first_packet: None,
             version,
         };
+        if this.config.receive_window_auto_tuning {
+            this.streams.enable_window_tuning(
+                this.config
+                    .max_receive_window
+                    .max(this.config.receive_window),
+                this.config
+                    .max_stream_receive_window
+                    .max(this.config.stream_receive_window),
+            );
+        }
         if side.is_client() {
             // Kick off the connection
             this.write_crypto();
@@ -375,6 +474,14 @@ impl Connection {
     /// Return endpoint-facing events
     #[must_use]
     pub fn poll_endpoint_events(&mut self) -> Option<EndpointEvent> {
+        // Add payload counts to the endpoint's totals before it can learn that the connection is
+        // drained
+        let application = self.application_stats();
+        if application != self.reported_application {
+            let delta = application.since(&self.reported_application);
+            self.reported_application = application;
+            self.application_totals.lock().unwrap().add(&delta);
+        }
         self.endpoint_events.pop_front().map(EndpointEvent)
     }
 
@@ -423,6 +530,7 @@ impl Connection {
     pub fn poll_transmit(&mut self, now: Instant, max_datagrams: usize) -> Option<Transmit> {
         assert!(max_datagrams != 0);
         let max_datagrams = max_datagrams.min(MAX_TRANSMIT_SEGMENTS);
+        self.streams.set_clock(now, self.path.rtt.get());
 
         let mut num_datagrams = 0;
 
@@ -467,12 +575,14 @@ impl Connection {
                 self.stats.udp_tx.datagrams += 1;
                 self.stats.udp_tx.transmits += 1;
                 self.stats.udp_tx.bytes += buf.len() as u64;
+                self.last_transmit_ack_only = false;
                 return Some(Transmit {
                     destination,
                     contents: buf.freeze(),
                     ecn: None,
                     segment_size: None,
                     src_ip: self.local_ip,
+                    send_at: None,
                 });
             }
         }
@@ -492,6 +602,9 @@ impl Connection {
                 // self.close is only reset once the associated packet had been
                 // encoded successfully
                 if !self.close {
+                    if self.abandoned {
+                        self.kill(ConnectionError::LocallyClosed);
+                    }
                     self.app_limited = true;
                     return None;
                 }
@@ -500,6 +613,10 @@ impl Connection {
             _ => false,
         };
 
+        if !close && self.delay_for_coalescing(now) {
+            return None;
+        }
+
         let mut buf = BytesMut::new();
         // Reserving capacity can provide more capacity than we asked for.
         // However we are not allowed to write more than MTU size. Therefore
@@ -511,6 +628,11 @@ impl Connection {
         let mut sent_frames = None;
         let mut pad_datagram = false;
         let mut congestion_blocked = false;
+        let mut ack_only = true;
+        // Transmits mustn't overtake those scheduled earlier, and pacing carries on from the time
+        // of the last one
+        let mut send_at = self.paced_until.filter(|&t| t > now);
+        let mut pacing_now = send_at.unwrap_or(now);
 
         // Iterate over all spaces and find data to send
         let mut space_idx = 0;
@@ -598,13 +720,41 @@ impl Connection {
                         bytes_to_send,
                         self.path.current_mtu(),
                         self.path.congestion.window(),
-                        now,
+                        pacing_now,
                     ) {
-                        self.timers.set(Timer::Pacing, delay);
-                        congestion_blocked = true;
-                        // Loss probes should be subject to pacing, even though
-                        // they are not congestion controlled.
-                        break;
+                        let horizon = self.config.pacing_horizon.map(|x| now + x);
+                        if horizon.map_or(true, |x| delay > x) {
+                            self.timers.set(Timer::Pacing, delay);
+                            congestion_blocked = true;
+                            // Loss probes should be subject to pacing, even though
+                            // they are not congestion controlled.
+                            break;
+                        }
+                        if num_datagrams > 0 {
+                            // Schedule the rest in a transmit of its own; the caller polls again
+                            // straight away, so no timer is needed
+                            congestion_blocked = true;
+                            break;
+                        }
+                        // The pacer has now moved on to `delay`, so later calls mustn't pass it
+                        // an earlier time
+                        self.paced_until = Some(delay);
+                        // Tokens are counted in whole bytes, so the pacer may want a moment longer
+                        // at the time it named
+                        if let Some(later) = self.path.pacing.delay(
+                            smoothed_rtt,
+                            bytes_to_send,
+                            self.path.current_mtu(),
+                            self.path.congestion.window(),
+                            delay,
+                        ) {
+                            self.timers.set(Timer::Pacing, later);
+                            congestion_blocked = true;
+                            break;
+                        }
+                        trace!(?delay, "scheduling paced transmit");
+                        send_at = Some(delay);
+                        pacing_now = delay;
                     }
                 }
 
@@ -736,6 +886,7 @@ impl Connection {
                 }
                 // Don't send another close packet
                 self.close = false;
+                ack_only = false;
                 // `CONNECTION_CLOSE` is the final packet
                 break;
             }
@@ -755,6 +906,7 @@ impl Connection {
                 "SendableFrames was {can_send:?}, but only ACKs have been written"
             );
             pad_datagram |= sent.requires_padding;
+            ack_only &= sent.is_ack_only(&self.streams);
 
             if sent.largest_acked.is_some() {
                 self.spaces[space_id].pending_acks.acks_sent();
@@ -819,6 +971,7 @@ impl Connection {
             self.stats.frame_tx.ping += 1;
             self.stats.path.sent_plpmtud_probes += 1;
             num_datagrams = 1;
+            ack_only = false;
 
             trace!(?probe_size, "writing MTUD probe");
         }
@@ -833,23 +986,76 @@ impl Connection {
         self.stats.udp_tx.datagrams += num_datagrams as u64;
         self.stats.udp_tx.bytes += buf.len() as u64;
         self.stats.udp_tx.transmits += 1;
+        if self.first_packet.is_none() {
+            self.first_packet = Some(now);
+        }
+        self.last_transmit_ack_only = ack_only;
 
         Some(Transmit {
             destination: self.path.remote,
             contents: buf.freeze(),
-            ecn: if self.path.sending_ecn {
-                Some(EcnCodepoint::Ect0)
-            } else {
-                None
+            ecn: match (self.path.sending_ecn, self.config.send_ect1) {
+                (false, _) => None,
+                (true, false) => Some(EcnCodepoint::Ect0),
+                (true, true) => Some(EcnCodepoint::Ect1),
             },
             segment_size: match num_datagrams {
                 1 => None,
                 _ => Some(self.path.current_mtu() as usize),
             },
             src_ip: self.local_ip,
+            send_at,
         })
     }
 
+    /// Whether the datagrams of the transmit last returned by
+    /// [`poll_transmit()`](Self::poll_transmit) carry nothing but ACK frames
+    ///
+    /// A newer transmit from the connection can stand in for such a transmit, so the I/O layer may
+    /// hold it back, or drop it once a newer one comes along, when sending is congested.
+    pub fn last_transmit_ack_only(&self) -> bool {
+        self.last_transmit_ack_only
+    }
+
+    /// Whether to hold back queued stream data in the hope of coalescing it with later writes
+    fn delay_for_coalescing(&mut self, now: Instant) -> bool {
+        let delay = match self.send_coalesce_delay {
+            Some(x) if !self.state.is_handshake() => x,
+            _ => return false,
+        };
+        let other_pending = SpaceId::iter().any(|id| {
+            let space = &self.spaces[id];
+            !space.pending.is_empty(&self.streams)
+                || space.ping_pending
+                || space.loss_probes != 0
+                || space.pending_acks.can_send()
+        }) || self.path.challenge_pending
+            || self
+                .prev_path
+                .as_ref()
+                .map_or(false, |x| x.challenge_pending)
+            || self.path_response.is_some()
+            || !self.datagrams.outgoing.is_empty();
+        let hold = !other_pending
+            && match self.streams.coalescable_bytes() {
+                Some(bytes) => bytes != 0 && bytes < u64::from(self.path.current_mtu()),
+                None => false,
+            };
+        if !hold {
+            self.coalesce_deadline = None;
+            self.timers.stop(Timer::Coalesce);
+            return false;
+        }
+
+        let deadline = *self.coalesce_deadline.get_or_insert(now + delay);
+        if deadline <= now {
+            self.coalesce_deadline = None;
+            return false;
+        }
+        self.timers.set(Timer::Coalesce, deadline);
+        true
+    }
+
     /// Indicate what types of frames are ready to send for the given space
     fn space_can_send(&self, space_id: SpaceId) -> SendableFrames {
         if self.spaces[space_id].crypto.is_some() {
@@ -892,6 +1098,7 @@ impl Connection {
             Datagram {
                 now,
                 remote,
+                local_ip,
                 ecn,
                 first_decode,
                 remaining,
@@ -899,13 +1106,17 @@ impl Connection {
                 // If this packet could initiate a migration and we're a client or a server that
                 // forbids migration, drop the datagram. This could be relaxed to heuristically
                 // permit NAT-rebinding-like migration.
-                if remote != self.path.remote
-                    && self.server_config.as_ref().map_or(true, |x| !x.migration)
-                {
+                if remote != self.path.remote && !self.accepts_migration(remote) {
                     trace!("discarding packet from unrecognized peer {}", remote);
                     return;
                 }
 
+                if self.relearn_local_ip && local_ip.is_some() {
+                    trace!("learned local IP {:?} after local address change", local_ip);
+                    self.local_ip = local_ip;
+                    self.relearn_local_ip = false;
+                }
+
                 let was_anti_amplification_blocked = self.path.anti_amplification_blocked(1);
 
                 self.stats.udp_rx.datagrams += 1;
@@ -958,6 +1169,7 @@ impl Connection {
     /// `Instant` that was output by `poll_timeout`; however spurious extra calls will simply
     /// no-op and therefore are safe.
     pub fn handle_timeout(&mut self, now: Instant) {
+        self.streams.set_clock(now, self.path.rtt.get());
         for &timer in &Timer::VALUES {
             if !self.timers.is_expired(timer, now) {
                 continue;
@@ -990,8 +1202,11 @@ impl Connection {
                     }
                     self.path.challenge = None;
                     self.path.challenge_pending = false;
+                    self.failed_validations = self.failed_validations.saturating_add(1);
+                    self.update_health(now);
                 }
                 Timer::Pacing => trace!("pacing timer expired"),
+                Timer::Coalesce => trace!("coalescing delay expired"),
                 Timer::PushNewCid => {
                     // Update `retire_prior_to` field in NEW_CONNECTION_ID frame
                     let num_new_cid = self.local_cid_state.on_cid_timeout().into();
@@ -1026,6 +1241,23 @@ impl Connection {
         )
     }
 
+    /// Close the connection, and let it go as soon as the close packet is sent
+    ///
+    /// Unlike [`close()`](Self::close), doesn't wait out the closing period for the peer to learn
+    /// of the close, so the endpoint is rid of the connection at once. Meant for handshakes given
+    /// up on, whose peer may never have answered in the first place.
+    pub fn abandon(&mut self, now: Instant, error_code: VarInt, reason: Bytes) {
+        if self.state.is_drained() {
+            return;
+        }
+        self.close(now, error_code, reason);
+        self.abandoned = true;
+        if self.state.is_closed() && !self.close {
+            // Already closed, and the close packet already sent
+            self.kill(ConnectionError::LocallyClosed);
+        }
+    }
+
     fn close_inner(&mut self, now: Instant, reason: Close) {
         let was_closed = self.state.is_closed();
         if !was_closed {
@@ -1041,15 +1273,64 @@ impl Connection {
         Datagrams { conn: self }
     }
 
+    /// Whether the path to the peer seems to be working
+    ///
+    /// Only changes while handling timeouts and incoming packets.
+    pub fn health(&self) -> ConnectionHealth {
+        self.health
+    }
+
+    /// Derive the connection's health from recent losses
+    fn update_health(&mut self, now: Instant) {
+        let ptos = self.consecutive_ptos;
+        let health = if ptos >= self.config.broken_after_ptos {
+            ConnectionHealth::Broken
+        } else if ptos >= self.config.degraded_after_ptos || self.failed_validations > 0 {
+            ConnectionHealth::Degraded {
+                since: match self.health {
+                    ConnectionHealth::Degraded { since, .. } => since,
+                    _ => now,
+                },
+                consecutive_ptos: ptos,
+            }
+        } else {
+            ConnectionHealth::Healthy
+        };
+        if health != self.health {
+            debug!(?health, "health changed");
+            self.health = health;
+        }
+    }
+
     /// Returns connection statistics
     pub fn stats(&self) -> ConnectionStats {
         let mut stats = self.stats;
         stats.path.rtt = self.path.rtt.get();
         stats.path.cwnd = self.path.congestion.window();
+        stats.path.pto =
+            self.pto(SpaceId::Data) * 2u32.pow(self.pto_count.min(MAX_BACKOFF_EXPONENT));
+        let (receive_window, stream_receive_window) = self.streams.receive_windows();
+        stats.flow_control.receive_window = receive_window;
+        stats.flow_control.stream_receive_window = stream_receive_window;
+        stats.application = self.application_stats();
+        for space in &self.spaces {
+            stats.ecn.received_ect0 += space.ecn_counters.ect0;
+            stats.ecn.received_ect1 += space.ecn_counters.ect1;
+            stats.ecn.received_ce += space.ecn_counters.ce;
+            stats.ecn.peer_ect0 += space.ecn_feedback.ect0;
+            stats.ecn.peer_ect1 += space.ecn_feedback.ect1;
+            stats.ecn.peer_ce += space.ecn_feedback.ce;
+        }
 
         stats
     }
 
+    fn application_stats(&self) -> ApplicationStats {
+        let mut stats = self.stats.application;
+        (stats.stream_bytes_sent, stats.stream_bytes_received) = self.streams.payload_bytes();
+        stats
+    }
+
     /// Ping the remote endpoint
     ///
     /// Causes an ACK-eliciting packet to be transmitted.
@@ -1057,6 +1338,21 @@ impl Connection {
         self.spaces[self.highest_space].ping_pending = true;
     }
 
+    /// Ping the remote endpoint to measure the round trip time
+    ///
+    /// Causes a PING frame to be transmitted in an application data packet. Once that packet is
+    /// acknowledged, [`Event::PingAcknowledged`] is emitted with the time it took, less the delay
+    /// the peer reports having added before acknowledging. The PING is retransmitted if lost.
+    ///
+    /// Calling this again before the event is emitted has no effect, so that concurrent callers
+    /// share a single sample.
+    pub fn ping_rtt(&mut self) {
+        if mem::replace(&mut self.rtt_probe_outstanding, true) {
+            return;
+        }
+        self.spaces[SpaceId::Data].pending.rtt_probe = true;
+    }
+
     #[doc(hidden)]
     pub fn initiate_key_update(&mut self) {
         self.update_keys(None, false);
@@ -1094,9 +1390,13 @@ impl Connection {
         self.state.is_drained()
     }
 
-    /// For clients, if the peer accepted the 0-RTT data packets
+    /// Whether 0-RTT data packets were accepted
     ///
-    /// The value is meaningless until after the handshake completes.
+    /// For clients, whether the peer accepted them; the value is meaningless until after the
+    /// handshake completes. For servers, whether the client's 0-RTT packets are being processed,
+    /// which is never the case for replays refused by the
+    /// [anti-replay cache](crate::ServerConfig::anti_replay_window), since those are rejected in
+    /// the TLS handshake.
     pub fn accepted_0rtt(&self) -> bool {
         self.accepted_0rtt
     }
@@ -1127,7 +1427,8 @@ impl Connection {
     /// This can be different from the address the endpoint is bound to, in case
     /// the endpoint is bound to a wildcard address like `0.0.0.0` or `::`.
     ///
-    /// This will return `None` for clients.
+    /// This will return `None` for clients, unless learned from incoming datagrams after
+    /// [`local_address_changed`](Self::local_address_changed) was called.
     ///
     /// Retrieving the local IP address is currently supported on the following
     /// platforms:
@@ -1139,6 +1440,154 @@ impl Connection {
         self.local_ip
     }
 
+    /// Notify the connection that the local socket was rebound to a different address
+    ///
+    /// The local IP address used for outgoing datagrams is forgotten, so the OS picks a suitable
+    /// source until the address is learned again from the next datagram received. Under
+    /// [`MigrationPolicy::Allow`], an established connection also queues a PING so the peer
+    /// validates the new path promptly; otherwise the path only changes once the connection next
+    /// sends something.
+    pub fn local_address_changed(&mut self) {
+        self.local_ip = None;
+        self.relearn_local_ip = true;
+        if let MigrationPolicy::Allow = self.migration_policy {
+            if self.state.is_established() {
+                self.ping();
+            }
+        }
+    }
+
+    /// Transport parameters outside of RFC 9000 that the peer sent, sorted by id
+    ///
+    /// Only parameters registered with `transport_parameter()` or `record_transport_parameter()`
+    /// on the [`ClientConfig`](crate::ClientConfig) or [`ServerConfig`] are included. Empty until the peer's
+    /// parameters are known, which for clients is once the handshake completes, or earlier from
+    /// a session ticket's when attempting 0-RTT.
+    pub fn peer_custom_transport_parameters(&self) -> Vec<(u64, Bytes)> {
+        self.peer_params
+            .custom
+            .iter()
+            .filter(|(id, _)| self.recorded_params.contains(id))
+            .map(|(id, value)| (id.0, value.clone()))
+            .collect()
+    }
+
+    /// The network path currently in use
+    pub fn current_path(&self) -> PathInfo {
+        PathInfo {
+            local_ip: self.local_ip,
+            remote_addr: self.path.remote,
+            validated: self.path.validated,
+            mtu: self.path.current_mtu(),
+            rtt: self.path.rtt.get(),
+            cwnd: self.path.congestion.window(),
+        }
+    }
+
+    pub(crate) fn set_post_handshake_verifier(
+        &mut self,
+        verifier: Arc<PostHandshakeVerifier>,
+        server_name: &str,
+    ) {
+        self.post_handshake_verifier = Some((verifier, server_name.into()));
+    }
+
+    pub(crate) fn require_jls(&mut self) {
+        self.require_jls = true;
+    }
+
+    /// Whether the server failed to authenticate itself with JLS, as the client requires
+    fn jls_rejected(&self) -> bool {
+        self.require_jls
+            && match self.crypto.is_jls() {
+                Some(authed) => !authed,
+                None => !self.crypto.is_handshaking(),
+            }
+    }
+
+    /// Override the transport config's MTU settings for this connection
+    ///
+    /// Must be called before the connection sends anything.
+    pub(crate) fn override_mtu(
+        &mut self,
+        initial_mtu: Option<u16>,
+        max_udp_payload_size: Option<u16>,
+        now: Instant,
+    ) {
+        if let Some(max_udp_payload_size) = max_udp_payload_size {
+            self.set_max_udp_payload_size(max_udp_payload_size);
+        }
+        if let Some(initial_mtu) = initial_mtu {
+            self.initial_mtu = initial_mtu
+                .max(self.config.min_mtu)
+                .min(self.max_udp_payload_size);
+            let congestion = self.build_congestion(now, self.initial_mtu);
+            self.path.set_initial_mtu(self.initial_mtu, congestion, now);
+        }
+    }
+
+    /// Control congestion with controllers from `factory` instead of the transport config's
+    ///
+    /// Must be called before the connection sends anything, before `override_mtu`.
+    pub(crate) fn override_congestion(
+        &mut self,
+        factory: Arc<dyn ControllerFactory + Send + Sync>,
+        now: Instant,
+    ) {
+        self.congestion_controller_factory = Some(factory);
+        let congestion = self.build_congestion(now, self.initial_mtu);
+        self.path.set_initial_mtu(self.initial_mtu, congestion, now);
+    }
+
+    /// A congestion controller for a new path starting from `mtu`
+    fn build_congestion(&self, now: Instant, mtu: u16) -> Box<dyn Controller> {
+        match self.congestion_controller_factory {
+            Some(ref factory) => factory.build(now, mtu),
+            None => self.config.congestion_controller_factory.build(now, mtu),
+        }
+    }
+
+    pub(crate) fn record_custom_params(&mut self, ids: Vec<VarInt>) {
+        self.recorded_params = ids;
+    }
+
+    /// Start from path estimates learned by an earlier connection to the same peer
+    ///
+    /// Must be called before the connection sends anything, after `override_mtu`.
+    pub(crate) fn resume_path(&mut self, rtt: Option<Duration>, window: Option<u64>, now: Instant) {
+        if rtt.is_some() || window.is_some() {
+            self.path.resume(rtt, window, now);
+        }
+    }
+
+    /// Limit the size of the UDP payloads this connection sends, including MTU probes
+    ///
+    /// The limit can only be lowered, and never below the transport config's
+    /// [`min_mtu`](crate::TransportConfig::min_mtu). It applies to the current path at once, as
+    /// well as to any path the connection migrates to, e.g. when the application learns the
+    /// traffic now goes through a tunnel.
+    pub fn set_max_udp_payload_size(&mut self, value: u16) {
+        self.max_udp_payload_size = self
+            .max_udp_payload_size
+            .min(value.max(self.config.min_mtu));
+        self.initial_mtu = self.initial_mtu.min(self.max_udp_payload_size);
+        self.path
+            .mtud
+            .limit_max_udp_payload_size(self.max_udp_payload_size);
+        if let Some(ref mut prev_path) = self.prev_path {
+            prev_path
+                .mtud
+                .limit_max_udp_payload_size(self.max_udp_payload_size);
+        }
+    }
+
+    /// Control whether the connection may move to a different network path
+    ///
+    /// Takes effect for datagrams received from now on; a migration already under way completes.
+    pub fn set_migration_policy(&mut self, policy: MigrationPolicy) {
+        self.migration_policy = policy;
+    }
+
     /// Current best estimate of this connection's latency (round-trip-time)
     pub fn rtt(&self) -> Duration {
         self.path.rtt.get()
@@ -1173,6 +1622,15 @@ impl Connection {
         }
     }
 
+    /// See [`TransportConfig::send_coalesce_delay()`]
+    pub fn set_send_coalesce_delay(&mut self, delay: Option<Duration>) {
+        self.send_coalesce_delay = delay;
+        if delay.is_none() {
+            self.coalesce_deadline = None;
+            self.timers.stop(Timer::Coalesce);
+        }
+    }
+
     fn on_ack_received(
         &mut self,
         now: Instant,
@@ -1201,6 +1659,18 @@ impl Connection {
             }
         };
 
+        // Packets we gave up on too early
+        let space_lost = &mut self.spaces[space].declared_lost;
+        if !space_lost.is_empty() {
+            for range in ack.iter() {
+                let spurious = space_lost.range(range).copied().collect::<Vec<_>>();
+                for packet in spurious {
+                    space_lost.remove(&packet);
+                    self.stats.path.spurious_lost_packets += 1;
+                }
+            }
+        }
+
         // Avoid DoS from unreasonably huge ack ranges by filtering out just the new acks.
         let mut newly_acked = ArrayRangeSet::new();
         for range in ack.iter() {
@@ -1212,10 +1682,17 @@ impl Connection {
         if newly_acked.is_empty() {
             return Ok(());
         }
+        self.consecutive_ptos = 0;
+        self.failed_validations = 0;
+        self.update_health(now);
 
         let mut ack_eliciting_acked = false;
+        let mut rtt_probe_acked = None;
         for packet in newly_acked.elts() {
             if let Some(info) = self.spaces[space].sent_packets.remove(&packet) {
+                if info.retransmits.get().map_or(false, |x| x.rtt_probe) {
+                    rtt_probe_acked = Some((packet, info.time_sent));
+                }
                 if let Some(acked) = info.largest_acked {
                     // Assume ACKs for all packets below the largest acknowledged in `packet` have
                     // been received. This can cause the peer to spuriously retransmit if some of
@@ -1245,15 +1722,25 @@ impl Connection {
             self.spaces[space].largest_acked_packet,
         );
 
+        let ack_delay = if space != SpaceId::Data {
+            Duration::from_micros(0)
+        } else {
+            cmp::min(
+                self.max_ack_delay(),
+                Duration::from_micros(ack.delay << self.peer_params.ack_delay_exponent.0),
+            )
+        };
+        if let Some((packet, time_sent)) = rtt_probe_acked {
+            let mut rtt = instant_saturating_sub(now, time_sent);
+            // The reported delay only applies to the largest acknowledged packet
+            if packet == ack.largest {
+                rtt = rtt.saturating_sub(ack_delay);
+            }
+            self.rtt_probe_outstanding = false;
+            self.events.push_back(Event::PingAcknowledged { rtt });
+        }
+
         if new_largest && ack_eliciting_acked {
-            let ack_delay = if space != SpaceId::Data {
-                Duration::from_micros(0)
-            } else {
-                cmp::min(
-                    self.max_ack_delay(),
-                    Duration::from_micros(ack.delay << self.peer_params.ack_delay_exponent.0),
-                )
-            };
             let rtt = instant_saturating_sub(now, self.spaces[space].largest_acked_packet_sent);
             self.path.rtt.update(ack_delay, rtt);
             if self.path.first_packet_after_rtt_sample.is_none() {
@@ -1300,7 +1787,7 @@ impl Connection {
         ecn: frame::EcnCounts,
         largest_sent_time: Instant,
     ) {
-        match self.spaces[space].detect_ecn(newly_acked, ecn) {
+        match self.spaces[space].detect_ecn(newly_acked, ecn, self.config.send_ect1) {
             Err(e) => {
                 debug!("halting ECN due to verification failure: {}", e);
                 self.path.sending_ecn = false;
@@ -1383,6 +1870,7 @@ impl Connection {
             ?space,
             "PTO fired"
         );
+        self.stats.path.pto_events += 1;
 
         let count = match self.in_flight.ack_eliciting {
             // A PTO when we're not expecting any ACKs must be due to handshake anti-amplification
@@ -1396,6 +1884,8 @@ impl Connection {
         };
         self.spaces[space].loss_probes = self.spaces[space].loss_probes.saturating_add(count);
         self.pto_count = self.pto_count.saturating_add(1);
+        self.consecutive_ptos = self.consecutive_ptos.saturating_add(1);
+        self.update_health(now);
         self.set_loss_detection_timer(now);
     }
 
@@ -1485,7 +1975,13 @@ impl Connection {
             );
 
             for packet in &lost_packets {
-                let info = self.spaces[pn_space].sent_packets.remove(packet).unwrap(); // safe: lost_packets is populated just above
+                let space = &mut self.spaces[pn_space];
+                space.declared_lost.insert(*packet);
+                if space.declared_lost.len() > MAX_DECLARED_LOST {
+                    let oldest = *space.declared_lost.iter().next().unwrap();
+                    space.declared_lost.remove(&oldest);
+                }
+                let info = space.sent_packets.remove(packet).unwrap(); // safe: lost_packets is populated just above
                 self.remove_in_flight(pn_space, &info);
                 for frame in info.stream_frames {
                     self.streams.retransmit(frame);
@@ -1707,6 +2203,7 @@ impl Connection {
         debug_assert!(self.side.is_server());
         let len = packet.header_data.len() + packet.payload.len();
         self.path.total_recvd = len as u64;
+        self.first_packet = Some(now);
 
         match self.state {
             State::Handshake(ref mut state) => match packet.header {
@@ -1765,6 +2262,9 @@ impl Connection {
         trace!("0-RTT enabled");
         self.zero_rtt_enabled = true;
         self.zero_rtt_crypto = Some(ZeroRttCrypto { header, packet });
+        if self.side.is_server() {
+            self.accepted_0rtt = true;
+        }
     }
 
     fn read_crypto(
@@ -2082,7 +2582,13 @@ impl Connection {
 
         // State transitions for error cases
         if let Err(conn_err) = result {
-            self.error = Some(conn_err.clone());
+            // Whatever went wrong with a server that didn't authenticate with JLS, that's the cause
+            self.error = Some(match conn_err {
+                ConnectionError::TransportError(_) if self.jls_rejected() => {
+                    ConnectionError::JlsRejected
+                }
+                ref x => x.clone(),
+            });
             self.state = match conn_err {
                 ConnectionError::ApplicationClosed(reason) => State::closed(reason),
                 ConnectionError::ConnectionClosed(reason) => State::closed(reason),
@@ -2099,8 +2605,10 @@ impl Connection {
                     State::closed(err)
                 }
                 ConnectionError::VersionMismatch => State::Draining,
-                ConnectionError::LocallyClosed => {
-                    unreachable!("LocallyClosed isn't generated by packet processing")
+                ConnectionError::LocallyClosed
+                | ConnectionError::ConnectTimedOut
+                | ConnectionError::JlsRejected => {
+                    unreachable!("local closes aren't generated by packet processing")
                 }
             };
         }
@@ -2256,6 +2764,32 @@ impl Connection {
                 }
 
                 if self.side.is_client() {
+                    if self.jls_rejected() {
+                        debug!("server didn't authenticate with JLS");
+                        // The server may be a genuine one, which mustn't learn why it's refused
+                        return Err(TransportError {
+                            code: TransportErrorCode::crypto(42),
+                            frame: None,
+                            reason: String::new(),
+                        });
+                    }
+                    if let Some((ref verifier, ref server_name)) = self.post_handshake_verifier {
+                        let info = HandshakeInfo {
+                            server_name: server_name.clone(),
+                            peer_identity: self.crypto.peer_identity(),
+                            handshake_data: self.crypto.handshake_data(),
+                            jls: self.crypto.is_jls(),
+                        };
+                        if let Err(abort) = verifier(info) {
+                            debug!(reason = %abort.reason, "server refused after handshake");
+                            return Err(TransportError {
+                                code: TransportErrorCode::crypto(abort.alert),
+                                frame: None,
+                                reason: abort.reason,
+                            });
+                        }
+                    }
+
                     // Client-only because server params were set from the client's Initial
                     let params =
                         self.crypto
@@ -2297,8 +2831,14 @@ impl Connection {
                     // Server-only
                     self.spaces[SpaceId::Data].pending.handshake_done = true;
                     self.discard_space(now, SpaceId::Handshake);
+                    self.endpoint_events
+                        .push_back(EndpointEventInner::HandshakeComplete);
                 }
 
+                self.stats.handshake.duration =
+                    self.first_packet.map(|x| now.saturating_duration_since(x));
+                self.stats.handshake.time_to_ready =
+                    Some(now.saturating_duration_since(self.created));
                 self.events.push_back(Event::Connected);
                 self.state = State::Established;
                 trace!("established");
@@ -2731,7 +3271,8 @@ impl Connection {
                 self.server_config
                     .as_ref()
                     .expect("packets from unknown remote should be dropped by clients")
-                    .migration,
+                    .migration
+                    && !matches!(self.migration_policy, MigrationPolicy::Disallow),
                 "migration-initiating packets should have been dropped immediately"
             );
             self.migrate(now, remote);
@@ -2743,6 +3284,12 @@ impl Connection {
         Ok(())
     }
 
+    /// Whether a datagram from `remote` may move the connection to a new path
+    fn accepts_migration(&self, remote: SocketAddr) -> bool {
+        self.server_config.as_ref().map_or(false, |x| x.migration)
+            && self.migration_policy.accepts(remote)
+    }
+
     fn migrate(&mut self, now: Instant, remote: SocketAddr) {
         trace!(%remote, "migration initiated");
         // Reset rtt/congestion state for new path unless it looks like a NAT rebinding.
@@ -2757,10 +3304,8 @@ impl Connection {
             PathData::new(
                 remote,
                 self.config.initial_rtt,
-                self.config
-                    .congestion_controller_factory
-                    .build(now, self.config.get_initial_mtu()),
-                self.config.get_initial_mtu(),
+                self.build_congestion(now, self.initial_mtu),
+                self.initial_mtu,
                 self.config.min_mtu,
                 Some(peer_max_udp_payload_size),
                 self.config.mtu_discovery_config.clone(),
@@ -2768,6 +3313,9 @@ impl Connection {
                 false,
             )
         };
+        new_path
+            .mtud
+            .limit_max_udp_payload_size(self.max_udp_payload_size);
         new_path.challenge = Some(self.rng.gen());
         new_path.challenge_pending = true;
         let prev_pto = self.pto(SpaceId::Data);
@@ -2847,6 +3395,11 @@ impl Connection {
             buf.write(frame::Type::PING);
             sent.non_retransmits = true;
             self.stats.frame_tx.ping += 1;
+        } else if mem::replace(&mut space.pending.rtt_probe, false) {
+            trace!("PING (RTT probe)");
+            buf.write(frame::Type::PING);
+            sent.retransmits.get_or_create().rtt_probe = true;
+            self.stats.frame_tx.ping += 1;
         }
 
         // ACK
@@ -2972,11 +3525,12 @@ impl Connection {
         // DATAGRAM
         while buf.len() + Datagram::SIZE_BOUND < max_size && space_id == SpaceId::Data {
             match self.datagrams.write(buf, max_size) {
-                true => {
+                Some(len) => {
                     sent.non_retransmits = true;
                     self.stats.frame_tx.datagram += 1;
+                    self.stats.application.datagram_bytes_sent += len as u64;
                 }
-                false => break,
+                None => break,
             }
         }
 
@@ -3318,6 +3872,16 @@ pub enum ConnectionError {
     /// The local application closed the connection
     #[error("closed")]
     LocallyClosed,
+    /// The handshake didn't complete within the time the local application allowed for it
+    #[error("connect timed out")]
+    ConnectTimedOut,
+    /// The server didn't authenticate itself with JLS, though the client required it
+    ///
+    /// Usually means the client's JLS password or IV is wrong, so that the server took it for a
+    /// stranger and forwarded it to its upstream. See
+    /// [`ClientConfig::require_jls()`](crate::ClientConfig::require_jls).
+    #[error("server didn't authenticate with JLS")]
+    JlsRejected,
 }
 
 impl From<Close> for ConnectionError {
@@ -3334,8 +3898,9 @@ impl From<ConnectionError> for io::Error {
     fn from(x: ConnectionError) -> Self {
         use self::ConnectionError::*;
         let kind = match x {
-            TimedOut => io::ErrorKind::TimedOut,
+            TimedOut | ConnectTimedOut => io::ErrorKind::TimedOut,
             Reset => io::ErrorKind::ConnectionReset,
+            JlsRejected => io::ErrorKind::PermissionDenied,
             ApplicationClosed(_) | ConnectionClosed(_) => io::ErrorKind::ConnectionAborted,
             TransportError(_) | VersionMismatch | LocallyClosed => io::ErrorKind::Other,
         };
@@ -3466,6 +4031,32 @@ pub enum Event {
     Stream(StreamEvent),
     /// One or more application datagrams have been received
     DatagramReceived,
+    /// A PING requested with [`Connection::ping_rtt()`] was acknowledged
+    PingAcknowledged {
+        /// Time from sending the PING until its acknowledgement, excluding the peer's ack delay
+        rtt: Duration,
+    },
+}
+
+/// Whether the path to the peer seems to be working, judging by how recently anything was
+/// acknowledged
+///
+/// Meant as an early, soft signal that the network has gone away, e.g. after a NAT rebinding,
+/// well before the idle timeout closes the connection. Thresholds are set by
+/// [`TransportConfig::degraded_after_ptos()`] and [`TransportConfig::broken_after_ptos()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionHealth {
+    /// Packets are being acknowledged
+    Healthy,
+    /// Several probe timeouts in a row have expired, or validating a new path failed
+    Degraded {
+        /// When the connection was first found degraded
+        since: Instant,
+        /// Probe timeouts expired since anything was last acknowledged
+        consecutive_ptos: u32,
+    },
+    /// So many probe timeouts have expired in a row that the path is likely gone
+    Broken,
 }
 
 struct PathResponse {
@@ -3484,6 +4075,8 @@ fn instant_saturating_sub(x: Instant, y: Instant) -> Duration {
 
 // Prevents overflow and improves behavior in extreme circumstances
 const MAX_BACKOFF_EXPONENT: u32 = 16;
+/// Number of packets declared lost per space that are remembered to detect spurious losses
+const MAX_DECLARED_LOST: usize = 256;
 // Minimal remaining size to allow packet coalescing
 const MIN_PACKET_SPACE: usize = 40;
 /// The maximum amount of datagrams that are sent in a single transmit
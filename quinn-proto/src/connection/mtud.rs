@@ -81,6 +81,30 @@ impl MtuDiscovery {
         }
     }
 
+    /// Overrides the MTU the path starts with
+    ///
+    /// Only meaningful before anything was sent on the path.
+    pub(crate) fn set_initial_mtu(&mut self, initial_plpmtu: u16) {
+        debug_assert!(initial_plpmtu >= self.black_hole_detector.min_mtu);
+        self.current_mtu = initial_plpmtu;
+    }
+
+    /// Lowers the largest UDP payload size that may be used or probed on the path
+    ///
+    /// A search under way is restarted below the new ceiling.
+    pub(crate) fn limit_max_udp_payload_size(&mut self, max_udp_payload_size: u16) {
+        debug_assert!(max_udp_payload_size >= self.black_hole_detector.min_mtu);
+        self.current_mtu = self.current_mtu.min(max_udp_payload_size);
+
+        if let Some(state) = self.state.as_mut() {
+            state.max_udp_payload_size = state.max_udp_payload_size.min(max_udp_payload_size);
+            if let Phase::Searching(_) = state.phase {
+                // The probe in flight, if any, may exceed the ceiling; its ACK will be ignored
+                state.phase = Phase::Initial;
+            }
+        }
+    }
+
     /// Notifies the [`MtuDiscovery`] that a packet has been ACKed
     ///
     /// Returns true if the packet was an MTU probe
@@ -168,6 +192,8 @@ impl MtuDiscovery {
 struct EnabledMtuDiscovery {
     phase: Phase,
     peer_max_udp_payload_size: u16,
+    /// Ceiling set locally for this path, independent of the config's upper bound
+    max_udp_payload_size: u16,
     config: MtuDiscoveryConfig,
 }
 
@@ -176,6 +202,7 @@ impl EnabledMtuDiscovery {
         Self {
             phase: Phase::Initial,
             peer_max_udp_payload_size: MAX_UDP_PAYLOAD,
+            max_udp_payload_size: MAX_UDP_PAYLOAD,
             config,
         }
     }
@@ -191,7 +218,8 @@ impl EnabledMtuDiscovery {
             // Start the first search
             self.phase = Phase::Searching(SearchState::new(
                 current_mtu,
-                self.peer_max_udp_payload_size,
+                self.peer_max_udp_payload_size
+                    .min(self.max_udp_payload_size),
                 &self.config,
             ));
         } else if let Phase::Complete(next_mtud_activation) = &self.phase {
@@ -202,7 +230,8 @@ impl EnabledMtuDiscovery {
             // Start a new search (we have reached the next activation time)
             self.phase = Phase::Searching(SearchState::new(
                 current_mtu,
-                self.peer_max_udp_payload_size,
+                self.peer_max_udp_payload_size
+                    .min(self.max_udp_payload_size),
                 &self.config,
             ));
         }
@@ -708,6 +737,35 @@ mod tests {
         assert!(completed(&mtud));
     }
 
+    #[test]
+    fn mtu_discovery_limited_during_search_restarts_below_limit() {
+        let mut mtud = default_mtud();
+        let now = Instant::now();
+
+        let probe_size = mtud.poll_transmit(now, 0).unwrap();
+        assert!(probe_size > 1_300);
+        mtud.limit_max_udp_payload_size(1_300);
+
+        // The ACK of the probe sent before the limit was set doesn't raise the MTU
+        assert!(!mtud.on_acked(SpaceId::Data, 0, probe_size));
+        assert_eq!(mtud.current_mtu, 1_200);
+
+        let probed_sizes = drive_to_completion(&mut mtud, now, 1_500);
+        assert_eq!(probed_sizes, &[1_250, 1_275, 1_300]);
+        assert_eq!(mtud.current_mtu, 1_300);
+        assert!(completed(&mtud));
+    }
+
+    #[test]
+    fn mtu_discovery_limit_lowers_current_mtu() {
+        let mut mtud = default_mtud();
+        drive_to_completion(&mut mtud, Instant::now(), 1_500);
+        assert_eq!(mtud.current_mtu, 1_452);
+
+        mtud.limit_max_udp_payload_size(1_280);
+        assert_eq!(mtud.current_mtu, 1_280);
+    }
+
     #[test]
     #[should_panic]
     fn mtu_discovery_with_peer_max_udp_payload_size_after_search_panics() {
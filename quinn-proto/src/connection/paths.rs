@@ -1,4 +1,9 @@
-use std::{cmp, net::SocketAddr, time::Duration, time::Instant};
+use std::{
+    cmp, fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use super::{mtud::MtuDiscovery, pacing::Pacer};
 use crate::{config::MtuDiscoveryConfig, congestion, packet::SpaceId, TIMER_GRANULARITY};
@@ -81,6 +86,46 @@ impl PathData {
         }
     }
 
+    /// Start the path from a different MTU than the transport config's
+    ///
+    /// Only meaningful before anything was sent on the path.
+    pub(super) fn set_initial_mtu(
+        &mut self,
+        initial_mtu: u16,
+        congestion: Box<dyn congestion::Controller>,
+        now: Instant,
+    ) {
+        self.mtud.set_initial_mtu(initial_mtu);
+        self.pacing = Pacer::new(
+            self.rtt.get(),
+            congestion.initial_window(),
+            initial_mtu,
+            now,
+        );
+        self.congestion = congestion;
+    }
+
+    /// Start the path from estimates learned by an earlier connection to the same peer
+    ///
+    /// Only meaningful before anything was sent on the path. Following careful resumption, only
+    /// half of a remembered congestion window is used, as conditions may have changed since.
+    pub(super) fn resume(&mut self, rtt: Option<Duration>, window: Option<u64>, now: Instant) {
+        if let Some(rtt) = rtt {
+            self.rtt = RttEstimator::new(rtt.clamp(TIMER_GRANULARITY, MAX_RESUMED_RTT));
+        }
+        if let Some(window) = window {
+            let initial = self.congestion.initial_window();
+            self.congestion
+                .resume((window / 2).clamp(initial, initial.max(MAX_RESUMED_WINDOW)));
+        }
+        self.pacing = Pacer::new(
+            self.rtt.get(),
+            self.congestion.window(),
+            self.current_mtu(),
+            now,
+        );
+    }
+
     /// Indicates whether we're a server that hasn't validated the peer's address and hasn't
     /// received enough data from the peer to permit sending `bytes_to_send` additional bytes
     pub(super) fn anti_amplification_blocked(&self, bytes_to_send: u64) -> bool {
@@ -93,6 +138,80 @@ impl PathData {
     }
 }
 
+/// Snapshot of the network path a connection is currently using
+#[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct PathInfo {
+    /// Local IP address datagrams are sent from, if known
+    pub local_ip: Option<IpAddr>,
+    /// Address of the peer
+    pub remote_addr: SocketAddr,
+    /// Whether the peer has been confirmed to be reachable at `remote_addr`
+    pub validated: bool,
+    /// Current maximum UDP payload size
+    pub mtu: u16,
+    /// Current best estimate of the path's round-trip time
+    pub rtt: Duration,
+    /// Current congestion window
+    ///
+    /// Along with `rtt`, this can be remembered once the connection closes to give the next
+    /// connection to the same peer a head start; see [`ClientConfig::initial_rtt()`] and
+    /// [`ClientConfig::initial_window()`].
+    ///
+    /// [`ClientConfig::initial_rtt()`]: crate::ClientConfig::initial_rtt
+    /// [`ClientConfig::initial_window()`]: crate::ClientConfig::initial_window
+    pub cwnd: u64,
+}
+
+/// Whether a connection may move to a different network path
+///
+/// Only a server can be migrated by its peer, and only if its
+/// [`ServerConfig::migration`](crate::ServerConfig::migration) permits it; a policy can further
+/// restrict but never extend that. Datagrams from an address that isn't accepted are ignored, as
+/// RFC 9000 requires when migration is not permitted, so the connection stays on its current path.
+#[derive(Clone)]
+pub enum MigrationPolicy {
+    /// Follow the peer to new addresses and validate local rebinds automatically
+    Allow,
+    /// Stay on the current path
+    ///
+    /// This includes NAT rebindings, so the connection will time out if the peer's address
+    /// changes. Local rebinds are not announced to the peer until the application next sends
+    /// something.
+    Disallow,
+    /// Ask the callback whether to follow the peer to a new address
+    ///
+    /// The callback may be invoked more than once for the same address. Local rebinds are treated
+    /// as under [`Disallow`](Self::Disallow).
+    ConfirmVia(Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>),
+}
+
+impl MigrationPolicy {
+    pub(super) fn accepts(&self, remote: SocketAddr) -> bool {
+        match *self {
+            Self::Allow => true,
+            Self::Disallow => false,
+            Self::ConfirmVia(ref confirm) => confirm(remote),
+        }
+    }
+}
+
+impl Default for MigrationPolicy {
+    fn default() -> Self {
+        Self::Allow
+    }
+}
+
+impl fmt::Debug for MigrationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Allow => f.write_str("Allow"),
+            Self::Disallow => f.write_str("Disallow"),
+            Self::ConfirmVia(_) => f.write_str("ConfirmVia(..)"),
+        }
+    }
+}
+
 /// RTT estimation for a particular network path
 #[derive(Copy, Clone)]
 pub struct RttEstimator {
@@ -164,3 +283,9 @@ impl RttEstimator {
         }
     }
 }
+
+/// Largest RTT estimate accepted from an earlier connection
+const MAX_RESUMED_RTT: Duration = Duration::from_secs(2);
+
+/// Largest congestion window a connection resumes from
+const MAX_RESUMED_WINDOW: u64 = 16 * 1024 * 1024;
@@ -17,6 +17,10 @@ pub(super) struct SendBuffer {
     ///
     /// Always lies in (offset - unacked.len())..offset
     unsent: u64,
+    /// The first offset that has never been sent
+    ///
+    /// Unlike `unsent`, this is not rewound when 0-RTT data must be sent again.
+    never_sent: u64,
     /// Acknowledged ranges which couldn't be discarded yet as they don't include the earliest
     /// offset in `unacked`
     // TODO: Recover storage from these by compacting (#700)
@@ -127,6 +131,7 @@ impl SendBuffer {
             .min((max_len as u64).saturating_add(self.unsent));
         let result = self.unsent..end;
         self.unsent = end;
+        self.never_sent = self.never_sent.max(end);
         (result, encode_length)
     }
 
@@ -155,6 +160,11 @@ impl SendBuffer {
         &[]
     }
 
+    /// Number of bytes that have been transmitted at least once
+    pub(super) fn sent_once(&self) -> u64 {
+        self.never_sent
+    }
+
     /// Queue a range of sent but unacknowledged data to be retransmitted
     pub(super) fn retransmit(&mut self, range: Range<u64>) {
         debug_assert!(range.end <= self.unsent, "unsent data can't be lost");
@@ -184,6 +194,16 @@ impl SendBuffer {
         self.unsent != self.offset || !self.retransmits.is_empty()
     }
 
+    /// Amount of data written by the application that has never been sent
+    pub(super) fn unsent_len(&self) -> u64 {
+        self.offset - self.unsent
+    }
+
+    /// Whether previously sent data awaits retransmission
+    pub(super) fn has_retransmits(&self) -> bool {
+        !self.retransmits.is_empty()
+    }
+
     /// Compute the amount of data that hasn't been acknowledged
     pub(super) fn unacked(&self) -> u64 {
         self.unacked_len as u64 - self.acks.iter().map(|x| x.end - x.start).sum::<u64>()
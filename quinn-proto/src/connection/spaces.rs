@@ -1,6 +1,6 @@
 use std::{
     cmp,
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     mem,
     ops::{Index, IndexMut},
     time::{Duration, Instant},
@@ -33,6 +33,8 @@ pub(super) struct PacketSpace {
     /// Transmitted but not acked
     // We use a BTreeMap here so we can efficiently query by range on ACK and for loss detection
     pub(super) sent_packets: BTreeMap<u64, SentPacket>,
+    /// Recently declared lost, so that a later acknowledgement can be recognized as spurious
+    pub(super) declared_lost: BTreeSet<u64>,
     /// Number of explicit congestion notification codepoints seen on incoming packets
     pub(super) ecn_counters: frame::EcnCounts,
     /// Recent ECN counters sent by the peer in ACK frames
@@ -77,6 +79,7 @@ impl PacketSpace {
             largest_acked_packet: None,
             largest_acked_packet_sent: now,
             sent_packets: BTreeMap::new(),
+            declared_lost: BTreeSet::new(),
             ecn_counters: frame::EcnCounts::ZERO,
             ecn_feedback: frame::EcnCounts::ZERO,
 
@@ -147,10 +150,13 @@ impl PacketSpace {
     }
 
     /// Verifies sanity of an ECN block and returns whether congestion was encountered.
+    ///
+    /// `ect1` indicates whether we mark our packets ECT(1) rather than ECT(0).
     pub(super) fn detect_ecn(
         &mut self,
         newly_acked: u64,
         ecn: frame::EcnCounts,
+        ect1: bool,
     ) -> Result<bool, &'static str> {
         let ect0_increase = ecn
             .ect0
@@ -168,7 +174,12 @@ impl PacketSpace {
         if total_increase < newly_acked {
             return Err("ECN bleaching");
         }
-        if (ect0_increase + ce_increase) < newly_acked || ect1_increase != 0 {
+        let (sent_increase, other_increase) = if ect1 {
+            (ect1_increase, ect0_increase)
+        } else {
+            (ect0_increase, ect1_increase)
+        };
+        if (sent_increase + ce_increase) < newly_acked || other_increase != 0 {
             return Err("ECN corruption");
         }
         // If total_increase > newly_acked (which happens when ACKs are lost), this is required by
@@ -234,6 +245,8 @@ pub struct Retransmits {
     pub(super) new_cids: Vec<IssuedCid>,
     pub(super) retire_cids: Vec<u64>,
     pub(super) handshake_done: bool,
+    /// A PING requested by `Connection::ping_rtt`
+    pub(super) rtt_probe: bool,
 }
 
 impl Retransmits {
@@ -250,6 +263,7 @@ impl Retransmits {
             && self.new_cids.is_empty()
             && self.retire_cids.is_empty()
             && !self.handshake_done
+            && !self.rtt_probe
     }
 }
 
@@ -270,6 +284,7 @@ impl ::std::ops::BitOrAssign for Retransmits {
         self.new_cids.extend(&rhs.new_cids);
         self.retire_cids.extend(rhs.retire_cids);
         self.handshake_done |= rhs.handshake_done;
+        self.rtt_probe |= rhs.rtt_probe;
     }
 }
 
@@ -140,6 +140,98 @@ pub struct PathStats {
     pub lost_plpmtud_probes: u64,
     /// The number of times a black hole was detected in the path
     pub black_holes_detected: u64,
+    /// The amount of packets counted by `lost_packets` that were acknowledged afterwards
+    pub spurious_lost_packets: u64,
+    /// The number of times the probe timeout (PTO) expired
+    pub pto_events: u64,
+    /// Current probe timeout for application data, including exponential backoff
+    pub pto: Duration,
+}
+
+/// Statistics about the flow control windows we advertise to the peer
+#[derive(Default, Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct FlowControlStats {
+    /// Current connection-level receive window, including any growth from auto-tuning
+    pub receive_window: u64,
+    /// Current per-stream receive window, including any growth from auto-tuning
+    pub stream_receive_window: u64,
+}
+
+/// Explicit congestion notification codepoints seen on a connection's packets
+///
+/// Counts are summed across packet number spaces.
+#[derive(Default, Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct EcnStats {
+    /// Packets received marked ECT(0)
+    pub received_ect0: u64,
+    /// Packets received marked ECT(1)
+    pub received_ect1: u64,
+    /// Packets received marked CE (congestion experienced)
+    pub received_ce: u64,
+    /// Our packets the peer reported receiving marked ECT(0), as of the latest ACK-ECN frame
+    ///
+    /// The peer's counts are reset if they fail validation, which also stops us marking packets.
+    pub peer_ect0: u64,
+    /// Our packets the peer reported receiving marked ECT(1), as of the latest ACK-ECN frame
+    pub peer_ect1: u64,
+    /// Our packets the peer reported receiving marked CE, as of the latest ACK-ECN frame
+    pub peer_ce: u64,
+}
+
+/// Timing of a connection's handshake
+#[derive(Default, Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct HandshakeStats {
+    /// Time from the first packet being sent (client) or received (server) until the handshake
+    /// completed
+    ///
+    /// `None` until the handshake completes.
+    pub duration: Option<Duration>,
+    /// Time from the connection being created until it was ready for use
+    ///
+    /// Clients measure from the call to [`Endpoint::connect`](crate::Endpoint::connect), servers
+    /// from the arrival of the first packet. `None` until the handshake completes.
+    pub time_to_ready: Option<Duration>,
+}
+
+/// Application payload carried by a connection, split by mechanism
+///
+/// Only payload bytes are counted, never frame or packet overhead. Stream data is counted once
+/// when first transmitted, however often it is retransmitted, and once when read by the
+/// application. Datagrams are counted when transmitted, and when received by the application.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ApplicationStats {
+    /// Stream data transmitted
+    pub stream_bytes_sent: u64,
+    /// Stream data read by the application
+    pub stream_bytes_received: u64,
+    /// Payload of DATAGRAM frames transmitted
+    pub datagram_bytes_sent: u64,
+    /// Payload of DATAGRAM frames received by the application
+    pub datagram_bytes_received: u64,
+}
+
+impl ApplicationStats {
+    /// Counts accumulated since `earlier`, a previous snapshot of the same connection
+    pub(crate) fn since(&self, earlier: &Self) -> Self {
+        Self {
+            stream_bytes_sent: self.stream_bytes_sent - earlier.stream_bytes_sent,
+            stream_bytes_received: self.stream_bytes_received - earlier.stream_bytes_received,
+            datagram_bytes_sent: self.datagram_bytes_sent - earlier.datagram_bytes_sent,
+            datagram_bytes_received: self.datagram_bytes_received - earlier.datagram_bytes_received,
+        }
+    }
+
+    pub(crate) fn add(&mut self, other: &Self) {
+        self.stream_bytes_sent += other.stream_bytes_sent;
+        self.stream_bytes_received += other.stream_bytes_received;
+        self.datagram_bytes_sent += other.datagram_bytes_sent;
+        self.datagram_bytes_received += other.datagram_bytes_received;
+    }
 }
 
 /// Connection statistics
@@ -156,4 +248,12 @@ pub struct ConnectionStats {
     pub frame_rx: FrameStats,
     /// Statistics related to the current transmission path
     pub path: PathStats,
+    /// Statistics about the receive windows advertised to the peer
+    pub flow_control: FlowControlStats,
+    /// Timing of the handshake
+    pub handshake: HandshakeStats,
+    /// ECN codepoints received, and reported by the peer
+    pub ecn: EcnStats,
+    /// Application payload sent and received, over streams and datagrams
+    pub application: ApplicationStats,
 }
@@ -1,6 +1,7 @@
 use std::{
     cell::RefCell,
     collections::{hash_map, BinaryHeap, VecDeque},
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
@@ -157,6 +158,17 @@ impl<'a> RecvStream<'a> {
 
         Ok(())
     }
+
+    /// Check whether the peer has reset the stream, get the error code if it has
+    ///
+    /// Yields `Ok(None)` while the stream is open and has not been reset. Once the stream has been
+    /// stopped or read to completion, further calls yield `UnknownStream`.
+    pub fn received_reset(&mut self) -> Result<Option<VarInt>, UnknownStream> {
+        match self.state.recv.get(&self.id) {
+            Some(s) if !s.stopped => Ok(s.reset_code()),
+            _ => Err(UnknownStream { _private: () }),
+        }
+    }
 }
 
 /// Access to streams
@@ -291,6 +303,15 @@ impl<'a> SendStream<'a> {
         Ok(())
     }
 
+    /// Send queued stream data without waiting to coalesce it with later writes
+    ///
+    /// Only has an effect when [`TransportConfig::send_coalesce_delay`] is set.
+    ///
+    /// [`TransportConfig::send_coalesce_delay`]: crate::TransportConfig::send_coalesce_delay
+    pub fn flush(&mut self) {
+        self.state.flush_requested = true;
+    }
+
     /// Set the priority of a stream
     ///
     /// # Panics
@@ -443,3 +464,41 @@ enum StreamHalf {
     Send,
     Recv,
 }
+
+/// Measures how quickly the application consumes a receive window
+///
+/// Used to decide when an auto-tuned receive window should grow.
+#[derive(Debug, Default, Copy, Clone)]
+pub(super) struct WindowTuner {
+    /// Start of the current measurement, and the amount of data consumed at that point
+    epoch: Option<(Instant, u64)>,
+}
+
+impl WindowTuner {
+    /// Record that `consumed` bytes have been read in total as of `now`
+    ///
+    /// Returns whether `window` should grow, which is the case once the delivery rate observed
+    /// over at least one round trip, multiplied by `rtt`, exceeds half of the window.
+    pub(super) fn update(
+        &mut self,
+        now: Instant,
+        consumed: u64,
+        window: u64,
+        rtt: Duration,
+    ) -> bool {
+        let (start, start_consumed) = match self.epoch {
+            Some(x) => x,
+            None => {
+                self.epoch = Some((now, consumed));
+                return false;
+            }
+        };
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed < rtt || elapsed.is_zero() {
+            return false;
+        }
+        self.epoch = Some((now, consumed));
+        let consumed = u128::from(consumed.saturating_sub(start_consumed));
+        consumed * rtt.as_nanos() * 2 > u128::from(window) * elapsed.as_nanos()
+    }
+}
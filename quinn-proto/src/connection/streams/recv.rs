@@ -4,7 +4,9 @@ use std::mem;
 use thiserror::Error;
 use tracing::debug;
 
-use super::{Retransmits, ShouldTransmit, StreamHalf, StreamId, StreamsState, UnknownStream};
+use super::{
+    Retransmits, ShouldTransmit, StreamHalf, StreamId, StreamsState, UnknownStream, WindowTuner,
+};
 use crate::connection::assembler::{Assembler, Chunk, IllegalOrderedRead};
 use crate::{frame, TransportError, VarInt};
 
@@ -15,6 +17,8 @@ pub(super) struct Recv {
     sent_max_stream_data: u64,
     pub(super) end: u64,
     pub(super) stopped: bool,
+    /// Measures the read rate when receive window auto-tuning is enabled
+    pub(super) window_tuner: WindowTuner,
 }
 
 impl Recv {
@@ -25,6 +29,7 @@ impl Recv {
             sent_max_stream_data: initial_max_data,
             end: 0,
             stopped: false,
+            window_tuner: WindowTuner::default(),
         }
     }
 
@@ -130,6 +135,14 @@ impl Recv {
         matches!(self.state, RecvState::Recv { .. })
     }
 
+    /// The error code supplied by the peer, if it reset the stream
+    pub(super) fn reset_code(&self) -> Option<VarInt> {
+        match self.state {
+            RecvState::ResetRecvd { error_code, .. } => Some(error_code),
+            RecvState::Recv { .. } => None,
+        }
+    }
+
     fn final_offset(&self) -> Option<u64> {
         match self.state {
             RecvState::Recv { size } => size,
@@ -253,6 +266,7 @@ impl<'a> Chunks<'a> {
 
         if let Some(chunk) = rs.assembler.read(max_length, self.ordered) {
             self.read += chunk.bytes.len() as u64;
+            self.streams.payload_read += chunk.bytes.len() as u64;
             return Ok(Some(chunk));
         }
 
@@ -307,6 +321,7 @@ impl<'a> Chunks<'a> {
 
         // If the stream hasn't finished, we may need to issue stream-level flow control credit
         if let ChunksState::Readable(mut rs) = state {
+            self.streams.tune_stream_window(&mut rs);
             let (_, max_stream_data) = rs.max_stream_data(self.streams.stream_receive_window);
             should_transmit |= max_stream_data.0;
             if max_stream_data.0 {
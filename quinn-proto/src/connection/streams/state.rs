@@ -2,6 +2,7 @@ use std::{
     collections::{binary_heap::PeekMut, hash_map, BinaryHeap, VecDeque},
     convert::TryFrom,
     mem,
+    time::{Duration, Instant},
 };
 
 use bytes::{BufMut, BytesMut};
@@ -10,7 +11,7 @@ use tracing::{debug, trace};
 
 use super::{
     push_pending, PendingLevel, Recv, Retransmits, Send, SendState, ShouldTransmit, StreamEvent,
-    StreamHalf, ThinRetransmits,
+    StreamHalf, ThinRetransmits, WindowTuner,
 };
 use crate::{
     coding::BufMutExt,
@@ -60,10 +61,20 @@ pub struct StreamsState {
     ///
     /// Streams are only added to this list when a write fails.
     pub(super) connection_blocked: Vec<StreamId>,
+    /// Whether the application asked for queued stream data to be sent without coalescing delay
+    pub(super) flush_requested: bool,
     /// Connection-level flow control budget dictated by the peer
     pub(super) max_data: u64,
-    /// The initial receive window
+    /// The current receive window
     receive_window: u64,
+    /// Upper bound to which `receive_window` may be grown by auto-tuning
+    max_receive_window: u64,
+    /// Measures the connection-level read rate for receive window auto-tuning
+    receive_window_tuner: WindowTuner,
+    /// Sum of flow control credit issued for data read by the application
+    data_read: u64,
+    /// When the connection last ran and its RTT at the time, against which reads are measured
+    clock: Option<(Instant, Duration)>,
     /// Limit on incoming data, which is transmitted through `MAX_DATA` frames
     local_max_data: u64,
     /// The last value of `MAX_DATA` which had been queued for transmission in
@@ -75,10 +86,18 @@ pub struct StreamsState {
     data_recvd: u64,
     /// Total quantity of unacknowledged outgoing data
     pub(super) unacked_data: u64,
+    /// Stream data transmitted at least once, counting each byte only once
+    payload_sent: u64,
+    /// Stream data delivered to the application
+    pub(super) payload_read: u64,
     /// Configured upper bound for `unacked_data`
     pub(super) send_window: u64,
-    /// Configured upper bound for how much unacked data the peer can send us per stream
+    /// Current upper bound for how much unacked data the peer can send us per stream
     pub(super) stream_receive_window: u64,
+    /// The per-stream window advertised in our transport parameters
+    initial_stream_receive_window: u64,
+    /// Upper bound to which `stream_receive_window` may be grown by auto-tuning
+    max_stream_receive_window: u64,
     /// Whether the corresponding `max_remote` has increased
     max_streams_dirty: [bool; 2],
 
@@ -118,15 +137,24 @@ impl StreamsState {
             pending: BinaryHeap::new(),
             events: VecDeque::new(),
             connection_blocked: Vec::new(),
+            flush_requested: false,
             max_data: 0,
             receive_window: receive_window.into(),
+            max_receive_window: receive_window.into(),
+            receive_window_tuner: WindowTuner::default(),
+            data_read: 0,
+            clock: None,
             local_max_data: receive_window.into(),
             sent_max_data: receive_window,
             data_sent: 0,
             data_recvd: 0,
             unacked_data: 0,
+            payload_sent: 0,
+            payload_read: 0,
             send_window,
             stream_receive_window: stream_receive_window.into(),
+            initial_stream_receive_window: stream_receive_window.into(),
+            max_stream_receive_window: stream_receive_window.into(),
             max_streams_dirty: [false, false],
             initial_max_stream_data_uni: 0u32.into(),
             initial_max_stream_data_bidi_local: 0u32.into(),
@@ -340,6 +368,30 @@ impl StreamsState {
         })
     }
 
+    /// Amount of new stream data queued for transmission, if it may be held back for coalescing
+    ///
+    /// Returns `None` when queued data must be sent without delay: when the application flushed, a
+    /// stream has a FIN or lost data to send, or flow control prevents further writes.
+    pub(crate) fn coalescable_bytes(&mut self) -> Option<u64> {
+        if mem::replace(&mut self.flush_requested, false) || self.write_limit() == 0 {
+            return None;
+        }
+        let mut total = 0;
+        for level in self.pending.iter() {
+            for id in level.queue.borrow().iter() {
+                let stream = match self.send.get(id) {
+                    Some(x) if !x.is_reset() => x,
+                    _ => continue,
+                };
+                if stream.fin_pending || stream.pending.has_retransmits() {
+                    return None;
+                }
+                total += stream.pending.unsent_len();
+            }
+        }
+        Some(total)
+    }
+
     /// Whether MAX_STREAM_DATA frames could be sent for stream `id`
     pub(crate) fn can_send_flow_control(&self, id: StreamId) -> bool {
         self.recv
@@ -523,7 +575,9 @@ impl StreamsState {
             // Now that we know the `StreamId`, we can better account for how many bytes
             // are required to encode it.
             let max_buf_size = max_buf_size - buf.len() - 1 - VarInt::size(id.into());
+            let sent_once = stream.pending.sent_once();
             let (offsets, encode_length) = stream.pending.poll_transmit(max_buf_size);
+            self.payload_sent += stream.pending.sent_once() - sent_once;
             let fin = offsets.end == stream.pending.offset()
                 && matches!(stream.state, SendState::DataSent { .. });
             if fin {
@@ -779,6 +833,80 @@ impl StreamsState {
         self.allocated_remote_count[dir as usize]
     }
 
+    /// Allow the receive windows to grow up to the given bounds as the read rate requires
+    pub(crate) fn enable_window_tuning(
+        &mut self,
+        max_receive_window: VarInt,
+        max_stream_receive_window: VarInt,
+    ) {
+        self.max_receive_window = max_receive_window.into();
+        self.max_stream_receive_window = max_stream_receive_window.into();
+    }
+
+    /// Stream data transmitted and delivered to the application, respectively
+    pub(crate) fn payload_bytes(&self) -> (u64, u64) {
+        (self.payload_sent, self.payload_read)
+    }
+
+    /// Record the time and RTT against which reads are measured for receive window auto-tuning
+    pub(crate) fn set_clock(&mut self, now: Instant, rtt: Duration) {
+        self.clock = Some((now, rtt));
+    }
+
+    /// Grow the connection receive window if the data read over a round trip approaches it
+    fn tune_receive_window(&mut self) {
+        let (now, rtt) = match self.clock {
+            Some(x) => x,
+            None => return,
+        };
+        if self.receive_window < self.max_receive_window
+            && self
+                .receive_window_tuner
+                .update(now, self.data_read, self.receive_window, rtt)
+        {
+            let window = self
+                .receive_window
+                .saturating_mul(2)
+                .min(self.max_receive_window);
+            debug!(window, "growing connection receive window");
+            // `max_receive_window` is a `VarInt`, so this cannot fail
+            self.set_receive_window(VarInt::from_u64(window).unwrap());
+        }
+    }
+
+    /// Grow the per-stream receive window if the data read from `rs` over a round trip
+    /// approaches it
+    pub(super) fn tune_stream_window(&mut self, rs: &mut Recv) {
+        let (now, rtt) = match self.clock {
+            Some(x) => x,
+            None => return,
+        };
+        if self.stream_receive_window < self.max_stream_receive_window
+            && rs.window_tuner.update(
+                now,
+                rs.assembler.bytes_read(),
+                self.stream_receive_window,
+                rtt,
+            )
+        {
+            // The window is shared by all streams, so streams opened later are granted the
+            // grown window with their first update
+            self.stream_receive_window = self
+                .stream_receive_window
+                .saturating_mul(2)
+                .min(self.max_stream_receive_window);
+            debug!(
+                window = self.stream_receive_window,
+                "growing stream receive window"
+            );
+        }
+    }
+
+    /// The current connection and per-stream receive windows
+    pub(crate) fn receive_windows(&self) -> (u64, u64) {
+        (self.receive_window, self.stream_receive_window)
+    }
+
     /// Set the receive_window and returns whether the receive_window has been
     /// expanded or shrunk: true if expanded, false if shrunk.
     pub(crate) fn set_receive_window(&mut self, receive_window: VarInt) -> bool {
@@ -813,7 +941,7 @@ impl StreamsState {
         if bi || remote {
             assert!(self
                 .recv
-                .insert(id, Recv::new(self.stream_receive_window))
+                .insert(id, Recv::new(self.initial_stream_receive_window))
                 .is_none());
         }
     }
@@ -827,6 +955,8 @@ impl StreamsState {
     /// suppress sending further updates until the window increases significantly
     /// again.
     pub(super) fn add_read_credits(&mut self, credits: u64) -> ShouldTransmit {
+        self.data_read = self.data_read.saturating_add(credits);
+        self.tune_receive_window();
         if credits > self.receive_window_shrink_debt {
             let net_credits = credits - self.receive_window_shrink_debt;
             self.local_max_data = self.local_max_data.saturating_add(net_credits);
@@ -95,6 +95,25 @@ pub trait Session: Send + 'static {
     /// return true if it's a authenticated JLS connection
     fn is_jls(&self) -> Option<bool>;
 
+    /// Whether the handshake resumed a previous session instead of authenticating from scratch
+    ///
+    /// Always `false` until the handshake completes.
+    fn is_resumed(&self) -> bool;
+
+    /// For servers, the identity of the session ticket a client used to send 0-RTT data
+    ///
+    /// Each ticket has a distinct identity, so seeing the same one twice indicates replayed early
+    /// data. `None` if the client did not attempt 0-RTT.
+    fn early_data_identity(&self) -> Option<Vec<u8>>;
+
+    /// For servers, let `accept` decide whether to take early data offered with a session ticket
+    ///
+    /// `accept` is called with the ticket's identity once the client's ClientHello is complete,
+    /// before the handshake processes it. If it returns `false`, the early data is rejected in the
+    /// TLS handshake itself, so the client sees its 0-RTT rejected just as if the ticket didn't
+    /// allow it. Has no effect once the ClientHello has been read.
+    fn filter_early_data(&mut self, accept: Box<dyn FnOnce(&[u8]) -> bool + Send>);
+
     /// Return the jls forward upstream addr return None if not found or it's 
     /// authentic jls connection
     fn jls_upstream_addr(&self) -> Option<SocketAddr>;
@@ -150,6 +169,15 @@ pub trait ServerConfig: Send + Sync {
         version: u32,
         params: &TransportParameters,
     ) -> Box<dyn Session>;
+
+    /// A copy of this configuration that authenticates JLS clients and picks their upstreams by
+    /// `jls` instead
+    ///
+    /// `None` if the configuration doesn't support JLS.
+    #[cfg(feature = "rustls")]
+    fn with_jls_config(&self, _jls: &::rustls::JlsServerConfig) -> Option<Arc<dyn ServerConfig>> {
+        None
+    }
 }
 
 /// Keys used to protect packet payloads
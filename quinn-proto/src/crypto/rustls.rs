@@ -1,4 +1,4 @@
-use std::{any::Any, convert::TryInto, io, str, sync::Arc};
+use std::{any::Any, convert::TryInto, io, mem, net::SocketAddr, str, sync::Arc};
 
 use bytes::BytesMut;
 use ring::aead;
@@ -9,6 +9,7 @@ use rustls::{
 };
 
 use crate::{
+    coding::{self, BufExt, UnexpectedEnd},
     crypto::{
         self, CryptoError, ExportKeyingMaterialError, HeaderKey, KeyPair, Keys, UnsupportedVersion,
     },
@@ -30,6 +31,11 @@ pub struct TlsSession {
     version: Version,
     got_handshake_data: bool,
     next_secrets: Option<Secrets>,
+    server_flight: ServerFlight,
+    client_hello: ClientHello,
+    /// What a server session was started with, to restart it if early data must be refused
+    server_start: Option<(Arc<rustls::ServerConfig>, Vec<u8>)>,
+    early_data_filter: Option<Box<dyn FnOnce(&[u8]) -> bool + Send>>,
     inner: Connection,
 }
 
@@ -40,6 +46,62 @@ impl TlsSession {
             Connection::Server(_) => Side::Server,
         }
     }
+
+    /// Restart a server session that hasn't read anything yet with early data disabled, if the
+    /// early data filter rejects the ticket the client offered
+    fn apply_early_data_filter(&mut self) {
+        let identity = match self.client_hello.early_data_identity {
+            Some(ref x) => x,
+            None => return,
+        };
+        let accept = match self.early_data_filter.take() {
+            Some(x) => x,
+            None => return,
+        };
+        if accept(identity) {
+            return;
+        }
+        let (config, params) = match self.server_start.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let mut config = (*config).clone();
+        config.max_early_data_size = 0;
+        self.inner = Connection::Server(
+            rustls::quic::ServerConnection::new(Arc::new(config), self.version, params).unwrap(),
+        );
+    }
+
+    fn read_hs(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
+        self.inner.read_hs(buf).map_err(|e| {
+            if let Some(alert) = self.inner.alert() {
+                TransportError {
+                    code: TransportErrorCode::crypto(alert.get_u8()),
+                    frame: None,
+                    reason: e.to_string(),
+                }
+            } else {
+                TransportError::PROTOCOL_VIOLATION(format!("TLS error: {e}"))
+            }
+        })?;
+        if !self.got_handshake_data {
+            // Hack around the lack of an explicit signal from rustls to reflect ClientHello being
+            // ready on incoming connections, or ALPN negotiation completing on outgoing
+            // connections.
+            let have_server_name = match self.inner {
+                Connection::Client(_) => false,
+                Connection::Server(ref session) => session.server_name().is_some(),
+            };
+            if self.inner.alpn_protocol().is_some()
+                || have_server_name
+                || !self.inner.is_handshaking()
+            {
+                self.got_handshake_data = true;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
 }
 
 impl crypto::Session for TlsSession {
@@ -83,31 +145,22 @@ impl crypto::Session for TlsSession {
     }
 
     fn read_handshake(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
-        self.inner.read_hs(buf).map_err(|e| {
-            if let Some(alert) = self.inner.alert() {
-                TransportError {
-                    code: TransportErrorCode::crypto(alert.get_u8()),
-                    frame: None,
-                    reason: e.to_string(),
+        match self.side() {
+            Side::Client => self.server_flight.observe(buf),
+            Side::Server if !self.client_hello.done => {
+                // Hold the ClientHello back from rustls until it's complete, so early data can
+                // still be refused before rustls accepts it
+                self.client_hello.observe(buf)?;
+                if !self.client_hello.done {
+                    return Ok(false);
                 }
-            } else {
-                TransportError::PROTOCOL_VIOLATION(format!("TLS error: {e}"))
-            }
-        })?;
-        if !self.got_handshake_data {
-            // Hack around the lack of an explicit signal from rustls to reflect ClientHello being
-            // ready on incoming connections, or ALPN negotiation completing on outgoing
-            // connections.
-            let have_server_name = match self.inner {
-                Connection::Client(_) => false,
-                Connection::Server(ref session) => session.server_name().is_some(),
-            };
-            if self.inner.alpn_protocol().is_some() || have_server_name || !self.is_handshaking() {
-                self.got_handshake_data = true;
-                return Ok(true);
+                self.apply_early_data_filter();
+                let hello = mem::take(&mut self.client_hello.buf);
+                return self.read_hs(&hello);
             }
+            Side::Server => {}
         }
-        Ok(false)
+        self.read_hs(buf)
     }
 
     fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
@@ -121,7 +174,12 @@ impl crypto::Session for TlsSession {
     }
 
     fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Keys> {
-        let keys = match self.inner.write_hs(buf)? {
+        let start = buf.len();
+        let change = self.inner.write_hs(buf);
+        if self.side().is_server() {
+            self.server_flight.observe(&buf[start..]);
+        }
+        let keys = match change? {
             KeyChange::Handshake { keys } => keys,
             KeyChange::OneRtt { keys, next } => {
                 self.next_secrets = Some(next);
@@ -193,6 +251,18 @@ impl crypto::Session for TlsSession {
         self.inner.jls_authed
     }
 
+    fn is_resumed(&self) -> bool {
+        !self.inner.is_handshaking() && !self.server_flight.certificate
+    }
+
+    fn early_data_identity(&self) -> Option<Vec<u8>> {
+        self.client_hello.early_data_identity.clone()
+    }
+
+    fn filter_early_data(&mut self, accept: Box<dyn FnOnce(&[u8]) -> bool + Send>) {
+        self.early_data_filter = Some(accept);
+    }
+
     fn jls_upstream_addr(&self) -> Option<std::net::SocketAddr> {
         match &self.inner {
             Connection::Server(conn) => {
@@ -205,6 +275,171 @@ impl crypto::Session for TlsSession {
     }
 }
 
+/// Follows the TLS handshake messages sent by the server
+///
+/// rustls does not report whether a handshake resumed a previous session, but a TLS 1.3 server
+/// only sends a Certificate message when it authenticates from scratch.
+#[derive(Default)]
+struct ServerFlight {
+    header: [u8; 4],
+    header_len: usize,
+    /// Bytes left in the body of the current message
+    remaining: usize,
+    /// Whether the server sent a Certificate message
+    certificate: bool,
+}
+
+impl ServerFlight {
+    fn observe(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len());
+                self.remaining -= n;
+                data = &data[n..];
+                continue;
+            }
+            self.header[self.header_len] = data[0];
+            self.header_len += 1;
+            data = &data[1..];
+            if self.header_len == self.header.len() {
+                self.header_len = 0;
+                self.certificate |= self.header[0] == HANDSHAKE_TYPE_CERTIFICATE;
+                self.remaining =
+                    u32::from_be_bytes([0, self.header[1], self.header[2], self.header[3]])
+                        as usize;
+            }
+        }
+    }
+}
+
+/// Extracts the PSK identity a client offered alongside early data from its ClientHello
+#[derive(Default)]
+struct ClientHello {
+    /// The ClientHello received so far, and any handshake data after it
+    buf: Vec<u8>,
+    done: bool,
+    early_data_identity: Option<Vec<u8>>,
+}
+
+impl ClientHello {
+    fn observe(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        if self.done {
+            return Ok(());
+        }
+        self.buf.extend_from_slice(data);
+        let len = match client_hello_len(&self.buf)? {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        self.done = true;
+        if self.buf[0] == HANDSHAKE_TYPE_CLIENT_HELLO {
+            self.early_data_identity = parse_early_data_identity(&self.buf[4..4 + len])
+                .ok()
+                .flatten();
+        }
+        Ok(())
+    }
+}
+
+/// Length of the body of the handshake message at the start of `buf`, once all of it is there
+///
+/// Fails for messages longer than rustls accepts, so that a peer can't make a ClientHello being
+/// held back take up to the 16 MiB its length field allows.
+fn client_hello_len(buf: &[u8]) -> Result<Option<usize>, TransportError> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([0, buf[1], buf[2], buf[3]]) as usize;
+    if len > MAX_CLIENT_HELLO_LEN {
+        return Err(TransportError::CRYPTO_BUFFER_EXCEEDED(
+            "ClientHello too long",
+        ));
+    }
+    Ok(match buf.len() < 4 + len {
+        true => None,
+        false => Some(len),
+    })
+}
+
+/// Find the first PSK identity in a ClientHello body that also requests early data
+fn parse_early_data_identity(hello: &[u8]) -> coding::Result<Option<Vec<u8>>> {
+    let mut extensions = hello_extensions(hello)?;
+    let mut early_data = false;
+    let mut identity = None;
+    while !extensions.is_empty() {
+        let ty = extensions.get::<u16>()?;
+        let len = extensions.get::<u16>()?;
+        let mut body = take(&mut extensions, len.into())?;
+        match ty {
+            EXTENSION_EARLY_DATA => early_data = true,
+            EXTENSION_PRE_SHARED_KEY => {
+                let len = body.get::<u16>()?;
+                let mut identities = take(&mut body, len.into())?;
+                let len = identities.get::<u16>()?;
+                identity = Some(take(&mut identities, len.into())?.to_vec());
+            }
+            _ => {}
+        }
+    }
+    Ok(identity.filter(|_| early_data))
+}
+
+/// Find the host name in a ClientHello body's server_name extension
+fn parse_server_name(hello: &[u8]) -> coding::Result<Option<String>> {
+    let mut extensions = hello_extensions(hello)?;
+    while !extensions.is_empty() {
+        let ty = extensions.get::<u16>()?;
+        let len = extensions.get::<u16>()?;
+        let mut body = take(&mut extensions, len.into())?;
+        if ty != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let len = body.get::<u16>()?;
+        let mut names = take(&mut body, len.into())?;
+        while !names.is_empty() {
+            let name_type = names.get::<u8>()?;
+            let len = names.get::<u16>()?;
+            let name = take(&mut names, len.into())?;
+            if name_type == SERVER_NAME_TYPE_HOST_NAME {
+                return Ok(str::from_utf8(name).ok().map(Into::into));
+            }
+        }
+        return Ok(None);
+    }
+    Ok(None)
+}
+
+/// Skip to the extensions of a ClientHello body
+fn hello_extensions(mut hello: &[u8]) -> coding::Result<&[u8]> {
+    take(&mut hello, 2 + 32)?; // legacy_version, random
+    let len = hello.get::<u8>()?;
+    take(&mut hello, len.into())?; // legacy_session_id
+    let len = hello.get::<u16>()?;
+    take(&mut hello, len.into())?; // cipher_suites
+    let len = hello.get::<u8>()?;
+    take(&mut hello, len.into())?; // legacy_compression_methods
+    let len = hello.get::<u16>()?;
+    take(&mut hello, len.into())
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> coding::Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(UnexpectedEnd);
+    }
+    let (x, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(x)
+}
+
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+/// Largest handshake message rustls accepts
+const MAX_CLIENT_HELLO_LEN: usize = 0xffff;
+const HANDSHAKE_TYPE_CERTIFICATE: u8 = 11;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0;
+const EXTENSION_PRE_SHARED_KEY: u16 = 41;
+const EXTENSION_EARLY_DATA: u16 = 42;
+
 const RETRY_INTEGRITY_KEY_DRAFT: [u8; 16] = [
     0xcc, 0xce, 0x18, 0x7e, 0xd0, 0x9a, 0x09, 0xd0, 0x57, 0x28, 0x15, 0x5a, 0x6c, 0xb9, 0x6b, 0xe1,
 ];
@@ -273,6 +508,10 @@ impl crypto::ClientConfig for rustls::ClientConfig {
             version,
             got_handshake_data: false,
             next_secrets: None,
+            server_flight: ServerFlight::default(),
+            client_hello: ClientHello::default(),
+            server_start: None,
+            early_data_filter: None,
             inner: rustls::quic::Connection::Client(
                 rustls::quic::ClientConnection::new(
                     self,
@@ -295,14 +534,7 @@ impl crypto::ServerConfig for rustls::ServerConfig {
         params: &TransportParameters,
     ) -> Box<dyn crypto::Session> {
         let version = interpret_version(version).unwrap();
-        Box::new(TlsSession {
-            version,
-            got_handshake_data: false,
-            next_secrets: None,
-            inner: rustls::quic::Connection::Server(
-                rustls::quic::ServerConnection::new(self, version, to_vec(params)).unwrap(),
-            ),
-        })
+        Box::new(server_session(self, version, to_vec(params)))
     }
 
     fn initial_keys(
@@ -338,6 +570,249 @@ impl crypto::ServerConfig for rustls::ServerConfig {
         result.copy_from_slice(tag.as_ref());
         result
     }
+
+    fn with_jls_config(
+        &self,
+        jls: &rustls::JlsServerConfig,
+    ) -> Option<Arc<dyn crypto::ServerConfig>> {
+        let mut config = self.clone();
+        config.jls_config = jls.clone();
+        Some(Arc::new(config))
+    }
+}
+
+fn server_session(
+    config: Arc<rustls::ServerConfig>,
+    version: Version,
+    params: Vec<u8>,
+) -> TlsSession {
+    TlsSession {
+        version,
+        got_handshake_data: false,
+        next_secrets: None,
+        server_flight: ServerFlight::default(),
+        client_hello: ClientHello::default(),
+        server_start: Some((config.clone(), params.clone())),
+        early_data_filter: None,
+        inner: rustls::quic::Connection::Server(
+            rustls::quic::ServerConnection::new(config, version, params).unwrap(),
+        ),
+    }
+}
+
+/// Serves some server names as a plain QUIC server, and the rest with JLS
+///
+/// Clients whose ClientHello asks for one of the `plain_names` are handled by the `plain`
+/// configuration and never forwarded, whether or not they try to authenticate with JLS. All
+/// others, including those that don't name a server, go through the `jls` configuration, which
+/// authenticates them with JLS or forwards them to its upstream.
+///
+/// This lets one endpoint serve genuine QUIC applications on some names while acting as a JLS
+/// endpoint on the rest.
+pub struct SniJlsServerConfig {
+    plain: Arc<rustls::ServerConfig>,
+    jls: Arc<rustls::ServerConfig>,
+    plain_names: Vec<String>,
+}
+
+impl SniJlsServerConfig {
+    /// Serve `plain_names` with `plain`, and everything else with `jls`
+    ///
+    /// Names are compared without regard to ASCII case.
+    pub fn new<I>(
+        plain: Arc<rustls::ServerConfig>,
+        jls: Arc<rustls::ServerConfig>,
+        plain_names: I,
+    ) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        Self {
+            plain,
+            jls,
+            plain_names: plain_names.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn is_plain(&self, server_name: &str) -> bool {
+        self.plain_names
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(server_name))
+    }
+}
+
+impl crypto::ServerConfig for SniJlsServerConfig {
+    fn start_session(
+        self: Arc<Self>,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Box<dyn crypto::Session> {
+        Box::new(SniJlsSession {
+            version: interpret_version(version).unwrap(),
+            params: to_vec(params),
+            config: self,
+            hello: Vec::new(),
+            early_data_filter: None,
+            inner: None,
+            plain: false,
+        })
+    }
+
+    fn initial_keys(
+        &self,
+        version: u32,
+        dst_cid: &ConnectionId,
+        side: Side,
+    ) -> Result<Keys, UnsupportedVersion> {
+        self.jls.initial_keys(version, dst_cid, side)
+    }
+
+    fn retry_tag(&self, version: u32, orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; 16] {
+        self.jls.retry_tag(version, orig_dst_cid, packet)
+    }
+
+    /// Only the JLS configuration changes; names served plainly stay so
+    fn with_jls_config(
+        &self,
+        jls: &rustls::JlsServerConfig,
+    ) -> Option<Arc<dyn crypto::ServerConfig>> {
+        let mut config = (*self.jls).clone();
+        config.jls_config = jls.clone();
+        Some(Arc::new(Self {
+            plain: self.plain.clone(),
+            jls: Arc::new(config),
+            plain_names: self.plain_names.clone(),
+        }))
+    }
+}
+
+/// Session of a [`SniJlsServerConfig`], which only starts once the ClientHello tells which
+/// configuration to use
+struct SniJlsSession {
+    version: Version,
+    params: Vec<u8>,
+    config: Arc<SniJlsServerConfig>,
+    /// The ClientHello received so far
+    hello: Vec<u8>,
+    early_data_filter: Option<Box<dyn FnOnce(&[u8]) -> bool + Send>>,
+    inner: Option<TlsSession>,
+    /// Whether `inner` uses the plain configuration
+    plain: bool,
+}
+
+impl crypto::Session for SniJlsSession {
+    fn initial_keys(&self, dst_cid: &ConnectionId, side: Side) -> Keys {
+        initial_keys(self.version, dst_cid, side)
+    }
+
+    fn handshake_data(&self) -> Option<Box<dyn Any>> {
+        self.inner.as_ref()?.handshake_data()
+    }
+
+    fn peer_identity(&self) -> Option<Box<dyn Any>> {
+        self.inner.as_ref()?.peer_identity()
+    }
+
+    fn early_crypto(&self) -> Option<(Box<dyn HeaderKey>, Box<dyn crypto::PacketKey>)> {
+        self.inner.as_ref()?.early_crypto()
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        None
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.inner.as_ref().map_or(true, |x| x.is_handshaking())
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
+        if let Some(ref mut inner) = self.inner {
+            return inner.read_handshake(buf);
+        }
+        self.hello.extend_from_slice(buf);
+        let len = match client_hello_len(&self.hello)? {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        self.plain = self.hello[0] == HANDSHAKE_TYPE_CLIENT_HELLO
+            && parse_server_name(&self.hello[4..4 + len])
+                .ok()
+                .flatten()
+                .map_or(false, |x| self.config.is_plain(&x));
+        let config = match self.plain {
+            true => self.config.plain.clone(),
+            false => self.config.jls.clone(),
+        };
+        let inner = self.inner.insert(server_session(
+            config,
+            self.version,
+            mem::take(&mut self.params),
+        ));
+        inner.early_data_filter = self.early_data_filter.take();
+        inner.read_handshake(&mem::take(&mut self.hello))
+    }
+
+    fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
+        match self.inner {
+            Some(ref inner) => inner.transport_parameters(),
+            None => Ok(None),
+        }
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Keys> {
+        self.inner.as_mut()?.write_handshake(buf)
+    }
+
+    fn next_1rtt_keys(&mut self) -> Option<KeyPair<Box<dyn crypto::PacketKey>>> {
+        self.inner.as_mut()?.next_1rtt_keys()
+    }
+
+    fn is_valid_retry(&self, _: &ConnectionId, _: &[u8], _: &[u8]) -> bool {
+        // Only clients receive Retry packets
+        false
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        match self.inner {
+            Some(ref inner) => inner.export_keying_material(output, label, context),
+            None => Err(ExportKeyingMaterialError),
+        }
+    }
+
+    fn is_jls(&self) -> Option<bool> {
+        match self.plain {
+            true => None,
+            false => self.inner.as_ref()?.is_jls(),
+        }
+    }
+
+    fn is_resumed(&self) -> bool {
+        self.inner.as_ref().map_or(false, |x| x.is_resumed())
+    }
+
+    fn early_data_identity(&self) -> Option<Vec<u8>> {
+        self.inner.as_ref()?.early_data_identity()
+    }
+
+    fn filter_early_data(&mut self, accept: Box<dyn FnOnce(&[u8]) -> bool + Send>) {
+        match self.inner {
+            Some(ref mut inner) => inner.filter_early_data(accept),
+            None => self.early_data_filter = Some(accept),
+        }
+    }
+
+    fn jls_upstream_addr(&self) -> Option<SocketAddr> {
+        match self.plain {
+            true => None,
+            false => self.inner.as_ref()?.jls_upstream_addr(),
+        }
+    }
 }
 
 fn to_vec(params: &TransportParameters) -> Vec<u8> {
@@ -431,6 +906,24 @@ pub(crate) fn server_config(
     Ok(cfg)
 }
 
+/// Initialize a sane QUIC-compatible TLS server configuration that obtains its certificates from
+/// `resolver` for every handshake
+///
+/// See [`server_config`] for the QUIC requirements this satisfies.
+pub(crate) fn server_config_with_resolver(
+    resolver: Arc<dyn rustls::server::ResolvesServerCert>,
+) -> rustls::ServerConfig {
+    let mut cfg = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    cfg.max_early_data_size = u32::MAX;
+    cfg
+}
+
 fn interpret_version(version: u32) -> Result<Version, UnsupportedVersion> {
     match version {
         0xff00_001d..=0xff00_0020 => Ok(Version::V1Draft),
@@ -1,11 +1,11 @@
 use std::{
-    collections::{hash_map, HashMap},
+    collections::{hash_map, HashMap, HashSet},
     convert::TryFrom,
-    fmt, iter,
+    fmt, iter, mem,
     net::{IpAddr, SocketAddr},
     ops::{Index, IndexMut},
-    sync::Arc,
-    time::{Instant, SystemTime},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
 use bytes::{BufMut, Bytes, BytesMut};
@@ -16,13 +16,15 @@ use thiserror::Error;
 use tracing::{debug, trace, warn};
 
 use crate::{
+    anti_replay::AntiReplay,
     cid_generator::{ConnectionIdGenerator, RandomConnectionIdGenerator},
     coding::BufMutExt,
-    config::{ClientConfig, EndpointConfig, ServerConfig},
-    connection::{Connection, ConnectionError},
+    config::{ClientConfig, EndpointConfig, ServerConfig, StatelessResetPolicy},
+    connection::{ApplicationStats, Connection, ConnectionError},
     crypto::{self, Keys, UnsupportedVersion},
     frame,
     packet::{Header, Packet, PacketDecodeError, PacketNumber, PartialDecode},
+    prefix_policy::{PrefixPolicy, PrefixVerdict},
     shared::{
         ConnectionEvent, ConnectionEventInner, ConnectionId, EcnCodepoint, EndpointEvent,
         EndpointEventInner, IssuedCid,
@@ -45,6 +47,26 @@ pub struct Endpoint {
     server_config: Option<Arc<ServerConfig>>,
     /// Whether the underlying UDP socket promises not to fragment packets
     allow_mtud: bool,
+    /// Session tickets recently used for 0-RTT by incoming connections
+    anti_replay: Arc<Mutex<AntiReplay>>,
+    /// Application payload totals, which connections add to directly
+    application: Arc<Mutex<ApplicationStats>>,
+    /// Number of incoming connections that have not yet completed their handshake
+    handshaking: usize,
+    /// How readily new incoming connections are admitted
+    admission: Admission,
+    /// Whether a Retry has ever been sent because of `admission` or at the application's request,
+    /// so that the tokens it carried must be honored even after the pressure subsides
+    admission_retried: bool,
+    /// Initial destination CIDs of the [`Incoming`]s the application has yet to decide on
+    ///
+    /// Uses a standard `HashSet` since the keys are chosen by peers.
+    incoming: HashSet<ConnectionId>,
+    /// Which addresses may start connections
+    prefix_policy: PrefixPolicy,
+    stats: EndpointStats,
+    /// When each class of dropped datagram was last logged, and how many went unlogged since
+    drop_log: [(Option<Instant>, u64); DropReason::COUNT],
 }
 
 impl Endpoint {
@@ -66,9 +88,26 @@ impl Endpoint {
             config,
             server_config,
             allow_mtud,
+            anti_replay: Arc::new(Mutex::new(AntiReplay::default())),
+            application: Arc::new(Mutex::new(ApplicationStats::default())),
+            handshaking: 0,
+            admission: Admission::Open,
+            admission_retried: false,
+            incoming: HashSet::new(),
+            prefix_policy: PrefixPolicy::default(),
+            stats: EndpointStats::default(),
+            drop_log: [(None, 0); DropReason::COUNT],
         }
     }
 
+    /// Set whether the underlying UDP socket promises not to fragment packets, affecting new
+    /// connections only
+    ///
+    /// For when the socket is replaced, as `allow_mtud` was given to [`new()`](Self::new).
+    pub fn set_allow_mtud(&mut self, value: bool) {
+        self.allow_mtud = value;
+    }
+
     /// Replace the server configuration, affecting new incoming connections only
     pub fn set_server_config(&mut self, server_config: Option<Arc<ServerConfig>>) {
         self.server_config = server_config;
@@ -104,9 +143,14 @@ impl Endpoint {
                     }
                 }
             }
+            HandshakeComplete => {
+                if mem::replace(&mut self.connections[ch].handshaking, false) {
+                    self.handshaking -= 1;
+                }
+            }
             Drained => {
                 let conn = self.connections.remove(ch.0);
-                self.index.remove(&conn);
+                self.forget(&conn);
             }
         }
         None
@@ -136,6 +180,10 @@ impl Endpoint {
             }) => {
                 if self.server_config.is_none() {
                     debug!("dropping packet with unsupported version");
+                    self.dropped(now, DropReason::Policy);
+                    return None;
+                }
+                if self.denied(now, remote) {
                     return None;
                 }
                 trace!("sending version negotiation");
@@ -162,10 +210,12 @@ impl Endpoint {
                     contents: buf.freeze(),
                     segment_size: None,
                     src_ip: local_ip,
+                    send_at: None,
                 }));
             }
             Err(e) => {
                 trace!("malformed header: {}", e);
+                self.dropped(now, DropReason::Malformed);
                 return None;
             }
         };
@@ -181,6 +231,7 @@ impl Endpoint {
                 ConnectionEvent(ConnectionEventInner::Datagram {
                     now,
                     remote: addresses.remote,
+                    local_ip: addresses.local_ip,
                     ecn,
                     first_decode,
                     remaining,
@@ -192,20 +243,27 @@ impl Endpoint {
         // Potentially create a new connection
         //
 
+        if self.server_config.is_some() && self.denied(now, remote) {
+            return None;
+        }
         let dst_cid = first_decode.dst_cid();
         let server_config = match &self.server_config {
             Some(config) => config,
             None => {
                 debug!("packet for unrecognized connection {}", dst_cid);
-                return self
-                    .stateless_reset(datagram_len, addresses, dst_cid)
-                    .map(DatagramEvent::Response);
+                return self.unknown_cid(now, datagram_len, addresses, dst_cid);
             }
         };
 
         if let Some(version) = first_decode.initial_version() {
             if datagram_len < MIN_INITIAL_SIZE as usize {
                 debug!("ignoring short initial for connection {}", dst_cid);
+                self.dropped(now, DropReason::ShortInitial);
+                return None;
+            }
+            if self.incoming.contains(dst_cid) {
+                // The client will retransmit once the application has decided
+                trace!("ignoring initial for undecided connection {}", dst_cid);
                 return None;
             }
 
@@ -221,15 +279,17 @@ impl Endpoint {
                         "ignoring initial packet version {:#x} unsupported by cryptographic layer",
                         version
                     );
+                    self.dropped(now, DropReason::Policy);
                     return None;
                 }
             };
             return match first_decode.finish(Some(&*crypto.header.remote)) {
                 Ok(packet) => {
-                    self.handle_first_packet(now, addresses, ecn, packet, remaining, &crypto)
+                    self.handle_first_packet(now, addresses, ecn, packet, remaining, crypto)
                 }
                 Err(e) => {
                     trace!("unable to decode initial packet: {}", e);
+                    self.dropped(now, DropReason::Malformed);
                     None
                 }
             };
@@ -238,6 +298,7 @@ impl Endpoint {
                 "ignoring non-initial packet for unknown connection {}",
                 dst_cid
             );
+            self.dropped(now, DropReason::UnknownCid);
             return None;
         }
 
@@ -246,32 +307,126 @@ impl Endpoint {
         // connection. Send a stateless reset if possible.
         //
         if !dst_cid.is_empty() {
-            return self
-                .stateless_reset(datagram_len, addresses, dst_cid)
-                .map(DatagramEvent::Response);
+            return self.unknown_cid(now, datagram_len, addresses, dst_cid);
         }
 
         trace!("dropping unrecognized short packet without ID");
+        self.dropped(now, DropReason::UnknownCid);
         None
     }
 
+    /// Drop a packet for an unknown connection, answering with a stateless reset if possible
+    fn unknown_cid(
+        &mut self,
+        now: Instant,
+        datagram_len: usize,
+        addresses: FourTuple,
+        dst_cid: &ConnectionId,
+    ) -> Option<DatagramEvent> {
+        if self.config.stateless_reset_policy == StatelessResetPolicy::Silent {
+            trace!("not answering packet for unknown connection {}", dst_cid);
+            self.dropped(now, DropReason::UnknownCid);
+            return None;
+        }
+        let response = self.stateless_reset(Some(datagram_len), addresses, dst_cid);
+        // A packet too small to answer is most likely a stateless reset itself, e.g. for a
+        // connection we've already forgotten
+        self.dropped(
+            now,
+            match response {
+                Some(_) => DropReason::UnknownCid,
+                None => DropReason::StatelessReset,
+            },
+        );
+        response.map(DatagramEvent::Response)
+    }
+
+    /// Whether the [`PrefixPolicy`] forbids `remote` from starting connections, counting the
+    /// verdict
+    fn denied(&mut self, now: Instant, remote: SocketAddr) -> bool {
+        match self.prefix_policy.verdict(remote.ip()) {
+            PrefixVerdict::Allow => {
+                self.stats.prefix_allowed += 1;
+                false
+            }
+            PrefixVerdict::Deny => {
+                trace!("dropping packet from {} denied by prefix policy", remote);
+                self.dropped(now, DropReason::Denied);
+                true
+            }
+        }
+    }
+
+    /// Count an incoming datagram that was not delivered to any connection
+    fn dropped(&mut self, now: Instant, reason: DropReason) {
+        let stats = &mut self.stats;
+        *match reason {
+            DropReason::Malformed => &mut stats.malformed,
+            DropReason::UnknownCid => &mut stats.unknown_cid,
+            DropReason::StatelessReset => &mut stats.stateless_reset,
+            DropReason::ShortInitial => &mut stats.short_initial,
+            DropReason::Policy => &mut stats.policy,
+            DropReason::Overloaded => &mut stats.overloaded,
+            DropReason::Denied => &mut stats.prefix_denied,
+        } += 1;
+
+        let (last, unlogged) = &mut self.drop_log[reason as usize];
+        *unlogged += 1;
+        if last.map_or(true, |x| {
+            now.saturating_duration_since(x) >= DROP_LOG_INTERVAL
+        }) {
+            debug!(
+                "dropped {} incoming datagrams: {}",
+                unlogged,
+                reason.describe()
+            );
+            *last = Some(now);
+            *unlogged = 0;
+        }
+    }
+
+    /// Construct a stateless reset for a connection this endpoint no longer knows
+    ///
+    /// `dst_cid` is a connection ID the peer sends packets to, as issued by this endpoint or a
+    /// previous instance of it sharing the same [`reset_key`](EndpointConfig::reset_key), e.g.
+    /// recorded by recovery tooling before a crash. Resets are sent automatically in answer to
+    /// packets for unknown connections; this is for when the application wants to tell the peer
+    /// without waiting for it to send anything. If the length of the datagram that prompted the
+    /// reset is known, the reset is kept smaller than it, and `None` is returned if that's too
+    /// small to answer.
+    pub fn stateless_reset_for(
+        &mut self,
+        remote: SocketAddr,
+        local_ip: Option<IpAddr>,
+        dst_cid: &ConnectionId,
+        inciting_dgram_len: Option<usize>,
+    ) -> Option<Transmit> {
+        self.stateless_reset(inciting_dgram_len, FourTuple { remote, local_ip }, dst_cid)
+    }
+
     fn stateless_reset(
         &mut self,
-        inciting_dgram_len: usize,
+        inciting_dgram_len: Option<usize>,
         addresses: FourTuple,
         dst_cid: &ConnectionId,
     ) -> Option<Transmit> {
         /// Minimum amount of padding for the stateless reset to look like a short-header packet
         const MIN_PADDING_LEN: usize = 5;
+        // Resets with at least this much padding can't possibly be distinguished from real packets
+        const IDEAL_MIN_PADDING_LEN: usize = MIN_PADDING_LEN + MAX_CID_SIZE;
 
-        // Prevent amplification attacks and reset loops by ensuring we pad to at most 1 byte
-        // smaller than the inciting packet.
-        let max_padding_len = match inciting_dgram_len.checked_sub(RESET_TOKEN_SIZE) {
-            Some(headroom) if headroom > MIN_PADDING_LEN => headroom - 1,
-            _ => {
-                debug!("ignoring unexpected {} byte packet: not larger than minimum stateless reset size", inciting_dgram_len);
-                return None;
-            }
+        let max_padding_len = match inciting_dgram_len {
+            // Prevent amplification attacks and reset loops by ensuring we pad to at most 1 byte
+            // smaller than the inciting packet.
+            Some(len) => match len.checked_sub(RESET_TOKEN_SIZE) {
+                Some(headroom) if headroom > MIN_PADDING_LEN => headroom - 1,
+                _ => {
+                    debug!("ignoring unexpected {} byte packet: not larger than minimum stateless reset size", len);
+                    return None;
+                }
+            },
+            // Nothing to answer, so stick to the smallest reset that passes for a real packet
+            None => IDEAL_MIN_PADDING_LEN,
         };
 
         debug!(
@@ -279,8 +434,6 @@ impl Endpoint {
             dst_cid, addresses.remote
         );
         let mut buf = BytesMut::new();
-        // Resets with at least this much padding can't possibly be distinguished from real packets
-        const IDEAL_MIN_PADDING_LEN: usize = MIN_PADDING_LEN + MAX_CID_SIZE;
         let padding_len = if max_padding_len <= IDEAL_MIN_PADDING_LEN {
             max_padding_len
         } else {
@@ -292,7 +445,7 @@ impl Endpoint {
         buf[0] = 0b0100_0000 | buf[0] >> 2;
         buf.extend_from_slice(&ResetToken::new(&*self.config.reset_key, dst_cid));
 
-        debug_assert!(buf.len() < inciting_dgram_len);
+        debug_assert!(inciting_dgram_len.map_or(true, |len| buf.len() < len));
 
         Some(Transmit {
             destination: addresses.remote,
@@ -300,6 +453,7 @@ impl Endpoint {
             contents: buf.freeze(),
             segment_size: None,
             src_ip: addresses.local_ip,
+            send_at: None,
         })
     }
 
@@ -325,18 +479,20 @@ impl Endpoint {
 
         let ch = ConnectionHandle(self.connections.vacant_key());
         let loc_cid = self.new_cid(ch);
-        let params = TransportParameters::new(
+        let mut params = TransportParameters::new(
             &config.transport,
             &self.config,
             self.local_cid_generator.as_ref(),
             loc_cid,
             None,
         );
+        params.custom = config.custom_params.sent.clone();
         let tls = config
             .crypto
             .start_session(config.version, server_name, &params)?;
 
-        let conn = self.add_connection(
+        let now = Instant::now();
+        let mut conn = self.add_connection(
             ch,
             config.version,
             remote_id,
@@ -346,14 +502,107 @@ impl Endpoint {
                 remote,
                 local_ip: None,
             },
-            Instant::now(),
+            now,
             tls,
             None,
             config.transport,
         );
+        if let Some(verifier) = config.post_handshake_verifier {
+            conn.set_post_handshake_verifier(verifier, server_name);
+        }
+        if config.require_jls {
+            conn.require_jls();
+        }
+        if let Some(factory) = config.congestion_controller_factory {
+            conn.override_congestion(factory, now);
+        }
+        conn.override_mtu(config.initial_mtu, config.max_udp_payload_size, now);
+        conn.resume_path(config.initial_rtt, config.initial_window, now);
+        conn.record_custom_params(config.custom_params.recorded);
         Ok((ch, conn))
     }
 
+    /// Start the connection an [`Incoming`] asked for
+    ///
+    /// Yields [`DatagramEvent::NewConnection`], or [`DatagramEvent::NewForward`] for a client
+    /// that fails JLS authentication while forwarding is enabled. The endpoint's limits are
+    /// checked again, since they may have been reached while the application was deciding, in
+    /// which case a [`DatagramEvent::Response`] refuses the connection instead. Yields `None` if
+    /// the connection was dropped, e.g. for failing JLS authentication without forwarding, or if
+    /// the endpoint no longer has a server configuration.
+    pub fn accept(&mut self, incoming: Incoming, now: Instant) -> Option<DatagramEvent> {
+        self.incoming.remove(&incoming.dst_cid);
+        let server_config = match &self.server_config {
+            Some(config) => config.clone(),
+            None => {
+                trace!("dropping accepted connection without a server config");
+                self.dropped(now, DropReason::Policy);
+                return None;
+            }
+        };
+        if self.connections.len() >= server_config.concurrent_connections as usize
+            || self.is_full()
+            || self.handshaking >= server_config.max_concurrent_handshakes as usize
+        {
+            debug!("refusing accepted connection");
+            self.dropped(now, DropReason::Policy);
+            return Some(DatagramEvent::Response(self.initial_close(
+                incoming.version,
+                incoming.addresses,
+                &incoming.crypto,
+                &incoming.src_cid,
+                TransportError::CONNECTION_REFUSED(""),
+            )));
+        }
+        self.start_connection(now, incoming, server_config)
+    }
+
+    /// Refuse the connection an [`Incoming`] asked for, returning the close to send the client
+    pub fn refuse(&mut self, incoming: Incoming, now: Instant) -> Transmit {
+        self.incoming.remove(&incoming.dst_cid);
+        debug!("application refused connection");
+        self.dropped(now, DropReason::Policy);
+        self.initial_close(
+            incoming.version,
+            incoming.addresses,
+            &incoming.crypto,
+            &incoming.src_cid,
+            TransportError::CONNECTION_REFUSED(""),
+        )
+    }
+
+    /// Ask the client behind an [`Incoming`] to prove it can receive at its address, returning
+    /// the Retry to send it
+    ///
+    /// The client's next attempt yields a new [`Incoming`], for which
+    /// [`Incoming::remote_address_validated`] is true. Fails if that's already the case for
+    /// this one, since a client may only be sent a single Retry, or if the endpoint no longer
+    /// has a server configuration.
+    pub fn retry(&mut self, incoming: Incoming) -> Result<Transmit, RetryError> {
+        let server_config = match &self.server_config {
+            Some(config) if !incoming.remote_address_validated() => config.clone(),
+            _ => return Err(RetryError(incoming)),
+        };
+        self.incoming.remove(&incoming.dst_cid);
+        // Tokens must be honored from now on, whatever the configuration says
+        self.admission_retried = true;
+        Ok(self.retry_packet(
+            &server_config,
+            incoming.addresses,
+            incoming.version,
+            &incoming.src_cid,
+            &incoming.dst_cid,
+            &incoming.crypto,
+        ))
+    }
+
+    /// Drop the connection attempt an [`Incoming`] stands for without answering it
+    pub fn ignore(&mut self, incoming: Incoming, now: Instant) {
+        self.incoming.remove(&incoming.dst_cid);
+        trace!("application ignored connection");
+        self.dropped(now, DropReason::Policy);
+    }
+
     fn send_new_identifiers(
         &mut self,
         now: Instant,
@@ -395,7 +644,7 @@ impl Endpoint {
         ecn: Option<EcnCodepoint>,
         mut packet: Packet,
         rest: Option<BytesMut>,
-        crypto: &Keys,
+        crypto: Keys,
     ) -> Option<DatagramEvent> {
         let (src_cid, dst_cid, token, packet_number, version) = match packet.header {
             Header::Initial {
@@ -417,31 +666,57 @@ impl Endpoint {
             .is_err()
         {
             debug!(packet_number, "failed to authenticate initial packet");
+            self.dropped(now, DropReason::Malformed);
             return None;
         };
 
         if !packet.reserved_bits_valid() {
             debug!("dropping connection attempt with invalid reserved bits");
+            self.dropped(now, DropReason::Malformed);
             return None;
         }
 
         let server_config = self.server_config.as_ref().unwrap().clone();
-
-        if self.connections.len() >= server_config.concurrent_connections as usize || self.is_full()
+        let handshakes_full = self.handshaking >= server_config.max_concurrent_handshakes as usize;
+        // Tokens can only have come from a Retry, so a client presenting one was already asked
+        // to come back later
+        let retry_excess = server_config.retry_excess_handshakes && token.is_empty();
+
+        if self.connections.len() >= server_config.concurrent_connections as usize
+            || self.is_full()
+            || (handshakes_full && !retry_excess)
         {
             debug!("refusing connection");
+            self.dropped(now, DropReason::Policy);
+            return Some(DatagramEvent::Response(self.initial_close(
+                version,
+                addresses,
+                &crypto,
+                &src_cid,
+                TransportError::CONNECTION_REFUSED(""),
+            )));
+        }
+        if self.admission == Admission::Refuse {
+            debug!("refusing connection under load");
+            self.dropped(now, DropReason::Overloaded);
             return Some(DatagramEvent::Response(self.initial_close(
                 version,
                 addresses,
-                crypto,
+                &crypto,
                 &src_cid,
                 TransportError::CONNECTION_REFUSED(""),
             )));
         }
 
-        if dst_cid.len() < 8
-            && (!server_config.use_retry || dst_cid.len() != self.local_cid_generator.cid_len())
-        {
+        // Whether a token presented by the client would have come from a Retry we sent
+        let may_retry = server_config.use_retry
+            || server_config.retry_excess_handshakes
+            || self.admission_retried
+            || self.admission == Admission::Validate;
+        // A short DCID is only acceptable if we chose it for a Retry
+        let retried_dcid = dst_cid.len() == self.local_cid_generator.cid_len()
+            && (server_config.use_retry || (may_retry && !token.is_empty()));
+        if dst_cid.len() < 8 && !retried_dcid {
             debug!(
                 "rejecting connection due to invalid DCID length {}",
                 dst_cid.len()
@@ -449,50 +724,31 @@ impl Endpoint {
             return Some(DatagramEvent::Response(self.initial_close(
                 version,
                 addresses,
-                crypto,
+                &crypto,
                 &src_cid,
                 TransportError::PROTOCOL_VIOLATION("invalid destination CID length"),
             )));
         }
 
-        let (retry_src_cid, orig_dst_cid) = if server_config.use_retry {
-            if token.is_empty() {
-                // First Initial
-                let mut random_bytes = vec![0u8; RetryToken::RANDOM_BYTES_LEN];
-                self.rng.fill_bytes(&mut random_bytes);
-                // The peer will use this as the DCID of its following Initials. Initial DCIDs are
-                // looked up separately from Handshake/Data DCIDs, so there is no risk of collision
-                // with established connections. In the unlikely event that a collision occurs
-                // between two connections in the initial phase, both will fail fast and may be
-                // retried by the application layer.
-                let loc_cid = self.local_cid_generator.generate_cid();
-
-                let token = RetryToken {
-                    orig_dst_cid: dst_cid,
-                    issued: SystemTime::now(),
-                    random_bytes: &random_bytes,
+        let (retry_src_cid, orig_dst_cid) = if may_retry {
+            let validate = self.admission == Admission::Validate;
+            if token.is_empty() && !server_config.use_retry && !handshakes_full && !validate {
+                (None, dst_cid)
+            } else if token.is_empty() {
+                if !server_config.use_retry && !(handshakes_full && retry_excess) {
+                    // Only the admission policy calls for this Retry
+                    self.admission_retried = true;
+                    self.stats.admission_retries += 1;
                 }
-                .encode(&*server_config.token_key, &addresses.remote, &loc_cid);
-
-                let header = Header::Retry {
-                    src_cid: loc_cid,
-                    dst_cid: src_cid,
+                // First Initial
+                return Some(DatagramEvent::Response(self.retry_packet(
+                    &server_config,
+                    addresses,
                     version,
-                };
-
-                let mut buf = BytesMut::new();
-                let encode = header.encode(&mut buf);
-                buf.put_slice(&token);
-                buf.extend_from_slice(&server_config.crypto.retry_tag(version, &dst_cid, &buf));
-                encode.finish(&mut buf, &*crypto.header.local, None);
-
-                return Some(DatagramEvent::Response(Transmit {
-                    destination: addresses.remote,
-                    ecn: None,
-                    contents: buf.freeze(),
-                    segment_size: None,
-                    src_ip: addresses.local_ip,
-                }));
+                    &src_cid,
+                    &dst_cid,
+                    &crypto,
+                )));
             }
 
             match RetryToken::from_bytes(
@@ -511,7 +767,7 @@ impl Endpoint {
                     return Some(DatagramEvent::Response(self.initial_close(
                         version,
                         addresses,
-                        crypto,
+                        &crypto,
                         &src_cid,
                         TransportError::INVALID_TOKEN(""),
                     )));
@@ -521,10 +777,52 @@ impl Endpoint {
             (None, dst_cid)
         };
 
+        let incoming = Incoming {
+            addresses,
+            ecn,
+            packet,
+            packet_number,
+            rest,
+            crypto,
+            version,
+            src_cid,
+            dst_cid,
+            orig_dst_cid,
+            retry_src_cid,
+        };
+        if server_config.defer_incoming {
+            trace!(icid = %dst_cid, "deferring connection to the application");
+            self.incoming.insert(dst_cid);
+            return Some(DatagramEvent::Incoming(incoming));
+        }
+        self.start_connection(now, incoming, server_config)
+    }
+
+    /// Start the connection an [`Incoming`] asked for, once it's been decided on
+    fn start_connection(
+        &mut self,
+        now: Instant,
+        incoming: Incoming,
+        server_config: Arc<ServerConfig>,
+    ) -> Option<DatagramEvent> {
+        let Incoming {
+            addresses,
+            ecn,
+            packet,
+            packet_number,
+            rest,
+            crypto,
+            version,
+            src_cid,
+            dst_cid,
+            orig_dst_cid,
+            retry_src_cid,
+        } = incoming;
         let ch = ConnectionHandle(self.connections.vacant_key());
         let loc_cid = self.new_cid(ch);
+        let transport_config = server_config.transport_for(addresses.remote);
         let mut params = TransportParameters::new(
-            &server_config.transport,
+            &transport_config,
             &self.config,
             self.local_cid_generator.as_ref(),
             loc_cid,
@@ -533,9 +831,10 @@ impl Endpoint {
         params.stateless_reset_token = Some(ResetToken::new(&*self.config.reset_key, &loc_cid));
         params.original_dst_cid = Some(orig_dst_cid);
         params.retry_src_cid = retry_src_cid;
+        params.custom = server_config.custom_params.sent.clone();
 
         let tls = server_config.crypto.clone().start_session(version, &params);
-        let transport_config = server_config.transport.clone();
+        let initial_mtu = server_config.initial_mtu;
         let mut conn = self.add_connection(
             ch,
             version,
@@ -548,6 +847,7 @@ impl Endpoint {
             Some(server_config),
             transport_config,
         );
+        conn.override_mtu(initial_mtu, None, now);
         if dst_cid.len() != 0 {
             self.index.insert_initial(dst_cid, ch);
         }
@@ -559,7 +859,12 @@ impl Endpoint {
         match conn.handle_first_packet(now, addresses.remote, ecn, packet_number, packet, rest) {
             Ok(()) => {
                 // Reconstruct client hello to forward to upstream
-                if conn.crypto_session().is_jls() == Some(false) {
+                if conn.crypto_session().is_jls() == Some(false) && !self.config.jls_forwarding {
+                    debug!("dropping client that failed JLS authentication");
+                    let conn_meta = self.connections.remove(ch.0);
+                    self.forget(&conn_meta);
+                    None
+                } else if conn.crypto_session().is_jls() == Some(false) {
                     debug!("start forward connection");
                     let mut buf = BytesMut::default();
                     let partial_encode = packet_clone.header.encode(&mut buf);
@@ -571,7 +876,7 @@ impl Endpoint {
                     );
                     // Remove connection information added by add_connection function
                     let conn_meta = self.connections.remove(ch.0);
-                    self.index.remove(&conn_meta);
+                    self.forget(&conn_meta);
                     Some(DatagramEvent::NewForward(ch, conn, buf))
                 } else {
                     trace!(id = ch.0, icid = %dst_cid, "connection incoming");
@@ -583,7 +888,7 @@ impl Endpoint {
                 self.handle_event(ch, EndpointEvent(EndpointEventInner::Drained));
                 match e {
                     ConnectionError::TransportError(e) => Some(DatagramEvent::Response(
-                        self.initial_close(version, addresses, crypto, &src_cid, e),
+                        self.initial_close(version, addresses, &crypto, &src_cid, e),
                     )),
                     _ => None,
                 }
@@ -591,6 +896,55 @@ impl Endpoint {
         }
     }
 
+    /// Construct a Retry asking the client that sent an Initial to `dst_cid` to prove it can
+    /// receive at its address
+    fn retry_packet(
+        &mut self,
+        server_config: &ServerConfig,
+        addresses: FourTuple,
+        version: u32,
+        src_cid: &ConnectionId,
+        dst_cid: &ConnectionId,
+        crypto: &Keys,
+    ) -> Transmit {
+        let mut random_bytes = vec![0u8; RetryToken::RANDOM_BYTES_LEN];
+        self.rng.fill_bytes(&mut random_bytes);
+        // The peer will use this as the DCID of its following Initials. Initial DCIDs are
+        // looked up separately from Handshake/Data DCIDs, so there is no risk of collision
+        // with established connections. In the unlikely event that a collision occurs
+        // between two connections in the initial phase, both will fail fast and may be
+        // retried by the application layer.
+        let loc_cid = self.local_cid_generator.generate_cid();
+
+        let token = RetryToken {
+            orig_dst_cid: *dst_cid,
+            issued: SystemTime::now(),
+            random_bytes: &random_bytes,
+        }
+        .encode(&*server_config.token_key, &addresses.remote, &loc_cid);
+
+        let header = Header::Retry {
+            src_cid: loc_cid,
+            dst_cid: *src_cid,
+            version,
+        };
+
+        let mut buf = BytesMut::new();
+        let encode = header.encode(&mut buf);
+        buf.put_slice(&token);
+        buf.extend_from_slice(&server_config.crypto.retry_tag(version, dst_cid, &buf));
+        encode.finish(&mut buf, &*crypto.header.local, None);
+
+        Transmit {
+            destination: addresses.remote,
+            ecn: None,
+            contents: buf.freeze(),
+            segment_size: None,
+            src_ip: addresses.local_ip,
+            send_at: None,
+        }
+    }
+
     fn add_connection(
         &mut self,
         ch: ConnectionHandle,
@@ -604,9 +958,17 @@ impl Endpoint {
         server_config: Option<Arc<ServerConfig>>,
         transport_config: Arc<TransportConfig>,
     ) -> Connection {
+        // Only incoming connections count towards `max_concurrent_handshakes`
+        let handshaking = server_config.is_some();
+        let anti_replay = server_config
+            .as_ref()
+            .filter(|x| x.anti_replay_window.is_some())
+            .map(|_| self.anti_replay.clone());
         let conn = Connection::new(
             self.config.clone(),
             server_config,
+            anti_replay,
+            self.application.clone(),
             transport_config,
             init_cid,
             loc_cid,
@@ -620,7 +982,11 @@ impl Endpoint {
             self.allow_mtud,
         );
 
+        if handshaking {
+            self.handshaking += 1;
+        }
         let id = self.connections.insert(ConnectionMeta {
+            handshaking,
             init_cid,
             cids_issued: 0,
             loc_cids: iter::once((0, loc_cid)).collect(),
@@ -672,6 +1038,15 @@ impl Endpoint {
             contents: buf.freeze(),
             segment_size: None,
             src_ip: addresses.local_ip,
+            send_at: None,
+        }
+    }
+
+    /// Drop the routing state of a connection that is going away
+    fn forget(&mut self, conn: &ConnectionMeta) {
+        self.index.remove(conn);
+        if conn.handshaking {
+            self.handshaking -= 1;
         }
     }
 
@@ -687,6 +1062,25 @@ impl Endpoint {
         }
     }
 
+    /// Control how readily new incoming connections are admitted
+    ///
+    /// Lets the application shed load as it falls behind on accepting connections: under
+    /// [`Admission::Validate`], clients must first prove they can receive at their claimed address
+    /// by answering a Retry, which spoofed floods can't do, and under [`Admission::Refuse`] they
+    /// are turned away outright. Defaults to [`Admission::Open`].
+    pub fn set_admission(&mut self, admission: Admission) {
+        self.admission = admission;
+    }
+
+    /// Replace the policy on which addresses may start connections
+    ///
+    /// Datagrams that could start a connection from a denied address are dropped before any
+    /// further work, without an answer. Existing connections are unaffected. Defaults to allowing
+    /// every address.
+    pub fn set_prefix_policy(&mut self, policy: PrefixPolicy) {
+        self.prefix_policy = policy;
+    }
+
     /// Access the configuration used by this endpoint
     pub fn config(&self) -> &EndpointConfig {
         &self.config
@@ -708,11 +1102,26 @@ impl Endpoint {
         self.index.connection_ids.len()
     }
 
+    /// Counts of incoming datagrams that were not delivered to any connection, and of the
+    /// application payload carried by connections
+    pub fn stats(&self) -> EndpointStats {
+        EndpointStats {
+            application: *self.application.lock().unwrap(),
+            ..self.stats
+        }
+    }
+
     /// Access Server Config
     pub fn server_config(&self) -> Option<&ServerConfig> {
         self.server_config.as_ref().map(|x| x.as_ref())
     }
 
+    /// Key material a replacement of this endpoint needs; see [`EndpointConfig::export_keys()`]
+    #[cfg(feature = "ring")]
+    pub fn export_keys(&self) -> Option<crate::SealedKeys> {
+        self.config.export_keys(self.server_config())
+    }
+
     /// Whether we've used up 3/4 of the available CID space
     ///
     /// We leave some space unused so that `new_cid` can be relied upon to finish quickly. We don't
@@ -832,6 +1241,8 @@ impl ConnectionIndex {
 
 #[derive(Debug)]
 pub(crate) struct ConnectionMeta {
+    /// Whether this is an incoming connection that has not completed its handshake yet
+    handshaking: bool,
     init_cid: ConnectionId,
     /// Number of local connection IDs that have been issued in NEW_CONNECTION_ID frames.
     cids_issued: u64,
@@ -869,6 +1280,123 @@ impl IndexMut<ConnectionHandle> for Slab<ConnectionMeta> {
     }
 }
 
+/// Statistics about an [`Endpoint`]
+///
+/// Apart from `admission_retries`, `prefix_allowed` and `application`, each counter is the number of incoming datagrams that were not
+/// delivered to any connection for one reason. A datagram answered by the endpoint itself, e.g.
+/// with a stateless reset or by refusing a connection, still counts as dropped.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct EndpointStats {
+    /// Datagrams that could not be parsed or authenticated
+    pub malformed: u64,
+    /// Packets for a connection this endpoint doesn't know
+    pub unknown_cid: u64,
+    /// Packets for an unknown connection too small to be anything but a stateless reset
+    pub stateless_reset: u64,
+    /// Initial packets in datagrams smaller than the 1200 bytes clients must pad them to
+    pub short_initial: u64,
+    /// Connection attempts refused by configuration, and packets of unsupported versions
+    pub policy: u64,
+    /// Connection attempts refused because of [`Admission::Refuse`]
+    pub overloaded: u64,
+    /// Datagrams that could have started a connection, from addresses the [`PrefixPolicy`]
+    /// denies
+    pub prefix_denied: u64,
+    /// Datagrams that could start a connection, from addresses the [`PrefixPolicy`] allows
+    ///
+    /// Not counted as dropped datagrams, but as the policy's other verdict. Those the endpoint
+    /// went on to drop for another reason are counted again.
+    pub prefix_allowed: u64,
+    /// Connection attempts answered with a Retry because of [`Admission::Validate`]
+    ///
+    /// Not counted as dropped datagrams: clients that answer the Retry are admitted. The
+    /// difference between this and the number of connections admitted after a Retry is roughly
+    /// the number of spoofed or abandoned attempts.
+    pub admission_retries: u64,
+    /// Application payload carried by all of the endpoint's connections, past and present
+    ///
+    /// Connections add their counts when [`Connection::poll_endpoint_events`] is called, so these
+    /// totals trail the connections' own [`ConnectionStats`](crate::ConnectionStats) until then.
+    pub application: ApplicationStats,
+    /// Incoming datagrams the local system likely dropped before they could be received
+    ///
+    /// Never counted by quinn-proto, which can't see them: the I/O layer driving the endpoint
+    /// fills this in from whatever its socket reports, e.g. receive buffer overflows.
+    pub local_receive_drops: u64,
+    /// Outgoing datagrams the local system likely dropped after they were handed to it
+    ///
+    /// Likewise filled in by the I/O layer, e.g. from send errors that were otherwise ignored, or
+    /// that kept recurring.
+    pub local_send_drops: u64,
+    /// ACK-only [`Transmit`]s the I/O layer held back while its send queue was long
+    ///
+    /// Likewise filled in by the I/O layer. Includes those counted in `coalesced_acks`.
+    pub deferred_acks: u64,
+    /// Held-back ACK-only [`Transmit`]s replaced by a newer one from the same connection, and
+    /// thus never sent
+    pub coalesced_acks: u64,
+    /// Bytes currently held by the receive buffers of JLS forward connections
+    ///
+    /// Likewise filled in by the I/O layer. Not a count of datagrams, unlike the rest.
+    pub forward_buffer_bytes: u64,
+    /// Bytes of forwarded datagrams currently waiting to be relayed, in either direction
+    ///
+    /// Likewise filled in by the I/O layer. Bounded by
+    /// [`jls_forward_total_queue_limit`](crate::EndpointConfig::jls_forward_total_queue_limit).
+    pub forward_queued_bytes: u64,
+    /// Responses to datagrams no connection took that were dropped for exceeding the
+    /// [rate](crate::EndpointConfig::response_rate) allowed towards their destination
+    ///
+    /// Likewise filled in by the I/O layer.
+    pub shaped_responses: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum DropReason {
+    Malformed,
+    UnknownCid,
+    StatelessReset,
+    ShortInitial,
+    Policy,
+    Overloaded,
+    Denied,
+}
+
+impl DropReason {
+    const COUNT: usize = 7;
+
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Malformed => "malformed",
+            Self::UnknownCid => "unknown connection ID",
+            Self::StatelessReset => "stateless reset for unknown connection",
+            Self::ShortInitial => "initial packet in undersized datagram",
+            Self::Policy => "refused by policy",
+            Self::Overloaded => "refused under load",
+            Self::Denied => "denied by prefix policy",
+        }
+    }
+}
+
+/// How readily an [`Endpoint`] admits new incoming connections
+///
+/// See [`Endpoint::set_admission()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// Admit connections, subject to the [`ServerConfig`]
+    Open,
+    /// Admit only connections from clients that have answered a Retry, proving they can receive
+    /// at their address
+    Validate,
+    /// Refuse all new connections
+    Refuse,
+}
+
+/// Minimum interval between log messages about dropped datagrams of the same kind
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Event resulting from processing a single datagram
 #[allow(clippy::large_enum_variant)] // Not passed around extensively
 pub enum DatagramEvent {
@@ -881,8 +1409,72 @@ pub enum DatagramEvent {
     /// JLS: Forward connection.
     /// BytesMut is the clienthello to forward
     NewForward(ConnectionHandle, Connection, BytesMut),
+    /// A connection attempt for the application to decide on, when
+    /// [`ServerConfig::defer_incoming`] is enabled
+    Incoming(Incoming),
+}
+
+/// A connection attempt that the endpoint has yet to do any TLS work for
+///
+/// Must be passed to exactly one of [`Endpoint::accept`], [`Endpoint::refuse`],
+/// [`Endpoint::retry`] or [`Endpoint::ignore`]. Until then, further Initials the client sends for
+/// the same connection are dropped.
+pub struct Incoming {
+    addresses: FourTuple,
+    ecn: Option<EcnCodepoint>,
+    /// The decrypted first packet
+    packet: Packet,
+    packet_number: u64,
+    rest: Option<BytesMut>,
+    crypto: Keys,
+    version: u32,
+    src_cid: ConnectionId,
+    dst_cid: ConnectionId,
+    orig_dst_cid: ConnectionId,
+    retry_src_cid: Option<ConnectionId>,
+}
+
+impl Incoming {
+    /// The client's address
+    pub fn remote_address(&self) -> SocketAddr {
+        self.addresses.remote
+    }
+
+    /// The local IP address the connection attempt was received at, if known
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        self.addresses.local_ip
+    }
+
+    /// The destination connection ID of the client's very first Initial
+    ///
+    /// Differs from the one the client is using if it was sent a Retry.
+    pub fn orig_dst_cid(&self) -> &ConnectionId {
+        &self.orig_dst_cid
+    }
+
+    /// Whether the client has proven it can receive at [`remote_address`](Self::remote_address)
+    /// by answering a Retry
+    pub fn remote_address_validated(&self) -> bool {
+        self.retry_src_cid.is_some()
+    }
+}
+
+impl fmt::Debug for Incoming {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Incoming")
+            .field("remote_address", &self.addresses.remote)
+            .field("local_ip", &self.addresses.local_ip)
+            .field("orig_dst_cid", &self.orig_dst_cid)
+            .field("remote_address_validated", &self.remote_address_validated())
+            .finish_non_exhaustive()
+    }
 }
 
+/// Error returned by [`Endpoint::retry`], handing the [`Incoming`] back to be decided on otherwise
+#[derive(Debug, Error)]
+#[error("connection attempt can't be retried")]
+pub struct RetryError(pub Incoming);
+
 /// Errors in the parameters being used to create a new connection
 ///
 /// These arise before any I/O has been performed.
@@ -24,9 +24,10 @@ use std::{
     fmt,
     net::{IpAddr, SocketAddr},
     ops,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+mod anti_replay;
 mod cid_queue;
 #[doc(hidden)]
 pub mod coding;
@@ -43,16 +44,19 @@ pub use varint::{VarInt, VarIntBoundsExceeded};
 
 mod connection;
 pub use crate::connection::{
-    BytesSource, Chunk, Chunks, Connection, ConnectionError, ConnectionStats, Datagrams, Event,
-    FinishError, FrameStats, PathStats, ReadError, ReadableError, RecvStream, RttEstimator,
-    SendDatagramError, SendStream, StreamEvent, Streams, UdpStats, UnknownStream, WriteError,
-    Written,
+    ApplicationStats, BytesSource, Chunk, Chunks, Connection, ConnectionError, ConnectionHealth,
+    ConnectionStats, Datagrams, EcnStats, Event, FinishError, FlowControlStats, FrameStats,
+    HandshakeStats, MigrationPolicy, PathInfo, PathStats, ReadError, ReadableError, RecvStream,
+    RttEstimator, SendDatagramError, SendStream, StreamEvent, Streams, UdpStats, UnknownStream,
+    WriteError, Written,
 };
 
 mod config;
 pub use config::{
-    ClientConfig, ConfigError, EndpointConfig, IdleTimeout, MtuDiscoveryConfig, ServerConfig,
-    TransportConfig,
+    Abort, ClientConfig, ConfigError, EndpointConfig, HandshakeInfo, IdleTimeout,
+    InvalidSealedKeys, JlsClientRedaction, JlsSocks5Proxy, JlsUpstreamSelection,
+    JlsUpstreamTransport, MtuDiscoveryConfig, PostHandshakeVerifier, SealedKeys, ServerConfig,
+    StatelessResetPolicy, TransportConfig,
 };
 
 pub mod crypto;
@@ -62,7 +66,13 @@ use crate::frame::Frame;
 pub use crate::frame::{ApplicationClose, ConnectionClose, Datagram};
 
 mod endpoint;
-pub use crate::endpoint::{ConnectError, ConnectionHandle, DatagramEvent, Endpoint};
+pub use crate::endpoint::{
+    Admission, ConnectError, ConnectionHandle, DatagramEvent, Endpoint, EndpointStats, Incoming,
+    RetryError,
+};
+
+mod prefix_policy;
+pub use crate::prefix_policy::{PrefixPolicy, PrefixVerdict};
 
 mod shared;
 pub use crate::shared::{ConnectionEvent, ConnectionId, EcnCodepoint, EndpointEvent};
@@ -288,8 +298,16 @@ pub struct Transmit {
     pub segment_size: Option<usize>,
     /// Optional source IP address for the datagram
     pub src_ip: Option<IpAddr>,
+    /// When the datagrams may be sent, if pacing holds them back until later
+    ///
+    /// Only set with [`TransportConfig::pacing_horizon()`] enabled. Transmits from the same
+    /// connection must still be sent in the order they were produced.
+    pub send_at: Option<Instant>,
 }
 
+/// Maximum length of a connection ID, in bytes
+pub const MAX_CID_SIZE: usize = 20;
+
 //
 // Useful internal constants
 //
@@ -297,7 +315,6 @@ pub struct Transmit {
 /// The maximum number of CIDs we bother to issue per connection
 const LOC_CID_COUNT: u64 = 8;
 const RESET_TOKEN_SIZE: usize = 16;
-const MAX_CID_SIZE: usize = 20;
 const MIN_INITIAL_SIZE: u16 = 1200;
 /// <https://www.rfc-editor.org/rfc/rfc9000.html#name-datagram-size>
 const INITIAL_MTU: u16 = 1200;
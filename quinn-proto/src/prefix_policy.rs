@@ -0,0 +1,131 @@
+use std::net::IpAddr;
+
+use crate::ConfigError;
+
+/// Which addresses may start connections with an [`Endpoint`](crate::Endpoint)
+///
+/// Each address gets the verdict of the longest prefix containing it, or the default verdict if
+/// none does. IPv4-mapped IPv6 addresses, as dual-stack sockets report IPv4 peers, match IPv4
+/// prefixes. Looking up an address takes at most one step per bit of it, and allocates nothing.
+///
+/// Datagrams of established connections are never subject to the policy, nor are those of JLS
+/// forward connections already set up.
+#[derive(Debug, Clone)]
+pub struct PrefixPolicy {
+    default: PrefixVerdict,
+    v4: Trie,
+    v6: Trie,
+}
+
+impl PrefixPolicy {
+    /// Create a policy giving addresses no prefix matches `default`
+    pub fn new(default: PrefixVerdict) -> Self {
+        Self {
+            default,
+            v4: Trie::default(),
+            v6: Trie::default(),
+        }
+    }
+
+    /// Give addresses whose first `len` bits match `addr` the verdict `verdict`
+    ///
+    /// Replaces any verdict given the same prefix before. Fails if `len` exceeds the length of
+    /// `addr`.
+    pub fn insert(
+        &mut self,
+        addr: IpAddr,
+        len: u8,
+        verdict: PrefixVerdict,
+    ) -> Result<&mut Self, ConfigError> {
+        match addr {
+            IpAddr::V4(x) if len <= 32 => self.v4.insert(u32::from(x).into(), 32, len, verdict),
+            IpAddr::V6(x) if len <= 128 => self.v6.insert(u128::from(x), 128, len, verdict),
+            _ => return Err(ConfigError::OutOfBounds),
+        }
+        Ok(self)
+    }
+
+    /// The verdict on `addr`
+    pub fn verdict(&self, addr: IpAddr) -> PrefixVerdict {
+        let found = match addr {
+            IpAddr::V4(x) => self.v4.find(u32::from(x).into(), 32),
+            IpAddr::V6(x) => match x.to_ipv4_mapped() {
+                Some(x) => self.v4.find(u32::from(x).into(), 32),
+                None => self.v6.find(u128::from(x), 128),
+            },
+        };
+        found.unwrap_or(self.default)
+    }
+}
+
+impl Default for PrefixPolicy {
+    /// Allows every address
+    fn default() -> Self {
+        Self::new(PrefixVerdict::Allow)
+    }
+}
+
+/// Whether a [`PrefixPolicy`] lets an address start connections
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PrefixVerdict {
+    /// Handle connection attempts as usual
+    Allow,
+    /// Drop datagrams that could start a connection, without answering them
+    Deny,
+}
+
+/// A binary trie over the bits of addresses of one family, most significant first
+#[derive(Debug, Clone)]
+struct Trie {
+    /// The root first; children are never the root, so index 0 stands for no child
+    nodes: Vec<Node>,
+}
+
+impl Trie {
+    fn insert(&mut self, addr: u128, bits: u32, len: u8, verdict: PrefixVerdict) {
+        let mut node = 0;
+        for i in 0..u32::from(len) {
+            let bit = (addr >> (bits - 1 - i)) as usize & 1;
+            node = match self.nodes[node].children[bit] {
+                0 => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+        self.nodes[node].verdict = Some(verdict);
+    }
+
+    /// The verdict of the longest prefix containing `addr`, if any
+    fn find(&self, addr: u128, bits: u32) -> Option<PrefixVerdict> {
+        let mut node = &self.nodes[0];
+        let mut found = node.verdict;
+        for i in 0..bits {
+            let bit = (addr >> (bits - 1 - i)) as usize & 1;
+            node = match node.children[bit] {
+                0 => break,
+                child => &self.nodes[child as usize],
+            };
+            found = node.verdict.or(found);
+        }
+        found
+    }
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node::default()],
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Node {
+    children: [u32; 2],
+    /// Set if a prefix ends here
+    verdict: Option<PrefixVerdict>,
+}
@@ -1,4 +1,8 @@
-use std::{fmt, net::SocketAddr, time::Instant};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
 
 use bytes::{Buf, BufMut, BytesMut};
 
@@ -14,6 +18,8 @@ pub(crate) enum ConnectionEventInner {
     Datagram {
         now: Instant,
         remote: SocketAddr,
+        /// The local IP address the datagram was received on, if known
+        local_ip: Option<IpAddr>,
         ecn: Option<EcnCodepoint>,
         first_decode: PartialDecode,
         remaining: Option<BytesMut>,
@@ -47,6 +53,8 @@ impl EndpointEvent {
 pub(crate) enum EndpointEventInner {
     /// The connection has been drained
     Drained,
+    /// An incoming connection completed its handshake
+    HandshakeComplete,
     /// The reset token and/or address eligible for generating resets has been updated
     ResetToken(SocketAddr, ResetToken),
     /// The connection needs connection identifiers
@@ -1,6 +1,6 @@
 use std::{
     convert::TryInto,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -187,6 +187,62 @@ fn server_stateless_reset() {
     );
 }
 
+#[test]
+fn dropped_datagram_stats() {
+    let _guard = subscribe();
+    let client_addr = "[::2]:7890".parse().unwrap();
+    let mut server = Endpoint::new(Default::default(), Some(Arc::new(server_config())), true);
+    let now = Instant::now();
+
+    // Too short to hold the CID of a short-header packet
+    let mut junk = [0; 4];
+    rand::thread_rng().fill_bytes(&mut junk);
+    junk[0] &= 0x7f;
+    assert!(server
+        .handle(now, client_addr, None, None, junk[..].into())
+        .is_none());
+    assert_eq!(server.stats().malformed, 1);
+
+    // Short-header packets for a connection the server never heard of
+    let mut packet = vec![0x40];
+    packet.extend_from_slice(&[0xab; 8]);
+    packet.resize(64, 0);
+    assert_matches!(
+        server.handle(now, client_addr, None, None, packet[..].into()),
+        Some(DatagramEvent::Response(_))
+    );
+    packet.truncate(20);
+    assert!(server
+        .handle(now, client_addr, None, None, packet[..].into())
+        .is_none());
+
+    let stats = server.stats();
+    assert_eq!(stats.unknown_cid, 1);
+    assert_eq!(stats.stateless_reset, 1);
+    assert_eq!(stats.malformed, 1);
+    assert_eq!(stats.short_initial, 0);
+    assert_eq!(stats.policy, 0);
+}
+
+#[test]
+fn silent_stateless_reset_policy() {
+    let _guard = subscribe();
+    let mut config = EndpointConfig::default();
+    config.stateless_reset_policy(StatelessResetPolicy::Silent);
+    let mut server = Endpoint::new(Arc::new(config), Some(Arc::new(server_config())), true);
+    let client_addr = "[::2]:7890".parse().unwrap();
+    let now = Instant::now();
+
+    // Large enough to answer, were resets allowed
+    let mut packet = vec![0x40];
+    packet.extend_from_slice(&[0xab; 8]);
+    packet.resize(64, 0);
+    assert!(server
+        .handle(now, client_addr, None, None, packet[..].into())
+        .is_none());
+    assert_eq!(server.stats().unknown_cid, 1);
+}
+
 #[test]
 fn client_stateless_reset() {
     let _guard = subscribe();
@@ -311,6 +367,33 @@ fn reset_stream() {
     assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
 }
 
+#[test]
+fn received_reset() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+    pair.client_send(client_ch, s).write(b"hello").unwrap();
+    pair.drive();
+    assert_matches!(pair.server_recv(server_ch, s).received_reset(), Ok(None));
+
+    const ERROR: VarInt = VarInt(42);
+    pair.client_send(client_ch, s).reset(ERROR).unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.server_recv(server_ch, s).received_reset(),
+        Ok(Some(ERROR))
+    );
+
+    // Observing the reset doesn't consume it
+    let mut recv = pair.server_recv(server_ch, s);
+    let mut chunks = recv.read(false).unwrap();
+    assert_matches!(chunks.next(usize::MAX), Err(ReadError::Reset(ERROR)));
+    let _ = chunks.finalize();
+    assert!(pair.server_recv(server_ch, s).received_reset().is_err());
+}
+
 #[test]
 fn stop_stream() {
     let _guard = subscribe();
@@ -511,6 +594,87 @@ fn zero_rtt_happypath() {
     assert_eq!(pair.client_conn_mut(client_ch).lost_packets(), 0);
 }
 
+#[test]
+fn oversized_client_hello() {
+    let _guard = subscribe();
+    let plain = Arc::new(server_crypto());
+    let sni = crypto::rustls::SniJlsServerConfig::new(plain.clone(), plain.clone(), ["api.test"]);
+    let configs: [Arc<dyn crypto::ServerConfig>; 2] = [plain, Arc::new(sni)];
+    let params = transport_parameters::TransportParameters::default();
+    for config in configs {
+        // As long as rustls allows: held back until the rest arrives
+        let mut session = config.clone().start_session(1, &params);
+        assert!(!session.read_handshake(&[1, 0x00, 0xff, 0xff]).unwrap());
+
+        // Longer: refused before any more of it is buffered
+        let mut session = config.start_session(1, &params);
+        let err = session.read_handshake(&[1, 0xff, 0xff, 0xff]).unwrap_err();
+        assert_eq!(err.code, TransportErrorCode::CRYPTO_BUFFER_EXCEEDED);
+    }
+}
+
+#[test]
+fn zero_rtt_replay() {
+    let _guard = subscribe();
+    let mut server_crypto = server_crypto();
+    // Stateless tickets may be redeemed any number of times, leaving replay detection to us
+    server_crypto.ticketer = rustls::Ticketer::new().unwrap();
+    let mut server_config = ServerConfig::with_crypto(Arc::new(server_crypto));
+    server_config.anti_replay_window(Some(Duration::from_secs(60)));
+    let mut pair = Pair::new(Default::default(), server_config);
+    let config = client_config();
+
+    // Establish normal connection
+    let client_ch = pair.begin_connect(config.clone());
+    pair.drive();
+    pair.server.assert_accept();
+    pair.client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .close(pair.time, VarInt(0), [][..].into());
+    pair.drive();
+
+    info!("resuming session");
+    let client_ch = pair.begin_connect(config);
+    assert!(pair.client_conn_mut(client_ch).has_0rtt());
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    pair.client_send(client_ch, s).write(MSG).unwrap();
+    pair.drive_client();
+    let flight = pair.server.inbound.clone();
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert!(pair.server_conn_mut(server_ch).accepted_0rtt());
+    assert!(pair
+        .server_conn_mut(server_ch)
+        .crypto_session()
+        .early_data_identity()
+        .is_some());
+    pair.client
+        .connections
+        .get_mut(&client_ch)
+        .unwrap()
+        .close(pair.time, VarInt(0), [][..].into());
+    pair.drive();
+
+    info!("replaying 0-RTT flight");
+    let now = pair.time;
+    for (_, ecn, packet) in flight {
+        pair.server.inbound.push_back((now, ecn, packet));
+    }
+    pair.drive_server();
+    let replay_ch = pair.server.assert_accept();
+    assert_ne!(replay_ch, server_ch);
+    let conn = pair.server_conn_mut(replay_ch);
+    assert!(!conn.accepted_0rtt());
+    // Refused in the TLS handshake, so the client would see its 0-RTT rejected
+    assert!(conn.crypto_session().early_crypto().is_none());
+    while let Some(event) = conn.poll() {
+        assert_matches!(event, Event::HandshakeDataReady);
+    }
+}
+
 #[test]
 fn zero_rtt_rejection() {
     let _guard = subscribe();
@@ -972,6 +1136,122 @@ fn idle_timeout() {
     );
 }
 
+#[test]
+fn handshake_duration() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_millis(50);
+    let (client_ch, server_ch) = pair.connect();
+
+    // Both sides complete the handshake one round trip after their first packet
+    let client = pair.client_conn_mut(client_ch).stats().handshake;
+    let server = pair.server_conn_mut(server_ch).stats().handshake;
+    for x in [client.duration.unwrap(), server.duration.unwrap()] {
+        assert!(x >= Duration::from_millis(100), "{x:?} too low");
+        assert!(x < Duration::from_millis(110), "{x:?} too high");
+    }
+    // The client's connection is created moments before the simulated clock starts advancing
+    let ready = client.time_to_ready.unwrap();
+    assert!(ready > Duration::from_millis(90), "{ready:?} too low");
+    assert!(ready < Duration::from_millis(110), "{ready:?} too high");
+    assert_eq!(server.time_to_ready, server.duration);
+}
+
+#[test]
+fn ping_rtt() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_millis(50);
+    let (client_ch, _) = pair.connect();
+    while pair.client_conn_mut(client_ch).poll().is_some() {}
+    let pings = pair.client_conn_mut(client_ch).stats().frame_tx.ping;
+
+    // Requests made before the first PING is acknowledged share a single sample
+    pair.client_conn_mut(client_ch).ping_rtt();
+    pair.drive_client();
+    pair.client_conn_mut(client_ch).ping_rtt();
+    pair.drive();
+
+    assert_eq!(
+        pair.client_conn_mut(client_ch).stats().frame_tx.ping,
+        pings + 1
+    );
+    let rtt = match pair.client_conn_mut(client_ch).poll() {
+        Some(Event::PingAcknowledged { rtt }) => rtt,
+        e => panic!("unexpected event: {e:?}"),
+    };
+    assert!(rtt >= Duration::from_millis(100), "rtt {rtt:?} too low");
+    assert!(rtt < Duration::from_millis(110), "rtt {rtt:?} too high");
+    assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
+
+    // Once acknowledged, a new request sends a new PING
+    pair.client_conn_mut(client_ch).ping_rtt();
+    pair.drive();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).stats().frame_tx.ping,
+        pings + 2
+    );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::PingAcknowledged { .. })
+    );
+}
+
+#[test]
+fn loss_stats() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    let ptos = pair.client_conn_mut(client_ch).stats().path.pto_events;
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+
+    // Drop a packet followed by enough data for it to be declared lost
+    pair.client_send(client_ch, s).write(b"first").unwrap();
+    pair.client.drive(pair.time, pair.server.addr);
+    pair.client.outbound.clear();
+    pair.client_send(client_ch, s).write(&[0; 8000]).unwrap();
+    pair.drive();
+
+    let stats = pair.client_conn_mut(client_ch).stats();
+    assert!(stats.path.lost_packets > 0);
+    assert_eq!(stats.path.spurious_lost_packets, 0);
+    assert_eq!(stats.path.pto_events, ptos);
+    assert!(stats.path.pto > Duration::ZERO);
+
+    // Drop the tail of the transmission, so that only a PTO can recover it
+    pair.client_send(client_ch, s).write(b"tail").unwrap();
+    pair.client.drive(pair.time, pair.server.addr);
+    pair.client.outbound.clear();
+    pair.drive();
+
+    let stats = pair.client_conn_mut(client_ch).stats();
+    assert!(stats.path.pto_events > ptos);
+    assert_eq!(stats.path.spurious_lost_packets, 0);
+}
+
+#[test]
+fn spurious_loss_stats() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, _) = pair.connect();
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+
+    // Reorder a packet behind enough later packets for it to be declared lost
+    pair.client_send(client_ch, s).write(b"first").unwrap();
+    pair.client.drive(pair.time, pair.server.addr);
+    pair.client.delay_outbound();
+    pair.client_send(client_ch, s).write(&[0; 8000]).unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    pair.client.finish_delay();
+    pair.drive();
+
+    let stats = pair.client_conn_mut(client_ch).stats();
+    assert!(stats.path.lost_packets > 0);
+    assert!(stats.path.spurious_lost_packets > 0);
+    assert!(stats.path.spurious_lost_packets <= stats.path.lost_packets);
+}
+
 #[test]
 fn connection_close_sends_acks() {
     let _guard = subscribe();
@@ -1066,6 +1346,54 @@ fn migration() {
     );
 }
 
+#[test]
+fn migration_policy() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let old_addr = pair.client.addr;
+    pair.server_conn_mut(server_ch)
+        .set_migration_policy(MigrationPolicy::Disallow);
+    pair.client.addr = SocketAddr::new(
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+    );
+    let new_addr = pair.client.addr;
+
+    // Datagrams from the new address are ignored
+    pair.client_conn_mut(client_ch).ping();
+    pair.drive_client();
+    pair.drive_server();
+    let path = pair.server_conn_mut(server_ch).current_path();
+    assert_eq!(path.remote_addr, old_addr);
+    assert!(path.validated);
+
+    // Once the application confirms the new address, the retransmitted PING moves the connection
+    pair.server_conn_mut(server_ch)
+        .set_migration_policy(MigrationPolicy::ConfirmVia(Arc::new(move |addr| {
+            addr == new_addr
+        })));
+    pair.drive();
+    assert_matches!(pair.client_conn_mut(client_ch).poll(), None);
+    let path = pair.server_conn_mut(server_ch).current_path();
+    assert_eq!(path.remote_addr, new_addr);
+    assert!(path.validated);
+}
+
+#[test]
+fn local_address_changed_during_handshake() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+
+    // Nothing is sent for a connection that isn't established yet
+    let now = pair.time;
+    let conn = pair.client_conn_mut(client_ch);
+    conn.local_address_changed();
+    assert!(conn.poll_transmit(now, 1).is_none());
+}
+
 fn test_flow_control(config: TransportConfig, window_size: usize) {
     let _guard = subscribe();
     let mut pair = Pair::new(
@@ -1187,6 +1515,133 @@ fn conn_flow_control() {
     );
 }
 
+/// Simulated time needed to transfer `size` bytes over a 100ms round trip
+fn timed_transfer(config: TransportConfig, size: usize) -> (Duration, ConnectionStats) {
+    let mut pair = Pair::new(
+        Default::default(),
+        ServerConfig {
+            transport: Arc::new(config),
+            ..server_config()
+        },
+    );
+    pair.latency = Duration::from_millis(50);
+    let (client_ch, server_ch) = pair.connect();
+    let start = pair.time;
+
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+    let msg = vec![0xab; size];
+    let mut written = 0;
+    let mut read = 0;
+    while read < size {
+        if written < size {
+            written += pair
+                .client_send(client_ch, s)
+                .write(&msg[written..])
+                .unwrap_or(0);
+        }
+        let active = pair.step();
+        let mut recv = pair.server_recv(server_ch, s);
+        if let Ok(mut chunks) = recv.read(true) {
+            while let Ok(Some(chunk)) = chunks.next(usize::MAX) {
+                read += chunk.bytes.len();
+            }
+            let _ = chunks.finalize();
+        }
+        assert!(active || read == size, "transfer stalled");
+    }
+    (pair.time - start, pair.server_conn_mut(server_ch).stats())
+}
+
+#[test]
+fn receive_window_auto_tuning() {
+    let _guard = subscribe();
+    const SIZE: usize = 4 * 1024 * 1024;
+    const WINDOW: u32 = 32 * 1024;
+    let config = |auto_tuning| TransportConfig {
+        stream_receive_window: WINDOW.into(),
+        receive_window_auto_tuning: auto_tuning,
+        max_stream_receive_window: (64 * WINDOW).into(),
+        ..TransportConfig::default()
+    };
+
+    // A fixed window can deliver at most one window per round trip
+    let (fixed, stats) = timed_transfer(config(false), SIZE);
+    assert_eq!(stats.flow_control.stream_receive_window, u64::from(WINDOW));
+    assert!(fixed >= Duration::from_millis(100) * (SIZE as u32 / WINDOW - 1));
+
+    let (tuned, stats) = timed_transfer(config(true), SIZE);
+    assert!(stats.flow_control.stream_receive_window > u64::from(WINDOW));
+    assert!(stats.flow_control.stream_receive_window <= u64::from(64 * WINDOW));
+    assert!(
+        tuned * 3 < fixed,
+        "auto-tuned transfer took {tuned:?}, fixed window took {fixed:?}"
+    );
+}
+
+#[test]
+fn send_coalesce_delay() {
+    let _guard = subscribe();
+    const DELAY: Duration = Duration::from_millis(5);
+    const MSG: &[u8] = b"request";
+    const COUNT: usize = 10;
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+    let stream_frames = |pair: &mut Pair| pair.client_conn_mut(client_ch).stats().frame_tx.stream;
+
+    // Without a delay, every write is sent in its own packet
+    let before = stream_frames(&mut pair);
+    for _ in 0..COUNT {
+        pair.client_send(client_ch, s).write(MSG).unwrap();
+        pair.drive_client();
+    }
+    assert_eq!(stream_frames(&mut pair) - before, COUNT as u64);
+    pair.drive();
+
+    // With a delay, writes made in quick succession share a packet
+    pair.client_conn_mut(client_ch)
+        .set_send_coalesce_delay(Some(DELAY));
+    let before = stream_frames(&mut pair);
+    let start = pair.time;
+    for _ in 0..COUNT {
+        pair.client_send(client_ch, s).write(MSG).unwrap();
+        pair.drive_client();
+    }
+    assert_eq!(stream_frames(&mut pair), before);
+    loop {
+        let now = pair.time;
+        assert!(pair.step());
+        if stream_frames(&mut pair) != before {
+            assert!(now - start <= DELAY, "data held for {:?}", now - start);
+            break;
+        }
+    }
+    pair.drive();
+    assert_eq!(stream_frames(&mut pair) - before, 1);
+
+    let mut recv = pair.server_recv(server_ch, s);
+    let mut chunks = recv.read(true).unwrap();
+    let mut read = 0;
+    while let Ok(Some(chunk)) = chunks.next(usize::MAX) {
+        read += chunk.bytes.len();
+    }
+    let _ = chunks.finalize();
+    assert_eq!(read, 2 * COUNT * MSG.len());
+
+    // Flushing and finishing send immediately
+    let before = stream_frames(&mut pair);
+    pair.client_send(client_ch, s).write(MSG).unwrap();
+    pair.drive_client();
+    assert_eq!(stream_frames(&mut pair), before);
+    pair.client_send(client_ch, s).flush();
+    pair.drive_client();
+    assert_eq!(stream_frames(&mut pair) - before, 1);
+    pair.client_send(client_ch, s).write(MSG).unwrap();
+    pair.client_send(client_ch, s).finish().unwrap();
+    pair.drive_client();
+    assert_eq!(stream_frames(&mut pair) - before, 2);
+}
+
 #[test]
 fn stop_opens_bidi() {
     let _guard = subscribe();
@@ -1948,6 +2403,61 @@ fn connect_lost_mtu_probes_do_not_trigger_congestion_control() {
     assert_eq!(server_stats.path.congestion_events, 0);
 }
 
+#[test]
+fn ecn_ce_marks() {
+    let _guard = subscribe();
+    let transfer = |ce_every| {
+        let mut pair = Pair::default();
+        pair.ce_every = ce_every;
+        let (client_ch, server_ch) = pair.connect();
+        let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+        pair.client_send(client_ch, s)
+            .write(&[0; 64 * 1024])
+            .unwrap();
+        pair.drive();
+        let client_stats = pair.client_conn_mut(client_ch).stats();
+        let server_stats = pair.server_conn_mut(server_ch).stats();
+        (client_stats, server_stats)
+    };
+
+    let (client_stats, server_stats) = transfer(None);
+    assert!(server_stats.ecn.received_ect0 > 0);
+    assert_eq!(server_stats.ecn.received_ce, 0);
+    assert_eq!(client_stats.ecn.peer_ce, 0);
+    assert_eq!(client_stats.path.congestion_events, 0);
+    let unmarked_cwnd = client_stats.path.cwnd;
+
+    let (client_stats, server_stats) = transfer(Some(4));
+    assert!(server_stats.ecn.received_ect0 > 0);
+    assert!(server_stats.ecn.received_ce > 0);
+    assert!(client_stats.ecn.peer_ce > 0);
+    assert!(client_stats.path.congestion_events > 0);
+    assert!(client_stats.path.cwnd < unmarked_cwnd);
+}
+
+#[test]
+fn ecn_ect1() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let mut config = client_config();
+    config.transport = Arc::new(TransportConfig {
+        send_ect1: true,
+        ..TransportConfig::default()
+    });
+    let (client_ch, server_ch) = pair.connect_with(config);
+    pair.drive();
+
+    assert!(pair.client_conn_mut(client_ch).using_ecn());
+    let client_stats = pair.client_conn_mut(client_ch).stats();
+    let server_stats = pair.server_conn_mut(server_ch).stats();
+    assert!(server_stats.ecn.received_ect1 > 0);
+    assert_eq!(server_stats.ecn.received_ect0, 0);
+    assert!(client_stats.ecn.peer_ect1 > 0);
+    assert_eq!(client_stats.ecn.peer_ect0, 0);
+    // The server still marks its packets ECT(0)
+    assert!(client_stats.ecn.received_ect0 > 0);
+}
+
 #[test]
 fn connect_detects_mtu() {
     let _guard = subscribe();
@@ -1966,12 +2476,82 @@ fn connect_detects_mtu() {
 }
 
 #[test]
-fn migrate_detects_new_mtu_and_respects_original_peer_max_udp_payload_size() {
+fn per_connection_mtu_overrides() {
     let _guard = subscribe();
+    // The harness can't keep apart concurrent connections on one endpoint, so each gets a pair
+    let mut tunneled_pair = Pair::default();
+    tunneled_pair.mtu = 1500;
+    let mut tunneled = client_config();
+    tunneled.max_udp_payload_size(1300);
+    let (tunneled_ch, _) = tunneled_pair.connect_with(tunneled);
+    tunneled_pair.drive();
 
-    let client_max_udp_payload_size: u16 = 1400;
-
-    // Set up a client with a max payload size of 1400 (and use the defaults for the server)
+    let mut pair = Pair::default();
+    pair.mtu = 1500;
+    let mut clean = client_config();
+    clean.initial_mtu(1400);
+    let (clean_ch, clean_server_ch) = pair.connect_with(clean);
+    pair.drive();
+
+    // Both connections use the default transport config, but probe up to different ceilings
+    assert_eq!(tunneled_pair.client_conn_mut(tunneled_ch).path_mtu(), 1300);
+    assert_eq!(pair.client_conn_mut(clean_ch).path_mtu(), 1452);
+    assert_eq!(
+        tunneled_pair
+            .client_conn_mut(tunneled_ch)
+            .stats()
+            .path
+            .sent_plpmtud_probes,
+        3
+    );
+    assert_eq!(
+        pair.client_conn_mut(clean_ch)
+            .stats()
+            .path
+            .sent_plpmtud_probes,
+        2
+    );
+
+    // The ceiling can be lowered at runtime, on either side
+    pair.client_conn_mut(clean_ch)
+        .set_max_udp_payload_size(1280);
+    pair.server_conn_mut(clean_server_ch)
+        .set_max_udp_payload_size(1250);
+    pair.drive();
+    assert_eq!(pair.client_conn_mut(clean_ch).path_mtu(), 1280);
+    assert_eq!(pair.server_conn_mut(clean_server_ch).path_mtu(), 1250);
+
+    // But never raised
+    pair.client_conn_mut(clean_ch)
+        .set_max_udp_payload_size(1452);
+    pair.drive();
+    assert_eq!(pair.client_conn_mut(clean_ch).path_mtu(), 1280);
+}
+
+#[test]
+fn server_initial_mtu() {
+    let _guard = subscribe();
+    let mut transport = TransportConfig::default();
+    transport.mtu_discovery_config(None);
+    let mut server_config = server_config();
+    server_config
+        .transport_config(Arc::new(transport))
+        .initial_mtu(1400);
+    let mut pair = Pair::new(Default::default(), server_config);
+    pair.mtu = 1500;
+    let (_, server_ch) = pair.connect();
+
+    // Without discovery, the server keeps the MTU it started from
+    assert_eq!(pair.server_conn_mut(server_ch).path_mtu(), 1400);
+}
+
+#[test]
+fn migrate_detects_new_mtu_and_respects_original_peer_max_udp_payload_size() {
+    let _guard = subscribe();
+
+    let client_max_udp_payload_size: u16 = 1400;
+
+    // Set up a client with a max payload size of 1400 (and use the defaults for the server)
     let server_endpoint_config = EndpointConfig::default();
     let server = Endpoint::new(
         Arc::new(server_endpoint_config),
@@ -2206,3 +2786,708 @@ fn reject_new_connections() {
     pair.server.assert_no_accept();
     assert!(pair.client.connections.get(&client_ch).unwrap().is_closed());
 }
+
+#[test]
+fn max_concurrent_handshakes() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    server_config.max_concurrent_handshakes(2);
+    let mut pair = Pair::new(Default::default(), server_config.clone());
+
+    // The harness hands an endpoint's events to whichever of its connections it drives first, so
+    // connections that are done with are taken out of it, leaving the endpoints to track them
+
+    // Established connections don't count towards the limit
+    let (client_ch, server_ch) = pair.connect();
+    pair.client.connections.remove(&client_ch);
+    pair.server.connections.remove(&server_ch);
+
+    // Stall two handshakes by never delivering the server's response
+    for _ in 0..2 {
+        let client_ch = pair.begin_connect(client_config());
+        pair.drive_client();
+        pair.drive_server();
+        let server_ch = pair.server.assert_accept();
+        pair.client.inbound.clear();
+        pair.client.connections.remove(&client_ch);
+        pair.server.connections.remove(&server_ch);
+    }
+
+    // A third client is asked to retry...
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    pair.server.assert_no_accept();
+    assert_eq!(pair.client.inbound.len(), 1);
+    let first_byte = pair.client.inbound[0].2[0];
+    assert_eq!(first_byte & 0xf0, 0xf0, "expected a Retry packet");
+
+    // ...and refused when it comes back while the handshakes are still in progress
+    pair.drive_client();
+    pair.drive_server();
+    pair.drive_client();
+    pair.server.assert_no_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                ..
+            }),
+        })
+    );
+
+    // Without retries, excess attempts are refused outright
+    pair.client.connections.remove(&client_ch);
+    server_config.retry_excess_handshakes(false);
+    pair.server.set_server_config(Some(Arc::new(server_config)));
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    pair.drive_client();
+    pair.server.assert_no_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                ..
+            }),
+        })
+    );
+}
+
+#[test]
+fn transport_selector() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    server_config.transport_selector(Arc::new(|remote| {
+        // Pretend IPv4 clients are on the local network
+        let mut transport = TransportConfig::default();
+        let window = if remote.is_ipv4() { 20_000u32 } else { 10_000 };
+        transport.receive_window(window.into());
+        Arc::new(transport)
+    }));
+
+    for (ip, window) in [
+        (IpAddr::from(Ipv6Addr::LOCALHOST), 10_000),
+        (IpAddr::from(Ipv4Addr::LOCALHOST), 20_000),
+    ] {
+        let mut pair = Pair::new(Default::default(), server_config.clone());
+        pair.client.addr = SocketAddr::new(ip, CLIENT_PORTS.lock().unwrap().next().unwrap());
+        let (client_ch, _) = pair.connect();
+
+        // The client may send exactly as much as the server's initial_max_data allows
+        let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+        assert_eq!(
+            pair.client_send(client_ch, s).write(&[0; 100_000]).unwrap(),
+            window
+        );
+    }
+}
+
+#[test]
+fn application_byte_split() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    const STREAM: &[u8] = &[0xAB; 3000];
+    const DATAGRAM: &[u8] = &[0xCD; 100];
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+    assert_eq!(
+        pair.client_send(client_ch, s).write(STREAM).unwrap(),
+        STREAM.len()
+    );
+    pair.client_send(client_ch, s).finish().unwrap();
+    pair.client_datagrams(client_ch)
+        .send(DATAGRAM.into())
+        .unwrap();
+
+    // Lose the first flight, so the stream data must be retransmitted and the datagram is gone
+    pair.drive_client();
+    pair.server.inbound.clear();
+    pair.drive();
+    let stats = pair.client_conn_mut(client_ch).stats();
+    assert!(stats.path.lost_packets > 0);
+    assert_eq!(stats.application.stream_bytes_sent, STREAM.len() as u64);
+    assert_eq!(stats.application.datagram_bytes_sent, DATAGRAM.len() as u64);
+
+    pair.client_datagrams(client_ch)
+        .send(DATAGRAM.into())
+        .unwrap();
+    pair.server_datagrams(server_ch)
+        .send(DATAGRAM[..40].to_vec().into())
+        .unwrap();
+    pair.drive();
+
+    assert_matches!(pair.server_streams(server_ch).accept(Dir::Uni), Some(stream) if stream == s);
+    let mut recv = pair.server_recv(server_ch, s);
+    let mut chunks = recv.read(false).unwrap();
+    let mut read = 0;
+    while let Ok(Some(chunk)) = chunks.next(usize::MAX) {
+        read += chunk.bytes.len();
+    }
+    let _ = chunks.finalize();
+    assert_eq!(read, STREAM.len());
+    assert_eq!(pair.server_datagrams(server_ch).recv().unwrap(), DATAGRAM);
+    assert_matches!(pair.server_datagrams(server_ch).recv(), None);
+    assert_eq!(
+        pair.client_datagrams(client_ch).recv().unwrap(),
+        &DATAGRAM[..40]
+    );
+    pair.drive();
+
+    let client = pair.client_conn_mut(client_ch).stats().application;
+    assert_eq!(client.stream_bytes_sent, 3000);
+    assert_eq!(client.stream_bytes_received, 0);
+    assert_eq!(client.datagram_bytes_sent, 200);
+    assert_eq!(client.datagram_bytes_received, 40);
+    let server = pair.server_conn_mut(server_ch).stats().application;
+    assert_eq!(server.stream_bytes_sent, 0);
+    assert_eq!(server.stream_bytes_received, 3000);
+    assert_eq!(server.datagram_bytes_sent, 40);
+    assert_eq!(server.datagram_bytes_received, 100);
+
+    // Each endpoint has heard about its sole connection's traffic
+    assert_eq!(pair.client.stats().application, client);
+    assert_eq!(pair.server.stats().application, server);
+}
+
+#[test]
+fn admission_under_load() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.server.set_admission(Admission::Validate);
+
+    // A flood of Initials from spoofed addresses only ever sees Retries
+    let mut spoofer = Endpoint::new(Default::default(), None, true);
+    let (_, mut conn) = spoofer
+        .connect(client_config(), pair.server.addr, "localhost")
+        .unwrap();
+    let initial = conn.poll_transmit(pair.time, 1).unwrap();
+    for i in 0..16 {
+        let remote = SocketAddr::new(Ipv4Addr::new(192, 0, 2, i).into(), 4433);
+        let now = pair.time;
+        assert_matches!(
+            pair.server.handle(now, remote, None, None, initial.contents[..].into()),
+            Some(DatagramEvent::Response(Transmit { contents, .. })) if contents[0] & 0xf0 == 0xf0
+        );
+    }
+    assert_eq!(pair.server.known_connections(), 0);
+    assert_eq!(pair.server.stats().admission_retries, 16);
+
+    // A legitimate client answers its Retry and gets in
+    let (client_ch, server_ch) = pair.connect();
+    assert_eq!(pair.server.known_connections(), 1);
+    assert_eq!(pair.server.stats().admission_retries, 17);
+    // The harness hands an endpoint's events to whichever of its connections it drives first
+    pair.client.connections.remove(&client_ch);
+    pair.server.connections.remove(&server_ch);
+
+    // Above the hard limit, clients are refused outright
+    pair.server.set_admission(Admission::Refuse);
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    pair.drive_client();
+    pair.server.assert_no_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                ..
+            }),
+        })
+    );
+    let stats = pair.server.stats();
+    assert_eq!(stats.overloaded, 1);
+    assert_eq!(stats.policy, 0);
+    pair.client.connections.remove(&client_ch);
+
+    // Once the pressure subsides, clients connect without a Retry
+    pair.server.set_admission(Admission::Open);
+    pair.connect();
+    assert_eq!(pair.server.stats().admission_retries, 17);
+}
+
+#[test]
+fn ack_only_transmits() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+
+    // Stream data is never ACK-only, even with ACKs alongside
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+    pair.client_send(client_ch, s).write(&[0; 4000]).unwrap();
+    let now = pair.time;
+    let conn = pair.client_conn_mut(client_ch);
+    let mut sent = Vec::new();
+    while let Some(t) = conn.poll_transmit(now, 1) {
+        assert!(!conn.last_transmit_ack_only());
+        sent.push(t);
+    }
+    assert!(sent.len() > 1);
+    pair.client.outbound.extend(sent);
+
+    // Several ack-eliciting packets get an immediate ACK, with nothing else to send
+    pair.drive_client();
+    pair.server.drive(pair.time, pair.client.addr);
+    assert!(!pair.server.outbound.is_empty());
+    assert!(pair.server_conn_mut(server_ch).last_transmit_ack_only());
+}
+
+#[test]
+fn imported_keys() {
+    let _guard = subscribe();
+    let endpoint_config = EndpointConfig::default();
+    let server_config = ServerConfig {
+        use_retry: true,
+        ..server_config()
+    };
+    let keys = endpoint_config.export_keys(Some(&server_config)).unwrap();
+    let reset_token = endpoint_config.reset_token(&ConnectionId::new(&[0xab; 8]));
+    let mut pair = Pair::new(Arc::new(endpoint_config), server_config);
+
+    // The first instance answers the client with a Retry token
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    pair.server.assert_no_accept();
+
+    // A second instance, built from the exported keys, accepts the token
+    let keys = SealedKeys::from_bytes(&keys.to_bytes()).unwrap();
+    let endpoint_config = EndpointConfig::with_imported_keys(&keys);
+    assert_eq!(
+        endpoint_config.reset_token(&ConnectionId::new(&[0xab; 8])),
+        reset_token
+    );
+    let mut server_config =
+        ServerConfig::with_imported_keys(Arc::new(server_crypto()), &keys).unwrap();
+    server_config.use_retry(true);
+    pair.server.endpoint = Endpoint::new(
+        Arc::new(endpoint_config),
+        Some(Arc::new(server_config)),
+        true,
+    );
+    pair.drive();
+    pair.server.assert_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::HandshakeDataReady)
+    );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+
+    // Keys supplied by the application can't be exported
+    let mut endpoint_config = EndpointConfig::default();
+    endpoint_config.reset_key(Arc::new(hmac::Key::new(hmac::HMAC_SHA256, &[0; 64])));
+    assert!(endpoint_config.export_keys(None).is_none());
+    assert!(SealedKeys::from_bytes(&keys.to_bytes()[1..]).is_err());
+}
+
+#[test]
+fn black_holed_path_health() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    let mut transport = TransportConfig::default();
+    transport
+        .degraded_after_ptos(1)
+        .unwrap()
+        .broken_after_ptos(3)
+        .unwrap();
+    // A connection can't break before it degrades
+    assert_matches!(
+        transport.broken_after_ptos(0),
+        Err(ConfigError::OutOfBounds)
+    );
+    assert_matches!(
+        transport.degraded_after_ptos(4),
+        Err(ConfigError::OutOfBounds)
+    );
+    let mut config = client_config();
+    config.transport = Arc::new(transport);
+    let (client_ch, _) = pair.connect_with(config);
+    pair.drive();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).health(),
+        ConnectionHealth::Healthy
+    );
+
+    // Cut the link, and send something that will never be acknowledged
+    pair.mtu = 0;
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+    pair.client_send(client_ch, s).write(b"hello").unwrap();
+    let mut degraded = None;
+    for _ in 0..100 {
+        pair.step();
+        match pair.client_conn_mut(client_ch).health() {
+            ConnectionHealth::Healthy => assert!(degraded.is_none()),
+            ConnectionHealth::Degraded {
+                since,
+                consecutive_ptos,
+            } => {
+                assert!(consecutive_ptos < 3);
+                assert_eq!(*degraded.get_or_insert(since), since);
+            }
+            ConnectionHealth::Broken => break,
+        }
+    }
+    assert!(degraded.is_some());
+    assert_eq!(
+        pair.client_conn_mut(client_ch).health(),
+        ConnectionHealth::Broken
+    );
+    assert!(!pair.client_conn_mut(client_ch).is_closed());
+
+    // Restore it; the next probe is acknowledged
+    pair.mtu = DEFAULT_MTU;
+    pair.drive();
+    assert_eq!(
+        pair.client_conn_mut(client_ch).health(),
+        ConnectionHealth::Healthy
+    );
+}
+
+#[test]
+fn resumed_path_estimates() {
+    let _guard = subscribe();
+    let mut pair = Pair::default();
+    pair.latency = Duration::from_millis(50);
+
+    /// Send `len` bytes on a new stream, returning how long until the server has read them all
+    fn transfer(
+        pair: &mut Pair,
+        client_ch: ConnectionHandle,
+        server_ch: ConnectionHandle,
+        len: usize,
+    ) -> Duration {
+        let start = pair.time;
+        let data = vec![0xab; len];
+        let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+        let (mut sent, mut received) = (0, 0);
+        let mut stream = None;
+        while received < len {
+            if sent < len {
+                sent += pair
+                    .client_send(client_ch, s)
+                    .write(&data[sent..])
+                    .unwrap_or(0);
+            }
+            assert!(pair.step(), "transfer stalled");
+            if stream.is_none() {
+                stream = pair.server_streams(server_ch).accept(Dir::Uni);
+            }
+            if let Some(id) = stream {
+                let mut recv = pair.server_recv(server_ch, id);
+                let mut chunks = recv.read(false).unwrap();
+                while let Ok(Some(chunk)) = chunks.next(usize::MAX) {
+                    received += chunk.bytes.len();
+                }
+                let _ = chunks.finalize();
+            }
+        }
+        pair.time - start
+    }
+
+    const LEN: usize = 1024 * 1024;
+    let (client_ch, server_ch) = pair.connect();
+    let unseeded = transfer(&mut pair, client_ch, server_ch, LEN);
+    let path = pair.client_conn_mut(client_ch).current_path();
+    assert!(path.rtt >= Duration::from_millis(100));
+    assert!(path.cwnd > 14720);
+    // The harness hands an endpoint's events to whichever of its connections it drives first
+    pair.client.connections.remove(&client_ch);
+    pair.server.connections.remove(&server_ch);
+
+    let mut config = client_config();
+    config.initial_rtt(path.rtt).initial_window(path.cwnd);
+    let (client_ch, server_ch) = pair.connect_with(config);
+    let seeded = transfer(&mut pair, client_ch, server_ch, LEN);
+    info!(?unseeded, ?seeded, "transfer durations");
+    assert!(
+        seeded < unseeded,
+        "seeded connection took {seeded:?}, unseeded {unseeded:?}"
+    );
+}
+
+#[test]
+fn custom_transport_parameters() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    server_config
+        .record_transport_parameter(0x7157)
+        .unwrap()
+        .transport_parameter(0x7158, &b"server"[..])
+        .unwrap();
+    let mut pair = Pair::new(Default::default(), server_config);
+    let mut client_config = client_config();
+    client_config
+        .transport_parameter(0x7157, &b"client"[..])
+        .unwrap()
+        .transport_parameter(0x7159, &b"unasked"[..])
+        .unwrap()
+        .record_transport_parameter(0x7158)
+        .unwrap();
+    assert_matches!(
+        client_config.transport_parameter(0x0f, Bytes::new()),
+        Err(ConfigError::ReservedTransportParameter)
+    );
+    assert_matches!(
+        client_config.record_transport_parameter(31 * 7 + 27),
+        Err(ConfigError::ReservedTransportParameter)
+    );
+    let (client_ch, server_ch) = pair.connect_with(client_config);
+
+    assert_eq!(
+        pair.client_conn_mut(client_ch)
+            .peer_custom_transport_parameters(),
+        [(0x7158, Bytes::from_static(b"server"))]
+    );
+    // Parameters the server didn't ask for are ignored
+    assert_eq!(
+        pair.server_conn_mut(server_ch)
+            .peer_custom_transport_parameters(),
+        [(0x7157, Bytes::from_static(b"client"))]
+    );
+}
+
+#[test]
+fn max_udp_payload_size_bounds() {
+    let mut config = EndpointConfig::default();
+    assert!(config.max_udp_payload_size(1199).is_err());
+    assert!(config.max_udp_payload_size(65_528).is_err());
+    assert_eq!(config.get_max_udp_payload_size(), 65_527);
+    config.max_udp_payload_size(1200).unwrap();
+    assert_eq!(config.get_max_udp_payload_size(), 1200);
+}
+
+#[test]
+fn prefix_policy() {
+    let _guard = subscribe();
+
+    // Thousands of denied prefixes of each family, some with allowed exceptions carved out
+    let mut policy = PrefixPolicy::default();
+    for i in 0..4096u32 {
+        let v4 = Ipv4Addr::from(0x0a00_0000 | (i << 8));
+        policy.insert(v4.into(), 24, PrefixVerdict::Deny).unwrap();
+        let v6 = Ipv6Addr::new(0x2001, 0xdb8, i as u16, 0, 0, 0, 0, 0);
+        policy.insert(v6.into(), 48, PrefixVerdict::Deny).unwrap();
+    }
+    policy
+        .insert(Ipv4Addr::new(10, 0, 7, 8).into(), 32, PrefixVerdict::Allow)
+        .unwrap();
+    let exception = Ipv4Addr::new(10, 0, 9, 128);
+    policy
+        .insert(exception.into(), 25, PrefixVerdict::Allow)
+        .unwrap();
+    assert!(policy
+        .insert(Ipv4Addr::new(10, 0, 0, 0).into(), 33, PrefixVerdict::Deny)
+        .is_err());
+    let verdict = |addr: &str| policy.verdict(addr.parse().unwrap());
+    assert_eq!(verdict("10.0.7.7"), PrefixVerdict::Deny);
+    assert_eq!(verdict("10.0.7.8"), PrefixVerdict::Allow);
+    assert_eq!(verdict("10.0.9.127"), PrefixVerdict::Deny);
+    assert_eq!(verdict("10.0.9.200"), PrefixVerdict::Allow);
+    assert_eq!(verdict("10.15.255.1"), PrefixVerdict::Deny);
+    assert_eq!(verdict("10.16.0.1"), PrefixVerdict::Allow);
+    assert_eq!(verdict("::ffff:10.3.4.5"), PrefixVerdict::Deny);
+    assert_eq!(verdict("2001:db8:fff::1"), PrefixVerdict::Deny);
+    assert_eq!(verdict("2001:db8:1000::1"), PrefixVerdict::Allow);
+    assert_eq!(verdict("::1"), PrefixVerdict::Allow);
+
+    // An allow list: only prefixes given are let in
+    let mut allow_list = PrefixPolicy::new(PrefixVerdict::Deny);
+    allow_list
+        .insert(Ipv4Addr::new(192, 0, 2, 0).into(), 24, PrefixVerdict::Allow)
+        .unwrap();
+    assert_eq!(
+        allow_list.verdict(Ipv4Addr::new(192, 0, 2, 1).into()),
+        PrefixVerdict::Allow
+    );
+    assert_eq!(
+        allow_list.verdict(Ipv4Addr::new(192, 0, 3, 1).into()),
+        PrefixVerdict::Deny
+    );
+
+    // Denying a client doesn't affect the connection it already has
+    let mut pair = Pair::default();
+    let (client_ch, server_ch) = pair.connect();
+    assert!(pair.server.stats().prefix_allowed > 0);
+    policy
+        .insert(pair.client.addr.ip(), 128, PrefixVerdict::Deny)
+        .unwrap();
+    pair.server.set_prefix_policy(policy);
+    let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+    pair.client_send(client_ch, s).write(b"hello").unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.server_streams(server_ch).accept(Dir::Uni),
+        Some(stream) if stream == s
+    );
+    assert_eq!(pair.server.stats().prefix_denied, 0);
+
+    // But its new connections get nowhere
+    pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    pair.server.assert_no_accept();
+    assert_eq!(pair.server.known_connections(), 1);
+    assert!(pair.server.stats().prefix_denied > 0);
+}
+
+#[test]
+fn pacing_horizon() {
+    let _guard = subscribe();
+
+    /// Upload a megabyte over a 40ms round trip, returning when each datagram reached the server
+    /// and how many times the client woke up for a timer
+    fn upload(horizon: Option<Duration>) -> (Vec<Instant>, usize) {
+        let mut pair = Pair::default();
+        pair.latency = Duration::from_millis(20);
+        let mut transport = TransportConfig::default();
+        transport.pacing_horizon(horizon);
+        let mut config = client_config();
+        config.transport_config(Arc::new(transport));
+        let (client_ch, _) = pair.connect_with(config);
+
+        let s = pair.client_streams(client_ch).open(Dir::Uni).unwrap();
+        const LEN: usize = 1024 * 1024;
+        let data = vec![0; LEN];
+        assert_eq!(pair.client_send(client_ch, s).write(&data).unwrap(), LEN);
+        pair.client_send(client_ch, s).finish().unwrap();
+
+        let mut arrivals = Vec::new();
+        let mut wakeups = 0;
+        loop {
+            let queued = pair.server.inbound.len();
+            pair.drive_client();
+            arrivals.extend(pair.server.inbound.iter().skip(queued).map(|x| x.0));
+            pair.drive_server();
+            let mut finished = false;
+            while let Some(event) = pair.client_conn_mut(client_ch).poll() {
+                finished |= matches!(event, Event::Stream(StreamEvent::Finished { id }) if id == s);
+            }
+            if finished {
+                break;
+            }
+            let client_t = pair.client.next_wakeup();
+            let t = min_opt(client_t, pair.server.next_wakeup()).unwrap();
+            if Some(t) == client_t && pair.client.inbound.front().map(|x| x.0) != Some(t) {
+                wakeups += 1;
+            }
+            pair.time = pair.time.max(t);
+        }
+        (arrivals, wakeups)
+    }
+
+    /// The most datagrams reaching the server within any `window`
+    fn max_within(arrivals: &[Instant], window: Duration) -> usize {
+        let mut start = 0;
+        let mut max = 0;
+        for (end, &t) in arrivals.iter().enumerate() {
+            while t - arrivals[start] >= window {
+                start += 1;
+            }
+            max = max.max(end + 1 - start);
+        }
+        max
+    }
+
+    let (timed, timed_wakeups) = upload(None);
+    let (scheduled, scheduled_wakeups) = upload(Some(Duration::from_millis(10)));
+
+    // Datagrams arrive in the order they were produced
+    assert!(scheduled.windows(2).all(|x| x[0] <= x[1]));
+    // The connection wakes up for the pacer far less often
+    assert!(
+        scheduled_wakeups < timed_wakeups,
+        "{scheduled_wakeups} wakeups with a horizon, {timed_wakeups} without"
+    );
+    // Yet datagrams are spaced out no worse than with a timer for each burst
+    for window in [Duration::from_millis(1), Duration::from_millis(10)] {
+        let timed = max_within(&timed, window);
+        let scheduled = max_within(&scheduled, window);
+        assert!(
+            scheduled <= timed + timed / 4,
+            "{scheduled} datagrams within {window:?} with a horizon, {timed} without"
+        );
+    }
+    let duration = |x: &[Instant]| *x.last().unwrap() - x[0];
+    assert!(duration(&scheduled) >= duration(&timed) * 9 / 10);
+}
+
+#[test]
+fn defer_incoming() {
+    let _guard = subscribe();
+    let mut server_config = server_config();
+    server_config.defer_incoming(true);
+    let mut pair = Pair::new(Default::default(), server_config);
+
+    // Nothing is started or answered until the application decides
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    pair.server.assert_no_accept();
+    assert!(pair.client.inbound.is_empty());
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    assert_eq!(incoming.remote_address(), pair.client.addr);
+    assert!(!incoming.remote_address_validated());
+    let orig_dst_cid = *incoming.orig_dst_cid();
+
+    // A client asked to retry comes back validated, and can't be asked again
+    let retry = pair.server.retry(incoming).unwrap();
+    pair.server.outbound.push_back(retry);
+    pair.drive_server();
+    pair.drive_client();
+    pair.drive_server();
+    pair.server.assert_no_accept();
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    assert!(incoming.remote_address_validated());
+    assert_eq!(*incoming.orig_dst_cid(), orig_dst_cid);
+    let RetryError(incoming) = pair.server.retry(incoming).unwrap_err();
+
+    // Once accepted, the connection proceeds as usual
+    let now = pair.time;
+    let event = pair.server.accept(incoming, now).unwrap();
+    pair.server.handle_datagram_event(event);
+    pair.drive();
+    let server_ch = pair.server.assert_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::HandshakeDataReady)
+    );
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::Connected)
+    );
+    assert_matches!(
+        pair.server_conn_mut(server_ch).poll(),
+        Some(Event::HandshakeDataReady)
+    );
+    // The harness hands an endpoint's events to whichever of its connections it drives first
+    pair.client.connections.remove(&client_ch);
+    pair.server.connections.remove(&server_ch);
+
+    // A refused client learns why
+    let client_ch = pair.begin_connect(client_config());
+    pair.drive_client();
+    pair.drive_server();
+    let incoming = pair.server.incoming.pop_front().unwrap();
+    let refusal = pair.server.refuse(incoming, now);
+    pair.server.outbound.push_back(refusal);
+    pair.drive();
+    pair.server.assert_no_accept();
+    assert_matches!(
+        pair.client_conn_mut(client_ch).poll(),
+        Some(Event::ConnectionLost {
+            reason: ConnectionError::ConnectionClosed(frame::ConnectionClose {
+                error_code: TransportErrorCode::CONNECTION_REFUSED,
+                ..
+            }),
+        })
+    );
+}
@@ -32,6 +32,9 @@ pub(super) struct Pair {
     /// Number of spin bit flips
     pub(super) spins: u64,
     last_spin: bool,
+    /// Mark every `n`th ECN-capable packet crossing the link as congestion experienced
+    pub(super) ce_every: Option<u64>,
+    ecn_capable_packets: u64,
 }
 
 impl Pair {
@@ -59,6 +62,8 @@ impl Pair {
             latency: Duration::new(0, 0),
             spins: 0,
             last_spin: false,
+            ce_every: None,
+            ecn_capable_packets: 0,
         }
     }
 
@@ -132,9 +137,12 @@ impl Pair {
                 socket.send_to(&x.contents, x.destination).unwrap();
             }
             if self.server.addr == x.destination {
+                let ecn = mark_ce(x.ecn, self.ce_every, &mut self.ecn_capable_packets);
+                // Paced datagrams leave when they were scheduled to
+                let sent = x.send_at.map_or(self.time, |t| t.max(self.time));
                 self.server.inbound.push_back((
-                    self.time + self.latency,
-                    x.ecn,
+                    sent + self.latency,
+                    ecn,
                     x.contents.as_ref().into(),
                 ));
             }
@@ -157,9 +165,10 @@ impl Pair {
                 socket.send_to(&x.contents, x.destination).unwrap();
             }
             if self.client.addr == x.destination {
+                let ecn = mark_ce(x.ecn, self.ce_every, &mut self.ecn_capable_packets);
                 self.client.inbound.push_back((
                     self.time + self.latency,
-                    x.ecn,
+                    ecn,
                     x.contents.as_ref().into(),
                 ));
             }
@@ -269,6 +278,8 @@ pub(super) struct TestEndpoint {
     delayed: VecDeque<Transmit>,
     pub(super) inbound: VecDeque<(Instant, Option<EcnCodepoint>, BytesMut)>,
     accepted: Option<ConnectionHandle>,
+    /// Connection attempts deferred to the test by [`ServerConfig::defer_incoming`]
+    pub(super) incoming: VecDeque<Incoming>,
     pub(super) connections: HashMap<ConnectionHandle, Connection>,
     conn_events: HashMap<ConnectionHandle, VecDeque<ConnectionEvent>>,
 }
@@ -293,6 +304,7 @@ impl TestEndpoint {
             delayed: VecDeque::new(),
             inbound: VecDeque::new(),
             accepted: None,
+            incoming: VecDeque::new(),
             connections: HashMap::default(),
             conn_events: HashMap::default(),
         }
@@ -311,21 +323,7 @@ impl TestEndpoint {
         while self.inbound.front().map_or(false, |x| x.0 <= now) {
             let (recv_time, ecn, packet) = self.inbound.pop_front().unwrap();
             if let Some(event) = self.endpoint.handle(recv_time, remote, None, ecn, packet) {
-                match event {
-                    DatagramEvent::NewConnection(ch, conn) => {
-                        self.connections.insert(ch, conn);
-                        self.accepted = Some(ch);
-                    }
-                    DatagramEvent::ConnectionEvent(ch, event) => {
-                        self.conn_events
-                            .entry(ch)
-                            .or_insert_with(VecDeque::new)
-                            .push_back(event);
-                    }
-                    DatagramEvent::Response(transmit) => {
-                        self.outbound.extend(split_transmit(transmit));
-                    }
-                }
+                self.handle_datagram_event(event);
             }
         }
 
@@ -367,6 +365,28 @@ impl TestEndpoint {
         }
     }
 
+    /// Act on the outcome of handling a datagram, or of deciding on an [`Incoming`]
+    pub(super) fn handle_datagram_event(&mut self, event: DatagramEvent) {
+        match event {
+            DatagramEvent::NewConnection(ch, conn) => {
+                self.connections.insert(ch, conn);
+                self.accepted = Some(ch);
+            }
+            DatagramEvent::ConnectionEvent(ch, event) => {
+                self.conn_events
+                    .entry(ch)
+                    .or_insert_with(VecDeque::new)
+                    .push_back(event);
+            }
+            DatagramEvent::Response(transmit) => {
+                self.outbound.extend(split_transmit(transmit));
+            }
+            DatagramEvent::Incoming(incoming) => {
+                self.incoming.push_back(incoming);
+            }
+        }
+    }
+
     pub(super) fn next_wakeup(&self) -> Option<Instant> {
         let next_inbound = self.inbound.front().map(|x| x.0);
         min_opt(self.timeout, next_inbound)
@@ -500,12 +520,22 @@ fn split_transmit(mut transmit: Transmit) -> Vec<Transmit> {
             contents,
             segment_size: None,
             src_ip: transmit.src_ip,
+            send_at: transmit.send_at,
         });
     }
 
     transmits
 }
 
+fn mark_ce(ecn: Option<EcnCodepoint>, every: Option<u64>, count: &mut u64) -> Option<EcnCodepoint> {
+    let ecn = ecn?;
+    *count += 1;
+    match every {
+        Some(n) if *count % n == 0 => Some(EcnCodepoint::Ce),
+        _ => Some(ecn),
+    }
+}
+
 fn packet_size(transmit: &Transmit) -> usize {
     if transmit.segment_size.is_some() {
         panic!("This transmit is meant to be split into multiple packets!");
@@ -11,14 +11,14 @@ use std::{
     net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
 };
 
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes};
 use thiserror::Error;
 
 use crate::{
     cid_generator::ConnectionIdGenerator,
     cid_queue::CidQueue,
     coding::{BufExt, BufMutExt, UnexpectedEnd},
-    config::{EndpointConfig, ServerConfig, TransportConfig},
+    config::{ConfigError, EndpointConfig, ServerConfig, TransportConfig},
     shared::ConnectionId,
     ResetToken, Side, TransportError, VarInt, LOC_CID_COUNT, MAX_CID_SIZE, MAX_STREAM_COUNT,
     RESET_TOKEN_SIZE,
@@ -66,7 +66,7 @@ macro_rules! apply_params {
 macro_rules! make_struct {
     {$($(#[$doc:meta])* $name:ident ($code:expr) = $default:expr,)*} => {
         /// Transport parameters used to negotiate connection-level preferences between peers
-        #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+        #[derive(Debug, Clone, Eq, PartialEq)]
         pub struct TransportParameters {
             $($(#[$doc])* pub(crate) $name : VarInt,)*
 
@@ -92,6 +92,12 @@ macro_rules! make_struct {
             pub(crate) stateless_reset_token: Option<ResetToken>,
             /// The server's preferred address for communication after handshake completion
             pub(crate) preferred_address: Option<PreferredAddress>,
+
+            /// Parameters outside of those above, sorted by id
+            ///
+            /// Holds those the application asked to send, or everything unrecognized but GREASE
+            /// when received from the peer.
+            pub(crate) custom: Vec<(VarInt, Bytes)>,
         }
 
         impl Default for TransportParameters {
@@ -109,6 +115,8 @@ macro_rules! make_struct {
                     retry_src_cid: None,
                     stateless_reset_token: None,
                     preferred_address: None,
+
+                    custom: Vec::new(),
                 }
             }
         }
@@ -179,6 +187,50 @@ impl TransportParameters {
     }
 }
 
+/// Transport parameters outside of RFC 9000 that the application exchanges with its peers
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CustomParameters {
+    /// Sent to the peer, sorted by id
+    pub(crate) sent: Vec<(VarInt, Bytes)>,
+    /// Ids of the parameters whose values are kept when received from the peer
+    pub(crate) recorded: Vec<VarInt>,
+}
+
+impl CustomParameters {
+    pub(crate) fn send(&mut self, id: VarInt, value: Bytes) -> Result<(), ConfigError> {
+        self.record(id)?;
+        match self.sent.binary_search_by_key(&id, |x| x.0) {
+            Ok(i) => self.sent[i].1 = value,
+            Err(i) => self.sent.insert(i, (id, value)),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record(&mut self, id: VarInt) -> Result<(), ConfigError> {
+        if is_reserved(id.0) {
+            return Err(ConfigError::ReservedTransportParameter);
+        }
+        if !self.recorded.contains(&id) {
+            self.recorded.push(id);
+        }
+        Ok(())
+    }
+}
+
+/// Whether a transport parameter id is used by RFC 9000 and its extensions, or for GREASE
+///
+/// The whole range up to and including `max_datagram_frame_size` is treated as taken, which
+/// also covers `version_information` from RFC 9368.
+fn is_reserved(id: u64) -> bool {
+    id <= 0x20 || id == 0x2ab2 || is_grease(id)
+}
+
+/// Whether a transport parameter id is reserved to exercise the requirement that unknown
+/// parameters be ignored
+fn is_grease(id: u64) -> bool {
+    id >= 27 && (id - 27) % 31 == 0
+}
+
 /// A server's preferred address
 ///
 /// This is communicated as a transport parameter during TLS session establishment.
@@ -329,6 +381,12 @@ impl TransportParameters {
             w.write_var(0x2ab2);
             w.write_var(0);
         }
+
+        for (id, value) in &self.custom {
+            w.write(*id);
+            w.write_var(value.len() as u64);
+            w.put_slice(value);
+        }
     }
 
     /// Decode `TransportParameters` from buffer
@@ -402,7 +460,8 @@ impl TransportParameters {
                                     params.$name = value.into();
                                     got.$name = true;
                                 })*
-                                _ => r.advance(len as usize),
+                                _ if is_grease(id) => r.advance(len as usize),
+                                _ => params.custom.push((VarInt(id), r.copy_to_bytes(len))),
                             }
                         }
                     }
@@ -411,6 +470,11 @@ impl TransportParameters {
             }
         }
 
+        params.custom.sort_by_key(|x| x.0);
+        if params.custom.windows(2).any(|x| x[0].0 == x[1].0) {
+            return Err(Error::Malformed);
+        }
+
         // Semantic validation
         if params.ack_delay_exponent.0 > 20
             || params.max_ack_delay.0 >= 1 << 14
@@ -458,6 +522,10 @@ mod test {
                 stateless_reset_token: [0xab; RESET_TOKEN_SIZE].into(),
             }),
             grease_quic_bit: true,
+            custom: vec![
+                (VarInt(0x2a), Bytes::from_static(b"hello")),
+                (VarInt(0x4b1d), Bytes::new()),
+            ],
             ..TransportParameters::default()
         };
         params.write(&mut buf);
@@ -1,12 +1,18 @@
 use std::{
     io::{self, IoSliceMut},
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
     time::Instant,
 };
 
 use proto::Transmit;
 
-use super::{log_sendmsg_error, RecvMeta, UdpSockRef, UdpState, IO_ERROR_LOG_INTERVAL};
+use super::{
+    is_transient_send_error, log_sendmsg_error, LocalDrops, RecvMeta, UdpSockRef, UdpState,
+    IO_ERROR_LOG_INTERVAL,
+};
 
 /// Fallback UDP socket interface that stubs out all special functionality
 ///
@@ -15,6 +21,7 @@ use super::{log_sendmsg_error, RecvMeta, UdpSockRef, UdpState, IO_ERROR_LOG_INTE
 #[derive(Debug)]
 pub struct UdpSocketState {
     last_send_error: Mutex<Instant>,
+    send_errors: AtomicU64,
 }
 
 impl UdpSocketState {
@@ -22,6 +29,7 @@ impl UdpSocketState {
         let now = Instant::now();
         Self {
             last_send_error: Mutex::new(now.checked_sub(2 * IO_ERROR_LOG_INTERVAL).unwrap_or(now)),
+            send_errors: AtomicU64::new(0),
         }
     }
 
@@ -29,6 +37,14 @@ impl UdpSocketState {
         socket.0.set_nonblocking(true)
     }
 
+    /// Have datagrams received on `socket` report when the kernel received them
+    ///
+    /// Receive timestamps aren't supported on this platform, so this does nothing.
+    pub fn set_recv_timestamps(socket: UdpSockRef<'_>, enabled: bool) -> io::Result<()> {
+        let _ = (socket, enabled);
+        Ok(())
+    }
+
     pub fn send(
         &self,
         socket: UdpSockRef<'_>,
@@ -49,17 +65,16 @@ impl UdpSocketState {
                 // recurring on the next call.
                 Err(_) if sent != 0 => return Ok(sent),
                 Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
+                    // Left for the caller to retry in a moment
+                    if e.kind() == io::ErrorKind::WouldBlock || is_transient_send_error(&e) {
                         return Err(e);
                     }
 
                     // Other errors are ignored, since they will usually be handled
                     // by higher level retransmits and timeouts.
-                    // - PermissionDenied errors have been observed due to iptable rules.
-                    //   Those are not fatal errors, since the
-                    //   configuration can be dynamically changed.
                     // - Destination unreachable errors have been observed for other
                     log_sendmsg_error(&self.last_send_error, e, transmit);
+                    self.send_errors.fetch_add(1, Ordering::Relaxed);
                     sent += 1;
                 }
             }
@@ -67,6 +82,16 @@ impl UdpSocketState {
         Ok(sent)
     }
 
+    /// Datagrams known to have been lost locally
+    ///
+    /// Only send errors are detected on this platform.
+    pub fn local_drops(&self) -> LocalDrops {
+        LocalDrops {
+            receive_overflows: 0,
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+        }
+    }
+
     pub fn recv(
         &self,
         socket: UdpSockRef<'_>,
@@ -87,6 +112,7 @@ impl UdpSocketState {
             addr: addr.as_socket().unwrap(),
             ecn: None,
             dst_ip: None,
+            timestamp: None,
         };
         Ok(1)
     }
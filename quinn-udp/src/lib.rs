@@ -9,12 +9,13 @@ use std::os::windows::io::AsSocket;
 #[cfg(not(windows))]
 use std::sync::atomic::AtomicBool;
 use std::{
+    io,
     net::{IpAddr, Ipv6Addr, SocketAddr},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Mutex,
     },
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use bytes::Bytes;
@@ -45,6 +46,19 @@ pub fn may_fragment() -> bool {
     imp::may_fragment()
 }
 
+/// Whether a send error is likely to clear up on its own within milliseconds
+///
+/// Firewall rate limits, e.g. nftables `limit` rules, reject sends with `EPERM` in bursts, and
+/// `ENOBUFS` signals a momentarily full queue in the kernel. [`UdpSocketState::send`] returns
+/// these errors, rather than dropping the datagram, for the caller to retry it later.
+pub fn is_transient_send_error(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ENOBUFS) {
+        return true;
+    }
+    e.kind() == io::ErrorKind::PermissionDenied
+}
+
 /// Number of UDP packets to send/receive at a time
 pub const BATCH_SIZE: usize = imp::BATCH_SIZE;
 
@@ -116,6 +130,18 @@ pub struct RecvMeta {
     pub ecn: Option<EcnCodepoint>,
     /// The destination IP address which was encoded in this datagram
     pub dst_ip: Option<IpAddr>,
+    timestamp: Option<SystemTime>,
+}
+
+impl RecvMeta {
+    /// When the kernel received the datagram, by the system clock
+    ///
+    /// Only reported on Linux, by sockets set up with
+    /// [`UdpSocketState::set_recv_timestamps()`]. All segments of a GRO batch share the timestamp
+    /// of the batch.
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        self.timestamp
+    }
 }
 
 impl Default for RecvMeta {
@@ -127,10 +153,25 @@ impl Default for RecvMeta {
             stride: 0,
             ecn: None,
             dst_ip: None,
+            timestamp: None,
         }
     }
 }
 
+/// Datagrams a socket is known to have lost locally, since it was configured
+///
+/// Losses which the operating system doesn't report are not counted, so these are lower bounds.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct LocalDrops {
+    /// Incoming datagrams discarded because the socket's receive buffer was full
+    ///
+    /// Only reported on Linux, through `SO_RXQ_OVFL`, and then only once a later datagram is
+    /// received.
+    pub receive_overflows: u64,
+    /// Outgoing datagrams reported as sent even though sending them failed
+    pub send_errors: u64,
+}
+
 /// An outgoing packet
 #[derive(Debug, Clone)]
 pub struct Transmit {
@@ -176,6 +217,18 @@ fn log_sendmsg_error(
 // Wrapper around socket2 to avoid making it a public dependency and incurring stability risk
 pub struct UdpSockRef<'a>(socket2::SockRef<'a>);
 
+impl UdpSockRef<'_> {
+    /// Size of the socket's receive buffer, as reported by the system
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        self.0.recv_buffer_size()
+    }
+
+    /// Size of the socket's send buffer, as reported by the system
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        self.0.send_buffer_size()
+    }
+}
+
 #[cfg(unix)]
 impl<'s, S> From<&'s S> for UdpSockRef<'s>
 where
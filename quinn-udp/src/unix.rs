@@ -7,17 +7,22 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     os::unix::io::AsRawFd,
     sync::{
-        atomic::{AtomicBool, AtomicUsize},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Mutex,
     },
     time::Instant,
 };
+#[cfg(target_os = "linux")]
+use std::{
+    sync::atomic::AtomicU8,
+    time::{Duration, UNIX_EPOCH},
+};
 
 use socket2::SockRef;
 
 use super::{
-    cmsg, log_sendmsg_error, EcnCodepoint, RecvMeta, Transmit, UdpSockRef, UdpState,
-    IO_ERROR_LOG_INTERVAL,
+    cmsg, is_transient_send_error, log_sendmsg_error, EcnCodepoint, LocalDrops, RecvMeta, Transmit,
+    UdpSockRef, UdpState, IO_ERROR_LOG_INTERVAL,
 };
 
 #[cfg(target_os = "freebsd")]
@@ -32,6 +37,12 @@ type IpTosTy = libc::c_int;
 #[derive(Debug)]
 pub struct UdpSocketState {
     last_send_error: Mutex<Instant>,
+    send_errors: AtomicU64,
+    /// The kernel's count of datagrams dropped by the socket, as last reported with a datagram
+    receive_overflows: AtomicU64,
+    /// Whether the socket reports receive timestamps: `TIMESTAMPS_UNKNOWN` until first received on
+    #[cfg(target_os = "linux")]
+    recv_timestamps: AtomicU8,
 }
 
 impl UdpSocketState {
@@ -39,6 +50,10 @@ impl UdpSocketState {
         let now = Instant::now();
         Self {
             last_send_error: Mutex::new(now.checked_sub(2 * IO_ERROR_LOG_INTERVAL).unwrap_or(now)),
+            send_errors: AtomicU64::new(0),
+            receive_overflows: AtomicU64::new(0),
+            #[cfg(target_os = "linux")]
+            recv_timestamps: AtomicU8::new(TIMESTAMPS_UNKNOWN),
         }
     }
 
@@ -46,13 +61,48 @@ impl UdpSocketState {
         init(sock.0)
     }
 
+    /// Have datagrams received on `sock` report when the kernel received them
+    ///
+    /// See [`RecvMeta::timestamp()`]. Off by default, since the larger control messages cost some
+    /// receive throughput. Must be set before a `UdpSocketState` first receives on the socket. Only
+    /// supported on Linux; elsewhere this does nothing.
+    pub fn set_recv_timestamps(sock: UdpSockRef<'_>, enabled: bool) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            assert!(
+                TIMESTAMPED_CMSG_LEN
+                    >= CMSG_LEN
+                        + unsafe {
+                            libc::CMSG_SPACE(mem::size_of::<libc::timespec>() as _) as usize
+                        }
+            );
+            set_socket_option(
+                &*sock.0,
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMPNS,
+                enabled as libc::c_int,
+            )
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (sock, enabled);
+            Ok(())
+        }
+    }
+
     pub fn send(
         &self,
         socket: UdpSockRef<'_>,
         state: &UdpState,
         transmits: &[Transmit],
     ) -> Result<usize, io::Error> {
-        send(state, socket.0, &self.last_send_error, transmits)
+        send(
+            state,
+            socket.0,
+            &self.last_send_error,
+            &self.send_errors,
+            transmits,
+        )
     }
 
     pub fn recv(
@@ -61,7 +111,40 @@ impl UdpSocketState {
         bufs: &mut [IoSliceMut<'_>],
         meta: &mut [RecvMeta],
     ) -> io::Result<usize> {
-        recv(socket.0, bufs, meta)
+        #[cfg(target_os = "linux")]
+        if self.recv_timestamps(&socket) {
+            return recv::<TIMESTAMPED_CMSG_LEN>(socket.0, bufs, meta, &self.receive_overflows);
+        }
+        recv::<CMSG_LEN>(socket.0, bufs, meta, &self.receive_overflows)
+    }
+
+    /// Whether `socket` was set to report receive timestamps, which is only checked once
+    #[cfg(target_os = "linux")]
+    fn recv_timestamps(&self, socket: &UdpSockRef<'_>) -> bool {
+        match self.recv_timestamps.load(Ordering::Relaxed) {
+            TIMESTAMPS_UNKNOWN => {
+                let enabled = get_socket_option(&*socket.0, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS)
+                    .map_or(false, |x| x != 0);
+                let state = if enabled {
+                    TIMESTAMPS_ON
+                } else {
+                    TIMESTAMPS_OFF
+                };
+                self.recv_timestamps.store(state, Ordering::Relaxed);
+                enabled
+            }
+            state => state == TIMESTAMPS_ON,
+        }
+    }
+
+    /// Datagrams known to have been lost locally
+    ///
+    /// Receive buffer overflows are only detected on Linux.
+    pub fn local_drops(&self) -> LocalDrops {
+        LocalDrops {
+            receive_overflows: self.receive_overflows.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+        }
     }
 }
 
@@ -106,6 +189,9 @@ fn init(io: SockRef<'_>) -> io::Result<()> {
         // opportunistically try to enable GRO. See gro::gro_segments().
         let _ = set_socket_option(&*io, libc::SOL_UDP, libc::UDP_GRO, OPTION_ON);
 
+        // Have received datagrams report how many were dropped for want of buffer space
+        let _ = set_socket_option(&*io, libc::SOL_SOCKET, libc::SO_RXQ_OVFL, OPTION_ON);
+
         // Forbid IPv4 fragmentation. Set even for IPv6 to account for IPv6 mapped IPv4 addresses.
         set_socket_option(
             &*io,
@@ -160,6 +246,7 @@ fn send(
     state: &UdpState,
     io: SockRef<'_>,
     last_send_error: &Mutex<Instant>,
+    send_errors: &AtomicU64,
     transmits: &[Transmit],
 ) -> io::Result<usize> {
     #[allow(unused_mut)] // only mutable on FreeBSD
@@ -239,13 +326,16 @@ fn send(
                         state.set_sendmsg_einval();
                     }
 
+                    // Nothing was sent, so the caller can retry the transmits in a moment
+                    if is_transient_send_error(&e) {
+                        return Err(e);
+                    }
+
                     // Other errors are ignored, since they will usually be handled
                     // by higher level retransmits and timeouts.
-                    // - PermissionDenied errors have been observed due to iptable rules.
-                    //   Those are not fatal errors, since the
-                    //   configuration can be dynamically changed.
                     // - Destination unreachable errors have been observed for other
                     log_sendmsg_error(last_send_error, e, &transmits[0]);
+                    send_errors.fetch_add(1, Ordering::Relaxed);
 
                     // The ERRORS section in https://man7.org/linux/man-pages/man2/sendmmsg.2.html
                     // describes that errors will only be returned if no message could be transmitted
@@ -264,6 +354,7 @@ fn send(
     state: &UdpState,
     io: SockRef<'_>,
     last_send_error: &Mutex<Instant>,
+    send_errors: &AtomicU64,
     transmits: &[Transmit],
 ) -> io::Result<usize> {
     let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
@@ -292,14 +383,15 @@ fn send(
                 }
                 io::ErrorKind::WouldBlock if sent != 0 => return Ok(sent),
                 io::ErrorKind::WouldBlock => return Err(e),
+                // Left for the caller to retry in a moment
+                _ if is_transient_send_error(&e) && sent != 0 => return Ok(sent),
+                _ if is_transient_send_error(&e) => return Err(e),
                 _ => {
                     // Other errors are ignored, since they will usually be handled
                     // by higher level retransmits and timeouts.
-                    // - PermissionDenied errors have been observed due to iptable rules.
-                    //   Those are not fatal errors, since the
-                    //   configuration can be dynamically changed.
                     // - Destination unreachable errors have been observed for other
                     log_sendmsg_error(last_send_error, e, &transmits[sent]);
+                    send_errors.fetch_add(1, Ordering::Relaxed);
                     sent += 1;
                 }
             }
@@ -377,9 +469,14 @@ unsafe fn sendmmsg_fallback(
 }
 
 #[cfg(not(any(target_os = "macos", target_os = "ios")))]
-fn recv(io: SockRef<'_>, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+fn recv<const N: usize>(
+    io: SockRef<'_>,
+    bufs: &mut [IoSliceMut<'_>],
+    meta: &mut [RecvMeta],
+    overflows: &AtomicU64,
+) -> io::Result<usize> {
     let mut names = [MaybeUninit::<libc::sockaddr_storage>::uninit(); BATCH_SIZE];
-    let mut ctrls = [cmsg::Aligned(MaybeUninit::<[u8; CMSG_LEN]>::uninit()); BATCH_SIZE];
+    let mut ctrls = [cmsg::Aligned(MaybeUninit::<[u8; N]>::uninit()); BATCH_SIZE];
     let mut hdrs = unsafe { mem::zeroed::<[libc::mmsghdr; BATCH_SIZE]>() };
     let max_msg_count = bufs.len().min(BATCH_SIZE);
     for i in 0..max_msg_count {
@@ -408,15 +505,25 @@ fn recv(io: SockRef<'_>, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) ->
         break n;
     };
     for i in 0..(msg_count as usize) {
-        meta[i] = decode_recv(&names[i], &hdrs[i].msg_hdr, hdrs[i].msg_len as usize);
+        meta[i] = decode_recv(
+            &names[i],
+            &hdrs[i].msg_hdr,
+            hdrs[i].msg_len as usize,
+            overflows,
+        );
     }
     Ok(msg_count as usize)
 }
 
 #[cfg(any(target_os = "macos", target_os = "ios"))]
-fn recv(io: SockRef<'_>, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+fn recv<const N: usize>(
+    io: SockRef<'_>,
+    bufs: &mut [IoSliceMut<'_>],
+    meta: &mut [RecvMeta],
+    overflows: &AtomicU64,
+) -> io::Result<usize> {
     let mut name = MaybeUninit::<libc::sockaddr_storage>::uninit();
-    let mut ctrl = cmsg::Aligned(MaybeUninit::<[u8; CMSG_LEN]>::uninit());
+    let mut ctrl = cmsg::Aligned(MaybeUninit::<[u8; N]>::uninit());
     let mut hdr = unsafe { mem::zeroed::<libc::msghdr>() };
     prepare_recv(&mut bufs[0], &mut name, &mut ctrl, &mut hdr);
     let n = loop {
@@ -433,7 +540,7 @@ fn recv(io: SockRef<'_>, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) ->
         }
         break n;
     };
-    meta[0] = decode_recv(&name, &hdr, n as usize);
+    meta[0] = decode_recv(&name, &hdr, n as usize, overflows);
     Ok(1)
 }
 
@@ -516,6 +623,18 @@ pub(crate) fn udp_state() -> UdpState {
 
 const CMSG_LEN: usize = 88;
 
+/// Control message space for sockets set to report receive timestamps, which take another message
+#[cfg(target_os = "linux")]
+const TIMESTAMPED_CMSG_LEN: usize = CMSG_LEN + 32;
+
+// States of `UdpSocketState::recv_timestamps`
+#[cfg(target_os = "linux")]
+const TIMESTAMPS_UNKNOWN: u8 = 0;
+#[cfg(target_os = "linux")]
+const TIMESTAMPS_OFF: u8 = 1;
+#[cfg(target_os = "linux")]
+const TIMESTAMPS_ON: u8 = 2;
+
 fn prepare_msg(
     transmit: &Transmit,
     dst_addr: &socket2::SockAddr,
@@ -596,10 +715,10 @@ fn prepare_msg(
     encoder.finish();
 }
 
-fn prepare_recv(
+fn prepare_recv<const N: usize>(
     buf: &mut IoSliceMut,
     name: &mut MaybeUninit<libc::sockaddr_storage>,
-    ctrl: &mut cmsg::Aligned<MaybeUninit<[u8; CMSG_LEN]>>,
+    ctrl: &mut cmsg::Aligned<MaybeUninit<[u8; N]>>,
     hdr: &mut libc::msghdr,
 ) {
     hdr.msg_name = name.as_mut_ptr() as _;
@@ -607,7 +726,7 @@ fn prepare_recv(
     hdr.msg_iov = buf as *mut IoSliceMut as *mut libc::iovec;
     hdr.msg_iovlen = 1;
     hdr.msg_control = ctrl.0.as_mut_ptr() as _;
-    hdr.msg_controllen = CMSG_LEN as _;
+    hdr.msg_controllen = N as _;
     hdr.msg_flags = 0;
 }
 
@@ -615,12 +734,16 @@ fn decode_recv(
     name: &MaybeUninit<libc::sockaddr_storage>,
     hdr: &libc::msghdr,
     len: usize,
+    #[allow(unused_variables)] // only used on Linux
+    overflows: &AtomicU64,
 ) -> RecvMeta {
     let name = unsafe { name.assume_init() };
     let mut ecn_bits = 0;
     let mut dst_ip = None;
     #[allow(unused_mut)] // only mutable on Linux
     let mut stride = len;
+    #[allow(unused_mut)] // only mutable on Linux
+    let mut timestamp = None;
 
     let cmsg_iter = unsafe { cmsg::Iter::new(hdr) };
     for cmsg in cmsg_iter {
@@ -661,6 +784,17 @@ fn decode_recv(
             (libc::SOL_UDP, libc::UDP_GRO) => unsafe {
                 stride = cmsg::decode::<libc::c_int>(cmsg) as usize;
             },
+            #[cfg(target_os = "linux")]
+            (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) => {
+                // A running total, which only wraps after billions of drops
+                let count = unsafe { cmsg::decode::<u32>(cmsg) };
+                overflows.store(count.into(), Ordering::Relaxed);
+            }
+            #[cfg(target_os = "linux")]
+            (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                let ts = unsafe { cmsg::decode::<libc::timespec>(cmsg) };
+                timestamp = Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32));
+            }
             _ => {}
         }
     }
@@ -695,6 +829,7 @@ fn decode_recv(
         addr,
         ecn: EcnCodepoint::from_bits(ecn_bits),
         dst_ip,
+        timestamp,
     }
 }
 
@@ -800,6 +935,30 @@ fn set_socket_option(
     }
 }
 
+#[cfg(target_os = "linux")]
+fn get_socket_option(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> Result<libc::c_int, io::Error> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of_val(&value) as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut _ as _,
+            &mut len,
+        )
+    };
+
+    match rc == 0 {
+        true => Ok(value),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
 const OPTION_ON: libc::c_int = 1;
 
 #[cfg(not(target_os = "linux"))]
@@ -2,18 +2,25 @@ use std::{
     io::{self, IoSliceMut},
     mem,
     os::windows::io::AsRawSocket,
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
     time::Instant,
 };
 
 use windows_sys::Win32::Networking::WinSock;
 
-use super::{log_sendmsg_error, RecvMeta, Transmit, UdpSockRef, UdpState, IO_ERROR_LOG_INTERVAL};
+use super::{
+    is_transient_send_error, log_sendmsg_error, LocalDrops, RecvMeta, Transmit, UdpSockRef,
+    UdpState, IO_ERROR_LOG_INTERVAL,
+};
 
 /// QUIC-friendly UDP interface for Windows
 #[derive(Debug)]
 pub struct UdpSocketState {
     last_send_error: Mutex<Instant>,
+    send_errors: AtomicU64,
 }
 
 impl UdpSocketState {
@@ -21,6 +28,7 @@ impl UdpSocketState {
         let now = Instant::now();
         Self {
             last_send_error: Mutex::new(now.checked_sub(2 * IO_ERROR_LOG_INTERVAL).unwrap_or(now)),
+            send_errors: AtomicU64::new(0),
         }
     }
 
@@ -80,6 +88,14 @@ impl UdpSocketState {
         Ok(())
     }
 
+    /// Have datagrams received on `socket` report when the kernel received them
+    ///
+    /// Receive timestamps aren't supported on this platform, so this does nothing.
+    pub fn set_recv_timestamps(socket: UdpSockRef<'_>, enabled: bool) -> io::Result<()> {
+        let _ = (socket, enabled);
+        Ok(())
+    }
+
     pub fn send(
         &self,
         socket: UdpSockRef<'_>,
@@ -100,13 +116,15 @@ impl UdpSocketState {
                 // recurring on the next call.
                 Err(_) if sent != 0 => return Ok(sent),
                 Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
+                    // Left for the caller to retry in a moment
+                    if e.kind() == io::ErrorKind::WouldBlock || is_transient_send_error(&e) {
                         return Err(e);
                     }
 
                     // Other errors are ignored, since they will usually be handled
                     // by higher level retransmits and timeouts.
                     log_sendmsg_error(&self.last_send_error, e, transmit);
+                    self.send_errors.fetch_add(1, Ordering::Relaxed);
                     sent += 1;
                 }
             }
@@ -114,6 +132,16 @@ impl UdpSocketState {
         Ok(sent)
     }
 
+    /// Datagrams known to have been lost locally
+    ///
+    /// Only send errors are detected on this platform.
+    pub fn local_drops(&self) -> LocalDrops {
+        LocalDrops {
+            receive_overflows: 0,
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+        }
+    }
+
     pub fn recv(
         &self,
         socket: UdpSockRef<'_>,
@@ -134,6 +162,7 @@ impl UdpSocketState {
             addr: addr.as_socket().unwrap(),
             ecn: None,
             dst_ip: None,
+            timestamp: None,
         };
         Ok(1)
     }
@@ -16,30 +16,41 @@ benchmark_group!(
     large_data_1_stream,
     large_data_10_streams,
     small_data_1_stream,
-    small_data_100_streams
+    small_data_100_streams,
+    small_data_100_streams_jls_forwarding_disabled
 );
 benchmark_main!(benches);
 
 fn large_data_1_stream(bench: &mut Bencher) {
-    send_data(bench, LARGE_DATA, 1);
+    send_data(bench, LARGE_DATA, 1, true);
 }
 
 fn large_data_10_streams(bench: &mut Bencher) {
-    send_data(bench, LARGE_DATA, 10);
+    send_data(bench, LARGE_DATA, 10, true);
 }
 
 fn small_data_1_stream(bench: &mut Bencher) {
-    send_data(bench, SMALL_DATA, 1);
+    send_data(bench, SMALL_DATA, 1, true);
 }
 
 fn small_data_100_streams(bench: &mut Bencher) {
-    send_data(bench, SMALL_DATA, 100);
+    send_data(bench, SMALL_DATA, 100, true);
 }
 
-fn send_data(bench: &mut Bencher, data: &'static [u8], concurrent_streams: usize) {
+/// Per-datagram cost without JLS forwarding, to compare with `small_data_100_streams`
+fn small_data_100_streams_jls_forwarding_disabled(bench: &mut Bencher) {
+    send_data(bench, SMALL_DATA, 100, false);
+}
+
+fn send_data(
+    bench: &mut Bencher,
+    data: &'static [u8],
+    concurrent_streams: usize,
+    jls_forwarding: bool,
+) {
     let _ = tracing_subscriber::fmt::try_init();
 
-    let ctx = Context::new();
+    let ctx = Context::new(jls_forwarding);
     let (addr, thread) = ctx.spawn_server();
     let (endpoint, client, runtime) = ctx.make_client(addr);
     let client = Arc::new(client);
@@ -69,12 +80,13 @@ fn send_data(bench: &mut Bencher, data: &'static [u8], concurrent_streams: usize
 }
 
 struct Context {
+    endpoint_config: quinn::EndpointConfig,
     server_config: quinn::ServerConfig,
     client_config: quinn::ClientConfig,
 }
 
 impl Context {
-    fn new() -> Self {
+    fn new(jls_forwarding: bool) -> Self {
         let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
         let key = rustls::PrivateKey(cert.serialize_private_key_der());
         let cert = rustls::Certificate(cert.serialize_der().unwrap());
@@ -88,7 +100,10 @@ impl Context {
         roots.add(&cert).unwrap();
 
         let client_config = quinn::ClientConfig::with_root_certificates(roots);
+        let mut endpoint_config = quinn::EndpointConfig::default();
+        endpoint_config.jls_forwarding(jls_forwarding);
         Self {
+            endpoint_config,
             server_config,
             client_config,
         }
@@ -97,18 +112,13 @@ impl Context {
     pub fn spawn_server(&self) -> (SocketAddr, thread::JoinHandle<()>) {
         let sock = UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
         let addr = sock.local_addr().unwrap();
+        let endpoint_config = self.endpoint_config.clone();
         let config = self.server_config.clone();
         let handle = thread::spawn(move || {
             let runtime = rt();
             let endpoint = {
                 let _guard = runtime.enter();
-                Endpoint::new(
-                    Default::default(),
-                    Some(config),
-                    sock,
-                    Arc::new(TokioRuntime),
-                )
-                .unwrap()
+                Endpoint::new(endpoint_config, Some(config), sock, Arc::new(TokioRuntime)).unwrap()
             };
             let handle = runtime.spawn(
                 async move {
@@ -144,7 +154,15 @@ impl Context {
         let runtime = rt();
         let endpoint = {
             let _guard = runtime.enter();
-            Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap()
+            let sock =
+                UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+            Endpoint::new(
+                self.endpoint_config.clone(),
+                None,
+                sock,
+                Arc::new(TokioRuntime),
+            )
+            .unwrap()
         };
         let connection = runtime
             .block_on(async {
@@ -0,0 +1,216 @@
+//! Counts the timer wakeups of a thousand paced connections, with and without a pacing horizon
+//!
+//! Connections only pace when the round trip is long enough for their congestion window to take
+//! several bursts, so traffic goes through a relay holding each datagram back for a while.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use quinn::{AsyncTimer, AsyncUdpSocket, Endpoint, Runtime, TokioRuntime};
+
+const CONNECTIONS: usize = 1000;
+const DATA_PER_CONNECTION: usize = 64 * 1024;
+const ONE_WAY_DELAY: Duration = Duration::from_millis(20);
+
+static DATA: [u8; DATA_PER_CONNECTION] = [0xAB; DATA_PER_CONNECTION];
+
+fn main() {
+    let _ = tracing_subscriber::fmt::try_init();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    for horizon in [None, Some(Duration::from_millis(5))] {
+        let start = Instant::now();
+        let wakeups = runtime.block_on(run(horizon));
+        println!(
+            "pacing horizon {:?}: {} timer wakeups for {} connections in {:?}",
+            horizon,
+            wakeups,
+            CONNECTIONS,
+            start.elapsed()
+        );
+    }
+}
+
+/// Upload `DATA_PER_CONNECTION` over each of `CONNECTIONS` connections at once, returning how many
+/// times the endpoints' and connections' timers woke them up
+async fn run(horizon: Option<Duration>) -> u64 {
+    let runtime = Arc::new(CountingRuntime {
+        inner: TokioRuntime,
+        wakeups: Arc::default(),
+    });
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let cert = rustls::Certificate(cert.serialize_der().unwrap());
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&cert).unwrap();
+
+    let mut transport_config = quinn::TransportConfig::default();
+    transport_config.pacing_horizon(horizon);
+    let transport_config = Arc::new(transport_config);
+    let mut server_config = quinn::ServerConfig::with_single_cert(vec![cert], key).unwrap();
+    server_config.transport_config(transport_config.clone());
+    let mut client_config = quinn::ClientConfig::with_root_certificates(roots);
+    client_config.transport_config(transport_config);
+
+    let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let server = Endpoint::new(
+        Default::default(),
+        Some(server_config),
+        UdpSocket::bind(localhost).unwrap(),
+        runtime.clone(),
+    )
+    .unwrap();
+    let relay = tokio::net::UdpSocket::bind(localhost).await.unwrap();
+    let relay_addr = relay.local_addr().unwrap();
+    tokio::spawn(run_relay(relay, server.local_addr().unwrap()));
+    let mut client = Endpoint::new(
+        Default::default(),
+        None,
+        UdpSocket::bind(localhost).unwrap(),
+        runtime.clone(),
+    )
+    .unwrap();
+    client.set_default_client_config(client_config);
+
+    let acceptor = server.clone();
+    let accepted = tokio::spawn(async move {
+        let mut readers = Vec::new();
+        for _ in 0..CONNECTIONS {
+            let connection = acceptor.accept().await.unwrap().await.unwrap();
+            readers.push(tokio::spawn(async move {
+                let mut recv = connection.accept_uni().await.unwrap();
+                recv.read_to_end(usize::MAX).await.unwrap();
+            }));
+        }
+        for reader in readers {
+            reader.await.unwrap();
+        }
+    });
+
+    let connections = (0..CONNECTIONS)
+        .map(|_| {
+            let connecting = client.connect(relay_addr, "localhost").unwrap();
+            tokio::spawn(async move {
+                let connection = connecting.await.unwrap();
+                let mut send = connection.open_uni().await.unwrap();
+                send.write_all(&DATA).await.unwrap();
+                send.finish().await.unwrap();
+                connection
+            })
+        })
+        .collect::<Vec<_>>();
+    // Connections stay open until the server has read everything
+    let mut open = Vec::new();
+    for connection in connections {
+        open.push(connection.await.unwrap());
+    }
+    accepted.await.unwrap();
+    let wakeups = runtime.wakeups.load(Ordering::Relaxed);
+
+    client.close(0u32.into(), b"done");
+    server.close(0u32.into(), b"done");
+    wakeups
+}
+
+/// Relay datagrams between the client and `server` after `ONE_WAY_DELAY`
+///
+/// The client is whichever address last sent something other than the server.
+async fn run_relay(socket: tokio::net::UdpSocket, server: SocketAddr) {
+    let socket = Arc::new(socket);
+    let (send, mut recv) = mpsc::unbounded_channel::<(tokio::time::Instant, SocketAddr, Vec<u8>)>();
+    let sender = socket.clone();
+    // Every datagram waits equally long, so taking them in turn keeps them in order
+    tokio::spawn(async move {
+        while let Some((deadline, destination, data)) = recv.recv().await {
+            tokio::time::sleep_until(deadline).await;
+            let _ = sender.send_to(&data, destination).await;
+        }
+    });
+
+    let mut client = None;
+    let mut buf = vec![0; 65536];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let destination = if from == server {
+            match client {
+                Some(x) => x,
+                None => continue,
+            }
+        } else {
+            client = Some(from);
+            server
+        };
+        let deadline = tokio::time::Instant::now() + ONE_WAY_DELAY;
+        if send
+            .send((deadline, destination, buf[..len].to_vec()))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Counts how many times timers expire, e.g. to wake a connection up for pacing
+#[derive(Debug)]
+struct CountingRuntime {
+    inner: TokioRuntime,
+    wakeups: Arc<AtomicU64>,
+}
+
+impl Runtime for CountingRuntime {
+    fn new_timer(&self, i: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(CountingTimer {
+            inner: self.inner.new_timer(i),
+            wakeups: self.wakeups.clone(),
+            armed: true,
+        })
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn std::future::Future<Output = ()> + Send>>) {
+        self.inner.spawn(future)
+    }
+
+    fn wrap_udp_socket(&self, t: UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        self.inner.wrap_udp_socket(t)
+    }
+}
+
+#[derive(Debug)]
+struct CountingTimer {
+    inner: Pin<Box<dyn AsyncTimer>>,
+    wakeups: Arc<AtomicU64>,
+    /// Whether the timer was set since it last expired
+    armed: bool,
+}
+
+impl AsyncTimer for CountingTimer {
+    fn reset(mut self: Pin<&mut Self>, i: Instant) {
+        self.armed = true;
+        self.inner.as_mut().reset(i)
+    }
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let poll = self.inner.as_mut().poll(cx);
+        if poll.is_ready() && self.armed {
+            self.armed = false;
+            self.wakeups.fetch_add(1, Ordering::Relaxed);
+        }
+        poll
+    }
+}
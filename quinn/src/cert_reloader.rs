@@ -0,0 +1,91 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+};
+
+/// Serves a certificate chain from files on disk, reloading them on demand
+///
+/// Pass to [`ServerConfig::with_cert_resolver`](crate::ServerConfig::with_cert_resolver) so
+/// certificates can be rotated without touching the endpoint: after new files are written, call
+/// [`reload()`](Self::reload), e.g. on `SIGHUP` or from a file watcher, and every handshake from
+/// then on presents the new chain. Established connections are unaffected.
+pub struct CertReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertReloader {
+    /// Load a PEM-encoded certificate chain and private key
+    ///
+    /// The chain must start with the end-entity certificate. The key may be in PKCS#8, PKCS#1
+    /// (RSA) or SEC1 (EC) format.
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> io::Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let current = load(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Read the files again, replacing the chain presented to new handshakes
+    ///
+    /// On error, the previously loaded chain stays in use.
+    pub fn reload(&self) -> io::Result<()> {
+        let new = load(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = new;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for CertReloader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertReloader")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish_non_exhaustive()
+    }
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let chain = rustls_pemfile::certs(&mut &*fs::read(cert_path)?)?;
+    if chain.is_empty() {
+        return Err(invalid_data("no certificates found"));
+    }
+    let chain = chain.into_iter().map(rustls::Certificate).collect();
+
+    let key = fs::read(key_path)?;
+    let mut key = &*key;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key)? {
+            Some(rustls_pemfile::Item::PKCS8Key(x))
+            | Some(rustls_pemfile::Item::RSAKey(x))
+            | Some(rustls_pemfile::Item::ECKey(x)) => break rustls::PrivateKey(x),
+            Some(_) => {}
+            None => return Err(invalid_data("no private key found")),
+        }
+    };
+    let key =
+        sign::any_supported_type(&key).map_err(|_| invalid_data("unsupported private key"))?;
+
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -4,7 +4,10 @@ use std::{
     future::Future,
     net::{IpAddr, SocketAddr},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll, Waker},
     time::{Duration, Instant},
 };
@@ -12,18 +15,23 @@ use std::{
 use crate::runtime::{AsyncTimer, Runtime};
 use bytes::Bytes;
 use pin_project_lite::pin_project;
-use proto::{ConnectionError, ConnectionHandle, ConnectionStats, Dir, StreamEvent, StreamId};
+use proto::{
+    ConnectionError, ConnectionHandle, ConnectionHealth, ConnectionStats, Dir, MigrationPolicy,
+    PathInfo, StreamEvent, StreamId,
+};
 use rustc_hash::FxHashMap;
 use thiserror::Error;
-use tokio::sync::{futures::Notified, mpsc, oneshot, Notify};
+use tokio::sync::{futures::Notified, oneshot, Notify};
 use tracing::debug_span;
 use udp::UdpState;
 
 use crate::{
+    event_queue::{EventQueueStats, EventReceiver},
     mutex::Mutex,
+    reaper::ActivityTracker,
     recv_stream::RecvStream,
     send_stream::{SendStream, WriteError},
-    ConnectionEvent, EndpointEvent, VarInt,
+    ConnectionEvent, ConnectionPhase, EndpointEvent, EndpointEventSender, VarInt,
 };
 use proto::congestion::Controller;
 
@@ -34,16 +42,19 @@ pub struct Connecting {
     conn: Option<ConnectionRef>,
     connected: oneshot::Receiver<bool>,
     handshake_data_ready: Option<oneshot::Receiver<()>>,
+    /// Fires when the handshake is given up on, if it's [to be](Self::with_timeout)
+    timeout: Option<Pin<Box<dyn AsyncTimer>>>,
 }
 
 impl Connecting {
     pub(crate) fn new(
         handle: ConnectionHandle,
         conn: proto::Connection,
-        endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
-        conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
+        endpoint_events: EndpointEventSender,
+        conn_events: EventReceiver,
         udp_state: Arc<UdpState>,
         runtime: Arc<dyn Runtime>,
+        deferred: Option<Deferred>,
     ) -> Self {
         let (on_handshake_data_send, on_handshake_data_recv) = oneshot::channel();
         let (on_connected_send, on_connected_recv) = oneshot::channel();
@@ -57,6 +68,7 @@ impl Connecting {
             udp_state,
             runtime.clone(),
         );
+        conn.state.lock("new").deferred = deferred;
 
         runtime.spawn(Box::pin(ConnectionDriver(conn.clone())));
 
@@ -64,9 +76,29 @@ impl Connecting {
             conn: Some(conn),
             connected: on_connected_recv,
             handshake_data_ready: Some(on_handshake_data_recv),
+            timeout: None,
         }
     }
 
+    /// Give up on the handshake unless it completes within `timeout`
+    ///
+    /// Dropping a `Connecting` that a timeout wrapper gave up on leaves the connection to go on
+    /// retransmitting until the handshake's own idle timeout. Instead, once `timeout` passes, this
+    /// closes the connection, sending the peer a close packet in case it's listening, lets the
+    /// endpoint forget it at once, and fails with [`ConnectionError::ConnectTimedOut`]. The timer
+    /// runs on the endpoint's runtime.
+    ///
+    /// Will panic if called after `poll` has returned `Ready`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        let conn_ref: &ConnectionRef = self.conn.as_ref().expect("used after yielding Ready");
+        let runtime = conn_ref.state.lock("with_timeout").runtime.clone();
+        // A deadline too far off to represent never comes
+        self.timeout = Instant::now()
+            .checked_add(timeout)
+            .map(|x| runtime.new_timer(x));
+        self
+    }
+
     /// Convert into a 0-RTT or 0.5-RTT connection at the cost of weakened security
     ///
     /// Opens up the connection for use before the handshake finishes, allowing the API user to
@@ -165,15 +197,54 @@ impl Connecting {
         let conn_ref: &ConnectionRef = self.conn.as_ref().expect("used after yielding Ready");
         conn_ref.state.lock("remote_address").inner.remote_address()
     }
+
+    /// The [`Connection::stable_id()`] the connection will have once established
+    ///
+    /// Will panic if called after `poll` has returned `Ready`.
+    pub fn stable_id(&self) -> usize {
+        let conn_ref: &ConnectionRef = self.conn.as_ref().expect("used after yielding Ready");
+        conn_ref.stable_id()
+    }
+
+    /// Whether the peer authenticated itself with JLS; see [`Connection::is_jls_authenticated()`]
+    ///
+    /// Servers know as soon as they accept the connection, since that takes the client's
+    /// ClientHello.
+    ///
+    /// Will panic if called after `poll` has returned `Ready`.
+    pub fn is_jls_authenticated(&self) -> bool {
+        let conn_ref: &ConnectionRef = self.conn.as_ref().expect("used after yielding Ready");
+        let conn = conn_ref.state.lock("is_jls_authenticated");
+        conn.inner.crypto_session().is_jls() == Some(true)
+    }
+
+    /// Limit the size of the UDP payloads the connection sends
+    ///
+    /// Lets a server lower the ceiling per incoming connection, much as
+    /// [`ClientConfig::max_udp_payload_size`](crate::ClientConfig::max_udp_payload_size) lets a
+    /// client choose it per outgoing one. Only datagrams sent from then on are affected: by the time
+    /// the application sees the connection, the server's first flight has usually gone out at the
+    /// [initial MTU](crate::ServerConfig::initial_mtu). See
+    /// [`Connection::set_max_udp_payload_size()`].
+    ///
+    /// Will panic if called after `poll` has returned `Ready`.
+    pub fn set_max_udp_payload_size(&self, value: u16) {
+        let conn_ref: &ConnectionRef = self.conn.as_ref().expect("used after yielding Ready");
+        conn_ref
+            .state
+            .lock("set_max_udp_payload_size")
+            .inner
+            .set_max_udp_payload_size(value);
+    }
 }
 
 impl Future for Connecting {
     type Output = Result<Connection, ConnectionError>;
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        Pin::new(&mut self.connected).poll(cx).map(|_| {
+        if let Poll::Ready(_) = Pin::new(&mut self.connected).poll(cx) {
             let conn = self.conn.take().unwrap();
             let inner = conn.state.lock("connecting");
-            if inner.connected {
+            return Poll::Ready(if inner.connected {
                 drop(inner);
                 Ok(Connection(conn))
             } else {
@@ -181,15 +252,31 @@ impl Future for Connecting {
                     .error
                     .clone()
                     .expect("connected signaled without connection success or error"))
-            }
-        })
+            });
+        }
+        match self.timeout {
+            Some(ref mut timer) if timer.as_mut().poll(cx).is_ready() => {}
+            _ => return Poll::Pending,
+        }
+        let conn = self.conn.take().unwrap();
+        let mut inner = conn.state.lock("connecting");
+        // The handshake may have finished, one way or the other, since `connected` was polled
+        if inner.connected {
+            drop(inner);
+            return Poll::Ready(Ok(Connection(conn)));
+        }
+        if let Some(ref e) = inner.error {
+            return Poll::Ready(Err(e.clone()));
+        }
+        inner.abandon(ConnectionError::ConnectTimedOut, &conn.shared);
+        Poll::Ready(Err(ConnectionError::ConnectTimedOut))
     }
 }
 
 /// Future that completes when a connection is fully established
 ///
-/// For clients, the resulting value indicates if 0-RTT was accepted. For servers, the resulting
-/// value is meaningless.
+/// The resulting value indicates if 0-RTT was accepted, as reported by
+/// [`Connection::early_data_accepted`].
 #[must_use = "futures/streams/sinks do nothing unless you `.await` or poll them"]
 pub struct ZeroRttAccepted(oneshot::Receiver<bool>);
 
@@ -200,6 +287,24 @@ impl Future for ZeroRttAccepted {
     }
 }
 
+/// Holds back a connection's sending until dropped
+///
+/// Returned by [`Connection::batch_scope()`].
+#[derive(Debug)]
+#[must_use = "the batch ends as soon as the guard is dropped"]
+pub struct BatchGuard(ConnectionRef);
+
+impl Drop for BatchGuard {
+    fn drop(&mut self) {
+        let conn = &mut *self.0.state.lock("BatchGuard::drop");
+        conn.batches -= 1;
+        if conn.batches == 0 {
+            conn.batch_timer = None;
+            conn.wake();
+        }
+    }
+}
+
 /// A future that drives protocol logic for a connection
 ///
 /// This future handles the protocol logic for a single connection, routing events from the
@@ -221,19 +326,30 @@ impl Future for ConnectionDriver {
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let conn = &mut *self.0.state.lock("poll");
 
-        let span = debug_span!("drive", id = conn.handle.0);
+        let span = debug_span!("drive", id = conn.handle.0, stable_id = self.0.stable_id());
         let _guard = span.enter();
 
         if let Err(e) = conn.process_conn_events(&self.0.shared, cx) {
             conn.terminate(e, &self.0.shared);
             return Poll::Ready(());
         }
-        let mut keep_going = conn.drive_transmit();
+        if let Some(ref mut deferred) = conn.deferred {
+            if deferred.as_mut().poll(cx).is_pending() && !conn.inner.is_closed() {
+                conn.driver = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            conn.deferred = None;
+        }
+        let mut keep_going = !conn.holding_batch(cx) && conn.drive_transmit();
         // If a timer expires, there might be more to transmit. When we transmit something, we
         // might need to reset a timer. Hence, we must loop until neither happens.
         keep_going |= conn.drive_timer(cx);
+        // Reported first so that the endpoint sees the connection draining before it's drained
+        conn.report_phase();
+        conn.report_health(&self.0.shared);
         conn.forward_endpoint_events();
         conn.forward_app_events(&self.0.shared);
+        conn.report_activity(self.0.stable_id());
 
         if !conn.inner.is_drained() {
             if keep_going {
@@ -313,6 +429,96 @@ impl Connection {
         }
     }
 
+    /// Measure the current round trip time to the peer
+    ///
+    /// Immediately sends a PING frame and completes with the time until the packet carrying it is
+    /// acknowledged, less any delay the peer reports having added before acknowledging. Calls made
+    /// while an earlier PING is still unacknowledged share its sample.
+    pub async fn ping(&self) -> Result<Duration, ConnectionError> {
+        let recv = {
+            let mut conn = self.0.state.lock("ping");
+            if let Some(ref x) = conn.error {
+                return Err(x.clone());
+            }
+            let (send, recv) = oneshot::channel();
+            conn.inner.ping_rtt();
+            conn.ping_waiters.push(send);
+            conn.wake();
+            recv
+        };
+        match recv.await {
+            Ok(rtt) => Ok(rtt),
+            // Waiters are only dropped when the connection is terminated
+            Err(_) => Err(self
+                .0
+                .state
+                .lock("ping")
+                .error
+                .clone()
+                .expect("ping abandoned without an error")),
+        }
+    }
+
+    /// Hold back sending while the application writes a burst of data
+    ///
+    /// Until the returned guard is dropped, the connection's driver doesn't build any packets,
+    /// so that data written to any number of streams in the meantime goes out together, in as
+    /// few packets and GSO batches as possible. ACKs are held back as well, so a batch that's
+    /// left open for longer than a few milliseconds is flushed regardless. Guards may overlap, in
+    /// which case the batch lasts until all of them are dropped.
+    pub fn batch_scope(&self) -> BatchGuard {
+        let mut conn = self.0.state.lock("batch_scope");
+        if conn.batches == 0 {
+            let deadline = Instant::now() + MAX_BATCH_DELAY;
+            conn.batch_timer = Some(conn.runtime.new_timer(deadline));
+        }
+        conn.batches += 1;
+        drop(conn);
+        BatchGuard(self.0.clone())
+    }
+
+    /// Whether the path to the peer seems to be working
+    ///
+    /// Reports trouble well before the idle timeout would close the connection, e.g. when a NAT
+    /// rebinding or network change leaves it sending into a black hole, and recovers once packets
+    /// are acknowledged again. Thresholds are set by
+    /// [`TransportConfig::degraded_after_ptos()`](crate::TransportConfig::degraded_after_ptos)
+    /// and [`TransportConfig::broken_after_ptos()`](crate::TransportConfig::broken_after_ptos).
+    pub fn health(&self) -> ConnectionHealth {
+        self.0.state.lock("health").health
+    }
+
+    /// Wait for [`health()`](Self::health) to change, returning the new value
+    ///
+    /// Fails once the connection is closed, after which its health no longer changes.
+    pub async fn health_changed(&self) -> Result<ConnectionHealth, ConnectionError> {
+        {
+            let conn = self.0.state.lock("health_changed");
+            if let Some(error) = conn.error.as_ref() {
+                return Err(error.clone());
+            }
+            // Construct the future while the lock is held, as in `closed()`
+            self.0.shared.health.notified()
+        }
+        .await;
+        let conn = self.0.state.lock("health_changed");
+        match conn.error.as_ref() {
+            Some(error) => Err(error.clone()),
+            None => Ok(conn.health),
+        }
+    }
+
+    /// Call `f` with each new value of [`health()`](Self::health)
+    ///
+    /// Replaces any callback set earlier. Called from the connection's driver, which is blocked
+    /// until it returns, so `f` should be quick and must not use the connection.
+    pub fn on_health_change<F>(&self, f: F)
+    where
+        F: Fn(ConnectionHealth) + Send + 'static,
+    {
+        self.0.state.lock("on_health_change").on_health_change = Some(Box::new(f));
+    }
+
     /// Wait for the connection to be closed for any reason
     ///
     /// Despite the return type's name, closed connections are often not an error condition at the
@@ -437,7 +643,8 @@ impl Connection {
     /// This can be different from the address the endpoint is bound to, in case
     /// the endpoint is bound to a wildcard address like `0.0.0.0` or `::`.
     ///
-    /// This will return `None` for clients.
+    /// This will return `None` for clients, unless the endpoint was
+    /// [rebound](crate::Endpoint::rebind) and a datagram has since been received.
     ///
     /// Retrieving the local IP address is currently supported on the following
     /// platforms:
@@ -454,11 +661,63 @@ impl Connection {
         self.0.state.lock("rtt").inner.rtt()
     }
 
+    /// Transport parameters outside of RFC 9000 that the peer sent, sorted by id
+    ///
+    /// Only parameters registered with `transport_parameter()` or `record_transport_parameter()`
+    /// on the [`ClientConfig`](crate::ClientConfig) or [`ServerConfig`](crate::ServerConfig) are
+    /// included. Complete once the connection is established.
+    pub fn peer_custom_transport_parameters(&self) -> Vec<(u64, Bytes)> {
+        self.0
+            .state
+            .lock("peer_custom_transport_parameters")
+            .inner
+            .peer_custom_transport_parameters()
+    }
+
+    /// The network path currently in use
+    pub fn current_path(&self) -> PathInfo {
+        self.0.state.lock("current_path").inner.current_path()
+    }
+
+    /// Control whether the connection may move to a different network path
+    ///
+    /// Use [`MigrationPolicy::Disallow`] to pin the current path, so that the peer can't move a
+    /// server connection elsewhere and [rebinding](crate::Endpoint::rebind) the endpoint isn't
+    /// announced to the peer until the application next sends something.
+    pub fn set_migration_policy(&self, policy: MigrationPolicy) {
+        self.0
+            .state
+            .lock("set_migration_policy")
+            .inner
+            .set_migration_policy(policy);
+    }
+
+    /// Limit the size of the UDP payloads this connection sends, including MTU probes
+    ///
+    /// The limit can only be lowered, and never below the transport config's
+    /// [`min_mtu`](crate::TransportConfig::min_mtu). It applies at once, and to any path the
+    /// connection migrates to.
+    pub fn set_max_udp_payload_size(&self, value: u16) {
+        self.0
+            .state
+            .lock("set_max_udp_payload_size")
+            .inner
+            .set_max_udp_payload_size(value);
+    }
+
     /// Returns connection statistics
     pub fn stats(&self) -> ConnectionStats {
         self.0.state.lock("stats").inner.stats()
     }
 
+    /// Statistics about the events the endpoint has queued for this connection
+    ///
+    /// A persistently deep queue means the connection's driver task isn't keeping up with incoming
+    /// traffic.
+    pub fn event_queue_stats(&self) -> EventQueueStats {
+        self.0.state.lock("event_queue_stats").conn_events.stats()
+    }
+
     /// Current state of the congestion control algorithm, for debugging purposes
     pub fn congestion_state(&self) -> Box<dyn Controller> {
         self.0
@@ -485,6 +744,22 @@ impl Connection {
             .handshake_data()
     }
 
+    /// The application protocol negotiated with ALPN, if any
+    ///
+    /// Available as soon as the connection is, so a server can route connections by protocol
+    /// before accepting any streams. Servers need not check for a missing or unexpected protocol:
+    /// when [`rustls::ServerConfig::alpn_protocols`] is non-empty, handshakes from clients offering
+    /// none of them fail with the TLS `no_application_protocol` alert, as QUIC requires. Leave it
+    /// empty to accept clients that don't use ALPN.
+    #[cfg(feature = "tls-rustls")]
+    pub fn alpn(&self) -> Option<Bytes> {
+        self.handshake_data()?
+            .downcast::<proto::crypto::rustls::HandshakeData>()
+            .ok()?
+            .protocol
+            .map(Bytes::from)
+    }
+
     /// Cryptographic identity of the peer
     ///
     /// The dynamic type returned is determined by the configured
@@ -502,7 +777,12 @@ impl Connection {
     /// A stable identifier for this connection
     ///
     /// Peer addresses and connection IDs can change, but this value will remain
-    /// fixed for the lifetime of the connection.
+    /// fixed for the lifetime of the connection. It's never reused by another connection in the
+    /// same process.
+    ///
+    /// To correlate logs, the same value is recorded as the `stable_id` field of the `drive` spans
+    /// in which the connection does its work, and reported by its endpoint's
+    /// [`debug_snapshot()`](crate::Endpoint::debug_snapshot) and reaper.
     pub fn stable_id(&self) -> usize {
         self.0.stable_id()
     }
@@ -557,6 +837,13 @@ impl Connection {
         conn.wake();
     }
 
+    /// See [`proto::TransportConfig::send_coalesce_delay()`]
+    pub fn set_send_coalesce_delay(&self, delay: Option<Duration>) {
+        let mut conn = self.0.state.lock("set_send_coalesce_delay");
+        conn.inner.set_send_coalesce_delay(delay);
+        conn.wake();
+    }
+
     /// Modify the number of remotely initiated bidirectional streams that may be concurrently open
     ///
     /// No streams may be opened by the peer unless fewer than `count` are already open. Large
@@ -574,6 +861,57 @@ impl Connection {
         let conn = self.0.state.lock("get jls authentication state");
         conn.inner.crypto_session().is_jls()
     }
+
+    /// Whether the peer authenticated itself with JLS
+    ///
+    /// Tells genuine JLS clients apart from those that merely completed an ordinary TLS
+    /// handshake, e.g. with forwarding disabled. Always `false` for connections accepted with a
+    /// [`ServerConfig`](crate::ServerConfig) that doesn't use JLS, or for names an
+    /// [`SniJlsServerConfig`](proto::crypto::rustls::SniJlsServerConfig) serves plainly.
+    pub fn is_jls_authenticated(&self) -> bool {
+        self.is_jls() == Some(true)
+    }
+
+    /// The server name the client asked for, if any
+    ///
+    /// For JLS connections, this is the camouflage name observers see in the ClientHello. Always
+    /// `None` for outgoing connections.
+    #[cfg(feature = "tls-rustls")]
+    pub fn server_name(&self) -> Option<String> {
+        self.handshake_data()?
+            .downcast::<proto::crypto::rustls::HandshakeData>()
+            .ok()?
+            .server_name
+    }
+
+    /// Whether the handshake resumed a previous TLS session
+    ///
+    /// Resumed sessions skip certificate authentication, which saves a round of expensive
+    /// cryptography and allows 0-RTT. Always `false` until the handshake completes.
+    pub fn resumed(&self) -> bool {
+        let conn = self.0.state.lock("resumed");
+        conn.inner.crypto_session().is_resumed()
+    }
+
+    /// Whether 0-RTT data was accepted
+    ///
+    /// For clients, whether the server accepted the 0-RTT data sent before the handshake
+    /// completed; meaningless until then. For servers, whether the client's 0-RTT data is being
+    /// accepted, which is never the case for replays refused under
+    /// [`ServerConfig::anti_replay_window`](crate::ServerConfig::anti_replay_window).
+    pub fn early_data_accepted(&self) -> bool {
+        let conn = self.0.state.lock("early_data_accepted");
+        conn.inner.accepted_0rtt()
+    }
+
+    /// For servers, the identity of the session ticket the client sent 0-RTT data with
+    ///
+    /// Distinct for every ticket, so applications may use it for their own replay protection.
+    /// `None` if the client did not attempt 0-RTT.
+    pub fn early_data_identity(&self) -> Option<Vec<u8>> {
+        let conn = self.0.state.lock("early_data_identity");
+        conn.inner.crypto_session().early_data_identity()
+    }
 }
 
 pin_project! {
@@ -750,13 +1088,14 @@ impl ConnectionRef {
     fn new(
         handle: ConnectionHandle,
         conn: proto::Connection,
-        endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
-        conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
+        endpoint_events: EndpointEventSender,
+        conn_events: EventReceiver,
         on_handshake_data: oneshot::Sender<()>,
         on_connected: oneshot::Sender<bool>,
         udp_state: Arc<UdpState>,
         runtime: Arc<dyn Runtime>,
     ) -> Self {
+        let phase = ConnectionPhase::of(&conn);
         Self(Arc::new(ConnectionInner {
             state: Mutex::new(State {
                 inner: conn,
@@ -773,17 +1112,27 @@ impl ConnectionRef {
                 blocked_readers: FxHashMap::default(),
                 finishing: FxHashMap::default(),
                 stopped: FxHashMap::default(),
+                ping_waiters: Vec::new(),
                 error: None,
                 ref_count: 0,
                 udp_state,
                 runtime,
+                activity: ActivityTracker::new(Instant::now()),
+                phase,
+                deferred: None,
+                health: ConnectionHealth::Healthy,
+                on_health_change: None,
+                batches: 0,
+                batch_timer: None,
+                transmits: 0,
             }),
             shared: Shared::default(),
+            stable_id: NEXT_STABLE_ID.fetch_add(1, Ordering::Relaxed),
         }))
     }
 
     fn stable_id(&self) -> usize {
-        &*self.0 as *const _ as usize
+        self.0.stable_id
     }
 }
 
@@ -821,6 +1170,7 @@ impl std::ops::Deref for ConnectionRef {
 pub(crate) struct ConnectionInner {
     pub(crate) state: Mutex<State>,
     pub(crate) shared: Shared,
+    stable_id: usize,
 }
 
 #[derive(Debug, Default)]
@@ -832,6 +1182,8 @@ pub(crate) struct Shared {
     stream_incoming: [Notify; 2],
     datagrams: Notify,
     closed: Notify,
+    /// Notified when the connection's health changes, or it's closed
+    health: Notify,
 }
 
 pub(crate) struct State {
@@ -843,21 +1195,50 @@ pub(crate) struct State {
     connected: bool,
     timer: Option<Pin<Box<dyn AsyncTimer>>>,
     timer_deadline: Option<Instant>,
-    conn_events: mpsc::UnboundedReceiver<ConnectionEvent>,
-    endpoint_events: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
+    conn_events: EventReceiver,
+    endpoint_events: EndpointEventSender,
     pub(crate) blocked_writers: FxHashMap<StreamId, Waker>,
     pub(crate) blocked_readers: FxHashMap<StreamId, Waker>,
     pub(crate) finishing: FxHashMap<StreamId, oneshot::Sender<Option<WriteError>>>,
     pub(crate) stopped: FxHashMap<StreamId, Waker>,
+    /// Callers of `Connection::ping` awaiting the next RTT sample
+    ping_waiters: Vec<oneshot::Sender<Duration>>,
     /// Always set to Some before the connection becomes drained
     pub(crate) error: Option<ConnectionError>,
     /// Number of live handles that can be used to initiate or handle I/O; excludes the driver
     ref_count: usize,
     udp_state: Arc<UdpState>,
     runtime: Arc<dyn Runtime>,
+    activity: ActivityTracker,
+    /// The phase the endpoint was last told the connection is in
+    phase: ConnectionPhase,
+    /// Must resolve before the connection starts sending, unless it's closed first
+    deferred: Option<Deferred>,
+    /// The health the application was last told of
+    health: ConnectionHealth,
+    on_health_change: Option<Box<dyn Fn(ConnectionHealth) + Send>>,
+    /// Number of live `BatchGuard`s
+    batches: usize,
+    /// Ends the current batch early; unset once it does, or if no batch is open
+    batch_timer: Option<Pin<Box<dyn AsyncTimer>>>,
+    /// Number of transmits handed to the endpoint so far
+    transmits: u64,
 }
 
 impl State {
+    /// Whether sending is held back by an open batch
+    fn holding_batch(&mut self, cx: &mut Context) -> bool {
+        let timer = match self.batch_timer {
+            Some(ref mut x) => x,
+            None => return false,
+        };
+        if timer.as_mut().poll(cx).is_pending() && !self.inner.is_closed() {
+            return true;
+        }
+        self.batch_timer = None;
+        false
+    }
+
     fn drive_transmit(&mut self) -> bool {
         let now = Instant::now();
         let mut transmits = 0;
@@ -869,10 +1250,14 @@ impl State {
                 None => 1,
                 Some(s) => (t.contents.len() + s - 1) / s, // round up
             };
+            let event = EndpointEvent::Transmit {
+                transmit: t,
+                handshake: self.inner.is_handshaking(),
+                ack_only: self.inner.last_transmit_ack_only(),
+            };
             // If the endpoint driver is gone, noop.
-            let _ = self
-                .endpoint_events
-                .send((self.handle, EndpointEvent::Transmit(t)));
+            let _ = self.endpoint_events.send((self.handle, event));
+            self.transmits += 1;
 
             if transmits >= MAX_TRANSMIT_DATAGRAMS {
                 // TODO: What isn't ideal here yet is that if we don't poll all
@@ -888,10 +1273,59 @@ impl State {
 
     fn forward_endpoint_events(&mut self) {
         while let Some(event) = self.inner.poll_endpoint_events() {
+            let event = match event.is_drained() {
+                true => EndpointEvent::Drained {
+                    transmits: self.transmits,
+                },
+                false => EndpointEvent::Proto(event),
+            };
+            // If the endpoint driver is gone, noop.
+            let _ = self.endpoint_events.send((self.handle, event));
+        }
+    }
+
+    /// Let the application know if the connection's health has changed
+    fn report_health(&mut self, shared: &Shared) {
+        let health = self.inner.health();
+        if health == self.health {
+            return;
+        }
+        self.health = health;
+        shared.health.notify_waiters();
+        if let Some(ref f) = self.on_health_change {
+            f(health);
+        }
+    }
+
+    /// Let the endpoint know if the connection has completed its handshake or been closed
+    fn report_phase(&mut self) {
+        let phase = ConnectionPhase::of(&self.inner);
+        if phase == self.phase || self.inner.is_drained() {
+            return;
+        }
+        self.phase = phase;
+        // If the endpoint driver is gone, noop.
+        let _ = self
+            .endpoint_events
+            .send((self.handle, EndpointEvent::Phase(phase)));
+    }
+
+    /// Let the endpoint know about recent stream and datagram activity, for use by its reaper
+    fn report_activity(&mut self, stable_id: usize) {
+        if self.inner.is_drained() {
+            // The endpoint has forgotten the connection, and may have reused its handle
+            return;
+        }
+        let stats = self.inner.stats();
+        let remote = self.inner.remote_address();
+        if let Some(activity) =
+            self.activity
+                .update(Instant::now(), self.handle, stable_id, remote, &stats)
+        {
             // If the endpoint driver is gone, noop.
             let _ = self
                 .endpoint_events
-                .send((self.handle, EndpointEvent::Proto(event)));
+                .send((self.handle, EndpointEvent::Activity(activity)));
         }
     }
 
@@ -903,8 +1337,8 @@ impl State {
     ) -> Result<(), ConnectionError> {
         loop {
             match self.conn_events.poll_recv(cx) {
-                Poll::Ready(Some(ConnectionEvent::Ping)) => {
-                    self.inner.ping();
+                Poll::Ready(Some(ConnectionEvent::Rebind)) => {
+                    self.inner.local_address_changed();
                 }
                 Poll::Ready(Some(ConnectionEvent::Proto(event))) => {
                     self.inner.handle_event(event);
@@ -912,6 +1346,8 @@ impl State {
                 Poll::Ready(Some(ConnectionEvent::Close { reason, error_code })) => {
                     self.close(error_code, reason, shared);
                 }
+                // A drained connection has no further use for the endpoint
+                Poll::Ready(None) if self.inner.is_drained() => return Ok(()),
                 Poll::Ready(None) => {
                     return Err(ConnectionError::TransportError(proto::TransportError {
                         code: proto::TransportErrorCode::INTERNAL_ERROR,
@@ -977,6 +1413,12 @@ impl State {
                         stopped.wake();
                     }
                 }
+                PingAcknowledged { rtt } => {
+                    for x in self.ping_waiters.drain(..) {
+                        // We don't care if the ping future was dropped
+                        let _ = x.send(rtt);
+                    }
+                }
                 Stream(StreamEvent::Stopped { id, error_code }) => {
                     if let Some(stopped) = self.stopped.remove(&id) {
                         stopped.wake();
@@ -1075,7 +1517,9 @@ impl State {
         for (_, waker) in self.stopped.drain() {
             waker.wake();
         }
+        self.ping_waiters.clear();
         shared.closed.notify_waiters();
+        shared.health.notify_waiters();
     }
 
     fn close(&mut self, error_code: VarInt, reason: Bytes, shared: &Shared) {
@@ -1084,6 +1528,15 @@ impl State {
         self.wake();
     }
 
+    /// Close without waiting out the closing period, so the endpoint forgets the connection as soon
+    /// as the close packet is sent
+    fn abandon(&mut self, reason: ConnectionError, shared: &Shared) {
+        self.inner
+            .abandon(Instant::now(), 0u32.into(), Bytes::new());
+        self.terminate(reason, shared);
+        self.wake();
+    }
+
     /// Close for a reason other than the application's explicit request
     pub(crate) fn implicit_close(&mut self, shared: &Shared) {
         self.close(0u32.into(), Bytes::new(), shared);
@@ -1104,10 +1557,14 @@ impl State {
 impl Drop for State {
     fn drop(&mut self) {
         if !self.inner.is_drained() {
-            // Ensure the endpoint can tidy up
+            // Ensure the endpoint can tidy up, having first heard everything the connection had to
+            // report
+            self.forward_endpoint_events();
             let _ = self.endpoint_events.send((
                 self.handle,
-                EndpointEvent::Proto(proto::EndpointEvent::drained()),
+                EndpointEvent::Drained {
+                    transmits: self.transmits,
+                },
             ));
         }
     }
@@ -1145,6 +1602,15 @@ pub enum SendDatagramError {
 /// and allows other tasks (like receiving ACKs) to run in between.
 const MAX_TRANSMIT_DATAGRAMS: usize = 20;
 
+/// How long a batch opened by [`Connection::batch_scope()`] may hold back sending
+const MAX_BATCH_DELAY: Duration = Duration::from_millis(5);
+
+/// Source of [`Connection::stable_id()`]
+static NEXT_STABLE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A future a connection waits on before sending its first packet
+pub(crate) type Deferred = Pin<Box<dyn Future<Output = ()> + Send>>;
+
 /// Error indicating that a stream has already been finished or reset
 #[derive(Debug, Error, Clone, PartialEq, Eq)]
 #[error("unknown stream")]
@@ -0,0 +1,163 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{mpsc, Arc},
+    thread,
+    time::Instant,
+};
+
+use proto::{EndpointConfig, ServerConfig};
+use tokio::{runtime::Handle, sync::oneshot};
+use tracing::error;
+
+use crate::{
+    endpoint::{check_socket_buffers, wrap_socket},
+    runtime::{AsyncTimer, AsyncUdpSocket, Runtime, TokioRuntime},
+    Endpoint, EndpointSetupError,
+};
+
+/// Options for [`Endpoint::new_on_dedicated_thread()`]
+#[derive(Debug, Clone)]
+pub struct DriverThreadConfig {
+    name: String,
+    #[cfg(target_os = "linux")]
+    core: Option<usize>,
+}
+
+impl DriverThreadConfig {
+    /// Name of the driver's thread, as shown by debuggers and profilers
+    ///
+    /// Defaults to `quinn-endpoint`.
+    pub fn name(&mut self, name: impl Into<String>) -> &mut Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Restrict the driver's thread to the CPU core numbered `core`
+    ///
+    /// Defaults to none, leaving the thread free to run on any core.
+    #[cfg(target_os = "linux")]
+    pub fn core(&mut self, core: usize) -> &mut Self {
+        self.core = Some(core);
+        self
+    }
+}
+
+impl Default for DriverThreadConfig {
+    fn default() -> Self {
+        Self {
+            name: "quinn-endpoint".into(),
+            #[cfg(target_os = "linux")]
+            core: None,
+        }
+    }
+}
+
+/// Build an endpoint whose driver runs on a runtime of its own, on a new thread
+pub(crate) fn spawn(
+    config: EndpointConfig,
+    server_config: Option<ServerConfig>,
+    socket: std::net::UdpSocket,
+    options: &DriverThreadConfig,
+) -> io::Result<Endpoint> {
+    let app = Handle::try_current().map_err(|_| EndpointSetupError::NoRuntime)?;
+    check_socket_buffers(&socket, &config);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let runtime = Arc::new(SplitRuntime {
+        app: app.clone(),
+        driver: rt.handle().clone(),
+    });
+    let socket = wrap_socket(&*runtime, socket, config.get_recv_timestamps())?;
+    let (endpoint, driver) =
+        Endpoint::new_with_manual_driver(config, server_config, socket, runtime)?;
+
+    let (started_send, started) = mpsc::channel();
+    let (exited_send, exited) = oneshot::channel();
+    #[cfg(target_os = "linux")]
+    let core = options.core;
+    let thread = thread::Builder::new()
+        .name(options.name.clone())
+        .spawn(move || {
+            #[cfg(target_os = "linux")]
+            if let Some(core) = core {
+                if let Err(e) = pin_to_core(core) {
+                    let _ = started_send.send(Err(e));
+                    return;
+                }
+            }
+            let _ = started_send.send(Ok(()));
+            if let Err(e) = rt.block_on(driver) {
+                error!("I/O error: {}", e);
+            }
+            drop(rt);
+            let _ = exited_send.send(());
+        })?;
+
+    if let Err(e) = started.recv().unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "endpoint driver thread failed to start",
+        ))
+    }) {
+        let _ = thread.join();
+        return Err(e);
+    }
+    app.spawn(async move {
+        let _ = exited.await;
+        // The thread has nothing left to do once it signals, but joining it still blocks
+        let joined = tokio::task::spawn_blocking(move || thread.join()).await;
+        if !matches!(joined, Ok(Ok(()))) {
+            error!("endpoint driver thread panicked");
+        }
+    });
+    Ok(endpoint)
+}
+
+/// Spawns tasks on the application's runtime, registers sockets with the driver's, and creates
+/// timers on whichever runtime is current
+///
+/// Connections are thus driven alongside the application, while the endpoint's sockets, including
+/// those it's [rebound](Endpoint::rebind) to, and the driver's timers stay on the driver's thread.
+#[derive(Debug)]
+struct SplitRuntime {
+    app: Handle,
+    driver: Handle,
+}
+
+impl Runtime for SplitRuntime {
+    fn new_timer(&self, i: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        TokioRuntime.new_timer(i)
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        self.app.spawn(future);
+    }
+
+    fn wrap_udp_socket(&self, t: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        let _guard = self.driver.enter();
+        TokioRuntime.wrap_udp_socket(t)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_core(core: usize) -> io::Result<()> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "CPU core out of range",
+        ));
+    }
+    // Safety: `cpu_set_t` is plain data, and `core` was checked to fit in it
+    let result = unsafe {
+        let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,32 +1,53 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BinaryHeap, HashMap, VecDeque},
+    fmt,
     future::Future,
+    hash::{BuildHasher, Hasher},
     io,
     io::IoSliceMut,
-    mem::MaybeUninit,
-    net::{SocketAddr, SocketAddrV6, ToSocketAddrs},
+    mem::{self, MaybeUninit},
+    net::{IpAddr, SocketAddr, SocketAddrV6, ToSocketAddrs},
     pin::Pin,
     str,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
     task::{Context, Poll, Waker},
-    time::Instant,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::runtime::{default_runtime, AsyncUdpSocket, Runtime};
+#[cfg(feature = "runtime-tokio")]
+use crate::driver_thread::{self, DriverThreadConfig};
+use crate::runtime::{default_runtime, AsyncTimer, AsyncUdpSocket, Runtime};
 use bytes::{Bytes, BytesMut};
 use pin_project_lite::pin_project;
 use proto::{
-    self as proto, ClientConfig, ConnectError, ConnectionHandle, DatagramEvent, ServerConfig,
+    self as proto, ClientConfig, ConnectError, ConnectionError, ConnectionHandle, ConnectionId,
+    DatagramEvent, EndpointStats, JlsUpstreamSelection, PrefixPolicy, ServerConfig,
+    StatelessResetPolicy, TransportConfig,
 };
 use rustc_hash::FxHashMap;
-use tokio::sync::{futures::Notified, mpsc, Notify};
-use tracing::{debug, trace};
-use udp::{RecvMeta, Transmit, UdpState, BATCH_SIZE};
+use thiserror::Error;
+use tokio::sync::{futures::Notified, mpsc, watch, Notify};
+use tracing::{debug, debug_span, trace, warn, Span};
+use udp::{LocalDrops, RecvMeta, UdpState, BATCH_SIZE};
 
 use crate::{
-    connection::Connecting, work_limiter::WorkLimiter, ConnectionEvent, EndpointConfig,
-    EndpointEvent, VarInt, IO_LOOP_BOUND, MAX_TRANSMIT_QUEUE_CONTENTS_LEN, RECV_TIME_BOUND,
-    SEND_TIME_BOUND,
+    connection::{Connecting, Connection, Deferred},
+    event_queue::{event_queue, EventSender},
+    ip_stats::{IpStats, IpStatsTable, IpThreshold},
+    jls_forward::{
+        client_label, long_header_cids, queue_segments, ForwardCommand, ForwardEnd, ForwardEvent,
+        ForwardPool,
+    },
+    reaper::{ConnectionActivity, Reap, ReapPolicy},
+    response_shaper::ResponseShaper,
+    snapshot::{ConnectionPhase, ConnectionSnapshot, EndpointSnapshot, ForwardSnapshot},
+    work_limiter::WorkLimiter,
+    EndpointConfig, EndpointEvent, EndpointEventSender, VarInt, IO_LOOP_BOUND,
+    MAX_TRANSMIT_QUEUE_CONTENTS_LEN, RECV_TIME_BOUND, SEND_TIME_BOUND,
 };
 
 /// A QUIC endpoint.
@@ -53,17 +74,13 @@ impl Endpoint {
     /// IPv6 address on Windows will not by default be able to communicate with IPv4
     /// addresses. Portable applications should bind an address that matches the family they wish to
     /// communicate within.
+    ///
+    /// Errors carry an [`EndpointSetupError`] telling which step failed.
     #[cfg(feature = "ring")]
     pub fn client(addr: SocketAddr) -> io::Result<Self> {
-        let socket = std::net::UdpSocket::bind(addr)?;
-        let runtime = default_runtime()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no async runtime found"))?;
-        Self::new_with_abstract_socket(
-            EndpointConfig::default(),
-            None,
-            runtime.wrap_udp_socket(socket)?,
-            runtime,
-        )
+        let socket = std::net::UdpSocket::bind(addr).map_err(EndpointSetupError::Bind)?;
+        let runtime = default_runtime().ok_or(EndpointSetupError::NoRuntime)?;
+        Self::new(EndpointConfig::default(), None, socket, runtime)
     }
 
     /// Helper to construct an endpoint for use with both incoming and outgoing connections
@@ -72,27 +89,27 @@ impl Endpoint {
     /// IPv6 address on Windows will not by default be able to communicate with IPv4
     /// addresses. Portable applications should bind an address that matches the family they wish to
     /// communicate within.
+    ///
+    /// Errors carry an [`EndpointSetupError`] telling which step failed.
     #[cfg(feature = "ring")]
     pub fn server(config: ServerConfig, addr: SocketAddr) -> io::Result<Self> {
-        let socket = std::net::UdpSocket::bind(addr)?;
-        let runtime = default_runtime()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no async runtime found"))?;
-        Self::new_with_abstract_socket(
-            EndpointConfig::default(),
-            Some(config),
-            runtime.wrap_udp_socket(socket)?,
-            runtime,
-        )
+        let socket = std::net::UdpSocket::bind(addr).map_err(EndpointSetupError::Bind)?;
+        let runtime = default_runtime().ok_or(EndpointSetupError::NoRuntime)?;
+        Self::new(EndpointConfig::default(), Some(config), socket, runtime)
     }
 
     /// Construct an endpoint with arbitrary configuration and socket
+    ///
+    /// Fails with an [`EndpointSetupError::SocketRejected`] if `runtime` can't wrap `socket`.
+    /// Warns if `socket`'s buffers are too small for the datagrams `config` allows.
     pub fn new(
         config: EndpointConfig,
         server_config: Option<ServerConfig>,
         socket: std::net::UdpSocket,
         runtime: Arc<dyn Runtime>,
     ) -> io::Result<Self> {
-        let socket = runtime.wrap_udp_socket(socket)?;
+        check_socket_buffers(&socket, &config);
+        let socket = wrap_socket(&*runtime, socket, config.get_recv_timestamps())?;
         Self::new_with_abstract_socket(config, server_config, socket, runtime)
     }
 
@@ -106,6 +123,53 @@ impl Endpoint {
         socket: Box<dyn AsyncUdpSocket>,
         runtime: Arc<dyn Runtime>,
     ) -> io::Result<Self> {
+        let (endpoint, driver) =
+            Self::new_with_manual_driver(config, server_config, socket, runtime)?;
+        endpoint.runtime.spawn(Box::pin(async {
+            if let Err(e) = driver.await {
+                tracing::error!("I/O error: {}", e);
+            }
+        }));
+        Ok(endpoint)
+    }
+
+    /// Construct an endpoint whose driver runs on a dedicated thread, with a Tokio runtime of its
+    /// own
+    ///
+    /// Keeps the endpoint's I/O isolated from the application, e.g. on a core reserved for it.
+    /// Must be called from within a Tokio runtime, on which
+    /// the connections' tasks are spawned, so the returned endpoint and its connections are used
+    /// just as if the endpoint had been constructed with [`new()`](Self::new).
+    ///
+    /// The thread exits, and is joined from the calling runtime, once the endpoint's driver
+    /// stops: when every handle to the endpoint has been dropped and its connections have
+    /// drained, or on an I/O error.
+    #[cfg(feature = "runtime-tokio")]
+    pub fn new_on_dedicated_thread(
+        config: EndpointConfig,
+        server_config: Option<ServerConfig>,
+        socket: std::net::UdpSocket,
+        options: &DriverThreadConfig,
+    ) -> io::Result<Self> {
+        driver_thread::spawn(config, server_config, socket, options)
+    }
+
+    /// Construct an endpoint whose I/O is driven by the caller, rather than by a task spawned on
+    /// `runtime`
+    ///
+    /// For applications with an event loop of their own. The endpoint does nothing until the
+    /// returned [`EndpointDriver`] is polled, and must be polled again whenever the waker passed
+    /// to its last poll is woken, including by calls on the endpoint or its connections, e.g.
+    /// [`connect()`](Self::connect) or [`close()`](Self::close). `runtime` is still used for the
+    /// connections' own tasks and for all timers, which wake the driver when its deadlines pass, so
+    /// the event loop needn't keep time for it. Time is read from the system clock throughout; it
+    /// can't be substituted.
+    pub fn new_with_manual_driver(
+        config: EndpointConfig,
+        server_config: Option<ServerConfig>,
+        socket: Box<dyn AsyncUdpSocket>,
+        runtime: Arc<dyn Runtime>,
+    ) -> io::Result<(Self, EndpointDriver)> {
         let addr = socket.local_addr()?;
         let allow_mtud = !socket.may_fragment();
         let rc = EndpointRef::new(
@@ -115,22 +179,21 @@ impl Endpoint {
             runtime.clone(),
         );
         let driver = EndpointDriver(rc.clone());
-        runtime.spawn(Box::pin(async {
-            if let Err(e) = driver.await {
-                tracing::error!("I/O error: {}", e);
-            }
-        }));
-        Ok(Self {
-            inner: rc,
-            default_client_config: None,
-            runtime,
-        })
+        Ok((
+            Self {
+                inner: rc,
+                default_client_config: None,
+                runtime,
+            },
+            driver,
+        ))
     }
 
     /// Get the next incoming connection attempt from a client
     ///
     /// Yields [`Connecting`] futures that must be `await`ed to obtain the final `Connection`, or
-    /// `None` if the endpoint is [`close`](Self::close)d.
+    /// `None` if the endpoint is [`close`](Self::close)d. Connections which are drained before
+    /// they're accepted, e.g. since the client gave up, are never yielded.
     pub fn accept(&self) -> Accept<'_> {
         Accept {
             endpoint: self,
@@ -138,6 +201,20 @@ impl Endpoint {
         }
     }
 
+    /// Get the next connection attempt from a client, before any TLS work is done for it
+    ///
+    /// Only yields anything if the server configuration enables
+    /// [`defer_incoming`](ServerConfig::defer_incoming), in which case [`accept()`](Self::accept)
+    /// yields nothing: [`Incoming::accept()`] hands over the connection instead. Attempts are
+    /// yielded once they pass the endpoint's own limits and address validation, so JLS clients
+    /// haven't been authenticated yet. Yields `None` if the endpoint is [`close`](Self::close)d.
+    pub fn accept_incoming(&self) -> AcceptIncoming<'_> {
+        AcceptIncoming {
+            endpoint: self,
+            notify: self.inner.shared.incoming.notified(),
+        }
+    }
+
     /// Set the client configuration used by `connect`
     pub fn set_default_client_config(&mut self, config: ClientConfig) {
         self.default_client_config = Some(config);
@@ -170,6 +247,81 @@ impl Endpoint {
         config: ClientConfig,
         addr: SocketAddr,
         server_name: &str,
+    ) -> Result<Connecting, ConnectError> {
+        self.connect_deferred(config, addr, server_name, None)
+    }
+
+    /// Connect to a remote endpoint using the default client configuration, with parts of it
+    /// overridden for this connection alone
+    ///
+    /// See [`connect()`] for details.
+    ///
+    /// [`connect()`]: Endpoint::connect
+    pub fn connect_with_options(
+        &self,
+        addr: SocketAddr,
+        server_name: &str,
+        options: &ConnectOptions,
+    ) -> Result<Connecting, ConnectError> {
+        let mut config = match &self.default_client_config {
+            Some(config) => config.clone(),
+            None => return Err(ConnectError::NoDefaultClientConfig),
+        };
+        if let Some(ref transport) = options.transport {
+            config.transport_config(transport.clone());
+        }
+        if let Some(ref factory) = options.congestion_controller_factory {
+            config.congestion_controller_factory(factory.clone());
+        }
+        self.connect_with(config, addr, server_name)
+    }
+
+    /// Connect to a remote endpoint once the network is ready
+    ///
+    /// Like [`connect_with()`](Self::connect_with), but the first flight of the handshake is only
+    /// sent once `ready` has resolved and the socket then reports that it can send, so that the
+    /// handshake's retransmissions aren't spent before e.g. the network interface is up. Pass
+    /// `async {}` as `ready` to wait for the socket alone. The handshake's timers don't start until
+    /// the first flight is sent, but closing the connection in the meantime abandons the wait.
+    pub fn connect_when_ready<F>(
+        &self,
+        config: ClientConfig,
+        addr: SocketAddr,
+        server_name: &str,
+        ready: F,
+    ) -> Result<Connecting, ConnectError>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let writable = SocketWritable {
+            endpoint: Arc::downgrade(&self.inner.0),
+            epoch: None,
+        };
+        let deferred = async move {
+            ready.await;
+            // Any error will be reported again by the first attempt to send
+            let _ = writable.await;
+        };
+        self.connect_deferred(config, addr, server_name, Some(Box::pin(deferred)))
+    }
+
+    /// Wait until the endpoint's socket reports that it can send
+    ///
+    /// Sockets which don't track send readiness are always reported as ready.
+    pub async fn wait_socket_writable(&self) -> io::Result<()> {
+        SocketWritable {
+            endpoint: Arc::downgrade(&self.inner.0),
+            epoch: None,
+        }
+        .await
+    }
+
+    fn connect_deferred(
+        &self,
+        config: ClientConfig,
+        addr: SocketAddr,
+        server_name: &str,
+        deferred: Option<Deferred>,
     ) -> Result<Connecting, ConnectError> {
         let mut endpoint = self.inner.state.lock().unwrap();
         if endpoint.driver_lost {
@@ -185,9 +337,39 @@ impl Endpoint {
         };
         let (ch, conn) = endpoint.inner.connect(config, addr, server_name)?;
         let udp_state = endpoint.udp_state.clone();
+        let now = Instant::now();
+        endpoint.ip_stats.opened(addr.ip(), now);
         Ok(endpoint
             .connections
-            .insert(ch, conn, udp_state, self.runtime.clone()))
+            .insert(ch, conn, now, udp_state, self.runtime.clone(), deferred))
+    }
+
+    /// Connect to whichever of several addresses of the same server answers first
+    ///
+    /// A handshake is started with the first address immediately. Each following address is tried
+    /// once the previous attempt has failed or [`CONNECTION_ATTEMPT_DELAY`] has elapsed without it
+    /// completing, whichever comes first, as in the Happy Eyeballs algorithm (RFC 8305). Callers
+    /// should therefore order `addrs` by preference, e.g. interleaving address families.
+    ///
+    /// Completes with the first connection to be established. All other attempts are closed and
+    /// their state released as soon as that happens, or when the returned future is dropped. If
+    /// every attempt fails, the error of the last one to fail is returned.
+    pub async fn connect_racing(
+        &self,
+        config: ClientConfig,
+        addrs: Vec<SocketAddr>,
+        server_name: &str,
+    ) -> Result<Connection, ConnectRacingError> {
+        ConnectRacing {
+            endpoint: self,
+            config,
+            server_name,
+            addrs: addrs.into_iter().collect(),
+            attempts: Vec::new(),
+            timer: None,
+            error: None,
+        }
+        .await
     }
 
     /// Switch to a new UDP socket
@@ -195,23 +377,120 @@ impl Endpoint {
     /// Allows the endpoint's address to be updated live, affecting all active connections. Incoming
     /// connections and connections to servers unreachable from the new address will be lost.
     ///
+    /// Clients forwarded to a JLS upstream only know the old address, so their forward connections
+    /// end here, along with any of the upstream's datagrams still queued for them, and are counted
+    /// in [`JlsUpstreamStats::ended_by_rebind`]. Should such a client send to the new address, it's
+    /// treated like any other new client.
+    ///
+    /// The old UDP socket is closed once the driver has next run, by which time it polls the new
+    /// one instead. Concurrent calls take effect one at a time, so the last to do so leaves the
+    /// endpoint on its socket and those of the others are closed like the old one.
+    ///
     /// On error, the old UDP socket is retained.
     pub fn rebind(&self, socket: std::net::UdpSocket) -> io::Result<()> {
+        let recv_timestamps = {
+            let endpoint = self.inner.state.lock().unwrap();
+            check_socket_buffers(&socket, endpoint.inner.config());
+            endpoint.inner.config().get_recv_timestamps()
+        };
+        let socket = wrap_socket(&*self.runtime, socket, recv_timestamps)?;
+        self.rebind_abstract(socket)
+    }
+
+    /// Switch to a new abstract UDP socket
+    ///
+    /// As [`rebind()`](Self::rebind), for endpoints on a socket of their own implementation, e.g.
+    /// one constructed with [`new_with_abstract_socket()`](Self::new_with_abstract_socket). Path
+    /// MTU discovery is allowed for new connections according to the new socket's
+    /// [`may_fragment()`](AsyncUdpSocket::may_fragment); existing connections keep what they
+    /// started with.
+    pub fn rebind_abstract(&self, socket: Box<dyn AsyncUdpSocket>) -> io::Result<()> {
         let addr = socket.local_addr()?;
-        let socket = self.runtime.wrap_udp_socket(socket)?;
         let mut inner = self.inner.state.lock().unwrap();
-        inner.socket = socket;
+        inner.inner.set_allow_mtud(!socket.may_fragment());
+        // The driver may still be registered to be woken by the old socket alone
+        let old = mem::replace(&mut inner.socket, socket);
+        let moved = old.local_addr().ok() != Some(addr);
+        inner.retired_sockets.push(old);
         inner.ipv6 = addr.is_ipv6();
 
-        // Generate some activity so peers notice the rebind
-        for sender in inner.connections.senders.values() {
-            // Ignoring errors from dropped connections
-            let _ = sender.send(ConnectionEvent::Ping);
+        let forwarded = inner.jls_state.end_all_by_rebind();
+        if !forwarded.is_empty() {
+            debug!("rebind ended {} JLS forward connections", forwarded.len());
+            inner.discard_outgoing_to(&forwarded);
+        }
+
+        // Connections whose address didn't change have nothing to tell their peers
+        if moved {
+            for sender in inner.connections.senders.values() {
+                sender.rebind();
+            }
+            let now = Instant::now();
+            for id in inner.pending.live() {
+                let conn = &mut inner.pending.conns.get_mut(&id).unwrap().conn;
+                conn.local_address_changed();
+                inner.drive_pending(id, now);
+            }
         }
+        inner.wake();
 
         Ok(())
     }
 
+    /// Receive datagrams on another UDP socket as well, e.g. one on a port middleboxes steer some
+    /// clients to
+    ///
+    /// Datagrams arriving on the socket are handled just like those arriving on the endpoint's
+    /// own, so connections carry on whichever of the sockets their peers' datagrams arrive on.
+    /// Datagrams are sent from the endpoint's own socket, unless
+    /// [`set_reply_socket()`](Self::set_reply_socket) says otherwise. The socket must be of the
+    /// same address family as the endpoint's own. A socket that fails is detached.
+    pub fn attach_socket(&self, socket: std::net::UdpSocket) -> io::Result<SocketId> {
+        let addr = socket.local_addr()?;
+        let recv_timestamps = {
+            let endpoint = self.inner.state.lock().unwrap();
+            if addr.is_ipv6() != endpoint.ipv6 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "address family differs from the endpoint's socket",
+                ));
+            }
+            check_socket_buffers(&socket, endpoint.inner.config());
+            endpoint.inner.config().get_recv_timestamps()
+        };
+        let socket = wrap_socket(&*self.runtime, socket, recv_timestamps)?;
+        let mut endpoint = self.inner.state.lock().unwrap();
+        let id = SocketId(endpoint.next_socket_id);
+        endpoint.next_socket_id += 1;
+        endpoint.attached.push(AttachedSocket {
+            id,
+            socket,
+            udp_state: Arc::new(UdpState::new()),
+            local_ip: Some(addr.ip()).filter(|x| !x.is_unspecified()),
+        });
+        endpoint.wake();
+        Ok(id)
+    }
+
+    /// Stop receiving on a socket attached with [`attach_socket()`](Self::attach_socket)
+    ///
+    /// Returns whether the socket was still attached.
+    pub fn detach_socket(&self, id: SocketId) -> bool {
+        self.inner.state.lock().unwrap().detach(id).is_some()
+    }
+
+    /// Choose which socket datagrams are sent from, given
+    /// [attached sockets](Self::attach_socket)
+    ///
+    /// Defaults to [`ReplySocket::Primary`].
+    pub fn set_reply_socket(&self, policy: ReplySocket) {
+        let mut endpoint = self.inner.state.lock().unwrap();
+        endpoint.reply_socket = policy;
+        if policy == ReplySocket::Primary {
+            endpoint.peer_sockets.clear();
+        }
+    }
+
     /// Replace the server configuration, affecting new incoming connections only
     ///
     /// Useful for e.g. refreshing TLS certificates without disrupting existing connections.
@@ -224,6 +503,75 @@ impl Endpoint {
             .set_server_config(server_config.map(Arc::new))
     }
 
+    /// Replace the JLS password, IV and upstreams, affecting new incoming connections only
+    ///
+    /// Useful for e.g. rotating the JLS secret. Established connections and clients already being
+    /// forwarded are left alone, the latter keeping the upstream they were forwarded to; clients
+    /// that only authenticate with the old secret are forwarded to the new upstreams from now on.
+    ///
+    /// Returns `false`, changing nothing, if the endpoint has no server configuration or its
+    /// cryptographic configuration doesn't support JLS.
+    #[cfg(feature = "tls-rustls")]
+    pub fn set_jls_config(&self, jls_config: rustls::JlsServerConfig) -> bool {
+        let mut endpoint = self.inner.state.lock().unwrap();
+        let mut server_config = match endpoint.inner.server_config() {
+            Some(x) => x.clone(),
+            None => return false,
+        };
+        server_config.crypto = match server_config.crypto.with_jls_config(&jls_config) {
+            Some(x) => x,
+            None => return false,
+        };
+        server_config.jls_config = Arc::new(jls_config);
+        endpoint
+            .inner
+            .set_server_config(Some(Arc::new(server_config)));
+        true
+    }
+
+    /// Export the keys a process taking over from this one needs, for a restart that clients
+    /// don't notice
+    ///
+    /// See [`SealedKeys`](crate::SealedKeys) for how to handle them safely. Returns `None` if the
+    /// endpoint uses keys it didn't generate itself.
+    #[cfg(feature = "ring")]
+    pub fn export_keys(&self) -> Option<proto::SealedKeys> {
+        self.inner.state.lock().unwrap().inner.export_keys()
+    }
+
+    /// Tell the peer at `remote` that the connection it sends to `dcid` is gone
+    ///
+    /// Queues a stateless reset, as sent automatically in answer to packets for connections the
+    /// endpoint doesn't know, for recovery tooling that knows the connection IDs used before a
+    /// restart. `dcid` must be a connection ID issued by an endpoint with the same
+    /// [`reset_key`](proto::EndpointConfig::reset_key), and the peer only accepts the reset if it
+    /// arrives from the address it sends that connection's packets to. Fails with
+    /// [`io::ErrorKind::InvalidInput`] if `dcid` is too long to be a connection ID.
+    pub fn send_stateless_reset(&self, remote: SocketAddr, dcid: &[u8]) -> io::Result<()> {
+        if dcid.len() > proto::MAX_CID_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "connection ID too long",
+            ));
+        }
+        let mut endpoint = self.inner.state.lock().unwrap();
+        let remote = if endpoint.ipv6 {
+            SocketAddr::V6(ensure_ipv6(remote))
+        } else {
+            remote
+        };
+        let transmit = endpoint
+            .inner
+            .stateless_reset_for(remote, None, &proto::ConnectionId::new(dcid), None)
+            .expect("resets without an inciting datagram are always sent");
+        endpoint.transmit_queue_contents_len = endpoint
+            .transmit_queue_contents_len
+            .saturating_add(transmit.contents.len());
+        endpoint.outgoing.push_back(udp_transmit(transmit));
+        endpoint.wake();
+        Ok(())
+    }
+
     /// Get the local `SocketAddr` the underlying socket is bound to
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.inner.state.lock().unwrap().socket.local_addr()
@@ -246,7 +594,9 @@ impl Endpoint {
 
     /// Close all of this endpoint's connections immediately and cease accepting new connections.
     ///
-    /// See [`Connection::close()`] for details.
+    /// See [`Connection::close()`] for details. JLS forward connections end too, once what their
+    /// clients already sent is relayed to the upstream, or shortly after if that can't be done,
+    /// and clients are no longer forwarded.
     ///
     /// [`Connection::close()`]: crate::Connection::close
     pub fn close(&self, error_code: VarInt, reason: &[u8]) {
@@ -254,20 +604,260 @@ impl Endpoint {
         let mut endpoint = self.inner.state.lock().unwrap();
         endpoint.connections.close = Some((error_code, reason.clone()));
         for sender in endpoint.connections.senders.values() {
-            // Ignoring errors from dropped connections
-            let _ = sender.send(ConnectionEvent::Close {
-                error_code,
-                reason: reason.clone(),
-            });
+            sender.close(error_code, reason.clone());
+        }
+        let now = Instant::now();
+        for id in endpoint.pending.live() {
+            let conn = &mut endpoint.pending.conns.get_mut(&id).unwrap().conn;
+            conn.close(now, error_code, reason.clone());
+            endpoint.drive_pending(id, now);
+        }
+        while let Some(incoming) = endpoint.incoming.pop_front() {
+            let t = endpoint.inner.refuse(incoming, now);
+            endpoint.queue_response(t, now);
         }
+        endpoint.jls_state.close(now);
+        endpoint.shutdown.started = true;
+        endpoint.publish_shutdown();
+        endpoint.wake();
         self.inner.shared.incoming.notify_waiters();
     }
 
+    /// Follow the endpoint's shutdown as it progresses
+    ///
+    /// Nothing is reported until [`close()`](Self::close) is called. From then on, the driver
+    /// publishes a new [`ShutdownProgress`] whenever connections drain or JLS forward connections
+    /// end, until the last of them is gone, or the driver stops. That last one is
+    /// [`finished`](ShutdownProgress::finished), and nothing changes after it.
+    pub fn shutdown_progress(&self) -> watch::Receiver<ShutdownProgress> {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .shutdown_progress
+            .subscribe()
+    }
+
+    /// Periodically close connections selected by an application-level policy
+    ///
+    /// Every `interval`, `policy` is invoked with the most recent [`ConnectionActivity`] reported
+    /// by each of the endpoint's connections, and every connection named in the returned [`Reap`]s
+    /// is closed as if by [`Connection::close()`]. This complements the transport's idle timeout,
+    /// which keep-alives prevent from ever firing, e.g. to close connections that haven't carried
+    /// any stream data for a while, or to cap the number of connections per peer IP.
+    ///
+    /// The policy runs on its own task, driven by the runtime's timers, and never blocks the
+    /// endpoint driver. Installing a new policy replaces the previous one.
+    ///
+    /// [`Connection::close()`]: crate::Connection::close
+    pub fn set_reaper<F>(&self, policy: F, interval: Duration)
+    where
+        F: FnMut(&[ConnectionActivity]) -> Vec<Reap> + Send + 'static,
+    {
+        let generation = {
+            let mut endpoint = self.inner.state.lock().unwrap();
+            endpoint.reaper_generation += 1;
+            endpoint.reaper_generation
+        };
+        self.runtime.spawn(Box::pin(Reaper {
+            endpoint: Arc::downgrade(&self.inner.0),
+            policy: Box::new(policy),
+            interval,
+            timer: self.runtime.new_timer(Instant::now() + interval),
+            generation,
+        }));
+    }
+
+    /// Stop the policy installed by [`set_reaper()`](Self::set_reaper), if any
+    pub fn clear_reaper(&self) {
+        self.inner.state.lock().unwrap().reaper_generation += 1;
+    }
+
+    /// Hold back ACK-only datagrams while more than `watermark` bytes are queued for sending
+    ///
+    /// ACKs from many connections otherwise compete for the socket with the data they could be
+    /// sending. Beyond the watermark, each connection gets at most one ACK-only datagram per
+    /// pass of the endpoint driver, sent during the next pass; a newer one replaces any it had
+    /// held back, since it acknowledges at least as much. Datagrams carrying anything else are
+    /// never held back. Counted in [`stats()`](Self::stats), as `deferred_acks` and
+    /// `coalesced_acks`.
+    ///
+    /// Disabled by default.
+    pub fn set_ack_only_watermark(&self, watermark: Option<usize>) {
+        self.inner.state.lock().unwrap().ack_only_watermark = watermark;
+    }
+
+    /// Emit a `debug` event with a breakdown of any poll of the driver that takes longer than
+    /// `threshold`
+    ///
+    /// For finding out whether the driver itself is behind tail latency. `None` disables the
+    /// event; [`driver_timing()`](Self::driver_timing) is kept up to date regardless. Defaults to
+    /// 5ms.
+    pub fn set_slow_poll_threshold(&self, threshold: Option<Duration>) {
+        self.inner.state.lock().unwrap().slow_poll_threshold = threshold;
+    }
+
+    /// How long the driver's polls, and the waits between them, have taken since this was last
+    /// called
+    ///
+    /// Meant to be sampled periodically, each call starting a new period.
+    pub fn driver_timing(&self) -> DriverTiming {
+        mem::take(&mut self.inner.state.lock().unwrap().timing)
+    }
+
+    /// Shed load when the application falls behind on accepting incoming connections
+    ///
+    /// Once `retry_threshold` incoming connections are waiting to be [accepted](Self::accept), new
+    /// clients must answer a Retry before any state is kept for them. This costs legitimate
+    /// clients a round trip, but clients flooding the endpoint from spoofed addresses never
+    /// answer, so can't grow the queue any further. Once `max` connections are waiting, new
+    /// clients are refused outright. Both tiers are counted in [`stats()`](Self::stats), as
+    /// `admission_retries` and `overloaded` respectively.
+    ///
+    /// Neither limit applies by default.
+    pub fn set_incoming_limits(&self, retry_threshold: usize, max: usize) {
+        let mut endpoint = self.inner.state.lock().unwrap();
+        endpoint.incoming_retry_threshold = retry_threshold;
+        endpoint.max_incoming = max;
+        endpoint.update_admission();
+    }
+
+    /// Replace the policy on which addresses may start connections, or be JLS-forwarded
+    ///
+    /// Consulted before any other work on datagrams that could start a connection; those from
+    /// denied addresses are dropped without an answer. The policy is built beforehand, so
+    /// replacing it holds up the driver only as long as a swap. Existing connections, and JLS
+    /// forward connections already set up, are unaffected. Verdicts are counted in
+    /// [`stats()`](Self::stats), as `prefix_allowed` and `prefix_denied`.
+    ///
+    /// Every address is allowed by default.
+    pub fn set_prefix_policy(&self, policy: PrefixPolicy) {
+        let mut endpoint = self.inner.state.lock().unwrap();
+        endpoint.inner.set_prefix_policy(policy);
+    }
+
+    /// Counts of incoming datagrams the endpoint dropped, by reason
+    pub fn stats(&self) -> EndpointStats {
+        self.inner.state.lock().unwrap().stats()
+    }
+
+    /// Traffic with each remote IP address, over all of its connections
+    ///
+    /// IPv4 addresses are reported as such even when the endpoint's socket is dual-stack. An
+    /// address without open connections is forgotten once nothing has been counted for it for five
+    /// minutes. Only so many addresses are tracked at once, beyond which traffic with further ones
+    /// goes uncounted until others are forgotten.
+    pub fn per_ip_stats(&self) -> HashMap<IpAddr, IpStats> {
+        self.inner.state.lock().unwrap().ip_stats.snapshot()
+    }
+
+    /// Call `callback` whenever an address's [`per_ip_stats()`](Self::per_ip_stats) pass
+    /// `threshold`
+    ///
+    /// An address is reported once when the bytes sent to and received from it first reach the
+    /// limit, and each time the number of connections open with it climbs to the limit. The
+    /// callback runs on the endpoint driver with the endpoint locked, so it should be quick and
+    /// must not call back into the endpoint, e.g. handing the address to another task that does
+    /// so instead. Replaces any earlier threshold and callback.
+    pub fn set_ip_threshold<F>(&self, threshold: IpThreshold, callback: F)
+    where
+        F: FnMut(IpAddr, &IpStats) + Send + 'static,
+    {
+        let mut endpoint = self.inner.state.lock().unwrap();
+        endpoint
+            .ip_stats
+            .set_threshold(threshold, Box::new(callback));
+    }
+
+    /// Traffic relayed to each JLS upstream, keyed by upstream address
+    ///
+    /// An upstream appears once a forward connection to it has been attempted, and its totals are
+    /// kept after its last forward connection ends.
+    pub fn jls_upstream_stats(&self) -> HashMap<SocketAddr, JlsUpstreamStats> {
+        self.inner
+            .state
+            .lock()
+            .unwrap()
+            .jls_state
+            .upstream_stats
+            .clone()
+    }
+
+    /// The clients currently forwarded to JLS upstreams
+    ///
+    /// Only copies what the endpoint keeps track of, so this doesn't wait for any I/O. The same
+    /// list is part of [`debug_snapshot()`](Self::debug_snapshot).
+    pub fn jls_forwards(&self) -> Vec<ForwardSnapshot> {
+        self.inner.state.lock().unwrap().forwards(Instant::now())
+    }
+
+    /// End the forward connection of the client at `remote`, returning whether it had one
+    ///
+    /// The socket reaching the upstream is closed, if the client had one of its own. A further
+    /// Initial from the client has it forwarded anew.
+    pub fn evict_jls_forward(&self, remote: SocketAddr) -> bool {
+        let mut endpoint = self.inner.state.lock().unwrap();
+        let remote = if endpoint.ipv6 {
+            SocketAddr::V6(ensure_ipv6(remote))
+        } else {
+            remote
+        };
+        endpoint.jls_state.evict(&remote)
+    }
+
+    /// Traffic relayed to all JLS upstreams together
+    ///
+    /// The sum of [`jls_upstream_stats()`](Self::jls_upstream_stats) over every upstream.
+    pub fn jls_forward_stats(&self) -> JlsUpstreamStats {
+        let state = self.inner.state.lock().unwrap();
+        let mut total = JlsUpstreamStats::default();
+        for stats in state.jls_state.upstream_stats.values() {
+            total.add(stats);
+        }
+        total
+    }
+
+    /// Process `payload` as though the socket had received it from `remote`
+    ///
+    /// The datagram takes the same path as those from the socket, through JLS forwarding and
+    /// connection handling, so e.g. captured traffic can be replayed against a server. `dst_ip`
+    /// and `ecn` stand in for what the socket would have reported alongside it. Any response is
+    /// sent through the socket as usual.
+    pub fn inject_datagram(
+        &self,
+        remote: SocketAddr,
+        dst_ip: Option<IpAddr>,
+        ecn: Option<udp::EcnCodepoint>,
+        payload: Bytes,
+    ) {
+        let mut state = self.inner.state.lock().unwrap();
+        let payload = BytesMut::from(&payload[..]);
+        state.handle_datagram(Instant::now(), remote, dst_ip, ecn.map(proto_ecn), payload);
+        state.wake();
+    }
+
+    /// The sender connections use to reach the driver, for injecting events in tests
+    #[cfg(test)]
+    pub(crate) fn event_sender(&self) -> EndpointEventSender {
+        self.inner.state.lock().unwrap().connections.sender.clone()
+    }
+
+    /// Capture the endpoint's state, for debugging
+    ///
+    /// Only copies are made while the endpoint is locked, so this is cheap enough to call on a busy
+    /// endpoint, e.g. from a signal handler task in production.
+    pub fn debug_snapshot(&self) -> EndpointSnapshot {
+        self.inner.state.lock().unwrap().snapshot(Instant::now())
+    }
+
     /// Wait for all connections on the endpoint to be cleanly shut down
     ///
     /// Waiting for this condition before exiting ensures that a good-faith effort is made to notify
     /// peers of recent connection closes, whereas exiting immediately could force them to wait out
-    /// the idle timeout period.
+    /// the idle timeout period. Connections only count as shut down once the datagrams they queued,
+    /// such as those carrying their CONNECTION_CLOSE frames, have been handed to the socket.
+    ///
+    /// JLS forward connections, and clients waiting to be forwarded, count as well.
     ///
     /// Does not proactively close existing connections or cause incoming connections to be
     /// rejected. Consider calling [`close()`] if that is desired.
@@ -277,7 +867,9 @@ impl Endpoint {
         loop {
             {
                 let endpoint = &mut *self.inner.state.lock().unwrap();
-                if endpoint.connections.is_empty() {
+                // Nothing more gets sent without a driver
+                let flushed = endpoint.is_flushed() || endpoint.driver_lost;
+                if endpoint.is_idle() && flushed && endpoint.jls_state.is_empty() {
                     break;
                 }
                 // Construct future while lock is held to avoid race
@@ -286,6 +878,35 @@ impl Endpoint {
             .await;
         }
     }
+
+    /// Wait for the datagrams connections have already queued to be handed to the socket
+    ///
+    /// Resolves once the driver, after this call, has taken every datagram waiting in the
+    /// connections' channels and emptied its own queue, e.g. before handing the process over.
+    /// Doesn't make connections produce any datagrams they wouldn't have otherwise, and doesn't
+    /// keep them from producing more afterwards.
+    pub async fn flush(&self) -> Result<(), EndpointError> {
+        let target = {
+            let mut endpoint = self.inner.state.lock().unwrap();
+            endpoint.flush_requested += 1;
+            endpoint.wake();
+            endpoint.flush_requested
+        };
+        loop {
+            {
+                let endpoint = self.inner.state.lock().unwrap();
+                if endpoint.flush_completed >= target {
+                    return Ok(());
+                }
+                if endpoint.driver_lost {
+                    return Err(EndpointError::Stopped);
+                }
+                // Construct future while lock is held to avoid race
+                self.inner.shared.flushed.notified()
+            }
+            .await;
+        }
+    }
 }
 
 /// A future that drives IO on an endpoint
@@ -297,35 +918,80 @@ impl Endpoint {
 /// running this task is necessary to keep the endpoint's connections running.
 ///
 /// `EndpointDriver` futures terminate when all clones of the `Endpoint` have been dropped, or when
-/// an I/O error occurs.
-#[must_use = "endpoint drivers must be spawned for I/O to occur"]
+/// an I/O error occurs. Either way, the endpoint stops when its driver is dropped.
+///
+/// Endpoints normally spawn their driver; see
+/// [`Endpoint::new_with_manual_driver()`] to poll it from an event loop instead.
+#[must_use = "endpoint drivers must be spawned or polled for I/O to occur"]
 #[derive(Debug)]
-pub(crate) struct EndpointDriver(pub(crate) EndpointRef);
-
-impl Future for EndpointDriver {
-    type Output = Result<(), io::Error>;
+pub struct EndpointDriver(pub(crate) EndpointRef);
 
-    #[allow(unused_mut)] // MSRV
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+impl EndpointDriver {
+    /// Do as much of the endpoint's pending work as possible
+    ///
+    /// Returns `Ready` once the endpoint has stopped, after which the driver should be dropped.
+    /// Equivalent to polling the driver as a future.
+    pub fn poll_drive(&mut self, cx: &mut Context) -> Poll<Result<(), io::Error>> {
         let mut endpoint = self.0.state.lock().unwrap();
-        if endpoint.driver.is_none() {
-            endpoint.driver = Some(cx.waker().clone());
+        // A manual driver may be polled from different contexts
+        match endpoint.driver {
+            Some(ref waker) if waker.will_wake(cx.waker()) => {}
+            _ => endpoint.driver = Some(cx.waker().clone()),
         }
+        // Only the current socket is polled from here on
+        endpoint.retired_sockets.clear();
 
         let now = Instant::now();
+        if let Some(end) = endpoint.last_poll_end {
+            let wait = now.saturating_duration_since(end);
+            endpoint.timing.max_wait = endpoint.timing.max_wait.max(wait);
+        }
+        endpoint.poll_phases = DriverPhases::default();
         let mut keep_going = false;
+        endpoint.release_deferred_acks();
+        // Every other pass, send before receiving too, so that a flood of incoming datagrams can't
+        // hold back what connections queue, such as the ACKs that would quench it
+        endpoint.send_first = !endpoint.send_first;
+        // Shared between both sends, so passes that send first get no more send budget
+        let mut send_rounds = SEND_ROUNDS;
+        if endpoint.send_first {
+            keep_going |= endpoint.drive_events_and_send(cx, &self.0.shared, &mut send_rounds)?;
+        }
+        let start = Instant::now();
         keep_going |= endpoint.drive_recv(cx, now)?;
-        keep_going |= endpoint.handle_events(cx, &self.0.shared);
-        keep_going |= endpoint.drive_send(cx)?;
-        //JLS forward
-        keep_going |= endpoint.upstream_recv(cx, now)?;
-        keep_going |= endpoint.upstream_send(cx, now)?;
+        let recv_end = Instant::now();
+        endpoint.poll_phases.recv += recv_end - start;
+        keep_going |= endpoint.drive_pending_timers(cx, now);
+        let timers_end = Instant::now();
+        endpoint.poll_phases.events += timers_end - recv_end;
+        // JLS forwarding itself runs on a task of its own, which reports back here
+        if endpoint.jls_forwarding {
+            keep_going |= endpoint.handle_forward_events(cx, now);
+            endpoint.poll_phases.forwarding += timers_end.elapsed();
+        }
+        // Last, so that datagrams queued by any of the above are sent in this same poll
+        keep_going |= endpoint.drive_events_and_send(cx, &self.0.shared, &mut send_rounds)?;
+        keep_going |= endpoint.drive_paced(cx, Instant::now());
+        endpoint.poll_writable_waiters(cx);
+        // Held-back ACKs go out during the next pass
+        keep_going |= !endpoint.deferred_acks.is_empty();
+        if endpoint.flush_completed != endpoint.flush_requested && endpoint.is_flushed() {
+            endpoint.flush_completed = endpoint.flush_requested;
+            self.0.shared.flushed.notify_waiters();
+        }
+        endpoint.sample_local_drops(now);
+        endpoint.publish_shutdown();
+        endpoint.record_poll(now);
 
-        if !endpoint.incoming.is_empty() {
+        if !endpoint.pending.conns.is_empty() || !endpoint.incoming.is_empty() {
             self.0.shared.incoming.notify_waiters();
         }
+        if endpoint.is_idle() {
+            // Incoming connections may have been drained before being accepted
+            self.0.shared.idle.notify_waiters();
+        }
 
-        if endpoint.ref_count == 0 && endpoint.connections.is_empty() {
+        if endpoint.ref_count == 0 && endpoint.is_idle() && endpoint.flushed(cx, now, keep_going) {
             Poll::Ready(Ok(()))
         } else {
             drop(endpoint);
@@ -340,14 +1006,29 @@ impl Future for EndpointDriver {
     }
 }
 
+impl Future for EndpointDriver {
+    type Output = Result<(), io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.poll_drive(cx)
+    }
+}
+
 impl Drop for EndpointDriver {
     fn drop(&mut self) {
         let mut endpoint = self.0.state.lock().unwrap();
         endpoint.driver_lost = true;
+        endpoint.notify_writable(Ok(()));
+        endpoint.abandon_shutdown();
         self.0.shared.incoming.notify_waiters();
+        self.0.shared.flushed.notify_waiters();
+        self.0.shared.idle.notify_waiters();
         // Drop all outgoing channels, signaling the termination of the endpoint to the associated
         // connections.
         endpoint.connections.senders.clear();
+        endpoint.connections.records.clear();
+        // Likewise for the JLS forward pool
+        endpoint.jls_state.pool = None;
     }
 }
 
@@ -360,204 +1041,1996 @@ pub(crate) struct EndpointInner {
 #[derive(Debug)]
 pub(crate) struct State {
     socket: Box<dyn AsyncUdpSocket>,
+    /// Sockets replaced by [`Endpoint::rebind()`], closed once the driver next runs
+    retired_sockets: Vec<Box<dyn AsyncUdpSocket>>,
     udp_state: Arc<UdpState>,
     inner: proto::Endpoint,
-    outgoing: VecDeque<udp::Transmit>,
-    incoming: VecDeque<Connecting>,
+    outgoing: OutgoingQueue,
+    pending: PendingSet,
+    /// Connection attempts waiting for [`Endpoint::accept_incoming()`], under
+    /// [`ServerConfig::defer_incoming`]
+    incoming: VecDeque<proto::Incoming>,
     driver: Option<Waker>,
+    /// Tasks waiting for the socket to become writable, which only the driver polls it for
+    writable_waiters: Vec<Waker>,
+    /// Incremented whenever the driver finds the socket writable with tasks waiting for it
+    writable_epoch: u64,
+    /// What the socket reported instead of becoming writable, when it last did
+    writable_error: Option<(io::ErrorKind, String)>,
     ipv6: bool,
+    /// The time of the driver's last pass receiving datagrams
+    ///
+    /// Datagrams' kernel timestamps are never put before it, so that the times connections are
+    /// given don't go backwards.
+    last_recv: Instant,
     connections: ConnectionSet,
+    /// Events from connections other than transmits, e.g. that they have drained
     events: mpsc::UnboundedReceiver<(ConnectionHandle, EndpointEvent)>,
+    /// Datagrams connections want sent, each as an [`EndpointEvent::Transmit`]
+    transmits: mpsc::UnboundedReceiver<(ConnectionHandle, EndpointEvent)>,
     /// Number of live handles that can be used to initiate or handle I/O; excludes the driver
     ref_count: usize,
     driver_lost: bool,
     recv_limiter: WorkLimiter,
     recv_buf: Box<[u8]>,
     send_limiter: WorkLimiter,
+    /// Budget for handling what the JLS forward pool reports, so that many busy forward
+    /// connections can't starve the rest
+    forward_limiter: WorkLimiter,
+    /// Whether sending goes ahead of receiving during the current pass, alternating between passes
+    send_first: bool,
     runtime: Arc<dyn Runtime>,
     /// The aggregateed contents length of the packets in the transmit queue
     transmit_queue_contents_len: usize,
     /// JLS state
     jls_state: JlsState,
+    /// Incremented whenever the reaper policy is replaced, so stale reaper tasks can exit
+    reaper_generation: u64,
+    /// Bounds how long an abandoned endpoint keeps trying to flush its last transmits
+    shutdown_timer: Option<Pin<Box<dyn AsyncTimer>>>,
+    /// Holds off sending after a transient error, until the socket is worth trying again
+    send_backoff: Option<Pin<Box<dyn AsyncTimer>>>,
+    /// Destinations sends to which have been failing with transient errors, since when and as of
+    /// when
+    send_failures: FxHashMap<SocketAddr, (Instant, Instant)>,
+    /// Transmits the driver gave up on after sends to their destination kept failing
+    dropped_sends: u64,
+    /// Unaccepted incoming connections from which new clients must answer a Retry
+    incoming_retry_threshold: usize,
+    /// Unaccepted incoming connections from which new clients are refused
+    max_incoming: usize,
+    /// The socket's local drops as of the last sample, and when it was taken
+    local_drops: LocalDrops,
+    local_drops_sampled: Option<Instant>,
+    /// Queued bytes beyond which ACK-only transmits are held back
+    ack_only_watermark: Option<usize>,
+    /// ACK-only transmits held back during the current pass, at most one per connection
+    deferred_acks: FxHashMap<ConnectionHandle, proto::Transmit>,
+    /// Transmits held back until the time pacing scheduled them for
+    paced: PacedQueue,
+    /// Whether clients failing JLS authentication are forwarded, per
+    /// [`EndpointConfig::jls_forwarding`](proto::EndpointConfig::jls_forwarding)
+    jls_forwarding: bool,
+    deferred_acks_total: u64,
+    coalesced_acks_total: u64,
+    /// Limits the endpoint's own responses to each address
+    response_shaper: ResponseShaper,
+    shaped_responses_total: u64,
+    /// Accumulated since [`Endpoint::driver_timing()`] was last called
+    timing: DriverTiming,
+    /// Time spent in each phase of the current poll
+    poll_phases: DriverPhases,
+    last_poll_end: Option<Instant>,
+    slow_poll_threshold: Option<Duration>,
+    /// Whether the latest look at the connections' channel found it empty
+    events_drained: bool,
+    /// Calls to [`Endpoint::flush()`] so far, and how many of them the driver has satisfied
+    flush_requested: u64,
+    flush_completed: u64,
+    /// Sockets received on besides `socket`
+    attached: Vec<AttachedSocket>,
+    next_socket_id: u64,
+    reply_socket: ReplySocket,
+    /// The attached socket each peer's datagrams last arrived on, unless that was `socket`, while
+    /// replies go out the socket the peer last used
+    peer_sockets: FxHashMap<SocketAddr, SocketId>,
+    /// Traffic with each remote address, for [`Endpoint::per_ip_stats()`]
+    ip_stats: IpStatsTable,
+    /// How far shutting down has got, as last published to `shutdown_progress`
+    shutdown: ShutdownProgress,
+    /// Publishes `shutdown` for [`Endpoint::shutdown_progress()`]
+    shutdown_progress: watch::Sender<ShutdownProgress>,
 }
 
-#[derive(Debug, Default)]
+/// A socket received on with [`Endpoint::attach_socket()`]
+#[derive(Debug)]
+struct AttachedSocket {
+    id: SocketId,
+    socket: Box<dyn AsyncUdpSocket>,
+    udp_state: Arc<UdpState>,
+    /// The address the socket is bound to, unless it's a wildcard
+    ///
+    /// Stands in for the destination address of datagrams the platform doesn't report it for.
+    local_ip: Option<IpAddr>,
+}
+
+/// Peers whose last-used attached socket is remembered at once
+///
+/// Beyond this, replies to further peers go out the endpoint's own socket.
+const MAX_PEER_SOCKETS: usize = 64 * 1024;
+
+#[derive(Debug)]
 pub(crate) struct JlsState {
+    /// Forward connections by their clients' current addresses
     upstream_connections: HashMap<SocketAddr, JlsForwardConnection>,
+    /// Clients' current addresses by forward connection ID, which the pool's reports may predate
+    remotes: HashMap<u64, SocketAddr>,
+    /// Clients' current addresses by the connection IDs they address their upstreams by
+    cids: ForwardCids,
+    upstream_stats: HashMap<SocketAddr, JlsUpstreamStats>,
+    /// Commands to the task relaying the forward connections' traffic, while there are any
+    pool: Option<mpsc::UnboundedSender<ForwardCommand>>,
+    /// Reports from the pool, and from earlier ones still winding down
+    events: mpsc::UnboundedReceiver<ForwardEvent>,
+    /// Cloned into each new pool
+    events_sender: mpsc::UnboundedSender<ForwardEvent>,
+    /// Bytes held by the pools' receive buffers
+    buffer_bytes: Arc<AtomicUsize>,
+    /// Bytes of datagrams the forward connections have waiting to be relayed, in either
+    /// direction, as limited by [`EndpointConfig::jls_forward_total_queue_limit`]
+    queued_len: usize,
+    /// Identifies the next forward connection in the pool's reports
+    next_id: u64,
+    /// New forward connections that may be set up before the bucket next refills
+    forward_tokens: u64,
+    /// When `forward_tokens` was last refilled, if ever; the bucket starts out full
+    forward_refilled: Option<Instant>,
+    /// The configured upstream host and what it was found to resolve to, if it's been looked up
+    host: Option<ResolvedHost>,
+    /// Forward connections held back until the upstream host is resolved, by client address
+    awaiting: HashMap<SocketAddr, AwaitingForward>,
+    /// Where the next forward connection starts, when upstreams are taken in turn
+    next_upstream: usize,
+    /// Whether the endpoint was closed, so that no client is forwarded any longer
+    closed: bool,
+    /// Keys the hashes clients are logged by, if their addresses are to be
+    /// [hashed](proto::JlsClientRedaction::Hashed)
+    redaction_key: RandomState,
 }
 
 impl JlsState {
-    fn handle_jls_forward(
+    fn new() -> Self {
+        let (events_sender, events) = mpsc::unbounded_channel();
+        Self {
+            upstream_connections: HashMap::new(),
+            remotes: HashMap::new(),
+            cids: ForwardCids::default(),
+            upstream_stats: HashMap::new(),
+            pool: None,
+            events,
+            events_sender,
+            buffer_bytes: Arc::new(AtomicUsize::new(0)),
+            queued_len: 0,
+            next_id: 0,
+            forward_tokens: 0,
+            forward_refilled: None,
+            host: None,
+            awaiting: HashMap::new(),
+            next_upstream: 0,
+            closed: false,
+            redaction_key: RandomState::new(),
+        }
+    }
+
+    /// Whether no client is forwarded, nor waiting to be
+    fn is_empty(&self) -> bool {
+        self.upstream_connections.is_empty() && self.awaiting.is_empty()
+    }
+
+    /// Stop forwarding clients, ending each forward connection once the pool has sent what its
+    /// client already handed over, or after [`FORWARD_DRAIN_TIMEOUT`] if it can't
+    ///
+    /// Further datagrams from forwarded clients are dropped, as are their upstreams' answers.
+    fn close(&mut self, now: Instant) {
+        self.closed = true;
+        self.awaiting.clear();
+        if let Some(ref pool) = self.pool {
+            let _ = pool.send(ForwardCommand::Drain(now + FORWARD_DRAIN_TIMEOUT));
+        }
+    }
+
+    /// Start tracking a forward connection, returning the ID the pool will report it by
+    #[allow(clippy::too_many_arguments)]
+    fn insert(
+        &mut self,
+        remote: SocketAddr,
+        upstream_addr: SocketAddr,
+        by_host: bool,
+        cids: Vec<proto::ConnectionId>,
+        datagrams: &[BytesMut],
+        failover: Failover,
+        client_span: Span,
+        now: Instant,
+    ) -> u64 {
+        // The pool replaces the old connection's relay when told to open the new one
+        if let Some(old) = self.upstream_connections.remove(&remote) {
+            self.remotes.remove(&old.id);
+            self.cids.remove(&old.cids, remote);
+            self.queued_len = self.queued_len.saturating_sub(old.queued_len());
+            if let Some(stats) = self.upstream_stats.get_mut(&old.upstream_addr) {
+                stats.active_mappings -= 1;
+            }
+        }
+        let stats = self.upstream_stats.entry(upstream_addr).or_default();
+        stats.active_mappings += 1;
+        stats.created += 1;
+        let id = self.next_id;
+        self.next_id += 1;
+        let span = debug_span!(parent: &client_span, "upstream", addr = %upstream_addr, id);
+        debug!(parent: &span, "forward connection opened");
+        self.remotes.insert(id, remote);
+        for cid in &cids {
+            self.cids.insert(*cid, remote);
+        }
+        let to_upstream_len = datagrams.iter().map(|x| x.len()).sum();
+        self.queued_len += to_upstream_len;
+        self.upstream_connections.insert(
+            remote,
+            JlsForwardConnection {
+                id,
+                upstream_addr,
+                by_host,
+                to_upstream: datagrams.len(),
+                to_upstream_len,
+                to_client_len: 0,
+                local_ip: None,
+                bytes_to_upstream: 0,
+                bytes_from_upstream: 0,
+                active_time: now,
+                opened: now,
+                unanswered: 0,
+                unanswered_bytes: 0,
+                first_response: None,
+                handshake: ForwardHandshake::Started,
+                cids,
+                failover,
+                span,
+                client_span,
+            },
+        );
+        id
+    }
+
+    /// Stop tracking the forward connection from `remote`
+    ///
+    /// The pool is let go along with the last one, and exits once it's taken in every command
+    /// sent so far.
+    fn remove(&mut self, remote: &SocketAddr) -> Option<JlsForwardConnection> {
+        let conn = self.upstream_connections.remove(remote)?;
+        self.remotes.remove(&conn.id);
+        self.cids.remove(&conn.cids, *remote);
+        self.queued_len = self.queued_len.saturating_sub(conn.queued_len());
+        if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+            stats.active_mappings -= 1;
+        }
+        if self.upstream_connections.is_empty() {
+            self.pool = None;
+        }
+        Some(conn)
+    }
+
+    /// End every forward connection, returning the addresses of their clients
+    fn end_all_by_rebind(&mut self) -> Vec<SocketAddr> {
+        let mut remotes = Vec::with_capacity(self.upstream_connections.len());
+        for (remote, conn) in mem::take(&mut self.upstream_connections) {
+            if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+                stats.active_mappings -= 1;
+                stats.ended_by_rebind += 1;
+            }
+            remotes.push(remote);
+        }
+        self.remotes.clear();
+        self.cids = ForwardCids::default();
+        self.awaiting.clear();
+        self.queued_len = 0;
+        self.pool = None;
+        remotes
+    }
+
+    /// Whether a new forward connection from `remote` may be set up, given at most `limit` live at
+    /// once and `rate` new ones per second
+    fn admit(
+        &mut self,
+        remote: &SocketAddr,
+        upstream_addr: SocketAddr,
+        now: Instant,
+        limit: u64,
+        rate: u64,
+    ) -> bool {
+        let stats = self.upstream_stats.entry(upstream_addr).or_default();
+        // A client starting over replaces its forward connection, rather than adding one
+        if !self.upstream_connections.contains_key(remote)
+            && self.upstream_connections.len() as u64 >= limit
+        {
+            trace!("too many forward connections, refusing");
+            stats.refused_by_limit += 1;
+            return false;
+        }
+        match self.forward_refilled {
+            None => {
+                self.forward_tokens = rate;
+                self.forward_refilled = Some(now);
+            }
+            Some(refilled) => {
+                let elapsed = now.saturating_duration_since(refilled);
+                let earned = elapsed.as_nanos().saturating_mul(rate.into()) / 1_000_000_000;
+                // Left alone until a whole token is earned, so frequent calls don't lose time
+                if earned > 0 {
+                    let earned = earned.min(rate.into()) as u64;
+                    self.forward_tokens = self.forward_tokens.saturating_add(earned).min(rate);
+                    self.forward_refilled = Some(now);
+                }
+            }
+        }
+        if self.forward_tokens == 0 {
+            trace!("forward connections set up too fast, refusing");
+            stats.refused_by_rate += 1;
+            return false;
+        }
+        self.forward_tokens -= 1;
+        true
+    }
+
+    /// The upstreams a new forward connection tries in turn, `primary` being the one given by the
+    /// JLS server configuration or the upstream host
+    fn upstream_order(
+        &mut self,
+        primary: SocketAddr,
+        alternates: &[SocketAddr],
+        selection: JlsUpstreamSelection,
+    ) -> Vec<SocketAddr> {
+        let mut order = Vec::with_capacity(1 + alternates.len());
+        order.push(primary);
+        order.extend_from_slice(alternates);
+        if selection == JlsUpstreamSelection::RoundRobin {
+            order.rotate_left(self.next_upstream % order.len());
+            self.next_upstream = self.next_upstream.wrapping_add(1);
+        }
+        order
+    }
+
+    /// Relay a datagram from a forwarded client, returning whether it was taken care of
+    ///
+    /// Initial packets starting a handshake other than the one being forwarded aren't relayed, so
+    /// a client that goes on to authenticate with JLS from the same address isn't locked out.
+    /// Datagrams that would take the connection's queue to the upstream past `queue_limit` bytes
+    /// are dropped, as are those that would take what all forward connections have queued past
+    /// `total_limit` bytes, and those that would take what's relayed before the upstream answers
+    /// past `unanswered_limit` bytes. Once more than `unanswered_warning` are relayed before the
+    /// upstream answers, a warning is logged.
+    ///
+    /// Relayed datagrams keep their `ecn` marking, and `local_ip`, where the datagram was sent to,
+    /// becomes where the upstream's answers are sent from.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_jls_forward(
         &mut self,
         buf: &BytesMut,
         remote: &SocketAddr,
+        ecn: Option<udp::EcnCodepoint>,
+        local_ip: Option<IpAddr>,
+        queue_limit: u64,
+        total_limit: u64,
+        unanswered_warning: u32,
+        unanswered_limit: u64,
     ) -> bool {
-            match self.upstream_connections.get_mut(remote) {
-                Some(conn) => {
-                    let trans = upstream_udp_transmit(&conn.upstream_addr, buf.clone());
-                    conn.to_upstream.push_back(trans);
-                    true
+        if self.closed {
+            return self.upstream_connections.contains_key(remote);
+        }
+        if let Some(forward) = self.awaiting.get_mut(remote) {
+            if forward.starts_new_handshake(buf) {
+                trace!(parent: &forward.span, "new handshake from forwarded client");
+                return false;
+            }
+            forward.local_ip = local_ip;
+            if (forward.len + buf.len()) as u64 > queue_limit {
+                trace!(parent: &forward.span, "queue to upstream full, dropping datagram");
+                return true;
+            }
+            // Nothing queued here has been answered yet
+            if (forward.len + buf.len()) as u64 > unanswered_limit {
+                trace!(parent: &forward.span, "upstream yet to answer, dropping datagram");
+                return true;
+            }
+            forward.len += buf.len();
+            forward.datagrams.push(buf.clone());
+            return true;
+        }
+        if !self.upstream_connections.contains_key(remote) {
+            match self.cids.find(buf) {
+                Some(from) => self.migrate(from, *remote),
+                None => return false,
+            }
+        }
+        match self.upstream_connections.get_mut(remote) {
+            Some(conn) => {
+                if conn.starts_new_handshake(buf) {
+                    trace!(parent: &conn.span, "new handshake from forwarded client");
+                    return false;
+                }
+                conn.local_ip = local_ip;
+                if (conn.to_upstream_len + buf.len()) as u64 > queue_limit {
+                    trace!(parent: &conn.span, "queue to upstream full, dropping datagram");
+                    if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+                        stats.dropped_to_upstream += 1;
+                    }
+                    return true;
+                }
+                if (self.queued_len + buf.len()) as u64 > total_limit {
+                    trace!(parent: &conn.span, "forward queues full, dropping datagram");
+                    if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+                        stats.dropped_by_total_limit += 1;
+                    }
+                    return true;
+                }
+                if conn.exceeds_unanswered(buf.len(), unanswered_limit) {
+                    trace!(parent: &conn.span, "upstream yet to answer, dropping datagram");
+                    if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+                        stats.dropped_unanswered += 1;
+                    }
+                    return true;
                 }
-                None => false,
+                conn.to_upstream += 1;
+                conn.to_upstream_len += buf.len();
+                self.queued_len += buf.len();
+                let stats = self.upstream_stats.get_mut(&conn.upstream_addr);
+                conn.client_datagrams(1, buf.len(), unanswered_warning, stats);
+                if let Some(ref pool) = self.pool {
+                    let _ = pool.send(ForwardCommand::Datagram {
+                        remote: *remote,
+                        data: buf.clone(),
+                        segment_size: None,
+                        ecn,
+                    });
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Relay a whole GRO batch of `stride`-byte datagrams from a forwarded client at once,
+    /// returning whether it was taken care of
+    ///
+    /// Batches from clients not yet forwarded, or with a segment that
+    /// [`handle_jls_forward`](Self::handle_jls_forward) would turn away, are left to be handled
+    /// segment by segment.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_jls_forward_batch(
+        &mut self,
+        buf: &BytesMut,
+        stride: usize,
+        remote: &SocketAddr,
+        ecn: Option<udp::EcnCodepoint>,
+        local_ip: Option<IpAddr>,
+        queue_limit: u64,
+        total_limit: u64,
+        unanswered_warning: u32,
+        unanswered_limit: u64,
+    ) -> bool {
+        if self.awaiting.contains_key(remote) {
+            return false;
+        }
+        let conn = match self.upstream_connections.get_mut(remote) {
+            Some(x) => x,
+            None => return false,
+        };
+        if buf.chunks(stride).any(|x| conn.starts_new_handshake(x)) {
+            return false;
+        }
+        conn.local_ip = local_ip;
+        if (conn.to_upstream_len + buf.len()) as u64 > queue_limit {
+            trace!(parent: &conn.span, "queue to upstream full, dropping datagrams");
+            if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+                stats.dropped_to_upstream += buf.chunks(stride).len() as u64;
+            }
+            return true;
+        }
+        if (self.queued_len + buf.len()) as u64 > total_limit {
+            trace!(parent: &conn.span, "forward queues full, dropping datagrams");
+            if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+                stats.dropped_by_total_limit += buf.chunks(stride).len() as u64;
+            }
+            return true;
+        }
+        if conn.exceeds_unanswered(buf.len(), unanswered_limit) {
+            // Segments that still fit are relayed one by one
+            return false;
+        }
+        conn.to_upstream += buf.chunks(stride).len();
+        conn.to_upstream_len += buf.len();
+        self.queued_len += buf.len();
+        let stats = self.upstream_stats.get_mut(&conn.upstream_addr);
+        let count = buf.chunks(stride).len();
+        conn.client_datagrams(count, buf.len(), unanswered_warning, stats);
+        if let Some(ref pool) = self.pool {
+            let _ = pool.send(ForwardCommand::Datagram {
+                remote: *remote,
+                data: buf.clone(),
+                segment_size: Some(stride),
+                ecn,
+            });
+        }
+        true
+    }
+
+    /// Account for a forward connection the pool ended of its own accord, returning it unless the
+    /// report was stale
+    ///
+    /// The connection is returned along with its client's current address.
+    fn ended(&mut self, id: u64, reason: ForwardEnd) -> Option<(SocketAddr, JlsForwardConnection)> {
+        // Reports about a client's earlier connection are stale
+        let remote = *self.remotes.get(&id)?;
+        let conn = self.remove(&remote).unwrap();
+        debug!(parent: &conn.span, ?reason, "forward connection ended");
+        let closed = matches!(reason, ForwardEnd::Closed | ForwardEnd::DrainTimedOut);
+        if conn.by_host && reason != ForwardEnd::Idle && !closed {
+            self.host_failed(conn.upstream_addr);
+        }
+        if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+            match reason {
+                ForwardEnd::SetupFailed => {
+                    // Counted as created when handed to the pool
+                    stats.created -= 1;
+                    stats.setup_failures += 1;
+                }
+                ForwardEnd::Idle => stats.ended_by_idle += 1,
+                ForwardEnd::Error => stats.ended_by_error += 1,
+                ForwardEnd::Unanswered => stats.ended_unanswered += 1,
+                ForwardEnd::Closed | ForwardEnd::DrainTimedOut => stats.ended_by_close += 1,
+            }
+        }
+        Some((remote, conn))
+    }
+
+    /// Carry on forwarding the client at `from` now that its datagrams arrive from `to`, e.g.
+    /// after its NAT rebound it to another port
+    fn migrate(&mut self, from: SocketAddr, to: SocketAddr) {
+        let mut conn = match self.upstream_connections.remove(&from) {
+            Some(x) => x,
+            None => return,
+        };
+        debug!(parent: &conn.span, %to, "forwarded client moved");
+        // Answers already queued for the old address can't be told apart once they're sent
+        self.queued_len = self.queued_len.saturating_sub(conn.to_client_len);
+        conn.to_client_len = 0;
+        self.remotes.insert(conn.id, to);
+        for cid in &conn.cids {
+            self.cids.insert(*cid, to);
+        }
+        if let Some(ref pool) = self.pool {
+            let _ = pool.send(ForwardCommand::Migrate {
+                from,
+                to,
+                id: conn.id,
+            });
+        }
+        if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+            stats.migrations += 1;
+        }
+        self.upstream_connections.insert(to, conn);
+    }
+
+    /// Count a failed forward connection to what the upstream host resolved to, letting the address
+    /// go once too many failed in a row
+    fn host_failed(&mut self, addr: SocketAddr) {
+        if let Some(host) = self.host.as_mut().filter(|x| x.addr == Some(addr)) {
+            host.failures += 1;
+            if host.failures >= RERESOLVE_AFTER_FAILURES {
+                debug!(
+                    "forward connections to {} keep failing, looking up {} again",
+                    addr, host.name
+                );
+                host.addr = None;
+                host.failures = 0;
+            }
+        }
+    }
+
+    /// End the forward connection of a client whose new handshake the endpoint accepted itself
+    fn superseded(&mut self, remote: &SocketAddr) {
+        self.awaiting.remove(remote);
+        if let Some(conn) = self.remove(remote) {
+            debug!(parent: &conn.span, "new handshake supersedes forward connection");
+            if let Some(ref pool) = self.pool {
+                let _ = pool.send(ForwardCommand::Close {
+                    remote: *remote,
+                    id: conn.id,
+                });
+            }
+            if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+                stats.ended_by_new_handshake += 1;
+            }
+        }
+    }
+
+    /// End the forward connection of the client at `remote` on the application's behalf,
+    /// returning whether there was one
+    fn evict(&mut self, remote: &SocketAddr) -> bool {
+        let awaiting = self.awaiting.remove(remote).is_some();
+        let conn = match self.remove(remote) {
+            Some(x) => x,
+            None => return awaiting,
+        };
+        debug!(parent: &conn.span, "evicting forward connection");
+        if let Some(ref pool) = self.pool {
+            let _ = pool.send(ForwardCommand::Close {
+                remote: *remote,
+                id: conn.id,
+            });
+        }
+        if let Some(stats) = self.upstream_stats.get_mut(&conn.upstream_addr) {
+            stats.ended_by_eviction += 1;
+        }
+        true
+    }
+
+    /// Note that the client at `remote`, forwarded or about to be, sent to `local_ip`
+    fn note_local_ip(&mut self, remote: &SocketAddr, local_ip: Option<IpAddr>) {
+        if let Some(conn) = self.upstream_connections.get_mut(remote) {
+            conn.local_ip = local_ip;
+        } else if let Some(forward) = self.awaiting.get_mut(remote) {
+            forward.local_ip = local_ip;
+        }
+    }
+
+    /// Account for a transmit of `len` bytes to `remote` leaving the endpoint's transmit queue, in
+    /// case it's an upstream's answer to a forwarded client
+    fn dequeued(&mut self, remote: &SocketAddr, len: usize) {
+        if self.queued_len == 0 {
+            return;
+        }
+        if let Some(conn) = self.upstream_connections.get_mut(remote) {
+            let len = len.min(conn.to_client_len);
+            conn.to_client_len -= len;
+            self.queued_len -= len;
+        }
+    }
+}
+
+/// Traffic relayed between JLS-forwarded clients and one upstream, or all of them
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct JlsUpstreamStats {
+    /// Clients currently forwarded to the upstream
+    pub active_mappings: u64,
+    /// Bytes sent to the upstream on behalf of clients
+    pub bytes_to_upstream: u64,
+    /// Bytes received from the upstream and relayed back to clients
+    pub bytes_from_upstream: u64,
+    /// Forward connections set up, including those since ended
+    pub created: u64,
+    /// Forward connections that could not be set up, e.g. because no socket could be bound
+    pub setup_failures: u64,
+    /// Forward connections in which the upstream took part in the handshake
+    pub established: u64,
+    /// Times the upstream made a client start its handshake over with a Retry or Version
+    /// Negotiation packet
+    pub restarts: u64,
+    /// Forward connections ended because the endpoint was rebound to a new socket
+    pub ended_by_rebind: u64,
+    /// Times a forwarded client's datagrams started arriving from another address, and the
+    /// forward connection followed it there
+    pub migrations: u64,
+    /// Forward connections ended after relaying nothing for the
+    /// [idle timeout](EndpointConfig::jls_forward_idle_timeout)
+    pub ended_by_idle: u64,
+    /// Forward connections ended because their client started a new handshake that the endpoint
+    /// accepted itself, e.g. after fixing its JLS credentials
+    pub ended_by_new_handshake: u64,
+    /// Forward connections ended because their socket failed, e.g. after the upstream's host
+    /// reported it unreachable
+    pub ended_by_error: u64,
+    /// Forward connections ended because the endpoint was [closed](Endpoint::close)
+    pub ended_by_close: u64,
+    /// Forward connections ended by [`Endpoint::evict_jls_forward()`]
+    pub ended_by_eviction: u64,
+    /// Forward connections ended because the upstream didn't answer within the
+    /// [response timeout](EndpointConfig::jls_upstream_response_timeout), while there were
+    /// alternates to try
+    pub ended_unanswered: u64,
+    /// Forward connections moved on from the upstream to an
+    /// [alternate](EndpointConfig::jls_upstream_alternates)
+    pub failovers: u64,
+    /// Datagrams from clients dropped because their forward connection's queue to the upstream
+    /// was full
+    pub dropped_to_upstream: u64,
+    /// Datagrams from clients relayed to the upstream before it first answered them
+    pub unanswered_datagrams: u64,
+    /// Datagrams from clients dropped because the upstream hadn't answered them yet, and they'd
+    /// already sent [as much as allowed](EndpointConfig::jls_forward_unanswered_limit)
+    pub dropped_unanswered: u64,
+    /// Forward connections whose client sent more datagrams before the upstream first answered
+    /// than [allowed without a warning](EndpointConfig::jls_forward_unanswered_warning)
+    pub unanswered_warnings: u64,
+    /// Datagrams from the upstream dropped because the shared socket they arrived on couldn't
+    /// tell which client they were for
+    pub unroutable_from_upstream: u64,
+    /// Clients not forwarded because the endpoint already had
+    /// [as many forward connections as allowed](EndpointConfig::jls_forward_limit)
+    pub refused_by_limit: u64,
+    /// Clients not forwarded because the endpoint was setting up forward connections
+    /// [faster than allowed](EndpointConfig::jls_forward_rate)
+    pub refused_by_rate: u64,
+    /// Datagrams dropped, in either direction, because forward connections together had
+    /// [as much waiting as allowed](EndpointConfig::jls_forward_total_queue_limit)
+    pub dropped_by_total_limit: u64,
+    /// Clients sent to the upstream by the server name in their ClientHello, per
+    /// [`jls_upstream_routes`](EndpointConfig::jls_upstream_routes), whether or not they were then
+    /// forwarded
+    pub routed_by_server_name: u64,
+    /// Clients that would have gone to the upstream, as given by the JLS server configuration, but
+    /// weren't forwarded because their server name matched no route and
+    /// [unrouted clients are dropped](EndpointConfig::jls_drop_unrouted)
+    pub refused_unrouted: u64,
+}
+
+/// How long the endpoint driver's polls have taken
+///
+/// Returned by [`Endpoint::driver_timing()`], covering the period since its previous call.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct DriverTiming {
+    /// Polls of the driver that completed
+    pub polls: u64,
+    /// Time spent in those polls
+    pub total_poll: Duration,
+    /// Longest a single poll took
+    pub max_poll: Duration,
+    /// Longest the driver went between the end of one poll and the start of the next
+    pub max_wait: Duration,
+    /// Time spent in each phase of the driver's work, summed over all polls
+    pub phases: DriverPhases,
+    /// Longest each phase took within a single poll
+    pub max_phases: DriverPhases,
+}
+
+/// Time spent in each phase of the endpoint driver's work
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct DriverPhases {
+    /// Receiving datagrams from the socket and handling them
+    pub recv: Duration,
+    /// Handing queued datagrams to the socket
+    pub send: Duration,
+    /// Handling events from connections, and the timers of those not yet accepted
+    pub events: Duration,
+    /// Taking in what the task relaying JLS-forwarded clients' traffic reports
+    ///
+    /// The relaying itself happens on that task, outside the driver's polls.
+    pub forwarding: Duration,
+}
+
+impl DriverPhases {
+    fn add(&mut self, other: &Self) {
+        self.recv += other.recv;
+        self.send += other.send;
+        self.events += other.events;
+        self.forwarding += other.forwarding;
+    }
+
+    fn update_max(&mut self, other: &Self) {
+        self.recv = self.recv.max(other.recv);
+        self.send = self.send.max(other.send);
+        self.events = self.events.max(other.events);
+        self.forwarding = self.forwarding.max(other.forwarding);
+    }
+}
+
+impl JlsUpstreamStats {
+    fn add(&mut self, other: &Self) {
+        self.active_mappings += other.active_mappings;
+        self.bytes_to_upstream += other.bytes_to_upstream;
+        self.bytes_from_upstream += other.bytes_from_upstream;
+        self.created += other.created;
+        self.setup_failures += other.setup_failures;
+        self.established += other.established;
+        self.restarts += other.restarts;
+        self.ended_by_rebind += other.ended_by_rebind;
+        self.migrations += other.migrations;
+        self.ended_by_idle += other.ended_by_idle;
+        self.ended_by_new_handshake += other.ended_by_new_handshake;
+        self.ended_by_error += other.ended_by_error;
+        self.ended_by_close += other.ended_by_close;
+        self.ended_by_eviction += other.ended_by_eviction;
+        self.ended_unanswered += other.ended_unanswered;
+        self.failovers += other.failovers;
+        self.dropped_to_upstream += other.dropped_to_upstream;
+        self.unanswered_datagrams += other.unanswered_datagrams;
+        self.dropped_unanswered += other.dropped_unanswered;
+        self.unanswered_warnings += other.unanswered_warnings;
+        self.unroutable_from_upstream += other.unroutable_from_upstream;
+        self.refused_by_limit += other.refused_by_limit;
+        self.refused_by_rate += other.refused_by_rate;
+        self.dropped_by_total_limit += other.dropped_by_total_limit;
+        self.routed_by_server_name += other.routed_by_server_name;
+        self.refused_unrouted += other.refused_unrouted;
+    }
+}
+
+/// The upstream host set by [`EndpointConfig::jls_upstream_host()`], and what it resolved to
+#[derive(Debug)]
+struct ResolvedHost {
+    name: String,
+    port: u16,
+    /// Where forward connections go, unless the host is yet to be looked up
+    addr: Option<SocketAddr>,
+    /// Whether a lookup is underway
+    resolving: bool,
+    /// Forward connections to `addr` that failed in a row
+    failures: u32,
+}
+
+/// How long forward connections of a closed endpoint get to relay what their clients already sent
+const FORWARD_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Forward connections to `addr` that may fail in a row before the upstream host is looked up
+/// again
+const RERESOLVE_AFTER_FAILURES: u32 = 3;
+
+/// Finds forward connections by the connection IDs their clients address their upstreams by, so
+/// that a client is still forwarded when its datagrams start arriving from another address
+///
+/// Only connection IDs seen in long header packets are known, i.e. the client's first choice and
+/// those the upstream chose during the handshake. A client that moves to a connection ID issued
+/// later, as it may when migrating on purpose, is no longer recognized.
+#[derive(Debug, Default)]
+struct ForwardCids {
+    /// Clients' current addresses by connection ID
+    by_cid: HashMap<proto::ConnectionId, SocketAddr>,
+    /// Lengths of the connection IDs in `by_cid`, for finding those of short header packets
+    cid_lens: Vec<usize>,
+}
+
+impl ForwardCids {
+    /// Route datagrams addressed to `cid` to the client at `remote`
+    fn insert(&mut self, cid: proto::ConnectionId, remote: SocketAddr) {
+        // Clients using zero-length connection IDs are told apart by their address alone
+        if cid.is_empty() {
+            return;
+        }
+        self.by_cid.insert(cid, remote);
+        if !self.cid_lens.contains(&cid.len()) {
+            self.cid_lens.push(cid.len());
+        }
+    }
+
+    /// Forget the connection IDs of the client at `remote`
+    fn remove(&mut self, cids: &[proto::ConnectionId], remote: SocketAddr) {
+        for cid in cids {
+            if self.by_cid.get(cid) == Some(&remote) {
+                self.by_cid.remove(cid);
             }
+        }
+    }
+
+    /// The address of the client a datagram's destination connection ID belongs to, if any
+    fn find(&self, packet: &[u8]) -> Option<SocketAddr> {
+        if self.by_cid.is_empty() {
+            return None;
+        }
+        let by_cid = |cid: &[u8]| self.by_cid.get(&proto::ConnectionId::new(cid)).copied();
+        match long_header_cids(packet) {
+            Some((dcid, _)) => by_cid(&dcid),
+            // Short headers don't say how long their connection ID is
+            None if packet.first().map_or(false, |x| x & 0x80 == 0) => self
+                .cid_lens
+                .iter()
+                .find_map(|&len| by_cid(packet.get(1..1 + len)?)),
+            None => None,
+        }
+    }
+}
+
+/// A client to be forwarded once the upstream host is resolved
+#[derive(Debug)]
+struct AwaitingForward {
+    /// As in [`JlsForwardConnection::cids`]
+    cids: Vec<proto::ConnectionId>,
+    /// The client's datagrams so far, starting with the Initial that was forwarded
+    datagrams: Vec<BytesMut>,
+    /// Aggregate contents length of `datagrams`
+    len: usize,
+    /// As in [`JlsForwardConnection::local_ip`]
+    local_ip: Option<IpAddr>,
+    /// As in [`JlsForwardConnection::client_span`]
+    span: Span,
+}
+
+impl AwaitingForward {
+    /// Whether a datagram from the client is an Initial for a handshake other than the one to be
+    /// forwarded
+    fn starts_new_handshake(&self, packet: &[u8]) -> bool {
+        is_initial(packet)
+            && long_header_cids(packet).map_or(false, |(dcid, _)| !self.cids.contains(&dcid))
+    }
+}
+
+/// A client forwarded to an upstream, as far as the endpoint driver is concerned
+///
+/// The traffic itself is relayed by the [`ForwardPool`].
+#[derive(Debug)]
+pub(crate) struct JlsForwardConnection {
+    /// Tells the connection apart from the client's earlier ones in the pool's reports
+    id: u64,
+    upstream_addr: SocketAddr,
+    /// Whether `upstream_addr` is what the configured upstream host resolved to
+    by_host: bool,
+    /// Datagrams from the client handed to the pool but not yet sent to the upstream
+    to_upstream: usize,
+    /// Aggregate contents length of the datagrams counted in `to_upstream`
+    to_upstream_len: usize,
+    /// Bytes from the upstream waiting in the endpoint's transmit queue for the client
+    to_client_len: usize,
+    /// The local address the client last sent to, which datagrams to it are sent from
+    local_ip: Option<IpAddr>,
+    /// Bytes the pool sent to the upstream on behalf of the client
+    bytes_to_upstream: u64,
+    /// Bytes from the upstream relayed back to the client
+    bytes_from_upstream: u64,
+    /// When the pool last reported relaying a datagram in either direction
+    active_time: Instant,
+    /// When forwarding to `upstream_addr` started
+    opened: Instant,
+    /// Datagrams from the client handed to the pool before the upstream first answered
+    unanswered: u32,
+    /// Aggregate contents length of the datagrams counted in `unanswered`
+    unanswered_bytes: u64,
+    /// How long after `opened` the upstream first answered, if it has
+    first_response: Option<Duration>,
+    handshake: ForwardHandshake,
+    /// Destination connection IDs the client may use in Initials of the forwarded handshake
+    ///
+    /// The client's first choice, and the source connection IDs the upstream answered with.
+    cids: Vec<proto::ConnectionId>,
+    failover: Failover,
+    /// Covers the forwarding of the client to `upstream_addr`, within `client_span`
+    span: Span,
+    /// Covers the forwarding of the client, whichever upstreams it's forwarded to
+    client_span: Span,
+}
+
+/// What a forward connection needs to move on to another upstream, should its own fail
+#[derive(Debug, Default)]
+struct Failover {
+    /// [Alternate upstreams](EndpointConfig::jls_upstream_alternates) yet to be tried, in order
+    untried: Vec<SocketAddr>,
+    /// The datagram that carried the client's ClientHello, kept for the next upstream until this
+    /// one answers, if there's a next one
+    hello: Option<BytesMut>,
+    /// Times the connection moved on to another upstream
+    count: u32,
+}
+
+impl JlsForwardConnection {
+    /// Whether a datagram from the client is an Initial for a handshake other than the forwarded
+    /// one
+    fn starts_new_handshake(&self, packet: &[u8]) -> bool {
+        is_initial(packet)
+            && long_header_cids(packet).map_or(false, |(dcid, _)| !self.cids.contains(&dcid))
+    }
+
+    /// Bytes counted towards [`JlsState::queued_len`]
+    fn queued_len(&self) -> usize {
+        self.to_upstream_len + self.to_client_len
+    }
+
+    /// Whether handing the pool `len` more bytes from the client would take those the upstream
+    /// hasn't answered past `limit`
+    fn exceeds_unanswered(&self, len: usize, limit: u64) -> bool {
+        self.first_response.is_none() && self.unanswered_bytes.saturating_add(len as u64) > limit
+    }
+
+    /// Account for `count` datagrams of `len` bytes between them from the client handed to the
+    /// pool, warning if they take those the upstream hasn't answered past `warning`
+    fn client_datagrams(
+        &mut self,
+        count: usize,
+        len: usize,
+        warning: u32,
+        stats: Option<&mut JlsUpstreamStats>,
+    ) {
+        if self.first_response.is_some() {
+            return;
+        }
+        self.unanswered_bytes += len as u64;
+        let before = self.unanswered;
+        self.unanswered = before.saturating_add(count as u32);
+        let warned = before <= warning && self.unanswered > warning;
+        if warned {
+            warn!(
+                parent: &self.span,
+                "forwarded client sent {} datagrams without an answer from upstream",
+                self.unanswered
+            );
+        }
+        if let Some(stats) = stats {
+            stats.unanswered_datagrams += count as u64;
+            stats.unanswered_warnings += u64::from(warned);
+        }
+    }
+
+    /// Track the progress of the client's handshake with the upstream from one of the upstream's
+    /// datagrams
+    fn upstream_datagram(&mut self, packet: &[u8], stats: Option<&mut JlsUpstreamStats>) {
+        // The client addresses its further Initials to whichever connection ID the upstream chose
+        if let Some((_, scid)) = long_header_cids(packet) {
+            if !self.cids.contains(&scid) {
+                self.cids.push(scid);
+            }
+        }
+        if self.handshake == ForwardHandshake::Established {
+            return;
+        }
+        // The client answers a Retry or Version Negotiation packet with a new Initial, which is
+        // forwarded like any other datagram from its address, so only the tracking starts over
+        let restarted = restarts_handshake(packet);
+        self.handshake = if restarted {
+            trace!(parent: &self.span, "upstream restarted the forwarded handshake");
+            ForwardHandshake::Restarted
+        } else {
+            ForwardHandshake::Established
+        };
+        if let Some(stats) = stats {
+            if restarted {
+                stats.restarts += 1;
+            } else {
+                stats.established += 1;
+            }
+        }
+    }
+}
+
+/// Progress of the handshake a forwarded client is attempting with the upstream
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ForwardHandshake {
+    /// Waiting for the upstream to answer the client's first Initial
+    Started,
+    /// The upstream answered with a Retry or Version Negotiation packet, so the client will try
+    /// again with a new Initial
+    Restarted,
+    /// The upstream answered with a handshake packet of its own
+    Established,
+}
+
+/// Whether a datagram from an upstream is a Version Negotiation or Retry packet
+fn restarts_handshake(packet: &[u8]) -> bool {
+    if packet.len() < 5 || packet[0] & 0x80 == 0 {
+        return false;
+    }
+    let version = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+    // Retry is the only long header packet type with both type bits set
+    version == 0 || packet[0] & 0x30 == 0x30
+}
+
+/// The server name a client asked for in its ClientHello, if any
+#[cfg(feature = "tls-rustls")]
+fn forward_server_name(conn: &proto::Connection) -> Option<String> {
+    conn.crypto_session()
+        .handshake_data()?
+        .downcast::<proto::crypto::rustls::HandshakeData>()
+        .ok()?
+        .server_name
+}
+
+#[cfg(not(feature = "tls-rustls"))]
+fn forward_server_name(_: &proto::Connection) -> Option<String> {
+    None
+}
+
+/// The upstream of the route best matching `server_name`, if any
+///
+/// See [`EndpointConfig::jls_upstream_routes`].
+fn route_by_server_name(routes: &[(String, SocketAddr)], server_name: &str) -> Option<SocketAddr> {
+    let name = server_name.trim_end_matches('.').to_ascii_lowercase();
+    let mut best: Option<(usize, SocketAddr)> = None;
+    for (pattern, addr) in routes {
+        // Exact matches rank above any wildcard, and wildcards by the length of their domain
+        let rank = match pattern.strip_prefix("*.") {
+            None if *pattern == name => usize::MAX,
+            Some(domain)
+                if name.len() > domain.len() + 1
+                    && name.ends_with(domain)
+                    && name.as_bytes()[name.len() - domain.len() - 1] == b'.' =>
+            {
+                domain.len()
+            }
+            _ => continue,
+        };
+        if best.map_or(true, |(x, _)| rank > x) {
+            best = Some((rank, *addr));
+        }
+    }
+    best.map(|(_, addr)| addr)
+}
+
+/// Whether a datagram starts with an Initial packet
+fn is_initial(packet: &[u8]) -> bool {
+    if packet.len() < 5 || packet[0] & 0x80 == 0 {
+        return false;
+    }
+    let version = u32::from_be_bytes(packet[1..5].try_into().unwrap());
+    version != 0 && packet[0] & 0x30 == 0
+}
+
+#[derive(Debug)]
+pub(crate) struct Shared {
+    incoming: Notify,
+    idle: Notify,
+    /// Notified when the driver satisfies calls to [`Endpoint::flush()`]
+    flushed: Notify,
+}
+
+impl State {
+    /// The protocol's counters, along with those kept by the driver and the socket
+    fn stats(&self) -> EndpointStats {
+        let drops = self.socket.local_drops();
+        let mut stats = self.inner.stats();
+        stats.local_receive_drops = drops.receive_overflows;
+        stats.local_send_drops = drops.send_errors + self.dropped_sends;
+        stats.deferred_acks = self.deferred_acks_total;
+        stats.coalesced_acks = self.coalesced_acks_total;
+        stats.forward_buffer_bytes = self.jls_state.buffer_bytes.load(Ordering::Relaxed) as u64;
+        stats.forward_queued_bytes = self.jls_state.queued_len as u64;
+        stats.shaped_responses = self.shaped_responses_total;
+        stats
+    }
+
+    /// Warn if the socket has dropped datagrams locally since the last sample
+    fn sample_local_drops(&mut self, now: Instant) {
+        if self.local_drops_sampled.map_or(false, |x| {
+            now.saturating_duration_since(x) < LOCAL_DROPS_SAMPLE_INTERVAL
+        }) {
+            return;
+        }
+        self.local_drops_sampled = Some(now);
+        let drops = self.socket.local_drops();
+        if drops != self.local_drops {
+            warn!(
+                receive_overflows = drops
+                    .receive_overflows
+                    .saturating_sub(self.local_drops.receive_overflows),
+                send_errors = drops
+                    .send_errors
+                    .saturating_sub(self.local_drops.send_errors),
+                "socket dropped datagrams locally"
+            );
+            self.local_drops = drops;
+        }
+    }
+
+    fn snapshot(&self, now: Instant) -> EndpointSnapshot {
+        let records = &self.connections.records;
+        let accepted = self.connections.senders.iter().map(|(handle, sender)| {
+            let record = &records[handle];
+            ConnectionSnapshot {
+                handle: handle.0,
+                stable_id: Some(record.stable_id),
+                remote_address: record.remote_address,
+                accepted: true,
+                queued_events: sender.depth(),
+                phase: record.phase,
+                age: now.saturating_duration_since(record.created),
+            }
+        });
+        let incoming = self.pending.conns.values().map(|x| ConnectionSnapshot {
+            handle: x.handle.0,
+            stable_id: None,
+            remote_address: x.conn.remote_address(),
+            accepted: false,
+            queued_events: 0,
+            phase: ConnectionPhase::of(&x.conn),
+            age: now.saturating_duration_since(x.created),
+        });
+        EndpointSnapshot {
+            local_addr: self.socket.local_addr().ok(),
+            ipv6: self.ipv6,
+            accepting: self.inner.server_config().is_some(),
+            max_udp_payload_size: self.inner.config().get_max_udp_payload_size(),
+            max_queued_datagrams: self.connections.max_queued_datagrams,
+            handles: self.ref_count,
+            connections: accepted.chain(incoming).collect(),
+            incoming: self.pending.conns.len() + self.incoming.len(),
+            outgoing_datagrams: self.outgoing.len(),
+            outgoing_bytes: self.transmit_queue_contents_len,
+            recv_work: self.recv_limiter.snapshot(),
+            send_work: self.send_limiter.snapshot(),
+            forward_work: self.forward_limiter.snapshot(),
+            forwards: self.forwards(now),
+            upstreams: self
+                .jls_state
+                .upstream_stats
+                .iter()
+                .map(|(&addr, &stats)| (addr, stats))
+                .collect(),
+            stats: self.stats(),
+        }
+    }
+
+    fn forwards(&self, now: Instant) -> Vec<ForwardSnapshot> {
+        self.jls_state
+            .upstream_connections
+            .iter()
+            .map(|(&remote, conn)| ForwardSnapshot {
+                remote_address: remote,
+                upstream_address: conn.upstream_addr,
+                failovers: conn.failover.count,
+                queued_datagrams: conn.to_upstream,
+                queued_bytes: conn.to_upstream_len,
+                bytes_to_upstream: conn.bytes_to_upstream,
+                bytes_from_upstream: conn.bytes_from_upstream,
+                unanswered_datagrams: conn.unanswered,
+                first_response: conn.first_response,
+                established: conn.handshake == ForwardHandshake::Established,
+                idle: now.saturating_duration_since(conn.active_time),
+            })
+            .collect()
+    }
+
+    fn drive_recv(&mut self, cx: &mut Context, now: Instant) -> Result<bool, io::Error> {
+        self.recv_limiter.start_cycle();
+        // Taken out while receiving into it, so that each datagram can be handled with the state
+        // borrowed whole
+        let mut recv_buf = mem::take(&mut self.recv_buf);
+        let mut metas = [RecvMeta::default(); BATCH_SIZE];
+        let mut iovs = MaybeUninit::<[IoSliceMut; BATCH_SIZE]>::uninit();
+        let chunk_len = recv_buf.len() / BATCH_SIZE;
+        recv_buf
+            .chunks_mut(chunk_len)
+            .enumerate()
+            .for_each(|(i, buf)| unsafe {
+                iovs.as_mut_ptr()
+                    .cast::<IoSliceMut>()
+                    .add(i)
+                    .write(IoSliceMut::new(buf));
+            });
+        let mut iovs = unsafe { iovs.assume_init() };
+        let mut result = self.recv_on(None, cx, now, &mut iovs, &mut metas);
+        if result.is_err() {
+            self.recv_buf = recv_buf;
+            self.last_recv = now;
+            return result;
+        }
+        // An attached socket failing only detaches it
+        let mut i = 0;
+        while i < self.attached.len() {
+            match self.recv_on(Some(i), cx, now, &mut iovs, &mut metas) {
+                Ok(more) => {
+                    result = result.map(|x| x || more);
+                    i += 1;
+                }
+                Err(e) => {
+                    let id = self.attached[i].id;
+                    warn!("receiving on attached socket {:?} failed: {}", id, e);
+                    self.detach(id);
+                }
+            }
+        }
+
+        self.recv_buf = recv_buf;
+        self.recv_limiter.finish_cycle();
+        self.last_recv = now;
+        result
+    }
+
+    /// Receive on the endpoint's own socket, or the `attached` one numbered `index`, returning
+    /// whether it may have more
+    fn recv_on(
+        &mut self,
+        index: Option<usize>,
+        cx: &mut Context,
+        now: Instant,
+        iovs: &mut [IoSliceMut<'_>],
+        metas: &mut [RecvMeta],
+    ) -> Result<bool, io::Error> {
+        loop {
+            let poll = match index {
+                None => self.socket.poll_recv(cx, iovs, metas),
+                Some(i) => self.attached[i].socket.poll_recv(cx, iovs, metas),
+            };
+            match poll {
+                Poll::Ready(Ok(msgs)) => {
+                    self.recv_limiter.record_work(msgs);
+                    // Kernel timestamps are by the system clock, so are placed on the monotonic
+                    // one relative to a reading of both, though never before the last pass
+                    let clocks = (Instant::now(), SystemTime::now());
+                    for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
+                        let received = recv_time(meta.timestamp(), clocks)
+                            .map_or(now, |x| x.max(self.last_recv));
+                        self.ip_stats.received(meta.addr.ip(), meta.len, now);
+                        let dst_ip = match index {
+                            None => {
+                                self.note_peer_socket(meta.addr, None);
+                                meta.dst_ip
+                            }
+                            Some(i) => {
+                                let attached = &self.attached[i];
+                                let (id, local_ip) = (attached.id, attached.local_ip);
+                                self.note_peer_socket(meta.addr, Some(id));
+                                meta.dst_ip.or(local_ip)
+                            }
+                        };
+                        let mut data: BytesMut = buf[0..meta.len].into();
+                        // Forwarded clients' batches go to their upstream as they came
+                        if self.jls_forwarding
+                            && meta.stride < meta.len
+                            && self.jls_state.handle_jls_forward_batch(
+                                &data,
+                                meta.stride,
+                                &meta.addr,
+                                meta.ecn,
+                                dst_ip,
+                                self.inner.config().get_jls_forward_queue_limit(),
+                                self.inner.config().get_jls_forward_total_queue_limit(),
+                                self.inner.config().get_jls_forward_unanswered_warning(),
+                                self.inner.config().get_jls_forward_unanswered_limit(),
+                            )
+                        {
+                            continue;
+                        }
+                        while !data.is_empty() {
+                            let buf = data.split_to(meta.stride.min(data.len()));
+                            self.handle_datagram(
+                                received,
+                                meta.addr,
+                                dst_ip,
+                                meta.ecn.map(proto_ecn),
+                                buf,
+                            );
+                        }
+                    }
+                }
+                Poll::Pending => {
+                    return Ok(false);
+                }
+                // Ignore ECONNRESET as it's undefined in QUIC and may be injected by an
+                // attacker
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => {
+                    continue;
+                }
+                Poll::Ready(Err(e)) => {
+                    return Err(e);
+                }
+            }
+            if !self.recv_limiter.allow_work() {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Remember which socket `peer`'s datagrams last arrived on, `None` being the endpoint's own,
+    /// if replies go out that socket
+    fn note_peer_socket(&mut self, peer: SocketAddr, socket: Option<SocketId>) {
+        if self.reply_socket != ReplySocket::LastUsed {
+            return;
+        }
+        match socket {
+            Some(id) => {
+                if self.peer_sockets.len() < MAX_PEER_SOCKETS
+                    || self.peer_sockets.contains_key(&peer)
+                {
+                    self.peer_sockets.insert(peer, id);
+                }
+            }
+            None => {
+                self.peer_sockets.remove(&peer);
+            }
+        }
+    }
+
+    /// Stop receiving on an attached socket, and sending from it
+    fn detach(&mut self, id: SocketId) -> Option<AttachedSocket> {
+        let index = self.attached.iter().position(|x| x.id == id)?;
+        self.peer_sockets.retain(|_, x| *x != id);
+        Some(self.attached.remove(index))
+    }
+
+    /// The attached socket the transmits at the front of `transmits` go out, numbered as in
+    /// `attached`, unless it's the endpoint's own, and how many of them in a row do
+    fn send_run(&self, transmits: &[udp::Transmit]) -> (Option<usize>, usize) {
+        if self.peer_sockets.is_empty() {
+            return (None, transmits.len());
+        }
+        let socket_of = |t: &udp::Transmit| {
+            let id = self.peer_sockets.get(&t.destination)?;
+            self.attached.iter().position(|x| x.id == *id)
+        };
+        let first = socket_of(&transmits[0]);
+        let len = transmits
+            .iter()
+            .take_while(|t| socket_of(t) == first)
+            .count();
+        (first, len)
+    }
+
+    /// Process a single datagram, or GRO segment, received from `remote`
+    fn handle_datagram(
+        &mut self,
+        now: Instant,
+        remote: SocketAddr,
+        dst_ip: Option<IpAddr>,
+        ecn: Option<proto::EcnCodepoint>,
+        buf: BytesMut,
+    ) {
+        // GRO segments all share `remote`, but forwarding is decided per segment: a client whose
+        // Initial gets forwarded must have the rest of its segments forwarded as well
+        if self.jls_forwarding
+            && self.jls_state.handle_jls_forward(
+                &buf,
+                &remote,
+                ecn.map(udp_ecn),
+                dst_ip,
+                self.inner.config().get_jls_forward_queue_limit(),
+                self.inner.config().get_jls_forward_total_queue_limit(),
+                self.inner.config().get_jls_forward_unanswered_warning(),
+                self.inner.config().get_jls_forward_unanswered_limit(),
+            )
+        {
+            return;
+        }
+        match self.inner.handle(now, remote, dst_ip, ecn, buf) {
+            Some(DatagramEvent::NewConnection(handle, conn)) => {
+                self.new_pending(handle, conn, now);
+            }
+            Some(DatagramEvent::ConnectionEvent(handle, event)) => {
+                if let Some(sender) = self.connections.senders.get(&handle) {
+                    sender.datagram(event);
+                } else if let Some(&id) = self.pending.routes.get(&handle) {
+                    let pending = self.pending.conns.get_mut(&id).unwrap();
+                    pending.conn.handle_event(event);
+                    self.drive_pending(id, now);
+                } else {
+                    trace!("dropping datagram for unknown connection {}", handle.0);
+                }
+            }
+            Some(DatagramEvent::Response(t)) => self.queue_response(t, now),
+            Some(DatagramEvent::NewForward(_ch, conn, client_hello_buf)) => {
+                self.forward_client(conn, client_hello_buf, dst_ip, now);
+            }
+            Some(DatagramEvent::Incoming(incoming)) => {
+                if self.connections.close.is_some() {
+                    let t = self.inner.refuse(incoming, now);
+                    self.queue_response(t, now);
+                    return;
+                }
+                self.incoming.push_back(incoming);
+                self.update_admission();
+            }
+            None => {}
+        }
+    }
+
+    /// Start driving a new incoming connection until the application accepts it, returning its
+    /// identifier in `pending`
+    fn new_pending(
+        &mut self,
+        handle: ConnectionHandle,
+        conn: proto::Connection,
+        now: Instant,
+    ) -> u64 {
+        let remote = conn.remote_address();
+        if self.jls_forwarding {
+            self.jls_state.superseded(&remote);
+        }
+        self.ip_stats.opened(remote.ip(), now);
+        let id = self.pending.insert(handle, conn, now);
+        self.update_admission();
+        self.drive_pending(id, now);
+        id
+    }
+
+    /// Queue a datagram the endpoint sends on its own account, unless too many have been sent to
+    /// its destination lately or the queue is too long
+    fn queue_response(&mut self, t: proto::Transmit, now: Instant) {
+        let config = self.inner.config();
+        let rate = config.get_response_rate();
+        // Stateless resets are the only responses with short headers
+        let unshaped = config.get_stateless_reset_policy() == StatelessResetPolicy::Always
+            && t.contents.first().map_or(false, |x| x & 0x80 == 0);
+        if !unshaped
+            && !self
+                .response_shaper
+                .allow(t.destination.ip(), t.contents.len(), now, rate)
+        {
+            trace!("too many responses to {}, dropping", t.destination);
+            self.shaped_responses_total += 1;
+            return;
+        }
+        // Limiting the memory usage for items queued in the outgoing queue from endpoint
+        // generated packets. Otherwise, we may see a build-up of the queue under test with
+        // flood of initial packets against the endpoint. The sender with the sender-limiter
+        // may not keep up the pace of these packets queued into the queue.
+        if self.transmit_queue_contents_len < MAX_TRANSMIT_QUEUE_CONTENTS_LEN {
+            let contents_len = t.contents.len();
+            self.outgoing.push_back(udp_transmit(t));
+            self.transmit_queue_contents_len = self
+                .transmit_queue_contents_len
+                .saturating_add(contents_len);
+        }
+    }
+
+    /// Forward a client that failed JLS authentication to its upstream
+    fn forward_client(
+        &mut self,
+        conn: proto::Connection,
+        client_hello_buf: BytesMut,
+        dst_ip: Option<IpAddr>,
+        now: Instant,
+    ) {
+        let cids = match long_header_cids(&client_hello_buf) {
+            // The client's first choice of connection ID
+            Some((dcid, _)) => vec![dcid],
+            None => Vec::new(),
+        };
+        let remote = conn.remote_address();
+        let config = self.inner.config();
+        let redaction = config.get_jls_client_redaction();
+        let span = debug_span!(
+            "jls_forward",
+            client = %client_label(remote, redaction, &self.jls_state.redaction_key)
+        );
+        if self.jls_state.closed {
+            trace!(parent: &span, "endpoint closed, not forwarding");
+            return;
+        }
+        let routes = config.get_jls_upstream_routes();
+        if !routes.is_empty() {
+            let server_name = forward_server_name(&conn);
+            let routed = server_name
+                .as_deref()
+                .and_then(|x| route_by_server_name(routes, x));
+            match routed {
+                Some(upstream_addr) => {
+                    trace!(parent: &span, ?server_name, "routed to {}", upstream_addr);
+                    let stats = self.jls_state.upstream_stats.entry(upstream_addr);
+                    stats.or_default().routed_by_server_name += 1;
+                    let datagrams = vec![client_hello_buf];
+                    self.open_forward(remote, upstream_addr, false, cids, datagrams, span, now);
+                    self.jls_state.note_local_ip(&remote, dst_ip);
+                    return;
+                }
+                None if config.get_jls_drop_unrouted() => {
+                    trace!(parent: &span, ?server_name, "no route, not forwarding");
+                    if let Some(addr) = conn.crypto_session().jls_upstream_addr() {
+                        let stats = self.jls_state.upstream_stats.entry(addr);
+                        stats.or_default().refused_unrouted += 1;
+                    }
+                    return;
+                }
+                None => {}
+            }
+        }
+        match config.get_jls_upstream_host() {
+            Some((name, port)) => {
+                let name = name.to_owned();
+                let hello = client_hello_buf;
+                self.forward_to_host(remote, name, port, cids, hello, span, now);
+            }
+            None => {
+                if let Some(upstream_addr) = conn.crypto_session().jls_upstream_addr() {
+                    let datagrams = vec![client_hello_buf];
+                    self.open_forward(remote, upstream_addr, false, cids, datagrams, span, now);
+                }
+            }
+        }
+        self.jls_state.note_local_ip(&remote, dst_ip);
+    }
+
+    /// Drop queued datagrams to `remotes`, other than those of handshaking connections
+    fn discard_outgoing_to(&mut self, remotes: &[SocketAddr]) {
+        let jls_state = &mut self.jls_state;
+        let mut discarded = 0;
+        self.outgoing.rest.retain(|t| {
+            if !remotes.contains(&t.destination) {
+                return true;
+            }
+            discarded += t.contents.len();
+            jls_state.dequeued(&t.destination, t.contents.len());
+            false
+        });
+        self.transmit_queue_contents_len =
+            self.transmit_queue_contents_len.saturating_sub(discarded);
+    }
+
+    /// Drive an incoming connection that hasn't been accepted yet
+    ///
+    /// Once drained, the connection is let go of, having nothing left to report but its fate.
+    fn drive_pending(&mut self, id: u64, now: Instant) {
+        let pending = match self.pending.conns.get_mut(&id) {
+            Some(x) => x,
+            None => return,
+        };
+        let max_datagrams = self.udp_state.max_gso_segments();
+        let mut drained = false;
+        loop {
+            while let Some(t) = pending.conn.poll_transmit(now, max_datagrams) {
+                // Nobody has vouched for these connections yet, so like the endpoint's own
+                // responses, what they queue is bounded
+                if self.transmit_queue_contents_len >= MAX_TRANSMIT_QUEUE_CONTENTS_LEN {
+                    trace!("outgoing queue full, dropping datagram of incoming connection");
+                    continue;
+                }
+                self.transmit_queue_contents_len = self
+                    .transmit_queue_contents_len
+                    .saturating_add(t.contents.len());
+                // Connections are accepted once their handshake completes, so everything they send
+                // before then counts as a handshake
+                match t.send_at {
+                    Some(send_at) if send_at > now => self.paced.push(send_at, true, t),
+                    _ => self.outgoing.push_handshake(udp_transmit(t)),
+                }
+            }
+            let mut progress = false;
+            while let Some(event) = pending.conn.poll_endpoint_events() {
+                if event.is_drained() {
+                    drained = true;
+                    self.pending.routes.remove(&pending.handle);
+                    self.ip_stats
+                        .drained(pending.conn.remote_address().ip(), now);
+                    if self.shutdown.started {
+                        self.shutdown.closed_cleanly += 1;
+                    }
+                }
+                if let Some(event) = self.inner.handle_event(pending.handle, event) {
+                    pending.conn.handle_event(event);
+                    progress = true;
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        if drained {
+            self.pending.remove(id);
+            self.update_admission();
+            return;
+        }
+
+        let timeout = pending.conn.poll_timeout();
+        if timeout != pending.timeout {
+            pending.timeout = timeout;
+            if let Some(t) = timeout {
+                self.pending.timeouts.push(Reverse((t, id)));
+            }
+        }
+    }
+
+    /// Handle expired timers of incoming connections that haven't been accepted yet
+    fn drive_pending_timers(&mut self, cx: &mut Context, now: Instant) -> bool {
+        while let Some(&Reverse((t, id))) = self.pending.timeouts.peek() {
+            if t > now {
+                break;
+            }
+            self.pending.timeouts.pop();
+            match self.pending.conns.get_mut(&id) {
+                // Otherwise the timeout is stale
+                Some(pending) if pending.timeout == Some(t) => {
+                    pending.timeout = None;
+                    pending.conn.handle_timeout(now);
+                }
+                _ => continue,
+            }
+            self.drive_pending(id, now);
+        }
+
+        let next = match self.pending.timeouts.peek() {
+            Some(&Reverse((t, _))) => t,
+            None => return false,
+        };
+        match self.pending.timer {
+            Some(ref mut timer) => timer.as_mut().reset(next),
+            None => self.pending.timer = Some(self.runtime.new_timer(next)),
+        }
+        // If the timer already expired, poll again to handle it
+        let timer = self.pending.timer.as_mut().unwrap();
+        timer.as_mut().poll(cx).is_ready()
+    }
+
+    /// Tighten or relax admission of new connections according to how many are waiting to be
+    /// accepted
+    fn update_admission(&mut self) {
+        let waiting = self.pending.conns.len() + self.incoming.len();
+        self.inner.set_admission(if waiting >= self.max_incoming {
+            proto::Admission::Refuse
+        } else if waiting >= self.incoming_retry_threshold {
+            proto::Admission::Validate
+        } else {
+            proto::Admission::Open
+        });
+    }
+
+    /// Hand the oldest incoming connection to the application, allocating its channels and
+    /// driver task
+    fn accept_pending(&mut self) -> Option<Connecting> {
+        // Skipping those drained since they were queued
+        loop {
+            let id = self.pending.queue.pop_front()?;
+            if self.pending.conns.contains_key(&id) {
+                return Some(self.take_pending(id));
+            }
+        }
+    }
+
+    /// Hand the incoming connection `id`, no longer queued, to the application
+    fn take_pending(&mut self, id: u64) -> Connecting {
+        self.update_admission();
+        let PendingConnection {
+            handle,
+            conn,
+            created,
+            ..
+        } = self.pending.conns.remove(&id).unwrap();
+        self.pending.routes.remove(&handle);
+        self.connections.insert(
+            handle,
+            conn,
+            created,
+            self.udp_state.clone(),
+            self.runtime.clone(),
+            None,
+        )
+    }
+
+    /// Start the connection an [`Incoming`] asked for, as the application decided
+    fn accept_incoming(&mut self, incoming: proto::Incoming) -> Result<Connecting, AcceptError> {
+        let now = Instant::now();
+        if self.driver_lost || self.connections.close.is_some() {
+            let t = self.inner.refuse(incoming, now);
+            self.queue_response(t, now);
+            self.wake();
+            return Err(AcceptError::EndpointStopping);
+        }
+        let dst_ip = incoming.local_ip();
+        let result = match self.inner.accept(incoming, now) {
+            Some(DatagramEvent::NewConnection(handle, conn)) => {
+                let id = self.new_pending(handle, conn, now);
+                // It's the application's already, not for `accept()` to hand out
+                self.pending.queue.pop_back();
+                Ok(self.take_pending(id))
+            }
+            Some(DatagramEvent::NewForward(_ch, conn, client_hello_buf)) => {
+                self.forward_client(conn, client_hello_buf, dst_ip, now);
+                Err(AcceptError::Forwarded)
+            }
+            Some(DatagramEvent::Response(t)) => {
+                self.queue_response(t, now);
+                Err(AcceptError::Refused)
+            }
+            Some(DatagramEvent::ConnectionEvent(..)) | Some(DatagramEvent::Incoming(_)) => {
+                unreachable!("accepting yields a new connection or a response")
+            }
+            None => Err(AcceptError::Dropped),
+        };
+        self.wake();
+        result
+    }
+
+    /// Whether no connection needs the endpoint driver
+    fn is_idle(&self) -> bool {
+        self.connections.is_empty() && self.pending.routes.is_empty()
+    }
+
+    /// Whether every datagram connections have queued has been handed to the socket
+    fn is_flushed(&self) -> bool {
+        self.events_drained
+            && self.outgoing.is_empty()
+            && self.deferred_acks.is_empty()
+            && self.paced.is_empty()
+    }
+
+    /// Publish how far shutting down has got, if it's under way and anything changed
+    fn publish_shutdown(&mut self) {
+        let shutdown = &mut self.shutdown;
+        if !shutdown.started || shutdown.finished {
+            return;
+        }
+        shutdown.connections = (self.connections.senders.len() + self.pending.routes.len()) as u64;
+        shutdown.forwards = self.jls_state.upstream_connections.len() as u64;
+        shutdown.finished = shutdown.connections == 0 && shutdown.forwards == 0;
+        let shutdown = *shutdown;
+        self.shutdown_progress.send_if_modified(|x| {
+            let modified = *x != shutdown;
+            *x = shutdown;
+            modified
+        });
+    }
+
+    /// Publish a final summary of a shutdown cut short by the driver stopping, counting whatever
+    /// was left as closed forcibly
+    fn abandon_shutdown(&mut self) {
+        self.publish_shutdown();
+        let shutdown = &mut self.shutdown;
+        if !shutdown.started || shutdown.finished {
+            return;
+        }
+        shutdown.closed_forcibly += shutdown.connections + shutdown.forwards;
+        shutdown.connections = 0;
+        shutdown.forwards = 0;
+        shutdown.finished = true;
+        self.shutdown_progress.send_replace(*shutdown);
+    }
+
+    /// Whether the driver may exit without losing datagrams, e.g. the final CONNECTION_CLOSE of
+    /// a connection that was closed just before every handle was dropped
+    ///
+    /// `busy` indicates that some queue was left unfinished by the current iteration. If the
+    /// socket never lets us catch up, we give up after `SHUTDOWN_TIMEOUT`.
+    fn flushed(&mut self, cx: &mut Context, now: Instant, busy: bool) -> bool {
+        if !busy && self.outgoing.is_empty() && self.paced.is_empty() {
+            return true;
+        }
+        if self.shutdown_timer.is_none() {
+            self.shutdown_timer = Some(self.runtime.new_timer(now + SHUTDOWN_TIMEOUT));
+        }
+        let timer = self.shutdown_timer.as_mut().unwrap();
+        timer.as_mut().poll(cx).is_ready()
+    }
+
+    /// Schedule the endpoint driver, e.g. after queueing transmits outside of it
+    fn wake(&self) {
+        if let Some(x) = self.driver.as_ref() {
+            x.wake_by_ref();
+        }
+    }
+
+    /// Check the socket for tasks waiting until it's writable
+    ///
+    /// Registers the driver's own waker, since sockets may only remember one task to wake.
+    fn poll_writable_waiters(&mut self, cx: &mut Context) {
+        if self.writable_waiters.is_empty() {
+            return;
+        }
+        if let Poll::Ready(result) = self.socket.poll_send_ready(cx) {
+            self.notify_writable(result);
+        }
+    }
+
+    /// Wake the tasks waiting until the socket is writable
+    fn notify_writable(&mut self, result: io::Result<()>) {
+        self.writable_epoch += 1;
+        self.writable_error = result.err().map(|e| (e.kind(), e.to_string()));
+        for waker in self.writable_waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Handle events from connections and send the datagrams they queue, repeating while more
+    /// keep arriving
+    ///
+    /// Connections may queue datagrams while the driver is busy, e.g. from other threads, or
+    /// faster than one call to `handle_events` takes them in. Picking those up straight away
+    /// saves them waiting for the driver's next poll. Each send takes one of `rounds`, and running
+    /// out of send budget takes all of them.
+    fn drive_events_and_send(
+        &mut self,
+        cx: &mut Context,
+        shared: &Shared,
+        rounds: &mut usize,
+    ) -> Result<bool, io::Error> {
+        let mut events_left = self.timed_handle_events(cx, shared);
+        while *rounds > 0 {
+            *rounds -= 1;
+            let start = Instant::now();
+            let send_left = self.drive_send(cx)?;
+            self.poll_phases.send += start.elapsed();
+            if send_left {
+                *rounds = 0;
+                return Ok(true);
+            }
+            if !self.outgoing.is_empty() {
+                // The socket will wake us once it can take more
+                return Ok(events_left);
+            }
+            events_left = self.timed_handle_events(cx, shared);
+            if self.outgoing.is_empty() {
+                return Ok(events_left);
+            }
+        }
+        Ok(events_left || !self.outgoing.is_empty())
+    }
+
+    fn timed_handle_events(&mut self, cx: &mut Context, shared: &Shared) -> bool {
+        let start = Instant::now();
+        let events_left = self.handle_events(cx, shared);
+        self.poll_phases.events += start.elapsed();
+        events_left
     }
-}
 
-#[derive(Debug)]
-pub(crate) struct JlsForwardConnection {
-    upstream_socket: Box<dyn AsyncUdpSocket>,
-    upstream_addr: SocketAddr,
-    to_upstream: VecDeque<udp::Transmit>,
-    from_upstream: Box<[u8]>,
-    udp_state: Arc<UdpState>,
-    active_time: Instant,
-}
+    /// Account for a poll of the driver that started at `start`, reporting it if it was slow
+    fn record_poll(&mut self, start: Instant) {
+        let end = Instant::now();
+        let elapsed = end.saturating_duration_since(start);
+        self.last_poll_end = Some(end);
+        let phases = self.poll_phases;
+        let timing = &mut self.timing;
+        timing.polls += 1;
+        timing.total_poll += elapsed;
+        timing.max_poll = timing.max_poll.max(elapsed);
+        timing.phases.add(&phases);
+        timing.max_phases.update_max(&phases);
+        if self.slow_poll_threshold.map_or(false, |x| elapsed > x) {
+            debug!(
+                ?elapsed,
+                recv = ?phases.recv,
+                send = ?phases.send,
+                events = ?phases.events,
+                forwarding = ?phases.forwarding,
+                "slow endpoint driver poll"
+            );
+        }
+    }
 
-#[derive(Debug)]
-pub(crate) struct Shared {
-    incoming: Notify,
-    idle: Notify,
-}
+    /// Whether an ACK-only transmit should wait for the next pass rather than join the queue
+    fn defers_acks(&self) -> bool {
+        self.ack_only_watermark
+            .map_or(false, |x| self.transmit_queue_contents_len > x)
+    }
 
-impl State {
-    fn drive_recv<'a>(&'a mut self, cx: &mut Context, now: Instant) -> Result<bool, io::Error> {
-        self.recv_limiter.start_cycle();
-        let mut metas = [RecvMeta::default(); BATCH_SIZE];
-        let mut iovs = MaybeUninit::<[IoSliceMut<'a>; BATCH_SIZE]>::uninit();
-        self.recv_buf
-            .chunks_mut(self.recv_buf.len() / BATCH_SIZE)
-            .enumerate()
-            .for_each(|(i, buf)| unsafe {
-                iovs.as_mut_ptr()
-                    .cast::<IoSliceMut>()
-                    .add(i)
-                    .write(IoSliceMut::<'a>::new(buf));
-            });
-        let mut iovs = unsafe { iovs.assume_init() };
-        loop {
-            match self.socket.poll_recv(cx, &mut iovs, &mut metas) {
-                Poll::Ready(Ok(msgs)) => {
-                    self.recv_limiter.record_work(msgs);
-                    for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
-                        let mut data: BytesMut = buf[0..meta.len].into();
-                        while !data.is_empty() {
-                            let buf = data.split_to(meta.stride.min(data.len()));
-                            if self
-                                .jls_state
-                                .handle_jls_forward(&buf, &meta.addr)
-                            {
-                                continue;
-                            } else {
-                                match self.inner.handle(
-                                    now,
-                                    meta.addr,
-                                    meta.dst_ip,
-                                    meta.ecn.map(proto_ecn),
-                                    buf,
-                                ) {
-                                    Some(DatagramEvent::NewConnection(handle, conn)) => {
-                                        let conn = self.connections.insert(
-                                            handle,
-                                            conn,
-                                            self.udp_state.clone(),
-                                            self.runtime.clone(),
-                                        );
-                                        self.incoming.push_back(conn);
-                                    }
-                                    Some(DatagramEvent::ConnectionEvent(handle, event)) => {
-                                        // Ignoring errors from dropped connections that haven't yet been cleaned up
-                                        let _ = self
-                                            .connections
-                                            .senders
-                                            .get_mut(&handle)
-                                            .unwrap()
-                                            .send(ConnectionEvent::Proto(event));
-                                    }
-                                    Some(DatagramEvent::Response(t)) => {
-                                        // Limiting the memory usage for items queued in the outgoing queue from endpoint
-                                        // generated packets. Otherwise, we may see a build-up of the queue under test with
-                                        // flood of initial packets against the endpoint. The sender with the sender-limiter
-                                        // may not keep up the pace of these packets queued into the queue.
-                                        if self.transmit_queue_contents_len
-                                            < MAX_TRANSMIT_QUEUE_CONTENTS_LEN
-                                        {
-                                            let contents_len = t.contents.len();
-                                            self.outgoing.push_back(udp_transmit(t));
-                                            self.transmit_queue_contents_len = self
-                                                .transmit_queue_contents_len
-                                                .saturating_add(contents_len);
-                                        }
-                                    }
-                                    Some(DatagramEvent::NewForward(
-                                        _ch,
-                                        conn,
-                                        client_hello_buf,
-                                    )) => {
-                                        if let Some(upstream_addr) = conn.crypto_session().jls_upstream_addr() {
-                                            debug!("new forward connection");
-                                            let socket = std::net::UdpSocket::bind(
-                                                "[::]:0".parse::<SocketAddr>().unwrap(),
-                                            )?;
-                                            let udp_socket =
-                                                self.runtime.wrap_udp_socket(socket).unwrap();
-                                            let udp_state = UdpState::new();
-                                            let recv_buf = vec![
-                                                0;
-                                                self.inner
-                                                    .config()
-                                                    .get_max_udp_payload_size()
-                                                    .min(64 * 1024)
-                                                    as usize
-                                                    * udp_state.gro_segments()
-                                                    * BATCH_SIZE
-                                            ];
-                                            let mut jls_conn = JlsForwardConnection {
-                                                upstream_socket: udp_socket,
-                                                upstream_addr:upstream_addr,
-                                                to_upstream: VecDeque::new(),
-                                                from_upstream: recv_buf.into(),
-                                                active_time: now.clone(),
-                                                udp_state: udp_state.into(),
-                                            };
-                                            let trans = upstream_udp_transmit(
-                                                &upstream_addr,
-                                                client_hello_buf,
-                                            );
-                                            jls_conn.to_upstream.push_back(trans);
-                                            self.jls_state
-                                                .upstream_connections
-                                                .insert(conn.remote_address(), jls_conn);
-                                        }
-                                    }
-                                    None => {}
-                                }
-                            }
-                        }
-                    }
-                }
-                Poll::Pending => {
-                    break;
-                }
-                // Ignore ECONNRESET as it's undefined in QUIC and may be injected by an
-                // attacker
-                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => {
-                    continue;
-                }
-                Poll::Ready(Err(e)) => {
-                    return Err(e);
-                }
-            }
-            if !self.recv_limiter.allow_work() {
-                self.recv_limiter.finish_cycle();
-                return Ok(true);
-            }
+    /// Queue the ACK-only transmits held back during the previous pass
+    fn release_deferred_acks(&mut self) {
+        for (_, transmit) in self.deferred_acks.drain() {
+            self.transmit_queue_contents_len = self
+                .transmit_queue_contents_len
+                .saturating_add(transmit.contents.len());
+            self.outgoing.push_back(udp_transmit(transmit));
         }
+    }
 
-        self.recv_limiter.finish_cycle();
-        Ok(false)
+    /// Queue the paced transmits whose time has come
+    fn release_paced(&mut self, now: Instant) {
+        while let Some((handshake, transmit)) = self.paced.pop_due(now) {
+            self.queue_transmit(transmit, handshake);
+        }
+    }
+
+    /// Release due paced transmits, and set the timer for the next
+    ///
+    /// One timer serves every connection's paced transmits, so connections needn't wake up
+    /// between them. Returns whether any were released or the timer already expired.
+    fn drive_paced(&mut self, cx: &mut Context, now: Instant) -> bool {
+        let queued = self.outgoing.len();
+        self.release_paced(now);
+        let released = self.outgoing.len() != queued;
+        let next = match self.paced.earliest() {
+            Some(t) => t,
+            None => return released,
+        };
+        match self.paced.timer {
+            Some(ref mut timer) => timer.as_mut().reset(next),
+            None => self.paced.timer = Some(self.runtime.new_timer(next)),
+        }
+        let timer = self.paced.timer.as_mut().unwrap();
+        released || timer.as_mut().poll(cx).is_ready()
+    }
+
+    /// Add a transmit from a connection to `outgoing`
+    fn queue_transmit(&mut self, transmit: proto::Transmit, handshake: bool) {
+        self.transmit_queue_contents_len = self
+            .transmit_queue_contents_len
+            .saturating_add(transmit.contents.len());
+        if handshake {
+            self.outgoing.push_handshake(udp_transmit(transmit));
+        } else {
+            self.outgoing.push_back(udp_transmit(transmit));
+        }
     }
 
     fn drive_send(&mut self, cx: &mut Context) -> Result<bool, io::Error> {
+        if !self.paced.is_empty() {
+            self.release_paced(Instant::now());
+        }
         self.send_limiter.start_cycle();
 
         let result = loop {
@@ -565,20 +3038,50 @@ impl State {
                 break Ok(false);
             }
 
+            if let Some(timer) = self.send_backoff.as_mut() {
+                if timer.as_mut().poll(cx).is_pending() {
+                    break Ok(false);
+                }
+                self.send_backoff = None;
+            }
+
             if !self.send_limiter.allow_work() {
                 break Ok(true);
             }
 
-            match self
-                .socket
-                .poll_send(&self.udp_state, cx, self.outgoing.as_slices().0)
-            {
+            self.outgoing.make_contiguous();
+            let transmits = self.outgoing.front();
+            let (index, len) = self.send_run(transmits);
+            let poll = match index {
+                None => self
+                    .socket
+                    .poll_send(&self.udp_state, cx, &transmits[..len]),
+                Some(i) => {
+                    let attached = &self.attached[i];
+                    attached
+                        .socket
+                        .poll_send(&attached.udp_state, cx, &transmits[..len])
+                }
+            };
+            match poll {
+                // No progress, and nothing registered to wake the driver, so it's woken to retry
+                // rather than retrying at once
+                Poll::Ready(Ok(0)) => {
+                    break Ok(true);
+                }
                 Poll::Ready(Ok(n)) => {
-                    let contents_len: usize =
-                        self.outgoing.drain(..n).map(|t| t.contents.len()).sum();
-                    self.transmit_queue_contents_len = self
-                        .transmit_queue_contents_len
-                        .saturating_sub(contents_len);
+                    if !self.writable_waiters.is_empty() {
+                        self.notify_writable(Ok(()));
+                    }
+                    let now = Instant::now();
+                    for t in &self.outgoing.front()[..n] {
+                        self.ip_stats
+                            .sent(t.destination.ip(), t.contents.len(), now);
+                        if !self.send_failures.is_empty() {
+                            self.send_failures.remove(&t.destination);
+                        }
+                    }
+                    self.dequeue(n);
                     // We count transmits instead of `poll_send` calls since the cost
                     // of a `sendmmsg` still linearly increases with number of packets.
                     self.send_limiter.record_work(n);
@@ -586,9 +3089,36 @@ impl State {
                 Poll::Pending => {
                     break Ok(false);
                 }
-                Poll::Ready(Err(e)) => {
-                    break Err(e);
+                Poll::Ready(Err(e)) if udp::is_transient_send_error(&e) => {
+                    // Only the first transmit of a batch can have failed; the rest weren't tried
+                    let destination = self.outgoing.front()[0].destination;
+                    let now = Instant::now();
+                    let since = self.note_send_failure(destination, now);
+                    if now.saturating_duration_since(since) < SEND_FAILURE_PERSISTENCE {
+                        // Keep the transmits queued; they can usually be sent a moment later
+                        let delay = send_backoff_delay();
+                        debug!("sending failed with {}, retrying in {:?}", e, delay);
+                        self.send_backoff = Some(self.runtime.new_timer(now + delay));
+                    } else {
+                        // Don't hold up the transmits to everyone else for a destination that
+                        // can't be reached, e.g. because a firewall rule rejects it
+                        debug!(
+                            "sending to {} keeps failing with {}, dropping",
+                            destination, e
+                        );
+                        self.dequeue(1);
+                        self.dropped_sends += 1;
+                    }
                 }
+                Poll::Ready(Err(e)) => match index {
+                    // The transmits go out the endpoint's own socket instead
+                    Some(i) => {
+                        let id = self.attached[i].id;
+                        warn!("sending on attached socket {:?} failed: {}", id, e);
+                        self.detach(id);
+                    }
+                    None => break Err(e),
+                },
             }
         };
 
@@ -596,37 +3126,62 @@ impl State {
         result
     }
 
+    /// Remove the first `n` transmits from the queue, once they're sent or given up on
+    fn dequeue(&mut self, n: usize) {
+        let mut contents_len = 0;
+        for t in self.outgoing.drain_front(n) {
+            contents_len += t.contents.len();
+            self.jls_state.dequeued(&t.destination, t.contents.len());
+        }
+        self.transmit_queue_contents_len = self
+            .transmit_queue_contents_len
+            .saturating_sub(contents_len);
+    }
+
+    /// Record a transient failure to send to `destination`, returning since when sends there have
+    /// been failing
+    fn note_send_failure(&mut self, destination: SocketAddr, now: Instant) -> Instant {
+        if self.send_failures.len() >= MAX_SEND_FAILURES {
+            self.send_failures.retain(|_, &mut (_, last)| {
+                now.saturating_duration_since(last) < SEND_FAILURE_MEMORY
+            });
+        }
+        let failure = self.send_failures.entry(destination).or_insert((now, now));
+        if now.saturating_duration_since(failure.1) >= SEND_FAILURE_MEMORY {
+            failure.0 = now;
+        }
+        failure.1 = now;
+        failure.0
+    }
+
+    /// Take in events from connections, each class of them up to its own bound
+    ///
+    /// Returns whether either class has more events waiting.
     fn handle_events(&mut self, cx: &mut Context, shared: &Shared) -> bool {
+        let more_events = self.handle_events_of(cx, shared, false);
+        let more_transmits = self.handle_events_of(cx, shared, true);
+        self.events_drained = !more_events && !more_transmits;
+        more_events || more_transmits
+    }
+
+    fn handle_events_of(&mut self, cx: &mut Context, shared: &Shared, transmits: bool) -> bool {
         use EndpointEvent::*;
 
         for _ in 0..IO_LOOP_BOUND {
-            match self.events.poll_recv(cx) {
-                Poll::Ready(Some((ch, event))) => match event {
-                    Proto(e) => {
-                        if e.is_drained() {
-                            self.connections.senders.remove(&ch);
-                            if self.connections.is_empty() {
-                                shared.idle.notify_waiters();
-                            }
-                        }
-                        if let Some(event) = self.inner.handle_event(ch, e) {
-                            // Ignoring errors from dropped connections that haven't yet been cleaned up
-                            let _ = self
-                                .connections
-                                .senders
-                                .get_mut(&ch)
-                                .unwrap()
-                                .send(ConnectionEvent::Proto(event));
-                        }
+            let events = match transmits {
+                false => &mut self.events,
+                true => &mut self.transmits,
+            };
+            match events.poll_recv(cx) {
+                Poll::Ready(Some((ch, event))) => {
+                    // Whether this is the last transmit of a connection that has already drained
+                    let last =
+                        matches!(event, Transmit { .. }) && self.connections.take_transmit(ch);
+                    self.handle_event(shared, ch, event);
+                    if last {
+                        self.drained(shared, ch);
                     }
-                    Transmit(t) => {
-                        let contents_len = t.contents.len();
-                        self.outgoing.push_back(udp_transmit(t));
-                        self.transmit_queue_contents_len = self
-                            .transmit_queue_contents_len
-                            .saturating_add(contents_len);
-                    }
-                },
+                }
                 Poll::Ready(None) => unreachable!("EndpointInner owns one sender"),
                 Poll::Pending => {
                     return false;
@@ -637,175 +3192,748 @@ impl State {
         true
     }
 
-    fn upstream_recv<'a>(&'a mut self, cx: &mut Context, now: Instant) -> Result<bool, io::Error> {
-        let mut last_err: Option<io::Error> = None;
-        let mut to_remove = Vec::<SocketAddr>::new();
-        let upstream_conns = &mut self.jls_state.upstream_connections;
-        for (remote, conn) in upstream_conns.iter_mut() {
-            let mut metas = [RecvMeta::default(); BATCH_SIZE];
-            let mut iovs = MaybeUninit::<[IoSliceMut<'a>; BATCH_SIZE]>::uninit();
-            conn.from_upstream
-                .chunks_mut(conn.from_upstream.len() / BATCH_SIZE)
-                .enumerate()
-                .for_each(|(i, buf)| unsafe {
-                    iovs.as_mut_ptr()
-                        .cast::<IoSliceMut>()
-                        .add(i)
-                        .write(IoSliceMut::<'a>::new(buf));
-                });
-            let mut iovs = unsafe { iovs.assume_init() };
-            loop {
-                match conn.upstream_socket.poll_recv(cx, &mut iovs, &mut metas) {
-                    Poll::Ready(Ok(msgs)) => {
-                        for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
-                            let mut data: BytesMut = buf[0..meta.len].into();
-                            while !data.is_empty() {
-                                let buf = data.split_to(meta.stride.min(data.len()));
-                                if self.transmit_queue_contents_len
-                                    < MAX_TRANSMIT_QUEUE_CONTENTS_LEN
-                                {
-                                    let trans = Transmit {
-                                        destination: remote.clone(),
-                                        contents: buf.into(),
-                                        ecn: None,
-                                        segment_size: None,
-                                        src_ip: None,
-                                    };
-                                    let contents_len = trans.contents.len();
-                                    self.outgoing.push_back(trans);
-                                    self.transmit_queue_contents_len = self
-                                        .transmit_queue_contents_len
-                                        .saturating_add(contents_len);
-                                    trace!("recv from upstream: {:?} bytes", contents_len);
-                                }
-                            }
-                        }
-                        conn.active_time = now;
-                    }
-                    Poll::Pending => {
-                        if now.duration_since(conn.active_time).as_secs() > 30 {
-                            to_remove.push(remote.clone());
-                            //trace!("remove old forward connection from {:?}", remote);
-                        }
-                        break;
-                    }
-                    Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => {
-                        continue;
-                    }
-                    Poll::Ready(Err(e)) => {
-                        last_err = Some(e);
-                        to_remove.push(remote.clone());
-                        break;
+    fn handle_event(&mut self, shared: &Shared, ch: ConnectionHandle, event: EndpointEvent) {
+        use EndpointEvent::*;
+
+        match event {
+            Proto(e) => {
+                if let Some(event) = self.inner.handle_event(ch, e) {
+                    self.connections.senders[&ch].proto(event);
+                }
+            }
+            Drained { transmits } => {
+                // Let the connection's remaining transmits catch up first
+                match self.connections.records.get_mut(&ch) {
+                    Some(record) if record.transmits < transmits => {
+                        record.drained_after = Some(transmits);
                     }
+                    _ => self.drained(shared, ch),
+                }
+            }
+            Transmit {
+                transmit,
+                handshake,
+                ..
+            } if transmit.send_at.map_or(false, |t| t > Instant::now()) => {
+                let send_at = transmit.send_at.unwrap();
+                self.paced.push(send_at, handshake, transmit);
+            }
+            // Transmits from connections the endpoint has already let go of, as injected
+            // in tests, aren't held back
+            Transmit {
+                transmit,
+                handshake,
+                ack_only,
+            } if ack_only
+                && !handshake
+                && self.defers_acks()
+                && self.connections.senders.contains_key(&ch) =>
+            {
+                self.deferred_acks_total += 1;
+                if self.deferred_acks.insert(ch, transmit).is_some() {
+                    self.coalesced_acks_total += 1;
+                }
+            }
+            Transmit {
+                transmit,
+                handshake,
+                ..
+            } => {
+                // Paced transmits due by now may come from the same connection, and must
+                // go first
+                if !self.paced.is_empty() {
+                    self.release_paced(Instant::now());
+                }
+                self.queue_transmit(transmit, handshake);
+            }
+            Activity(activity) => {
+                // Ignore late reports from connections which have already been drained
+                if let Some(record) = self.connections.records.get_mut(&ch) {
+                    record.remote_address = activity.remote_address;
+                    self.connections.activity.insert(ch, activity);
+                }
+            }
+            Phase(phase) => {
+                if let Some(record) = self.connections.records.get_mut(&ch) {
+                    record.phase = phase;
                 }
             }
         }
-        // TODO remove old connections
-        // for k in to_remove.iter() {
-        //     upstream_conns.remove(k);
-        // }
-        if let Some(e) = last_err {
-            Err(e)
-        } else {
-            Ok(false)
+    }
+
+    /// Let go of a drained connection
+    fn drained(&mut self, shared: &Shared, ch: ConnectionHandle) {
+        self.connections.senders.remove(&ch);
+        self.connections.activity.remove(&ch);
+        if let Some(record) = self.connections.records.remove(&ch) {
+            self.ip_stats.drained(record.ip, Instant::now());
+        }
+        if self.shutdown.started {
+            self.shutdown.closed_cleanly += 1;
+        }
+        self.deferred_acks.remove(&ch);
+        if self.connections.is_empty() {
+            shared.idle.notify_waiters();
         }
+        self.inner.handle_event(ch, proto::EndpointEvent::drained());
     }
-    fn upstream_send(&mut self, cx: &mut Context, now: Instant) -> Result<bool, io::Error> {
-        let mut to_remove = Vec::<SocketAddr>::new();
-        let mut last_err: Option<io::Error> = None;
-        for (remote, conn) in self.jls_state.upstream_connections.iter_mut() {
-            loop {
-                if conn.to_upstream.is_empty() {
-                    break;
+
+    /// Hand the pool a new forward connection from `remote` to `upstream_addr`, along with the
+    /// client's datagrams so far
+    ///
+    /// `span` covers the forwarding of the client.
+    #[allow(clippy::too_many_arguments)]
+    fn open_forward(
+        &mut self,
+        remote: SocketAddr,
+        upstream_addr: SocketAddr,
+        by_host: bool,
+        cids: Vec<proto::ConnectionId>,
+        mut datagrams: Vec<BytesMut>,
+        span: Span,
+        now: Instant,
+    ) {
+        let config = self.inner.config();
+        let jls_state = &mut self.jls_state;
+        let admitted = span.in_scope(|| {
+            jls_state.admit(
+                &remote,
+                upstream_addr,
+                now,
+                config.get_jls_forward_limit(),
+                config.get_jls_forward_rate(),
+            )
+        });
+        if !admitted {
+            return;
+        }
+        let mut untried = self.jls_state.upstream_order(
+            upstream_addr,
+            config.get_jls_upstream_alternates(),
+            config.get_jls_upstream_selection(),
+        );
+        let first = untried.remove(0);
+        let failover = Failover {
+            untried,
+            ..Failover::default()
+        };
+        let by_host = by_host && first == upstream_addr;
+        self.start_forward(remote, first, by_host, cids, datagrams, failover, span, now);
+    }
+
+    /// Hand the pool a forward connection from `remote` to `upstream_addr`, along with the
+    /// client's datagrams so far
+    #[allow(clippy::too_many_arguments)]
+    fn start_forward(
+        &mut self,
+        remote: SocketAddr,
+        upstream_addr: SocketAddr,
+        by_host: bool,
+        cids: Vec<proto::ConnectionId>,
+        mut datagrams: Vec<BytesMut>,
+        mut failover: Failover,
+        span: Span,
+        now: Instant,
+    ) {
+        let config = self.inner.config();
+        let transport = config.get_jls_upstream_transport();
+        let socks5 = config.get_jls_upstream_socks5().cloned();
+        let socket_per_client = config.get_jls_forward_socket_per_client();
+        let bind = config.get_jls_upstream_bind();
+        let response_timeout = match failover.untried.is_empty() {
+            true => None,
+            false => {
+                failover.hello = Some(datagrams[0].clone());
+                Some(config.get_jls_upstream_response_timeout())
+            }
+        };
+        let unanswered_warning = config.get_jls_forward_unanswered_warning();
+        let id = self.jls_state.insert(
+            remote,
+            upstream_addr,
+            by_host,
+            cids,
+            &datagrams,
+            failover,
+            span,
+            now,
+        );
+        let jls_state = &mut self.jls_state;
+        let conn = jls_state.upstream_connections.get_mut(&remote).unwrap();
+        let span = conn.span.clone();
+        let stats = jls_state.upstream_stats.get_mut(&upstream_addr);
+        let len = datagrams.iter().map(|x| x.len()).sum();
+        conn.client_datagrams(datagrams.len(), len, unanswered_warning, stats);
+        let hello = datagrams.remove(0);
+        let pool = self.jls_pool();
+        let _ = pool.send(ForwardCommand::Open {
+            remote,
+            id,
+            upstream_addr,
+            hello,
+            transport,
+            socks5,
+            socket_per_client,
+            bind,
+            response_timeout,
+            span,
+        });
+        for data in datagrams {
+            let _ = pool.send(ForwardCommand::Datagram {
+                remote,
+                data,
+                segment_size: None,
+                ecn: None,
+            });
+        }
+    }
+
+    /// Account for a forward connection the pool ended, moving it on to the next upstream if that
+    /// one failed and there's another to try
+    fn forward_ended(&mut self, id: u64, reason: ForwardEnd, now: Instant) {
+        let (remote, conn) = match self.jls_state.ended(id, reason) {
+            Some(x) => x,
+            None => return,
+        };
+        if self.shutdown.started {
+            match reason {
+                ForwardEnd::DrainTimedOut => self.shutdown.closed_forcibly += 1,
+                _ => self.shutdown.closed_cleanly += 1,
+            }
+        }
+        if matches!(
+            reason,
+            ForwardEnd::Idle | ForwardEnd::Closed | ForwardEnd::DrainTimedOut
+        ) {
+            return;
+        }
+        let mut failover = conn.failover;
+        // Only kept while there's another upstream to try, and the upstream hasn't answered
+        let hello = match failover.hello.take() {
+            Some(x) => x,
+            None => return,
+        };
+        let next = failover.untried.remove(0);
+        failover.count += 1;
+        if let Some(stats) = self.jls_state.upstream_stats.get_mut(&conn.upstream_addr) {
+            stats.failovers += 1;
+        }
+        debug!(parent: &conn.span, "moving forward connection on to {}", next);
+        let cids = conn.cids;
+        self.start_forward(
+            remote,
+            next,
+            false,
+            cids,
+            vec![hello],
+            failover,
+            conn.client_span,
+            now,
+        );
+        self.jls_state.note_local_ip(&remote, conn.local_ip);
+    }
+
+    /// Forward the client at `remote` to the configured upstream host, once it's resolved
+    #[allow(clippy::too_many_arguments)]
+    fn forward_to_host(
+        &mut self,
+        remote: SocketAddr,
+        name: String,
+        port: u16,
+        cids: Vec<proto::ConnectionId>,
+        hello: BytesMut,
+        span: Span,
+        now: Instant,
+    ) {
+        let limit = self.inner.config().get_jls_forward_limit();
+        let jls_state = &mut self.jls_state;
+        if jls_state
+            .host
+            .as_ref()
+            .map_or(true, |x| x.name != name || x.port != port)
+        {
+            // Clients awaiting the host configured before go to this one instead
+            jls_state.host = Some(ResolvedHost {
+                name,
+                port,
+                addr: None,
+                resolving: false,
+                failures: 0,
+            });
+        }
+        let host = jls_state.host.as_mut().unwrap();
+        if let Some(addr) = host.addr {
+            self.open_forward(remote, addr, true, cids, vec![hello], span, now);
+            return;
+        }
+        if !jls_state.awaiting.contains_key(&remote)
+            && (jls_state.awaiting.len() + jls_state.upstream_connections.len()) as u64 >= limit
+        {
+            trace!(parent: &span, "too many forward connections, refusing");
+            return;
+        }
+        let len = hello.len();
+        jls_state.awaiting.insert(
+            remote,
+            AwaitingForward {
+                cids,
+                datagrams: vec![hello],
+                len,
+                local_ip: None,
+                span,
+            },
+        );
+        if host.resolving {
+            return;
+        }
+        host.resolving = true;
+        debug!("looking up upstream host {}", host.name);
+        let lookup = self.runtime.resolve(host.name.clone(), port);
+        let events = jls_state.events_sender.clone();
+        let host = host.name.clone();
+        self.runtime.spawn(Box::pin(async move {
+            let result = lookup.await;
+            let _ = events.send(ForwardEvent::Resolved { host, port, result });
+        }));
+    }
+
+    /// Set up the forward connections that awaited a lookup of the upstream host
+    fn resolved(
+        &mut self,
+        name: String,
+        port: u16,
+        result: io::Result<Vec<SocketAddr>>,
+        now: Instant,
+    ) {
+        let host = match self.jls_state.host {
+            Some(ref mut x) if x.name == name && x.port == port => x,
+            // Made stale by a change of configuration, whose own lookup is underway
+            _ => return,
+        };
+        host.resolving = false;
+        host.failures = 0;
+        host.addr = match result {
+            Ok(addrs) => addrs.into_iter().next(),
+            Err(e) => {
+                debug!("failed to look up upstream host {}: {}", name, e);
+                None
+            }
+        };
+        let awaiting = mem::take(&mut self.jls_state.awaiting);
+        let addr = match host.addr {
+            Some(x) => x,
+            None => {
+                debug!(
+                    "upstream host {} not found, dropping {} forward connections",
+                    name,
+                    awaiting.len()
+                );
+                return;
+            }
+        };
+        for (remote, forward) in awaiting {
+            let AwaitingForward {
+                cids,
+                datagrams,
+                local_ip,
+                span,
+                ..
+            } = forward;
+            self.open_forward(remote, addr, true, cids, datagrams, span, now);
+            self.jls_state.note_local_ip(&remote, local_ip);
+        }
+    }
+
+    /// The task relaying forward connections' traffic, started if there isn't one
+    fn jls_pool(&mut self) -> &mpsc::UnboundedSender<ForwardCommand> {
+        if self.jls_state.pool.is_none() {
+            let config = self.inner.config();
+            let slot = config.get_max_udp_payload_size().min(64 * 1024) as usize
+                * self.udp_state.gro_segments();
+            let (pool, commands) = ForwardPool::new(
+                self.runtime.clone(),
+                self.jls_state.events_sender.clone(),
+                self.jls_state.buffer_bytes.clone(),
+                slot,
+                config.get_jls_forward_idle_timeout(),
+            );
+            self.runtime.spawn(Box::pin(pool));
+            self.jls_state.pool = Some(commands);
+        }
+        self.jls_state.pool.as_ref().unwrap()
+    }
+
+    /// Take in the forward pool's reports, returning whether any are left, or the turn's budget
+    /// ran out
+    fn handle_forward_events(&mut self, cx: &mut Context, now: Instant) -> bool {
+        let total_limit = self.inner.config().get_jls_forward_total_queue_limit();
+        self.forward_limiter.start_cycle();
+        let keep_going = loop {
+            if !self.forward_limiter.allow_work() {
+                break true;
+            }
+            let event = match self.jls_state.events.poll_recv(cx) {
+                Poll::Ready(Some(x)) => x,
+                Poll::Ready(None) => unreachable!("JlsState owns one sender"),
+                Poll::Pending => break false,
+            };
+            // Relayed datagrams count one by one, like those the driver receives itself
+            self.forward_limiter.record_work(match event {
+                ForwardEvent::Relay {
+                    ref data,
+                    segment_size,
+                    ..
+                } => data.chunks(segment_size.unwrap_or(data.len()).max(1)).len(),
+                _ => 1,
+            });
+            match event {
+                ForwardEvent::Relay {
+                    id,
+                    data,
+                    segment_size,
+                    ecn,
+                } => {
+                    let jls_state = &mut self.jls_state;
+                    // Dropped if the client is no longer forwarded, e.g. since a rebind
+                    let remote = match jls_state.remotes.get(&id) {
+                        Some(&x) => x,
+                        None => continue,
+                    };
+                    let conn = jls_state.upstream_connections.get_mut(&remote).unwrap();
+                    if jls_state.closed {
+                        continue;
+                    }
+                    let mut stats = jls_state.upstream_stats.get_mut(&conn.upstream_addr);
+                    let known = conn.cids.len();
+                    for datagram in data.chunks(segment_size.unwrap_or(data.len()).max(1)) {
+                        conn.upstream_datagram(datagram, stats.as_deref_mut());
+                    }
+                    for cid in &conn.cids[known..] {
+                        jls_state.cids.insert(*cid, remote);
+                    }
+                    // The upstream answered, so the connection stays with it
+                    conn.failover.hello = None;
+                    if conn.first_response.is_none() {
+                        let after = now.saturating_duration_since(conn.opened);
+                        debug!(parent: &conn.span, ?after, "upstream answered");
+                        conn.first_response = Some(after);
+                    }
+                    if conn.by_host {
+                        // The upstream host's address works after all
+                        if let Some(host) = jls_state
+                            .host
+                            .as_mut()
+                            .filter(|x| x.addr == Some(conn.upstream_addr))
+                        {
+                            host.failures = 0;
+                        }
+                    }
+                    conn.active_time = now;
+                    if self.transmit_queue_contents_len >= MAX_TRANSMIT_QUEUE_CONTENTS_LEN {
+                        continue;
+                    }
+                    let contents_len = data.len();
+                    if (jls_state.queued_len + contents_len) as u64 > total_limit {
+                        trace!(parent: &conn.span, "forward queues full, dropping datagrams");
+                        if let Some(stats) = stats {
+                            let count = data.chunks(segment_size.unwrap_or(contents_len).max(1));
+                            stats.dropped_by_total_limit += count.len() as u64;
+                        }
+                        continue;
+                    }
+                    conn.to_client_len += contents_len;
+                    jls_state.queued_len += contents_len;
+                    queue_segments(
+                        &mut self.outgoing.rest,
+                        remote,
+                        data,
+                        segment_size,
+                        ecn,
+                        conn.local_ip,
+                        self.udp_state.max_gso_segments(),
+                    );
+                    self.transmit_queue_contents_len = self
+                        .transmit_queue_contents_len
+                        .saturating_add(contents_len);
+                    conn.bytes_from_upstream += contents_len as u64;
+                    if let Some(stats) = stats {
+                        stats.bytes_from_upstream += contents_len as u64;
+                    }
+                    trace!(parent: &conn.span, "recv from upstream: {:?} bytes", contents_len);
                 }
-                match conn.upstream_socket.poll_send(
-                    &conn.udp_state,
-                    cx,
-                    conn.to_upstream.as_slices().0,
-                ) {
-                    Poll::Ready(Ok(n)) => {
-                        let contents_len: usize =
-                            conn.to_upstream.drain(..n).map(|t| t.contents.len()).sum();
-                        trace!("forward to upstream: {:?} bytes", contents_len);
+                ForwardEvent::Sent {
+                    id,
+                    datagrams,
+                    bytes,
+                } => {
+                    let jls_state = &mut self.jls_state;
+                    if let Some(conn) = jls_state
+                        .remotes
+                        .get(&id)
+                        .and_then(|x| jls_state.upstream_connections.get_mut(x))
+                    {
+                        conn.to_upstream = conn.to_upstream.saturating_sub(datagrams);
+                        let sent = bytes.min(conn.to_upstream_len);
+                        conn.to_upstream_len -= sent;
+                        jls_state.queued_len -= sent;
+                        conn.bytes_to_upstream += bytes as u64;
                         conn.active_time = now;
-                    }
-                    Poll::Pending => {
-                        if conn.active_time.duration_since(conn.active_time).as_secs() > 30 {
-                            to_remove.push(remote.clone());
-                            trace!("remove old forward connection from {:?}", remote);
+                        if let Some(stats) = jls_state.upstream_stats.get_mut(&conn.upstream_addr) {
+                            stats.bytes_to_upstream += bytes as u64;
                         }
-                        break;
                     }
-                    Poll::Ready(Err(e)) => {
-                        last_err = Some(e);
-                        to_remove.push(remote.clone());
-                        break;
+                }
+                ForwardEvent::Unroutable { upstream } => {
+                    if let Some(stats) = self.jls_state.upstream_stats.get_mut(&upstream) {
+                        stats.unroutable_from_upstream += 1;
                     }
                 }
+                ForwardEvent::Ended { id, reason } => {
+                    self.forward_ended(id, reason, now);
+                }
+                ForwardEvent::Resolved { host, port, result } => {
+                    self.resolved(host, port, result, now);
+                }
+            }
+        };
+        self.forward_limiter.finish_cycle();
+        keep_going
+    }
+
+    // fn get_upstream_url(&self) -> Option<String> {
+    //     self.inn
+    // }
+}
+
+#[inline]
+fn udp_transmit(t: proto::Transmit) -> udp::Transmit {
+    udp::Transmit {
+        destination: t.destination,
+        ecn: t.ecn.map(udp_ecn),
+        contents: t.contents,
+        segment_size: t.segment_size,
+        src_ip: t.src_ip,
+    }
+}
+
+#[inline]
+fn udp_ecn(ecn: proto::EcnCodepoint) -> udp::EcnCodepoint {
+    match ecn {
+        proto::EcnCodepoint::Ect0 => udp::EcnCodepoint::Ect0,
+        proto::EcnCodepoint::Ect1 => udp::EcnCodepoint::Ect1,
+        proto::EcnCodepoint::Ce => udp::EcnCodepoint::Ce,
+    }
+}
+
+#[inline]
+fn proto_ecn(ecn: udp::EcnCodepoint) -> proto::EcnCodepoint {
+    match ecn {
+        udp::EcnCodepoint::Ect0 => proto::EcnCodepoint::Ect0,
+        udp::EcnCodepoint::Ect1 => proto::EcnCodepoint::Ect1,
+        udp::EcnCodepoint::Ce => proto::EcnCodepoint::Ce,
+    }
+}
+
+/// When a datagram the kernel timestamped at `timestamp` was received, by the monotonic clock
+///
+/// `clocks` are readings of the monotonic and system clocks taken together, after the datagram was
+/// received. Returns `None` without a timestamp, or with one the system clock must have been
+/// stepped across, for the caller to fall back on a time of its own.
+fn recv_time(timestamp: Option<SystemTime>, clocks: (Instant, SystemTime)) -> Option<Instant> {
+    let age = clocks.1.duration_since(timestamp?).ok()?;
+    if age > MAX_RECV_TIMESTAMP_AGE {
+        return None;
+    }
+    clocks.0.checked_sub(age)
+}
+
+/// Pick a delay between `SEND_BACKOFF_MIN` and `SEND_BACKOFF_MAX`, so that endpoints sharing a
+/// rate limit don't retry in lockstep
+fn send_backoff_delay() -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let range = (SEND_BACKOFF_MAX - SEND_BACKOFF_MIN).as_micros() as u64;
+    SEND_BACKOFF_MIN + Duration::from_micros(random % (range + 1))
+}
+
+/// Incoming connections that haven't been accepted by the application yet
+///
+/// These are driven by the endpoint driver itself, so that connections which are never accepted
+/// don't cost the channels and task of a [`Connecting`].
+#[derive(Debug, Default)]
+struct PendingSet {
+    /// Connections by an identifier that, unlike their handle, is never reused
+    conns: FxHashMap<u64, PendingConnection>,
+    /// Identifiers of the connections, in the order they arrived, along with some of those since
+    /// drained
+    queue: VecDeque<u64>,
+    /// Identifiers of the connections, by handle
+    routes: FxHashMap<ConnectionHandle, u64>,
+    /// Deadlines of the connections' timers, including stale ones
+    timeouts: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// Expires at the earliest deadline in `timeouts`
+    timer: Option<Pin<Box<dyn AsyncTimer>>>,
+    next_id: u64,
+}
+
+impl PendingSet {
+    fn insert(&mut self, handle: ConnectionHandle, conn: proto::Connection, now: Instant) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.conns.insert(
+            id,
+            PendingConnection {
+                handle,
+                conn,
+                created: now,
+                timeout: None,
+            },
+        );
+        self.queue.push_back(id);
+        self.routes.insert(handle, id);
+        id
+    }
+
+    /// Let go of the connection `id`, which was drained
+    fn remove(&mut self, id: u64) {
+        self.conns.remove(&id);
+        // Its place in the queue is skipped once reached, unless drained connections come to
+        // outnumber those waiting
+        if self.queue.len() > 2 * self.conns.len() + 16 {
+            let conns = &self.conns;
+            self.queue.retain(|id| conns.contains_key(id));
+        }
+    }
+
+    /// Identifiers of the connections
+    fn live(&self) -> Vec<u64> {
+        self.routes.values().copied().collect()
+    }
+}
+
+/// Transmits from connections that pacing scheduled for later, under
+/// [`TransportConfig::pacing_horizon()`](proto::TransportConfig::pacing_horizon)
+#[derive(Debug, Default)]
+struct PacedQueue {
+    /// Transmits with their handshake flag, by an identifier assigned in the order they arrived
+    transmits: FxHashMap<u64, (bool, proto::Transmit)>,
+    /// When each transmit may be sent; ties go in the order transmits arrived, which keeps those
+    /// of each connection in order
+    times: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// Expires at the earliest time in `times`
+    timer: Option<Pin<Box<dyn AsyncTimer>>>,
+    next_id: u64,
+}
+
+impl PacedQueue {
+    fn push(&mut self, send_at: Instant, handshake: bool, transmit: proto::Transmit) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.transmits.insert(id, (handshake, transmit));
+        self.times.push(Reverse((send_at, id)));
+    }
+
+    /// Take the earliest transmit, if it may be sent by `now`
+    fn pop_due(&mut self, now: Instant) -> Option<(bool, proto::Transmit)> {
+        match self.times.peek() {
+            Some(&Reverse((t, id))) if t <= now => {
+                self.times.pop();
+                self.transmits.remove(&id)
             }
+            _ => None,
         }
+    }
 
-        for remote in to_remove {
-            self.jls_state.upstream_connections.remove(&remote);
-        }
-        if let Some(e) = last_err {
-            return Err(e);
-        }
-        Ok(false)
+    fn earliest(&self) -> Option<Instant> {
+        self.times.peek().map(|&Reverse((t, _))| t)
     }
-    // fn get_upstream_url(&self) -> Option<String> {
-    //     self.inn
-    // }
-}
 
-#[inline]
-fn udp_transmit(t: proto::Transmit) -> udp::Transmit {
-    udp::Transmit {
-        destination: t.destination,
-        ecn: t.ecn.map(udp_ecn),
-        contents: t.contents,
-        segment_size: t.segment_size,
-        src_ip: t.src_ip,
+    fn is_empty(&self) -> bool {
+        self.times.is_empty()
     }
 }
 
-#[inline]
-fn udp_ecn(ecn: proto::EcnCodepoint) -> udp::EcnCodepoint {
-    match ecn {
-        proto::EcnCodepoint::Ect0 => udp::EcnCodepoint::Ect0,
-        proto::EcnCodepoint::Ect1 => udp::EcnCodepoint::Ect1,
-        proto::EcnCodepoint::Ce => udp::EcnCodepoint::Ce,
-    }
+/// Datagrams waiting to be sent on the socket
+///
+/// Those of handshaking connections are sent ahead of everything else, so that a flood of e.g.
+/// relayed JLS traffic can't delay handshakes until they time out.
+#[derive(Debug, Default)]
+struct OutgoingQueue {
+    handshakes: VecDeque<udp::Transmit>,
+    rest: VecDeque<udp::Transmit>,
 }
 
-#[inline]
-fn proto_ecn(ecn: udp::EcnCodepoint) -> proto::EcnCodepoint {
-    match ecn {
-        udp::EcnCodepoint::Ect0 => proto::EcnCodepoint::Ect0,
-        udp::EcnCodepoint::Ect1 => proto::EcnCodepoint::Ect1,
-        udp::EcnCodepoint::Ce => proto::EcnCodepoint::Ce,
+impl OutgoingQueue {
+    fn push_handshake(&mut self, transmit: udp::Transmit) {
+        self.handshakes.push_back(transmit);
+    }
+
+    fn push_back(&mut self, transmit: udp::Transmit) {
+        self.rest.push_back(transmit);
+    }
+
+    /// The queue transmits are currently sent from
+    fn front_queue(&self) -> &VecDeque<udp::Transmit> {
+        match self.handshakes.is_empty() {
+            true => &self.rest,
+            false => &self.handshakes,
+        }
+    }
+
+    /// The transmits to offer the socket next, in one batch
+    ///
+    /// Call [`make_contiguous()`](Self::make_contiguous) first, or a wrapped-around queue is
+    /// offered in two shorter batches.
+    fn front(&self) -> &[udp::Transmit] {
+        self.front_queue().as_slices().0
+    }
+
+    fn make_contiguous(&mut self) {
+        match self.handshakes.is_empty() {
+            true => self.rest.make_contiguous(),
+            false => self.handshakes.make_contiguous(),
+        };
+    }
+
+    /// Remove the first `n` transmits of [`front()`](Self::front)
+    fn drain_front(&mut self, n: usize) -> impl Iterator<Item = udp::Transmit> + '_ {
+        match self.handshakes.is_empty() {
+            true => self.rest.drain(..n),
+            false => self.handshakes.drain(..n),
+        }
     }
-}
 
-fn upstream_udp_transmit(addr: &SocketAddr, data: BytesMut) -> Transmit {
-    let remote = addr;
-    Transmit {
-        contents: data.into(),
-        destination: remote.clone(),
-        ecn: None,
-        segment_size: None,
-        src_ip: None,
+    fn len(&self) -> usize {
+        self.handshakes.len() + self.rest.len()
     }
+
+    fn is_empty(&self) -> bool {
+        self.handshakes.is_empty() && self.rest.is_empty()
+    }
+}
+
+#[derive(Debug)]
+struct PendingConnection {
+    handle: ConnectionHandle,
+    conn: proto::Connection,
+    created: Instant,
+    /// The deadline of the connection's timer, as last queued in `PendingSet::timeouts`
+    timeout: Option<Instant>,
 }
 
 #[derive(Debug)]
 struct ConnectionSet {
     /// Senders for communicating with the endpoint's connections
-    senders: FxHashMap<ConnectionHandle, mpsc::UnboundedSender<ConnectionEvent>>,
+    senders: FxHashMap<ConnectionHandle, EventSender>,
     /// Stored to give out clones to new ConnectionInners
-    sender: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
+    sender: EndpointEventSender,
     /// Set if the endpoint has been manually closed
     close: Option<(VarInt, Bytes)>,
+    /// Latest activity reported by each connection
+    activity: FxHashMap<ConnectionHandle, ConnectionActivity>,
+    /// What the endpoint knows about each connection, kept for as long as it has a sender
+    records: FxHashMap<ConnectionHandle, ConnectionRecord>,
+    /// Capacity of each connection's event queue, in datagrams, from the endpoint's config
+    max_queued_datagrams: usize,
 }
 
 impl ConnectionSet {
@@ -813,19 +3941,53 @@ impl ConnectionSet {
         &mut self,
         handle: ConnectionHandle,
         conn: proto::Connection,
+        created: Instant,
         udp_state: Arc<UdpState>,
         runtime: Arc<dyn Runtime>,
+        deferred: Option<Deferred>,
     ) -> Connecting {
-        let (send, recv) = mpsc::unbounded_channel();
+        let (send, recv) = event_queue(self.max_queued_datagrams);
         if let Some((error_code, ref reason)) = self.close {
-            send.send(ConnectionEvent::Close {
-                error_code,
-                reason: reason.clone(),
-            })
-            .unwrap();
+            send.close(error_code, reason.clone());
         }
         self.senders.insert(handle, send);
-        Connecting::new(handle, conn, self.sender.clone(), recv, udp_state, runtime)
+        let phase = ConnectionPhase::of(&conn);
+        let remote_address = conn.remote_address();
+        let connecting = Connecting::new(
+            handle,
+            conn,
+            self.sender.clone(),
+            recv,
+            udp_state,
+            runtime,
+            deferred,
+        );
+        self.records.insert(
+            handle,
+            ConnectionRecord {
+                created,
+                phase,
+                remote_address,
+                ip: remote_address.ip(),
+                stable_id: connecting.stable_id(),
+                transmits: 0,
+                drained_after: None,
+            },
+        );
+        connecting
+    }
+
+    /// Count a transmit taken in from `handle`
+    ///
+    /// Returns whether it was the last one the connection sent before it drained.
+    fn take_transmit(&mut self, handle: ConnectionHandle) -> bool {
+        match self.records.get_mut(&handle) {
+            Some(record) => {
+                record.transmits += 1;
+                record.drained_after == Some(record.transmits)
+            }
+            None => false,
+        }
     }
 
     fn is_empty(&self) -> bool {
@@ -833,6 +3995,118 @@ impl ConnectionSet {
     }
 }
 
+/// An accepted connection, as tracked by its endpoint
+#[derive(Debug)]
+struct ConnectionRecord {
+    created: Instant,
+    /// Updated as the connection's driver reports progress
+    phase: ConnectionPhase,
+    /// Updated with each activity report, so it follows migrations
+    remote_address: SocketAddr,
+    /// The peer's address when the connection opened, as counted in [`IpStats`]
+    ip: IpAddr,
+    stable_id: usize,
+    /// Number of transmits taken in from the connection
+    transmits: u64,
+    /// Set once the connection reports it drained before all its transmits were taken in
+    drained_after: Option<u64>,
+}
+
+/// Resolves once an endpoint's socket can send, or the endpoint is gone
+///
+/// The driver checks the socket on the waiting task's behalf, and wakes it once it has sent or
+/// found the socket writable. Polling the socket from here would take the place of the driver's
+/// own registration, leaving its sends stalled.
+struct SocketWritable {
+    endpoint: Weak<EndpointInner>,
+    /// The endpoint's `writable_epoch` when this started waiting
+    epoch: Option<u64>,
+}
+
+impl Future for SocketWritable {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let endpoint = match self.endpoint.upgrade() {
+            Some(x) => x,
+            None => return Poll::Ready(Ok(())),
+        };
+        let mut state = endpoint.state.lock().unwrap();
+        match self.epoch {
+            Some(epoch) if epoch != state.writable_epoch => {
+                return Poll::Ready(match state.writable_error {
+                    Some((kind, ref msg)) => Err(io::Error::new(kind, msg.clone())),
+                    None => Ok(()),
+                });
+            }
+            Some(_) => {}
+            None if state.driver_lost => return Poll::Ready(Ok(())),
+            None => self.epoch = Some(state.writable_epoch),
+        }
+        if !state
+            .writable_waiters
+            .iter()
+            .any(|x| x.will_wake(cx.waker()))
+        {
+            state.writable_waiters.push(cx.waker().clone());
+        }
+        state.wake();
+        Poll::Pending
+    }
+}
+
+/// Applies the policy installed by [`Endpoint::set_reaper`] on a timer
+struct Reaper {
+    endpoint: Weak<EndpointInner>,
+    policy: Box<ReapPolicy>,
+    interval: Duration,
+    timer: Pin<Box<dyn AsyncTimer>>,
+    generation: u64,
+}
+
+impl Future for Reaper {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            ready!(this.timer.as_mut().poll(cx));
+            let endpoint = match this.endpoint.upgrade() {
+                Some(x) => x,
+                None => return Poll::Ready(()),
+            };
+
+            let snapshot = {
+                let state = endpoint.state.lock().unwrap();
+                if state.driver_lost || state.reaper_generation != this.generation {
+                    return Poll::Ready(());
+                }
+                state
+                    .connections
+                    .activity
+                    .values()
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+
+            // Run the policy without holding the endpoint lock
+            let reaped = (this.policy)(&snapshot);
+            if !reaped.is_empty() {
+                let state = endpoint.state.lock().unwrap();
+                for reap in reaped {
+                    if let Some(sender) = state.connections.senders.get(&reap.handle) {
+                        let stable_id = state.connections.records[&reap.handle].stable_id;
+                        debug!(handle = reap.handle.0, stable_id, "reaping connection");
+                        sender.close(reap.error_code, reap.reason);
+                    }
+                }
+            }
+
+            this.timer.as_mut().reset(Instant::now() + this.interval);
+        }
+    }
+}
+
 fn ensure_ipv6(x: SocketAddr) -> SocketAddrV6 {
     match x {
         SocketAddr::V6(x) => x,
@@ -857,7 +4131,7 @@ impl<'a> Future for Accept<'a> {
         if endpoint.driver_lost {
             return Poll::Ready(None);
         }
-        if let Some(conn) = endpoint.incoming.pop_front() {
+        if let Some(conn) = endpoint.accept_pending() {
             return Poll::Ready(Some(conn));
         }
         if endpoint.connections.close.is_some() {
@@ -876,6 +4150,497 @@ impl<'a> Future for Accept<'a> {
     }
 }
 
+pin_project! {
+    /// Future produced by [`Endpoint::accept_incoming`]
+    pub struct AcceptIncoming<'a> {
+        endpoint: &'a Endpoint,
+        #[pin]
+        notify: Notified<'a>,
+    }
+}
+
+impl<'a> Future for AcceptIncoming<'a> {
+    type Output = Option<Incoming>;
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        let mut endpoint = this.endpoint.inner.state.lock().unwrap();
+        if endpoint.driver_lost {
+            return Poll::Ready(None);
+        }
+        if let Some(incoming) = endpoint.incoming.pop_front() {
+            endpoint.update_admission();
+            // Cloning the reference takes the lock again
+            drop(endpoint);
+            return Poll::Ready(Some(Incoming {
+                inner: Some(incoming),
+                endpoint: this.endpoint.inner.clone(),
+            }));
+        }
+        if endpoint.connections.close.is_some() {
+            return Poll::Ready(None);
+        }
+        loop {
+            match this.notify.as_mut().poll(ctx) {
+                // `state` lock ensures we didn't race with readiness
+                Poll::Pending => return Poll::Pending,
+                // Spurious wakeup, get a new future
+                Poll::Ready(()) => this
+                    .notify
+                    .set(this.endpoint.inner.shared.incoming.notified()),
+            }
+        }
+    }
+}
+
+/// A connection attempt from a client, yielded by [`Endpoint::accept_incoming()`]
+///
+/// Nothing has been committed to the connection yet, so deciding on it by the client's address,
+/// e.g. against an allowlist or a per-source rate limit, is cheap. Further Initials from the
+/// client are dropped until it's decided on. Dropping it [ignores](Self::ignore) the attempt.
+#[derive(Debug)]
+pub struct Incoming {
+    /// Taken once decided on
+    inner: Option<proto::Incoming>,
+    endpoint: EndpointRef,
+}
+
+impl Incoming {
+    /// The client's address
+    pub fn remote_address(&self) -> SocketAddr {
+        self.inner.as_ref().unwrap().remote_address()
+    }
+
+    /// The local IP address the attempt was received at, if known
+    pub fn local_ip(&self) -> Option<IpAddr> {
+        self.inner.as_ref().unwrap().local_ip()
+    }
+
+    /// The destination connection ID of the client's very first Initial
+    pub fn orig_dst_cid(&self) -> ConnectionId {
+        *self.inner.as_ref().unwrap().orig_dst_cid()
+    }
+
+    /// Whether the client has proven it can receive at its address by answering a Retry
+    pub fn remote_address_validated(&self) -> bool {
+        self.inner.as_ref().unwrap().remote_address_validated()
+    }
+
+    /// Start the handshake
+    ///
+    /// The endpoint's limits are checked again, since they may have been reached in the
+    /// meantime. A client failing JLS authentication is forwarded or dropped just as it would
+    /// have been without [`defer_incoming`](ServerConfig::defer_incoming).
+    pub fn accept(mut self) -> Result<Connecting, AcceptError> {
+        let incoming = self.inner.take().unwrap();
+        let mut endpoint = self.endpoint.state.lock().unwrap();
+        endpoint.accept_incoming(incoming)
+    }
+
+    /// Refuse the connection, telling the client so
+    pub fn refuse(mut self) {
+        let incoming = self.inner.take().unwrap();
+        let mut endpoint = self.endpoint.state.lock().unwrap();
+        let now = Instant::now();
+        let t = endpoint.inner.refuse(incoming, now);
+        endpoint.queue_response(t, now);
+        endpoint.wake();
+    }
+
+    /// Ask the client to prove it can receive at its address with a Retry
+    ///
+    /// A client that does comes back as a new [`Incoming`] that is
+    /// [validated](Self::remote_address_validated). Fails if this one already is, since a client
+    /// may only be sent a single Retry, or if the endpoint no longer accepts connections.
+    pub fn retry(mut self) -> Result<(), RetryError> {
+        let incoming = self.inner.take().unwrap();
+        let mut endpoint = self.endpoint.state.lock().unwrap();
+        match endpoint.inner.retry(incoming) {
+            Ok(t) => {
+                endpoint.queue_response(t, Instant::now());
+                endpoint.wake();
+                Ok(())
+            }
+            Err(proto::RetryError(incoming)) => {
+                drop(endpoint);
+                self.inner = Some(incoming);
+                Err(RetryError(self))
+            }
+        }
+    }
+
+    /// Drop the attempt without answering the client
+    pub fn ignore(self) {}
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        if let Some(incoming) = self.inner.take() {
+            let mut endpoint = self.endpoint.state.lock().unwrap();
+            endpoint.inner.ignore(incoming, Instant::now());
+        }
+    }
+}
+
+/// Reasons [`Incoming::accept()`] can yield no connection
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptError {
+    /// The endpoint's limits were reached, or the client's first packet was unacceptable, and
+    /// the client was told so
+    #[error("connection refused")]
+    Refused,
+    /// The client failed JLS authentication, and was forwarded to the upstream
+    #[error("client forwarded to upstream")]
+    Forwarded,
+    /// The client failed JLS authentication or its handshake, and was dropped
+    #[error("connection dropped")]
+    Dropped,
+    /// The endpoint was closed or its driver lost, and the client was refused
+    #[error("endpoint stopping")]
+    EndpointStopping,
+}
+
+/// Error returned by [`Incoming::retry()`], handing the attempt back to be decided on otherwise
+#[derive(Debug, Error)]
+#[error("connection attempt can't be retried")]
+pub struct RetryError(pub Incoming);
+
+/// Delay after which [`Endpoint::connect_racing`] starts a new attempt if none has completed
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long a driver whose endpoint and connections are all gone waits to flush its transmits
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bounds on how long sending is held off after a transient error
+const SEND_BACKOFF_MIN: Duration = Duration::from_millis(1);
+const SEND_BACKOFF_MAX: Duration = Duration::from_millis(10);
+
+/// How long sends to a destination may keep failing with transient errors before the driver stops
+/// holding off for it, and drops its transmits instead
+const SEND_FAILURE_PERSISTENCE: Duration = Duration::from_millis(50);
+
+/// How long after the last failed send to a destination its failures are forgotten
+const SEND_FAILURE_MEMORY: Duration = Duration::from_secs(1);
+
+/// Number of failing destinations the driver tracks before forgetting those that stopped failing
+const MAX_SEND_FAILURES: usize = 64;
+
+/// Maximum number of times the driver sends newly queued datagrams in one poll
+///
+/// Each round gets the full send budget, so this bounds how long a poll can take when connections
+/// queue datagrams as fast as they're sent.
+const SEND_ROUNDS: usize = 4;
+
+/// How often the driver checks whether its socket has been dropping datagrams
+const LOCAL_DROPS_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Kernel receive timestamps older than this are taken to be off by a step of the system clock,
+/// rather than of datagrams that waited in the receive buffer this long
+const MAX_RECV_TIMESTAMP_AGE: Duration = Duration::from_secs(10);
+
+/// Polls of the driver taking longer than this are reported, unless configured otherwise
+const DEFAULT_SLOW_POLL_THRESHOLD: Duration = Duration::from_millis(5);
+
+/// Why an [`Endpoint`] couldn't be constructed
+///
+/// Carried by the [`io::Error`]s of the endpoint constructors, from which it can be recovered with
+/// [`get_ref()`](io::Error::get_ref) and [`downcast_ref()`](std::error::Error::downcast_ref).
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum EndpointSetupError {
+    /// No async runtime was found to drive the endpoint; see [`default_runtime()`]
+    #[error("no async runtime found")]
+    NoRuntime,
+    /// The socket couldn't be bound
+    #[error("bind: {0}")]
+    Bind(io::Error),
+    /// The runtime can't drive the socket
+    #[error("socket rejected by runtime: {0}")]
+    SocketRejected(io::Error),
+}
+
+/// Errors from operations on a running [`Endpoint`]
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EndpointError {
+    /// The endpoint's driver stopped, e.g. after an I/O error
+    #[error("endpoint stopped")]
+    Stopped,
+}
+
+impl From<EndpointSetupError> for io::Error {
+    fn from(e: EndpointSetupError) -> Self {
+        let kind = match e {
+            EndpointSetupError::NoRuntime => io::ErrorKind::Other,
+            EndpointSetupError::Bind(ref e) | EndpointSetupError::SocketRejected(ref e) => e.kind(),
+        };
+        Self::new(kind, e)
+    }
+}
+
+/// Warn if `socket`'s buffers are too small for the datagrams `config` allows
+///
+/// Such a socket drops or truncates datagrams, which is hard to tell apart from loss on the
+/// network.
+pub(crate) fn check_socket_buffers(socket: &std::net::UdpSocket, config: &EndpointConfig) {
+    let payload = config.get_max_udp_payload_size() as usize;
+    let socket = udp::UdpSockRef::from(socket);
+    for (buffer, option, size) in [
+        ("receive", "SO_RCVBUF", socket.recv_buffer_size()),
+        ("send", "SO_SNDBUF", socket.send_buffer_size()),
+    ] {
+        let size = match size {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        if size < payload {
+            warn!(
+                "socket {} buffer of {} bytes can't hold a datagram of max_udp_payload_size {}; \
+                 raise it with setsockopt({}) or lower max_udp_payload_size",
+                buffer, size, payload, option
+            );
+        } else if size < payload * BATCH_SIZE {
+            warn!(
+                "socket {} buffer of {} bytes can't hold a batch of {} datagrams of {} bytes; \
+                 consider raising it with setsockopt({})",
+                buffer, size, BATCH_SIZE, payload, option
+            );
+        }
+    }
+}
+
+/// Hand `socket` to `runtime`
+///
+/// A socket that was already set up by another runtime, and so is non-blocking and close-on-exec,
+/// may fail to register with [`io::ErrorKind::AlreadyExists`]; registration is then retried once
+/// after clearing those flags. With `recv_timestamps`, the socket is set to report receive
+/// timestamps first.
+pub(crate) fn wrap_socket(
+    runtime: &dyn Runtime,
+    socket: std::net::UdpSocket,
+    recv_timestamps: bool,
+) -> io::Result<Box<dyn AsyncUdpSocket>> {
+    if recv_timestamps {
+        udp::UdpSocketState::set_recv_timestamps((&socket).into(), true)?;
+    }
+    // `wrap_udp_socket()` consumes the socket, so keep a handle on it to retry with
+    let retry = socket.try_clone();
+    let e = match runtime.wrap_udp_socket(socket) {
+        Ok(x) => return Ok(x),
+        Err(e) => e,
+    };
+    match retry {
+        Ok(socket) if e.kind() == io::ErrorKind::AlreadyExists => {
+            debug!("retrying registration with flags cleared: {}", e);
+            clear_socket_flags(&socket).and_then(|()| runtime.wrap_udp_socket(socket))
+        }
+        _ => Err(e),
+    }
+    .map_err(|e| EndpointSetupError::SocketRejected(e).into())
+}
+
+/// Make `socket` blocking and inheritable again, as a freshly created socket is
+fn clear_socket_flags(socket: &std::net::UdpSocket) -> io::Result<()> {
+    socket.set_nonblocking(false)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        let fd = socket.as_raw_fd();
+        // Safety: `fd` is a valid descriptor owned by `socket`
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Identifies a socket attached with [`Endpoint::attach_socket()`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SocketId(u64);
+
+/// Which socket an endpoint with [attached sockets](Endpoint::attach_socket) sends from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReplySocket {
+    /// Always the endpoint's own socket
+    Primary,
+    /// The socket the destination's datagrams last arrived on
+    LastUsed,
+}
+
+/// Overrides of the default client configuration for a single connection
+///
+/// Passed to [`Endpoint::connect_with_options()`], so that e.g. the congestion controller can be
+/// picked per destination without keeping a [`ClientConfig`] for each.
+#[derive(Clone, Default)]
+pub struct ConnectOptions {
+    transport: Option<Arc<TransportConfig>>,
+    congestion_controller_factory:
+        Option<Arc<dyn proto::congestion::ControllerFactory + Send + Sync>>,
+}
+
+impl ConnectOptions {
+    /// Override nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `transport` instead of the default client configuration's [`TransportConfig`]
+    pub fn transport_config(&mut self, transport: Arc<TransportConfig>) -> &mut Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Control congestion with controllers from `factory`
+    ///
+    /// See [`ClientConfig::congestion_controller_factory()`].
+    pub fn congestion_controller_factory(
+        &mut self,
+        factory: Arc<dyn proto::congestion::ControllerFactory + Send + Sync>,
+    ) -> &mut Self {
+        self.congestion_controller_factory = Some(factory);
+        self
+    }
+}
+
+impl fmt::Debug for ConnectOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectOptions")
+            .field("transport", &self.transport)
+            .field(
+                "congestion_controller_factory",
+                &self.congestion_controller_factory.as_ref().map(|_| ".."),
+            )
+            .finish()
+    }
+}
+
+/// How far an endpoint's shutdown has got, as published by [`Endpoint::shutdown_progress()`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ShutdownProgress {
+    /// Whether [`Endpoint::close()`] has been called
+    pub started: bool,
+    /// Connections yet to be drained, including incoming ones never accepted
+    pub connections: u64,
+    /// JLS forward connections yet to end
+    pub forwards: u64,
+    /// Connections drained, and JLS forward connections ended, since the shutdown started
+    ///
+    /// Peers aren't required to answer a close, so a drained connection only means the endpoint
+    /// waited long enough for them to have done so.
+    pub closed_cleanly: u64,
+    /// JLS forward connections ended with datagrams for their upstream still queued, and
+    /// connections and forward connections left when the driver stopped
+    pub closed_forcibly: u64,
+    /// Whether this is the final summary, with nothing left to close
+    pub finished: bool,
+}
+
+/// Errors that may arise from [`Endpoint::connect_racing`]
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ConnectRacingError {
+    /// No addresses were supplied
+    #[error("no addresses to connect to")]
+    NoAddresses,
+    /// No attempt could be started, carrying the reason the last one was refused
+    #[error("connect: {0}")]
+    Connect(#[from] ConnectError),
+    /// Every attempt failed, carrying the error of the last one to fail
+    #[error("connection: {0}")]
+    Connection(#[from] ConnectionError),
+}
+
+/// Future produced by [`Endpoint::connect_racing`]
+struct ConnectRacing<'a> {
+    endpoint: &'a Endpoint,
+    config: ClientConfig,
+    server_name: &'a str,
+    /// Addresses yet to be tried
+    addrs: VecDeque<SocketAddr>,
+    /// Attempts in progress, dropped (and so closed) once the race is decided
+    attempts: Vec<Connecting>,
+    /// Fires when the next address should be tried
+    timer: Option<Pin<Box<dyn AsyncTimer>>>,
+    error: Option<ConnectRacingError>,
+}
+
+impl ConnectRacing<'_> {
+    /// Start an attempt with the next address that can be connected to, if any
+    fn start_next(&mut self) {
+        while let Some(addr) = self.addrs.pop_front() {
+            match self
+                .endpoint
+                .connect_with(self.config.clone(), addr, self.server_name)
+            {
+                Ok(connecting) => {
+                    trace!(%addr, "starting connection attempt");
+                    self.attempts.push(connecting);
+                    break;
+                }
+                Err(e) => {
+                    debug!(%addr, "connection attempt refused: {}", e);
+                    self.error = Some(e.into());
+                }
+            }
+        }
+
+        let deadline = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+        match self.timer {
+            Some(ref mut timer) => timer.as_mut().reset(deadline),
+            None => self.timer = Some(self.endpoint.runtime.new_timer(deadline)),
+        }
+    }
+}
+
+impl Future for ConnectRacing<'_> {
+    type Output = Result<Connection, ConnectRacingError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if this.timer.is_none() {
+            if this.addrs.is_empty() {
+                return Poll::Ready(Err(ConnectRacingError::NoAddresses));
+            }
+            this.start_next();
+        }
+
+        loop {
+            let mut failed = false;
+            let mut i = 0;
+            while i < this.attempts.len() {
+                match Pin::new(&mut this.attempts[i]).poll(cx) {
+                    Poll::Ready(Ok(conn)) => {
+                        // Dropping the losers closes them
+                        this.attempts.clear();
+                        return Poll::Ready(Ok(conn));
+                    }
+                    Poll::Ready(Err(e)) => {
+                        this.attempts.swap_remove(i);
+                        this.error = Some(e.into());
+                        failed = true;
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+
+            if this.addrs.is_empty() {
+                if this.attempts.is_empty() {
+                    return Poll::Ready(Err(this.error.take().unwrap()));
+                }
+                return Poll::Pending;
+            }
+
+            // Move on as soon as an attempt fails, rather than waiting out the delay
+            if failed || this.timer.as_mut().unwrap().as_mut().poll(cx).is_ready() {
+                this.start_next();
+                continue;
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct EndpointRef(Arc<EndpointInner>);
 
@@ -893,34 +4658,84 @@ impl EndpointRef {
                 * udp_state.gro_segments()
                 * BATCH_SIZE
         ];
-        let (sender, events) = mpsc::unbounded_channel();
+        let jls_forwarding = inner.config().get_jls_forwarding();
+        let max_queued_datagrams = inner.config().get_max_queued_datagrams();
+        let (lifecycle, events) = mpsc::unbounded_channel();
+        let (transmits_send, transmits) = mpsc::unbounded_channel();
         Self(Arc::new(EndpointInner {
             shared: Shared {
                 incoming: Notify::new(),
                 idle: Notify::new(),
+                flushed: Notify::new(),
             },
             state: Mutex::new(State {
                 socket,
+                retired_sockets: Vec::new(),
                 udp_state,
                 inner,
                 ipv6,
+                last_recv: Instant::now(),
                 events,
-                outgoing: VecDeque::new(),
+                transmits,
+                outgoing: OutgoingQueue::default(),
+                pending: PendingSet::default(),
                 incoming: VecDeque::new(),
                 driver: None,
+                writable_waiters: Vec::new(),
+                writable_epoch: 0,
+                writable_error: None,
                 connections: ConnectionSet {
                     senders: FxHashMap::default(),
-                    sender,
+                    sender: EndpointEventSender {
+                        lifecycle,
+                        transmits: transmits_send,
+                    },
                     close: None,
+                    activity: FxHashMap::default(),
+                    records: FxHashMap::default(),
+                    max_queued_datagrams,
                 },
                 ref_count: 0,
                 driver_lost: false,
                 recv_buf: recv_buf.into(),
                 recv_limiter: WorkLimiter::new(RECV_TIME_BOUND),
                 send_limiter: WorkLimiter::new(SEND_TIME_BOUND),
+                forward_limiter: WorkLimiter::new(RECV_TIME_BOUND),
+                send_first: false,
                 runtime,
                 transmit_queue_contents_len: 0,
-                jls_state: JlsState::default(),
+                jls_state: JlsState::new(),
+                reaper_generation: 0,
+                shutdown_timer: None,
+                send_backoff: None,
+                send_failures: FxHashMap::default(),
+                dropped_sends: 0,
+                incoming_retry_threshold: usize::MAX,
+                max_incoming: usize::MAX,
+                local_drops: LocalDrops::default(),
+                local_drops_sampled: None,
+                ack_only_watermark: None,
+                deferred_acks: FxHashMap::default(),
+                paced: PacedQueue::default(),
+                jls_forwarding,
+                deferred_acks_total: 0,
+                coalesced_acks_total: 0,
+                response_shaper: ResponseShaper::default(),
+                shaped_responses_total: 0,
+                timing: DriverTiming::default(),
+                poll_phases: DriverPhases::default(),
+                last_poll_end: None,
+                slow_poll_threshold: Some(DEFAULT_SLOW_POLL_THRESHOLD),
+                events_drained: false,
+                flush_requested: 0,
+                flush_completed: 0,
+                attached: Vec::new(),
+                next_socket_id: 0,
+                reply_socket: ReplySocket::Primary,
+                peer_sockets: FxHashMap::default(),
+                ip_stats: IpStatsTable::default(),
+                shutdown: ShutdownProgress::default(),
+                shutdown_progress: watch::channel(ShutdownProgress::default()).0,
             }),
         }))
     }
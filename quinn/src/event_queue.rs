@@ -0,0 +1,156 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use crate::{ConnectionEvent, VarInt};
+
+/// Create the queue carrying events from an endpoint to one of its connections
+///
+/// Received datagrams travel over a channel bounded to `max_datagrams`, the endpoint's
+/// [`max_queued_datagrams`](proto::EndpointConfig::max_queued_datagrams); while it's full, further
+/// datagrams are dropped until the connection's driver catches up, just as a full socket buffer
+/// would. Other events go over a separate channel and are never dropped, but closes and rebinds the
+/// driver hasn't seen yet are coalesced, so neither channel grows however far the driver falls
+/// behind.
+pub(crate) fn event_queue(max_datagrams: usize) -> (EventSender, EventReceiver) {
+    let (datagrams, datagrams_recv) = mpsc::channel(max_datagrams);
+    let (control, control_recv) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared::default());
+    (
+        EventSender {
+            datagrams,
+            control,
+            shared: shared.clone(),
+        },
+        EventReceiver {
+            datagrams: datagrams_recv,
+            control: control_recv,
+            shared,
+        },
+    )
+}
+
+/// Statistics about the events queued by an endpoint for one of its connections
+#[derive(Debug, Default, Copy, Clone)]
+#[non_exhaustive]
+pub struct EventQueueStats {
+    /// Events waiting to be processed by the connection
+    pub depth: usize,
+    /// Received datagrams dropped because too many were already queued
+    pub dropped_datagrams: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct EventSender {
+    datagrams: mpsc::Sender<proto::ConnectionEvent>,
+    control: mpsc::UnboundedSender<ConnectionEvent>,
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    /// Queue a datagram received for the connection, unless too many already are
+    pub(crate) fn datagram(&self, event: proto::ConnectionEvent) {
+        match self.datagrams.try_send(event) {
+            Ok(()) => {
+                self.shared.depth.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // Ignoring errors from dropped connections that haven't yet been cleaned up
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// Queue an event generated by the endpoint
+    pub(crate) fn proto(&self, event: proto::ConnectionEvent) {
+        self.push(ConnectionEvent::Proto(event));
+    }
+
+    pub(crate) fn close(&self, error_code: VarInt, reason: Bytes) {
+        // Only the first close has any effect
+        if !self.shared.close_pending.swap(true, Ordering::Relaxed) {
+            self.push(ConnectionEvent::Close { error_code, reason });
+        }
+    }
+
+    pub(crate) fn rebind(&self) {
+        if !self.shared.rebind_pending.swap(true, Ordering::Relaxed) {
+            self.push(ConnectionEvent::Rebind);
+        }
+    }
+
+    /// Events waiting to be processed by the connection
+    pub(crate) fn depth(&self) -> usize {
+        self.shared.depth.load(Ordering::Relaxed)
+    }
+
+    fn push(&self, event: ConnectionEvent) {
+        self.shared.depth.fetch_add(1, Ordering::Relaxed);
+        // Ignoring errors from dropped connections that haven't yet been cleaned up
+        let _ = self.control.send(event);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EventReceiver {
+    datagrams: mpsc::Receiver<proto::ConnectionEvent>,
+    control: mpsc::UnboundedReceiver<ConnectionEvent>,
+    shared: Arc<Shared>,
+}
+
+impl EventReceiver {
+    /// Take the next event, giving those generated by the endpoint precedence over datagrams
+    ///
+    /// A close or rebind may thus overtake datagrams received before it, which is no different
+    /// from them arriving a little later.
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<ConnectionEvent>> {
+        let control = self.control.poll_recv(cx);
+        if let Poll::Ready(Some(event)) = control {
+            self.shared.depth.fetch_sub(1, Ordering::Relaxed);
+            match event {
+                ConnectionEvent::Close { .. } => {
+                    self.shared.close_pending.store(false, Ordering::Relaxed)
+                }
+                ConnectionEvent::Rebind => {
+                    self.shared.rebind_pending.store(false, Ordering::Relaxed)
+                }
+                ConnectionEvent::Proto(_) => {}
+            }
+            return Poll::Ready(Some(event));
+        }
+        match self.datagrams.poll_recv(cx) {
+            Poll::Ready(Some(event)) => {
+                self.shared.depth.fetch_sub(1, Ordering::Relaxed);
+                Poll::Ready(Some(ConnectionEvent::Proto(event)))
+            }
+            // Both channels close together when the endpoint drops its sender
+            Poll::Ready(None) if control.is_ready() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+
+    pub(crate) fn stats(&self) -> EventQueueStats {
+        EventQueueStats {
+            depth: self.shared.depth.load(Ordering::Relaxed),
+            dropped_datagrams: self.shared.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    /// Events of any kind in the queue
+    depth: AtomicUsize,
+    /// Datagrams dropped because the queue held too many
+    dropped: AtomicU64,
+    close_pending: AtomicBool,
+    rebind_pending: AtomicBool,
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Traffic with one remote IP address, over all of its connections
+///
+/// Returned by [`Endpoint::per_ip_stats()`](crate::Endpoint::per_ip_stats).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct IpStats {
+    /// Bytes in UDP datagrams sent to the address, including the endpoint's own responses and
+    /// traffic relayed to JLS-forwarded clients
+    pub bytes_sent: u64,
+    /// Bytes in UDP datagrams received from the address, whether or not they were accepted
+    pub bytes_received: u64,
+    /// Connections opened with the address, by either side
+    pub connections_opened: u64,
+    /// Connections with the address that haven't been drained yet
+    pub connections_open: u64,
+}
+
+/// Limits past which an address is reported to the callback installed with
+/// [`Endpoint::set_ip_threshold()`](crate::Endpoint::set_ip_threshold)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IpThreshold {
+    /// Bytes sent and received together
+    pub bytes: u64,
+    /// Connections open at once
+    pub connections: u64,
+}
+
+impl Default for IpThreshold {
+    /// Reports nothing
+    fn default() -> Self {
+        Self {
+            bytes: u64::MAX,
+            connections: u64::MAX,
+        }
+    }
+}
+
+pub(crate) type IpThresholdCallback = dyn FnMut(IpAddr, &IpStats) + Send;
+
+/// Keeps [`IpStats`] for each remote address the endpoint deals with
+///
+/// Addresses with no open connections are let go once they've been idle for a while, and only so
+/// many are tracked at once, so a flood from many addresses can't grow the map without bound.
+#[derive(Default)]
+pub(crate) struct IpStatsTable {
+    entries: HashMap<IpAddr, Entry>,
+    /// When idle entries were last let go, if ever
+    pruned: Option<Instant>,
+    threshold: IpThreshold,
+    callback: Option<Box<IpThresholdCallback>>,
+}
+
+impl IpStatsTable {
+    pub(crate) fn sent(&mut self, ip: IpAddr, len: usize, now: Instant) {
+        self.record(ip, now, true, |x| x.bytes_sent += len as u64);
+    }
+
+    pub(crate) fn received(&mut self, ip: IpAddr, len: usize, now: Instant) {
+        self.record(ip, now, true, |x| x.bytes_received += len as u64);
+    }
+
+    pub(crate) fn opened(&mut self, ip: IpAddr, now: Instant) {
+        self.record(ip, now, true, |x| {
+            x.connections_opened += 1;
+            x.connections_open += 1;
+        });
+    }
+
+    pub(crate) fn drained(&mut self, ip: IpAddr, now: Instant) {
+        // An address untracked when the connection opened stays so
+        self.record(ip, now, false, |x| {
+            x.connections_open = x.connections_open.saturating_sub(1)
+        });
+    }
+
+    pub(crate) fn snapshot(&self) -> HashMap<IpAddr, IpStats> {
+        self.entries.iter().map(|(&ip, x)| (ip, x.stats)).collect()
+    }
+
+    /// Report addresses past `threshold` to `callback`, replacing any earlier one
+    pub(crate) fn set_threshold(
+        &mut self,
+        threshold: IpThreshold,
+        callback: Box<IpThresholdCallback>,
+    ) {
+        self.threshold = threshold;
+        self.callback = Some(callback);
+        // Addresses already past the new limits are reported when next counted
+        for entry in self.entries.values_mut() {
+            entry.bytes_reported = false;
+            entry.connections_reported = false;
+        }
+    }
+
+    fn record(&mut self, ip: IpAddr, now: Instant, create: bool, f: impl FnOnce(&mut IpStats)) {
+        let ip = match ip {
+            IpAddr::V6(x) => x.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        if !self.entries.contains_key(&ip) {
+            if !create {
+                return;
+            }
+            self.prune(now);
+            if self.entries.len() >= MAX_ADDRESSES {
+                return;
+            }
+        }
+        let entry = self.entries.entry(ip).or_insert(Entry {
+            stats: IpStats::default(),
+            active: now,
+            bytes_reported: false,
+            connections_reported: false,
+        });
+        f(&mut entry.stats);
+        entry.active = now;
+
+        let bytes = entry.stats.bytes_sent + entry.stats.bytes_received;
+        let crossed_bytes = !entry.bytes_reported && bytes >= self.threshold.bytes;
+        entry.bytes_reported |= crossed_bytes;
+        // Reported again each time the address climbs back to the limit
+        let over_connections = entry.stats.connections_open >= self.threshold.connections;
+        let crossed_connections = !entry.connections_reported && over_connections;
+        entry.connections_reported = over_connections;
+        if crossed_bytes || crossed_connections {
+            if let Some(ref mut callback) = self.callback {
+                callback(ip, &entry.stats);
+            }
+        }
+    }
+
+    /// Let go of idle entries without open connections, at most once per idle period
+    fn prune(&mut self, now: Instant) {
+        if self
+            .pruned
+            .map_or(false, |x| now.saturating_duration_since(x) < IDLE_PERIOD)
+        {
+            return;
+        }
+        self.pruned = Some(now);
+        self.entries.retain(|_, x| {
+            x.stats.connections_open > 0 || now.saturating_duration_since(x.active) < IDLE_PERIOD
+        });
+    }
+}
+
+impl fmt::Debug for IpStatsTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IpStatsTable")
+            .field("entries", &self.entries.len())
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    stats: IpStats,
+    /// When anything was last counted for the address
+    active: Instant,
+    /// Whether the address was reported for passing the byte limit
+    bytes_reported: bool,
+    /// Whether the address was reported for reaching the connection limit, and hasn't dropped
+    /// below it since
+    connections_reported: bool,
+}
+
+/// How long an address without open connections is kept after anything was last counted for it
+const IDLE_PERIOD: Duration = Duration::from_secs(300);
+
+/// Addresses tracked at once
+///
+/// Traffic with further addresses goes uncounted until idle ones can be let go.
+const MAX_ADDRESSES: usize = 64 * 1024;
@@ -0,0 +1,1773 @@
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::RandomState, BinaryHeap, HashMap, HashSet, VecDeque},
+    future::Future,
+    hash::{BuildHasher, Hash, Hasher},
+    io::{self, IoSliceMut},
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use proto::{JlsClientRedaction, JlsSocks5Proxy, JlsUpstreamTransport};
+use tokio::sync::mpsc;
+use tracing::{debug, trace, Span};
+use udp::{EcnCodepoint, RecvMeta, Transmit, UdpState, BATCH_SIZE};
+
+#[cfg(feature = "jls-forward-socks5")]
+use crate::jls_socks;
+#[cfg(feature = "jls-forward-tcp")]
+use crate::runtime::AsyncTcpStream;
+use crate::{
+    runtime::{AsyncTimer, AsyncUdpSocket, Runtime},
+    work_limiter::WorkLimiter,
+    IO_LOOP_BOUND, RECV_TIME_BOUND, SEND_TIME_BOUND,
+};
+
+/// Instructions from an endpoint driver to its [`ForwardPool`]
+#[derive(Debug)]
+pub(crate) enum ForwardCommand {
+    /// Start relaying for the client at `remote`, beginning with the datagram that carried its
+    /// ClientHello
+    ///
+    /// Replaces any relay the client already had.
+    Open {
+        remote: SocketAddr,
+        id: u64,
+        upstream_addr: SocketAddr,
+        hello: BytesMut,
+        transport: JlsUpstreamTransport,
+        /// The proxy to reach the upstream through, if any
+        socks5: Option<JlsSocks5Proxy>,
+        socket_per_client: bool,
+        /// Where to bind the socket reaching the upstream, instead of a wildcard address
+        bind: Option<SocketAddr>,
+        /// How long the upstream gets to answer before the relay is ended, if it's to be ended
+        /// for that
+        response_timeout: Option<Duration>,
+        /// The forward connection's span, which the relay's events are logged under
+        span: Span,
+    },
+    /// Relay datagrams from the client at `remote` to its upstream
+    Datagram {
+        remote: SocketAddr,
+        data: BytesMut,
+        /// Set if `data` holds several datagrams of this size, the last possibly shorter
+        segment_size: Option<usize>,
+        /// The ECN codepoint the datagrams arrived with, for them to leave with
+        ecn: Option<EcnCodepoint>,
+    },
+    /// Stop relaying for the client at `remote`, if it's still the relay numbered `id`
+    Close { remote: SocketAddr, id: u64 },
+    /// The client at `from` moved to `to`, if it's still the relay numbered `id`
+    ///
+    /// Replaces any relay already at `to`.
+    Migrate {
+        from: SocketAddr,
+        to: SocketAddr,
+        id: u64,
+    },
+    /// End every relay once the datagrams queued for its upstream are sent, or at the given time
+    /// if they can't be sent by then
+    Drain(Instant),
+}
+
+/// Reports from a [`ForwardPool`] to its endpoint driver
+///
+/// Each concerning a particular relay names it by its `id`, as given in [`ForwardCommand::Open`],
+/// so that late reports about a client's earlier relay can be told apart. The driver knows where
+/// the client is, even if it [moved](ForwardCommand::Migrate) since.
+#[derive(Debug)]
+pub(crate) enum ForwardEvent {
+    /// Datagrams from the upstream, to be sent to the relay's client
+    Relay {
+        id: u64,
+        data: BytesMut,
+        /// Set if `data` holds several datagrams of this size, the last possibly shorter
+        segment_size: Option<usize>,
+        /// The ECN codepoint the datagrams arrived with, for them to leave with
+        ecn: Option<EcnCodepoint>,
+    },
+    /// `datagrams` from the relay's client, `bytes` in all, were sent to the upstream
+    Sent {
+        id: u64,
+        datagrams: usize,
+        bytes: usize,
+    },
+    /// A datagram from `upstream` arrived on a shared socket, but couldn't be matched to a client
+    Unroutable { upstream: SocketAddr },
+    /// A lookup of the configured upstream host finished
+    Resolved {
+        host: String,
+        port: u16,
+        result: io::Result<Vec<SocketAddr>>,
+    },
+    /// The pool stopped relaying for the relay's client of its own accord
+    Ended { id: u64, reason: ForwardEnd },
+}
+
+/// Why a [`ForwardPool`] stopped relaying for a client
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ForwardEnd {
+    /// No socket could be bound to reach the upstream
+    SetupFailed,
+    /// Nothing was relayed in either direction for the idle timeout
+    Idle,
+    /// The socket reaching the upstream failed
+    Error,
+    /// The upstream didn't answer within the response timeout given in [`ForwardCommand::Open`]
+    Unanswered,
+    /// The endpoint was closed, and the relay [drained](ForwardCommand::Drain)
+    Closed,
+    /// The endpoint was closed, and the relay's queue couldn't be sent before the
+    /// [drain](ForwardCommand::Drain) deadline
+    DrainTimedOut,
+}
+
+/// Relays traffic between JLS-forwarded clients and their upstreams, on a task of its own
+///
+/// Upstream I/O thus never runs under the endpoint's lock, nor holds up its driver. The driver
+/// only hands over each forwarded client's first datagram and passes on the rest of its datagrams;
+/// the upstreams' answers go back to the driver, to be sent from the endpoint's socket.
+///
+/// Exits once the driver drops its [`ForwardCommand`] sender, which it does when no client is
+/// forwarded any longer, taking the sockets and buffers with it.
+#[derive(Debug)]
+pub(crate) struct ForwardPool {
+    runtime: Arc<dyn Runtime>,
+    commands: mpsc::UnboundedReceiver<ForwardCommand>,
+    events: mpsc::UnboundedSender<ForwardEvent>,
+    relays: HashMap<SocketAddr, ForwardRelay>,
+    /// The client each relay is for, by the relay's ID
+    remotes: HashMap<u64, SocketAddr>,
+    /// Sockets shared by relays without one of their own, by the address they're bound to
+    ///
+    /// Usually just the one, unless upstreams of both address families are reached from wildcard
+    /// addresses.
+    shared_sockets: HashMap<SocketAddr, SharedSocket>,
+    /// Which client each datagram arriving on `shared_sockets` is for
+    routes: UpstreamRoutes,
+    /// Receive buffer for a batch of datagrams, allocated once there's a socket to receive on
+    ///
+    /// Sockets are polled one at a time, so this one buffer serves all of them, however many
+    /// clients are forwarded.
+    shared_buf: Box<[u8]>,
+    /// Bytes held by `shared_buf`, along with those of any other pool of the same endpoint that's
+    /// still winding down
+    buffer_bytes: Arc<AtomicUsize>,
+    /// Room for one datagram, or one GRO batch
+    slot: usize,
+    idle_timeout: Duration,
+    /// When relays still waiting to send their queues are ended, once the pool is
+    /// [draining](ForwardCommand::Drain)
+    drain_deadline: Option<Instant>,
+    /// When each relay may next be due to end, by its ID, earliest first
+    ///
+    /// Entries aren't updated as relays see activity, nor removed as they end. Each is checked
+    /// once it comes due instead, and replaced by one for the relay's actual deadline if that's
+    /// yet to come.
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// Fires when the earliest of `deadlines` comes due
+    expiry_timer: Option<Pin<Box<dyn AsyncTimer>>>,
+    /// What the pool was woken for
+    ready: Arc<Mutex<Ready>>,
+    /// IDs of the relays served in the current turn, kept to reuse the allocation
+    serving: VecDeque<u64>,
+    /// Budget for receiving from upstreams in each turn of the pool
+    recv_limiter: WorkLimiter,
+    /// Budget for sending to upstreams in each turn of the pool
+    send_limiter: WorkLimiter,
+}
+
+impl ForwardPool {
+    /// Create a pool, along with the sender through which the driver commands it
+    pub(crate) fn new(
+        runtime: Arc<dyn Runtime>,
+        events: mpsc::UnboundedSender<ForwardEvent>,
+        buffer_bytes: Arc<AtomicUsize>,
+        slot: usize,
+        idle_timeout: Duration,
+    ) -> (Self, mpsc::UnboundedSender<ForwardCommand>) {
+        let (send, commands) = mpsc::unbounded_channel();
+        let pool = Self {
+            runtime,
+            commands,
+            events,
+            relays: HashMap::new(),
+            remotes: HashMap::new(),
+            shared_sockets: HashMap::new(),
+            routes: UpstreamRoutes::default(),
+            shared_buf: Box::default(),
+            buffer_bytes,
+            slot,
+            idle_timeout,
+            drain_deadline: None,
+            deadlines: BinaryHeap::new(),
+            expiry_timer: None,
+            ready: Arc::default(),
+            serving: VecDeque::new(),
+            recv_limiter: WorkLimiter::new(RECV_TIME_BOUND),
+            send_limiter: WorkLimiter::new(SEND_TIME_BOUND),
+        };
+        (pool, send)
+    }
+
+    /// Carry out the driver's commands, returning whether any are left, or `None` once the driver
+    /// has let go of the pool
+    fn handle_commands(&mut self, cx: &mut Context, now: Instant) -> Option<bool> {
+        for _ in 0..IO_LOOP_BOUND {
+            match self.commands.poll_recv(cx) {
+                Poll::Ready(Some(ForwardCommand::Open {
+                    remote,
+                    id,
+                    upstream_addr,
+                    hello,
+                    transport,
+                    socks5,
+                    socket_per_client,
+                    bind,
+                    response_timeout,
+                    span,
+                })) => self.open(
+                    remote,
+                    id,
+                    upstream_addr,
+                    hello,
+                    transport,
+                    socks5,
+                    socket_per_client,
+                    bind,
+                    response_timeout,
+                    span,
+                    now,
+                ),
+                Poll::Ready(Some(ForwardCommand::Datagram {
+                    remote,
+                    data,
+                    segment_size,
+                    ecn,
+                })) => self.queue(remote, data, segment_size, ecn),
+                Poll::Ready(Some(ForwardCommand::Close { remote, id })) => {
+                    if self.relays.get(&remote).map_or(false, |x| x.id == id) {
+                        self.remove(&remote);
+                    }
+                }
+                Poll::Ready(Some(ForwardCommand::Migrate { from, to, id })) => {
+                    self.migrate(from, to, id);
+                }
+                Poll::Ready(Some(ForwardCommand::Drain(deadline))) => self.drain(deadline),
+                Poll::Ready(None) => return None,
+                Poll::Pending => return Some(false),
+            }
+        }
+        Some(true)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        &mut self,
+        remote: SocketAddr,
+        id: u64,
+        upstream_addr: SocketAddr,
+        hello: BytesMut,
+        transport: JlsUpstreamTransport,
+        socks5: Option<JlsSocks5Proxy>,
+        socket_per_client: bool,
+        bind: Option<SocketAddr>,
+        response_timeout: Option<Duration>,
+        span: Span,
+        now: Instant,
+    ) {
+        // A client starting over replaces its relay, so its routes go first
+        self.remove(&remote);
+        let socks5 = match transport {
+            JlsUpstreamTransport::Udp => socks5,
+            JlsUpstreamTransport::Tcp => None,
+        };
+        // Through a proxy, the sockets reach the proxy rather than the upstream
+        let reached = socks5.as_ref().map_or(upstream_addr, |x| x.addr());
+        let bind = match upstream_bind(bind, reached) {
+            Ok(x) => x,
+            Err(e) => return self.setup_failed(id, &span, e),
+        };
+        let link = match (transport, socks5, socket_per_client) {
+            #[cfg(feature = "jls-forward-socks5")]
+            (JlsUpstreamTransport::Udp, Some(proxy), _) => {
+                match UpstreamSocket::bind(&*self.runtime, bind) {
+                    Ok(socket) => UpstreamLink::Socks(SocksLink {
+                        socket,
+                        association: SocksState::Associating(jls_socks::associate(
+                            &*self.runtime,
+                            bind,
+                            proxy,
+                        )),
+                    }),
+                    Err(e) => return self.setup_failed(id, &span, e),
+                }
+            }
+            #[cfg(not(feature = "jls-forward-socks5"))]
+            (JlsUpstreamTransport::Udp, Some(_), _) => {
+                let e = io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "SOCKS5 proxies need the jls-forward-socks5 feature",
+                );
+                return self.setup_failed(id, &span, e);
+            }
+            (JlsUpstreamTransport::Udp, None, false) => {
+                if !self.shared_sockets.contains_key(&bind) {
+                    match UpstreamSocket::bind(&*self.runtime, bind) {
+                        Ok(socket) => {
+                            let key = ReadyKey::Shared(bind);
+                            self.shared_sockets.insert(
+                                bind,
+                                SharedSocket {
+                                    socket,
+                                    waker: ReadyWaker::new(&self.ready, key),
+                                    blocked: false,
+                                    waiting: HashSet::new(),
+                                },
+                            );
+                            // Polled once, to be woken when it has datagrams to receive
+                            self.mark_ready(key);
+                        }
+                        Err(e) => return self.setup_failed(id, &span, e),
+                    }
+                }
+                UpstreamLink::Shared
+            }
+            (JlsUpstreamTransport::Udp, None, true) => {
+                match UpstreamSocket::bind(&*self.runtime, bind) {
+                    Ok(x) => UpstreamLink::Socket(x),
+                    Err(e) => return self.setup_failed(id, &span, e),
+                }
+            }
+            #[cfg(feature = "jls-forward-tcp")]
+            (JlsUpstreamTransport::Tcp, _, _) => UpstreamLink::Tcp(TcpTunnel {
+                stream: TcpState::Connecting(self.runtime.connect_tcp(bind, upstream_addr)),
+                out: BytesMut::new(),
+                out_datagrams: 0,
+                out_bytes: 0,
+                partial: BytesMut::new(),
+            }),
+            #[cfg(not(feature = "jls-forward-tcp"))]
+            (JlsUpstreamTransport::Tcp, _, _) => {
+                let e = io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "TCP upstreams need the jls-forward-tcp feature",
+                );
+                return self.setup_failed(id, &span, e);
+            }
+        };
+        let relay = ForwardRelay {
+            id,
+            link,
+            bind,
+            upstream_addr,
+            to_upstream: VecDeque::new(),
+            active_time: now,
+            answer_deadline: response_timeout.and_then(|x| now.checked_add(x)),
+            client_cids: Vec::new(),
+            waker: ReadyWaker::new(&self.ready, ReadyKey::Relay(id)),
+            span,
+        };
+        if let Some(deadline) = self.deadline(&relay) {
+            self.deadlines.push(Reverse((deadline, id)));
+        }
+        self.relays.insert(remote, relay);
+        self.remotes.insert(id, remote);
+        self.routes.add_client(upstream_addr, remote);
+        self.queue(remote, hello, None, None);
+    }
+
+    fn setup_failed(&mut self, id: u64, span: &Span, e: io::Error) {
+        debug!(parent: span, "forward setup failed: {}", e);
+        let _ = self.events.send(ForwardEvent::Ended {
+            id,
+            reason: ForwardEnd::SetupFailed,
+        });
+    }
+
+    /// Queue datagrams from the client at `remote` for its upstream
+    fn queue(
+        &mut self,
+        remote: SocketAddr,
+        data: BytesMut,
+        segment_size: Option<usize>,
+        ecn: Option<EcnCodepoint>,
+    ) {
+        // The relay may have ended before the driver heard of it
+        let relay = match self.relays.get_mut(&remote) {
+            Some(x) => x,
+            None => return,
+        };
+        // The upstream addresses the client by the connection IDs it chooses
+        for datagram in data.chunks(segment_size.unwrap_or(data.len()).max(1)) {
+            if let Some((_, scid)) = long_header_cids(datagram) {
+                if !scid.is_empty() && !relay.client_cids.contains(&scid) {
+                    relay.client_cids.push(scid);
+                    self.routes.add_cid(relay.upstream_addr, remote, scid);
+                }
+            }
+        }
+        let max_segments = match relay.link {
+            UpstreamLink::Shared => self.shared_sockets.get(&relay.bind).map(|x| &x.socket),
+            UpstreamLink::Socket(ref x) => Some(x),
+            #[cfg(feature = "jls-forward-tcp")]
+            UpstreamLink::Tcp(_) => None,
+            // Each datagram gets a header of its own
+            #[cfg(feature = "jls-forward-socks5")]
+            UpstreamLink::Socks(_) => None,
+        }
+        .map_or(1, |x| x.udp_state.max_gso_segments());
+        queue_segments(
+            &mut relay.to_upstream,
+            relay.upstream_addr,
+            data,
+            segment_size,
+            ecn,
+            None,
+            max_segments,
+        );
+        let key = ReadyKey::Relay(relay.id);
+        self.mark_ready(key);
+    }
+
+    /// Relay for the client at `from` as the one at `to` from now on
+    fn migrate(&mut self, from: SocketAddr, to: SocketAddr, id: u64) {
+        if self.relays.get(&from).map_or(true, |x| x.id != id) {
+            return;
+        }
+        let relay = self.remove(&from).unwrap();
+        self.remove(&to);
+        self.routes.add_client(relay.upstream_addr, to);
+        for cid in &relay.client_cids {
+            self.routes.add_cid(relay.upstream_addr, to, *cid);
+        }
+        self.remotes.insert(id, to);
+        self.relays.insert(to, relay);
+    }
+
+    fn remove(&mut self, remote: &SocketAddr) -> Option<ForwardRelay> {
+        let relay = self.relays.remove(remote)?;
+        self.remotes.remove(&relay.id);
+        self.routes.remove(*remote, &relay);
+        Some(relay)
+    }
+
+    /// Stop relaying for the client at `remote`, and tell the driver why
+    fn end(&mut self, remote: &SocketAddr, reason: ForwardEnd) {
+        if let Some(relay) = self.remove(remote) {
+            trace!(parent: &relay.span, ?reason, "ending relay");
+            let _ = self.events.send(ForwardEvent::Ended {
+                id: relay.id,
+                reason,
+            });
+        }
+    }
+
+    /// Start draining: relays end once they've sent everything queued, or at `deadline`
+    fn drain(&mut self, deadline: Instant) {
+        self.drain_deadline = Some(deadline);
+        // Relays are otherwise only checked as they send, or come due
+        let flushed = self
+            .relays
+            .iter()
+            .filter(|(_, relay)| relay.flushed())
+            .map(|(&remote, _)| remote)
+            .collect::<Vec<_>>();
+        for remote in flushed {
+            self.end(&remote, ForwardEnd::Closed);
+        }
+        for relay in self.relays.values() {
+            self.deadlines.push(Reverse((deadline, relay.id)));
+        }
+    }
+
+    /// Note that the pool has something to do for `key` in its next turn
+    fn mark_ready(&self, key: ReadyKey) {
+        self.ready.lock().unwrap().push(key);
+    }
+
+    /// Serve the relays and shared sockets the pool was woken for, or which have datagrams
+    /// queued, returning whether any of them may have more to do
+    ///
+    /// Those the turn's budget runs out before are served again in the next turn, so that no
+    /// relay waits on the others for long.
+    fn serve(&mut self, now: Instant) -> bool {
+        let shared = {
+            let mut ready = self.ready.lock().unwrap();
+            let shared = mem::take(&mut ready.shared);
+            // Relays that found a shared socket blocked may send on it again
+            for bind in &shared {
+                if let Some(socket) = self.shared_sockets.get_mut(bind) {
+                    socket.blocked = false;
+                    for id in socket.waiting.drain() {
+                        ready.push(ReadyKey::Relay(id));
+                    }
+                }
+            }
+            mem::swap(&mut ready.relays, &mut self.serving);
+            ready.queued.clear();
+            shared
+        };
+        let mut keep_going = false;
+        // Each direction gets a budget, so that many busy relays can't hold up the runtime
+        self.recv_limiter.start_cycle();
+        for bind in shared {
+            if self.recv_shared(bind, now) {
+                self.mark_ready(ReadyKey::Shared(bind));
+                keep_going = true;
+            }
+        }
+        for i in 0..self.serving.len() {
+            let id = self.serving[i];
+            if self.recv_relay(id, now) {
+                self.mark_ready(ReadyKey::Relay(id));
+                keep_going = true;
+            }
+        }
+        self.recv_limiter.finish_cycle();
+        self.send_limiter.start_cycle();
+        for i in 0..self.serving.len() {
+            let id = self.serving[i];
+            if self.send_relay(id, now) {
+                self.mark_ready(ReadyKey::Relay(id));
+                keep_going = true;
+            }
+        }
+        self.send_limiter.finish_cycle();
+        self.serving.clear();
+        keep_going
+    }
+
+    /// Relay datagrams from the upstream of relay `id` to the driver, for its client, returning
+    /// whether there may be more
+    fn recv_relay(&mut self, id: u64, now: Instant) -> bool {
+        let remote = match self.remotes.get(&id) {
+            Some(&x) => x,
+            None => return false,
+        };
+        let waker = self.relays[&remote].waker.clone();
+        let cx = &mut Context::from_waker(&waker);
+        #[allow(unused_mut)]
+        let mut keep_going = self.recv_socket(cx, remote, now);
+        #[cfg(feature = "jls-forward-tcp")]
+        {
+            self.connect_tunnel(cx, remote);
+            keep_going |= self.recv_tunnel(cx, remote, now);
+        }
+        #[cfg(feature = "jls-forward-socks5")]
+        {
+            self.associate_socks(cx, remote);
+            keep_going |= self.recv_socks(cx, remote, now);
+        }
+        keep_going
+    }
+
+    /// Relay the queued datagrams of relay `id` to its upstream, returning whether the turn's
+    /// budget ran out before the queue was sent
+    ///
+    /// Once the pool is draining, the relay ends as soon as its queue is sent.
+    fn send_relay(&mut self, id: u64, now: Instant) -> bool {
+        let remote = match self.remotes.get(&id) {
+            Some(&x) => x,
+            None => return false,
+        };
+        #[allow(unused_mut)]
+        let mut keep_going = self.send_udp(remote, now);
+        #[cfg(any(feature = "jls-forward-tcp", feature = "jls-forward-socks5"))]
+        {
+            let waker = match self.relays.get(&remote) {
+                Some(x) => x.waker.clone(),
+                None => return false,
+            };
+            let cx = &mut Context::from_waker(&waker);
+            #[cfg(feature = "jls-forward-tcp")]
+            {
+                keep_going |= self.send_tunnel(cx, remote, now);
+            }
+            #[cfg(feature = "jls-forward-socks5")]
+            {
+                keep_going |= self.send_socks(cx, remote, now);
+            }
+        }
+        let flushed = self.relays.get(&remote).map_or(false, |x| x.flushed());
+        if self.drain_deadline.is_some() && flushed {
+            self.end(&remote, ForwardEnd::Closed);
+            return false;
+        }
+        keep_going
+    }
+
+    /// Relay datagrams to the driver from the upstream of the client at `remote`, if its relay
+    /// has a socket of its own, returning whether the socket may have more
+    ///
+    /// The relay is ended if its socket fails.
+    fn recv_socket(&mut self, cx: &mut Context, remote: SocketAddr, now: Instant) -> bool {
+        match self.relays.get(&remote) {
+            Some(ForwardRelay {
+                link: UpstreamLink::Socket(_),
+                ..
+            }) => {}
+            _ => return false,
+        }
+        self.alloc_shared_buf();
+        let mut metas = [RecvMeta::default(); BATCH_SIZE];
+        let mut chunks = self.shared_buf.chunks_mut(self.slot);
+        let mut iovs: [IoSliceMut; BATCH_SIZE] =
+            std::array::from_fn(|_| IoSliceMut::new(chunks.next().unwrap_or(&mut [])));
+        let relay = self.relays.get_mut(&remote).unwrap();
+        let socket = match relay.link {
+            UpstreamLink::Socket(ref x) => &x.socket,
+            _ => unreachable!(),
+        };
+        let mut failed = false;
+        let mut exhausted = true;
+        for _ in 0..IO_LOOP_BOUND {
+            if !self.recv_limiter.allow_work() {
+                break;
+            }
+            match socket.poll_recv(cx, &mut iovs, &mut metas) {
+                Poll::Ready(Ok(msgs)) => {
+                    self.recv_limiter.record_work(msgs);
+                    for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
+                        let data = buf[0..meta.len].into();
+                        relay_datagrams(&self.events, relay.id, data, meta.stride, meta.ecn);
+                    }
+                    relay.active_time = now;
+                    relay.answer_deadline = None;
+                }
+                Poll::Pending => {
+                    exhausted = false;
+                    break;
+                }
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => {
+                    continue;
+                }
+                Poll::Ready(Err(e)) => {
+                    debug!(parent: &relay.span, "receiving from upstream failed: {}", e);
+                    failed = true;
+                    exhausted = false;
+                    break;
+                }
+            }
+        }
+        if failed {
+            self.end(&remote, ForwardEnd::Error);
+        }
+        exhausted
+    }
+
+    /// Relay datagrams arriving on the shared socket bound to `bind` to the driver, for the
+    /// clients they're for, returning whether the socket may have more
+    fn recv_shared(&mut self, bind: SocketAddr, now: Instant) -> bool {
+        if !self.shared_sockets.contains_key(&bind) {
+            return false;
+        }
+        self.alloc_shared_buf();
+        let mut metas = [RecvMeta::default(); BATCH_SIZE];
+        let mut chunks = self.shared_buf.chunks_mut(self.slot);
+        let mut iovs: [IoSliceMut; BATCH_SIZE] =
+            std::array::from_fn(|_| IoSliceMut::new(chunks.next().unwrap_or(&mut [])));
+        let shared = &self.shared_sockets[&bind];
+        let cx = &mut Context::from_waker(&shared.waker);
+        let mut failed = false;
+        let mut exhausted = true;
+        for _ in 0..IO_LOOP_BOUND {
+            if !self.recv_limiter.allow_work() {
+                break;
+            }
+            match shared.socket.socket.poll_recv(cx, &mut iovs, &mut metas) {
+                Poll::Ready(Ok(msgs)) => {
+                    self.recv_limiter.record_work(msgs);
+                    for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
+                        let mut data: BytesMut = buf[0..meta.len].into();
+                        // Consecutive segments for the same client go to the driver together
+                        let mut run: Option<(SocketAddr, BytesMut)> = None;
+                        while !data.is_empty() || run.is_some() {
+                            let (segment, client) = match data.is_empty() {
+                                true => (BytesMut::new(), None),
+                                false => {
+                                    let segment = data.split_to(meta.stride.min(data.len()));
+                                    let client = self
+                                        .routes
+                                        .client(meta.addr, &segment)
+                                        .filter(|x| self.relays.contains_key(x));
+                                    if client.is_none() {
+                                        trace!(
+                                            "no client for datagram from upstream {}",
+                                            meta.addr
+                                        );
+                                        let _ = self.events.send(ForwardEvent::Unroutable {
+                                            upstream: meta.addr,
+                                        });
+                                    }
+                                    (segment, client)
+                                }
+                            };
+                            if let Some((remote, ref mut batch)) = run {
+                                if client == Some(remote) {
+                                    batch.unsplit(segment);
+                                    continue;
+                                }
+                            }
+                            if let Some((remote, batch)) = run.take() {
+                                let relay = self.relays.get_mut(&remote).unwrap();
+                                relay.active_time = now;
+                                relay.answer_deadline = None;
+                                let id = relay.id;
+                                relay_datagrams(&self.events, id, batch, meta.stride, meta.ecn);
+                            }
+                            if let Some(client) = client {
+                                run = Some((client, segment));
+                            }
+                        }
+                    }
+                }
+                Poll::Pending => {
+                    exhausted = false;
+                    break;
+                }
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => {
+                    continue;
+                }
+                Poll::Ready(Err(e)) => {
+                    debug!("receiving on shared upstream socket {} failed: {}", bind, e);
+                    failed = true;
+                    exhausted = false;
+                    break;
+                }
+            }
+        }
+        if failed {
+            self.shared_socket_failed(bind);
+        }
+        exhausted
+    }
+
+    /// Allocate `shared_buf`, if it hasn't been already
+    fn alloc_shared_buf(&mut self) {
+        if self.shared_buf.is_empty() {
+            let len = self.slot * BATCH_SIZE;
+            self.shared_buf = vec![0; len].into();
+            self.buffer_bytes.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+
+    /// Relay the queued datagrams of the client at `remote` to its upstream, if its relay reaches
+    /// it over UDP directly
+    ///
+    /// A relay whose own socket fails is ended alone. If a shared socket fails, every relay
+    /// using it is ended. A relay finding a shared socket blocked waits for that socket to be
+    /// woken. Returns whether the turn's budget ran out before the queue was sent.
+    fn send_udp(&mut self, remote: SocketAddr, now: Instant) -> bool {
+        let relay = match self.relays.get_mut(&remote) {
+            Some(x) if !x.to_upstream.is_empty() => x,
+            _ => return false,
+        };
+        let (upstream_socket, waker) = match relay.link {
+            UpstreamLink::Socket(ref x) => (x, &relay.waker),
+            UpstreamLink::Shared => match self.shared_sockets.get_mut(&relay.bind) {
+                Some(x) if x.blocked => {
+                    x.waiting.insert(relay.id);
+                    return false;
+                }
+                Some(x) => (&x.socket, &x.waker),
+                None => return false,
+            },
+            #[allow(unreachable_patterns)]
+            _ => return false,
+        };
+        let cx = &mut Context::from_waker(waker);
+        let is_shared = matches!(relay.link, UpstreamLink::Shared);
+        let mut keep_going = false;
+        let mut blocked = false;
+        let mut failed = false;
+        while !relay.to_upstream.is_empty() {
+            if !self.send_limiter.allow_work() {
+                keep_going = true;
+                break;
+            }
+            match upstream_socket.socket.poll_send(
+                &upstream_socket.udp_state,
+                cx,
+                relay.to_upstream.make_contiguous(),
+            ) {
+                // No progress, and nothing registered to wake the pool, so it's woken to retry
+                Poll::Ready(Ok(0)) => {
+                    keep_going = true;
+                    break;
+                }
+                Poll::Ready(Ok(n)) => {
+                    self.send_limiter.record_work(n);
+                    let (datagrams, bytes) =
+                        relay
+                            .to_upstream
+                            .drain(..n)
+                            .fold((0, 0), |(datagrams, bytes), t| {
+                                (datagrams + segments(&t), bytes + t.contents.len())
+                            });
+                    trace!(parent: &relay.span, "forward to upstream: {:?} bytes", bytes);
+                    relay.active_time = now;
+                    let _ = self.events.send(ForwardEvent::Sent {
+                        id: relay.id,
+                        datagrams,
+                        bytes,
+                    });
+                }
+                Poll::Pending => {
+                    // The shared socket will wake us once it can take more, for everyone
+                    blocked = is_shared;
+                    break;
+                }
+                Poll::Ready(Err(e)) if is_shared => {
+                    debug!(
+                        "sending on shared upstream socket {} failed: {}",
+                        relay.bind, e
+                    );
+                    failed = true;
+                    break;
+                }
+                Poll::Ready(Err(e)) => {
+                    debug!(parent: &relay.span, "sending to upstream failed: {}", e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        let (id, bind) = (relay.id, relay.bind);
+        if blocked {
+            let shared = self.shared_sockets.get_mut(&bind).unwrap();
+            shared.blocked = true;
+            shared.waiting.insert(id);
+        }
+        match (failed, is_shared) {
+            (true, true) => self.shared_socket_failed(bind),
+            (true, false) => self.end(&remote, ForwardEnd::Error),
+            (false, _) => {}
+        }
+        keep_going
+    }
+
+    /// Finish opening the UDP association of the client at `remote`, if its relay goes through a
+    /// SOCKS5 proxy and is still associating, ending the relay if that fails
+    #[cfg(feature = "jls-forward-socks5")]
+    fn associate_socks(&mut self, cx: &mut Context, remote: SocketAddr) {
+        let relay = match self.relays.get_mut(&remote) {
+            Some(x) => x,
+            None => return,
+        };
+        let link = match relay.link {
+            UpstreamLink::Socks(ref mut x) => x,
+            _ => return,
+        };
+        let associating = match link.association {
+            SocksState::Associating(ref mut x) => x,
+            SocksState::Associated { .. } => return,
+        };
+        match associating.as_mut().poll(cx) {
+            Poll::Ready(Ok((control, proxy_relay))) => {
+                trace!(parent: &relay.span, "proxy relays from {}", proxy_relay);
+                link.association = SocksState::Associated {
+                    control,
+                    relay: proxy_relay,
+                };
+            }
+            Poll::Ready(Err(e)) => {
+                debug!(parent: &relay.span, "opening UDP association failed: {}", e);
+                self.end(&remote, ForwardEnd::SetupFailed);
+            }
+            Poll::Pending => {}
+        }
+    }
+
+    /// Relay datagrams to the driver from the upstream of the client at `remote`, if it's reached
+    /// through a SOCKS5 proxy, returning whether the socket may have more
+    ///
+    /// The relay is ended once the proxy closes the connection its association lasts as long as.
+    #[cfg(feature = "jls-forward-socks5")]
+    fn recv_socks(&mut self, cx: &mut Context, remote: SocketAddr, now: Instant) -> bool {
+        match self.relays.get(&remote) {
+            Some(ForwardRelay {
+                link:
+                    UpstreamLink::Socks(SocksLink {
+                        association: SocksState::Associated { .. },
+                        ..
+                    }),
+                ..
+            }) => {}
+            _ => return false,
+        }
+        self.alloc_shared_buf();
+        let mut metas = [RecvMeta::default(); BATCH_SIZE];
+        let mut chunks = self.shared_buf.chunks_mut(self.slot);
+        let mut iovs: [IoSliceMut; BATCH_SIZE] =
+            std::array::from_fn(|_| IoSliceMut::new(chunks.next().unwrap_or(&mut [])));
+        let relay = self.relays.get_mut(&remote).unwrap();
+        let (socket, control, proxy_relay) = match relay.link {
+            UpstreamLink::Socks(SocksLink {
+                ref socket,
+                association:
+                    SocksState::Associated {
+                        ref mut control,
+                        relay,
+                    },
+            }) => (&socket.socket, control, relay),
+            _ => unreachable!(),
+        };
+        // Proxies don't send anything more, except by closing the connection
+        let mut discard = [0; 64];
+        let closed = loop {
+            match control.poll_read(cx, &mut discard) {
+                Poll::Ready(Ok(0)) => break Some(io::ErrorKind::UnexpectedEof.into()),
+                Poll::Ready(Ok(_)) => continue,
+                Poll::Ready(Err(e)) => break Some(e),
+                Poll::Pending => break None,
+            }
+        };
+        if let Some(e) = closed {
+            debug!(parent: &relay.span, "UDP association ended: {}", e);
+            self.end(&remote, ForwardEnd::Error);
+            return false;
+        }
+        let mut failed = false;
+        let mut exhausted = true;
+        for _ in 0..IO_LOOP_BOUND {
+            if !self.recv_limiter.allow_work() {
+                break;
+            }
+            match socket.poll_recv(cx, &mut iovs, &mut metas) {
+                Poll::Ready(Ok(msgs)) => {
+                    self.recv_limiter.record_work(msgs);
+                    for (meta, buf) in metas.iter().zip(iovs.iter()).take(msgs) {
+                        // Anyone else could claim to relay from the upstream
+                        if meta.addr != proxy_relay {
+                            continue;
+                        }
+                        for datagram in buf[0..meta.len].chunks(meta.stride.max(1)) {
+                            let header = match jls_socks::decapsulate(datagram) {
+                                Some((len, _)) => len,
+                                None => {
+                                    trace!(parent: &relay.span, "malformed datagram from proxy");
+                                    continue;
+                                }
+                            };
+                            let data = BytesMut::from(&datagram[header..]);
+                            let len = data.len();
+                            // The proxy's marking says nothing of the upstream's
+                            relay_datagrams(&self.events, relay.id, data, len, None);
+                            relay.active_time = now;
+                            relay.answer_deadline = None;
+                        }
+                    }
+                }
+                Poll::Pending => {
+                    exhausted = false;
+                    break;
+                }
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::ConnectionReset => {
+                    continue;
+                }
+                Poll::Ready(Err(e)) => {
+                    debug!(parent: &relay.span, "receiving from proxy failed: {}", e);
+                    failed = true;
+                    exhausted = false;
+                    break;
+                }
+            }
+        }
+        if failed {
+            self.end(&remote, ForwardEnd::Error);
+        }
+        exhausted
+    }
+
+    /// Relay the queued datagrams of the client at `remote` to its upstream through a SOCKS5
+    /// proxy, if it's reached that way, each with the header telling the proxy where it goes
+    ///
+    /// Returns whether the turn's budget ran out before the queue was sent.
+    #[cfg(feature = "jls-forward-socks5")]
+    fn send_socks(&mut self, cx: &mut Context, remote: SocketAddr, now: Instant) -> bool {
+        let relay = match self.relays.get_mut(&remote) {
+            Some(x) => x,
+            None => return false,
+        };
+        let (socket, proxy_relay) = match relay.link {
+            UpstreamLink::Socks(SocksLink {
+                ref socket,
+                association: SocksState::Associated { relay, .. },
+            }) => (socket, relay),
+            _ => return false,
+        };
+        let mut keep_going = false;
+        let mut failed = false;
+        while let Some(transmit) = relay.to_upstream.front() {
+            if !self.send_limiter.allow_work() {
+                keep_going = true;
+                break;
+            }
+            let bytes = transmit.contents.len();
+            let encapsulated = upstream_udp_transmit(
+                &proxy_relay,
+                jls_socks::encapsulate(transmit.destination, &transmit.contents),
+                None,
+                transmit.ecn,
+            );
+            match socket
+                .socket
+                .poll_send(&socket.udp_state, cx, &[encapsulated])
+            {
+                Poll::Ready(Ok(0)) => {
+                    keep_going = true;
+                    break;
+                }
+                Poll::Pending => break,
+                Poll::Ready(Ok(_)) => {
+                    self.send_limiter.record_work(1);
+                    relay.to_upstream.pop_front();
+                    trace!(parent: &relay.span, "forward to upstream: {:?} bytes", bytes);
+                    relay.active_time = now;
+                    let _ = self.events.send(ForwardEvent::Sent {
+                        id: relay.id,
+                        datagrams: 1,
+                        bytes,
+                    });
+                }
+                Poll::Ready(Err(e)) => {
+                    debug!(parent: &relay.span, "sending to proxy failed: {}", e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if failed {
+            self.end(&remote, ForwardEnd::Error);
+        }
+        keep_going
+    }
+
+    /// Finish connecting the TCP tunnel of the client at `remote` to its upstream, if it has one
+    /// that's still connecting, ending the relay if that fails
+    #[cfg(feature = "jls-forward-tcp")]
+    fn connect_tunnel(&mut self, cx: &mut Context, remote: SocketAddr) {
+        let relay = match self.relays.get_mut(&remote) {
+            Some(x) => x,
+            None => return,
+        };
+        let tunnel = match relay.link {
+            UpstreamLink::Tcp(ref mut x) => x,
+            _ => return,
+        };
+        let connecting = match tunnel.stream {
+            TcpState::Connecting(ref mut x) => x,
+            TcpState::Connected(_) => return,
+        };
+        match connecting.as_mut().poll(cx) {
+            Poll::Ready(Ok(stream)) => {
+                trace!(parent: &relay.span, "connected to upstream over TCP");
+                tunnel.stream = TcpState::Connected(stream);
+            }
+            Poll::Ready(Err(e)) => {
+                debug!(parent: &relay.span, "connecting to upstream failed: {}", e);
+                self.end(&remote, ForwardEnd::SetupFailed);
+            }
+            Poll::Pending => {}
+        }
+    }
+
+    /// Relay datagrams to the driver from the upstream of the client at `remote`, if it's reached
+    /// over TCP, returning whether the tunnel may have more
+    #[cfg(feature = "jls-forward-tcp")]
+    fn recv_tunnel(&mut self, cx: &mut Context, remote: SocketAddr, now: Instant) -> bool {
+        match self.relays.get(&remote) {
+            Some(ForwardRelay {
+                link:
+                    UpstreamLink::Tcp(TcpTunnel {
+                        stream: TcpState::Connected(_),
+                        ..
+                    }),
+                ..
+            }) => {}
+            _ => return false,
+        }
+        self.alloc_shared_buf();
+        let buf = &mut self.shared_buf[..];
+        let relay = self.relays.get_mut(&remote).unwrap();
+        let (partial, stream) = match relay.link {
+            UpstreamLink::Tcp(ref mut tunnel) => match tunnel.stream {
+                TcpState::Connected(ref mut x) => (&mut tunnel.partial, x),
+                TcpState::Connecting(_) => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        let mut failed = false;
+        let mut exhausted = true;
+        for _ in 0..IO_LOOP_BOUND {
+            if !self.recv_limiter.allow_work() {
+                break;
+            }
+            match stream.poll_read(cx, buf) {
+                Poll::Ready(Ok(0)) => {
+                    debug!(parent: &relay.span, "upstream closed the TCP connection");
+                    failed = true;
+                    exhausted = false;
+                    break;
+                }
+                Poll::Ready(Ok(n)) => {
+                    self.recv_limiter.record_work(1);
+                    partial.extend_from_slice(&buf[..n]);
+                    while let Some(datagram) = next_frame(partial) {
+                        if datagram.is_empty() {
+                            continue;
+                        }
+                        let len = datagram.len();
+                        relay_datagrams(&self.events, relay.id, datagram, len, None);
+                        relay.active_time = now;
+                        relay.answer_deadline = None;
+                    }
+                }
+                Poll::Pending => {
+                    exhausted = false;
+                    break;
+                }
+                Poll::Ready(Err(e)) => {
+                    debug!(parent: &relay.span, "receiving from upstream failed: {}", e);
+                    failed = true;
+                    exhausted = false;
+                    break;
+                }
+            }
+        }
+        if failed {
+            self.end(&remote, ForwardEnd::Error);
+        }
+        exhausted
+    }
+
+    /// Relay the queued datagrams of the client at `remote` to its upstream, if it's reached over
+    /// TCP
+    ///
+    /// A transmit is only framed once the one before it is written in full, so that the queue
+    /// stays bounded as the driver sees it. Returns whether the turn's budget ran out before the
+    /// queue was sent.
+    #[cfg(feature = "jls-forward-tcp")]
+    fn send_tunnel(&mut self, cx: &mut Context, remote: SocketAddr, now: Instant) -> bool {
+        let relay = match self.relays.get_mut(&remote) {
+            Some(x) => x,
+            None => return false,
+        };
+        let tunnel = match relay.link {
+            UpstreamLink::Tcp(ref mut x) => x,
+            _ => return false,
+        };
+        let stream = match tunnel.stream {
+            TcpState::Connected(ref mut x) => x,
+            TcpState::Connecting(_) => return false,
+        };
+        let mut keep_going = false;
+        let mut failed = false;
+        loop {
+            if tunnel.out.is_empty() && !relay.to_upstream.is_empty() {
+                if !self.send_limiter.allow_work() {
+                    keep_going = true;
+                    break;
+                }
+                self.send_limiter.record_work(1);
+            }
+            if tunnel.out.is_empty() {
+                let transmit = match relay.to_upstream.pop_front() {
+                    Some(x) => x,
+                    None => break,
+                };
+                tunnel.out_datagrams = segments(&transmit);
+                tunnel.out_bytes = transmit.contents.len();
+                let stride = transmit.segment_size.unwrap_or(transmit.contents.len());
+                for datagram in transmit.contents.chunks(stride.max(1)) {
+                    tunnel
+                        .out
+                        .extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+                    tunnel.out.extend_from_slice(datagram);
+                }
+            }
+            match stream.poll_write(cx, &tunnel.out) {
+                Poll::Ready(Ok(0)) => {
+                    debug!(parent: &relay.span, "upstream closed the TCP connection");
+                    failed = true;
+                    break;
+                }
+                Poll::Ready(Ok(n)) => {
+                    let _ = tunnel.out.split_to(n);
+                    if tunnel.out.is_empty() {
+                        let bytes = tunnel.out_bytes;
+                        trace!(parent: &relay.span, "forward to upstream: {:?} bytes", bytes);
+                        relay.active_time = now;
+                        let _ = self.events.send(ForwardEvent::Sent {
+                            id: relay.id,
+                            datagrams: tunnel.out_datagrams,
+                            bytes,
+                        });
+                    }
+                }
+                Poll::Pending => break,
+                Poll::Ready(Err(e)) => {
+                    debug!(parent: &relay.span, "sending to upstream failed: {}", e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if failed {
+            self.end(&remote, ForwardEnd::Error);
+        }
+        keep_going
+    }
+
+    /// End every relay using the shared socket bound to `bind`, which failed
+    fn shared_socket_failed(&mut self, bind: SocketAddr) {
+        self.shared_sockets.remove(&bind);
+        let remotes = self
+            .relays
+            .iter()
+            .filter(|(_, relay)| matches!(relay.link, UpstreamLink::Shared) && relay.bind == bind)
+            .map(|(&remote, _)| remote)
+            .collect::<Vec<_>>();
+        for remote in remotes {
+            self.end(&remote, ForwardEnd::Error);
+        }
+    }
+
+    /// When `relay` is next due to end, if ever
+    fn deadline(&self, relay: &ForwardRelay) -> Option<Instant> {
+        let idle = relay.active_time.checked_add(self.idle_timeout);
+        [idle, relay.answer_deadline, self.drain_deadline]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// End the relays that came due: idle and unanswered ones, and drained ones if the pool is
+    /// draining, and arrange to be woken when the next one may be
+    ///
+    /// Returns whether the timer already expired.
+    fn expire(&mut self, cx: &mut Context, now: Instant) -> bool {
+        while let Some(&Reverse((due, id))) = self.deadlines.peek() {
+            if due > now {
+                break;
+            }
+            self.deadlines.pop();
+            // The relay may have ended, or seen activity since
+            let remote = match self.remotes.get(&id) {
+                Some(&x) => x,
+                None => continue,
+            };
+            let relay = &self.relays[&remote];
+            let reason = if self.drain_deadline.map_or(false, |x| x <= now) {
+                match relay.flushed() {
+                    true => ForwardEnd::Closed,
+                    false => ForwardEnd::DrainTimedOut,
+                }
+            } else if relay.answer_deadline.map_or(false, |x| x <= now) {
+                ForwardEnd::Unanswered
+            } else if now.saturating_duration_since(relay.active_time) >= self.idle_timeout {
+                ForwardEnd::Idle
+            } else {
+                if let Some(next) = self.deadline(relay) {
+                    self.deadlines.push(Reverse((next, id)));
+                }
+                continue;
+            };
+            self.end(&remote, reason);
+        }
+        let next = match self.deadlines.peek() {
+            Some(&Reverse((x, _))) => x,
+            None => {
+                self.expiry_timer = None;
+                return false;
+            }
+        };
+        match self.expiry_timer {
+            Some(ref mut timer) => timer.as_mut().reset(next),
+            None => self.expiry_timer = Some(self.runtime.new_timer(next)),
+        }
+        let timer = self.expiry_timer.as_mut().unwrap();
+        timer.as_mut().poll(cx).is_ready()
+    }
+}
+
+impl Future for ForwardPool {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let now = Instant::now();
+        // Relays and shared sockets wake the pool through this, noting what they woke it for
+        this.ready.lock().unwrap().waker = Some(cx.waker().clone());
+        // Commands first, so that datagrams from clients are sent in this same poll
+        let mut keep_going = match this.handle_commands(cx, now) {
+            Some(x) => x,
+            None => {
+                this.ready.lock().unwrap().waker = None;
+                return Poll::Ready(());
+            }
+        };
+        keep_going |= this.serve(now);
+        keep_going |= this.expire(cx, now);
+        if keep_going {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for ForwardPool {
+    fn drop(&mut self) {
+        self.buffer_bytes
+            .fetch_sub(self.shared_buf.len(), Ordering::Relaxed);
+    }
+}
+
+/// A forwarded client, as far as relaying its traffic goes
+#[derive(Debug)]
+struct ForwardRelay {
+    id: u64,
+    link: UpstreamLink,
+    /// Where the socket reaching the upstream is bound
+    bind: SocketAddr,
+    upstream_addr: SocketAddr,
+    to_upstream: VecDeque<Transmit>,
+    /// When a datagram was last relayed in either direction
+    ///
+    /// Queueing datagrams for an upstream doesn't count, so a relay whose socket never takes them
+    /// expires, and its queue with it.
+    active_time: Instant,
+    /// When the relay ends unless the upstream has sent something, if it's to end for that
+    answer_deadline: Option<Instant>,
+    /// Non-empty source connection IDs the client has used, by which the upstream addresses it
+    client_cids: Vec<proto::ConnectionId>,
+    /// Wakes the pool to serve this relay, as registered with its socket, tunnel, or proxy
+    waker: Waker,
+    span: Span,
+}
+
+impl ForwardRelay {
+    /// Whether everything from the client was sent to the upstream
+    fn flushed(&self) -> bool {
+        #[cfg(feature = "jls-forward-tcp")]
+        if let UpstreamLink::Tcp(ref x) = self.link {
+            if !x.out.is_empty() {
+                return false;
+            }
+        }
+        self.to_upstream.is_empty()
+    }
+}
+
+/// How a relay reaches its upstream
+#[derive(Debug)]
+enum UpstreamLink {
+    /// Through the [shared socket](ForwardPool::shared_sockets) bound to the relay's `bind`
+    Shared,
+    /// Through a socket of the relay's own
+    Socket(UpstreamSocket),
+    /// Through a TCP connection of the relay's own
+    #[cfg(feature = "jls-forward-tcp")]
+    Tcp(TcpTunnel),
+    /// Through a SOCKS5 proxy, from a socket of the relay's own
+    #[cfg(feature = "jls-forward-socks5")]
+    Socks(SocksLink),
+}
+
+/// A socket reaching an upstream through a SOCKS5 proxy, by way of a UDP association with it
+#[cfg(feature = "jls-forward-socks5")]
+struct SocksLink {
+    socket: UpstreamSocket,
+    association: SocksState,
+}
+
+#[cfg(feature = "jls-forward-socks5")]
+impl std::fmt::Debug for SocksLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let relay = match self.association {
+            SocksState::Associating(_) => None,
+            SocksState::Associated { relay, .. } => Some(relay),
+        };
+        f.debug_struct("SocksLink")
+            .field("socket", &self.socket)
+            .field("relay", &relay)
+            .finish()
+    }
+}
+
+#[cfg(feature = "jls-forward-socks5")]
+enum SocksState {
+    Associating(jls_socks::Associating),
+    Associated {
+        /// The connection to the proxy, which the association lasts as long as
+        control: Box<dyn AsyncTcpStream>,
+        /// Where the proxy relays datagrams from, and takes those to be relayed
+        relay: SocketAddr,
+    },
+}
+
+/// A TCP connection to an upstream, over which datagrams are sent and received prefixed with
+/// their length
+#[cfg(feature = "jls-forward-tcp")]
+struct TcpTunnel {
+    stream: TcpState,
+    /// The framed datagrams of the transmit being written
+    out: BytesMut,
+    /// Datagrams and bytes the transmit being written amounts to, before framing
+    out_datagrams: usize,
+    out_bytes: usize,
+    /// What was read of a datagram not yet received in full
+    partial: BytesMut,
+}
+
+#[cfg(feature = "jls-forward-tcp")]
+impl std::fmt::Debug for TcpTunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TcpTunnel")
+            .field("connected", &matches!(self.stream, TcpState::Connected(_)))
+            .field("out", &self.out.len())
+            .field("partial", &self.partial.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "jls-forward-tcp")]
+enum TcpState {
+    Connecting(Pin<Box<dyn Future<Output = io::Result<Box<dyn AsyncTcpStream>>> + Send>>),
+    Connected(Box<dyn AsyncTcpStream>),
+}
+
+/// Take the next datagram off the front of what was read from a [`TcpTunnel`], if it's all there
+#[cfg(feature = "jls-forward-tcp")]
+fn next_frame(buf: &mut BytesMut) -> Option<BytesMut> {
+    let len = usize::from(u16::from_be_bytes([*buf.first()?, *buf.get(1)?]));
+    if buf.len() < 2 + len {
+        return None;
+    }
+    Some(buf.split_to(2 + len).split_off(2))
+}
+
+/// A socket reaching JLS upstreams on behalf of every relay without one of its own
+#[derive(Debug)]
+struct SharedSocket {
+    socket: UpstreamSocket,
+    /// Wakes the pool to serve the socket, and the relays waiting on it
+    waker: Waker,
+    /// Whether the socket couldn't take more since it was last woken
+    blocked: bool,
+    /// Relays with datagrams to send once the socket is woken, by ID
+    waiting: HashSet<u64>,
+}
+
+/// A socket for reaching JLS upstreams
+#[derive(Debug)]
+struct UpstreamSocket {
+    socket: Box<dyn AsyncUdpSocket>,
+    udp_state: Arc<UdpState>,
+}
+
+impl UpstreamSocket {
+    fn bind(runtime: &dyn Runtime, addr: SocketAddr) -> io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(addr)?;
+        Ok(Self {
+            socket: runtime.wrap_udp_socket(socket)?,
+            udp_state: Arc::new(UdpState::new()),
+        })
+    }
+}
+
+/// Where to bind a socket reaching `upstream`: the configured address, or else a wildcard address
+/// of the upstream's family
+///
+/// A configured address of the other family can't reach the upstream, unless it's the IPv6
+/// wildcard address of a dual-stack socket.
+fn upstream_bind(configured: Option<SocketAddr>, upstream: SocketAddr) -> io::Result<SocketAddr> {
+    let addr = match configured {
+        Some(x) => x,
+        None if upstream.is_ipv4() => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        None => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    if addr.is_ipv4() != upstream.is_ipv4() && addr.ip() != IpAddr::from(Ipv6Addr::UNSPECIFIED) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "upstream bind address of the wrong family",
+        ));
+    }
+    Ok(addr)
+}
+
+/// Matches datagrams arriving on the shared upstream sockets to the clients they're for
+#[derive(Debug, Default)]
+struct UpstreamRoutes {
+    /// Clients by upstream and the connection IDs they chose
+    by_cid: HashMap<(SocketAddr, proto::ConnectionId), SocketAddr>,
+    /// Lengths of the connection IDs in `by_cid`, for finding those of short header packets
+    cid_lens: Vec<usize>,
+    /// Clients forwarded to each upstream
+    clients: HashMap<SocketAddr, HashSet<SocketAddr>>,
+}
+
+impl UpstreamRoutes {
+    fn add_client(&mut self, upstream: SocketAddr, client: SocketAddr) {
+        self.clients.entry(upstream).or_default().insert(client);
+    }
+
+    fn add_cid(&mut self, upstream: SocketAddr, client: SocketAddr, cid: proto::ConnectionId) {
+        self.by_cid.insert((upstream, cid), client);
+        if !self.cid_lens.contains(&cid.len()) {
+            self.cid_lens.push(cid.len());
+        }
+    }
+
+    fn remove(&mut self, client: SocketAddr, relay: &ForwardRelay) {
+        for cid in &relay.client_cids {
+            let key = (relay.upstream_addr, *cid);
+            if self.by_cid.get(&key) == Some(&client) {
+                self.by_cid.remove(&key);
+            }
+        }
+        if let Some(clients) = self.clients.get_mut(&relay.upstream_addr) {
+            clients.remove(&client);
+            if clients.is_empty() {
+                self.clients.remove(&relay.upstream_addr);
+            }
+        }
+    }
+
+    /// The client a datagram from `upstream` is for, if it can be told
+    fn client(&self, upstream: SocketAddr, packet: &[u8]) -> Option<SocketAddr> {
+        let by_cid = |cid: &[u8]| {
+            self.by_cid
+                .get(&(upstream, proto::ConnectionId::new(cid)))
+                .copied()
+        };
+        let found = match long_header_cids(packet) {
+            Some((dcid, _)) => by_cid(&dcid),
+            // Short headers don't say how long their connection ID is
+            None if packet.first().map_or(false, |x| x & 0x80 == 0) => self
+                .cid_lens
+                .iter()
+                .find_map(|&len| by_cid(packet.get(1..1 + len)?)),
+            None => None,
+        };
+        found.or_else(|| {
+            let clients = self.clients.get(&upstream)?;
+            match clients.len() {
+                1 => clients.iter().next().copied(),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// What a [`ForwardPool`] was woken for, so that it only serves the relays and shared sockets
+/// which have something to do
+#[derive(Debug, Default)]
+struct Ready {
+    /// Relays to serve, by ID, in the order they were woken
+    relays: VecDeque<u64>,
+    /// The relays in `relays`, so that each is there once
+    queued: HashSet<u64>,
+    /// Shared sockets to serve, by the address they're bound to
+    shared: Vec<SocketAddr>,
+    /// The pool's own waker, as of its last poll
+    waker: Option<Waker>,
+}
+
+impl Ready {
+    fn push(&mut self, key: ReadyKey) {
+        match key {
+            ReadyKey::Relay(id) => {
+                if self.queued.insert(id) {
+                    self.relays.push_back(id);
+                }
+            }
+            ReadyKey::Shared(bind) => {
+                if !self.shared.contains(&bind) {
+                    self.shared.push(bind);
+                }
+            }
+        }
+    }
+}
+
+/// Something a [`ForwardPool`] can be woken to serve
+#[derive(Debug, Copy, Clone)]
+enum ReadyKey {
+    /// A relay, by its ID
+    Relay(u64),
+    /// A shared socket, by the address it's bound to
+    Shared(SocketAddr),
+}
+
+/// Wakes a [`ForwardPool`], noting what for
+struct ReadyWaker {
+    ready: Arc<Mutex<Ready>>,
+    key: ReadyKey,
+}
+
+impl ReadyWaker {
+    fn new(ready: &Arc<Mutex<Ready>>, key: ReadyKey) -> Waker {
+        Waker::from(Arc::new(Self {
+            ready: ready.clone(),
+            key,
+        }))
+    }
+}
+
+impl Wake for ReadyWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let waker = {
+            let mut ready = self.ready.lock().unwrap();
+            ready.push(self.key);
+            ready.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Pass datagrams received for the client of relay `id` to the driver, all at once
+///
+/// `data` holds datagrams of `stride` bytes each, the last possibly shorter, as received with GRO.
+fn relay_datagrams(
+    events: &mpsc::UnboundedSender<ForwardEvent>,
+    id: u64,
+    data: BytesMut,
+    stride: usize,
+    ecn: Option<EcnCodepoint>,
+) {
+    let segment_size = match data.len() > stride {
+        true => Some(stride),
+        false => None,
+    };
+    let _ = events.send(ForwardEvent::Relay {
+        id,
+        data,
+        segment_size,
+        ecn,
+    });
+}
+
+/// Queue datagrams for `destination`, each `segment_size` bytes but the last if that's set
+///
+/// They stay together as one transmit, to be sent with GSO, if the socket they're sent from can
+/// send that many segments at once. Otherwise each gets a transmit of its own. All are marked with
+/// `ecn`, and sent from `src_ip` if that's set.
+pub(crate) fn queue_segments(
+    queue: &mut VecDeque<Transmit>,
+    destination: SocketAddr,
+    mut data: BytesMut,
+    segment_size: Option<usize>,
+    ecn: Option<EcnCodepoint>,
+    src_ip: Option<IpAddr>,
+    max_segments: usize,
+) {
+    let transmit = |data, segment_size| Transmit {
+        src_ip,
+        ..upstream_udp_transmit(&destination, data, segment_size, ecn)
+    };
+    let stride = match segment_size {
+        Some(x) if x < data.len() => x,
+        _ => {
+            queue.push_back(transmit(data, None));
+            return;
+        }
+    };
+    if data.chunks(stride).len() <= max_segments {
+        queue.push_back(transmit(data, Some(stride)));
+        return;
+    }
+    while !data.is_empty() {
+        let datagram = data.split_to(stride.min(data.len()));
+        queue.push_back(transmit(datagram, None));
+    }
+}
+
+/// The datagrams a transmit amounts to
+fn segments(transmit: &Transmit) -> usize {
+    match transmit.segment_size {
+        Some(x) => transmit.contents.chunks(x).len(),
+        None => 1,
+    }
+}
+
+/// How the client at `remote` is named in the logs of its forward connections
+///
+/// `key` keys the hash of [`JlsClientRedaction::Hashed`].
+pub(crate) fn client_label(
+    remote: SocketAddr,
+    redaction: JlsClientRedaction,
+    key: &RandomState,
+) -> String {
+    // Dual-stack sockets report IPv4 clients by mapped addresses
+    let ip = match remote.ip() {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map_or(remote.ip(), IpAddr::V4),
+        x => x,
+    };
+    match redaction {
+        JlsClientRedaction::Off => remote.to_string(),
+        JlsClientRedaction::Prefix => match ip {
+            IpAddr::V4(x) => format!("{}/24", Ipv4Addr::from(u32::from(x) & !0xff)),
+            IpAddr::V6(x) => format!("{}/48", Ipv6Addr::from(u128::from(x) & !(!0 >> 48))),
+        },
+        JlsClientRedaction::Hashed => {
+            let mut hasher = key.build_hasher();
+            ip.hash(&mut hasher);
+            format!("{:016x}", hasher.finish())
+        }
+    }
+}
+
+/// The destination and source connection IDs of a datagram starting with a long header packet
+pub(crate) fn long_header_cids(
+    packet: &[u8],
+) -> Option<(proto::ConnectionId, proto::ConnectionId)> {
+    if packet.first()? & 0x80 == 0 {
+        return None;
+    }
+    let mut rest = packet.get(5..)?;
+    let mut cid = || {
+        let len = usize::from(*rest.first()?);
+        if len > proto::MAX_CID_SIZE {
+            return None;
+        }
+        let cid = proto::ConnectionId::new(rest.get(1..1 + len)?);
+        rest = &rest[1 + len..];
+        Some(cid)
+    };
+    let dcid = cid()?;
+    let scid = cid()?;
+    Some((dcid, scid))
+}
+
+pub(crate) fn upstream_udp_transmit(
+    addr: &SocketAddr,
+    data: BytesMut,
+    segment_size: Option<usize>,
+    ecn: Option<EcnCodepoint>,
+) -> Transmit {
+    let remote = addr;
+    Transmit {
+        contents: data.into(),
+        destination: remote.clone(),
+        ecn,
+        segment_size,
+        src_ip: None,
+    }
+}
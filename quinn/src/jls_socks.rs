@@ -0,0 +1,196 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{BufMut, BytesMut};
+use proto::JlsSocks5Proxy;
+
+use crate::runtime::{AsyncTcpStream, Runtime};
+
+/// A UDP association being opened with a SOCKS5 proxy, per RFC 1928
+///
+/// Resolves to the connection to the proxy, which the association lasts as long as, and the
+/// address the proxy relays datagrams from.
+pub(crate) type Associating =
+    Pin<Box<dyn Future<Output = io::Result<(Box<dyn AsyncTcpStream>, SocketAddr)>> + Send>>;
+
+/// Open a UDP association with `proxy`, connecting to it from `bind`
+pub(crate) fn associate(
+    runtime: &dyn Runtime,
+    bind: SocketAddr,
+    proxy: JlsSocks5Proxy,
+) -> Associating {
+    let connecting = runtime.connect_tcp(bind, proxy.addr());
+    Box::pin(async move {
+        let mut stream = connecting.await?;
+        let relay = handshake(&mut *stream, &proxy).await?;
+        Ok((stream, relay))
+    })
+}
+
+async fn handshake(
+    stream: &mut dyn AsyncTcpStream,
+    proxy: &JlsSocks5Proxy,
+) -> io::Result<SocketAddr> {
+    let method = match proxy.credentials() {
+        Some(_) => USERNAME_PASSWORD,
+        None => NO_AUTHENTICATION,
+    };
+    write_all(stream, &[VERSION, 1, method]).await?;
+    let mut reply = [0; 2];
+    read_exact(stream, &mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid_data("not a SOCKS5 proxy"));
+    }
+    if reply[1] != method {
+        return Err(refused("proxy refused the authentication method"));
+    }
+    if let Some((username, password)) = proxy.credentials() {
+        // Lengths were checked by `JlsSocks5Proxy::with_credentials`
+        let mut request = Vec::with_capacity(3 + username.len() + password.len());
+        request.push(1);
+        request.push(username.len() as u8);
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        write_all(stream, &request).await?;
+        read_exact(stream, &mut reply).await?;
+        if reply[1] != 0 {
+            return Err(refused("proxy refused the credentials"));
+        }
+    }
+
+    // Where datagrams will come from isn't known ahead of time, which the zero address says
+    write_all(
+        stream,
+        &[VERSION, UDP_ASSOCIATE, 0, ADDR_IPV4, 0, 0, 0, 0, 0, 0],
+    )
+    .await?;
+    let mut reply = [0; 4];
+    read_exact(stream, &mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid_data("not a SOCKS5 proxy"));
+    }
+    if reply[1] != 0 {
+        return Err(refused("proxy refused the UDP association"));
+    }
+    let ip = match reply[3] {
+        ADDR_IPV4 => {
+            let mut ip = [0; 4];
+            read_exact(stream, &mut ip).await?;
+            IpAddr::from(ip)
+        }
+        ADDR_IPV6 => {
+            let mut ip = [0; 16];
+            read_exact(stream, &mut ip).await?;
+            IpAddr::from(ip)
+        }
+        _ => return Err(invalid_data("proxy relays from a host name")),
+    };
+    let mut port = [0; 2];
+    read_exact(stream, &mut port).await?;
+    // Proxies commonly answer with the wildcard address, meaning their own
+    let ip = match ip.is_unspecified() {
+        true => proxy.addr().ip(),
+        false => ip,
+    };
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+/// Prefix `datagram` with the header telling the proxy to relay it to `destination`
+pub(crate) fn encapsulate(destination: SocketAddr, datagram: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(MAX_HEADER_LEN + datagram.len());
+    // Reserved, then the fragment number, as fragments aren't used
+    buf.put_slice(&[0, 0, 0]);
+    match destination.ip() {
+        IpAddr::V4(x) => {
+            buf.put_u8(ADDR_IPV4);
+            buf.put_slice(&x.octets());
+        }
+        IpAddr::V6(x) => {
+            buf.put_u8(ADDR_IPV6);
+            buf.put_slice(&x.octets());
+        }
+    }
+    buf.put_u16(destination.port());
+    buf.put_slice(datagram);
+    buf
+}
+
+/// The length of the header on a datagram relayed by the proxy, and where the proxy says it came
+/// from
+///
+/// `None` if the header is malformed, or the datagram is a fragment, as those aren't reassembled.
+pub(crate) fn decapsulate(datagram: &[u8]) -> Option<(usize, SocketAddr)> {
+    if datagram.get(..3)? != [0, 0, 0] {
+        return None;
+    }
+    let (ip, port_at) = match *datagram.get(3)? {
+        ADDR_IPV4 => (
+            IpAddr::from(<[u8; 4]>::try_from(datagram.get(4..8)?).ok()?),
+            8,
+        ),
+        ADDR_IPV6 => (
+            IpAddr::from(<[u8; 16]>::try_from(datagram.get(4..20)?).ok()?),
+            20,
+        ),
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(datagram.get(port_at..port_at + 2)?.try_into().ok()?);
+    Some((port_at + 2, SocketAddr::new(ip, port)))
+}
+
+async fn write_all(stream: &mut dyn AsyncTcpStream, buf: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        match PollFn(|cx: &mut Context| stream.poll_write(cx, &buf[written..])).await? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => written += n,
+        }
+    }
+    Ok(())
+}
+
+async fn read_exact(stream: &mut dyn AsyncTcpStream, buf: &mut [u8]) -> io::Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        match PollFn(|cx: &mut Context| stream.poll_read(cx, &mut buf[read..])).await? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    Ok(())
+}
+
+/// A future polled by calling a closure, as `std::future::poll_fn` is newer than our MSRV
+struct PollFn<F>(F);
+
+impl<T, F: FnMut(&mut Context) -> Poll<T> + Unpin> Future for PollFn<F> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let f = &mut self.0;
+        f(cx)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn refused(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, msg)
+}
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const UDP_ASSOCIATE: u8 = 3;
+const ADDR_IPV4: u8 = 1;
+const ADDR_IPV6: u8 = 4;
+/// Length of the header on datagrams to and from an IPv6 address
+const MAX_HEADER_LEN: usize = 22;
@@ -43,6 +43,8 @@
 
 use std::time::Duration;
 
+use tokio::sync::mpsc;
+
 macro_rules! ready {
     ($e:expr $(,)?) => {
         match $e {
@@ -52,33 +54,70 @@ macro_rules! ready {
     };
 }
 
+#[cfg(feature = "cert-reloader")]
+mod cert_reloader;
 mod connection;
+#[cfg(feature = "runtime-tokio")]
+mod driver_thread;
 mod endpoint;
+mod event_queue;
+mod ip_stats;
+mod jls_forward;
+#[cfg(feature = "jls-forward-socks5")]
+mod jls_socks;
 mod mutex;
+mod reaper;
 mod recv_stream;
+mod response_shaper;
 mod runtime;
 mod send_stream;
+#[cfg(feature = "tls-rustls")]
+mod session;
+mod snapshot;
 mod work_limiter;
 
 pub use proto::{
-    congestion, crypto, ApplicationClose, Chunk, ClientConfig, ConfigError, ConnectError,
-    ConnectionClose, ConnectionError, EndpointConfig, IdleTimeout, MtuDiscoveryConfig,
-    ServerConfig, StreamId, Transmit, TransportConfig, VarInt,
+    congestion, crypto, Abort, ApplicationClose, Chunk, ClientConfig, ConfigError, ConnectError,
+    ConnectionClose, ConnectionError, ConnectionHandle, ConnectionHealth, ConnectionId,
+    EndpointConfig, EndpointStats, HandshakeInfo, IdleTimeout, InvalidSealedKeys,
+    JlsClientRedaction, JlsSocks5Proxy, JlsUpstreamSelection, JlsUpstreamTransport,
+    MigrationPolicy, MtuDiscoveryConfig, PathInfo, PostHandshakeVerifier, PrefixPolicy,
+    PrefixVerdict, SealedKeys, ServerConfig, StatelessResetPolicy, StreamId, Transmit,
+    TransportConfig, VarInt,
 };
 pub use udp;
 
+#[cfg(feature = "cert-reloader")]
+pub use crate::cert_reloader::CertReloader;
 pub use crate::connection::{
-    AcceptBi, AcceptUni, Connecting, Connection, OpenBi, OpenUni, ReadDatagram, SendDatagramError,
-    UnknownStream, ZeroRttAccepted,
+    AcceptBi, AcceptUni, BatchGuard, Connecting, Connection, OpenBi, OpenUni, ReadDatagram,
+    SendDatagramError, UnknownStream, ZeroRttAccepted,
+};
+#[cfg(feature = "runtime-tokio")]
+pub use crate::driver_thread::DriverThreadConfig;
+pub use crate::endpoint::{
+    Accept, AcceptError, AcceptIncoming, ConnectOptions, ConnectRacingError, DriverPhases,
+    DriverTiming, Endpoint, EndpointDriver, EndpointError, EndpointSetupError, Incoming,
+    JlsUpstreamStats, ReplySocket, RetryError, ShutdownProgress, SocketId,
+    CONNECTION_ATTEMPT_DELAY,
 };
-pub use crate::endpoint::{Accept, Endpoint};
-pub use crate::recv_stream::{ReadError, ReadExactError, ReadToEndError, RecvStream};
+pub use crate::event_queue::EventQueueStats;
+pub use crate::ip_stats::{IpStats, IpThreshold};
+pub use crate::reaper::{ConnectionActivity, Reap};
+pub use crate::recv_stream::{ReadError, ReadExactError, ReadToEndError, RecvStream, ResetError};
 #[cfg(feature = "runtime-async-std")]
 pub use crate::runtime::AsyncStdRuntime;
+#[cfg(feature = "jls-forward-tcp")]
+pub use crate::runtime::AsyncTcpStream;
 #[cfg(feature = "runtime-tokio")]
 pub use crate::runtime::TokioRuntime;
 pub use crate::runtime::{default_runtime, AsyncTimer, AsyncUdpSocket, Runtime};
 pub use crate::send_stream::{SendStream, StoppedError, WriteError};
+#[cfg(feature = "tls-rustls")]
+pub use crate::session::{set_session_store, MemorySessionStore, SessionStore};
+pub use crate::snapshot::{
+    ConnectionPhase, ConnectionSnapshot, EndpointSnapshot, ForwardSnapshot, WorkLimitSnapshot,
+};
 
 #[cfg(test)]
 mod tests;
@@ -90,13 +129,56 @@ enum ConnectionEvent {
         reason: bytes::Bytes,
     },
     Proto(proto::ConnectionEvent),
-    Ping,
+    /// The endpoint's socket was rebound to a new local address
+    Rebind,
 }
 
 #[derive(Debug)]
 enum EndpointEvent {
     Proto(proto::EndpointEvent),
-    Transmit(proto::Transmit),
+    Transmit {
+        transmit: proto::Transmit,
+        /// Whether the connection was still handshaking when the datagram was produced
+        handshake: bool,
+        /// Whether the datagrams carry nothing but ACK frames, which a newer transmit from the
+        /// same connection can stand in for
+        ack_only: bool,
+    },
+    /// The connection is drained, after handing the endpoint `transmits` transmits in all
+    ///
+    /// Transmits travel separately, so the endpoint holds on to the connection until it has taken
+    /// in every one of them, lest it count the connection gone while e.g. its CONNECTION_CLOSE
+    /// is still on its way.
+    Drained {
+        transmits: u64,
+    },
+    Activity(reaper::ConnectionActivity),
+    /// The connection entered a new [`ConnectionPhase`]
+    Phase(ConnectionPhase),
+}
+
+/// Carries [`EndpointEvent`]s from connections to their endpoint
+///
+/// Transmits travel on a channel of their own, which the endpoint drains under a separate bound,
+/// so that a burst of datagrams from one connection can't hold up e.g. another's report that it
+/// has drained, nor the other way around.
+#[derive(Debug, Clone)]
+struct EndpointEventSender {
+    lifecycle: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
+    transmits: mpsc::UnboundedSender<(ConnectionHandle, EndpointEvent)>,
+}
+
+impl EndpointEventSender {
+    /// Fails only if the endpoint driver is gone
+    fn send(
+        &self,
+        event: (ConnectionHandle, EndpointEvent),
+    ) -> Result<(), mpsc::error::SendError<(ConnectionHandle, EndpointEvent)>> {
+        match event.1 {
+            EndpointEvent::Transmit { .. } => self.transmits.send(event),
+            _ => self.lifecycle.send(event),
+        }
+    }
 }
 
 /// Maximum number of datagrams processed in send/recv calls to make before moving on to other processing
@@ -0,0 +1,125 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use proto::{ConnectionHandle, ConnectionStats};
+
+use crate::VarInt;
+
+/// Snapshot of a connection's application-level activity
+///
+/// Reported periodically by each connection's driver to its endpoint, and handed to the policy
+/// installed with [`Endpoint::set_reaper`](crate::Endpoint::set_reaper).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ConnectionActivity {
+    /// Endpoint-local handle of the connection, used to select it in a [`Reap`]
+    pub handle: ConnectionHandle,
+    /// Matches [`Connection::stable_id`](crate::Connection::stable_id) for the same connection
+    pub stable_id: usize,
+    /// The latest socket address of the connection's peer
+    pub remote_address: SocketAddr,
+    /// When the connection was created
+    pub created: Instant,
+    /// When stream data was last sent or received, if ever
+    pub last_stream_activity: Option<Instant>,
+    /// When an application datagram was last sent or received, if ever
+    pub last_datagram_activity: Option<Instant>,
+    /// Total bytes sent in UDP datagrams on this connection
+    pub bytes_sent: u64,
+    /// Total bytes received in UDP datagrams on this connection
+    pub bytes_received: u64,
+    /// When this snapshot was taken
+    pub reported: Instant,
+}
+
+impl ConnectionActivity {
+    /// The most recent stream or datagram activity, or the creation time if there was none
+    pub fn last_activity(&self) -> Instant {
+        self.last_stream_activity
+            .into_iter()
+            .chain(self.last_datagram_activity)
+            .fold(self.created, |a, b| a.max(b))
+    }
+}
+
+/// A connection selected for closing by a reaper policy
+#[derive(Debug, Clone)]
+pub struct Reap {
+    /// The connection to close
+    pub handle: ConnectionHandle,
+    /// Application error code sent to the peer
+    pub error_code: VarInt,
+    /// Reason sent to the peer
+    pub reason: Bytes,
+}
+
+pub(crate) type ReapPolicy = dyn FnMut(&[ConnectionActivity]) -> Vec<Reap> + Send;
+
+/// Tracks the activity of a single connection on behalf of its driver
+#[derive(Debug)]
+pub(crate) struct ActivityTracker {
+    created: Instant,
+    last_stream_activity: Option<Instant>,
+    last_datagram_activity: Option<Instant>,
+    stream_frames: u64,
+    datagram_frames: u64,
+    last_report: Option<Instant>,
+}
+
+impl ActivityTracker {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            created: now,
+            last_stream_activity: None,
+            last_datagram_activity: None,
+            stream_frames: 0,
+            datagram_frames: 0,
+            last_report: None,
+        }
+    }
+
+    /// Fold `stats` into the tracked timestamps, returning a snapshot if one is due
+    pub(crate) fn update(
+        &mut self,
+        now: Instant,
+        handle: ConnectionHandle,
+        stable_id: usize,
+        remote_address: SocketAddr,
+        stats: &ConnectionStats,
+    ) -> Option<ConnectionActivity> {
+        let stream_frames = stats.frame_tx.stream + stats.frame_rx.stream;
+        if stream_frames != self.stream_frames {
+            self.stream_frames = stream_frames;
+            self.last_stream_activity = Some(now);
+        }
+        let datagram_frames = stats.frame_tx.datagram + stats.frame_rx.datagram;
+        if datagram_frames != self.datagram_frames {
+            self.datagram_frames = datagram_frames;
+            self.last_datagram_activity = Some(now);
+        }
+
+        if self.last_report.map_or(false, |x| {
+            now.saturating_duration_since(x) < ACTIVITY_REPORT_INTERVAL
+        }) {
+            return None;
+        }
+        self.last_report = Some(now);
+        Some(ConnectionActivity {
+            handle,
+            stable_id,
+            remote_address,
+            created: self.created,
+            last_stream_activity: self.last_stream_activity,
+            last_datagram_activity: self.last_datagram_activity,
+            bytes_sent: stats.udp_tx.bytes,
+            bytes_received: stats.udp_rx.bytes,
+            reported: now,
+        })
+    }
+}
+
+/// Minimum time between two activity reports from the same connection
+const ACTIVITY_REPORT_INTERVAL: Duration = Duration::from_millis(100);
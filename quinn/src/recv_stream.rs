@@ -76,6 +76,7 @@ pub struct RecvStream {
     is_0rtt: bool,
     all_data_read: bool,
     reset: Option<VarInt>,
+    received_reset: Option<VarInt>,
 }
 
 impl RecvStream {
@@ -86,6 +87,7 @@ impl RecvStream {
             is_0rtt,
             all_data_read: false,
             reset: None,
+            received_reset: None,
         }
     }
 
@@ -256,6 +258,49 @@ impl RecvStream {
         Ok(())
     }
 
+    /// Completes when the stream has been reset by the peer or otherwise closed
+    ///
+    /// Yields `Some` with the reset error code when the stream is reset by the peer. Yields `None`
+    /// when the stream was previously [`stop()`](Self::stop)ed, or when it was finished by the
+    /// peer and all of its data has been read, after which it is no longer meaningful for the
+    /// stream to be reset.
+    ///
+    /// This operation is cancel-safe.
+    pub async fn received_reset(&mut self) -> Result<Option<VarInt>, ResetError> {
+        ReceivedReset { stream: self }.await
+    }
+
+    #[doc(hidden)]
+    pub fn poll_received_reset(
+        &mut self,
+        cx: &mut Context,
+    ) -> Poll<Result<Option<VarInt>, ResetError>> {
+        if let Some(code) = self.reset.or(self.received_reset) {
+            return Poll::Ready(Ok(Some(code)));
+        }
+        if self.all_data_read {
+            return Poll::Ready(Ok(None));
+        }
+
+        let mut conn = self.conn.state.lock("RecvStream::poll_received_reset");
+        if self.is_0rtt {
+            conn.check_0rtt()
+                .map_err(|()| ResetError::ZeroRttRejected)?;
+        }
+        match conn.inner.recv_stream(self.stream).received_reset() {
+            Err(_) => Poll::Ready(Ok(None)),
+            Ok(Some(error_code)) => Poll::Ready(Ok(Some(error_code))),
+            Ok(None) => {
+                if let Some(ref x) = conn.error {
+                    return Poll::Ready(Err(ResetError::ConnectionLost(x.clone())));
+                }
+                // Resets are signaled to readers, so share their waker slot
+                conn.blocked_readers.insert(self.stream, cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
     /// Check if this stream has been opened during 0-RTT.
     ///
     /// In which case any non-idempotent request should be considered dangerous at the application
@@ -328,6 +373,7 @@ impl RecvStream {
             ReadStatus::Failed(read, Reset(error_code)) => match read {
                 None => {
                     self.all_data_read = true;
+                    self.received_reset = Some(error_code);
                     Poll::Ready(Err(ReadError::Reset(error_code)))
                 }
                 done => {
@@ -502,6 +548,38 @@ impl From<ReadError> for io::Error {
     }
 }
 
+/// Errors that arise while waiting for a stream to be reset
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ResetError {
+    /// The connection was lost
+    #[error("connection lost")]
+    ConnectionLost(#[from] ConnectionError),
+    /// This was a 0-RTT stream and the server rejected it
+    ///
+    /// Can only occur on clients for 0-RTT streams, which can be opened using
+    /// [`Connecting::into_0rtt()`].
+    ///
+    /// [`Connecting::into_0rtt()`]: crate::Connecting::into_0rtt()
+    #[error("0-RTT rejected")]
+    ZeroRttRejected,
+}
+
+/// Future produced by [`RecvStream::received_reset()`].
+///
+/// [`RecvStream::received_reset()`]: crate::RecvStream::received_reset
+#[must_use = "futures/streams/sinks do nothing unless you `.await` or poll them"]
+struct ReceivedReset<'a> {
+    stream: &'a mut RecvStream,
+}
+
+impl Future for ReceivedReset<'_> {
+    type Output = Result<Option<VarInt>, ResetError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().stream.poll_received_reset(cx)
+    }
+}
+
 /// Future produced by [`RecvStream::read()`].
 ///
 /// [`RecvStream::read()`]: crate::RecvStream::read
@@ -0,0 +1,80 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Limits the bytes the endpoint sends of its own accord towards each address
+///
+/// Version negotiation, Retry and refusal packets answer datagrams whose source address nobody
+/// has validated, so a flood of spoofed Initial packets could otherwise make the endpoint reflect
+/// traffic at a victim. Each destination address gets a token bucket holding up to a second's
+/// worth of bytes.
+#[derive(Debug, Default)]
+pub(crate) struct ResponseShaper {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// When full buckets were last let go, if ever
+    pruned: Option<Instant>,
+}
+
+impl ResponseShaper {
+    /// Whether a response of `len` bytes may be sent to `addr`, given `rate` bytes per second
+    pub(crate) fn allow(&mut self, addr: IpAddr, len: usize, now: Instant, rate: u64) -> bool {
+        if rate == u64::MAX {
+            return true;
+        }
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&addr) {
+            self.prune(now);
+            if self.buckets.len() >= MAX_BUCKETS {
+                return false;
+            }
+        }
+        let bucket = self.buckets.entry(addr).or_insert(Bucket {
+            tokens: rate,
+            refilled: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled);
+        let earned = elapsed.as_nanos().saturating_mul(rate.into()) / 1_000_000_000;
+        // Left alone until a whole token is earned, so frequent calls don't lose time
+        if earned > 0 {
+            let earned = earned.min(rate.into()) as u64;
+            bucket.tokens = bucket.tokens.saturating_add(earned).min(rate);
+            bucket.refilled = now;
+        }
+        if bucket.tokens < len as u64 {
+            return false;
+        }
+        bucket.tokens -= len as u64;
+        true
+    }
+
+    /// Let go of buckets that have had time to refill, at most once per refill period
+    fn prune(&mut self, now: Instant) {
+        if self
+            .pruned
+            .map_or(false, |x| now.saturating_duration_since(x) < REFILL_PERIOD)
+        {
+            return;
+        }
+        self.pruned = Some(now);
+        self.buckets
+            .retain(|_, x| now.saturating_duration_since(x.refilled) < REFILL_PERIOD);
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may be sent before the bucket next refills
+    tokens: u64,
+    /// When `tokens` was last refilled
+    refilled: Instant,
+}
+
+/// How long an emptied bucket takes to fill up again
+const REFILL_PERIOD: Duration = Duration::from_secs(1);
+
+/// Addresses tracked at once
+///
+/// Responses to further addresses are held back until full buckets can be let go, so a flood
+/// spoofing many addresses can't grow the map without bound.
+const MAX_BUCKETS: usize = 64 * 1024;
@@ -2,14 +2,14 @@ use std::{
     fmt::Debug,
     future::Future,
     io::{self, IoSliceMut},
-    net::SocketAddr,
+    net::{SocketAddr, ToSocketAddrs},
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
     time::Instant,
 };
 
-use udp::{RecvMeta, Transmit, UdpState};
+use udp::{LocalDrops, RecvMeta, Transmit, UdpState};
 
 /// Abstracts I/O and timer operations for runtime independence
 pub trait Runtime: Send + Sync + Debug + 'static {
@@ -19,6 +19,51 @@ pub trait Runtime: Send + Sync + Debug + 'static {
     fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>);
     /// Convert `t` into the socket type used by this runtime
     fn wrap_udp_socket(&self, t: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>>;
+    /// Look up the addresses of `host` with `port`, without blocking the calling thread
+    ///
+    /// The default implementation does the blocking lookup on a thread of its own.
+    fn resolve(
+        &self,
+        host: String,
+        port: u16,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>> {
+        let (send, recv) = ::tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            let result = (host.as_str(), port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect());
+            let _ = send.send(result);
+        });
+        Box::pin(async move {
+            recv.await.unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "lookup thread panicked",
+                ))
+            })
+        })
+    }
+
+    /// Open a TCP connection to `addr` from `bind`, without blocking the calling thread
+    ///
+    /// Used by JLS forward connections reaching their upstreams
+    /// [over TCP](proto::JlsUpstreamTransport::Tcp), or
+    /// [through a SOCKS5 proxy](proto::EndpointConfig::jls_upstream_socks5). The default implementation fails with
+    /// [`Unsupported`](io::ErrorKind::Unsupported).
+    #[cfg(feature = "jls-forward-tcp")]
+    fn connect_tcp(
+        &self,
+        bind: SocketAddr,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Box<dyn AsyncTcpStream>>> + Send>> {
+        let _ = (bind, addr);
+        Box::pin(async {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "runtime can't open TCP connections",
+            ))
+        })
+    }
 }
 
 /// Abstract implementation of an async timer for runtime independence
@@ -33,6 +78,10 @@ pub trait AsyncTimer: Send + Debug + 'static {
 pub trait AsyncUdpSocket: Send + Debug + 'static {
     /// Send UDP datagrams from `transmits`, or register to be woken if sending may succeed in the
     /// future
+    ///
+    /// Errors of kind [`PermissionDenied`](io::ErrorKind::PermissionDenied), and `ENOBUFS`, are
+    /// taken to be transient: the endpoint keeps the transmits and tries again after a few
+    /// milliseconds. Any other error stops the endpoint.
     fn poll_send(
         &self,
         state: &UdpState,
@@ -48,9 +97,25 @@ pub trait AsyncUdpSocket: Send + Debug + 'static {
         meta: &mut [RecvMeta],
     ) -> Poll<io::Result<usize>>;
 
+    /// Check whether datagrams can currently be sent, or register to be woken when they may be
+    ///
+    /// The default implementation always reports the socket as ready.
+    fn poll_send_ready(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let _ = cx;
+        Poll::Ready(Ok(()))
+    }
+
     /// Look up the local IP address and port used by this socket
     fn local_addr(&self) -> io::Result<SocketAddr>;
 
+    /// Datagrams the operating system is known to have dropped on this socket
+    ///
+    /// Sampled periodically by the endpoint driver, which warns when they increase, and reported in
+    /// [`EndpointStats`](crate::EndpointStats). The default implementation reports none.
+    fn local_drops(&self) -> LocalDrops {
+        LocalDrops::default()
+    }
+
     /// Whether datagrams might get fragmented into multiple parts
     ///
     /// Sockets should prevent this for best performance. See e.g. the `IPV6_DONTFRAG` socket
@@ -60,6 +125,18 @@ pub trait AsyncUdpSocket: Send + Debug + 'static {
     }
 }
 
+/// Abstract implementation of a TCP stream for runtime independence
+#[cfg(feature = "jls-forward-tcp")]
+pub trait AsyncTcpStream: Send + Debug + 'static {
+    /// Read bytes into `buf`, or register to be woken if reading may succeed in the future
+    ///
+    /// Returns `Ok(0)` once the peer has closed the stream.
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+
+    /// Write bytes from `buf`, or register to be woken if writing may succeed in the future
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>>;
+}
+
 /// Automatically select an appropriate runtime from those enabled at compile time
 ///
 /// If `runtime-tokio` is enabled and this function is called from within a Tokio runtime context,
@@ -1,6 +1,7 @@
 use std::{
     future::Future,
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
     time::Instant,
@@ -23,6 +24,18 @@ impl Runtime for AsyncStdRuntime {
         async_std::task::spawn(future);
     }
 
+    fn resolve(
+        &self,
+        host: String,
+        port: u16,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>> {
+        Box::pin(async move {
+            async_std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), port))
+                .await
+                .map(|addrs| addrs.collect())
+        })
+    }
+
     fn wrap_udp_socket(&self, sock: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
         udp::UdpSocketState::configure((&sock).into())?;
         Ok(Box::new(UdpSocket {
@@ -77,6 +90,10 @@ impl AsyncUdpSocket for UdpSocket {
         }
     }
 
+    fn poll_send_ready(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.io.poll_writable(cx)
+    }
+
     fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
         self.io.as_ref().local_addr()
     }
@@ -84,4 +101,8 @@ impl AsyncUdpSocket for UdpSocket {
     fn may_fragment(&self) -> bool {
         udp::may_fragment()
     }
+
+    fn local_drops(&self) -> udp::LocalDrops {
+        self.inner.local_drops()
+    }
 }
@@ -1,6 +1,7 @@
 use std::{
     future::Future,
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
     time::Instant,
@@ -11,12 +12,30 @@ use tokio::{
     time::{sleep_until, Sleep},
 };
 
+#[cfg(feature = "jls-forward-tcp")]
+use super::AsyncTcpStream;
 use super::{AsyncTimer, AsyncUdpSocket, Runtime};
 
 /// A Quinn runtime for Tokio
 #[derive(Debug)]
 pub struct TokioRuntime;
 
+impl TokioRuntime {
+    /// Check whether `socket` could be passed to [`wrap_udp_socket()`](Runtime::wrap_udp_socket)
+    ///
+    /// Sockets are registered with the reactor of the current Tokio runtime, so this fails outside
+    /// of one, as well as for sockets that aren't bound.
+    pub fn supports_socket(&self, socket: &std::net::UdpSocket) -> io::Result<()> {
+        if tokio::runtime::Handle::try_current().is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "not within a Tokio runtime",
+            ));
+        }
+        socket.local_addr().map(|_| ())
+    }
+}
+
 impl Runtime for TokioRuntime {
     fn new_timer(&self, t: Instant) -> Pin<Box<dyn AsyncTimer>> {
         Box::pin(sleep_until(t.into()))
@@ -26,13 +45,69 @@ impl Runtime for TokioRuntime {
         tokio::spawn(future);
     }
 
+    fn resolve(
+        &self,
+        host: String,
+        port: u16,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>> {
+        Box::pin(async move {
+            tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map(|addrs| addrs.collect())
+        })
+    }
+
     fn wrap_udp_socket(&self, sock: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        self.supports_socket(&sock)?;
         udp::UdpSocketState::configure((&sock).into())?;
         Ok(Box::new(UdpSocket {
             io: tokio::net::UdpSocket::from_std(sock)?,
             inner: udp::UdpSocketState::new(),
         }))
     }
+
+    #[cfg(feature = "jls-forward-tcp")]
+    fn connect_tcp(
+        &self,
+        bind: SocketAddr,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Box<dyn AsyncTcpStream>>> + Send>> {
+        Box::pin(async move {
+            let socket = match bind {
+                SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+            };
+            socket.bind(bind)?;
+            // A dual-stack socket reaches IPv4 addresses by their mapped form
+            let addr = match (bind, addr) {
+                (SocketAddr::V6(_), SocketAddr::V4(x)) => {
+                    SocketAddr::new(x.ip().to_ipv6_mapped().into(), x.port())
+                }
+                _ => addr,
+            };
+            let stream = socket.connect(addr).await?;
+            // Datagrams are relayed as they come, not held back to fill segments
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream) as Box<dyn AsyncTcpStream>)
+        })
+    }
+}
+
+#[cfg(feature = "jls-forward-tcp")]
+impl AsyncTcpStream for tokio::net::TcpStream {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut buf = tokio::io::ReadBuf::new(buf);
+        ready!(tokio::io::AsyncRead::poll_read(
+            Pin::new(self),
+            cx,
+            &mut buf
+        ))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        tokio::io::AsyncWrite::poll_write(Pin::new(self), cx, buf)
+    }
 }
 
 impl AsyncTimer for Sleep {
@@ -85,6 +160,10 @@ impl AsyncUdpSocket for UdpSocket {
         }
     }
 
+    fn poll_send_ready(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.io.poll_send_ready(cx)
+    }
+
     fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
         self.io.local_addr()
     }
@@ -92,4 +171,8 @@ impl AsyncUdpSocket for UdpSocket {
     fn may_fragment(&self) -> bool {
         udp::may_fragment()
     }
+
+    fn local_drops(&self) -> udp::LocalDrops {
+        self.inner.local_drops()
+    }
 }
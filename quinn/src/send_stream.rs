@@ -27,6 +27,7 @@ pub struct SendStream {
     stream: StreamId,
     is_0rtt: bool,
     finishing: Option<oneshot::Receiver<Option<WriteError>>>,
+    reset: bool,
 }
 
 impl SendStream {
@@ -36,6 +37,7 @@ impl SendStream {
             stream,
             is_0rtt,
             finishing: None,
+            reset: false,
         }
     }
 
@@ -173,6 +175,7 @@ impl SendStream {
             return Ok(());
         }
         conn.inner.send_stream(self.stream).reset(error_code)?;
+        self.reset = true;
         conn.wake();
         Ok(())
     }
@@ -196,24 +199,86 @@ impl SendStream {
         Ok(conn.inner.send_stream(self.stream).priority()?)
     }
 
-    /// Completes if/when the peer stops the stream, yielding the error code
-    pub async fn stopped(&mut self) -> Result<VarInt, StoppedError> {
+    /// Send buffered data without waiting to coalesce it with later writes
+    ///
+    /// Only matters when
+    /// [`TransportConfig::send_coalesce_delay`](crate::TransportConfig::send_coalesce_delay) is
+    /// set. Exposed through the `poll_flush` methods of the `AsyncWrite` implementations.
+    #[cfg(any(feature = "futures-io", feature = "runtime-tokio"))]
+    fn request_flush(&mut self) {
+        let mut conn = self.conn.state.lock("SendStream::flush");
+        conn.inner.send_stream(self.stream).flush();
+        conn.wake();
+    }
+
+    /// Completes when the peer stops the stream or reads the stream to completion
+    ///
+    /// Yields `Some` with the stop error code if the peer stops the stream. Yields `None` if the
+    /// local side [`finish()`](Self::finish)es the stream and then the peer acknowledges receipt
+    /// of all stream data (although not necessarily the processing of it), after which the peer
+    /// closing the stream is no longer meaningful. Also yields `None` once the stream has been
+    /// [`reset()`](Self::reset) locally.
+    pub async fn stopped(&mut self) -> Result<Option<VarInt>, StoppedError> {
         Stopped { stream: self }.await
     }
 
     #[doc(hidden)]
-    pub fn poll_stopped(&mut self, cx: &mut Context) -> Poll<Result<VarInt, StoppedError>> {
+    pub fn poll_stopped(&mut self, cx: &mut Context) -> Poll<Result<Option<VarInt>, StoppedError>> {
         let mut conn = self.conn.state.lock("SendStream::poll_stopped");
 
         if self.is_0rtt {
             conn.check_0rtt()
                 .map_err(|()| StoppedError::ZeroRttRejected)?;
         }
+        if self.reset {
+            return Poll::Ready(Ok(None));
+        }
+
+        match conn.inner.send_stream(self.stream).stopped() {
+            // The stream has been finished and fully acknowledged
+            Err(_) => Poll::Ready(Ok(None)),
+            Ok(Some(error_code)) => Poll::Ready(Ok(Some(error_code))),
+            Ok(None) => {
+                if let Some(ref x) = conn.error {
+                    return Poll::Ready(Err(StoppedError::ConnectionLost(x.clone())));
+                }
+                conn.stopped.insert(self.stream, cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Completes when the peer has acknowledged all data written to the stream, including the FIN
+    ///
+    /// Unlike [`finish()`](Self::finish), this does not itself finish the stream, so it only
+    /// completes once the stream has been finished by some other means. Fails with
+    /// [`WriteError::Stopped`] if the peer stops the stream first, or with
+    /// [`WriteError::UnknownStream`] if the stream was [`reset()`](Self::reset) locally.
+    pub async fn acked(&mut self) -> Result<(), WriteError> {
+        Acked { stream: self }.await
+    }
+
+    #[doc(hidden)]
+    pub fn poll_acked(&mut self, cx: &mut Context) -> Poll<Result<(), WriteError>> {
+        let mut conn = self.conn.state.lock("SendStream::poll_acked");
+
+        if self.is_0rtt {
+            conn.check_0rtt()
+                .map_err(|()| WriteError::ZeroRttRejected)?;
+        }
+        if self.reset {
+            return Poll::Ready(Err(WriteError::UnknownStream));
+        }
 
         match conn.inner.send_stream(self.stream).stopped() {
-            Err(_) => Poll::Ready(Err(StoppedError::UnknownStream)),
-            Ok(Some(error_code)) => Poll::Ready(Ok(error_code)),
+            // Send stream state is only discarded once all data has been acknowledged
+            Err(_) => Poll::Ready(Ok(())),
+            Ok(Some(error_code)) => Poll::Ready(Err(WriteError::Stopped(error_code))),
             Ok(None) => {
+                if let Some(ref x) = conn.error {
+                    return Poll::Ready(Err(WriteError::ConnectionLost(x.clone())));
+                }
+                // Woken on both `StreamEvent::Finished` and `StreamEvent::Stopped`
                 conn.stopped.insert(self.stream, cx.waker().clone());
                 Poll::Pending
             }
@@ -233,6 +298,7 @@ impl futures_io::AsyncWrite for SendStream {
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().request_flush();
         Poll::Ready(Ok(()))
     }
 
@@ -252,6 +318,7 @@ impl tokio::io::AsyncWrite for SendStream {
     }
 
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().request_flush();
         Poll::Ready(Ok(()))
     }
 
@@ -308,13 +375,27 @@ struct Stopped<'a> {
 }
 
 impl Future for Stopped<'_> {
-    type Output = Result<VarInt, StoppedError>;
+    type Output = Result<Option<VarInt>, StoppedError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         self.get_mut().stream.poll_stopped(cx)
     }
 }
 
+/// Future produced by `SendStream::acked`
+#[must_use = "futures/streams/sinks do nothing unless you `.await` or poll them"]
+struct Acked<'a> {
+    stream: &'a mut SendStream,
+}
+
+impl Future for Acked<'_> {
+    type Output = Result<(), WriteError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().stream.poll_acked(cx)
+    }
+}
+
 /// Future produced by [`SendStream::write()`].
 ///
 /// [`SendStream::write()`]: crate::SendStream::write
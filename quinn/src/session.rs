@@ -0,0 +1,182 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{
+    client::{ClientSessionStore, Resumption, Tls12ClientSessionValue, Tls13ClientSessionValue},
+    NamedGroup, ServerName,
+};
+
+/// In-process storage for the TLS session tickets a client uses to resume sessions and send 0-RTT
+/// data
+///
+/// Tickets are keyed by the server name passed to [`Endpoint::connect`](crate::Endpoint::connect).
+/// Sharing one store between several client configurations, or between endpoints, lets new
+/// connections resume sessions established by any of them. Install a store with
+/// [`set_session_store`].
+///
+/// Tickets don't outlive the process. rustls keeps them as opaque values with no way to serialize
+/// them, so no file-backed store is provided and none can be implemented on this trait; a store
+/// only carries resumption over to endpoints created later by the same process.
+pub trait SessionStore: Send + Sync {
+    /// Store a ticket issued by `server_name`
+    fn put(&self, server_name: &str, ticket: Tls13ClientSessionValue);
+
+    /// Remove and return a ticket issued by `server_name`, if any
+    ///
+    /// Tickets are single-use: a ticket returned here must not be returned again. Expired
+    /// tickets are discarded by the TLS stack, so stores need not check expiry themselves.
+    fn get(&self, server_name: &str) -> Option<Tls13ClientSessionValue>;
+}
+
+/// Use `store` for the session tickets of connections made with `config`
+///
+/// Replaces the per-configuration cache rustls uses by default. Tickets stay in memory either way.
+pub fn set_session_store(config: &mut rustls::ClientConfig, store: Arc<dyn SessionStore>) {
+    config.resumption = Resumption::store(Arc::new(StoreAdapter {
+        store,
+        kx_hints: Mutex::new(KxHints::default()),
+    }));
+}
+
+/// An in-memory [`SessionStore`] holding tickets for a bounded number of servers
+pub struct MemorySessionStore {
+    state: Mutex<MemoryState>,
+}
+
+impl MemorySessionStore {
+    /// Create a store that remembers tickets for up to `max_servers` servers
+    ///
+    /// When full, the tickets of the least recently stored server are evicted.
+    pub fn new(max_servers: usize) -> Self {
+        Self {
+            state: Mutex::new(MemoryState {
+                tickets: HashMap::new(),
+                order: VecDeque::new(),
+                max_servers,
+            }),
+        }
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn put(&self, server_name: &str, ticket: Tls13ClientSessionValue) {
+        let mut state = self.state.lock().unwrap();
+        if state.max_servers == 0 {
+            return;
+        }
+        if let Some(i) = state.order.iter().position(|x| x == server_name) {
+            state.order.remove(i);
+        } else if state.order.len() == state.max_servers {
+            let oldest = state.order.pop_front().unwrap();
+            state.tickets.remove(&oldest);
+        }
+        state.order.push_back(server_name.to_owned());
+        let tickets = state.tickets.entry(server_name.to_owned()).or_default();
+        if tickets.len() == MAX_TICKETS_PER_SERVER {
+            tickets.pop_front();
+        }
+        tickets.push_back(ticket);
+    }
+
+    fn get(&self, server_name: &str) -> Option<Tls13ClientSessionValue> {
+        let mut state = self.state.lock().unwrap();
+        // Newest first, as older tickets are closer to expiry
+        state.tickets.get_mut(server_name)?.pop_back()
+    }
+}
+
+impl fmt::Debug for MemorySessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("MemorySessionStore")
+            .field("servers", &state.order.len())
+            .field("max_servers", &state.max_servers)
+            .finish()
+    }
+}
+
+struct MemoryState {
+    tickets: HashMap<String, VecDeque<Tls13ClientSessionValue>>,
+    /// Server names in the order their tickets were last stored
+    order: VecDeque<String>,
+    max_servers: usize,
+}
+
+/// Exposes a [`SessionStore`] to rustls
+///
+/// QUIC only uses TLS 1.3, so TLS 1.2 sessions are never stored.
+struct StoreAdapter {
+    store: Arc<dyn SessionStore>,
+    kx_hints: Mutex<KxHints>,
+}
+
+impl ClientSessionStore for StoreAdapter {
+    fn set_kx_hint(&self, server_name: &ServerName, group: NamedGroup) {
+        let server_name = key(server_name);
+        let mut hints = self.kx_hints.lock().unwrap();
+        if hints.groups.insert(server_name.clone(), group).is_some() {
+            return;
+        }
+        if hints.order.len() == MAX_KX_HINTS {
+            let oldest = hints.order.pop_front().unwrap();
+            hints.groups.remove(&oldest);
+        }
+        hints.order.push_back(server_name);
+    }
+
+    fn kx_hint(&self, server_name: &ServerName) -> Option<NamedGroup> {
+        self.kx_hints
+            .lock()
+            .unwrap()
+            .groups
+            .get(&key(server_name))
+            .copied()
+    }
+
+    fn set_tls12_session(&self, _: &ServerName, _: Tls12ClientSessionValue) {}
+
+    fn tls12_session(&self, _: &ServerName) -> Option<Tls12ClientSessionValue> {
+        None
+    }
+
+    fn remove_tls12_session(&self, _: &ServerName) {}
+
+    fn insert_tls13_ticket(&self, server_name: &ServerName, value: Tls13ClientSessionValue) {
+        self.store.put(&key(server_name), value);
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName) -> Option<Tls13ClientSessionValue> {
+        self.store.get(&key(server_name))
+    }
+}
+
+impl fmt::Debug for StoreAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreAdapter").finish_non_exhaustive()
+    }
+}
+
+/// The key exchange group each server last used, for a bounded number of servers
+#[derive(Default)]
+struct KxHints {
+    groups: HashMap<String, NamedGroup>,
+    /// Server names in the order they were first hinted
+    order: VecDeque<String>,
+}
+
+fn key(server_name: &ServerName) -> String {
+    match server_name {
+        ServerName::DnsName(name) => name.as_ref().to_owned(),
+        ServerName::IpAddress(ip) => ip.to_string(),
+        other => format!("{other:?}"),
+    }
+}
+
+/// Tickets kept per server, matching rustls' own in-memory cache
+const MAX_TICKETS_PER_SERVER: usize = 8;
+
+/// Servers whose key exchange group is remembered, so hints can't grow without bound
+const MAX_KX_HINTS: usize = 256;
@@ -0,0 +1,141 @@
+use std::{net::SocketAddr, time::Duration};
+
+use proto::EndpointStats;
+
+use crate::JlsUpstreamStats;
+
+/// Point-in-time view of an endpoint's state, for debugging
+///
+/// Returned by [`Endpoint::debug_snapshot()`](crate::Endpoint::debug_snapshot). With the `serde`
+/// feature enabled, it can be serialized, e.g. to dump it as JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct EndpointSnapshot {
+    /// The address the endpoint's socket is bound to, if it could be determined
+    pub local_addr: Option<SocketAddr>,
+    /// Whether the socket is an IPv6 socket
+    pub ipv6: bool,
+    /// Whether a server configuration is installed
+    pub accepting: bool,
+    /// Maximum size of received UDP payloads
+    pub max_udp_payload_size: u64,
+    /// Capacity of each connection's queue, in datagrams
+    pub max_queued_datagrams: usize,
+    /// Live handles to the endpoint, excluding its driver
+    pub handles: usize,
+    /// Connections known to the endpoint, accepted or not
+    pub connections: Vec<ConnectionSnapshot>,
+    /// Incoming connections waiting to be accepted by the application
+    pub incoming: usize,
+    /// Datagrams waiting to be sent on the socket
+    pub outgoing_datagrams: usize,
+    /// Total size of the datagrams waiting to be sent
+    pub outgoing_bytes: usize,
+    /// Budget for receiving datagrams in each turn of the driver
+    pub recv_work: WorkLimitSnapshot,
+    /// Budget for sending datagrams in each turn of the driver
+    pub send_work: WorkLimitSnapshot,
+    /// Budget for handling datagrams relayed from JLS upstreams in each turn of the driver
+    pub forward_work: WorkLimitSnapshot,
+    /// Clients currently forwarded to a JLS upstream
+    pub forwards: Vec<ForwardSnapshot>,
+    /// Traffic relayed to each JLS upstream
+    pub upstreams: Vec<(SocketAddr, JlsUpstreamStats)>,
+    /// Counts of incoming datagrams the endpoint dropped
+    pub stats: EndpointStats,
+}
+
+/// A connection, as last seen by its endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ConnectionSnapshot {
+    /// The connection's [`ConnectionHandle`](crate::ConnectionHandle)
+    pub handle: usize,
+    /// The connection's [`Connection::stable_id()`](crate::Connection::stable_id), unless it's an
+    /// incoming connection the application hasn't accepted yet
+    pub stable_id: Option<usize>,
+    /// The peer's latest known address
+    pub remote_address: SocketAddr,
+    /// Whether the connection has been handed to the application
+    ///
+    /// Until then, it's driven by the endpoint itself.
+    pub accepted: bool,
+    /// Events queued for the connection's driver
+    pub queued_events: usize,
+    /// How far the connection has progressed, as last reported to the endpoint
+    pub phase: ConnectionPhase,
+    /// Time since the connection was created
+    pub age: Duration,
+}
+
+/// Coarse lifecycle state of a connection, as tracked by its endpoint
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionPhase {
+    /// The handshake is still in progress
+    Handshaking,
+    /// The handshake has completed and the connection hasn't been closed
+    Established,
+    /// The connection has been closed and is waiting for its peer to notice
+    ///
+    /// The endpoint forgets the connection once it's drained.
+    Draining,
+}
+
+impl ConnectionPhase {
+    pub(crate) fn of(conn: &proto::Connection) -> Self {
+        if conn.is_closed() {
+            Self::Draining
+        } else if conn.is_handshaking() {
+            Self::Handshaking
+        } else {
+            Self::Established
+        }
+    }
+}
+
+/// A client forwarded to a JLS upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ForwardSnapshot {
+    /// The client's address
+    pub remote_address: SocketAddr,
+    /// The upstream's address
+    pub upstream_address: SocketAddr,
+    /// Times the client was moved on to an
+    /// [alternate upstream](crate::EndpointConfig::jls_upstream_alternates)
+    pub failovers: u32,
+    /// Datagrams from the client waiting to be relayed to the upstream
+    pub queued_datagrams: usize,
+    /// Aggregate size of the datagrams waiting to be relayed to the upstream
+    pub queued_bytes: usize,
+    /// Bytes sent to the upstream on the client's behalf
+    pub bytes_to_upstream: u64,
+    /// Bytes from the upstream relayed back to the client
+    pub bytes_from_upstream: u64,
+    /// Datagrams from the client relayed before the upstream first answered
+    ///
+    /// A client retransmitting its first flight over and over suggests the upstream, or the path to
+    /// it, is broken.
+    pub unanswered_datagrams: u32,
+    /// How long the upstream took to first answer, if it has
+    pub first_response: Option<Duration>,
+    /// Whether the upstream has taken part in the client's handshake
+    pub established: bool,
+    /// Time since traffic was last relayed in either direction
+    pub idle: Duration,
+}
+
+/// How much work the endpoint driver does in one go
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct WorkLimitSnapshot {
+    /// The time a turn is meant to take
+    pub cycle_time: Duration,
+    /// Items currently allowed per turn, as estimated from previous turns
+    pub allowed: usize,
+}
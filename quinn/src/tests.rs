@@ -222,21 +222,82 @@ async fn accept_after_close() {
     assert!(receiver.open_uni().await.is_err());
 }
 
+#[tokio::test]
+async fn drained_before_accept() {
+    let _guard = subscribe();
+    let endpoint = endpoint();
+
+    let sender = endpoint
+        .connect(endpoint.local_addr().unwrap(), "localhost")
+        .unwrap()
+        .await
+        .expect("connect");
+    sender.close(42u32.into(), b"bye");
+    drop(sender);
+
+    // The incoming connection finishes draining without ever being accepted
+    tokio::time::timeout(Duration::from_secs(5), endpoint.wait_idle())
+        .await
+        .expect("connection never drained");
+
+    // Nothing of it is left to accept
+    assert_eq!(endpoint.debug_snapshot().incoming, 0);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), endpoint.accept())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn unaccepted_connections_spawn_nothing() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let server_config = crate::ServerConfig::with_single_cert(vec![cert.clone()], key).unwrap();
+    let runtime = Arc::new(CountingRuntime::default());
+    let server = Endpoint::new(
+        Default::default(),
+        Some(server_config),
+        UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap(),
+        runtime.clone(),
+    )
+    .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(trusting(&cert)));
+    let spawned = runtime.spawned();
+
+    // Handshakes complete without the server accepting, and cost it no tasks
+    let server_addr = server.local_addr().unwrap();
+    let client = &client;
+    let connect = || async move {
+        client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap()
+    };
+    let conns = tokio::join!(connect(), connect(), connect());
+    assert_eq!(server.debug_snapshot().incoming, 3);
+    assert_eq!(runtime.spawned(), spawned);
+
+    // Only accepting a connection gives it a driver of its own
+    let _conn = server.accept().await.unwrap().await.unwrap();
+    assert_eq!(runtime.spawned(), spawned + 1);
+    drop(conns);
+}
+
 /// Construct an endpoint suitable for connecting to itself
 fn endpoint() -> Endpoint {
     endpoint_with_config(TransportConfig::default())
 }
 
 fn endpoint_with_config(transport_config: TransportConfig) -> Endpoint {
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-    let key = rustls::PrivateKey(cert.serialize_private_key_der());
-    let cert = rustls::Certificate(cert.serialize_der().unwrap());
+    let (cert, key) = self_signed_cert();
     let transport_config = Arc::new(transport_config);
     let mut server_config = crate::ServerConfig::with_single_cert(vec![cert.clone()], key).unwrap();
     server_config.transport_config(transport_config.clone());
 
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(&cert).unwrap();
+    let roots = trusting(&cert);
     let mut endpoint = Endpoint::server(
         server_config,
         SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
@@ -249,6 +310,61 @@ fn endpoint_with_config(transport_config: TransportConfig) -> Endpoint {
     endpoint
 }
 
+/// A self-signed certificate for `localhost` and its private key
+fn self_signed_cert() -> (rustls::Certificate, rustls::PrivateKey) {
+    self_signed_cert_for(&["localhost"])
+}
+
+fn self_signed_cert_for(names: &[&str]) -> (rustls::Certificate, rustls::PrivateKey) {
+    let names = names.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    let cert = rcgen::generate_simple_self_signed(names).unwrap();
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    (rustls::Certificate(cert.serialize_der().unwrap()), key)
+}
+
+/// Roots trusting only `cert`
+fn trusting(cert: &rustls::Certificate) -> rustls::RootCertStore {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    roots
+}
+
+/// TLS 1.3 server crypto presenting `cert`
+fn server_crypto(cert: rustls::Certificate, key: rustls::PrivateKey) -> rustls::ServerConfig {
+    rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap()
+}
+
+/// Server crypto authenticating JLS clients as `user_pwd`/`user_iv`, and forwarding everyone else
+/// to `upstream`
+fn jls_server_crypto(
+    cert: rustls::Certificate,
+    key: rustls::PrivateKey,
+    upstream: SocketAddr,
+) -> rustls::ServerConfig {
+    let mut crypto = server_crypto(cert, key);
+    crypto.jls_config =
+        rustls::JlsServerConfig::new("user_pwd", "user_iv", &format!("https://{upstream}"))
+            .unwrap();
+    crypto
+}
+
+/// Client crypto trusting `roots`, which authenticates with JLS as `pwd`/`iv`
+fn jls_client_crypto(roots: rustls::RootCertStore, pwd: &str, iv: &str) -> rustls::ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.jls_config = rustls::JlsConfig::new(pwd, iv);
+    crypto
+}
+
 #[tokio::test]
 async fn zero_rtt() {
     let _guard = subscribe();
@@ -337,6 +453,95 @@ async fn zero_rtt() {
     endpoint.wait_idle().await;
 }
 
+#[tokio::test]
+async fn session_store_resumption() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let server_config = crate::ServerConfig::with_single_cert(vec![cert.clone()], key).unwrap();
+    let server = Endpoint::server(
+        server_config,
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    const MSG: &[u8] = b"hello";
+    let (resumed_tx, mut resumed_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for _ in 0..2 {
+            let incoming = server.accept().await.unwrap();
+            let (connection, established) = incoming.into_0rtt().unwrap_or_else(|_| unreachable!());
+            let c = connection.clone();
+            tokio::spawn(async move {
+                while let Ok(mut x) = c.accept_uni().await {
+                    let msg = x.read_to_end(usize::max_value()).await.unwrap();
+                    assert_eq!(msg, MSG);
+                }
+            });
+            established.await;
+            resumed_tx.send(connection.resumed()).unwrap();
+            // Send 1-RTT data so the client waits for the server's NewSessionTicket
+            let mut s = connection.open_uni().await.expect("open_uni");
+            s.write_all(MSG).await.expect("write");
+            let _ = s.finish().await;
+        }
+    });
+
+    let store = Arc::new(crate::MemorySessionStore::new(16));
+    let client = |store: Arc<crate::MemorySessionStore>| {
+        let roots = trusting(&cert);
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.enable_early_data = true;
+        crate::set_session_store(&mut crypto, store);
+        let mut endpoint =
+            Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+        endpoint
+    };
+
+    let endpoint = client(store.clone());
+    let connection = endpoint
+        .connect(server_addr, "localhost")
+        .unwrap()
+        .into_0rtt()
+        .err()
+        .expect("0-RTT succeeded without a ticket")
+        .await
+        .expect("connect");
+    assert!(!connection.resumed());
+    let mut stream = connection.accept_uni().await.expect("incoming streams");
+    let msg = stream
+        .read_to_end(usize::max_value())
+        .await
+        .expect("read_to_end");
+    assert_eq!(msg, MSG);
+    drop((stream, connection));
+    endpoint.wait_idle().await;
+    drop(endpoint);
+    assert!(!resumed_rx.recv().await.unwrap());
+
+    // Tickets can't be persisted, but a store outlives the endpoints that use it
+    info!("reconnecting from a fresh endpoint in the same process");
+    let endpoint = client(store);
+    let (connection, zero_rtt) = endpoint
+        .connect(server_addr, "localhost")
+        .unwrap()
+        .into_0rtt()
+        .unwrap_or_else(|_| panic!("ticket was not stored"));
+    let mut s = connection.open_uni().await.expect("0-RTT open uni");
+    s.write_all(MSG).await.expect("0-RTT write");
+    s.finish().await.expect("0-RTT finish");
+    assert!(zero_rtt.await);
+    assert!(connection.resumed());
+    assert!(resumed_rx.recv().await.unwrap());
+
+    drop(connection);
+    endpoint.wait_idle().await;
+}
+
 #[test]
 fn echo_v6() {
     run_echo(EchoArgs {
@@ -454,8 +659,7 @@ fn run_echo(args: EchoArgs) {
             .unwrap()
         };
 
-        let mut roots = rustls::RootCertStore::empty();
-        roots.add(&cert).unwrap();
+        let roots = trusting(&cert);
         let mut client_crypto = rustls::ClientConfig::builder()
             .with_safe_defaults()
             .with_root_certificates(roots)
@@ -608,12 +812,9 @@ fn rt_threaded() -> Runtime {
 async fn rebind_recv() {
     let _guard = subscribe();
 
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-    let key = rustls::PrivateKey(cert.serialize_private_key_der());
-    let cert = rustls::Certificate(cert.serialize_der().unwrap());
+    let (cert, key) = self_signed_cert();
 
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(&cert).unwrap();
+    let roots = trusting(&cert);
 
     let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
     let mut client_config = ClientConfig::new(Arc::new(
@@ -670,6 +871,61 @@ async fn rebind_recv() {
     server.await.unwrap();
 }
 
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn rebind_src_ip() {
+    let _guard = subscribe();
+
+    let (cert, key) = self_signed_cert();
+
+    let roots = trusting(&cert);
+
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+
+    let server_config = crate::ServerConfig::with_single_cert(vec![cert], key).unwrap();
+    let server = Endpoint::server(
+        server_config,
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Reports the address each request arrived from
+    tokio::spawn(async move {
+        let connection = server.accept().await.unwrap().await.unwrap();
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            recv.read_to_end(0).await.unwrap();
+            let remote = connection.remote_address().to_string();
+            send.write_all(remote.as_bytes()).await.unwrap();
+            send.finish().await.unwrap();
+        }
+    });
+
+    let connection = client
+        .connect(server_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+
+    for ip in [Ipv4Addr::new(127, 0, 0, 2), Ipv4Addr::LOCALHOST] {
+        let ip = IpAddr::V4(ip);
+        client
+            .rebind(UdpSocket::bind(SocketAddr::new(ip, 0)).unwrap())
+            .unwrap();
+        info!(%ip, "rebound");
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        send.finish().await.unwrap();
+        let remote = recv.read_to_end(64).await.unwrap();
+        let remote = str::from_utf8(&remote)
+            .unwrap()
+            .parse::<SocketAddr>()
+            .unwrap();
+        assert_eq!(remote.ip(), ip);
+        assert_eq!(connection.local_ip(), Some(ip));
+    }
+}
+
 #[tokio::test]
 async fn stream_id_flow_control() {
     let _guard = subscribe();
@@ -739,3 +995,5754 @@ async fn two_datagram_readers() {
     assert!(*a == *b"one" || *b == *b"one");
     assert!(*a == *b"two" || *b == *b"two");
 }
+
+#[tokio::test]
+async fn reap_idle_connection() {
+    let _guard = subscribe();
+    let mut cfg = TransportConfig::default();
+    cfg.keep_alive_interval(Some(Duration::from_millis(50)));
+    let endpoint = endpoint_with_config(cfg);
+
+    let (client, server) = tokio::join!(
+        endpoint
+            .connect(endpoint.local_addr().unwrap(), "localhost")
+            .unwrap(),
+        async { endpoint.accept().await.unwrap().await }
+    );
+    let client = client.unwrap();
+    let server = server.unwrap();
+
+    // Only reap the server side, so the client observes the application close
+    let server_id = server.stable_id();
+    endpoint.set_reaper(
+        move |activity| {
+            let now = std::time::Instant::now();
+            activity
+                .iter()
+                .filter(|x| {
+                    x.stable_id == server_id
+                        && now.duration_since(x.last_activity()) > Duration::from_millis(300)
+                })
+                .map(|x| crate::Reap {
+                    handle: x.handle,
+                    error_code: 42u32.into(),
+                    reason: Bytes::from_static(b"idle"),
+                })
+                .collect()
+        },
+        Duration::from_millis(50),
+    );
+
+    // Keep-alives alone must not prevent reaping
+    let err = tokio::time::timeout(Duration::from_secs(5), client.closed())
+        .await
+        .expect("connection was not reaped");
+    match err {
+        crate::ConnectionError::ApplicationClosed(close) => {
+            assert_eq!(close.error_code, 42u32.into());
+            assert_eq!(&close.reason[..], b"idle");
+        }
+        e => panic!("unexpected error: {e}"),
+    }
+    assert!(server.stats().frame_rx.ping > 0);
+}
+
+#[tokio::test]
+async fn stream_close_notifications() {
+    let _guard = subscribe();
+    let endpoint = endpoint();
+
+    let (client, server) = tokio::join!(
+        endpoint
+            .connect(endpoint.local_addr().unwrap(), "localhost")
+            .unwrap(),
+        async { endpoint.accept().await.unwrap().await }
+    );
+    let client = client.unwrap();
+    let server = server.unwrap();
+
+    // STOP_SENDING
+    let mut send = client.open_uni().await.unwrap();
+    send.write_all(b"stop").await.unwrap();
+    let mut recv = server.accept_uni().await.unwrap();
+    recv.stop(7u32.into()).unwrap();
+    assert_eq!(send.stopped().await.unwrap(), Some(7u32.into()));
+    assert_eq!(
+        send.acked().await.unwrap_err(),
+        crate::WriteError::Stopped(7u32.into())
+    );
+
+    // RESET_STREAM
+    let mut send = client.open_uni().await.unwrap();
+    send.write_all(b"reset").await.unwrap();
+    let mut recv = server.accept_uni().await.unwrap();
+    send.reset(9u32.into()).unwrap();
+    assert_eq!(send.stopped().await.unwrap(), None);
+    assert_eq!(recv.received_reset().await.unwrap(), Some(9u32.into()));
+
+    // Clean finish
+    let mut send = client.open_uni().await.unwrap();
+    send.write_all(b"finish").await.unwrap();
+    let mut recv = server.accept_uni().await.unwrap();
+    let (finished, read) = tokio::join!(
+        async {
+            send.finish().await.unwrap();
+            send.acked().await.unwrap();
+            send.stopped().await.unwrap()
+        },
+        async {
+            let data = recv.read_to_end(usize::MAX).await.unwrap();
+            (data, recv.received_reset().await.unwrap())
+        }
+    );
+    assert_eq!(finished, None);
+    assert_eq!(read, (b"finish".to_vec(), None));
+}
+
+#[tokio::test]
+async fn ping_rtt() {
+    let _guard = subscribe();
+    let endpoint = endpoint();
+
+    let (client, server) = tokio::join!(
+        endpoint
+            .connect(endpoint.local_addr().unwrap(), "localhost")
+            .unwrap(),
+        async { endpoint.accept().await.unwrap().await }
+    );
+    let client = client.unwrap();
+    let _server = server.unwrap();
+
+    // Concurrent pings share a single sample
+    let (a, b) = tokio::join!(client.ping(), client.ping());
+    let rtt = a.unwrap();
+    assert_eq!(rtt, b.unwrap());
+    assert!(rtt < Duration::from_secs(1));
+
+    client.close(0u32.into(), b"done");
+    assert_eq!(
+        client.ping().await.unwrap_err(),
+        crate::ConnectionError::LocallyClosed
+    );
+}
+
+#[tokio::test]
+async fn connect_racing() {
+    let _guard = subscribe();
+    let endpoint = endpoint();
+    let config = endpoint.default_client_config.clone().unwrap();
+
+    // Nothing will ever answer on this socket
+    let dead = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let addrs = vec![dead.local_addr().unwrap(), endpoint.local_addr().unwrap()];
+
+    let start = Instant::now();
+    let (client, server) =
+        tokio::join!(endpoint.connect_racing(config, addrs, "localhost"), async {
+            endpoint.accept().await.unwrap().await
+        });
+    let client = client.unwrap();
+    let server = server.unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(client.remote_address(), endpoint.local_addr().unwrap());
+
+    // The losing attempt must not linger once the winner is gone
+    client.close(0u32.into(), b"done");
+    drop(server);
+    tokio::time::timeout(Duration::from_secs(10), endpoint.wait_idle())
+        .await
+        .expect("connection state leaked");
+
+    assert_eq!(
+        endpoint
+            .connect_racing(
+                endpoint.default_client_config.clone().unwrap(),
+                Vec::new(),
+                "localhost"
+            )
+            .await
+            .unwrap_err(),
+        crate::ConnectRacingError::NoAddresses
+    );
+}
+
+#[tokio::test]
+async fn jls_upstream_stats() {
+    let _guard = subscribe();
+
+    // Stands in for an upstream that unauthenticated clients get forwarded to, answering the
+    // first datagram from each client with `reply_len` bytes
+    async fn upstream(reply_len: usize) -> SocketAddr {
+        let socket = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 65536];
+            let mut seen = Vec::new();
+            while let Ok((_, client)) = socket.recv_from(&mut buf).await {
+                if !seen.contains(&client) {
+                    seen.push(client);
+                    socket.send_to(&vec![0; reply_len], client).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+    let upstream_a = upstream(100).await;
+    let upstream_b = upstream(300).await;
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+
+    let mut server_crypto = jls_server_crypto(cert, key, upstream_a);
+    server_crypto
+        .jls_config
+        .push_sni("b.example", &format!("https://{}", upstream_b))
+        .unwrap();
+    // The upstreams tell clients apart by the address they're relayed from
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_socket_per_client(true);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Clients without JLS credentials, which the server forwards by SNI. Each needs its own
+    // endpoint, as forwarding is keyed by the client's address.
+    let mut clients = Vec::new();
+    for server_name in ["a.example", "a.example", "b.example"] {
+        let mut client =
+            Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+        client.set_default_client_config(ClientConfig::with_root_certificates(roots.clone()));
+        let connecting = client.connect(server_addr, server_name).unwrap();
+        clients.push((client, connecting));
+    }
+
+    let stats = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let stats = server.jls_upstream_stats();
+            let relayed = |addr| stats.get(&addr).map_or(0, |x| x.bytes_from_upstream);
+            if relayed(upstream_a) == 200 && relayed(upstream_b) == 300 {
+                break stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("upstream replies not relayed");
+
+    let a = stats[&upstream_a];
+    let b = stats[&upstream_b];
+    assert_eq!(a.active_mappings, 2);
+    assert_eq!(b.active_mappings, 1);
+    // Every client's Initial is padded to at least 1200 bytes
+    assert!(a.bytes_to_upstream >= 2 * 1200);
+    assert!(b.bytes_to_upstream >= 1200);
+    assert_eq!(a.setup_failures, 0);
+    assert_eq!(b.setup_failures, 0);
+}
+
+#[tokio::test]
+async fn jls_forward_gro() {
+    let _guard = subscribe();
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    // Short-header packets for unknown connections, which the server would only answer with a
+    // stateless reset
+    let junk = || {
+        let mut segment = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut segment);
+        segment[0] = 0x40;
+        segment
+    };
+    let mapped = "[::1]:40001".parse().unwrap();
+    let unmapped = "[::1]:40002".parse().unwrap();
+    let mut mapped_buf = initial;
+    let mut unmapped_buf = Vec::new();
+    for _ in 0..3 {
+        mapped_buf.extend(junk());
+    }
+    for _ in 0..4 {
+        unmapped_buf.extend(junk());
+    }
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        Box::new(GroSocket::new(
+            "[::1]:4433".parse().unwrap(),
+            vec![(mapped, len, mapped_buf), (unmapped, len, unmapped_buf)],
+        )),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+
+    // The Initial and the three segments coalesced with it
+    let mut buf = vec![0; 65536];
+    for _ in 0..4 {
+        tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+            .await
+            .expect("segment not forwarded")
+            .unwrap();
+    }
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), upstream.recv_from(&mut buf))
+            .await
+            .is_err(),
+        "unmapped client's segments forwarded"
+    );
+
+    // Only the unmapped client's segments reached the QUIC endpoint
+    assert_eq!(server.stats().unknown_cid, 4);
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.active_mappings, 1);
+}
+
+#[tokio::test]
+async fn jls_forward_retry() {
+    let _guard = subscribe();
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // The forwarded client is played by a plain socket, so it can retry with the same Initial
+    let downstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    // Sends the client's Initial through the forwarder, then checks that `reply` from the upstream
+    // is relayed back
+    let exchange = |reply: Vec<u8>| {
+        let (downstream, upstream, initial) = (&downstream, &upstream, &initial);
+        async move {
+            let mut buf = vec![0; 65536];
+            downstream.send_to(initial, server_addr).await.unwrap();
+            let (_, forwarder) =
+                tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+                    .await
+                    .expect("Initial not forwarded")
+                    .unwrap();
+            upstream.send_to(&reply, forwarder).await.unwrap();
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), downstream.recv_from(&mut buf))
+                    .await
+                    .expect("reply not relayed")
+                    .unwrap();
+            assert_eq!(&buf[..len], &reply[..]);
+        }
+    };
+
+    // Retry, version 1, with 8-byte CIDs, a token and an integrity tag
+    let mut retry = vec![0xf0, 0, 0, 0, 1, 8];
+    retry.extend_from_slice(&[0xaa; 8]);
+    retry.push(8);
+    retry.extend_from_slice(&[0xbb; 8]);
+    retry.extend_from_slice(&[0xcc; 32]);
+    exchange(retry).await;
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.restarts, 1);
+    assert_eq!(stats.established, 0);
+    assert_eq!(stats.active_mappings, 1);
+
+    // The client's second Initial reaches the upstream through the same mapping, and is answered
+    // with an Initial of the upstream's own
+    let mut response = vec![0xc0, 0, 0, 0, 1, 8];
+    response.extend_from_slice(&[0xbb; 8]);
+    response.push(8);
+    response.extend_from_slice(&[0xdd; 8]);
+    response.resize(1200, 0);
+    exchange(response).await;
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.restarts, 1);
+    assert_eq!(stats.established, 1);
+    assert_eq!(stats.active_mappings, 1);
+}
+
+#[tokio::test]
+async fn debug_snapshot() {
+    let _guard = subscribe();
+
+    // An upstream that never answers, so the forwarded client stays mapped
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Two clients with JLS credentials, sharing an endpoint
+    let client_crypto = jls_client_crypto(roots.clone(), "user_pwd", "user_iv");
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::new(Arc::new(client_crypto)));
+    let mut conns = Vec::new();
+    for _ in 0..2 {
+        let (conn, server_conn) = tokio::join!(
+            async { client.connect(server_addr, "localhost").unwrap().await },
+            async { server.accept().await.unwrap().await }
+        );
+        conns.push((conn.unwrap(), server_conn.unwrap()));
+    }
+
+    // And one without, which gets forwarded
+    let mut forwarded =
+        Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    forwarded.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = forwarded.connect(server_addr, "localhost").unwrap();
+    let mut buf = [0; 65536];
+    tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+
+    let snapshot = server.debug_snapshot();
+    assert_eq!(snapshot.local_addr, Some(server_addr));
+    assert!(snapshot.accepting);
+    assert_eq!(snapshot.connections.len(), 2);
+    assert!(snapshot.connections.iter().all(|x| x.accepted));
+    assert_eq!(snapshot.incoming, 0);
+    assert_eq!(snapshot.forwards.len(), 1);
+    let forward = &snapshot.forwards[0];
+    assert_eq!(forward.remote_address, forwarded.local_addr().unwrap());
+    assert_eq!(forward.upstream_address, upstream_addr);
+    assert!(!forward.established);
+    assert_eq!(snapshot.upstreams.len(), 1);
+    assert_eq!(snapshot.upstreams[0].0, upstream_addr);
+    assert_eq!(snapshot.upstreams[0].1.active_mappings, 1);
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let parsed: crate::EndpointSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, snapshot);
+    }
+}
+
+#[tokio::test]
+async fn jls_handshake_under_relay_load() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let _guard = subscribe();
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let client_crypto = jls_client_crypto(roots.clone(), "user_pwd", "user_iv");
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::new(Arc::new(client_crypto)));
+    let (client_ref, server_ref) = (&client, &server);
+    let handshake = move || async move {
+        let start = Instant::now();
+        let (conn, server_conn) = tokio::join!(
+            async { client_ref.connect(server_addr, "localhost").unwrap().await },
+            async { server_ref.accept().await.unwrap().await }
+        );
+        let elapsed = start.elapsed();
+        (conn.unwrap(), server_conn.unwrap(), elapsed)
+    };
+    let (_conn, _server_conn, baseline) = handshake().await;
+
+    // A forwarded client, whose upstream then floods it with traffic for the server to relay
+    let mut forwarded =
+        Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    forwarded.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = forwarded.connect(server_addr, "localhost").unwrap();
+    let mut buf = [0; 65536];
+    let (_, forwarder) = tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let flood = tokio::spawn({
+        let stop = stop.clone();
+        async move {
+            let datagram = [0x40; 1200];
+            while !stop.load(Ordering::Relaxed) {
+                for _ in 0..32 {
+                    let _ = upstream.send_to(&datagram, forwarder).await;
+                }
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.jls_upstream_stats()[&upstream_addr].bytes_from_upstream < 1_000_000 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("relay not saturated");
+
+    let (_conn, _server_conn, loaded) = handshake().await;
+    stop.store(true, Ordering::Relaxed);
+    flood.await.unwrap();
+    assert!(
+        loaded < baseline * 2 + Duration::from_millis(100),
+        "handshake took {loaded:?} under relay load, {baseline:?} without"
+    );
+}
+
+#[tokio::test]
+async fn post_handshake_verifier() {
+    let _guard = subscribe();
+    let server = endpoint();
+    let server_addr = server.local_addr().unwrap();
+    let pinned = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+        .unwrap()
+        .serialize_der()
+        .unwrap();
+
+    // Pins a certificate other than the one the server presents
+    let mut config = server.default_client_config.clone().unwrap();
+    config.post_handshake_verifier(Arc::new(move |info: crate::HandshakeInfo| {
+        assert_eq!(info.server_name, "localhost");
+        match info.peer_certificates() {
+            Some([leaf, ..]) if leaf.0 == pinned => Ok(()),
+            _ => Err(crate::Abort::new("certificate not pinned")),
+        }
+    }));
+    let (conn, _) = tokio::join!(
+        async {
+            server
+                .connect_with(config, server_addr, "localhost")
+                .unwrap()
+                .await
+        },
+        async { server.accept().await.unwrap().await }
+    );
+    match conn {
+        Err(crate::ConnectionError::TransportError(e)) => {
+            assert_eq!(e.code, proto::TransportErrorCode::crypto(42));
+            assert_eq!(e.reason, "certificate not pinned");
+        }
+        Err(e) => panic!("unexpected error: {e:?}"),
+        Ok(_) => panic!("connection established despite pin mismatch"),
+    }
+
+    // A verifier that accepts lets the connection through
+    let mut config = server.default_client_config.clone().unwrap();
+    config.post_handshake_verifier(Arc::new(|info: crate::HandshakeInfo| {
+        assert!(info.peer_certificates().is_some());
+        Ok(())
+    }));
+    let (conn, server_conn) = tokio::join!(
+        async {
+            server
+                .connect_with(config, server_addr, "localhost")
+                .unwrap()
+                .await
+        },
+        async { server.accept().await.unwrap().await }
+    );
+    conn.unwrap();
+    server_conn.unwrap();
+}
+
+#[cfg(feature = "cert-reloader")]
+#[tokio::test]
+async fn cert_reloader() {
+    let _guard = subscribe();
+
+    let dir = std::env::temp_dir().join(format!("quinn-cert-reloader-{}", rand::random::<u64>()));
+    std::fs::create_dir(&dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    let mut roots = rustls::RootCertStore::empty();
+    let mut issue = || {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        let cert = rustls::Certificate(cert.serialize_der().unwrap());
+        roots.add(&cert).unwrap();
+        cert
+    };
+    let old_cert = issue();
+
+    let reloader = Arc::new(crate::CertReloader::new(&cert_path, &key_path).unwrap());
+    let server = Endpoint::server(
+        crate::ServerConfig::with_cert_resolver(reloader.clone()),
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let new_cert = issue();
+    let mut client_crypto = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    // A resumed session reports the certificate of the session it resumes
+    client_crypto.resumption = rustls::client::Resumption::disabled();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::new(Arc::new(client_crypto)));
+
+    let connect = || async {
+        let (client, server) = tokio::join!(
+            async { client.connect(server_addr, "localhost").unwrap().await },
+            async { server.accept().await.unwrap().await }
+        );
+        let client = client.unwrap();
+        let chain = client
+            .peer_identity()
+            .unwrap()
+            .downcast::<Vec<rustls::Certificate>>()
+            .unwrap();
+        (client, server.unwrap(), chain[0].clone())
+    };
+
+    // New files are ignored until the reloader is triggered
+    let (old_client, old_server, cert) = connect().await;
+    assert_eq!(cert, old_cert);
+
+    reloader.reload().unwrap();
+    let (_new_client, _new_server, cert) = connect().await;
+    assert_eq!(cert, new_cert);
+
+    // Unusable files leave the current chain in place
+    std::fs::write(&key_path, "garbage").unwrap();
+    reloader.reload().unwrap_err();
+    let (_, _, cert) = connect().await;
+    assert_eq!(cert, new_cert);
+
+    // The connection established with the old certificate is unaffected
+    let mut send = old_client.open_uni().await.unwrap();
+    send.write_all(b"still here").await.unwrap();
+    send.finish().await.unwrap();
+    let mut recv = old_server.accept_uni().await.unwrap();
+    assert_eq!(recv.read_to_end(64).await.unwrap(), b"still here");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn stalled_connection_queue() {
+    use std::sync::atomic::Ordering;
+
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let runtime = Arc::new(CountingRuntime::default());
+    let mut config = crate::EndpointConfig::default();
+    config.max_queued_datagrams(4);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_single_cert(vec![cert], key).unwrap()),
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        runtime.clone(),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let connect = || {
+        let roots = roots.clone();
+        async move {
+            let mut client = Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+            client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+            let conn = client
+                .connect(server_addr, "localhost")
+                .unwrap()
+                .await
+                .unwrap();
+            (client, conn)
+        }
+    };
+
+    // Accept a connection whose driver never runs
+    let (_stalled_client, stalled_conn) = connect().await;
+    runtime.stall.store(true, Ordering::Relaxed);
+    let (stalled, _) = server.accept().await.unwrap().into_0rtt().unwrap();
+    runtime.stall.store(false, Ordering::Relaxed);
+
+    for _ in 0..32 {
+        stalled_conn
+            .send_datagram(Bytes::from_static(&[0; 1000]))
+            .unwrap();
+    }
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while stalled.event_queue_stats().dropped_datagrams == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no datagrams dropped");
+    assert!(stalled.event_queue_stats().depth <= 4);
+
+    // Other connections are unaffected
+    let (_client, conn) = connect().await;
+    let server_conn = server.accept().await.unwrap().await.unwrap();
+    let mut send = conn.open_uni().await.unwrap();
+    send.write_all(b"hello").await.unwrap();
+    send.finish().await.unwrap();
+    let mut recv = server_conn.accept_uni().await.unwrap();
+    assert_eq!(recv.read_to_end(64).await.unwrap(), b"hello");
+    assert!(stalled.event_queue_stats().depth <= 4);
+}
+
+#[tokio::test]
+async fn close_then_drop_endpoint() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+
+    let server = Endpoint::server(
+        crate::ServerConfig::with_single_cert(vec![cert], key).unwrap(),
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+
+    let conn = client
+        .connect(server.local_addr().unwrap(), "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let server_conn = server.accept().await.unwrap().await.unwrap();
+
+    // The connection's driver and the endpoint's driver are left to deliver the close on their own
+
+    conn.close(7u32.into(), b"bye");
+    drop(conn);
+    drop(client);
+
+    let error = tokio::time::timeout(Duration::from_secs(5), server_conn.closed())
+        .await
+        .expect("CONNECTION_CLOSE not received");
+    match error {
+        crate::ConnectionError::ApplicationClosed(close) => {
+            assert_eq!(close.error_code, 7u32.into());
+            assert_eq!(&close.reason[..], b"bye");
+        }
+        e => panic!("unexpected error: {e:?}"),
+    }
+}
+
+#[tokio::test]
+async fn connect_when_ready() {
+    use crate::Runtime as _;
+
+    let _guard = subscribe();
+    let server = endpoint();
+    // Behaves like an interface that isn't up until the gate opens
+    let gate = Arc::new(SendGate::default());
+    gate.close();
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let socket = FaultySocket::new(TokioRuntime.wrap_udp_socket(socket).unwrap())
+        .gate(gate.clone())
+        .lossy();
+    let client = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        None,
+        Box::new(socket),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+
+    let (ready_send, ready_recv) = tokio::sync::oneshot::channel::<()>();
+    let connecting = client
+        .connect_when_ready(
+            server.default_client_config.clone().unwrap(),
+            server.local_addr().unwrap(),
+            "localhost",
+            async move {
+                let _ = ready_recv.await;
+            },
+        )
+        .unwrap();
+
+    // Neither the readiness future nor the socket alone lets the first flight out
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    ready_send.send(()).unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), client.wait_socket_writable())
+            .await
+            .is_err()
+    );
+    assert_eq!(gate.lost(), 0);
+
+    gate.open();
+    client.wait_socket_writable().await.unwrap();
+    let (conn, server_conn) = tokio::join!(connecting, async {
+        server.accept().await.unwrap().await.unwrap()
+    });
+    let conn = conn.unwrap();
+    assert_eq!(gate.lost(), 0);
+    assert_eq!(conn.stats().path.lost_packets, 0);
+    assert_eq!(server_conn.remote_address(), client.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn socket_writable_waiters_leave_driver_woken() {
+    use std::{
+        future::Future,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll, Waker},
+    };
+
+    let _guard = subscribe();
+    let sent = Arc::new(AtomicUsize::new(0));
+    let gate = Arc::new(SendGate::default());
+    gate.close();
+    let socket = FaultySocket::new(Box::new(SinkSocket { sent: sent.clone() })).gate(gate.clone());
+    let (mut client, mut driver) = Endpoint::new_with_manual_driver(
+        Default::default(),
+        None,
+        Box::new(socket),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let driver_woken = Arc::new(FlagWaker::default());
+    let waker = Waker::from(driver_woken.clone());
+    let mut driver_cx = Context::from_waker(&waker);
+
+    // The driver finds it can't send the Initial
+    let _connecting = client
+        .connect("127.0.0.1:4433".parse().unwrap(), "localhost")
+        .unwrap();
+    while gate.waker.lock().unwrap().is_none() {
+        assert!(driver.poll_drive(&mut driver_cx).is_pending());
+        tokio::task::yield_now().await;
+    }
+    driver_woken.0.store(false, Ordering::Relaxed);
+
+    // Another task waits for the socket, leaving the driver to poll it
+    let waiter_woken = Arc::new(FlagWaker::default());
+    let waiter_waker = Waker::from(waiter_woken.clone());
+    let mut waiter = Box::pin(client.wait_socket_writable());
+    let mut waiter_cx = Context::from_waker(&waiter_waker);
+    assert!(waiter.as_mut().poll(&mut waiter_cx).is_pending());
+    assert!(driver_woken.0.swap(false, Ordering::Relaxed));
+    assert!(driver.poll_drive(&mut driver_cx).is_pending());
+    assert!(waiter.as_mut().poll(&mut waiter_cx).is_pending());
+
+    // Once the socket can send, the driver is the one woken, and it wakes the waiter in turn
+    gate.open();
+    assert!(driver_woken.0.swap(false, Ordering::Relaxed));
+    assert!(driver.poll_drive(&mut driver_cx).is_pending());
+    assert!(sent.load(Ordering::Relaxed) > 0);
+    assert!(waiter_woken.0.load(Ordering::Relaxed));
+    assert!(matches!(
+        waiter.as_mut().poll(&mut waiter_cx),
+        Poll::Ready(Ok(()))
+    ));
+}
+
+#[tokio::test]
+async fn send_stateless_reset() {
+    let _guard = subscribe();
+
+    // Number the server's connection IDs, so the one the client sends to is known
+    struct CountingCids(u8);
+    impl proto::ConnectionIdGenerator for CountingCids {
+        fn generate_cid(&mut self) -> proto::ConnectionId {
+            self.0 += 1;
+            proto::ConnectionId::new(&[self.0; 8])
+        }
+        fn cid_len(&self) -> usize {
+            8
+        }
+        fn cid_lifetime(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let mut endpoint_config = crate::EndpointConfig::default();
+    endpoint_config.cid_generator(|| Box::new(CountingCids(0)));
+    let server = Endpoint::new(
+        endpoint_config,
+        Some(crate::ServerConfig::with_single_cert(vec![cert], key).unwrap()),
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let client_addr = client.local_addr().unwrap();
+
+    let (conn, server_conn) = tokio::join!(
+        async {
+            client
+                .connect(server_addr, "localhost")
+                .unwrap()
+                .await
+                .unwrap()
+        },
+        async { server.accept().await.unwrap().await.unwrap() }
+    );
+
+    // Too long to be a connection ID
+    assert_eq!(
+        server
+            .send_stateless_reset(client_addr, &[0; 21])
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::InvalidInput
+    );
+
+    // The server still knows the connection, so the only reset the client can see is this one,
+    // for the connection ID the server chose during the handshake
+    server.send_stateless_reset(client_addr, &[1; 8]).unwrap();
+    match tokio::time::timeout(Duration::from_secs(5), conn.closed())
+        .await
+        .expect("reset not recognized")
+    {
+        crate::ConnectionError::Reset => {}
+        e => panic!("unexpected error: {e:?}"),
+    }
+    assert!(server_conn.close_reason().is_none());
+}
+
+#[tokio::test]
+async fn rebind_ends_jls_forwards() {
+    let _guard = subscribe();
+
+    // Answers every datagram, so the forward connection would stay busy if left alone
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 65536];
+        while let Ok((_, client)) = upstream.recv_from(&mut buf).await {
+            upstream.send_to(&[0; 100], client).await.unwrap();
+        }
+    });
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // A client without JLS credentials gets forwarded
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client.connect(server_addr, "localhost").unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server
+            .jls_upstream_stats()
+            .get(&upstream_addr)
+            .map_or(0, |x| x.bytes_from_upstream)
+            == 0
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("client not forwarded");
+
+    server
+        .rebind(UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap())
+        .unwrap();
+
+    // The mapping is gone, and says why, rather than relaying from an address the client never
+    // sent to
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.active_mappings, 0);
+    assert_eq!(stats.ended_by_rebind, 1);
+    let snapshot = server.debug_snapshot();
+    assert!(snapshot.forwards.is_empty());
+    assert_eq!(snapshot.upstreams, vec![(upstream_addr, stats)]);
+}
+
+#[tokio::test]
+async fn manual_driver() {
+    use std::{
+        future::Future,
+        sync::atomic::Ordering,
+        task::{Context, Poll, Waker},
+    };
+
+    let _guard = subscribe();
+    let runtime: Arc<dyn crate::Runtime> = Arc::new(TokioRuntime);
+    let manual = |server_config| {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        Endpoint::new_with_manual_driver(
+            Default::default(),
+            server_config,
+            runtime.wrap_udp_socket(socket).unwrap(),
+            runtime.clone(),
+        )
+        .unwrap()
+    };
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let (server, mut server_driver) = manual(Some(
+        crate::ServerConfig::with_single_cert(vec![cert], key).unwrap(),
+    ));
+    let (mut client, mut client_driver) = manual(None);
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+
+    // Tells the event loop it has work to do
+    let flag = Arc::new(FlagWaker::default());
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut connecting = Box::pin(
+        client
+            .connect(server.local_addr().unwrap(), "localhost")
+            .unwrap(),
+    );
+    let mut accept = Box::pin(server.accept());
+    let mut incoming = None;
+    let (mut conn, mut server_conn) = (None, None);
+
+    // Nothing happens until the drivers are polled
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(connecting.as_mut().poll(&mut cx).is_pending());
+    assert!(accept.as_mut().poll(&mut cx).is_pending());
+    assert!(server.debug_snapshot().connections.is_empty());
+
+    // An event loop polling everything it owns, then waiting to be woken
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while conn.is_none() || server_conn.is_none() {
+        assert!(Instant::now() < deadline, "handshake not completed");
+        flag.0.store(false, Ordering::Relaxed);
+        assert!(client_driver.poll_drive(&mut cx).is_pending());
+        assert!(server_driver.poll_drive(&mut cx).is_pending());
+        if conn.is_none() {
+            if let Poll::Ready(x) = connecting.as_mut().poll(&mut cx) {
+                conn = Some(x.unwrap());
+            }
+        }
+        if incoming.is_none() {
+            if let Poll::Ready(x) = accept.as_mut().poll(&mut cx) {
+                incoming = Some(Box::pin(x.unwrap()));
+            }
+        }
+        match incoming {
+            Some(ref mut x) if server_conn.is_none() => {
+                if let Poll::Ready(x) = x.as_mut().poll(&mut cx) {
+                    server_conn = Some(x.unwrap());
+                }
+            }
+            _ => {}
+        }
+        // Let the runtime run the connections' tasks, report I/O and fire timers, until something
+        // wakes us
+        tokio::task::yield_now().await;
+        while !flag.0.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    let (conn, server_conn) = (conn.unwrap(), server_conn.unwrap());
+    assert_eq!(conn.remote_address(), server.local_addr().unwrap());
+    assert_eq!(server_conn.remote_address(), client.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn send_backoff_on_rate_limit() {
+    use crate::Runtime as _;
+
+    let _guard = subscribe();
+    let server = endpoint();
+    let limit = Arc::new(RateLimit::default());
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let client = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        None,
+        Box::new(
+            FaultySocket::new(TokioRuntime.wrap_udp_socket(socket).unwrap())
+                .rate_limit(limit.clone()),
+        ),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+
+    let (conn, server_conn) = tokio::join!(
+        async {
+            client
+                .connect_with(
+                    server.default_client_config.clone().unwrap(),
+                    server.local_addr().unwrap(),
+                    "localhost",
+                )
+                .unwrap()
+                .await
+                .unwrap()
+        },
+        async { server.accept().await.unwrap().await.unwrap() }
+    );
+
+    // Everything sent during the limit goes out once it lifts
+    limit.limit_for(Duration::from_millis(5));
+    const MSG: &[u8] = &[0xAB; 10_000];
+    let mut send = conn.open_uni().await.unwrap();
+    send.write_all(MSG).await.unwrap();
+    send.finish().await.unwrap();
+    conn.send_datagram(Bytes::from_static(b"datagram")).unwrap();
+
+    let mut recv = server_conn.accept_uni().await.unwrap();
+    assert_eq!(recv.read_to_end(usize::MAX).await.unwrap(), MSG);
+    assert_eq!(server_conn.read_datagram().await.unwrap(), &b"datagram"[..]);
+    assert!(limit.rejected() > 0);
+    assert_eq!(conn.stats().path.lost_packets, 0);
+
+    // The driver survived, and still sends
+    conn.send_datagram(Bytes::from_static(b"after")).unwrap();
+    assert_eq!(server_conn.read_datagram().await.unwrap(), &b"after"[..]);
+}
+
+#[test]
+#[cfg(unix)]
+fn transient_send_errors_returned() {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    udp::UdpSocketState::configure((&socket).into()).unwrap();
+    let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    // Rejected with `EACCES` without `SO_BROADCAST`
+    let broadcast = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 4433);
+    let transmit = |destination| udp::Transmit {
+        destination,
+        ecn: None,
+        contents: Bytes::from_static(b"hello"),
+        segment_size: None,
+        src_ip: None,
+    };
+
+    let state = udp::UdpSocketState::new();
+    let udp_state = udp::UdpState::new();
+    let err = state
+        .send(
+            (&socket).into(),
+            &udp_state,
+            &[transmit(broadcast), transmit(peer_addr)],
+        )
+        .unwrap_err();
+    assert!(udp::is_transient_send_error(&err), "{err}");
+    // Nothing was dropped; the datagram is left for the caller to retry
+    assert_eq!(state.local_drops().send_errors, 0);
+
+    // Those sent before the failure are reported as sent
+    let sent = state
+        .send(
+            (&socket).into(),
+            &udp_state,
+            &[transmit(peer_addr), transmit(broadcast)],
+        )
+        .unwrap();
+    assert_eq!(sent, 1);
+    let mut buf = [0; 16];
+    assert_eq!(peer.recv(&mut buf).unwrap(), 5);
+}
+
+#[tokio::test]
+#[cfg(unix)]
+async fn rejected_destination_does_not_stall_sends() {
+    let _guard = subscribe();
+    let server = endpoint();
+    let client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).unwrap();
+    let config = server.default_client_config.clone().unwrap();
+
+    // Every send to a broadcast address fails, and the client keeps retransmitting its Initial
+    let _rejected = client
+        .connect_with(
+            config.clone(),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 4433),
+            "localhost",
+        )
+        .unwrap();
+    let (_conn, _server_conn) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            async {
+                client
+                    .connect_with(config, server.local_addr().unwrap(), "localhost")
+                    .unwrap()
+                    .await
+                    .unwrap()
+            },
+            async { server.accept().await.unwrap().await.unwrap() }
+        )
+    })
+    .await
+    .expect("sends stalled behind a rejected destination");
+
+    // Queued behind the rejected Initial, which had to be given up on first
+    assert!(client.stats().local_send_drops > 0);
+}
+
+#[tokio::test]
+async fn alpn() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let mut server_crypto = server_crypto(cert.clone(), key);
+    server_crypto.alpn_protocols = vec![b"h3".to_vec(), b"doq".to_vec()];
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let client_config = |protocols: &[&[u8]]| {
+        let roots = trusting(&cert);
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = protocols.iter().map(|x| x.to_vec()).collect();
+        ClientConfig::new(Arc::new(crypto))
+    };
+
+    // The server knows the protocol as soon as the connection is established
+    let connecting = client
+        .connect_with(
+            client_config(&[b"doq"]),
+            server.local_addr().unwrap(),
+            "localhost",
+        )
+        .unwrap();
+    let (conn, server_conn) = tokio::join!(connecting, async {
+        server.accept().await.unwrap().await.unwrap()
+    });
+    assert_eq!(server_conn.alpn().as_deref(), Some(&b"doq"[..]));
+    assert_eq!(conn.unwrap().alpn().as_deref(), Some(&b"doq"[..]));
+
+    // A client offering nothing the server supports is refused during the handshake
+    let connecting = client
+        .connect_with(
+            client_config(&[b"smtp"]),
+            server.local_addr().unwrap(),
+            "localhost",
+        )
+        .unwrap();
+    match connecting.await {
+        Err(crate::ConnectionError::ConnectionClosed(close)) => {
+            // no_application_protocol
+            assert_eq!(close.error_code, proto::TransportErrorCode::crypto(0x78));
+        }
+        e => panic!("unexpected result: {e:?}"),
+    }
+}
+
+#[tokio::test]
+async fn incoming_limits() {
+    let _guard = subscribe();
+    let server = endpoint();
+    let server_addr = server.local_addr().unwrap();
+    let client_config = server.default_client_config.clone().unwrap();
+    let client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let connect = || {
+        client
+            .connect_with(client_config.clone(), server_addr, "localhost")
+            .unwrap()
+    };
+    server.set_incoming_limits(1, usize::MAX);
+
+    // One connection the application hasn't accepted yet is enough to require address validation
+    let _first = connect().await.unwrap();
+
+    // Capture a genuine Initial, then replay it from addresses that never answer the Retry
+    let capture = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let capture_addr = capture.local_addr().unwrap();
+    let _abandoned = client
+        .connect_with(client_config.clone(), capture_addr, "localhost")
+        .unwrap();
+    let mut initial = vec![0; 2048];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+    for _ in 0..8 {
+        let spoofed = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        spoofed.send_to(&initial, server_addr).await.unwrap();
+        let mut buf = vec![0; 2048];
+        tokio::time::timeout(Duration::from_secs(5), spoofed.recv(&mut buf))
+            .await
+            .expect("no response")
+            .unwrap();
+        assert_eq!(buf[0] & 0xf0, 0xf0, "expected a Retry packet");
+    }
+    assert_eq!(server.stats().admission_retries, 8);
+    assert_eq!(server.debug_snapshot().incoming, 1);
+
+    // Legitimate clients answer the Retry and get in
+    let _second = connect().await.unwrap();
+    assert_eq!(server.stats().admission_retries, 9);
+
+    // At the hard limit, they are refused
+    server.set_incoming_limits(1, 2);
+    match connect().await {
+        Err(crate::ConnectionError::ConnectionClosed(close)) => {
+            assert_eq!(
+                close.error_code,
+                proto::TransportErrorCode::CONNECTION_REFUSED
+            );
+        }
+        e => panic!("unexpected result: {e:?}"),
+    }
+    assert_eq!(server.stats().overloaded, 1);
+
+    // Accepting the waiting connections lifts both limits
+    for _ in 0..2 {
+        let accepted = server.accept().await.unwrap().await.unwrap();
+        assert_eq!(accepted.remote_address(), client.local_addr().unwrap());
+    }
+    connect().await.unwrap();
+    assert_eq!(server.stats().admission_retries, 9);
+}
+
+#[tokio::test]
+async fn connection_phases() {
+    use crate::ConnectionPhase::*;
+    let _guard = subscribe();
+    let endpoint = endpoint();
+    let addr = endpoint.local_addr().unwrap();
+
+    // Poll the endpoint's view until every connection it knows of is in `phase`
+    let wait_for = |phase, count| {
+        let endpoint = endpoint.clone();
+        async move {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let conns = endpoint.debug_snapshot().connections;
+                    if conns.len() == count && conns.iter().all(|x| x.phase == phase) {
+                        return conns;
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("connections never reached {phase:?}"))
+        }
+    };
+
+    // Recorded as soon as it's created, before its driver has run
+    let connecting = endpoint.connect(addr, "localhost").unwrap();
+    let conns = endpoint.debug_snapshot().connections;
+    assert_eq!(conns.len(), 1);
+    assert_eq!(conns[0].phase, Handshaking);
+    assert_eq!(conns[0].remote_address, addr);
+
+    let (client, server) =
+        tokio::join!(connecting, async { endpoint.accept().await.unwrap().await });
+    let client = client.unwrap();
+    let _server = server.unwrap();
+    let started = wait_for(Established, 2).await;
+    assert!(started.iter().all(|x| x.remote_address == addr));
+
+    // Ages keep counting from creation
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let later = endpoint.debug_snapshot().connections;
+    for conn in &later {
+        let before = started.iter().find(|x| x.handle == conn.handle).unwrap();
+        assert!(conn.age >= before.age + Duration::from_millis(20));
+    }
+
+    // Both sides drain after a close, then are forgotten
+    client.close(0u32.into(), b"done");
+    wait_for(Draining, 2).await;
+    tokio::time::timeout(Duration::from_secs(5), endpoint.wait_idle())
+        .await
+        .unwrap();
+    assert!(endpoint.debug_snapshot().connections.is_empty());
+}
+
+#[tokio::test]
+async fn dedicated_driver_thread() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+
+    let mut options = crate::DriverThreadConfig::default();
+    options.name("quinn-test-drv");
+    let server = Endpoint::new_on_dedicated_thread(
+        Default::default(),
+        Some(crate::ServerConfig::with_single_cert(vec![cert], key).unwrap()),
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        &options,
+    )
+    .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+
+    // Connections on the dedicated endpoint are used from this runtime as usual, including after
+    // it's rebound
+    for i in 0..2 {
+        if i == 1 {
+            server
+                .rebind(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap())
+                .unwrap();
+        }
+        let server_addr = server.local_addr().unwrap();
+        let (conn, server_conn) = tokio::join!(
+            async { client.connect(server_addr, "localhost").unwrap().await },
+            async { server.accept().await.unwrap().await }
+        );
+        let (conn, server_conn) = (conn.unwrap(), server_conn.unwrap());
+        let mut send = conn.open_uni().await.unwrap();
+        send.write_all(b"ping").await.unwrap();
+        send.finish().await.unwrap();
+        let mut recv = server_conn.accept_uni().await.unwrap();
+        assert_eq!(&recv.read_to_end(4).await.unwrap()[..], b"ping");
+        server_conn.close(0u32.into(), b"done");
+    }
+
+    #[cfg(target_os = "linux")]
+    let threads = || {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+            .filter(|name| name.trim_end() == "quinn-test-drv")
+            .count()
+    };
+    #[cfg(target_os = "linux")]
+    assert_eq!(threads(), 1);
+
+    // Dropping the last handle stops the driver once its connections have drained
+    server.wait_idle().await;
+    drop(server);
+    client.wait_idle().await;
+    #[cfg(target_os = "linux")]
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while threads() != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("driver thread still running");
+}
+
+#[tokio::test]
+async fn local_drops() {
+    use std::sync::Mutex;
+
+    use crate::Runtime as _;
+
+    let _guard = subscribe();
+    let server = endpoint();
+    // Reports whatever drops the test tells it to
+    let drops = Arc::new(Mutex::new(udp::LocalDrops::default()));
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let client = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        None,
+        Box::new(
+            FaultySocket::new(TokioRuntime.wrap_udp_socket(socket).unwrap()).drops(drops.clone()),
+        ),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    assert_eq!(client.stats().local_receive_drops, 0);
+    assert_eq!(client.stats().local_send_drops, 0);
+
+    // Drops show up in the stats whether or not the driver has sampled them yet
+    *drops.lock().unwrap() = udp::LocalDrops {
+        receive_overflows: 3,
+        send_errors: 2,
+    };
+    let (conn, server_conn) = tokio::join!(
+        async {
+            client
+                .connect_with(
+                    server.default_client_config.clone().unwrap(),
+                    server.local_addr().unwrap(),
+                    "localhost",
+                )
+                .unwrap()
+                .await
+        },
+        async { server.accept().await.unwrap().await }
+    );
+    conn.unwrap();
+    server_conn.unwrap();
+    let stats = client.stats();
+    assert_eq!(stats.local_receive_drops, 3);
+    assert_eq!(stats.local_send_drops, 2);
+    assert_eq!(client.debug_snapshot().stats, stats);
+    assert_eq!(server.stats().local_receive_drops, 0);
+}
+
+#[tokio::test]
+async fn send_queued_in_same_poll() {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Context,
+    };
+
+    let _guard = subscribe();
+    let sent = Arc::new(AtomicUsize::new(0));
+    let (mut client, mut driver) = Endpoint::new_with_manual_driver(
+        Default::default(),
+        None,
+        Box::new(SinkSocket { sent: sent.clone() }),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+
+    // Each connection queues its Initial and an activity report, which together take more than
+    // one pass over the endpoint's events
+    const CONNECTIONS: usize = 100;
+    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2);
+    let _connecting = (0..CONNECTIONS)
+        .map(|_| client.connect(server_addr, "localhost").unwrap())
+        .collect::<Vec<_>>();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(sent.load(Ordering::Relaxed), 0);
+
+    // A single poll sends all of them
+    let waker = NoopWaker::waker();
+    let mut cx = Context::from_waker(&waker);
+    assert!(driver.poll_drive(&mut cx).is_pending());
+    assert_eq!(sent.load(Ordering::Relaxed), CONNECTIONS);
+}
+
+#[tokio::test]
+async fn stable_id_correlation() {
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    /// Collects the `stable_id` field of every span
+    struct StableIds(Arc<Mutex<Vec<u64>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for StableIds {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Visitor<'a>(&'a Mutex<Vec<u64>>);
+            impl Visit for Visitor<'_> {
+                fn record_u64(&mut self, field: &Field, value: u64) {
+                    if field.name() == "stable_id" {
+                        self.0.lock().unwrap().push(value);
+                    }
+                }
+                fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+            }
+            attrs.record(&mut Visitor(&self.0));
+        }
+    }
+
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let _guard = tracing::subscriber::set_default(
+        tracing_subscriber::registry().with(StableIds(ids.clone())),
+    );
+    let endpoint = endpoint();
+    let connecting = endpoint
+        .connect(endpoint.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let client_id = connecting.stable_id();
+    let (client, server) = tokio::join!(connecting, async {
+        let incoming = endpoint.accept().await.unwrap();
+        let id = incoming.stable_id();
+        let conn = incoming.await.unwrap();
+        assert_eq!(conn.stable_id(), id);
+        conn
+    });
+    let client = client.unwrap();
+    assert_eq!(client.stable_id(), client_id);
+    assert_ne!(client.stable_id(), server.stable_id());
+
+    // The endpoint lists both connections under the same identifiers
+    let mut listed = endpoint
+        .debug_snapshot()
+        .connections
+        .iter()
+        .map(|x| x.stable_id.unwrap())
+        .collect::<Vec<_>>();
+    listed.sort_unstable();
+    let mut expected = vec![client.stable_id(), server.stable_id()];
+    expected.sort_unstable();
+    assert_eq!(listed, expected);
+
+    // Both connection drivers recorded them in their spans
+    let ids = ids.lock().unwrap();
+    for id in expected {
+        assert!(ids.contains(&(id as u64)));
+    }
+}
+
+#[tokio::test]
+async fn endpoint_setup_errors() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use crate::{EndpointSetupError, Runtime as _};
+
+    let _guard = subscribe();
+
+    fn setup_error(e: &io::Error) -> &EndpointSetupError {
+        e.get_ref().unwrap().downcast_ref().unwrap()
+    }
+
+    // Binding an address that's taken
+    let taken = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let e = Endpoint::client(taken.local_addr().unwrap()).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+    assert!(matches!(setup_error(&e), EndpointSetupError::Bind(_)));
+
+    // A socket the runtime can't drive
+    let runtime = Arc::new(MockRuntime::wrapping(|_| {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "nope"))
+    }));
+    let e = Endpoint::new(
+        Default::default(),
+        None,
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        runtime,
+    )
+    .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    assert!(matches!(
+        setup_error(&e),
+        EndpointSetupError::SocketRejected(_)
+    ));
+
+    // A registration that only succeeds once the socket's flags are cleared, as if the descriptor
+    // were first found registered elsewhere
+    let wraps = Arc::new(AtomicUsize::new(0));
+    // Whether the last socket wrapped was blocking and inheritable
+    let cleared = Arc::new(AtomicBool::new(false));
+    let runtime = Arc::new(MockRuntime::wrapping({
+        let (wraps, cleared) = (wraps.clone(), cleared.clone());
+        move |t| {
+            if wraps.fetch_add(1, Ordering::Relaxed) == 0 {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::io::AsRawFd;
+                let fd = t.as_raw_fd();
+                let (status, flags) = unsafe {
+                    (
+                        libc::fcntl(fd, libc::F_GETFL),
+                        libc::fcntl(fd, libc::F_GETFD),
+                    )
+                };
+                cleared.store(
+                    status & libc::O_NONBLOCK == 0 && flags & libc::FD_CLOEXEC == 0,
+                    Ordering::Relaxed,
+                );
+            }
+            TokioRuntime.wrap_udp_socket(t)
+        }
+    }));
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let endpoint = Endpoint::new(Default::default(), None, socket, runtime).unwrap();
+    assert_eq!(wraps.load(Ordering::Relaxed), 2);
+    #[cfg(unix)]
+    assert!(cleared.load(Ordering::Relaxed));
+    assert!(endpoint.local_addr().is_ok());
+}
+
+// With async-std enabled, a runtime is always available
+#[test]
+#[cfg(not(feature = "runtime-async-std"))]
+fn endpoint_setup_without_runtime() {
+    let e = Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap_err();
+    assert!(matches!(
+        e.get_ref().unwrap().downcast_ref(),
+        Some(crate::EndpointSetupError::NoRuntime)
+    ));
+}
+
+#[tokio::test]
+async fn ack_only_suppression() {
+    use std::task::Context;
+
+    let _guard = subscribe();
+    let gate = Arc::new(SendGate::default());
+    let socket = GroSocket::new(
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1),
+        Vec::new(),
+    );
+    let sent = socket.sent.clone();
+    let (mut client, mut driver) = Endpoint::new_with_manual_driver(
+        Default::default(),
+        None,
+        Box::new(FaultySocket::new(Box::new(socket)).gate(gate.clone())),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    client.set_ack_only_watermark(Some(1000));
+    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2);
+    // The connection the injected transmits claim to come from, which the endpoint must know
+    let _conn = client.connect(server_addr, "localhost").unwrap();
+
+    // With the socket blocked, data fills the queue past the watermark, and ACKs from the same
+    // connection follow
+    gate.close();
+    let events = client.event_sender();
+    let transmit = |len, ack_only| {
+        let transmit = proto::Transmit {
+            destination: server_addr,
+            ecn: None,
+            contents: Bytes::from(vec![0; len]),
+            segment_size: None,
+            src_ip: None,
+            send_at: None,
+        };
+        let event = crate::EndpointEvent::Transmit {
+            transmit,
+            handshake: false,
+            ack_only,
+        };
+        events.send((proto::ConnectionHandle(0), event)).unwrap();
+    };
+    transmit(2000, false);
+    for len in [50, 51, 52] {
+        transmit(len, true);
+    }
+    let waker = NoopWaker::waker();
+    let mut cx = Context::from_waker(&waker);
+    assert!(driver.poll_drive(&mut cx).is_pending());
+    let stats = client.stats();
+    assert_eq!(stats.deferred_acks, 3);
+    assert_eq!(stats.coalesced_acks, 2);
+    assert!(sent.lock().unwrap().is_empty());
+
+    // Once the socket clears, only the newest ACK goes out, behind the data
+    gate.open();
+    for _ in 0..2 {
+        assert!(driver.poll_drive(&mut cx).is_pending());
+    }
+    let lens = sent
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|x| x.contents.len())
+        .filter(|&x| x < 1200 || x == 2000)
+        .collect::<Vec<_>>();
+    assert_eq!(lens, [2000, 52]);
+}
+
+#[tokio::test]
+async fn batch_scope() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::Runtime as _;
+
+    /// Write a little to each of several streams, letting the connection's driver run in
+    /// between, and count the messages sent
+    async fn burst(conn: &crate::Connection, sent: &AtomicUsize, batch: bool) -> usize {
+        const STREAMS: usize = 8;
+        let before = sent.load(Ordering::Relaxed);
+        let guard = batch.then(|| conn.batch_scope());
+        let mut streams = Vec::new();
+        for _ in 0..STREAMS {
+            let mut send = conn.open_uni().await.unwrap();
+            send.write_all(&[0; 100]).await.unwrap();
+            streams.push(send);
+            tokio::task::yield_now().await;
+        }
+        drop(guard);
+        // Give the driver and endpoint time to send everything
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        sent.load(Ordering::Relaxed) - before
+    }
+
+    let _guard = subscribe();
+    let server = endpoint();
+    let sent = Arc::new(AtomicUsize::new(0));
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let client = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        None,
+        Box::new(CountingSocket {
+            inner: TokioRuntime.wrap_udp_socket(socket).unwrap(),
+            sent: sent.clone(),
+        }),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let (conn, _server_conn) = tokio::join!(
+        async {
+            client
+                .connect_with(
+                    server.default_client_config.clone().unwrap(),
+                    server.local_addr().unwrap(),
+                    "localhost",
+                )
+                .unwrap()
+                .await
+                .unwrap()
+        },
+        async { server.accept().await.unwrap().await.unwrap() }
+    );
+    // Let the handshake's last packets settle
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let unbatched = burst(&conn, &sent, false).await;
+    let batched = burst(&conn, &sent, true).await;
+    info!(unbatched, batched, "bursts sent");
+    assert!(unbatched > 1);
+    assert_eq!(batched, 1);
+}
+
+#[tokio::test]
+async fn send_not_starved_by_recv_flood() {
+    use std::task::Context;
+
+    /// Poll a manual driver `passes` times after `connections` connections queue their Initials
+    async fn run(slow_send: bool, connections: usize, passes: usize) -> Vec<&'static str> {
+        let socket = FloodSocket::default();
+        let log = socket.log.clone();
+        let socket = match slow_send {
+            // Take one datagram per send, slowly, so that the send budget always runs out
+            true => FaultySocket::new(Box::new(socket))
+                .delay(Duration::from_millis(1))
+                .one_by_one(),
+            false => FaultySocket::new(Box::new(socket)),
+        };
+        let (mut client, mut driver) = Endpoint::new_with_manual_driver(
+            Default::default(),
+            None,
+            Box::new(socket),
+            Arc::new(TokioRuntime),
+        )
+        .unwrap();
+        client.set_default_client_config(ClientConfig::with_root_certificates(
+            rustls::RootCertStore::empty(),
+        ));
+        let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2);
+        let _connecting = (0..connections)
+            .map(|_| client.connect(server_addr, "localhost").unwrap())
+            .collect::<Vec<_>>();
+        // Let the connections queue their Initials
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let waker = NoopWaker::waker();
+        let mut cx = Context::from_waker(&waker);
+        for _ in 0..passes {
+            log.lock().unwrap().push("pass");
+            assert!(driver.poll_drive(&mut cx).is_pending());
+        }
+        let log = log.lock().unwrap();
+        log.clone()
+    }
+
+    let _guard = subscribe();
+
+    // The Initial went out within two passes, in one of them ahead of the flood
+    let log = run(false, 1, 2).await;
+    assert!(log.contains(&"send"), "{log:?}");
+    assert!(log.windows(2).any(|x| x == ["pass", "send"]), "{log:?}");
+
+    // With more to send than any pass can take, every pass both receives and sends, and passes
+    // that send first don't also send again after receiving
+    let log = run(true, 16, 4).await;
+    let passes = log.split(|x| *x == "pass").skip(1).collect::<Vec<_>>();
+    assert_eq!(passes.len(), 4);
+    for pass in passes {
+        assert_eq!(pass.iter().filter(|x| **x == "recv").count(), 1, "{log:?}");
+        assert_eq!(pass.iter().filter(|x| **x == "send").count(), 1, "{log:?}");
+    }
+}
+
+#[tokio::test]
+async fn idle_jls_forwards_expire() {
+    let _guard = subscribe();
+
+    // Answers the first datagram only
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 65536];
+        let (_, client) = upstream.recv_from(&mut buf).await.unwrap();
+        upstream.send_to(&[0; 100], client).await.unwrap();
+        while upstream.recv_from(&mut buf).await.is_ok() {}
+    });
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_idle_timeout(Duration::from_millis(200));
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // A client without JLS credentials gets forwarded, then goes quiet
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client.connect(server_addr, "localhost").unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.debug_snapshot().forwards.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("client not forwarded");
+    client.close(0u32.into(), b"done");
+
+    // The mapping, and with it its socket, goes away without any further traffic
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !server.debug_snapshot().forwards.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("idle forward connection not ended");
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.active_mappings, 0);
+    assert_eq!(stats.ended_by_idle, 1);
+}
+
+#[tokio::test]
+async fn plain_and_jls_server_names() {
+    let _guard = subscribe();
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+
+    let (cert, key) = self_signed_cert_for(&["api.test", "cover.test"]);
+    let roots = trusting(&cert);
+    let plain_crypto = server_crypto(cert.clone(), key.clone());
+    let jls_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let crypto = proto::crypto::rustls::SniJlsServerConfig::new(
+        Arc::new(plain_crypto),
+        Arc::new(jls_crypto),
+        ["API.test"],
+    );
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let mut plain_client =
+        Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    plain_client.set_default_client_config(ClientConfig::with_root_certificates(roots.clone()));
+    let client_crypto = jls_client_crypto(roots, "user_pwd", "user_iv");
+    let mut jls_client =
+        Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    jls_client.set_default_client_config(ClientConfig::new(Arc::new(client_crypto)));
+
+    // Allowlisted name, plain client: an ordinary QUIC connection
+    let (conn, server_conn) = tokio::join!(
+        async { plain_client.connect(server_addr, "api.test").unwrap().await },
+        async { server.accept().await.unwrap().await }
+    );
+    conn.unwrap();
+    assert_eq!(server_conn.unwrap().is_jls(), None);
+
+    // Allowlisted name, JLS client: served by the plain configuration, never forwarded
+    let connecting = jls_client.connect(server_addr, "api.test").unwrap();
+    let incoming = tokio::time::timeout(Duration::from_secs(5), server.accept())
+        .await
+        .expect("allowlisted JLS client not accepted")
+        .unwrap();
+    let (conn, server_conn) = tokio::join!(
+        tokio::time::timeout(Duration::from_secs(5), connecting),
+        tokio::time::timeout(Duration::from_secs(5), incoming)
+    );
+    conn.expect("allowlisted JLS client timed out").unwrap();
+    let server_conn = server_conn
+        .expect("allowlisted JLS connection timed out")
+        .unwrap();
+    assert_eq!(server_conn.is_jls(), None);
+
+    // Other name, JLS client: authenticated with JLS
+    let (conn, server_conn) = tokio::join!(
+        async { jls_client.connect(server_addr, "cover.test").unwrap().await },
+        async { server.accept().await.unwrap().await }
+    );
+    conn.unwrap();
+    assert_eq!(server_conn.unwrap().is_jls(), Some(true));
+    assert!(server.jls_upstream_stats().is_empty());
+
+    // Other name, plain client: forwarded to the upstream
+    let _connecting = plain_client.connect(server_addr, "cover.test").unwrap();
+    let mut buf = [0; 65536];
+    let (_, from) = tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+    assert_ne!(from, server_addr);
+    assert_eq!(server.debug_snapshot().forwards.len(), 1);
+}
+
+#[tokio::test]
+async fn jls_forward_idle_timeout_disabled() {
+    let _guard = subscribe();
+
+    // Answers the first datagram only
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 65536];
+        let (_, client) = upstream.recv_from(&mut buf).await.unwrap();
+        upstream.send_to(&[0; 100], client).await.unwrap();
+        while upstream.recv_from(&mut buf).await.is_ok() {}
+    });
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_idle_timeout(Duration::MAX);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client.connect(server_addr, "localhost").unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.debug_snapshot().forwards.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("client not forwarded");
+    client.close(0u32.into(), b"done");
+
+    // However long it stays quiet, the mapping is kept
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(server.debug_snapshot().forwards.len(), 1);
+    assert_eq!(server.jls_upstream_stats()[&upstream_addr].ended_by_idle, 0);
+}
+
+#[tokio::test]
+async fn forwarded_client_later_authenticates() {
+    let _guard = subscribe();
+
+    // An upstream that never answers
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Without JLS credentials, the client gets forwarded
+    let client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    let connecting = client
+        .connect_with(
+            ClientConfig::with_root_certificates(roots.clone()),
+            server_addr,
+            "localhost",
+        )
+        .unwrap();
+    let mut buf = [0; 65536];
+    tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+    drop(connecting);
+
+    // With them fixed, its new handshake from the same address reaches the endpoint
+    let client_crypto = jls_client_crypto(roots, "user_pwd", "user_iv");
+    let (conn, server_conn) = tokio::join!(
+        async {
+            client
+                .connect_with(
+                    ClientConfig::new(Arc::new(client_crypto)),
+                    server_addr,
+                    "localhost",
+                )
+                .unwrap()
+                .await
+        },
+        async { server.accept().await.unwrap().await }
+    );
+    let conn = conn.unwrap();
+    let server_conn = server_conn.unwrap();
+    assert_eq!(server_conn.is_jls(), Some(true));
+    assert_eq!(server_conn.remote_address(), client.local_addr().unwrap());
+
+    // The forward connection gave way to the authenticated one
+    assert!(server.debug_snapshot().forwards.is_empty());
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.active_mappings, 0);
+    assert_eq!(stats.ended_by_new_handshake, 1);
+
+    let mut stream = conn.open_uni().await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.finish().await.unwrap();
+    let mut stream = server_conn.accept_uni().await.unwrap();
+    assert_eq!(stream.read_to_end(16).await.unwrap(), b"hello");
+}
+
+#[tokio::test]
+async fn upstream_socket_error_spares_endpoint() {
+    let _guard = subscribe();
+    let upstream_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9);
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::new(
+        Default::default(),
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(MockRuntime::upstream(|_| Ok(Box::new(BrokenSocket)))),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let client_crypto = jls_client_crypto(roots.clone(), "user_pwd", "user_iv");
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::new(Arc::new(client_crypto)));
+    let (conn, server_conn) = tokio::join!(
+        async { client.connect(server_addr, "localhost").unwrap().await },
+        async { server.accept().await.unwrap().await }
+    );
+    let conn = conn.unwrap();
+    let server_conn = server_conn.unwrap();
+
+    // A client without JLS credentials gets forwarded, over a socket that fails
+    let mut forwarded =
+        Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    forwarded.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = forwarded.connect(server_addr, "localhost").unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server
+            .jls_upstream_stats()
+            .get(&upstream_addr)
+            .map_or(true, |x| x.ended_by_error == 0)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("failed forward connection not ended");
+
+    // The authenticated connection carries on
+    let mut stream = conn.open_uni().await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.finish().await.unwrap();
+    let mut stream = server_conn.accept_uni().await.unwrap();
+    assert_eq!(stream.read_to_end(16).await.unwrap(), b"hello");
+}
+
+#[tokio::test]
+async fn jls_forwards_share_one_buffer() {
+    let _guard = subscribe();
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    // Even with a socket for each connection
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_socket_per_client(true);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Many clients without JLS credentials, forwarded to an upstream that stays quiet
+    const CLIENTS: usize = 16;
+    let mut clients = Vec::new();
+    for _ in 0..CLIENTS {
+        let mut client =
+            Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+        client.set_default_client_config(ClientConfig::with_root_certificates(roots.clone()));
+        let connecting = client.connect(server_addr, "localhost").unwrap();
+        clients.push((client, connecting));
+    }
+    let mut buf = [0; 65536];
+    let mut relays = std::collections::HashSet::new();
+    while relays.len() < CLIENTS {
+        let (_, relay) = tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+            .await
+            .expect("Initial not forwarded")
+            .unwrap();
+        relays.insert(relay);
+    }
+
+    // Only the one shared buffer is allocated
+    let buffered = server.stats().forward_buffer_bytes;
+    assert!(buffered > 0);
+    assert_eq!(server.debug_snapshot().forwards.len(), CLIENTS);
+
+    // It's all that's needed for every upstream to answer
+    for relay in &relays {
+        upstream.send_to(&[0; 100], relay).await.unwrap();
+    }
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.jls_upstream_stats()[&upstream_addr].bytes_from_upstream
+            < (CLIENTS * 100) as u64
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("answers not relayed");
+    assert_eq!(server.stats().forward_buffer_bytes, buffered);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn small_socket_buffers_warn() {
+    use std::{os::unix::io::AsRawFd, sync::Mutex};
+
+    /// Keeps what's logged
+    #[derive(Clone)]
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let log = Log(Arc::default());
+    let writer = log.clone();
+    let _guard = tracing::subscriber::set_default(
+        tracing_subscriber::FmtSubscriber::builder()
+            .with_max_level(tracing::Level::WARN)
+            .with_writer(move || writer.clone())
+            .finish(),
+    );
+
+    // Far too small for the default max_udp_payload_size
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let size: libc::c_int = 1024;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(result, 0);
+    let _endpoint =
+        Endpoint::new(Default::default(), None, socket, Arc::new(TokioRuntime)).unwrap();
+
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    assert!(log.contains("setsockopt(SO_RCVBUF)"), "{log}");
+}
+
+/// Takes every datagram at once, counting them, and never has anything to receive
+#[derive(Debug, Default)]
+struct SinkSocket {
+    sent: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl crate::AsyncUdpSocket for SinkSocket {
+    fn poll_send(
+        &self,
+        _: &udp::UdpState,
+        _: &mut std::task::Context,
+        transmits: &[udp::Transmit],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.sent
+            .fetch_add(transmits.len(), std::sync::atomic::Ordering::Relaxed);
+        std::task::Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        _: &mut std::task::Context,
+        _: &mut [io::IoSliceMut<'_>],
+        _: &mut [udp::RecvMeta],
+    ) -> std::task::Poll<io::Result<usize>> {
+        std::task::Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1))
+    }
+}
+
+/// Counts the datagrams sent through another socket
+#[derive(Debug)]
+struct CountingSocket {
+    inner: Box<dyn crate::AsyncUdpSocket>,
+    sent: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl crate::AsyncUdpSocket for CountingSocket {
+    fn poll_send(
+        &self,
+        state: &udp::UdpState,
+        cx: &mut std::task::Context,
+        transmits: &[udp::Transmit],
+    ) -> std::task::Poll<io::Result<usize>> {
+        let result = self.inner.poll_send(state, cx, transmits);
+        if let std::task::Poll::Ready(Ok(n)) = result {
+            self.sent.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut std::task::Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [udp::RecvMeta],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.inner.poll_recv(cx, bufs, meta)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Holds back a [`FaultySocket`]'s sends while closed, like a send buffer that stays full
+///
+/// Like a tokio socket, it only remembers the last task to register for being woken.
+#[derive(Debug, Default)]
+struct SendGate {
+    closed: std::sync::atomic::AtomicBool,
+    waker: std::sync::Mutex<Option<std::task::Waker>>,
+    /// Datagrams a [lossy](FaultySocket::lossy) socket took while closed
+    lost: std::sync::atomic::AtomicUsize,
+}
+
+impl SendGate {
+    fn close(&self) {
+        self.closed
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn open(&self) {
+        self.closed
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Whether the gate is open, registering `cx` to be woken when it opens if it isn't
+    fn poll_open(&self, cx: &mut std::task::Context) -> bool {
+        if !self.closed.load(std::sync::atomic::Ordering::Relaxed) {
+            return true;
+        }
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        false
+    }
+
+    fn lost(&self) -> usize {
+        self.lost.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Rejects a [`FaultySocket`]'s sends with `EPERM` until a deadline, like an nftables rate limit
+#[derive(Debug, Default)]
+struct RateLimit {
+    until: std::sync::Mutex<Option<Instant>>,
+    rejected: std::sync::atomic::AtomicUsize,
+}
+
+impl RateLimit {
+    fn limit_for(&self, duration: Duration) {
+        *self.until.lock().unwrap() = Some(Instant::now() + duration);
+    }
+
+    fn rejected(&self) -> usize {
+        self.rejected.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Whether a send made now is rejected, counting it if so
+    fn reject(&self) -> bool {
+        let limited = matches!(*self.until.lock().unwrap(), Some(until) if Instant::now() < until);
+        if limited {
+            self.rejected
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        limited
+    }
+}
+
+/// Makes sending through another socket slow or unreliable
+#[derive(Debug)]
+struct FaultySocket {
+    inner: Box<dyn crate::AsyncUdpSocket>,
+    /// How long each send blocks the caller
+    delay: Duration,
+    /// Whether to send one datagram per call
+    one_by_one: bool,
+    /// Whether to send nothing every other call, reporting no progress
+    stutter: bool,
+    skip: std::sync::atomic::AtomicBool,
+    gate: Option<Arc<SendGate>>,
+    /// Whether sends made while `gate` is closed are taken and lost rather than held back
+    lossy: bool,
+    rate_limit: Option<Arc<RateLimit>>,
+    /// What to report as dropped instead of the inner socket's drops
+    drops: Option<Arc<std::sync::Mutex<udp::LocalDrops>>>,
+}
+
+impl FaultySocket {
+    fn new(inner: Box<dyn crate::AsyncUdpSocket>) -> Self {
+        Self {
+            inner,
+            delay: Duration::ZERO,
+            one_by_one: false,
+            stutter: false,
+            skip: std::sync::atomic::AtomicBool::new(false),
+            gate: None,
+            lossy: false,
+            rate_limit: None,
+            drops: None,
+        }
+    }
+
+    fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    fn one_by_one(mut self) -> Self {
+        self.one_by_one = true;
+        self
+    }
+
+    fn stutter(mut self) -> Self {
+        self.stutter = true;
+        self
+    }
+
+    fn gate(mut self, gate: Arc<SendGate>) -> Self {
+        self.gate = Some(gate);
+        self
+    }
+
+    /// Lose what's sent while the gate is closed, like an interface that isn't up
+    fn lossy(mut self) -> Self {
+        self.lossy = true;
+        self
+    }
+
+    fn rate_limit(mut self, limit: Arc<RateLimit>) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    fn drops(mut self, drops: Arc<std::sync::Mutex<udp::LocalDrops>>) -> Self {
+        self.drops = Some(drops);
+        self
+    }
+}
+
+impl crate::AsyncUdpSocket for FaultySocket {
+    fn poll_send(
+        &self,
+        state: &udp::UdpState,
+        cx: &mut std::task::Context,
+        transmits: &[udp::Transmit],
+    ) -> std::task::Poll<io::Result<usize>> {
+        use std::sync::atomic::Ordering;
+        if let Some(ref gate) = self.gate {
+            if self.lossy && gate.closed.load(Ordering::Relaxed) {
+                gate.lost.fetch_add(transmits.len(), Ordering::Relaxed);
+                return std::task::Poll::Ready(Ok(transmits.len()));
+            }
+            if !gate.poll_open(cx) {
+                return std::task::Poll::Pending;
+            }
+        }
+        if let Some(ref limit) = self.rate_limit {
+            if limit.reject() {
+                return std::task::Poll::Ready(Err(io::ErrorKind::PermissionDenied.into()));
+            }
+        }
+        if self.stutter && !self.skip.fetch_xor(true, Ordering::Relaxed) {
+            return std::task::Poll::Ready(Ok(0));
+        }
+        if !self.delay.is_zero() {
+            std::thread::sleep(self.delay);
+        }
+        match self.one_by_one {
+            true => self.inner.poll_send(state, cx, &transmits[..1]),
+            false => self.inner.poll_send(state, cx, transmits),
+        }
+    }
+
+    fn poll_send_ready(&self, cx: &mut std::task::Context) -> std::task::Poll<io::Result<()>> {
+        if let Some(ref gate) = self.gate {
+            if !gate.poll_open(cx) {
+                return std::task::Poll::Pending;
+            }
+        }
+        self.inner.poll_send_ready(cx)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut std::task::Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [udp::RecvMeta],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.inner.poll_recv(cx, bufs, meta)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn local_drops(&self) -> udp::LocalDrops {
+        match self.drops {
+            Some(ref drops) => *drops.lock().unwrap(),
+            None => self.inner.local_drops(),
+        }
+    }
+}
+
+/// Every transmit a [`GroSocket`] sent
+type GroSent = Arc<std::sync::Mutex<Vec<udp::Transmit>>>;
+
+/// Delivers preset GRO buffers, then nothing; records everything sent
+#[derive(Debug)]
+struct GroSocket {
+    addr: SocketAddr,
+    /// Source address, segment size and contents of each buffer
+    buffers: std::sync::Mutex<Vec<(SocketAddr, usize, Vec<u8>)>>,
+    /// ECN codepoint and destination IP the buffers are received with
+    ecn: Option<udp::EcnCodepoint>,
+    dst_ip: Option<IpAddr>,
+    sent: GroSent,
+}
+
+impl GroSocket {
+    fn new(addr: SocketAddr, buffers: Vec<(SocketAddr, usize, Vec<u8>)>) -> Self {
+        Self {
+            addr,
+            buffers: std::sync::Mutex::new(buffers),
+            ecn: None,
+            dst_ip: None,
+            sent: GroSent::default(),
+        }
+    }
+
+    fn marked(mut self, ecn: Option<udp::EcnCodepoint>, dst_ip: Option<IpAddr>) -> Self {
+        self.ecn = ecn;
+        self.dst_ip = dst_ip;
+        self
+    }
+}
+
+impl crate::AsyncUdpSocket for GroSocket {
+    fn poll_send(
+        &self,
+        _: &udp::UdpState,
+        _: &mut std::task::Context,
+        transmits: &[udp::Transmit],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.sent.lock().unwrap().extend_from_slice(transmits);
+        std::task::Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        _: &mut std::task::Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [udp::RecvMeta],
+    ) -> std::task::Poll<io::Result<usize>> {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.is_empty() {
+            return std::task::Poll::Pending;
+        }
+        let n = buffers.len().min(bufs.len());
+        for ((buf, meta), (addr, stride, contents)) in
+            bufs.iter_mut().zip(meta).zip(buffers.drain(..n))
+        {
+            buf[..contents.len()].copy_from_slice(&contents);
+            *meta = udp::RecvMeta::default();
+            meta.addr = addr;
+            meta.len = contents.len();
+            meta.stride = stride;
+            meta.ecn = self.ecn;
+            meta.dst_ip = self.dst_ip;
+        }
+        std::task::Poll::Ready(Ok(n))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+/// Never ready to send or receive, like a socket whose upstream can't be reached
+#[derive(Debug)]
+struct StuckSocket;
+
+impl crate::AsyncUdpSocket for StuckSocket {
+    fn poll_send(
+        &self,
+        _: &udp::UdpState,
+        _: &mut std::task::Context,
+        _: &[udp::Transmit],
+    ) -> std::task::Poll<io::Result<usize>> {
+        std::task::Poll::Pending
+    }
+
+    fn poll_recv(
+        &self,
+        _: &mut std::task::Context,
+        _: &mut [io::IoSliceMut<'_>],
+        _: &mut [udp::RecvMeta],
+    ) -> std::task::Poll<io::Result<usize>> {
+        std::task::Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 1))
+    }
+}
+
+/// Fails every operation, like a socket whose upstream's host is unreachable
+#[derive(Debug)]
+struct BrokenSocket;
+
+impl crate::AsyncUdpSocket for BrokenSocket {
+    fn poll_send(
+        &self,
+        _: &udp::UdpState,
+        _: &mut std::task::Context,
+        _: &[udp::Transmit],
+    ) -> std::task::Poll<io::Result<usize>> {
+        std::task::Poll::Ready(Err(io::ErrorKind::Other.into()))
+    }
+
+    fn poll_recv(
+        &self,
+        _: &mut std::task::Context,
+        _: &mut [io::IoSliceMut<'_>],
+        _: &mut [udp::RecvMeta],
+    ) -> std::task::Poll<io::Result<usize>> {
+        std::task::Poll::Ready(Err(io::ErrorKind::Other.into()))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 1))
+    }
+}
+
+/// Always has garbage to receive, takes everything sent, and records the order of its I/O
+#[derive(Debug, Default)]
+struct FloodSocket {
+    log: Arc<std::sync::Mutex<Vec<&'static str>>>,
+}
+
+impl FloodSocket {
+    fn record(&self, what: &'static str) {
+        let mut log = self.log.lock().unwrap();
+        if log.last() != Some(&what) {
+            log.push(what);
+        }
+    }
+}
+
+impl crate::AsyncUdpSocket for FloodSocket {
+    fn poll_send(
+        &self,
+        _: &udp::UdpState,
+        _: &mut std::task::Context,
+        transmits: &[udp::Transmit],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.record("send");
+        std::task::Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        _: &mut std::task::Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [udp::RecvMeta],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.record("recv");
+        for (buf, meta) in bufs.iter_mut().zip(metas.iter_mut()) {
+            buf[0] = 0;
+            meta.addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 3);
+            meta.len = 1;
+            meta.stride = 1;
+        }
+        std::task::Poll::Ready(Ok(bufs.len().min(metas.len())))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1))
+    }
+}
+
+/// Runs spawned tasks on Tokio, counting them, except for those spawned while `stall` is set,
+/// which are kept but never polled
+#[derive(Default)]
+struct CountingRuntime {
+    spawned: std::sync::atomic::AtomicUsize,
+    stall: std::sync::atomic::AtomicBool,
+    stalled: std::sync::Mutex<Vec<std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>>>,
+}
+
+impl CountingRuntime {
+    fn spawned(&self) -> usize {
+        self.spawned.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+impl std::fmt::Debug for CountingRuntime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CountingRuntime")
+            .field("spawned", &self.spawned)
+            .field("stall", &self.stall)
+            .finish_non_exhaustive()
+    }
+}
+
+impl crate::Runtime for CountingRuntime {
+    fn new_timer(&self, i: std::time::Instant) -> std::pin::Pin<Box<dyn crate::AsyncTimer>> {
+        crate::Runtime::new_timer(&TokioRuntime, i)
+    }
+
+    fn spawn(&self, future: std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>) {
+        self.spawned
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if self.stall.load(std::sync::atomic::Ordering::Relaxed) {
+            self.stalled.lock().unwrap().push(future);
+        } else {
+            crate::Runtime::spawn(&TokioRuntime, future);
+        }
+    }
+
+    fn wrap_udp_socket(&self, t: UdpSocket) -> io::Result<Box<dyn crate::AsyncUdpSocket>> {
+        crate::Runtime::wrap_udp_socket(&TokioRuntime, t)
+    }
+}
+
+/// Does nothing when woken, for polling by hand
+struct NoopWaker;
+
+impl NoopWaker {
+    fn waker() -> std::task::Waker {
+        std::task::Waker::from(Arc::new(Self))
+    }
+}
+
+impl std::task::Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Records that it was woken, as an executor would before polling its task again
+#[derive(Default)]
+struct FlagWaker(std::sync::atomic::AtomicBool);
+
+impl std::task::Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Turns a socket into the one a [`MockRuntime`] hands out
+type WrapSocket =
+    Box<dyn Fn(UdpSocket) -> io::Result<Box<dyn crate::AsyncUdpSocket>> + Send + Sync>;
+
+/// A name lookup in progress, as [`Runtime::resolve`](crate::Runtime::resolve) returns
+type Lookup =
+    std::pin::Pin<Box<dyn std::future::Future<Output = io::Result<Vec<SocketAddr>>> + Send>>;
+
+/// Looks up a host and port for a [`MockRuntime`]
+type Resolve = Box<dyn Fn(String, u16) -> Lookup + Send + Sync>;
+
+/// Runs everything on Tokio, except for the sockets or name lookups a test takes over
+#[derive(Default)]
+struct MockRuntime {
+    wrap: Option<WrapSocket>,
+    /// Whether the first socket, the endpoint's own, is left to Tokio
+    upstream_only: bool,
+    wrapped: std::sync::atomic::AtomicBool,
+    resolve: Option<Resolve>,
+}
+
+impl MockRuntime {
+    /// Hands out `wrap(socket)` for every socket
+    fn wrapping(
+        wrap: impl Fn(UdpSocket) -> io::Result<Box<dyn crate::AsyncUdpSocket>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            wrap: Some(Box::new(wrap)),
+            ..Self::default()
+        }
+    }
+
+    /// Wraps the first socket, the endpoint's own, for Tokio, and hands out `wrap(socket)` for
+    /// the forward connections'
+    fn upstream(
+        wrap: impl Fn(UdpSocket) -> io::Result<Box<dyn crate::AsyncUdpSocket>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            upstream_only: true,
+            ..Self::wrapping(wrap)
+        }
+    }
+
+    /// Looks up every name with `resolve`
+    fn resolving(resolve: impl Fn(String, u16) -> Lookup + Send + Sync + 'static) -> Self {
+        Self {
+            resolve: Some(Box::new(resolve)),
+            ..Self::default()
+        }
+    }
+}
+
+impl std::fmt::Debug for MockRuntime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockRuntime")
+            .field("upstream_only", &self.upstream_only)
+            .finish_non_exhaustive()
+    }
+}
+
+impl crate::Runtime for MockRuntime {
+    fn new_timer(&self, i: std::time::Instant) -> std::pin::Pin<Box<dyn crate::AsyncTimer>> {
+        crate::Runtime::new_timer(&TokioRuntime, i)
+    }
+
+    fn spawn(&self, future: std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>) {
+        crate::Runtime::spawn(&TokioRuntime, future);
+    }
+
+    fn wrap_udp_socket(&self, t: UdpSocket) -> io::Result<Box<dyn crate::AsyncUdpSocket>> {
+        let first = !self
+            .wrapped
+            .swap(true, std::sync::atomic::Ordering::Relaxed);
+        match self.wrap {
+            Some(ref wrap) if !(first && self.upstream_only) => wrap(t),
+            _ => crate::Runtime::wrap_udp_socket(&TokioRuntime, t),
+        }
+    }
+
+    fn resolve(&self, host: String, port: u16) -> Lookup {
+        match self.resolve {
+            Some(ref resolve) => resolve(host, port),
+            None => crate::Runtime::resolve(&TokioRuntime, host, port),
+        }
+    }
+}
+
+#[tokio::test]
+async fn stuck_jls_forward_expires() {
+    let _guard = subscribe();
+
+    let upstream_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9);
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_idle_timeout(Duration::from_millis(200));
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(MockRuntime::upstream(|_| Ok(Box::new(StuckSocket)))),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // A client without JLS credentials gets forwarded, but nothing reaches the upstream
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client.connect(server_addr, "localhost").unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.debug_snapshot().forwards.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("client not forwarded");
+    client.close(0u32.into(), b"done");
+
+    // The forward connection is ended along with the datagrams it could never send
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !server.debug_snapshot().forwards.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("stuck forward connection not ended");
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.active_mappings, 0);
+    assert_eq!(stats.ended_by_idle, 1);
+    assert_eq!(stats.bytes_to_upstream, 0);
+}
+
+#[tokio::test]
+async fn jls_forward_queue_bounded() {
+    let _guard = subscribe();
+
+    let upstream_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9);
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    const LIMIT: u64 = 4000;
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_queue_limit(LIMIT);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(MockRuntime::upstream(|_| Ok(Box::new(StuckSocket)))),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // A client without JLS credentials gets forwarded to an upstream that takes nothing
+    let socket = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let blaster = socket.try_clone().unwrap();
+    let mut client =
+        Endpoint::new(Default::default(), None, socket, Arc::new(TokioRuntime)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client.connect(server_addr, "localhost").unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.debug_snapshot().forwards.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("client not forwarded");
+
+    // Then blasts it with datagrams from the same address
+    const DATAGRAMS: u64 = 20;
+    for _ in 0..DATAGRAMS {
+        let mut datagram = [0; 1000];
+        datagram[0] = 0x40;
+        blaster.send_to(&datagram, server_addr).unwrap();
+    }
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.jls_upstream_stats()[&upstream_addr].dropped_to_upstream < DATAGRAMS - 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("datagrams not dropped");
+    let forward = &server.debug_snapshot().forwards[0];
+    assert!(forward.queued_bytes as u64 <= LIMIT);
+}
+
+#[tokio::test]
+async fn flush_waits_for_queued_datagrams() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::Runtime as _;
+
+    let _guard = subscribe();
+
+    let server = endpoint();
+    let gate = Arc::new(SendGate::default());
+    let sent = Arc::new(AtomicUsize::new(0));
+    let socket = CountingSocket {
+        inner: TokioRuntime
+            .wrap_udp_socket(
+                UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap(),
+            )
+            .unwrap(),
+        sent: sent.clone(),
+    };
+    let client = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        None,
+        Box::new(FaultySocket::new(Box::new(socket)).gate(gate.clone())),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+
+    // Nothing is queued yet
+    tokio::time::timeout(Duration::from_secs(5), client.flush())
+        .await
+        .expect("idle endpoint not flushed")
+        .unwrap();
+
+    let (conn, _server_conn) = tokio::join!(
+        async {
+            client
+                .connect_with(
+                    server.default_client_config.clone().unwrap(),
+                    server.local_addr().unwrap(),
+                    "localhost",
+                )
+                .unwrap()
+                .await
+                .unwrap()
+        },
+        async { server.accept().await.unwrap().await.unwrap() }
+    );
+
+    // Build a backlog the socket won't take
+    gate.close();
+    let mut send = conn.open_uni().await.unwrap();
+    send.write_all(&[0xab; 20_000]).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while client.debug_snapshot().outgoing_datagrams == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no backlog");
+    let backlog = client.debug_snapshot().outgoing_datagrams;
+    let sent_before = sent.load(Ordering::Relaxed);
+
+    let mut flush = tokio::spawn({
+        let client = client.clone();
+        async move { client.flush().await }
+    });
+    assert!(tokio::time::timeout(Duration::from_millis(100), &mut flush)
+        .await
+        .is_err());
+
+    gate.open();
+    tokio::time::timeout(Duration::from_secs(5), flush)
+        .await
+        .expect("backlog not flushed")
+        .unwrap()
+        .unwrap();
+    assert!(sent.load(Ordering::Relaxed) - sent_before >= backlog);
+}
+
+#[tokio::test]
+async fn wait_idle_waits_for_close_to_be_sent() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::Runtime as _;
+
+    let _guard = subscribe();
+
+    let server = endpoint();
+    let gate = Arc::new(SendGate::default());
+    let sent = Arc::new(AtomicUsize::new(0));
+    let socket = CountingSocket {
+        inner: TokioRuntime
+            .wrap_udp_socket(
+                UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap(),
+            )
+            .unwrap(),
+        sent: sent.clone(),
+    };
+    let client = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        None,
+        Box::new(FaultySocket::new(Box::new(socket)).gate(gate.clone())),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+
+    let (conn, server_conn) = tokio::join!(
+        async {
+            client
+                .connect_with(
+                    server.default_client_config.clone().unwrap(),
+                    server.local_addr().unwrap(),
+                    "localhost",
+                )
+                .unwrap()
+                .await
+                .unwrap()
+        },
+        async { server.accept().await.unwrap().await.unwrap() }
+    );
+
+    // The CONNECTION_CLOSE can't leave while the socket is held shut, however long the connection
+    // takes to drain
+    gate.close();
+    let sent_before = sent.load(Ordering::Relaxed);
+    conn.close(0u32.into(), b"bye");
+    let mut idle = tokio::spawn({
+        let client = client.clone();
+        async move { client.wait_idle().await }
+    });
+    assert!(tokio::time::timeout(Duration::from_millis(500), &mut idle)
+        .await
+        .is_err());
+    assert_eq!(sent.load(Ordering::Relaxed), sent_before);
+
+    gate.open();
+    tokio::time::timeout(Duration::from_secs(5), idle)
+        .await
+        .expect("endpoint not idle")
+        .unwrap();
+    assert!(sent.load(Ordering::Relaxed) > sent_before);
+    assert!(matches!(
+        tokio::time::timeout(Duration::from_secs(5), server_conn.closed())
+            .await
+            .expect("close not received"),
+        crate::ConnectionError::ApplicationClosed(_)
+    ));
+}
+
+#[tokio::test]
+async fn drained_connection_not_held_up_by_transmits() {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Context,
+    };
+
+    let _guard = subscribe();
+    let sent = Arc::new(AtomicUsize::new(0));
+    let (mut client, mut driver) = Endpoint::new_with_manual_driver(
+        Default::default(),
+        None,
+        Box::new(SinkSocket { sent: sent.clone() }),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2);
+    let _busy = client.connect(server_addr, "localhost").unwrap();
+    let mut client_config = ClientConfig::with_root_certificates(rustls::RootCertStore::empty());
+    let mut transport_config = TransportConfig::default();
+    transport_config.max_idle_timeout(Some(Duration::from_millis(100).try_into().unwrap()));
+    client_config.transport_config(Arc::new(transport_config));
+    let closing = client
+        .connect_with(client_config, server_addr, "localhost")
+        .unwrap();
+
+    // One connection floods the endpoint with datagrams, ahead of the other's report that it's gone
+    const FLOOD: usize = 10 * crate::IO_LOOP_BOUND;
+    let events = client.event_sender();
+    for _ in 0..FLOOD {
+        let transmit = proto::Transmit {
+            destination: server_addr,
+            ecn: None,
+            contents: Bytes::from_static(&[0; 100]),
+            segment_size: None,
+            src_ip: None,
+            send_at: None,
+        };
+        let event = crate::EndpointEvent::Transmit {
+            transmit,
+            handshake: false,
+            ack_only: false,
+        };
+        events.send((proto::ConnectionHandle(0), event)).unwrap();
+    }
+    tokio::time::timeout(Duration::from_secs(5), closing)
+        .await
+        .unwrap()
+        .unwrap_err();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(client.debug_snapshot().connections.len(), 2);
+
+    let waker = NoopWaker::waker();
+    let mut cx = Context::from_waker(&waker);
+    for _ in 0..2 {
+        assert!(driver.poll_drive(&mut cx).is_pending());
+    }
+
+    // The drained connection was forgotten while most of the flood still waits
+    assert_eq!(client.debug_snapshot().connections.len(), 1);
+    assert!(sent.load(Ordering::Relaxed) < FLOOD);
+}
+
+#[tokio::test]
+async fn jls_forwards_limited() {
+    let _guard = subscribe();
+
+    // Takes everything, answers nothing
+    let upstream = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_limit(2).jls_forward_rate(1);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Clients without JLS credentials, each from an address of its own
+    let mut clients = Vec::new();
+    let mut connect = || {
+        let mut client =
+            Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+        client.set_default_client_config(ClientConfig::with_root_certificates(roots.clone()));
+        let connecting = client.connect(server_addr, "localhost").unwrap();
+        clients.push((client, connecting));
+    };
+    let stats = || server.jls_upstream_stats()[&upstream_addr];
+    async fn wait_for(done: impl Fn() -> bool) -> Result<(), tokio::time::error::Elapsed> {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+    }
+
+    // The first takes the only token, so the second has to wait for the bucket to refill
+    connect();
+    wait_for(|| server.debug_snapshot().forwards.len() == 1)
+        .await
+        .expect("first client not forwarded");
+    connect();
+    wait_for(|| stats().refused_by_rate > 0)
+        .await
+        .expect("second client not refused");
+    assert_eq!(server.debug_snapshot().forwards.len(), 1);
+    wait_for(|| server.debug_snapshot().forwards.len() == 2)
+        .await
+        .expect("second client not forwarded on retransmitting");
+
+    // The third finds every forward connection taken
+    connect();
+    wait_for(|| stats().refused_by_limit > 0)
+        .await
+        .expect("third client not refused");
+    assert_eq!(server.debug_snapshot().forwards.len(), 2);
+    assert_eq!(stats().active_mappings, 2);
+}
+
+#[tokio::test]
+async fn inject_captured_initial() {
+    let _guard = subscribe();
+
+    // Capture a client's Initial
+    let capture = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let client = endpoint();
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut buf = [0; 65536];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(5), capture.recv_from(&mut buf))
+        .await
+        .expect("no Initial")
+        .unwrap();
+    let initial = Bytes::copy_from_slice(&buf[..len]);
+    // Long header: flags, version, then the destination and source connection IDs
+    let dcid_len = initial[5] as usize;
+    let client_scid = &initial[7 + dcid_len..][..initial[6 + dcid_len] as usize];
+
+    // Replay it against a server as though from another address, which gets the response
+    let server = endpoint();
+    let observer = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    server.inject_datagram(observer.local_addr().unwrap(), None, None, initial.clone());
+    let (len, from) = tokio::time::timeout(Duration::from_secs(5), observer.recv_from(&mut buf))
+        .await
+        .expect("no response")
+        .unwrap();
+    assert_eq!(from, server.local_addr().unwrap());
+    // An Initial from the server, addressed to the connection ID the client chose. The fixed bit
+    // may be greased.
+    assert_eq!(buf[0] & 0xb0, 0x80);
+    assert_eq!(buf[1..5], initial[1..5]);
+    assert_eq!(&buf[6..6 + buf[5] as usize], client_scid);
+    assert!(len > 6 + client_scid.len());
+}
+
+#[tokio::test]
+async fn jls_forward_stats() {
+    let _guard = subscribe();
+
+    // Answers every datagram
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 65536];
+        while let Ok((_, client)) = upstream.recv_from(&mut buf).await {
+            let _ = upstream.send_to(&[0; 100], client).await;
+        }
+    });
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    assert_eq!(
+        server.jls_forward_stats(),
+        crate::JlsUpstreamStats::default()
+    );
+
+    // A client without JLS credentials gets forwarded, and hears back from the upstream
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client
+        .connect(server.local_addr().unwrap(), "localhost")
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.jls_forward_stats().bytes_from_upstream == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("nothing relayed from upstream");
+
+    let stats = server.jls_forward_stats();
+    assert!(stats.bytes_to_upstream > 0);
+    assert_eq!(stats.created, 1);
+    assert_eq!(stats.active_mappings, 1);
+}
+
+#[tokio::test]
+async fn driver_timing_attributes_slow_sends() {
+    use std::task::Context;
+
+    let _guard = subscribe();
+    // Takes a while to accept each batch
+    let socket =
+        FaultySocket::new(Box::new(SinkSocket::default())).delay(Duration::from_millis(20));
+    let (mut client, mut driver) = Endpoint::new_with_manual_driver(
+        Default::default(),
+        None,
+        Box::new(socket),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let server_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2);
+    let _connecting = client.connect(server_addr, "localhost").unwrap();
+    // Let the connection queue its Initial
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let waker = NoopWaker::waker();
+    let mut cx = Context::from_waker(&waker);
+    assert!(driver.poll_drive(&mut cx).is_pending());
+    assert!(driver.poll_drive(&mut cx).is_pending());
+
+    let timing = client.driver_timing();
+    assert_eq!(timing.polls, 2);
+    assert!(timing.max_poll >= Duration::from_millis(20));
+    assert!(timing.phases.send >= Duration::from_millis(20));
+    assert!(timing.phases.send > timing.phases.recv + timing.phases.events);
+    assert!(timing.max_phases.send <= timing.max_poll);
+    // Reading the timing starts a new period
+    assert_eq!(client.driver_timing().polls, 0);
+}
+
+#[tokio::test]
+async fn jls_forwards_share_upstream_socket() {
+    let _guard = subscribe();
+
+    // Capture genuine Initials of several handshakes from clients without JLS credentials
+    const CLIENTS: usize = 3;
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let mut initials = Vec::new();
+    let mut connecting = Vec::new();
+    for _ in 0..CLIENTS {
+        connecting.push(
+            client
+                .connect(capture.local_addr().unwrap(), "localhost")
+                .unwrap(),
+        );
+        let mut initial = vec![0; 65536];
+        let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+        initial.truncate(len);
+        initials.push(initial);
+    }
+    // The source connection ID of a long header packet
+    let scid = |packet: &[u8]| {
+        let at = 6 + packet[5] as usize;
+        packet[at + 1..at + 1 + packet[at] as usize].to_vec()
+    };
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // The forwarded clients are played by plain sockets, each sending one of the Initials
+    let mut downstreams = Vec::new();
+    for initial in &initials {
+        let downstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        downstream.send_to(initial, server_addr).await.unwrap();
+        downstreams.push(downstream);
+    }
+
+    // All of them reach the upstream from the same address, and it answers each with an Initial
+    // addressed to the connection ID the client chose
+    let mut buf = vec![0; 65536];
+    let mut relays = std::collections::HashSet::new();
+    for _ in 0..CLIENTS {
+        let (len, relay) =
+            tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+                .await
+                .expect("Initial not forwarded")
+                .unwrap();
+        relays.insert(relay);
+        let client_cid = scid(&buf[..len]);
+        let mut response = vec![0xc0, 0, 0, 0, 1, client_cid.len() as u8];
+        response.extend_from_slice(&client_cid);
+        response.push(8);
+        response.extend_from_slice(&[0xdd; 8]);
+        response.resize(1200, 0);
+        upstream.send_to(&response, relay).await.unwrap();
+    }
+    assert_eq!(relays.len(), 1);
+
+    // Each answer is relayed to the client it was meant for
+    for (downstream, initial) in downstreams.iter().zip(&initials) {
+        let (len, from) =
+            tokio::time::timeout(Duration::from_secs(5), downstream.recv_from(&mut buf))
+                .await
+                .expect("answer not relayed")
+                .unwrap();
+        assert_eq!(from, server_addr);
+        assert_eq!(&buf[6..6 + buf[5] as usize], &scid(initial)[..]);
+        assert_eq!(len, 1200);
+    }
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.active_mappings, CLIENTS as u64);
+    assert_eq!(stats.established, CLIENTS as u64);
+    assert_eq!(stats.unroutable_from_upstream, 0);
+}
+
+#[tokio::test]
+async fn jls_forward_io_outside_driver() {
+    use crate::Runtime as _;
+
+    let _guard = subscribe();
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::new(
+        Default::default(),
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(MockRuntime::upstream(|t| {
+            let socket = TokioRuntime.wrap_udp_socket(t)?;
+            Ok(Box::new(
+                FaultySocket::new(socket).delay(Duration::from_millis(100)),
+            ))
+        })),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // A client without JLS credentials is forwarded through the slow upstream socket
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client.connect(server_addr, "localhost").unwrap();
+    let mut buf = vec![0; 65536];
+    tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+
+    // Sending to the upstream held up the task relaying it, but none of the driver's polls
+    let timing = server.driver_timing();
+    assert!(timing.polls > 0);
+    assert!(timing.max_poll < Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn responses_shaped_per_address() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let mut config = crate::EndpointConfig::default();
+    config.response_rate(1000);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_single_cert(vec![cert], key).unwrap()),
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let victim = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let victim_addr = victim.local_addr().unwrap();
+
+    // Spray Initials of an unsupported version as though from the victim, each of which the
+    // endpoint would answer with a version negotiation packet
+    let mut initial = vec![0xc0, 0x0a, 0x0a, 0x0a, 0x0a, 8];
+    initial.extend_from_slice(&[0xaa; 8]);
+    initial.push(8);
+    initial.extend_from_slice(&[0xbb; 8]);
+    initial.resize(1200, 0);
+    let initial = Bytes::from(initial);
+    let start = Instant::now();
+    for _ in 0..500 {
+        server.inject_datagram(victim_addr, None, None, initial.clone());
+    }
+
+    // Only about a second's worth of responses reaches the victim
+    let mut received = 0;
+    let mut buf = [0; 65536];
+    while let Ok(result) =
+        tokio::time::timeout(Duration::from_millis(200), victim.recv_from(&mut buf)).await
+    {
+        let (len, from) = result.unwrap();
+        assert_eq!(from, server.local_addr().unwrap());
+        received += len;
+    }
+    let allowed = 1000 + 1000 * start.elapsed().as_millis() as usize / 1000;
+    assert!(received > 0);
+    assert!(received <= allowed, "{received} > {allowed}");
+    assert!(server.stats().shaped_responses > 0);
+}
+
+#[tokio::test]
+async fn jls_upstream_bind() {
+    let _guard = subscribe();
+    let upstream = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server = |bind: Option<SocketAddr>| {
+        let server_crypto = jls_server_crypto(cert.clone(), key.clone(), upstream_addr);
+        let mut config = crate::EndpointConfig::default();
+        config.jls_upstream_bind(bind);
+        Endpoint::new(
+            config,
+            Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+            Arc::new(TokioRuntime),
+        )
+        .unwrap()
+    };
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+
+    // By default, an IPv4 upstream is reached from an IPv4 socket
+    let default_bind = server(None);
+    let _connecting = client
+        .connect(default_bind.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut buf = vec![0; 65536];
+    let (_, relay) = tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+    assert!(relay.is_ipv4());
+
+    // An address of the other family fails only the forward connection
+    let wrong_family = server(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)));
+    let _connecting = client
+        .connect(wrong_family.local_addr().unwrap(), "localhost")
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while wrong_family
+            .jls_upstream_stats()
+            .get(&upstream_addr)
+            .map_or(true, |x| x.setup_failures == 0)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("forward setup didn't fail");
+}
+
+#[tokio::test]
+async fn jls_upstream_host() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let _guard = subscribe();
+    let upstream = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    // Where the JLS configuration points, which the configured host overrides
+    let decoy = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let mut server_crypto = server_crypto(cert, key);
+    server_crypto.jls_config = rustls::JlsServerConfig::new(
+        "user_pwd",
+        "user_iv",
+        &format!("https://{}", decoy.local_addr().unwrap()),
+    )
+    .unwrap();
+    let mut config = crate::EndpointConfig::default();
+    config.jls_upstream_host(Some(("upstream.test".into(), upstream_addr.port())));
+    // Resolves every name to the upstream's address, slowly
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let runtime = MockRuntime::resolving({
+        let lookups = lookups.clone();
+        move |host, port| {
+            assert_eq!(host, "upstream.test");
+            lookups.fetch_add(1, Ordering::Relaxed);
+            let addr = SocketAddr::new(upstream_addr.ip(), port);
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok(vec![addr])
+            })
+        }
+    });
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(runtime),
+    )
+    .unwrap();
+
+    // The Initial that started the forward connection is held until the lookup completes
+    let mut buf = vec![0; 65536];
+    for _ in 0..2 {
+        let mut client =
+            Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        client.set_default_client_config(ClientConfig::with_root_certificates(roots.clone()));
+        let _connecting = client
+            .connect(server.local_addr().unwrap(), "localhost")
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+            .await
+            .expect("Initial not forwarded")
+            .unwrap();
+    }
+    // The second connection reused what the first lookup found
+    assert_eq!(lookups.load(Ordering::Relaxed), 1);
+    assert!(server.jls_upstream_stats().contains_key(&upstream_addr));
+}
+
+#[tokio::test]
+async fn jls_upstream_failover() {
+    let _guard = subscribe();
+    // Takes datagrams, but never answers
+    let silent = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let silent_addr = silent.local_addr().unwrap();
+    let alternate = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let alternate_addr = alternate.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, silent_addr);
+    let mut config = crate::EndpointConfig::default();
+    config
+        .jls_upstream_alternates(vec![alternate_addr])
+        .jls_upstream_response_timeout(Duration::from_millis(200));
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client
+        .connect(server.local_addr().unwrap(), "localhost")
+        .unwrap();
+
+    // The primary gets the ClientHello first, and the alternate once the primary stays silent
+    let mut hello = vec![0; 65536];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(5), silent.recv_from(&mut hello))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+    hello.truncate(len);
+    let mut buf = vec![0; 65536];
+    let (len, relay) = tokio::time::timeout(Duration::from_secs(5), alternate.recv_from(&mut buf))
+        .await
+        .expect("Initial not replayed to the alternate")
+        .unwrap();
+    assert_eq!(&buf[..len], &hello[..]);
+
+    // Once the alternate answers, the connection stays with it
+    alternate.send_to(&buf[..len], relay).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let snapshot = server.debug_snapshot();
+    assert_eq!(snapshot.forwards.len(), 1);
+    assert_eq!(snapshot.forwards[0].upstream_address, alternate_addr);
+    assert_eq!(snapshot.forwards[0].failovers, 1);
+    let stats = server.jls_upstream_stats();
+    assert_eq!(stats[&silent_addr].ended_unanswered, 1);
+    assert_eq!(stats[&silent_addr].failovers, 1);
+    assert_eq!(stats[&alternate_addr].created, 1);
+}
+
+#[tokio::test]
+async fn attached_socket() {
+    let _guard = subscribe();
+    let server = endpoint();
+    let server_addr = server.local_addr().unwrap();
+    let secondary = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let secondary_addr = secondary.local_addr().unwrap();
+    let id = server.attach_socket(secondary).unwrap();
+
+    // Stands in for a middlebox that starts steering the client to the secondary port
+    let front = Arc::new(
+        tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap(),
+    );
+    let back = Arc::new(
+        tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap(),
+    );
+    let proxy_addr = front.local_addr().unwrap();
+    let target = Arc::new(std::sync::Mutex::new(server_addr));
+    let replied_from = Arc::new(std::sync::Mutex::new(None));
+    let proxy = tokio::spawn({
+        let target = target.clone();
+        let replied_from = replied_from.clone();
+        async move {
+            let mut client = None;
+            let mut front_buf = vec![0; 65536];
+            let mut back_buf = vec![0; 65536];
+            loop {
+                tokio::select! {
+                    Ok((len, from)) = front.recv_from(&mut front_buf) => {
+                        client = Some(from);
+                        let to = *target.lock().unwrap();
+                        let _ = back.send_to(&front_buf[..len], to).await;
+                    }
+                    Ok((len, from)) = back.recv_from(&mut back_buf) => {
+                        *replied_from.lock().unwrap() = Some(from);
+                        if let Some(client) = client {
+                            let _ = front.send_to(&back_buf[..len], client).await;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    let server_task = tokio::spawn({
+        let server = server.clone();
+        async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            while let Ok(mut stream) = connection.accept_uni().await {
+                assert_eq!(stream.read_to_end(5).await.unwrap(), b"hello");
+            }
+        }
+    });
+    let client = endpoint();
+    let connection = client
+        .connect(proxy_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    // Finishing a stream waits for the server to acknowledge it
+    let send = |connection: crate::Connection| async move {
+        let mut stream = connection.open_uni().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), stream.finish())
+            .await
+            .expect("stream not acknowledged")
+            .unwrap();
+    };
+    send(connection.clone()).await;
+    assert_eq!(*replied_from.lock().unwrap(), Some(server_addr));
+
+    // The connection carries on via the secondary socket, still answered from the primary
+    *target.lock().unwrap() = secondary_addr;
+    send(connection.clone()).await;
+    assert_eq!(*replied_from.lock().unwrap(), Some(server_addr));
+
+    // Unless answers are to go out the socket the client last used
+    server.set_reply_socket(crate::ReplySocket::LastUsed);
+    send(connection.clone()).await;
+    assert_eq!(*replied_from.lock().unwrap(), Some(secondary_addr));
+
+    assert!(server.detach_socket(id));
+    assert!(!server.detach_socket(id));
+    // Back on the primary socket, and answered from it once more
+    *target.lock().unwrap() = server_addr;
+    send(connection.clone()).await;
+    assert_eq!(*replied_from.lock().unwrap(), Some(server_addr));
+
+    connection.close(0u32.into(), b"done");
+    server_task.await.unwrap();
+    proxy.abort();
+}
+
+#[tokio::test]
+async fn jls_forward_gso() {
+    let _guard = subscribe();
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    // Short-header packets, which only the upstream could make sense of
+    let junk = |count| {
+        let mut buf = vec![0; count * len];
+        rand::thread_rng().fill_bytes(&mut buf);
+        for segment in buf.chunks_mut(len) {
+            segment[0] = 0x40;
+        }
+        buf
+    };
+    let remote = "[::1]:40001".parse().unwrap();
+    let upstream_addr = "[::1]:40003".parse().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_socket_per_client(true);
+    let to_client = GroSent::default();
+    let to_upstream = GroSent::default();
+    let _server = Endpoint::new_with_abstract_socket(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        Box::new(GroSocket {
+            sent: to_client.clone(),
+            ..GroSocket::new(
+                "[::1]:4433".parse().unwrap(),
+                vec![(remote, len, initial), (remote, len, junk(3))],
+            )
+        }),
+        // The upstream's socket is bound for real only to have an address
+        Arc::new(MockRuntime::wrapping({
+            let (buffer, sent) = ((upstream_addr, len, junk(4)), to_upstream.clone());
+            move |t| {
+                Ok(Box::new(GroSocket {
+                    sent: sent.clone(),
+                    ..GroSocket::new(t.local_addr()?, vec![buffer.clone()])
+                }))
+            }
+        })),
+    )
+    .unwrap();
+
+    let total = |sent: &GroSent| {
+        sent.lock()
+            .unwrap()
+            .iter()
+            .map(|x| x.contents.len())
+            .sum::<usize>()
+    };
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while total(&to_upstream) < 4 * len || total(&to_client) < 4 * len {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("datagrams not relayed");
+
+    // Batches stay whole wherever the socket they go out of can send them that way
+    let batched = |count| match udp::UdpState::new().max_gso_segments() >= count {
+        true => vec![(Some(len), count * len)],
+        false => vec![(None, len); count],
+    };
+    let sent = |sent: &GroSent, to| {
+        sent.lock()
+            .unwrap()
+            .iter()
+            .map(|x| {
+                assert_eq!(x.destination, to);
+                (x.segment_size, x.contents.len())
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut expected = vec![(None, len)];
+    expected.extend(batched(3));
+    assert_eq!(sent(&to_upstream, upstream_addr), expected);
+    assert_eq!(sent(&to_client, remote), batched(4));
+}
+
+#[tokio::test]
+async fn jls_forward_first_flight() {
+    let _guard = subscribe();
+    // Capture a genuine Initial from a client without JLS credentials, to replay as if the client
+    // were retransmitting it
+    let capture = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    /// Forward a client sending `initial` four times, the upstream answering the first if
+    /// `responsive`
+    async fn forward(
+        initial: &[u8],
+        responsive: bool,
+    ) -> (crate::ForwardSnapshot, crate::JlsUpstreamStats) {
+        let upstream = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let (cert, key) = self_signed_cert();
+        let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+        let mut config = crate::EndpointConfig::default();
+        config.jls_forward_unanswered_warning(2);
+        let server = Endpoint::new(
+            config,
+            Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+            Arc::new(TokioRuntime),
+        )
+        .unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+
+        let mut buf = vec![0; 65536];
+        for i in 0..4 {
+            client.send_to(initial, server_addr).await.unwrap();
+            let (len, relay) =
+                tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+                    .await
+                    .expect("Initial not forwarded")
+                    .unwrap();
+            if responsive && i == 0 {
+                upstream.send_to(&buf[..len], relay).await.unwrap();
+                tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                    .await
+                    .expect("answer not relayed")
+                    .unwrap();
+            }
+        }
+        let snapshot = server.debug_snapshot();
+        assert_eq!(snapshot.forwards.len(), 1);
+        (
+            snapshot.forwards[0].clone(),
+            server.jls_upstream_stats()[&upstream_addr],
+        )
+    }
+
+    let (forward, stats) = forward(&initial, true).await;
+    assert_eq!(forward.unanswered_datagrams, 1);
+    assert!(forward.first_response.unwrap() < Duration::from_secs(5));
+    assert_eq!(stats.unanswered_datagrams, 1);
+    assert_eq!(stats.unanswered_warnings, 0);
+
+    let (forward, stats) = forward(&initial, false).await;
+    assert_eq!(forward.unanswered_datagrams, 4);
+    assert_eq!(forward.first_response, None);
+    assert_eq!(stats.unanswered_datagrams, 4);
+    assert_eq!(stats.unanswered_warnings, 1);
+}
+
+#[tokio::test]
+async fn stateless_reset_policy() {
+    let _guard = subscribe();
+
+    /// Whether a peer still sending to a connection the endpoint forgot gets an answer
+    async fn answered(policy: crate::StatelessResetPolicy, response_rate: u64) -> bool {
+        let mut config = crate::EndpointConfig::default();
+        config
+            .stateless_reset_policy(policy)
+            .response_rate(response_rate);
+        let server = Endpoint::new(
+            config,
+            None,
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+            Arc::new(TokioRuntime),
+        )
+        .unwrap();
+        let peer = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let mut packet = vec![0; 64];
+        rand::thread_rng().fill_bytes(&mut packet);
+        packet[0] = 0x40 | packet[0] & 0x3f;
+        peer.send_to(&packet, server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buf = vec![0; 65536];
+        match tokio::time::timeout(Duration::from_millis(200), peer.recv_from(&mut buf)).await {
+            Ok(Ok((len, _))) => {
+                // A stateless reset passes for a short-header packet, smaller than what prompted it
+                assert_eq!(buf[0] & 0xc0, 0x40);
+                assert!(len < packet.len());
+                true
+            }
+            Ok(Err(e)) => panic!("{}", e),
+            Err(_) => false,
+        }
+    }
+
+    use crate::StatelessResetPolicy::*;
+    assert!(answered(Always, 4096).await);
+    assert!(answered(Always, 1).await);
+    assert!(answered(RateLimited, 4096).await);
+    assert!(!answered(RateLimited, 1).await);
+    assert!(!answered(Silent, 4096).await);
+}
+
+#[tokio::test]
+async fn close_drains_jls_forwards() {
+    let _guard = subscribe();
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::new(
+        Default::default(),
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let peer = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    peer.send_to(&initial, server_addr).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.debug_snapshot().forwards.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("client not forwarded");
+
+    // What the client already sent still reaches the upstream, and then the forward ends
+    server.close(0u32.into(), b"");
+    let mut buf = vec![0; 65536];
+    tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server.wait_idle())
+        .await
+        .expect("forward connection outlived the endpoint");
+    assert!(server.debug_snapshot().forwards.is_empty());
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.ended_by_close, 1);
+    assert_eq!(stats.active_mappings, 0);
+
+    // Nor is the client forwarded again
+    peer.send_to(&initial, server_addr).await.unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), upstream.recv_from(&mut buf))
+            .await
+            .is_err(),
+        "client forwarded after close"
+    );
+}
+
+#[tokio::test]
+async fn evict_jls_forward() {
+    let _guard = subscribe();
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_socket_per_client(true);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let peer = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    peer.send_to(&initial, server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mut buf = vec![0; 65536];
+    let (_, relay) = tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+    upstream.send_to(&[0x40; 100], relay).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+        .await
+        .expect("answer not relayed")
+        .unwrap();
+
+    let forwards = server.jls_forwards();
+    assert_eq!(forwards.len(), 1);
+    assert_eq!(forwards[0].remote_address, peer_addr);
+    assert_eq!(forwards[0].upstream_address, upstream_addr);
+    assert_eq!(forwards[0].bytes_to_upstream, len as u64);
+    assert_eq!(forwards[0].bytes_from_upstream, 100);
+    assert_eq!(forwards[0].queued_datagrams, 0);
+
+    assert!(server.evict_jls_forward(peer_addr));
+    assert!(!server.evict_jls_forward(peer_addr));
+    assert!(server.jls_forwards().is_empty());
+    assert_eq!(
+        server.jls_upstream_stats()[&upstream_addr].ended_by_eviction,
+        1
+    );
+    // The client's own socket to the upstream is closed, freeing its port
+    let port = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), relay.port());
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while UdpSocket::bind(port).is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("upstream socket not closed");
+}
+
+#[tokio::test]
+async fn connect_timeout() {
+    let _guard = subscribe();
+    // Swallows everything sent to it
+    let black_hole = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let mut client_config =
+        crate::ClientConfig::with_root_certificates(rustls::RootCertStore::empty());
+    let mut transport_config = crate::TransportConfig::default();
+    transport_config.initial_rtt(Duration::from_millis(10));
+    client_config.transport_config(Arc::new(transport_config));
+
+    let err = client
+        .connect_with(client_config, black_hole.local_addr().unwrap(), "localhost")
+        .unwrap()
+        .with_timeout(Duration::from_millis(100))
+        .await
+        .unwrap_err();
+    assert_eq!(err, crate::ConnectionError::ConnectTimedOut);
+
+    // Let the close packet go out, then count what arrived
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut buf = vec![0; 65536];
+    let mut received = 0;
+    while black_hole.try_recv_from(&mut buf).is_ok() {
+        received += 1;
+    }
+    assert!(received > 0);
+    // Several PTOs later, nothing more has been sent
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(black_hole.try_recv_from(&mut buf).is_err());
+    assert!(client.debug_snapshot().connections.is_empty());
+}
+
+#[tokio::test]
+async fn per_ip_stats() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_config = crate::ServerConfig::with_single_cert(vec![cert], key).unwrap();
+    let server = Endpoint::server(
+        server_config,
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = reported.clone();
+    server.set_ip_threshold(
+        crate::IpThreshold {
+            bytes: 50_000,
+            connections: u64::MAX,
+        },
+        move |ip, stats| sink.lock().unwrap().push((ip, *stats)),
+    );
+
+    // Reads every stream to the end, keeping connections open until their peers close them
+    let acceptor = server.clone();
+    tokio::spawn(async move {
+        while let Some(connecting) = acceptor.accept().await {
+            let connection = connecting.await.unwrap();
+            tokio::spawn(async move {
+                while let Ok(mut recv) = connection.accept_uni().await {
+                    recv.read_to_end(usize::MAX).await.unwrap();
+                }
+            });
+        }
+    });
+
+    let small = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let large = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    let mut connections = Vec::new();
+    for (ip, size) in [(small, 1_000), (large, 100_000)] {
+        let mut client = Endpoint::client(SocketAddr::new(ip, 0)).unwrap();
+        client.set_default_client_config(ClientConfig::with_root_certificates(roots.clone()));
+        let connection = client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let mut send = connection.open_uni().await.unwrap();
+        send.write_all(&vec![0; size]).await.unwrap();
+        send.finish().await.unwrap();
+        connections.push((client, connection));
+    }
+
+    let stats = server.per_ip_stats();
+    assert_eq!(stats.len(), 2);
+    for ip in [small, large] {
+        assert_eq!(stats[&ip].connections_opened, 1);
+        assert_eq!(stats[&ip].connections_open, 1);
+        assert!(stats[&ip].bytes_sent > 0);
+    }
+    assert!(stats[&small].bytes_received >= 1_000);
+    assert!(stats[&small].bytes_received + stats[&small].bytes_sent < 50_000);
+    assert!(stats[&large].bytes_received >= 100_000);
+    // Only the address that passed the byte limit is reported
+    let reported = reported.lock().unwrap().clone();
+    assert_eq!(reported.len(), 1);
+    assert_eq!(reported[0].0, large);
+
+    for (_, connection) in &connections {
+        connection.close(0u32.into(), b"done");
+    }
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server
+            .per_ip_stats()
+            .values()
+            .any(|x| x.connections_open > 0)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("connections not drained");
+    assert_eq!(server.per_ip_stats()[&large].connections_opened, 1);
+}
+
+#[test]
+fn jls_client_redaction() {
+    use crate::{jls_forward::client_label, JlsClientRedaction};
+    use std::collections::hash_map::RandomState;
+
+    let key = RandomState::new();
+    let v4 = "203.0.113.77:4433".parse().unwrap();
+    let mapped = "[::ffff:203.0.113.77]:4433".parse().unwrap();
+    let v6 = "[2001:db8:1234:5678::1]:4433".parse().unwrap();
+    assert_eq!(
+        client_label(v4, JlsClientRedaction::Off, &key),
+        "203.0.113.77:4433"
+    );
+    for addr in [v4, mapped] {
+        assert_eq!(
+            client_label(addr, JlsClientRedaction::Prefix, &key),
+            "203.0.113.0/24"
+        );
+    }
+    assert_eq!(
+        client_label(v6, JlsClientRedaction::Prefix, &key),
+        "2001:db8:1234::/48"
+    );
+    // The same client gets the same hash, whichever way its address is reported
+    let hashed = client_label(v4, JlsClientRedaction::Hashed, &key);
+    assert_eq!(hashed.len(), 16);
+    assert_eq!(
+        hashed,
+        client_label(mapped, JlsClientRedaction::Hashed, &key)
+    );
+    // Another endpoint's key gives another hash
+    assert_ne!(
+        hashed,
+        client_label(v4, JlsClientRedaction::Hashed, &RandomState::new())
+    );
+}
+
+#[tokio::test]
+async fn jls_forwarding_disabled() {
+    let _guard = subscribe();
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut endpoint_config = crate::EndpointConfig::default();
+    endpoint_config.jls_forwarding(false);
+    let server = Endpoint::new(
+        endpoint_config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // A client without JLS credentials is ignored rather than forwarded
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let err = client
+        .connect(server_addr, "localhost")
+        .unwrap()
+        .with_timeout(Duration::from_millis(300))
+        .await
+        .unwrap_err();
+    assert_eq!(err, crate::ConnectionError::ConnectTimedOut);
+    let mut buf = vec![0; 65536];
+    assert!(upstream.try_recv_from(&mut buf).is_err());
+    assert!(server.jls_upstream_stats().is_empty());
+    let snapshot = server.debug_snapshot();
+    assert!(snapshot.forwards.is_empty());
+    assert!(snapshot.connections.is_empty());
+}
+
+#[tokio::test]
+async fn jls_forward_unanswered_limit() {
+    let _guard = subscribe();
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    // An upstream that never answers, as when the client's address is spoofed
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    const LIMIT: usize = 3000;
+    let mut endpoint_config = crate::EndpointConfig::default();
+    endpoint_config.jls_forward_unanswered_limit(LIMIT as u64);
+    let server = Endpoint::new(
+        endpoint_config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Once the Initial is forwarded, it's followed by a burst of short header packets
+    let downstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    downstream.send_to(&initial, server_addr).await.unwrap();
+    let mut buf = vec![0; 65536];
+    let (mut relayed, _) =
+        tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+            .await
+            .expect("Initial not forwarded")
+            .unwrap();
+    let mut junk = vec![0x40];
+    junk.resize(1200, 0xee);
+    for _ in 0..20 {
+        downstream.send_to(&junk, server_addr).await.unwrap();
+    }
+
+    while let Ok(x) =
+        tokio::time::timeout(Duration::from_millis(300), upstream.recv_from(&mut buf)).await
+    {
+        relayed += x.unwrap().0;
+    }
+    assert!(relayed >= initial.len());
+    assert!(relayed <= LIMIT, "{relayed} bytes relayed");
+    assert!(server.jls_upstream_stats()[&upstream_addr].dropped_unanswered > 0);
+}
+
+#[tokio::test]
+async fn pacing_horizon() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let mut transport_config = crate::TransportConfig::default();
+    transport_config.pacing_horizon(Some(Duration::from_millis(5)));
+    let transport_config = Arc::new(transport_config);
+    let mut server_config = crate::ServerConfig::with_single_cert(vec![cert], key).unwrap();
+    server_config.transport_config(transport_config.clone());
+    let server = Endpoint::server(
+        server_config,
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client_config = ClientConfig::with_root_certificates(roots);
+    client_config.transport_config(transport_config);
+    let client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+
+    // Echoes one stream, so paced transmits flow both ways
+    tokio::spawn(async move {
+        let connection = server.accept().await.unwrap().await.unwrap();
+        let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+        let data = recv.read_to_end(usize::MAX).await.unwrap();
+        send.write_all(&data).await.unwrap();
+        send.finish().await.unwrap();
+    });
+
+    let connection = client
+        .connect_with(client_config, server_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let data = (0..4 * 1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+    let (mut send, mut recv) = connection.open_bi().await.unwrap();
+    let (_, echoed) = tokio::join!(
+        async {
+            send.write_all(&data).await.unwrap();
+            send.finish().await.unwrap();
+        },
+        async { recv.read_to_end(usize::MAX).await.unwrap() }
+    );
+    // Compared without printing megabytes on failure
+    assert!(echoed == data);
+}
+
+#[tokio::test]
+async fn rebind_idle() {
+    use std::{
+        sync::atomic::Ordering,
+        task::{Context, Waker},
+    };
+
+    let _guard = subscribe();
+    let runtime: Arc<dyn crate::Runtime> = Arc::new(TokioRuntime);
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let (endpoint, mut driver) = Endpoint::new_with_manual_driver(
+        Default::default(),
+        None,
+        runtime.wrap_udp_socket(socket).unwrap(),
+        runtime,
+    )
+    .unwrap();
+    let flag = Arc::new(FlagWaker::default());
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    assert!(driver.poll_drive(&mut cx).is_pending());
+    flag.0.store(false, Ordering::Relaxed);
+
+    // Rebinding twice in a row leaves the endpoint on the second socket, and wakes the driver
+    let old = endpoint.local_addr().unwrap();
+    let first = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let intermediate = first.local_addr().unwrap();
+    endpoint.rebind(first).unwrap();
+    let second = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let new = second.local_addr().unwrap();
+    endpoint.rebind(second).unwrap();
+    assert_eq!(endpoint.local_addr().unwrap(), new);
+    assert!(flag.0.load(Ordering::Relaxed));
+
+    // A datagram to the new address is received on the very next poll
+    let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    peer.send_to(&[0; 1200], new).unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(driver.poll_drive(&mut cx).is_pending());
+    let stats = endpoint.per_ip_stats();
+    assert_eq!(stats[&IpAddr::V4(Ipv4Addr::LOCALHOST)].bytes_received, 1200);
+
+    // By then, the sockets the endpoint was on before are closed
+    UdpSocket::bind(old).unwrap();
+    UdpSocket::bind(intermediate).unwrap();
+}
+
+#[tokio::test]
+async fn connect_with_options() {
+    use crate::{congestion, ConnectOptions};
+
+    let _guard = subscribe();
+    let endpoint = endpoint();
+    let server_addr = endpoint.local_addr().unwrap();
+    let acceptor = endpoint.clone();
+    let server = tokio::spawn(async move {
+        for _ in 0..2 {
+            let conn = acceptor.accept().await.unwrap().await.unwrap();
+            tokio::spawn(async move { conn.closed().await });
+        }
+    });
+
+    // Each connection gets the controller it was opened with, though they share a config
+    let mut long_haul = ConnectOptions::new();
+    long_haul.congestion_controller_factory(Arc::new(congestion::BbrConfig::default()));
+    let mut tiny = ConnectOptions::new();
+    tiny.congestion_controller_factory(Arc::new(congestion::NewRenoConfig::default()));
+    let bbr = endpoint
+        .connect_with_options(server_addr, "localhost", &long_haul)
+        .unwrap()
+        .await
+        .unwrap();
+    let new_reno = endpoint
+        .connect_with_options(server_addr, "localhost", &tiny)
+        .unwrap()
+        .await
+        .unwrap();
+    server.await.unwrap();
+
+    let controller = bbr.congestion_state().into_any();
+    assert!(controller.downcast::<congestion::Bbr>().is_ok());
+    let controller = new_reno.congestion_state().into_any();
+    assert!(controller.downcast::<congestion::NewReno>().is_ok());
+}
+
+#[tokio::test]
+async fn shutdown_progress() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_single_cert(vec![cert], key).unwrap(),
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+
+    let mut progress = server.shutdown_progress();
+    assert_eq!(*progress.borrow(), crate::ShutdownProgress::default());
+
+    // One connection is accepted, and the other left waiting to be
+    let accepted = client
+        .connect(server_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let _server_conn = server.accept().await.unwrap().await.unwrap();
+    let waiting = client
+        .connect(server_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+
+    server.close(0u32.into(), b"bye");
+    let mut snapshots = vec![*progress.borrow_and_update()];
+    while !snapshots.last().unwrap().finished {
+        tokio::time::timeout(Duration::from_secs(5), progress.changed())
+            .await
+            .expect("shutdown stalled")
+            .unwrap();
+        snapshots.push(*progress.borrow_and_update());
+    }
+    let first = snapshots[0];
+    assert!(first.started);
+    assert_eq!(first.connections, 2);
+    assert_eq!(first.closed_cleanly, 0);
+    for pair in snapshots.windows(2) {
+        assert!(pair[1].connections <= pair[0].connections);
+        assert!(pair[1].closed_cleanly >= pair[0].closed_cleanly);
+    }
+    let last = *snapshots.last().unwrap();
+    assert_eq!(last.connections, 0);
+    assert_eq!(last.forwards, 0);
+    assert_eq!(last.closed_cleanly, 2);
+    assert_eq!(last.closed_forcibly, 0);
+    drop((accepted, waiting));
+}
+
+#[tokio::test]
+async fn jls_forward_migration() {
+    let _guard = subscribe();
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // The forwarded client is played by plain sockets, standing for the addresses its NAT gives it
+    // before and after rebinding
+    let before = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let after = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut buf = vec![0; 65536];
+    let timeout = Duration::from_secs(5);
+
+    // The upstream answers with an Initial of its own, telling the client its connection ID
+    before.send_to(&initial, server_addr).await.unwrap();
+    let (_, forwarder) = tokio::time::timeout(timeout, upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+    let scid_at = 6 + initial[5] as usize;
+    let scid = &initial[scid_at..scid_at + 1 + initial[scid_at] as usize];
+    let mut response = vec![0xc0, 0, 0, 0, 1];
+    response.extend_from_slice(scid);
+    response.push(8);
+    response.extend_from_slice(&[0xdd; 8]);
+    response.resize(1200, 0);
+    upstream.send_to(&response, forwarder).await.unwrap();
+    let (len, _) = tokio::time::timeout(timeout, before.recv_from(&mut buf))
+        .await
+        .expect("response not relayed")
+        .unwrap();
+    assert_eq!(&buf[..len], &response[..]);
+
+    // A short header packet to the upstream's connection ID arrives from the client's new address
+    let mut moved = vec![0x40];
+    moved.extend_from_slice(&[0xdd; 8]);
+    moved.resize(100, 0);
+    after.send_to(&moved, server_addr).await.unwrap();
+    let (len, from) = tokio::time::timeout(timeout, upstream.recv_from(&mut buf))
+        .await
+        .expect("datagram from new address not forwarded")
+        .unwrap();
+    assert_eq!(&buf[..len], &moved[..]);
+    assert_eq!(from, forwarder);
+
+    // The upstream's answers now go to the new address
+    let mut answer = vec![0x40];
+    answer.extend_from_slice(&[0xee; 8]);
+    answer.resize(100, 0);
+    upstream.send_to(&answer, forwarder).await.unwrap();
+    let (len, _) = tokio::time::timeout(timeout, after.recv_from(&mut buf))
+        .await
+        .expect("answer not relayed to new address")
+        .unwrap();
+    assert_eq!(&buf[..len], &answer[..]);
+
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.migrations, 1);
+    assert_eq!(stats.active_mappings, 1);
+}
+
+#[tokio::test]
+async fn set_jls_config() {
+    let _guard = subscribe();
+
+    // Upstreams before and after the rotation, which never answer
+    let old_upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let old_upstream_addr = old_upstream.local_addr().unwrap();
+    let new_upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let new_upstream_addr = new_upstream.local_addr().unwrap();
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let mut server_crypto = server_crypto(cert, key);
+    server_crypto.jls_config = rustls::JlsServerConfig::new(
+        "old_pwd",
+        "old_iv",
+        &format!("https://{}", old_upstream_addr),
+    )
+    .unwrap();
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // Each client gets its own endpoint, as forwarding is keyed by the client's address
+    let client = |pwd: &str, iv: &str| {
+        let client_crypto = jls_client_crypto(roots.clone(), pwd, iv);
+        let mut client =
+            Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+        client.set_default_client_config(ClientConfig::new(Arc::new(client_crypto)));
+        client
+    };
+    async fn forwarded_to(upstream: &tokio::net::UdpSocket) {
+        let mut buf = [0; 65536];
+        tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+            .await
+            .expect("Initial not forwarded")
+            .unwrap();
+    }
+
+    // Before the rotation, the old secret authenticates and others go to the old upstream
+    let old_client = client("old_pwd", "old_iv");
+    let (conn, server_conn) = tokio::join!(
+        async { old_client.connect(server_addr, "localhost").unwrap().await },
+        async { server.accept().await.unwrap().await }
+    );
+    let old_conn = conn.unwrap();
+    let old_server_conn = server_conn.unwrap();
+    assert_eq!(old_server_conn.is_jls(), Some(true));
+    let plain = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    let _plain_connecting = plain
+        .connect_with(
+            ClientConfig::with_root_certificates(roots.clone()),
+            server_addr,
+            "localhost",
+        )
+        .unwrap();
+    forwarded_to(&old_upstream).await;
+
+    assert!(server.set_jls_config(
+        rustls::JlsServerConfig::new(
+            "new_pwd",
+            "new_iv",
+            &format!("https://{}", new_upstream_addr),
+        )
+        .unwrap()
+    ));
+
+    // The old secret no longer authenticates, so its ClientHello is forwarded to the new upstream
+    let stale_client = client("old_pwd", "old_iv");
+    let _stale_connecting = stale_client.connect(server_addr, "localhost").unwrap();
+    forwarded_to(&new_upstream).await;
+
+    // The new secret authenticates
+    let new_client = client("new_pwd", "new_iv");
+    let (conn, server_conn) = tokio::join!(
+        async { new_client.connect(server_addr, "localhost").unwrap().await },
+        async { server.accept().await.unwrap().await }
+    );
+    conn.unwrap();
+    assert_eq!(server_conn.unwrap().is_jls(), Some(true));
+
+    // What was established before is undisturbed
+    assert!(old_conn.close_reason().is_none());
+    assert!(old_server_conn.close_reason().is_none());
+    let stats = server.jls_upstream_stats();
+    assert_eq!(stats[&old_upstream_addr].active_mappings, 1);
+    assert_eq!(stats[&new_upstream_addr].active_mappings, 1);
+
+    // Client endpoints have nothing to rotate
+    assert!(!old_client.set_jls_config(
+        rustls::JlsServerConfig::new("pwd", "iv", &format!("https://{}", new_upstream_addr))
+            .unwrap()
+    ));
+}
+
+#[tokio::test]
+async fn client_with_jls() {
+    let _guard = subscribe();
+
+    // A genuine QUIC server standing upstream, which forwarded clients end up handshaking with
+    let upstream = endpoint();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Some(incoming) = upstream.accept().await {
+            let _ = incoming.await;
+        }
+    });
+
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+
+    assert_eq!(
+        ClientConfig::with_jls("", "user_iv").unwrap_err(),
+        crate::ConfigError::EmptyJlsCredential
+    );
+
+    // The right credentials authenticate the server, though the client trusts no certificate
+    let (conn, server_conn) = tokio::join!(
+        async {
+            let config = ClientConfig::with_jls("user_pwd", "user_iv").unwrap();
+            client
+                .connect_with(config, server_addr, "localhost")
+                .unwrap()
+                .await
+        },
+        async { server.accept().await.unwrap().await }
+    );
+    assert_eq!(conn.unwrap().is_jls(), Some(true));
+    assert_eq!(server_conn.unwrap().is_jls(), Some(true));
+
+    // A wrong password gets the client forwarded upstream, which it's told apart from other
+    // handshake failures
+    let config = ClientConfig::with_jls("wrong_pwd", "user_iv").unwrap();
+    let err = tokio::time::timeout(
+        Duration::from_secs(5),
+        client
+            .connect_with(config, server_addr, "localhost")
+            .unwrap(),
+    )
+    .await
+    .expect("connection not refused")
+    .unwrap_err();
+    assert_eq!(err, crate::ConnectionError::JlsRejected);
+    assert_eq!(server.jls_upstream_stats()[&upstream_addr].established, 1);
+}
+
+#[tokio::test]
+async fn jls_authenticated() {
+    let _guard = subscribe();
+
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let mut server_crypto = server_crypto(cert, key);
+    let plain_server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto.clone())),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    server_crypto.jls_config =
+        rustls::JlsServerConfig::new("user_pwd", "user_iv", "https://[::1]:443").unwrap();
+    let jls_server = Endpoint::server(
+        crate::ServerConfig::with_crypto(Arc::new(server_crypto)),
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+
+    let client_crypto = jls_client_crypto(roots.clone(), "user_pwd", "user_iv");
+    let client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+
+    for (server, config, jls) in [
+        (
+            &jls_server,
+            ClientConfig::new(Arc::new(client_crypto.clone())),
+            true,
+        ),
+        (
+            &plain_server,
+            ClientConfig::with_root_certificates(roots.clone()),
+            false,
+        ),
+        // Credentials are of no use with a server that doesn't use JLS
+        (
+            &plain_server,
+            ClientConfig::new(Arc::new(client_crypto)),
+            false,
+        ),
+    ] {
+        let (conn, server_conn) = tokio::join!(
+            async {
+                client
+                    .connect_with(config, server.local_addr().unwrap(), "localhost")
+                    .unwrap()
+                    .await
+            },
+            async {
+                let connecting = server.accept().await.unwrap();
+                assert_eq!(connecting.is_jls_authenticated(), jls);
+                connecting.await
+            }
+        );
+        let (conn, server_conn) = (conn.unwrap(), server_conn.unwrap());
+        assert_eq!(conn.is_jls_authenticated(), jls);
+        assert_eq!(server_conn.is_jls_authenticated(), jls);
+        assert_eq!(server_conn.server_name().as_deref(), Some("localhost"));
+        assert_eq!(conn.server_name(), None);
+    }
+}
+
+#[cfg(feature = "jls-forward-tcp")]
+#[tokio::test]
+async fn jls_forward_tcp() {
+    use std::io::{Read, Write};
+    let _guard = subscribe();
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    // The upstream reads one framed datagram and answers with another
+    let listener = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let upstream_addr = listener.local_addr().unwrap();
+    let upstream = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut datagram = vec![0; u16::from_be_bytes(len).into()];
+        stream.read_exact(&mut datagram).unwrap();
+        let mut answer = 100u16.to_be_bytes().to_vec();
+        answer.resize(102, 0x40);
+        stream.write_all(&answer).unwrap();
+        (datagram, stream)
+    });
+
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut config = crate::EndpointConfig::default();
+    config.jls_upstream_transport(crate::JlsUpstreamTransport::Tcp);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+
+    let forwarded = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    forwarded
+        .send_to(&initial, server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mut buf = vec![0; 65536];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(5), forwarded.recv_from(&mut buf))
+        .await
+        .expect("answer not relayed")
+        .unwrap();
+    assert_eq!(&buf[..len], &[0x40; 100][..]);
+    let (datagram, _stream) = upstream.join().unwrap();
+    assert_eq!(datagram, initial);
+
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.bytes_to_upstream, initial.len() as u64);
+    assert_eq!(stats.bytes_from_upstream, 100);
+}
+
+#[cfg(feature = "jls-forward-socks5")]
+#[tokio::test]
+async fn jls_forward_socks5() {
+    use std::io::{Read, Write};
+    let _guard = subscribe();
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    // Only the proxy ever hears from the forwarder, so the upstream needn't do anything
+    let upstream = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let mut header = vec![0, 0, 0, 4];
+    header.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    header.extend_from_slice(&upstream_addr.port().to_be_bytes());
+
+    // The proxy authenticates the forwarder, opens an association, and answers the first datagram
+    // relayed through it on the upstream's behalf
+    let proxy = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let proxy_relay = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let proxy_relay_port = proxy_relay.local_addr().unwrap().port();
+    let proxy = std::thread::spawn(move || {
+        let (mut stream, _) = proxy.accept().unwrap();
+        let mut buf = vec![0; 65536];
+        stream.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(&buf[..3], &[5, 1, 2]);
+        stream.write_all(&[5, 2]).unwrap();
+        stream.read_exact(&mut buf[..11]).unwrap();
+        assert_eq!(&buf[..11], b"\x01\x04user\x04pass");
+        stream.write_all(&[1, 0]).unwrap();
+        stream.read_exact(&mut buf[..10]).unwrap();
+        assert_eq!(&buf[..4], &[5, 3, 0, 1]);
+        // The unspecified address stands for the proxy's own
+        let mut reply = vec![5, 0, 0, 4];
+        reply.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        reply.extend_from_slice(&proxy_relay_port.to_be_bytes());
+        stream.write_all(&reply).unwrap();
+
+        let (len, forwarder) = proxy_relay.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..header.len()], &header[..]);
+        let datagram = buf[header.len()..len].to_vec();
+        let mut answer = header;
+        answer.resize(answer.len() + 100, 0x40);
+        proxy_relay.send_to(&answer, forwarder).unwrap();
+        (datagram, stream)
+    });
+
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let mut config = crate::EndpointConfig::default();
+    config.jls_upstream_socks5(Some(
+        crate::JlsSocks5Proxy::with_credentials(proxy_addr, "user", "pass").unwrap(),
+    ));
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+
+    let forwarded = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    forwarded
+        .send_to(&initial, server.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mut buf = vec![0; 65536];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(5), forwarded.recv_from(&mut buf))
+        .await
+        .expect("answer not relayed")
+        .unwrap();
+    assert_eq!(&buf[..len], &[0x40; 100][..]);
+    let (datagram, _stream) = proxy.join().unwrap();
+    assert_eq!(datagram, initial);
+
+    let stats = server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.bytes_to_upstream, initial.len() as u64);
+    assert_eq!(stats.bytes_from_upstream, 100);
+    assert_eq!(stats.setup_failures, 0);
+    drop(upstream);
+}
+
+#[tokio::test]
+async fn jls_upstream_routes() {
+    let _guard = subscribe();
+    let upstream = || async {
+        let socket = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    };
+    let (a, a_addr) = upstream().await;
+    let (b, b_addr) = upstream().await;
+    let (default, default_addr) = upstream().await;
+
+    let (cert, key) = self_signed_cert();
+    let server = |drop_unrouted: bool| {
+        let server_crypto = jls_server_crypto(cert.clone(), key.clone(), default_addr);
+        let mut config = crate::EndpointConfig::default();
+        config
+            .jls_upstream_routes(vec![
+                ("*.example.com".into(), b_addr),
+                ("A.example.com.".into(), a_addr),
+            ])
+            .jls_drop_unrouted(drop_unrouted);
+        Endpoint::new(
+            config,
+            Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+            UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+            Arc::new(TokioRuntime),
+        )
+        .unwrap()
+    };
+
+    // Clients without JLS credentials, each from an address of its own
+    let mut clients = Vec::new();
+    let mut connect = |server: &Endpoint, server_name: &str| {
+        let mut client =
+            Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+        client.set_default_client_config(ClientConfig::with_root_certificates(
+            rustls::RootCertStore::empty(),
+        ));
+        let connecting = client
+            .connect(server.local_addr().unwrap(), server_name)
+            .unwrap();
+        clients.push((client, connecting));
+    };
+    async fn forwarded(upstream: &tokio::net::UdpSocket) {
+        let mut buf = vec![0; 65536];
+        tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+            .await
+            .expect("client not forwarded")
+            .unwrap();
+    }
+
+    // The exact match beats the wildcard, which covers any name below its domain
+    let routing = server(false);
+    connect(&routing, "a.example.com");
+    forwarded(&a).await;
+    connect(&routing, "www.b.example.com");
+    forwarded(&b).await;
+    connect(&routing, "example.org");
+    forwarded(&default).await;
+    let stats = routing.jls_upstream_stats();
+    assert_eq!(stats[&a_addr].routed_by_server_name, 1);
+    assert_eq!(stats[&b_addr].routed_by_server_name, 1);
+    assert_eq!(stats[&default_addr].routed_by_server_name, 0);
+    let mut upstreams = routing
+        .jls_forwards()
+        .into_iter()
+        .map(|x| x.upstream_address)
+        .collect::<Vec<_>>();
+    upstreams.sort();
+    let mut expected = vec![a_addr, b_addr, default_addr];
+    expected.sort();
+    assert_eq!(upstreams, expected);
+
+    // The domain itself doesn't match its wildcard
+    let dropping = server(true);
+    connect(&dropping, "example.com");
+    let refused = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let stats = dropping.jls_upstream_stats();
+            if stats
+                .get(&default_addr)
+                .map_or(false, |x| x.refused_unrouted == 1)
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+    refused.await.expect("unrouted client not refused");
+    assert!(dropping.jls_forwards().is_empty());
+}
+
+#[tokio::test]
+async fn jls_forward_total_queue_limit() {
+    let _guard = subscribe();
+
+    let upstream_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9);
+    let (cert, key) = self_signed_cert();
+    let roots = trusting(&cert);
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    // Far below the default per-connection limit, so only the total turns datagrams away
+    const LIMIT: u64 = 4000;
+    let mut config = crate::EndpointConfig::default();
+    config.jls_forward_total_queue_limit(LIMIT);
+    let server = Endpoint::new(
+        config,
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(MockRuntime::upstream(|_| Ok(Box::new(StuckSocket)))),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    // A client without JLS credentials gets forwarded to an upstream that takes nothing
+    let socket = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+    let blaster = socket.try_clone().unwrap();
+    let mut client =
+        Endpoint::new(Default::default(), None, socket, Arc::new(TokioRuntime)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let _connecting = client.connect(server_addr, "localhost").unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.debug_snapshot().forwards.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("client not forwarded");
+
+    const DATAGRAMS: u64 = 20;
+    for _ in 0..DATAGRAMS {
+        let mut datagram = [0; 1000];
+        datagram[0] = 0x40;
+        blaster.send_to(&datagram, server_addr).unwrap();
+    }
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while server.jls_upstream_stats()[&upstream_addr].dropped_by_total_limit < DATAGRAMS - 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("datagrams not dropped");
+    let stats = &server.jls_upstream_stats()[&upstream_addr];
+    assert_eq!(stats.dropped_to_upstream, 0);
+    assert!(server.stats().forward_queued_bytes <= LIMIT);
+    assert!(server.stats().forward_queued_bytes > 0);
+}
+
+#[tokio::test]
+async fn jls_forward_burst_from_upstream() {
+    let _guard = subscribe();
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    let upstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+    let server = Endpoint::new(
+        Default::default(),
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).unwrap(),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let downstream = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    downstream.send_to(&initial, server_addr).await.unwrap();
+    let mut buf = vec![0; 65536];
+    let (_, relay) = tokio::time::timeout(Duration::from_secs(5), upstream.recv_from(&mut buf))
+        .await
+        .expect("Initial not forwarded")
+        .unwrap();
+
+    // Far more than fits in one turn's budget, which the pool and driver work through over
+    // several turns
+    const DATAGRAMS: usize = 500;
+    let receiver = tokio::spawn(async move {
+        let mut received = 0;
+        while received < DATAGRAMS {
+            tokio::time::timeout(Duration::from_secs(5), downstream.recv_from(&mut buf))
+                .await
+                .expect("burst not relayed")
+                .unwrap();
+            received += 1;
+        }
+    });
+    for chunk in 0..DATAGRAMS / 50 {
+        for i in 0..50 {
+            let mut datagram = [0x40; 100];
+            datagram[1..9].copy_from_slice(&((chunk * 50 + i) as u64).to_be_bytes());
+            upstream.send_to(&datagram, relay).await.unwrap();
+        }
+        // Paced so that no socket buffer overflows on the way
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    receiver.await.unwrap();
+    let snapshot = server.debug_snapshot();
+    assert_eq!(snapshot.forward_work.cycle_time, crate::RECV_TIME_BOUND);
+}
+
+#[tokio::test]
+async fn send_partial_progress() {
+    use crate::Runtime as _;
+
+    let _guard = subscribe();
+    let server = endpoint();
+    let server_addr = server.local_addr().unwrap();
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let client = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        None,
+        // Sends one transmit per call at most, and every other call none at all, so the endpoint's
+        // queue keeps wrapping around as it's refilled
+        Box::new(
+            FaultySocket::new(TokioRuntime.wrap_udp_socket(socket).unwrap())
+                .one_by_one()
+                .stutter(),
+        ),
+        Arc::new(TokioRuntime),
+    )
+    .unwrap();
+
+    const LEN: usize = 256 * 1024;
+    let connecting = client
+        .connect_with(
+            server.default_client_config.clone().unwrap(),
+            server_addr,
+            "localhost",
+        )
+        .unwrap();
+    let upload = async {
+        let conn = connecting.await.unwrap();
+        let mut send = conn.open_uni().await.unwrap();
+        send.write_all(&vec![0xab; LEN]).await.unwrap();
+        send.finish().await.unwrap();
+        conn
+    };
+    let download = async {
+        let conn = server.accept().await.unwrap().await.unwrap();
+        let mut recv = conn.accept_uni().await.unwrap();
+        recv.read_to_end(LEN).await.unwrap()
+    };
+    let (_conn, data) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(upload, download)
+    })
+    .await
+    .expect("transfer stalled");
+    assert_eq!(data.len(), LEN);
+}
+
+#[tokio::test]
+async fn jls_forward_ecn() {
+    use std::{
+        sync::Mutex,
+        task::{Context, Poll, Waker},
+    };
+
+    use udp::EcnCodepoint;
+
+    let _guard = subscribe();
+
+    /// Records the ECN codepoints of what's sent, answering each datagram with one marked ECT(1)
+    #[derive(Debug)]
+    struct EchoSocket {
+        upstream: SocketAddr,
+        sent: Arc<Mutex<Vec<Option<EcnCodepoint>>>>,
+        /// Answers owed, and the task to wake for them
+        owed: Mutex<(usize, Option<Waker>)>,
+    }
+
+    impl crate::AsyncUdpSocket for EchoSocket {
+        fn poll_send(
+            &self,
+            _: &udp::UdpState,
+            _: &mut Context,
+            transmits: &[udp::Transmit],
+        ) -> Poll<io::Result<usize>> {
+            self.sent
+                .lock()
+                .unwrap()
+                .extend(transmits.iter().map(|t| t.ecn));
+            let mut owed = self.owed.lock().unwrap();
+            owed.0 += transmits.len();
+            if let Some(waker) = owed.1.take() {
+                waker.wake();
+            }
+            Poll::Ready(Ok(transmits.len()))
+        }
+
+        fn poll_recv(
+            &self,
+            cx: &mut Context,
+            bufs: &mut [io::IoSliceMut<'_>],
+            meta: &mut [udp::RecvMeta],
+        ) -> Poll<io::Result<usize>> {
+            let mut owed = self.owed.lock().unwrap();
+            if owed.0 == 0 {
+                owed.1 = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            owed.0 -= 1;
+            bufs[0][..100].fill(0x40);
+            meta[0] = udp::RecvMeta::default();
+            meta[0].addr = self.upstream;
+            meta[0].len = 100;
+            meta[0].stride = 100;
+            meta[0].ecn = Some(EcnCodepoint::Ect1);
+            Poll::Ready(Ok(1))
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 40000))
+        }
+    }
+
+    // Capture a genuine Initial from a client without JLS credentials
+    let capture = tokio::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(
+        rustls::RootCertStore::empty(),
+    ));
+    let _connecting = client
+        .connect(capture.local_addr().unwrap(), "localhost")
+        .unwrap();
+    let mut initial = vec![0; 65536];
+    let (len, _) = capture.recv_from(&mut initial).await.unwrap();
+    initial.truncate(len);
+
+    let upstream_addr = "[::1]:9".parse().unwrap();
+    let (cert, key) = self_signed_cert();
+    let server_crypto = jls_server_crypto(cert, key, upstream_addr);
+
+    // The client reaches the server at one of its addresses, marking what it sends ECT(0)
+    let client_addr = "[::1]:40001".parse().unwrap();
+    let local_ip = Some(IpAddr::V6("::2".parse().unwrap()));
+    let mut short = vec![0x40; 100];
+    short[1..].fill(0xee);
+    let ect0 = Some(EcnCodepoint::Ect0);
+    let socket = GroSocket::new(
+        "[::]:4433".parse().unwrap(),
+        vec![
+            (client_addr, initial.len(), initial),
+            (client_addr, 100, short),
+        ],
+    )
+    .marked(ect0, local_ip);
+    let sent = socket.sent.clone();
+    let upstream_sent = Arc::new(Mutex::new(Vec::new()));
+    let _server = Endpoint::new_with_abstract_socket(
+        Default::default(),
+        Some(crate::ServerConfig::with_crypto(Arc::new(server_crypto))),
+        Box::new(socket),
+        // Gives the forward pool an `EchoSocket`
+        Arc::new(MockRuntime::wrapping({
+            let sent = upstream_sent.clone();
+            move |_| {
+                Ok(Box::new(EchoSocket {
+                    upstream: upstream_addr,
+                    sent: sent.clone(),
+                    owed: Mutex::new((0, None)),
+                }))
+            }
+        })),
+    )
+    .unwrap();
+
+    // Both datagrams reach the upstream, and both answers the client
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while sent.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("answers not relayed");
+    // The ClientHello is handed over by the QUIC state machine, which keeps no marking
+    assert_eq!(*upstream_sent.lock().unwrap(), [None, ect0]);
+    for transmit in sent.lock().unwrap().iter() {
+        assert_eq!(transmit.destination, client_addr);
+        assert_eq!(transmit.ecn, Some(EcnCodepoint::Ect1));
+        assert_eq!(transmit.src_ip, local_ip);
+    }
+}
+
+#[tokio::test]
+async fn accept_incoming() {
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let mut server_config = crate::ServerConfig::with_single_cert(vec![cert.clone()], key).unwrap();
+    server_config.defer_incoming(true);
+    let server = Endpoint::server(
+        server_config,
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let roots = trusting(&cert);
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let client_addr = client.local_addr().unwrap();
+
+    // The first attempt is asked to retry, and accepted once validated
+    let connecting = client.connect(server_addr, "localhost").unwrap();
+    let incoming = server.accept_incoming().await.unwrap();
+    assert_eq!(incoming.remote_address(), client_addr);
+    assert!(!incoming.remote_address_validated());
+    let orig_dst_cid = incoming.orig_dst_cid();
+    incoming.retry().unwrap();
+    let incoming = server.accept_incoming().await.unwrap();
+    assert!(incoming.remote_address_validated());
+    assert_eq!(incoming.orig_dst_cid(), orig_dst_cid);
+    let crate::RetryError(incoming) = incoming.retry().unwrap_err();
+    let accepted = incoming.accept().unwrap();
+    let (client_conn, server_conn) = tokio::join!(connecting, accepted);
+    let client_conn = client_conn.unwrap();
+    let server_conn = server_conn.unwrap();
+    assert_eq!(server_conn.remote_address(), client_addr);
+    // Accepted connections are handed over directly, not through `accept()`
+    assert_eq!(server.debug_snapshot().incoming, 0);
+
+    // The second is refused
+    let connecting = client.connect(server_addr, "localhost").unwrap();
+    server.accept_incoming().await.unwrap().refuse();
+    match connecting.await {
+        Err(crate::ConnectionError::ConnectionClosed(close)) => {
+            assert_eq!(
+                close.error_code,
+                proto::TransportErrorCode::CONNECTION_REFUSED
+            );
+        }
+        x => panic!("unexpected outcome: {:?}", x.map(|_| ())),
+    }
+    drop(client_conn);
+}
+
+#[tokio::test]
+async fn rebind_abstract() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::Runtime as _;
+
+    let _guard = subscribe();
+    let (cert, key) = self_signed_cert();
+    let server_config = crate::ServerConfig::with_single_cert(vec![cert.clone()], key).unwrap();
+    let server = Endpoint::server(
+        server_config,
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+    )
+    .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let roots = trusting(&cert);
+    let mut client = Endpoint::client(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    client.set_default_client_config(ClientConfig::with_root_certificates(roots));
+    let connection = client
+        .connect(server_addr, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+    let accepted = server.accept().await.unwrap().await.unwrap();
+
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+    let new_addr = socket.local_addr().unwrap();
+    let sent = Arc::new(AtomicUsize::new(0));
+    let socket = CountingSocket {
+        inner: TokioRuntime.wrap_udp_socket(socket).unwrap(),
+        sent: sent.clone(),
+    };
+    client.rebind_abstract(Box::new(socket)).unwrap();
+    assert_eq!(client.local_addr().unwrap(), new_addr);
+
+    // The connection carries on over the new socket
+    let mut stream = connection.open_uni().await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.finish().await.unwrap();
+    let mut stream = accepted.accept_uni().await.unwrap();
+    assert_eq!(stream.read_to_end(5).await.unwrap(), b"hello");
+    assert!(sent.load(Ordering::Relaxed) > 0);
+    assert_eq!(accepted.remote_address(), new_addr);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn recv_timestamps() {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    udp::UdpSocketState::configure((&socket).into()).unwrap();
+    udp::UdpSocketState::set_recv_timestamps((&socket).into(), true).unwrap();
+    let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    // Too far apart in time to share a timestamp, but received in one batch
+    const DATAGRAMS: usize = 4;
+    for i in 0..DATAGRAMS {
+        sender
+            .send_to(&[i as u8; 32], socket.local_addr().unwrap())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+    }
+
+    let state = udp::UdpSocketState::new();
+    let mut storage = vec![0; DATAGRAMS * 64];
+    let mut bufs = storage
+        .chunks_mut(64)
+        .map(io::IoSliceMut::new)
+        .collect::<Vec<_>>();
+    let mut metas = [udp::RecvMeta::default(); DATAGRAMS];
+    let n = state.recv((&socket).into(), &mut bufs, &mut metas).unwrap();
+    assert_eq!(n, DATAGRAMS);
+    let timestamps = metas
+        .iter()
+        .map(|x| x.timestamp().expect("no timestamp"))
+        .collect::<Vec<_>>();
+    for pair in timestamps.windows(2) {
+        assert!(pair[0] < pair[1], "{timestamps:?}");
+    }
+    let age = std::time::SystemTime::now()
+        .duration_since(timestamps[0])
+        .unwrap();
+    assert!(age < Duration::from_secs(5));
+
+    // Sockets that weren't set up for them report none
+    let plain = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    udp::UdpSocketState::configure((&plain).into()).unwrap();
+    sender
+        .send_to(&[0; 32], plain.local_addr().unwrap())
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    let n = udp::UdpSocketState::new()
+        .recv((&plain).into(), &mut bufs, &mut metas)
+        .unwrap();
+    assert_eq!(n, 1);
+    assert_eq!(metas[0].timestamp(), None);
+}
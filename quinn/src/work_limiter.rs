@@ -1,5 +1,7 @@
 use std::time::{Duration, Instant};
 
+use crate::snapshot::WorkLimitSnapshot;
+
 /// Limits the amount of time spent on a certain type of work in a cycle
 ///
 /// The limiter works dynamically: For a sampled subset of cycles it measures
@@ -118,6 +120,13 @@ impl WorkLimiter {
         };
     }
 
+    pub(crate) fn snapshot(&self) -> WorkLimitSnapshot {
+        WorkLimitSnapshot {
+            cycle_time: self.desired_cycle_time,
+            allowed: self.allowed,
+        }
+    }
+
     #[cfg(not(test))]
     fn now(&self) -> Instant {
         Instant::now()